@@ -0,0 +1,165 @@
+// Benchmarks of every decoder on payloads like those seen in the wild, so that decoding, which
+// every advertisement goes through, doesn't get slower unnoticed. Run with
+// `cargo bench --features bench`.
+
+use std::collections::HashMap;
+
+use btleplug::api::bleuuid::uuid_from_u16;
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+use blueplug::bench;
+
+// Advertisements, by protocol: the manufacturer id or service UUID, and payloads in hex.
+const RUUVI: (u16, &[&str]) = (
+    0x0499,
+    &[
+        // RAWv2, the test vectors of Ruuvi's specification: valid, maximum and minimum values.
+        "0512fc5394c37c0004fffc040cac364200cdcbb8334c884f",
+        "057ffffffefffe7fff7fff7fffffdefefffecbb8334c884f",
+        "058001000000008001800180010000000000cbb8334c884f",
+        // RAWv1.
+        "03291a1ece1efc18f94202ca0b53",
+    ],
+);
+const BTHOME: (u16, &[&str]) = (
+    0xfcd2,
+    &[
+        // Packet id, battery, temperature and humidity.
+        "40007e0164027c07033c0f",
+        // The same, and a voltage.
+        "4000010164027c07033c0f0c020c",
+    ],
+);
+const ATC: (u16, &[&str]) = (
+    0x181a,
+    &[
+        // ATC1441 and pvvx custom formats.
+        "a4c1388f2c1a00e53c640b862a",
+        "1a2c8f38c1a454083c0f860b64170e",
+    ],
+);
+const MI_SCALE: (u16, &[&str]) = (0x181b, &["0226e807050e081e00f401a438"]);
+// An iBeacon, which nothing decodes.
+const UNKNOWN: (u16, &[&str]) = (0x004c, &["0215e2c56db5dffb48d2b060d0f5a71096e000010002c5"]);
+
+// GATT characteristic values, in hex.
+const WAVE_PLUS: &str = "015f00003c00ffff520854c520038c0000000000";
+// 12.62 V at 83 %, encrypted.
+const BM2: &str = "a505b9a9d1f4b3203b7ea790ee18f885";
+// Battery at 87 % and 13.2 V, charging at 2.45 A, 25 °C, -5 °C outside and 58 W of solar.
+const RENOGY: &str = "ff0344 0057 0084 00f5 1985 0000 0000 0000 0000 0000 003a 0000 0000 \
+                      0000 0000 0000 0000 0000 0000 0000 0000 0000 0000 0000 0000 \
+                      0000 0000 0000 0000 0000 0000 0000 0000 0002 0000 0fb2";
+const WEIGHT: &str = "00a438";
+const BODY_COMPOSITION: &str = "0006d2008813a438";
+const HEART_RATE: &str = "1790000004 0003";
+const CYCLING_POWER: &[&str] = &["2000fa000a000004", "200004010b00ab06"];
+
+type Decoder = fn(&[u8]) -> color_eyre::Result<Vec<blueplug::Measurement>>;
+
+fn bytes(hex: &str) -> Vec<u8> {
+    let hex: String = hex.split_whitespace().collect();
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).expect("valid hex"))
+        .collect()
+}
+
+fn manufacturer_data((id, payloads): (u16, &[&str])) -> Vec<HashMap<u16, Vec<u8>>> {
+    payloads
+        .iter()
+        .map(|payload| HashMap::from([(id, bytes(payload))]))
+        .collect()
+}
+
+fn service_data((uuid, payloads): (u16, &[&str])) -> Vec<HashMap<uuid::Uuid, Vec<u8>>> {
+    payloads
+        .iter()
+        .map(|payload| HashMap::from([(uuid_from_u16(uuid), bytes(payload))]))
+        .collect()
+}
+
+fn advertisements(c: &mut Criterion) {
+    let mut group = c.benchmark_group("advertisements");
+    let ruuvi = manufacturer_data(RUUVI);
+    // Make sure a corpus decodes, so a broken payload doesn't benchmark the error path.
+    assert!(ruuvi
+        .iter()
+        .all(|data| !bench::manufacturer_data(data).is_empty()));
+    group.bench_function("ruuvi", |b| {
+        b.iter(|| {
+            for data in &ruuvi {
+                black_box(bench::manufacturer_data(black_box(data)));
+            }
+        })
+    });
+    let bthome = service_data(BTHOME);
+    assert!(bthome
+        .iter()
+        .all(|data| !bench::service_data(data).is_empty()));
+    group.bench_function("bthome", |b| {
+        b.iter(|| {
+            for data in &bthome {
+                black_box(bench::service_data(black_box(data)));
+            }
+        })
+    });
+    // ATC advertisements are parsed, but their readings aren't published yet.
+    let atc = service_data(ATC);
+    group.bench_function("atc", |b| {
+        b.iter(|| {
+            for data in &atc {
+                black_box(bench::service_data(black_box(data)));
+            }
+        })
+    });
+    let mi_scale = service_data(MI_SCALE);
+    assert!(mi_scale
+        .iter()
+        .all(|data| !bench::mi_scale(data).is_empty()));
+    group.bench_function("mi_scale", |b| {
+        b.iter(|| {
+            for data in &mi_scale {
+                black_box(bench::mi_scale(black_box(data)));
+            }
+        })
+    });
+    let unknown = manufacturer_data(UNKNOWN);
+    group.bench_function("unknown", |b| {
+        b.iter(|| {
+            for data in &unknown {
+                black_box(bench::manufacturer_data(black_box(data)));
+            }
+        })
+    });
+    group.finish();
+}
+
+fn gatt(c: &mut Criterion) {
+    let mut group = c.benchmark_group("gatt");
+    let decoders: [(&str, Decoder, &str); 6] = [
+        ("airthings_wave_plus", bench::airthings_wave_plus, WAVE_PLUS),
+        ("bm2", bench::bm2, BM2),
+        ("renogy", bench::renogy, RENOGY),
+        ("weight", bench::weight, WEIGHT),
+        (
+            "body_composition",
+            bench::body_composition,
+            BODY_COMPOSITION,
+        ),
+        ("heart_rate", bench::heart_rate, HEART_RATE),
+    ];
+    for (name, decode, payload) in decoders {
+        let value = bytes(payload);
+        assert!(!decode(&value).expect(name).is_empty(), "{}", name);
+        group.bench_function(name, |b| b.iter(|| black_box(decode(black_box(&value)))));
+    }
+    let cycling_power: Vec<_> = CYCLING_POWER.iter().map(|value| bytes(value)).collect();
+    group.bench_function("cycling_power", |b| {
+        b.iter(|| black_box(bench::cycling_power(black_box(&cycling_power))))
+    });
+    group.finish();
+}
+
+criterion_group!(decoders, advertisements, gatt);
+criterion_main!(decoders);
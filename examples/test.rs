@@ -3,12 +3,8 @@ use btleplug::platform::{Manager, PeripheralId};
 use color_eyre::eyre::eyre;
 use color_eyre::Result;
 use futures::stream::StreamExt;
-use futures::{ready, Stream};
-use ruuvi_sensor_protocol::{BatteryPotential, Humidity, ParseError, SensorValues, Temperature};
+use ruuvi_sensor_protocol::{BatteryPotential, Humidity, SensorValues, Temperature};
 use std::collections::HashMap;
-use std::future::{ready, Future};
-use std::pin::Pin;
-use std::task::{Context, Poll};
 
 // struct Payload {
 //     humdity: f64
@@ -105,13 +101,12 @@ async fn main() -> Result<()> {
             CentralEvent::DeviceDisconnected(_id) => { /* println!("DeviceDisconnected: {:?}", id); */
             }
             CentralEvent::ManufacturerDataAdvertisement {
-                id: id,
+                id,
                 manufacturer_data: data,
             } => {
                 if let Some(name) = device_names.get(&id) {
                     for (id, data) in data.iter() {
-                        if let Ok(parsed) =
-                            SensorValues::from_manufacturer_specific_data(id.clone(), data)
+                        if let Ok(parsed) = SensorValues::from_manufacturer_specific_data(*id, data)
                         {
                             let mut output = name.clone();
                             if let Some(humidity) = parsed.humidity_as_ppm() {
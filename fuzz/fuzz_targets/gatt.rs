@@ -0,0 +1,6 @@
+#![no_main]
+
+use blueplug::fuzzing;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| fuzzing::gatt(data));
@@ -0,0 +1,11 @@
+#![no_main]
+
+use blueplug::fuzzing;
+use libfuzzer_sys::fuzz_target;
+
+// The 16 bit service UUID, little endian as advertised, followed by the data.
+fuzz_target!(|data: &[u8]| {
+    if let [low, high, data @ ..] = data {
+        fuzzing::service_data(u16::from_le_bytes([*low, *high]), data);
+    }
+});
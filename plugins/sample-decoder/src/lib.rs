@@ -0,0 +1,73 @@
+//! A sample blueplug decoder plugin.
+//!
+//! It decodes a made-up sensor that advertises manufacturer data under the 0xffff test company
+//! id, laid out as a little-endian i16 temperature in hundredths of a degree followed by a u8
+//! relative humidity percentage. See `src/plugin.rs` in blueplug for the host API.
+
+use std::alloc::{alloc, dealloc, Layout};
+
+const TEST_COMPANY_ID: i32 = 0xffff;
+
+#[link(wasm_import_module = "blueplug")]
+extern "C" {
+    fn log(ptr: *const u8, len: usize);
+}
+
+fn debug(message: &str) {
+    unsafe { log(message.as_ptr(), message.len()) }
+}
+
+#[no_mangle]
+pub extern "C" fn blueplug_alloc(len: i32) -> *mut u8 {
+    match Layout::array::<u8>(len.max(1) as usize) {
+        Ok(layout) => unsafe { alloc(layout) },
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
+/// # Safety
+///
+/// `ptr` must have come from `blueplug_alloc` with the same `len`.
+#[no_mangle]
+pub unsafe extern "C" fn blueplug_free(ptr: *mut u8, len: i32) {
+    if let Ok(layout) = Layout::array::<u8>(len.max(1) as usize) {
+        dealloc(ptr, layout)
+    }
+}
+
+/// # Safety
+///
+/// `ptr` must point at `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn blueplug_decode_manufacturer_data(
+    company_id: i32,
+    ptr: *const u8,
+    len: i32,
+) -> i64 {
+    if company_id != TEST_COMPANY_ID {
+        return 0;
+    }
+    let data = std::slice::from_raw_parts(ptr, len as usize);
+    let [t0, t1, humidity, ..] = *data else {
+        debug("payload too short");
+        return 0;
+    };
+    let temperature = i16::from_le_bytes([t0, t1]) as f64 / 100.0;
+
+    let json = format!(
+        r#"[{{"kind":"temperature","value":{temperature}}},{{"kind":"humidity","value":{humidity}}}]"#
+    );
+    into_result(json.into_bytes())
+}
+
+// Hands ownership of `bytes` to the host, which releases it through blueplug_free.
+fn into_result(bytes: Vec<u8>) -> i64 {
+    let len = bytes.len();
+    let ptr = blueplug_alloc(len as i32);
+    if ptr.is_null() {
+        // Out of memory; the host treats this like a payload the plugin doesn't understand.
+        return 0;
+    }
+    unsafe { std::ptr::copy_nonoverlapping(bytes.as_ptr(), ptr, len) };
+    ((ptr as i64) << 32) | len as i64
+}
@@ -0,0 +1,333 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use btleplug::api::{Peripheral as _, WriteType};
+use btleplug::platform::{Adapter, Manager};
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use rumqttc::{AsyncClient, Event, Packet, QoS};
+use serde::de::IntoDeserializer;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time;
+
+use crate::adapter::{select_adapter, AdapterSelector};
+use crate::config::{ActionConfig, BrokerConfig, CommandsConfig, GattConfig};
+use crate::gatt::{characteristic, connect, find, ValueFormat};
+use crate::mqtt::{mqtt_options, Connection};
+use crate::preflight::explain;
+use crate::supervisor::SUPERVISOR;
+
+// Actions are characteristic writes to GATT devices, such as making a Ruuvi tag blink to identify
+// it or changing how often a sensor advertises, run when a command arrives over MQTT.
+pub struct Actions(Vec<Action>);
+
+struct Action {
+    config: ActionConfig,
+    payload: Vec<Segment>,
+}
+
+// Segment is a piece of a payload template: bytes given in hex, or a parameter of the command.
+#[derive(Debug, PartialEq)]
+enum Segment {
+    Bytes(Vec<u8>),
+    Parameter(String, ValueFormat),
+}
+
+impl Actions {
+    pub fn new(actions: &[ActionConfig]) -> Result<Self> {
+        let actions = actions
+            .iter()
+            .map(|config| {
+                let payload = template(&config.payload)
+                    .wrap_err_with(|| format!("action {:?}: invalid payload", config.name))?;
+                Ok(Action {
+                    config: config.clone(),
+                    payload,
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Actions(actions))
+    }
+
+    fn get(&self, name: &str) -> Option<&Action> {
+        self.0.iter().find(|action| action.config.name == name)
+    }
+}
+
+impl Action {
+    // payload fills in the template with the command's parameters.
+    fn payload(&self, parameters: &Map<String, Value>) -> Result<Vec<u8>> {
+        let mut payload = Vec::new();
+        for segment in &self.payload {
+            match segment {
+                Segment::Bytes(bytes) => payload.extend(bytes),
+                Segment::Parameter(name, format) => {
+                    let value = parameters
+                        .get(name)
+                        .and_then(Value::as_f64)
+                        .ok_or_else(|| eyre!("parameter {:?} is missing or not a number", name))?;
+                    payload.extend(format.encode(value)?);
+                }
+            }
+        }
+        Ok(payload)
+    }
+}
+
+// template parses a payload such as "02{interval:u16}ff".
+fn template(payload: &str) -> Result<Vec<Segment>> {
+    let mut segments = Vec::new();
+    let mut rest = payload;
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix('{') {
+            let (placeholder, after) = after
+                .split_once('}')
+                .ok_or_else(|| eyre!("unclosed {{ in {:?}", payload))?;
+            let (name, format) = placeholder.split_once(':').ok_or_else(|| {
+                eyre!(
+                    "{{{}}} needs a format, e.g. {{{}:u8}}",
+                    placeholder,
+                    placeholder
+                )
+            })?;
+            let format: Result<ValueFormat, serde::de::value::Error> =
+                ValueFormat::deserialize(format.into_deserializer());
+            let format = format.map_err(|_| {
+                eyre!(
+                    "{:?} isn't a format, use u8, i8, u16, i16, u32, i32 or f32",
+                    placeholder
+                )
+            })?;
+            segments.push(Segment::Parameter(name.to_string(), format));
+            rest = after;
+        } else {
+            let end = rest.find('{').unwrap_or(rest.len());
+            let hex: String = rest[..end].split_whitespace().collect();
+            if !hex.len().is_multiple_of(2) {
+                return Err(eyre!("odd number of hex digits in {:?}", payload));
+            }
+            let bytes = (0..hex.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+                .collect::<Result<_, _>>()
+                .wrap_err_with(|| format!("{:?} isn't hex", &rest[..end]))?;
+            segments.push(Segment::Bytes(bytes));
+            rest = &rest[end..];
+        }
+    }
+    Ok(segments)
+}
+
+// Actions that may run at once. Each holds a GATT connection, so a command arriving while this
+// many run is refused rather than connecting as well.
+const MAX_RUNNING_ACTIONS: usize = 4;
+
+// Commands runs actions for one broker's command topics.
+struct Commands {
+    actions: Actions,
+    devices: Vec<GattConfig>,
+    central: Adapter,
+    client: AsyncClient,
+    response_topic_prefix: String,
+    running: Arc<Semaphore>,
+}
+
+// spawn_commands subscribes to `<topic_prefix>/<action>` on the broker, using its own session as
+// ingest does, and runs the action named by the topic with the message's parameters. The outcome
+// is published to `<response_topic_prefix>/<action>` as {"action", "id", "ok", "error"}, the id
+// being whatever the command passed as "id", so callers can tell responses apart. A command
+// arriving while MAX_RUNNING_ACTIONS run is answered with an error.
+pub fn spawn_commands(
+    broker: &BrokerConfig,
+    commands: &CommandsConfig,
+    actions: Actions,
+    devices: Vec<GattConfig>,
+    adapter: Option<AdapterSelector>,
+) -> Result<()> {
+    let mut broker = broker.clone();
+    broker.client_id = format!("{}-commands", broker.client_id);
+    let (client, eventloop) = AsyncClient::new(mqtt_options(&broker)?, 10);
+    let topic_prefix = format!("{}/", commands.topic_prefix);
+    let response_topic_prefix = commands.response_topic_prefix.clone();
+
+    SUPERVISOR.spawn_once(format!("{} commands", broker.name), async move {
+        let manager = match Manager::new().await.map_err(explain) {
+            Ok(manager) => manager,
+            Err(e) => return println!("{}: commands: {:?}", broker.name, e),
+        };
+        let central = match select_adapter(&manager, adapter.as_ref()).await {
+            Ok(central) => central,
+            Err(e) => return println!("{}: commands: {:?}", broker.name, e),
+        };
+        let commands = Arc::new(Commands {
+            actions,
+            devices,
+            central,
+            client: client.clone(),
+            response_topic_prefix,
+            running: Arc::new(Semaphore::new(MAX_RUNNING_ACTIONS)),
+        });
+        let topic = format!("{}+", topic_prefix);
+        let mut connection = Connection::new(&format!("{} commands", broker.name), eventloop);
+        loop {
+            match connection.next().await {
+                // Subscriptions don't survive reconnecting with a clean session.
+                Event::Incoming(Packet::ConnAck(_)) => {
+                    if let Err(e) = client.subscribe(&topic, QoS::AtLeastOnce).await {
+                        println!("{}: commands subscribe failed {:?}", broker.name, e);
+                    }
+                }
+                Event::Incoming(Packet::Publish(publish)) => {
+                    let Some(name) = publish.topic.strip_prefix(&topic_prefix) else {
+                        continue;
+                    };
+                    // Actions take a while, so they mustn't hold up the event loop.
+                    let permit = commands.running.clone().try_acquire_owned().ok();
+                    tokio::spawn(commands.clone().run(
+                        name.to_string(),
+                        publish.payload.to_vec(),
+                        permit,
+                    ));
+                }
+                _ => {}
+            }
+        }
+    });
+    Ok(())
+}
+
+impl Commands {
+    // run runs the action if it got a permit, and responds either way.
+    async fn run(
+        self: Arc<Self>,
+        name: String,
+        payload: Vec<u8>,
+        permit: Option<OwnedSemaphorePermit>,
+    ) {
+        let parameters = if payload.is_empty() {
+            Ok(Map::new())
+        } else {
+            serde_json::from_slice(&payload).wrap_err("parameters must be a JSON object")
+        };
+        let id = parameters
+            .as_ref()
+            .ok()
+            .and_then(|parameters| parameters.get("id").cloned());
+        let result = match (parameters, permit) {
+            (Err(e), _) => Err(e),
+            (Ok(_), None) => Err(eyre!("{} actions are running already", MAX_RUNNING_ACTIONS)),
+            (Ok(parameters), Some(_permit)) => self.action(&name, &parameters).await,
+        };
+        let response = match &result {
+            Ok(()) => json!({"action": name, "id": id, "ok": true}),
+            Err(e) => {
+                println!("action {}: {:?}", name, e);
+                json!({"action": name, "id": id, "ok": false, "error": format!("{:#}", e)})
+            }
+        };
+        let topic = format!("{}/{}", self.response_topic_prefix, name);
+        if let Err(e) = self
+            .client
+            .publish(topic, QoS::AtLeastOnce, false, response.to_string())
+            .await
+        {
+            println!("action {}: response failed {:?}", name, e);
+        }
+    }
+
+    async fn action(&self, name: &str, parameters: &Map<String, Value>) -> Result<()> {
+        let action = self
+            .actions
+            .get(name)
+            .ok_or_else(|| eyre!("no action {:?}", name))?;
+        let device = self
+            .devices
+            .iter()
+            .find(|device| device.name == action.config.device)
+            .ok_or_else(|| eyre!("no gatt device {:?}", action.config.device))?;
+        let payload = action.payload(parameters)?;
+        write(&self.central, device, &action.config, &payload).await?;
+        println!(
+            "action {}: wrote {} bytes to {}",
+            name,
+            payload.len(),
+            device.name
+        );
+        Ok(())
+    }
+}
+
+// write writes payload to the action's characteristic, leaving the device connected if it already
+// was, e.g. because it is subscribed to.
+async fn write(
+    central: &Adapter,
+    device: &GattConfig,
+    action: &ActionConfig,
+    payload: &[u8],
+) -> Result<()> {
+    let was_connected = find(central, device).await?.is_connected().await?;
+    let (peripheral, _) = connect(central, device).await?;
+    let write_type = if action.with_response {
+        WriteType::WithResponse
+    } else {
+        WriteType::WithoutResponse
+    };
+    let written = time::timeout(Duration::from_secs(device.timeout), async {
+        peripheral.discover_services().await?;
+        let characteristic = characteristic(&peripheral, action.characteristic)?;
+        peripheral
+            .write(&characteristic, payload, write_type)
+            .await?;
+        Ok::<_, color_eyre::Report>(())
+    })
+    .await
+    .map_err(|_| eyre!("writing timed out"))
+    .and_then(|written| written);
+    if !was_connected {
+        let _ = peripheral.disconnect().await;
+    }
+    written
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::actions::Actions;
+    use crate::config::ActionConfig;
+
+    #[test]
+    fn test_payload() {
+        let action = |payload: &str| ActionConfig {
+            name: "interval".to_string(),
+            device: "soil".to_string(),
+            characteristic: uuid::Uuid::nil(),
+            payload: payload.to_string(),
+            with_response: true,
+        };
+        let actions = Actions::new(&[action("02 {interval:u16}ff{offset:i8}")]).unwrap();
+        let parameters = json!({"interval": 1000, "offset": -2});
+        let payload = actions
+            .get("interval")
+            .unwrap()
+            .payload(parameters.as_object().unwrap());
+        assert_eq!(payload.unwrap(), vec![0x02, 0xe8, 0x03, 0xff, 0xfe]);
+
+        let parameters = json!({"interval": 70000, "offset": 0});
+        let payload = actions
+            .get("interval")
+            .unwrap()
+            .payload(parameters.as_object().unwrap());
+        assert!(payload.is_err());
+        assert!(actions
+            .get("interval")
+            .unwrap()
+            .payload(&Default::default())
+            .is_err());
+
+        for invalid in ["0", "zz", "{interval}", "{interval:u24}", "01{interval:u8"] {
+            assert!(Actions::new(&[action(invalid)]).is_err(), "{}", invalid);
+        }
+    }
+}
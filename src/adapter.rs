@@ -0,0 +1,148 @@
+use std::convert::Infallible;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use btleplug::api::{Central, Manager as _};
+use btleplug::platform::{Adapter, Manager};
+use color_eyre::eyre::{eyre, Result};
+
+use crate::preflight::{adapter_address, explain, no_adapter};
+
+// AdapterSelector pins blueplug to one of several radios, by position (in name order), by name
+// such as `hci1`, or by MAC address.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AdapterSelector {
+    Index(usize),
+    Name(String),
+    Address(String),
+}
+
+impl FromStr for AdapterSelector {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if let Ok(index) = s.parse() {
+            Ok(AdapterSelector::Index(index))
+        } else if s.len() == 17 && s.split(':').count() == 6 {
+            Ok(AdapterSelector::Address(s.to_uppercase()))
+        } else {
+            Ok(AdapterSelector::Name(s.to_string()))
+        }
+    }
+}
+
+impl Display for AdapterSelector {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AdapterSelector::Index(i) => f.write_fmt(format_args!("#{}", i)),
+            AdapterSelector::Name(name) => f.write_str(name),
+            AdapterSelector::Address(address) => f.write_str(address),
+        }
+    }
+}
+
+impl AdapterSelector {
+    pub fn matches(&self, index: usize, name: &str, address: Option<&str>) -> bool {
+        match self {
+            AdapterSelector::Index(i) => *i == index,
+            AdapterSelector::Name(n) => n == name,
+            AdapterSelector::Address(a) => address.is_some_and(|address| address == a),
+        }
+    }
+}
+
+// select_adapter picks the adapter to scan with, the first one unless a selector says otherwise.
+pub async fn select_adapter(
+    manager: &Manager,
+    selector: Option<&AdapterSelector>,
+) -> Result<Adapter> {
+    let mut adapters = Vec::new();
+    for adapter in manager.adapters().await.map_err(explain)? {
+        let info = adapter.adapter_info().await.map_err(explain)?;
+        adapters.push((info, adapter));
+    }
+    adapters.sort_by(|(a, _), (b, _)| adapter_order(a).cmp(&adapter_order(b)));
+
+    let Some(selector) = selector else {
+        let (info, adapter) = adapters.into_iter().next().ok_or_else(no_adapter)?;
+        println!("using Bluetooth adapter {}", info);
+        return Ok(adapter);
+    };
+
+    let available = adapters
+        .iter()
+        .map(|(info, _)| info.clone())
+        .collect::<Vec<_>>();
+    for (index, (info, adapter)) in adapters.into_iter().enumerate() {
+        let name = adapter_name(&info);
+        let address = match selector {
+            AdapterSelector::Address(_) => adapter_address(name).await,
+            _ => None,
+        };
+        if selector.matches(index, name, address.as_deref()) {
+            println!("using Bluetooth adapter {}", info);
+            return Ok(adapter);
+        }
+    }
+    Err(eyre!(
+        "no Bluetooth adapter matches {}, available adapters are: {}",
+        selector,
+        available.join(", ")
+    ))
+}
+
+// btleplug describes adapters as e.g. `hci0 (usb:v1D6Bp0246d0540)`; the first word is the name.
+fn adapter_name(info: &str) -> &str {
+    info.split_whitespace().next().unwrap_or(info)
+}
+
+// adapter_order sorts adapters by their hci index, so hci10 comes after hci2, and any not named
+// like that after them by name.
+fn adapter_order(info: &str) -> (u32, &str) {
+    let index = adapter_name(info)
+        .strip_prefix("hci")
+        .and_then(|index| index.parse().ok());
+    (index.unwrap_or(u32::MAX), info)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::adapter::{adapter_order, AdapterSelector};
+
+    #[test]
+    fn test_adapter_order() {
+        let mut adapters = vec![
+            "hci10 (usb:v1D6Bp0246d0540)",
+            "other",
+            "hci2 (usb:v1D6Bp0246d0540)",
+            "hci0 (usb:v1D6Bp0246d0540)",
+        ];
+        adapters.sort_by(|a, b| adapter_order(a).cmp(&adapter_order(b)));
+        assert_eq!(
+            adapters,
+            [
+                "hci0 (usb:v1D6Bp0246d0540)",
+                "hci2 (usb:v1D6Bp0246d0540)",
+                "hci10 (usb:v1D6Bp0246d0540)",
+                "other",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_adapter_selector() {
+        assert_eq!("1".parse(), Ok(AdapterSelector::Index(1)));
+        assert_eq!(
+            "hci1".parse(),
+            Ok(AdapterSelector::Name("hci1".to_string()))
+        );
+        assert_eq!(
+            "aa:bb:cc:dd:ee:ff".parse(),
+            Ok(AdapterSelector::Address("AA:BB:CC:DD:EE:FF".to_string()))
+        );
+
+        let selector: AdapterSelector = "AA:BB:CC:DD:EE:FF".parse().unwrap();
+        assert!(selector.matches(3, "hci3", Some("AA:BB:CC:DD:EE:FF")));
+        assert!(!selector.matches(3, "hci3", None));
+    }
+}
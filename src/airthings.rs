@@ -0,0 +1,84 @@
+use color_eyre::eyre::{eyre, Result};
+use uuid::Uuid;
+
+use crate::gatt::Reader;
+use crate::Measurement;
+
+// The Wave Plus keeps its latest sensor values in one characteristic, updated every five minutes.
+pub const CURRENT_VALUES: Uuid = Uuid::from_u128(0xb42e2a68_ade7_11e4_89d3_123b93f75cba);
+
+const VERSION: u8 = 1;
+
+// Radon averages above this mean the sensor hasn't collected enough data yet, e.g. shortly after
+// the batteries were changed.
+const MAX_RADON: u16 = 16383;
+
+// wave_plus decodes the Wave Plus's current values: version, humidity, two bytes of ambient light
+// and status, then radon short and long term averages, temperature, pressure, CO2, VOC and two
+// unused fields.
+pub fn wave_plus(value: &[u8]) -> Result<Vec<Measurement>> {
+    let mut reader = Reader(value);
+    let version = reader.u8()?;
+    if version != VERSION {
+        return Err(eyre!("unsupported version {}", version));
+    }
+    let humidity = reader.u8()? as f64 / 2.0;
+    reader.skip(2)?;
+    let radon_short_term = reader.u16()?;
+    let radon_long_term = reader.u16()?;
+    let temperature = reader.u16()? as f64 / 100.0;
+    let pressure = reader.u16()? as f64 / 50.0;
+    let co2 = reader.u16()? as f64;
+    let voc = reader.u16()? as f64;
+
+    let mut measurements = vec![
+        Measurement::Humidity(humidity),
+        Measurement::Temperature(temperature),
+        other("pressure", pressure, "hPa"),
+        other("co2", co2, "ppm"),
+        other("voc", voc, "ppb"),
+    ];
+    if radon_short_term <= MAX_RADON {
+        measurements.push(other("radon_short_term", radon_short_term as f64, "Bq/m³"));
+    }
+    if radon_long_term <= MAX_RADON {
+        measurements.push(other("radon_long_term", radon_long_term as f64, "Bq/m³"));
+    }
+    Ok(measurements)
+}
+
+fn other(kind: &str, value: f64, unit: &str) -> Measurement {
+    Measurement::Other {
+        kind: kind.to_string(),
+        value,
+        unit: unit.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::airthings::wave_plus;
+    use crate::Measurement;
+
+    #[test]
+    fn test_wave_plus() {
+        let value = [
+            0x01, 0x5f, 0x00, 0x00, // version 1, 47.5 %
+            0x3c, 0x00, 0xff, 0xff, // radon 60 Bq/m³, long term not ready
+            0x52, 0x08, 0x54, 0xc5, // 21.3 °C, 1010.32 hPa
+            0x20, 0x03, 0x8c, 0x00, // 800 ppm CO2, 140 ppb VOC
+            0x00, 0x00, 0x00, 0x00,
+        ];
+        let measurements = wave_plus(&value).unwrap();
+        assert_eq!(measurements[0], Measurement::Humidity(47.5));
+        assert_eq!(measurements[1], Measurement::Temperature(21.3));
+        assert_eq!(measurements[2].value(), 1010.32);
+        assert_eq!(measurements[3].value(), 800.0);
+        assert_eq!(measurements[4].value(), 140.0);
+        assert_eq!(measurements.len(), 6);
+        assert_eq!(measurements[5].kind(), "radon_short_term");
+        assert_eq!(measurements[5].value(), 60.0);
+
+        assert!(wave_plus(&[0x02; 20]).is_err());
+    }
+}
@@ -0,0 +1,215 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::Html;
+use axum::routing::get;
+use axum::{Json, Router};
+use jiff::Timestamp;
+use serde::Serialize;
+
+use crate::health::{BrokerHealth, HEALTH};
+use crate::history::{history, History, HistoryQuery};
+use crate::latest::{LatestReading, LatestReadings};
+use crate::metrics::{Counters, PublishStats, METRICS};
+use crate::names::TOPIC_NAMES;
+
+// DASHBOARD is a single page, polling the API, so an install is useful without anything else.
+const DASHBOARD: &str = include_str!("../dashboard/index.html");
+
+// router serves the REST API, and device history when readings are archived to directory.
+pub fn router(latest: Arc<Mutex<LatestReadings>>, archive: Option<PathBuf>) -> Router {
+    let router = Router::new()
+        .route("/", get(dashboard))
+        .route("/readings", get(readings))
+        .route("/devices", get(devices))
+        .route("/telemetry", get(telemetry))
+        .route("/metrics", get(metrics))
+        .route("/health", get(health))
+        .route("/healthz", get(healthz))
+        .with_state(latest);
+    match archive {
+        Some(directory) => router.merge(
+            Router::new()
+                .route("/devices/:device/history", get(device_history))
+                .with_state(Arc::new(directory)),
+        ),
+        None => router,
+    }
+}
+
+// readings lists the latest reading of each kind from each device, including those restored from
+// the state file, which are marked stale.
+async fn readings(State(latest): State<Arc<Mutex<LatestReadings>>>) -> Json<Vec<LatestReading>> {
+    Json(latest.lock().unwrap().readings())
+}
+
+// device_history returns a device's readings over a time range from the archive, downsampled
+// and paged per the query. device is the device's topic name, as in the archive's partitions.
+async fn device_history(
+    State(directory): State<Arc<PathBuf>>,
+    Path(device): Path<String>,
+    Query(query): Query<HistoryQuery>,
+) -> Result<Json<History>, (StatusCode, String)> {
+    let now = Timestamp::now();
+    tokio::task::spawn_blocking(move || history(&directory, &device, &query, now))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map(Json)
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("{:#}", e)))
+}
+
+async fn dashboard() -> Html<&'static str> {
+    Html(DASHBOARD)
+}
+
+// DeviceSummary is a device with its latest readings, named as it is in topics and dashboards.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct DeviceSummary {
+    pub id: String,
+    pub name: String,
+    // The name of the device in topics and the archive's partitions.
+    pub topic: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
+    pub address: String,
+    // When any reading was last seen, in seconds since the Unix epoch.
+    pub last_seen: u64,
+    // Whether every reading was restored from the state file rather than seen since the start.
+    pub is_stale: bool,
+    pub readings: Vec<ReadingSummary>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ReadingSummary {
+    pub kind: String,
+    pub value: f64,
+    pub unit: String,
+    pub timestamp: u64,
+    pub is_stale: bool,
+}
+
+// devices lists every device with a latest reading.
+async fn devices(State(latest): State<Arc<Mutex<LatestReadings>>>) -> Json<Vec<DeviceSummary>> {
+    let readings = latest.lock().unwrap().readings();
+    Json(summarize(&readings))
+}
+
+// summarize groups readings, which are ordered by device, into devices.
+pub fn summarize(readings: &[LatestReading]) -> Vec<DeviceSummary> {
+    let mut devices: Vec<DeviceSummary> = Vec::new();
+    for latest in readings {
+        let device_id = &latest.reading.device_id;
+        let measurement = &latest.reading.measurement;
+        if devices.last().is_none_or(|d| d.id != device_id.id) {
+            devices.push(DeviceSummary {
+                id: device_id.id.clone(),
+                name: TOPIC_NAMES.display_name(device_id),
+                topic: TOPIC_NAMES.topic_name(device_id),
+                location: TOPIC_NAMES.location(device_id),
+                address: device_id.address.clone(),
+                last_seen: 0,
+                is_stale: true,
+                readings: Vec::new(),
+            });
+        }
+        let device = devices.last_mut().unwrap();
+        device.last_seen = device.last_seen.max(latest.timestamp);
+        device.is_stale &= latest.is_stale;
+        device.readings.push(ReadingSummary {
+            kind: measurement.kind().to_string(),
+            value: measurement.value(),
+            unit: measurement.unit().to_string(),
+            timestamp: latest.timestamp,
+            is_stale: latest.is_stale,
+        });
+    }
+    devices
+}
+
+// Telemetry is what the brokers' telemetry topics carry, in one response.
+#[derive(Serialize)]
+struct Telemetry {
+    counters: BTreeMap<String, BTreeMap<String, Counters>>,
+    brokers: BTreeMap<String, BrokerHealth>,
+    publishing: BTreeMap<String, PublishStats>,
+}
+
+async fn telemetry() -> Json<Telemetry> {
+    Json(Telemetry {
+        counters: METRICS.snapshot(),
+        brokers: HEALTH.snapshot(),
+        publishing: METRICS.publishing(),
+    })
+}
+
+// metrics exposes the counters for Prometheus to scrape.
+async fn metrics() -> String {
+    METRICS.prometheus()
+}
+
+// health reports the connection state of every broker, including whose circuits are open.
+async fn health() -> Json<BTreeMap<String, BrokerHealth>> {
+    Json(HEALTH.snapshot())
+}
+
+// healthz is health for probes: 503 unless every broker is connected, including while they are
+// still connecting at startup, with the same detail so an operator can see which one is broken.
+async fn healthz() -> (StatusCode, Json<BTreeMap<String, BrokerHealth>>) {
+    let status = if HEALTH.is_healthy() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(HEALTH.snapshot()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::api::{summarize, ReadingSummary};
+    use crate::latest::LatestReadings;
+    use crate::{DeviceId, DeviceReading, Measurement};
+
+    #[test]
+    fn test_summarize() {
+        let mut latest = LatestReadings::default();
+        for (address, measurement) in [
+            ("A4:C1:38:00:00:0C", Measurement::Temperature(21.5)),
+            ("A4:C1:38:00:00:0C", Measurement::Humidity(40.0)),
+            ("A4:C1:38:00:00:0D", Measurement::Battery(90.0)),
+        ] {
+            latest.update(Arc::new(DeviceReading {
+                device_id: DeviceId {
+                    id: format!("hci0/dev_{}", address.replace(':', "_")),
+                    device_name: format!("ATC_SUMMARY_{}", &address[15..]),
+                    address: address.to_string(),
+                    random_address: None,
+                },
+                measurement,
+                advertisement: None,
+                quality: Vec::new(),
+            }));
+        }
+        let devices = summarize(&latest.readings());
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[0].name, "ATC_SUMMARY_0C");
+        assert_eq!(devices[0].topic, "ATC_SUMMARY_0C");
+        assert_eq!(devices[0].address, "A4:C1:38:00:00:0C");
+        assert!(!devices[0].is_stale);
+        assert_eq!(
+            devices[0].readings[1],
+            ReadingSummary {
+                kind: "temperature".to_string(),
+                value: 21.5,
+                unit: "°C".to_string(),
+                timestamp: devices[0].readings[1].timestamp,
+                is_stale: false,
+            }
+        );
+        assert_eq!(devices[1].readings.len(), 1);
+    }
+}
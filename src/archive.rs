@@ -0,0 +1,534 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use jiff::tz::TimeZone;
+use jiff::Timestamp;
+use parquet::basic::{Compression, ZstdLevel};
+use parquet::data_type::{ByteArray, ByteArrayType, DoubleType, Int64Type};
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::schema::parser::parse_message_type;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time;
+
+use crate::config::{ArchiveConfig, ArchivePartition};
+use crate::metrics::METRICS;
+use crate::names::TOPIC_NAMES;
+use crate::supervisor::SUPERVISOR;
+use crate::DeviceReading;
+
+// The name routes refer to the archive by.
+pub const SINK_NAME: &str = "archive";
+
+// Readings kept while writes fail, say on a full disk, before new ones are dropped: hours of a
+// busy install's readings, in a few hundred MB at most.
+const MAX_ROWS: usize = 1_000_000;
+
+// SCHEMA has a row per reading rather than a column per measurement kind, so kinds that appear
+// later, say from a new device or a new derived expression, don't change it, and files written
+// months apart read as one table. DuckDB's PIVOT or Polars' pivot turn it into a column per kind.
+const SCHEMA: &str = "
+message reading {
+    required int64 timestamp (TIMESTAMP(MILLIS, true));
+    required binary device_id (STRING);
+    required binary device_name (STRING);
+    required binary address (STRING);
+    required binary kind (STRING);
+    required double value;
+    required binary unit (STRING);
+    optional int64 advertisement;
+}
+";
+
+struct Row {
+    timestamp: i64,
+    device_id: String,
+    device_name: String,
+    address: String,
+    kind: String,
+    value: f64,
+    unit: String,
+    advertisement: Option<i64>,
+}
+
+// Archive collects readings and writes them every interval to Parquet files in Hive-style
+// partitions, `<directory>/date=2024-06-01/device=ATC_8F2C1A/<unix time>.parquet`, with an
+// `hour=13` level between the two when partitioned hourly, so DuckDB (`read_parquet('<directory>/
+// **/*.parquet', hive_partitioning = true)`) and Polars can skip what a query doesn't need. Dates
+// and hours are in the configured time zone. Parquet files can't be appended to, so every write
+// makes new files; readings collected since the last one are written when blueplug stops. While
+// writes fail, at most max_rows readings are kept for the next one, and the rest are dropped.
+pub struct Archive {
+    directory: PathBuf,
+    partition: ArchivePartition,
+    time_zone: TimeZone,
+    // Rows not yet written, by partition directory, and how many there are.
+    rows: BTreeMap<PathBuf, Vec<Row>>,
+    buffered: usize,
+    max_rows: usize,
+    retention: Option<Retention>,
+}
+
+// Retention deletes archived files written more than max_age seconds ago, and the oldest ones
+// beyond max_size bytes, going by the Unix time in their names.
+#[derive(Clone)]
+pub struct Retention {
+    directory: PathBuf,
+    max_age: Option<u64>,
+    max_size: Option<u64>,
+}
+
+impl Archive {
+    pub fn new(config: &ArchiveConfig, time_zone: Option<&str>) -> Result<Self> {
+        let time_zone = match time_zone {
+            Some(name) => {
+                TimeZone::get(name).wrap_err_with(|| format!("archive time zone {}", name))?
+            }
+            None => TimeZone::system(),
+        };
+        Ok(Archive {
+            directory: config.directory.clone(),
+            partition: config.partition,
+            time_zone,
+            rows: BTreeMap::new(),
+            buffered: 0,
+            max_rows: MAX_ROWS,
+            retention: (config.max_age.is_some() || config.max_size.is_some()).then(|| Retention {
+                directory: config.directory.clone(),
+                max_age: config.max_age,
+                max_size: config.max_size,
+            }),
+        })
+    }
+
+    pub fn retention(&self) -> Option<Retention> {
+        self.retention.clone()
+    }
+
+    pub fn push(&mut self, reading: &DeviceReading, now: Timestamp) {
+        if self.buffered >= self.max_rows {
+            METRICS.archive_dropped();
+            return;
+        }
+        self.buffered += 1;
+        let zoned = now.to_zoned(self.time_zone.clone());
+        let mut partition = self.directory.join(format!("date={}", zoned.date()));
+        if self.partition == ArchivePartition::Hourly {
+            partition.push(format!("hour={:02}", zoned.hour()));
+        }
+        partition.push(device_partition(
+            &TOPIC_NAMES.topic_name(&reading.device_id),
+        ));
+        self.rows.entry(partition).or_default().push(Row {
+            timestamp: now.as_millisecond(),
+            device_id: reading.device_id.id.clone(),
+            device_name: reading.device_id.device_name.clone(),
+            address: reading.device_id.address.clone(),
+            kind: reading.measurement.kind().to_string(),
+            value: reading.measurement.value(),
+            unit: reading.measurement.unit().to_string(),
+            advertisement: reading.advertisement.map(|a| a as i64),
+        });
+    }
+
+    // write writes the rows collected so far, a file per partition named after now. Rows of a
+    // partition that couldn't be written are kept for the next time, and the other partitions are
+    // written regardless.
+    pub fn write(&mut self, now: Timestamp) -> Result<()> {
+        let name = format!("{}.parquet", now.as_second());
+        let mut errors = Vec::new();
+        for (partition, rows) in std::mem::take(&mut self.rows) {
+            match write_file(&partition, &name, &rows) {
+                Ok(()) => self.buffered -= rows.len(),
+                Err(e) => {
+                    errors.push(format!("{:#}", e));
+                    self.rows.insert(partition, rows);
+                }
+            }
+        }
+        if !errors.is_empty() {
+            return Err(eyre!("{}", errors.join("; ")));
+        }
+        Ok(())
+    }
+}
+
+// device_partition is the partition directory of a device's topic name. Names come from the
+// devices themselves, so `/`, `\`, NUL and `%` are percent-encoded, the way Hive escapes partition
+// values, and a name can't reach outside its partition. `.` and `..` need nothing, as the
+// directory name starts with `device=`.
+pub fn device_partition(name: &str) -> String {
+    let mut partition = String::from("device=");
+    for c in name.chars() {
+        match c {
+            '/' | '\\' | '\0' | '%' => partition.push_str(&format!("%{:02X}", c as u32)),
+            c => partition.push(c),
+        }
+    }
+    partition
+}
+
+impl Retention {
+    // prune deletes what is beyond retention at now, and the partitions that leaves empty.
+    pub fn prune(&self, now: Timestamp) -> Result<()> {
+        let mut files = Vec::new();
+        archived_files(&self.directory, &mut files)?;
+        files.sort();
+        let mut total: u64 = files.iter().map(|(_, _, size)| size).sum();
+        let expired_before = self.max_age.map(|max_age| now.as_second() - max_age as i64);
+        for (written, path, size) in files {
+            let expired = expired_before.is_some_and(|before| written < before);
+            let over = self.max_size.is_some_and(|max_size| total > max_size);
+            if !expired && !over {
+                break;
+            }
+            std::fs::remove_file(&path).wrap_err_with(|| format!("deleting {}", path.display()))?;
+            total -= size;
+            METRICS.store_pruned(SINK_NAME, size);
+        }
+        remove_empty(&self.directory);
+        Ok(())
+    }
+}
+
+// archived_files collects the Parquet files under directory with when they were written and their
+// size. Files still being written, or not written by the archive, are left alone.
+fn archived_files(directory: &Path, files: &mut Vec<(i64, PathBuf, u64)>) -> Result<()> {
+    let entries = match std::fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).wrap_err_with(|| format!("reading {}", directory.display())),
+    };
+    for entry in entries {
+        let entry = entry?;
+        let path = entry.path();
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            archived_files(&path, files)?;
+        } else if path
+            .extension()
+            .is_some_and(|extension| extension == "parquet")
+        {
+            let written = path
+                .file_stem()
+                .and_then(|stem| stem.to_str()?.parse().ok());
+            if let Some(written) = written {
+                files.push((written, path, metadata.len()));
+            }
+        }
+    }
+    Ok(())
+}
+
+// remove_empty removes the directories under directory left without files.
+fn remove_empty(directory: &Path) {
+    let Ok(entries) = std::fs::read_dir(directory) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            remove_empty(&path);
+            // Only succeeds once empty.
+            let _ = std::fs::remove_dir(&path);
+        }
+    }
+}
+
+// write_file writes rows to a file in directory, under a temporary name until complete so
+// readers never see part of one.
+fn write_file(directory: &Path, name: &str, rows: &[Row]) -> Result<()> {
+    std::fs::create_dir_all(directory)
+        .wrap_err_with(|| format!("creating {}", directory.display()))?;
+    let path = directory.join(name);
+    let tmp = path.with_extension("tmp");
+    let schema = Arc::new(parse_message_type(SCHEMA)?);
+    let properties = WriterProperties::builder()
+        .set_compression(Compression::ZSTD(ZstdLevel::default()))
+        .build();
+    let file = File::create(&tmp).wrap_err_with(|| format!("creating {}", tmp.display()))?;
+    let mut writer = SerializedFileWriter::new(file, schema, Arc::new(properties))?;
+
+    let advertisements: Vec<i64> = rows.iter().filter_map(|row| row.advertisement).collect();
+    let advertisement_levels: Vec<i16> = rows
+        .iter()
+        .map(|row| row.advertisement.is_some() as i16)
+        .collect();
+
+    let mut row_group = writer.next_row_group()?;
+    let mut index = 0;
+    while let Some(mut column) = row_group.next_column()? {
+        match index {
+            0 => {
+                let timestamps: Vec<i64> = rows.iter().map(|row| row.timestamp).collect();
+                column
+                    .typed::<Int64Type>()
+                    .write_batch(&timestamps, None, None)?;
+            }
+            1..=4 | 6 => {
+                let field: fn(&Row) -> &str = match index {
+                    1 => |row| &row.device_id,
+                    2 => |row| &row.device_name,
+                    3 => |row| &row.address,
+                    4 => |row| &row.kind,
+                    _ => |row| &row.unit,
+                };
+                let values: Vec<ByteArray> =
+                    rows.iter().map(|row| ByteArray::from(field(row))).collect();
+                column
+                    .typed::<ByteArrayType>()
+                    .write_batch(&values, None, None)?;
+            }
+            5 => {
+                let values: Vec<f64> = rows.iter().map(|row| row.value).collect();
+                column
+                    .typed::<DoubleType>()
+                    .write_batch(&values, None, None)?;
+            }
+            _ => {
+                column.typed::<Int64Type>().write_batch(
+                    &advertisements,
+                    Some(&advertisement_levels),
+                    None,
+                )?;
+            }
+        }
+        column.close()?;
+        index += 1;
+    }
+    row_group.close()?;
+    writer.close()?;
+    std::fs::rename(&tmp, &path).wrap_err_with(|| format!("writing {}", path.display()))?;
+    Ok(())
+}
+
+// spawn_archive writes the readings received to archive every interval, and once more on
+// shutdown. After each write, files beyond the retention are deleted on a blocking thread.
+pub fn spawn_archive(
+    mut archive: Archive,
+    interval: Duration,
+    mut readings: broadcast::Receiver<Arc<DeviceReading>>,
+) {
+    SUPERVISOR.spawn_graceful("archive", async move {
+        let mut interval = time::interval(interval);
+        interval.tick().await;
+        loop {
+            tokio::select! {
+                received = readings.recv() => {
+                    match received {
+                        Ok(reading) => archive.push(&reading, Timestamp::now()),
+                        Err(RecvError::Lagged(skipped)) => {
+                            println!("archive: falling behind, dropped {} readings", skipped);
+                        }
+                        Err(RecvError::Closed) => return,
+                    }
+                }
+                _ = interval.tick() => {
+                    if let Err(e) = archive.write(Timestamp::now()) {
+                        println!("archive: writing failed: {:?}", e);
+                    }
+                    if let Some(retention) = archive.retention() {
+                        let pruned =
+                            tokio::task::spawn_blocking(move || retention.prune(Timestamp::now()));
+                        match pruned.await {
+                            Ok(Ok(())) => {}
+                            Ok(Err(e)) => println!("archive: pruning failed: {:?}", e),
+                            Err(e) => println!("archive: pruning failed: {:?}", e),
+                        }
+                    }
+                }
+                _ = SUPERVISOR.cancelled() => {
+                    if let Err(e) = archive.write(Timestamp::now()) {
+                        println!("archive: writing failed: {:?}", e);
+                    }
+                    return;
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+
+    use jiff::Timestamp;
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+
+    use crate::archive::{device_partition, Archive, Retention};
+    use crate::config::{ArchiveConfig, ArchivePartition};
+    use crate::{DeviceId, DeviceReading, Measurement};
+
+    #[test]
+    fn test_archive() {
+        let directory =
+            std::env::temp_dir().join(format!("blueplug-archive-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        let mut archive = Archive::new(
+            &ArchiveConfig {
+                directory: directory.clone(),
+                partition: ArchivePartition::Hourly,
+                interval: 3600,
+                max_age: None,
+                max_size: None,
+            },
+            Some("Europe/Berlin"),
+        )
+        .unwrap();
+        let reading = |measurement, advertisement| DeviceReading {
+            device_id: DeviceId {
+                id: "hci0/dev_A4_C1_38_00_00_0A".to_string(),
+                device_name: "ATC_ARCHIVE".to_string(),
+                address: "A4:C1:38:00:00:0A".to_string(),
+                random_address: None,
+            },
+            measurement,
+            advertisement,
+            quality: Vec::new(),
+        };
+        let now: Timestamp = "2024-06-01T20:30:00Z".parse().unwrap();
+        archive.push(&reading(Measurement::Temperature(21.5), Some(1)), now);
+        archive.push(&reading(Measurement::Humidity(40.0), None), now);
+        archive.write(now).unwrap();
+        assert_eq!(archive.buffered, 0);
+
+        let path = directory.join("date=2024-06-01/hour=22/device=ATC_ARCHIVE/1717273800.parquet");
+        let reader = SerializedFileReader::new(File::open(&path).unwrap()).unwrap();
+        let rows: Vec<String> = reader
+            .get_row_iter(None)
+            .unwrap()
+            .map(|row| row.unwrap().to_string())
+            .collect();
+        assert_eq!(rows.len(), 2);
+        assert!(
+            rows[0].contains(r#"kind: "temperature", value: 21.5, unit: "°C", advertisement: 1}"#)
+        );
+        assert!(
+            rows[1].contains(r#"kind: "humidity", value: 40.0, unit: "%", advertisement: null}"#)
+        );
+
+        // While writes fail, readings beyond max_rows are dropped.
+        archive.max_rows = 1;
+        archive.push(&reading(Measurement::Temperature(21.6), None), now);
+        archive.push(&reading(Measurement::Temperature(21.7), None), now);
+        assert_eq!(archive.buffered, 1);
+        archive.write(now).unwrap();
+        assert_eq!(archive.buffered, 0);
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_archive_partitions() {
+        let directory =
+            std::env::temp_dir().join(format!("blueplug-partitions-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        let mut archive = Archive::new(
+            &ArchiveConfig {
+                directory: directory.clone(),
+                partition: ArchivePartition::Daily,
+                interval: 3600,
+                max_age: None,
+                max_size: None,
+            },
+            Some("UTC"),
+        )
+        .unwrap();
+        let reading = |name: &str, address: &str| DeviceReading {
+            device_id: DeviceId {
+                id: format!("hci0/dev_{}", address.replace(':', "_")),
+                device_name: name.to_string(),
+                address: address.to_string(),
+                random_address: None,
+            },
+            measurement: Measurement::Temperature(21.5),
+            advertisement: None,
+            quality: Vec::new(),
+        };
+        let now: Timestamp = "2024-06-01T20:30:00Z".parse().unwrap();
+        let date = directory.join("date=2024-06-01");
+
+        // A name can't reach outside the archive.
+        archive.push(&reading("x/../../../../tmp/y", "A4:C1:38:00:00:10"), now);
+        // A partition that can't be written doesn't hold up the others.
+        std::fs::create_dir_all(&date).unwrap();
+        std::fs::write(date.join("device=ATC_BLOCKED"), "").unwrap();
+        archive.push(&reading("ATC_BLOCKED", "A4:C1:38:00:00:13"), now);
+        assert!(archive.write(now).is_err());
+        assert_eq!(archive.buffered, 1);
+
+        let mut written: Vec<_> = std::fs::read_dir(&date)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        written.sort();
+        assert_eq!(
+            written,
+            [
+                "device=ATC_BLOCKED",
+                "device=x%2F..%2F..%2F..%2F..%2Ftmp%2Fy"
+            ]
+        );
+        assert!(date
+            .join("device=x%2F..%2F..%2F..%2F..%2Ftmp%2Fy/1717273800.parquet")
+            .exists());
+        assert_eq!(device_partition("a\\b\0c%d"), "device=a%5Cb%00c%25d");
+
+        // It's written once it can be.
+        std::fs::remove_file(date.join("device=ATC_BLOCKED")).unwrap();
+        archive.write(now).unwrap();
+        assert_eq!(archive.buffered, 0);
+        assert!(date.join("device=ATC_BLOCKED/1717273800.parquet").exists());
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_archive_retention() {
+        let directory =
+            std::env::temp_dir().join(format!("blueplug-retention-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        let retention = Retention {
+            directory: directory.clone(),
+            max_age: Some(7 * 86400),
+            max_size: Some(25),
+        };
+        let partition = |date: &str| directory.join(format!("date={}/device=ATC", date));
+        for (date, written) in [
+            ("2024-05-01", 1714521600),
+            ("2024-06-01", 1717200000),
+            ("2024-06-02", 1717250000),
+            ("2024-06-02", 1717270000),
+        ] {
+            std::fs::create_dir_all(partition(date)).unwrap();
+            std::fs::write(
+                partition(date).join(format!("{}.parquet", written)),
+                [0; 10],
+            )
+            .unwrap();
+        }
+        std::fs::write(partition("2024-06-02").join("1717280000.tmp"), [0; 10]).unwrap();
+
+        let now: Timestamp = "2024-06-02T12:00:00Z".parse().unwrap();
+        retention.prune(now).unwrap();
+        // May is too old, the first of June over the size, and its partition removed with it.
+        assert!(!directory.join("date=2024-05-01").exists());
+        assert!(!directory.join("date=2024-06-01").exists());
+        let mut left: Vec<_> = std::fs::read_dir(partition("2024-06-02"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        left.sort();
+        assert_eq!(
+            left,
+            ["1717250000.parquet", "1717270000.parquet", "1717280000.tmp"]
+        );
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
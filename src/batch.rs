@@ -0,0 +1,155 @@
+use std::mem;
+
+use serde_json::Value;
+
+use crate::encoding::PayloadFormat;
+use crate::mqtt::{Message, PUBLISH_OVERHEAD};
+use crate::names::TOPIC_NAMES;
+use crate::DeviceId;
+
+// Batcher coalesces the messages of each device between flushes into one publish on
+// `<topic_prefix>/batch/<device>`, whose payload is the array of the messages' payloads in the
+// order they arrived. On links where every packet costs, such as LTE backhaul, that saves the
+// per-publish overhead of every reading. A device's batch that wouldn't fit in one MQTT packet is
+// split over several publishes.
+#[derive(Default)]
+pub struct Batcher {
+    // Payloads by device, in the order devices were first heard from since the last flush.
+    pending: Vec<(DeviceId, Vec<Value>)>,
+}
+
+impl Batcher {
+    pub fn push(&mut self, message: Message) {
+        let position = self
+            .pending
+            .iter()
+            .position(|(device_id, _)| device_id.id == message.device_id.id);
+        match position {
+            Some(i) => self.pending[i].1.push(message.payload),
+            None => self
+                .pending
+                .push((message.device_id, vec![message.payload])),
+        }
+    }
+
+    // flush returns each device's batch, in publishes of at most max_packet_size bytes once their
+    // payload is encoded in format.
+    pub fn flush(
+        &mut self,
+        topic_prefix: &str,
+        max_packet_size: usize,
+        format: PayloadFormat,
+    ) -> Vec<Message> {
+        let mut messages = Vec::new();
+        for (device_id, payloads) in self.pending.drain(..) {
+            let topic = format!(
+                "{}/batch/{}",
+                TOPIC_NAMES.expand_location(topic_prefix, &device_id),
+                TOPIC_NAMES.topic_name(&device_id)
+            );
+            let size = max_packet_size.saturating_sub(PUBLISH_OVERHEAD + topic.len());
+            for payloads in split(payloads, size, format) {
+                messages.push(Message {
+                    device_id: device_id.clone(),
+                    topic: topic.clone(),
+                    payload: Value::Array(payloads),
+                });
+            }
+        }
+        messages
+    }
+}
+
+// Bytes an array adds to its elements at most, besides their element_overhead: JSON's brackets,
+// the length in CBOR and MessagePack, or protobuf's tag and length of a list.
+const ARRAY_OVERHEAD: usize = 9;
+
+// split divides payloads into arrays that encode to at most size bytes, keeping their order. A
+// payload too large on its own is still returned alone.
+fn split(payloads: Vec<Value>, size: usize, format: PayloadFormat) -> Vec<Vec<Value>> {
+    let mut arrays = Vec::new();
+    let mut array = Vec::new();
+    let mut used = ARRAY_OVERHEAD;
+    for payload in payloads {
+        let bytes =
+            format.encode(&payload).map_or(0, |bytes| bytes.len()) + format.element_overhead();
+        if !array.is_empty() && used + bytes > size {
+            arrays.push(mem::take(&mut array));
+            used = ARRAY_OVERHEAD;
+        }
+        used += bytes;
+        array.push(payload);
+    }
+    if !array.is_empty() {
+        arrays.push(array);
+    }
+    arrays
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::batch::Batcher;
+    use crate::encoding::PayloadFormat;
+    use crate::mqtt::Message;
+    use crate::DeviceId;
+
+    fn message(device_name: &str, value: f64) -> Message {
+        Message {
+            device_id: DeviceId {
+                id: format!("hci0/{}", device_name),
+                device_name: device_name.to_string(),
+                address: String::new(),
+                random_address: None,
+            },
+            topic: format!("blueplug/temperature/{}", device_name),
+            payload: json!({ "value": value }),
+        }
+    }
+
+    #[test]
+    fn test_batcher() {
+        let mut batcher = Batcher::default();
+        batcher.push(message("kitchen", 21.5));
+        batcher.push(message("porch", 9.0));
+        batcher.push(message("kitchen", 21.6));
+
+        let messages = batcher.flush("blueplug", 10 * 1024, PayloadFormat::Json);
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].topic, "blueplug/batch/kitchen");
+        assert_eq!(
+            messages[0].payload,
+            json!([{ "value": 21.5 }, { "value": 21.6 }])
+        );
+        assert_eq!(messages[1].topic, "blueplug/batch/porch");
+        assert!(batcher
+            .flush("blueplug", 10 * 1024, PayloadFormat::Json)
+            .is_empty());
+
+        // A batch too large for one packet is split, in order, over packets that fit.
+        for i in 0..1000 {
+            batcher.push(message("attic", i as f64));
+        }
+        for format in [
+            PayloadFormat::Json,
+            PayloadFormat::Cbor,
+            PayloadFormat::Msgpack,
+            PayloadFormat::Protobuf,
+        ] {
+            let mut batcher = Batcher {
+                pending: batcher.pending.clone(),
+            };
+            let messages = batcher.flush("blueplug", 1024, format);
+            assert!(messages.len() > 1);
+            let mut values = Vec::new();
+            for message in messages {
+                let bytes = format.encode(&message.payload).unwrap();
+                assert!(message.topic.len() + bytes.len() + 9 <= 1024);
+                values.extend(message.payload.as_array().unwrap().clone());
+            }
+            let expected: Vec<_> = (0..1000).map(|i| json!({ "value": i as f64 })).collect();
+            assert_eq!(values, expected);
+        }
+    }
+}
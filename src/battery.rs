@@ -0,0 +1,333 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use async_stream::stream;
+use color_eyre::eyre::{eyre, Result};
+use futures_core::stream::Stream;
+
+use crate::config::{BatteryConfig, BatteryDrainConfig, Chemistry};
+use crate::fanout::devices_match;
+use crate::{DeviceReading, Measurement, Quality};
+
+// Discharge curves as (volts, percent), from the typical discharge at the low currents sensors
+// draw. Both chemistries hold their voltage for most of their life and then fall off quickly.
+const COIN_CELL: &[[f64; 2]] = &[
+    [2.0, 0.0],
+    [2.5, 5.0],
+    [2.7, 20.0],
+    [2.8, 40.0],
+    [2.9, 75.0],
+    [3.0, 100.0],
+];
+const ALKALINE_2X: &[[f64; 2]] = &[
+    [2.0, 0.0],
+    [2.3, 10.0],
+    [2.5, 30.0],
+    [2.7, 60.0],
+    [2.9, 85.0],
+    [3.1, 100.0],
+];
+
+impl Chemistry {
+    fn curve(self) -> &'static [[f64; 2]] {
+        match self {
+            Chemistry::Coin => COIN_CELL,
+            Chemistry::Alkaline => ALKALINE_2X,
+        }
+    }
+}
+
+struct Curve {
+    devices: Vec<String>,
+    points: Vec<[f64; 2]>,
+}
+
+// BatteryCurves estimates battery percentage from the voltage of devices that only report volts,
+// such as Ruuvi tags, so every device has a battery kind to chart and alert on.
+#[derive(Default)]
+pub struct BatteryCurves {
+    curves: Vec<Curve>,
+}
+
+impl BatteryCurves {
+    pub fn new(battery: &[BatteryConfig]) -> Result<Self> {
+        let curves = battery
+            .iter()
+            .map(|config| {
+                let points = match (&config.chemistry, &config.curve) {
+                    (Some(chemistry), None) => chemistry.curve().to_vec(),
+                    (None, Some(curve)) => curve.clone(),
+                    _ => {
+                        return Err(eyre!(
+                            "battery of {:?}: exactly one of chemistry and curve must be given",
+                            config.devices
+                        ))
+                    }
+                };
+                let increasing = points.windows(2).all(|w| w[0][0] < w[1][0]);
+                let percentages = points.iter().all(|p| (0.0..=100.0).contains(&p[1]));
+                if points.len() < 2 || !increasing || !percentages {
+                    return Err(eyre!(
+                        "battery of {:?}: curve needs at least two [volts, percent] points with increasing volts and percentages from 0 to 100",
+                        config.devices
+                    ));
+                }
+                Ok(Curve {
+                    devices: config.devices.clone(),
+                    points,
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(BatteryCurves { curves })
+    }
+
+    // estimate returns the battery percentage for a voltage reading, interpolating the first curve
+    // matching its device.
+    pub fn estimate(&self, reading: &DeviceReading) -> Option<Measurement> {
+        let Measurement::Voltage(volts) = reading.measurement else {
+            return None;
+        };
+        let curve = self
+            .curves
+            .iter()
+            .find(|curve| devices_match(&curve.devices, &reading.device_id))?;
+        Some(Measurement::Battery(interpolate(&curve.points, volts)))
+    }
+}
+
+fn interpolate(points: &[[f64; 2]], volts: f64) -> f64 {
+    let [low_volts, low_percent] = points[0];
+    if volts <= low_volts {
+        return low_percent;
+    }
+    for w in points.windows(2) {
+        let ([v0, p0], [v1, p1]) = (w[0], w[1]);
+        if volts <= v1 {
+            let percent = p0 + (volts - v0) / (v1 - v0) * (p1 - p0);
+            return (percent * 10.0).round() / 10.0;
+        }
+    }
+    points[points.len() - 1][1]
+}
+
+// The kind of the estimated days until a battery runs out.
+pub const DAYS_REMAINING: &str = "battery_days";
+
+// How often a device's battery level is sampled for its drain rate, at most. Levels change slowly,
+// and every sample is kept for the whole window.
+const DRAIN_SAMPLE_INTERVAL: Duration = Duration::from_secs(3600);
+
+const SECONDS_PER_DAY: f64 = 86400.0;
+
+// BatteryDrain estimates how many days the batteries of devices have left, fitting a line to their
+// battery levels over the last window days and extending it to zero. Levels that hold steady or
+// rise, as a battery is replaced or charged, have no estimate.
+#[derive(Default)]
+pub struct BatteryDrain {
+    config: Option<BatteryDrainConfig>,
+    // Battery levels by device id, oldest first.
+    samples: HashMap<String, VecDeque<(Instant, f64)>>,
+}
+
+impl BatteryDrain {
+    pub fn new(config: Option<&BatteryDrainConfig>) -> Self {
+        BatteryDrain {
+            config: config.cloned(),
+            samples: HashMap::new(),
+        }
+    }
+
+    // sample takes a battery level reading into account, returning the device's days remaining if
+    // the level was sampled and the samples span at least min_days.
+    pub fn sample(&mut self, reading: &DeviceReading, now: Instant) -> Option<Measurement> {
+        let config = self.config.as_ref()?;
+        let Measurement::Battery(percent) = reading.measurement else {
+            return None;
+        };
+        if !devices_match(&config.devices, &reading.device_id) {
+            return None;
+        }
+        let samples = self
+            .samples
+            .entry(reading.device_id.id.clone())
+            .or_default();
+        if samples
+            .back()
+            .is_some_and(|(at, _)| now.duration_since(*at) < DRAIN_SAMPLE_INTERVAL)
+        {
+            return None;
+        }
+        samples.push_back((now, percent));
+        let window = Duration::from_secs_f64(config.window * SECONDS_PER_DAY);
+        while samples
+            .front()
+            .is_some_and(|(at, _)| now.duration_since(*at) > window)
+        {
+            samples.pop_front();
+        }
+        let (first, _) = *samples.front()?;
+        if now.duration_since(first).as_secs_f64() < config.min_days * SECONDS_PER_DAY {
+            return None;
+        }
+        let points: Vec<(f64, f64)> = samples
+            .iter()
+            .map(|(at, percent)| {
+                (
+                    at.duration_since(first).as_secs_f64() / SECONDS_PER_DAY,
+                    *percent,
+                )
+            })
+            .collect();
+        let per_day = slope(&points)?;
+        if per_day >= 0.0 {
+            return None;
+        }
+        let days = (percent / -per_day).max(0.0);
+        Some(Measurement::Other {
+            kind: DAYS_REMAINING.to_string(),
+            value: (days * 10.0).round() / 10.0,
+            unit: "d".to_string(),
+        })
+    }
+}
+
+// slope is the slope of the least squares line through points.
+fn slope(points: &[(f64, f64)]) -> Option<f64> {
+    let n = points.len() as f64;
+    let mean_x = points.iter().map(|(x, _)| x).sum::<f64>() / n;
+    let mean_y = points.iter().map(|(_, y)| y).sum::<f64>() / n;
+    let covariance: f64 = points
+        .iter()
+        .map(|(x, y)| (x - mean_x) * (y - mean_y))
+        .sum();
+    let variance: f64 = points.iter().map(|(x, _)| (x - mean_x).powi(2)).sum();
+    (variance > 0.0).then(|| covariance / variance)
+}
+
+// battery_stream passes readings through, following voltages with the estimated battery level and
+// battery levels with the estimated days remaining.
+pub fn battery_stream(
+    readings: impl Stream<Item = DeviceReading>,
+    curves: BatteryCurves,
+    mut drain: BatteryDrain,
+) -> impl Stream<Item = DeviceReading> {
+    stream! {
+        for await reading in readings {
+            let mut derived: Vec<Measurement> = curves.estimate(&reading).into_iter().collect();
+            let level = derived.first().cloned().unwrap_or_else(|| reading.measurement.clone());
+            let level = DeviceReading {
+                device_id: reading.device_id.clone(),
+                measurement: level,
+                advertisement: reading.advertisement,
+                quality: Vec::new(),
+            };
+            derived.extend(drain.sample(&level, Instant::now()));
+            let device_id = reading.device_id.clone();
+            let advertisement = reading.advertisement;
+            yield reading;
+            for measurement in derived {
+                let device_id = device_id.clone();
+                let quality = if measurement.kind() == DAYS_REMAINING {
+                    vec![Quality::Extrapolated]
+                } else {
+                    Vec::new()
+                };
+                yield DeviceReading { device_id, measurement, advertisement, quality };
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::battery::{BatteryCurves, BatteryDrain};
+    use crate::config::{BatteryConfig, BatteryDrainConfig, Chemistry};
+    use crate::{DeviceId, DeviceReading, Measurement};
+
+    fn reading(device_name: &str, measurement: Measurement) -> DeviceReading {
+        DeviceReading {
+            device_id: DeviceId {
+                id: format!("hci0/{}", device_name),
+                device_name: device_name.to_string(),
+                address: String::new(),
+                random_address: None,
+            },
+            measurement,
+            advertisement: None,
+            quality: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_battery_curves() {
+        let curves = BatteryCurves::new(&[
+            BatteryConfig {
+                devices: vec!["Ruuvi *".to_string()],
+                chemistry: Some(Chemistry::Coin),
+                curve: None,
+            },
+            BatteryConfig {
+                devices: vec!["Lock".to_string()],
+                chemistry: None,
+                curve: Some(vec![[4.4, 0.0], [6.0, 100.0]]),
+            },
+        ])
+        .unwrap();
+
+        let estimate = |name, volts| curves.estimate(&reading(name, Measurement::Voltage(volts)));
+        assert_eq!(
+            estimate("Ruuvi 1A2B", 2.85),
+            Some(Measurement::Battery(57.5))
+        );
+        assert_eq!(
+            estimate("Ruuvi 1A2B", 3.2),
+            Some(Measurement::Battery(100.0))
+        );
+        assert_eq!(estimate("Ruuvi 1A2B", 1.8), Some(Measurement::Battery(0.0)));
+        assert_eq!(estimate("Lock", 5.2), Some(Measurement::Battery(50.0)));
+        assert_eq!(estimate("ATC_1", 2.9), None);
+        assert_eq!(
+            curves.estimate(&reading("Lock", Measurement::Temperature(5.2))),
+            None
+        );
+
+        assert!(BatteryCurves::new(&[BatteryConfig {
+            devices: Vec::new(),
+            chemistry: None,
+            curve: Some(vec![[3.0, 100.0], [2.0, 0.0]]),
+        }])
+        .is_err());
+    }
+
+    #[test]
+    fn test_battery_drain() {
+        let mut drain = BatteryDrain::new(Some(&BatteryDrainConfig {
+            devices: vec!["ATC_DRAIN*".to_string()],
+            window: 10.0,
+            min_days: 2.0,
+        }));
+        let start = Instant::now();
+        let at = |hours: u64| start + Duration::from_secs(hours * 3600);
+        let mut sample = |name, percent, hours| {
+            drain
+                .sample(&reading(name, Measurement::Battery(percent)), at(hours))
+                .map(|m| m.value())
+        };
+
+        // 1 % a day, so far too soon to tell.
+        assert_eq!(sample("ATC_DRAIN_1", 90.0, 0), None);
+        assert_eq!(sample("ATC_DRAIN_1", 89.5, 12), None);
+        // Sampled at most hourly.
+        assert_eq!(sample("ATC_DRAIN_1", 89.0, 24), None);
+        assert_eq!(sample("ATC_DRAIN_1", 80.0, 24), None);
+        assert_eq!(sample("ATC_DRAIN_1", 88.0, 48), Some(88.0));
+        // Old samples leave the window, so a new battery is estimated on its own.
+        assert_eq!(sample("ATC_DRAIN_1", 100.0, 300), None);
+        assert_eq!(sample("ATC_DRAIN_1", 99.0, 348), Some(198.0));
+
+        assert_eq!(sample("ATC_OTHER", 90.0, 0), None);
+        assert_eq!(sample("ATC_OTHER", 50.0, 100), None);
+    }
+}
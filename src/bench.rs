@@ -0,0 +1,67 @@
+use std::collections::HashMap;
+
+use color_eyre::eyre::Result;
+use uuid::Uuid;
+
+use crate::fitness::CyclingPower;
+use crate::scale::MiScales;
+use crate::{airthings, bm2, fitness, renogy, scale};
+use crate::{measurements_from_manufacturer_data, measurements_from_service_data};
+use crate::{DeviceId, Measurement};
+
+// The advertisement decoders that are otherwise private, for benches/decoders.rs. Only built with
+// the bench feature.
+
+pub fn manufacturer_data(manufacturer_data: &HashMap<u16, Vec<u8>>) -> Vec<Measurement> {
+    measurements_from_manufacturer_data(manufacturer_data)
+}
+
+pub fn service_data(service_data: &HashMap<Uuid, Vec<u8>>) -> Vec<Measurement> {
+    measurements_from_service_data(service_data)
+}
+
+// mi_scale decodes a Mi Scale advertisement as the first of its weighing, as MiScales only
+// decodes each weighing once.
+pub fn mi_scale(service_data: &HashMap<Uuid, Vec<u8>>) -> Vec<Measurement> {
+    let device_id = DeviceId {
+        id: "bench/dev_C8_47_8C_10_22_33".to_string(),
+        device_name: "MIBFS".to_string(),
+        address: "C8:47:8C:10:22:33".to_string(),
+        random_address: None,
+    };
+    MiScales::default().measurements(&device_id, service_data)
+}
+
+pub fn airthings_wave_plus(value: &[u8]) -> Result<Vec<Measurement>> {
+    airthings::wave_plus(value)
+}
+
+pub fn bm2(value: &[u8]) -> Result<Vec<Measurement>> {
+    bm2::measurements(value)
+}
+
+pub fn renogy(frame: &[u8]) -> Result<Vec<Measurement>> {
+    renogy::measurements(frame)
+}
+
+pub fn weight(value: &[u8]) -> Result<Vec<Measurement>> {
+    scale::weight_measurement(value)
+}
+
+pub fn body_composition(value: &[u8]) -> Result<Vec<Measurement>> {
+    scale::body_composition(value)
+}
+
+pub fn heart_rate(value: &[u8]) -> Result<Vec<Measurement>> {
+    fitness::heart_rate(value)
+}
+
+// cycling_power decodes a sequence of Cycling Power notifications, as one connection would
+// receive them, since power is computed from the difference between consecutive ones.
+pub fn cycling_power(values: &[Vec<u8>]) -> Vec<Measurement> {
+    let mut decoder = CyclingPower::default();
+    values
+        .iter()
+        .flat_map(|value| decoder.measurements(value).unwrap_or_default())
+        .collect()
+}
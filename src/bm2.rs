@@ -0,0 +1,71 @@
+use aes::cipher::{BlockDecrypt, KeyInit};
+use aes::Aes128;
+use color_eyre::eyre::{eyre, Result};
+use uuid::Uuid;
+
+use crate::Measurement;
+
+// The BM2 car battery monitor only notifies its readings to a connected client, so unlike most
+// sensors it is read over GATT rather than from its advertisements.
+pub const NOTIFY_CHARACTERISTIC: Uuid = Uuid::from_u128(0x0000fff4_0000_1000_8000_00805f9b34fb);
+
+// Notifications are AES-128-CBC encrypted with a zero IV and this key, shared by every BM2.
+const KEY: [u8; 16] = *b"leagend\xff\xfe0100009";
+
+const VOLTAGE_MESSAGE: u8 = 0xf5;
+
+// measurements decodes a notification. Only voltage messages carry readings: 12 bits of
+// centivolts, 4 bits of status, then the state of charge in percent.
+pub fn measurements(value: &[u8]) -> Result<Vec<Measurement>> {
+    let message = decrypt(value)?;
+    if message[0] != VOLTAGE_MESSAGE {
+        return Ok(Vec::new());
+    }
+    let centivolts = u16::from_be_bytes([message[1], message[2]]) >> 4;
+    Ok(vec![
+        Measurement::Voltage(centivolts as f64 / 100.0),
+        Measurement::Battery(message[3] as f64),
+    ])
+}
+
+fn decrypt(value: &[u8]) -> Result<Vec<u8>> {
+    if value.is_empty() || !value.len().is_multiple_of(16) {
+        return Err(eyre!(
+            "expected whole AES blocks, got {} bytes",
+            value.len()
+        ));
+    }
+    let cipher = Aes128::new(&KEY.into());
+    let mut previous = [0u8; 16];
+    let mut message = Vec::with_capacity(value.len());
+    for chunk in value.chunks(16) {
+        let mut block = *aes::Block::from_slice(chunk);
+        cipher.decrypt_block(&mut block);
+        message.extend(block.iter().zip(previous).map(|(b, p)| b ^ p));
+        previous.copy_from_slice(chunk);
+    }
+    Ok(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use aes::cipher::{BlockEncrypt, KeyInit};
+    use aes::Aes128;
+
+    use crate::bm2::{measurements, KEY};
+    use crate::Measurement;
+
+    #[test]
+    fn test_measurements() {
+        // 12.62 V (1262 = 0x4ee), status 1, 83 %.
+        let mut block = aes::Block::clone_from_slice(&[
+            0xf5, 0x4e, 0xe1, 0x53, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        ]);
+        Aes128::new(&KEY.into()).encrypt_block(&mut block);
+        assert_eq!(
+            measurements(&block).unwrap(),
+            vec![Measurement::Voltage(12.62), Measurement::Battery(83.0)]
+        );
+        assert!(measurements(&block[..8]).is_err());
+    }
+}
@@ -0,0 +1,73 @@
+use aes::Aes128;
+use btsensor::bthome::v2::BtHomeV2;
+use btsensor::bthome::DecodeError;
+use ccm::aead::generic_array::GenericArray;
+use ccm::aead::{Aead, Payload};
+use ccm::consts::{U13, U4};
+use ccm::{Ccm, KeyInit};
+
+use crate::Measurement;
+
+// Objects BTHome added after the btsensor crate was released, which it refuses whole payloads
+// over. Weather stations send both, each a u16.
+const WIND_DIRECTION: u8 = 0x5e;
+const PRECIPITATION: u8 = 0x5f;
+const ENCRYPTED: u8 = 0x01;
+
+// decrypt returns the plain payload of an encrypted BTHome v2 payload, with the encryption flag
+// cleared for the decoder. It is the device info byte, the objects encrypted with AES-CCM, a
+// counter and a MIC of 4 bytes each, with a nonce of the MAC address, the service data UUID, the
+// device info byte and the counter. It is None if the payload isn't encrypted or doesn't
+// authenticate with key.
+pub fn decrypt(data: &[u8], mac: &[u8; 6], key: &[u8]) -> Option<Vec<u8>> {
+    let info = *data.first()?;
+    if info & ENCRYPTED == 0 || data.len() < 9 {
+        return None;
+    }
+    let (objects, trailer) = data[1..].split_at(data.len() - 9);
+    let (counter, mic) = trailer.split_at(4);
+    let nonce = [mac.as_slice(), &[0xd2, 0xfc, info], counter].concat();
+    let mut plain = Ccm::<Aes128, U4, U13>::new_from_slice(key)
+        .ok()?
+        .decrypt(
+            GenericArray::from_slice(&nonce),
+            Payload {
+                msg: &[objects, mic].concat(),
+                aad: &[],
+            },
+        )
+        .ok()?;
+    plain.insert(0, info & !ENCRYPTED);
+    Some(plain)
+}
+
+// split_newer_objects takes the objects btsensor doesn't know off the end of an unencrypted BTHome
+// v2 payload, where BTHome's ordering by object id puts them, returning the rest of the payload
+// and their measurements. It is None if btsensor decodes the payload as it is, or it can't be
+// split that way.
+pub fn split_newer_objects(data: &[u8]) -> Option<(Vec<u8>, Vec<Measurement>)> {
+    if data.first()? & ENCRYPTED != 0 {
+        return None;
+    }
+    match BtHomeV2::decode(data) {
+        Err(DecodeError::InvalidProperty(WIND_DIRECTION | PRECIPITATION)) => {}
+        _ => return None,
+    }
+    let mut known = data;
+    let mut measurements = Vec::new();
+    while known.len() >= 4 {
+        let (rest, object) = known.split_at(known.len() - 3);
+        let value = u16::from_le_bytes([object[1], object[2]]) as f64;
+        let measurement = match object[0] {
+            WIND_DIRECTION => Measurement::WindDirection(value / 100.0),
+            PRECIPITATION => Measurement::Rainfall(value / 10.0),
+            _ => return None,
+        };
+        measurements.insert(0, measurement);
+        known = rest;
+        if BtHomeV2::decode(known).is_ok() {
+            return Some((known.to_vec(), measurements));
+        }
+    }
+    None
+}
@@ -0,0 +1,196 @@
+use std::collections::HashSet;
+
+use async_stream::stream;
+use btleplug::api::bleuuid::uuid_from_u16;
+use color_eyre::eyre::{eyre, Result};
+use futures_core::stream::Stream;
+use uuid::Uuid;
+
+use crate::config::ChatterConfig;
+use crate::error::BlueplugError;
+use crate::metrics::{Stage, METRICS};
+use crate::DeviceEvent;
+
+// Recognizer names the company ids and service UUIDs of one vendor's non-sensor advertisements.
+struct Recognizer {
+    name: &'static str,
+    manufacturer_ids: &'static [u16],
+    service_uuids: &'static [u16],
+}
+
+// RECOGNIZERS are the built-in recognizers, for devices that advertise constantly on a dense
+// smart-home network but carry no readings blueplug decodes.
+const RECOGNIZERS: &[Recognizer] = &[
+    // Philips Hue bulbs, plugs and bridges (Signify).
+    Recognizer {
+        name: "hue",
+        manufacturer_ids: &[],
+        service_uuids: &[0xfe0f],
+    },
+    // iPhones, AirPods, AirTags and Macs: Continuity, Find My and iBeacon frames.
+    Recognizer {
+        name: "apple",
+        manufacturer_ids: &[0x004c],
+        service_uuids: &[],
+    },
+    // Windows Swift Pair and device discovery beacons.
+    Recognizer {
+        name: "microsoft",
+        manufacturer_ids: &[0x0006],
+        service_uuids: &[],
+    },
+    // Galaxy phones, watches and SmartThings devices.
+    Recognizer {
+        name: "samsung",
+        manufacturer_ids: &[0x0075],
+        service_uuids: &[],
+    },
+    // Fast Pair headphones, Nest and Chromecast devices.
+    Recognizer {
+        name: "google",
+        manufacturer_ids: &[0x00e0],
+        service_uuids: &[0xfe2c, 0xfe9f, 0xfef3],
+    },
+    // Echo speakers and Fire TV.
+    Recognizer {
+        name: "amazon",
+        manufacturer_ids: &[0x0171],
+        service_uuids: &[0xfe03],
+    },
+];
+
+// recognizer_names returns the names of the built-in recognizers, all of which are enabled by
+// default.
+pub fn recognizer_names() -> Vec<String> {
+    RECOGNIZERS.iter().map(|r| r.name.to_string()).collect()
+}
+
+// Chatter recognizes advertisements that can't carry a reading, so they are dropped before
+// deduplication and decoding. An advertisement is chatter if every company id in its
+// manufacturer data, or every UUID in its service data, is one of the enabled recognizers' or
+// the configured ones, so a device advertising something else as well is still decoded.
+pub struct Chatter {
+    manufacturer_ids: HashSet<u16>,
+    service_uuids: HashSet<Uuid>,
+}
+
+impl Chatter {
+    pub fn new(config: &ChatterConfig) -> Result<Self> {
+        let mut manufacturer_ids: HashSet<u16> = config.manufacturer_ids.iter().copied().collect();
+        let mut service_uuids: HashSet<u16> = config.service_uuids.iter().copied().collect();
+        for name in &config.recognizers {
+            let recognizer = RECOGNIZERS.iter().find(|r| r.name == name).ok_or_else(|| {
+                eyre!(
+                    "unknown chatter recognizer {:?}, expected one of {}",
+                    name,
+                    recognizer_names().join(", ")
+                )
+            })?;
+            manufacturer_ids.extend(recognizer.manufacturer_ids);
+            service_uuids.extend(recognizer.service_uuids);
+        }
+        Ok(Chatter {
+            manufacturer_ids,
+            service_uuids: service_uuids.into_iter().map(uuid_from_u16).collect(),
+        })
+    }
+
+    pub fn is_chatter(&self, event: &DeviceEvent) -> bool {
+        match event {
+            DeviceEvent::ManufacturerDataAdvertisement {
+                manufacturer_data, ..
+            } => {
+                !manufacturer_data.is_empty()
+                    && manufacturer_data
+                        .keys()
+                        .all(|id| self.manufacturer_ids.contains(id))
+            }
+            DeviceEvent::ServiceDataAdvertisement { service_data, .. } => {
+                !service_data.is_empty()
+                    && service_data
+                        .keys()
+                        .all(|uuid| self.service_uuids.contains(uuid))
+            }
+        }
+    }
+}
+
+pub fn chatter_stream(
+    event_stream: impl Stream<Item = Result<DeviceEvent, BlueplugError>>,
+    chatter: Chatter,
+) -> impl Stream<Item = Result<DeviceEvent, BlueplugError>> {
+    stream! {
+        for await event in event_stream {
+            match event {
+                Ok(event) if chatter.is_chatter(&event) => {
+                    METRICS.advertisement(Stage::Ignored, &event)
+                }
+                event => yield event,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use btleplug::api::bleuuid::uuid_from_u16;
+
+    use crate::chatter::Chatter;
+    use crate::config::ChatterConfig;
+    use crate::{DeviceEvent, DeviceId};
+
+    #[test]
+    fn test_chatter() {
+        let device_id = DeviceId {
+            id: "hci0/dev_F0_01_02_03_04_05".to_string(),
+            device_name: "Hue lamp".to_string(),
+            address: "F0:01:02:03:04:05".to_string(),
+            random_address: None,
+        };
+        let manufacturer = |ids: &[u16]| DeviceEvent::ManufacturerDataAdvertisement {
+            device_id: device_id.clone(),
+            manufacturer_data: ids.iter().map(|id| (*id, vec![0x10, 0x05])).collect(),
+            rssi: None,
+        };
+        let service = |uuids: &[u16]| DeviceEvent::ServiceDataAdvertisement {
+            device_id: device_id.clone(),
+            service_data: uuids
+                .iter()
+                .map(|uuid| (uuid_from_u16(*uuid), vec![0x40]))
+                .collect(),
+            rssi: None,
+        };
+
+        let chatter = Chatter::new(&ChatterConfig::default()).unwrap();
+        assert!(chatter.is_chatter(&service(&[0xfe0f])));
+        assert!(chatter.is_chatter(&manufacturer(&[0x004c])));
+        // A BTHome frame alongside is still decoded.
+        assert!(!chatter.is_chatter(&service(&[0xfe0f, 0xfcd2])));
+        assert!(!chatter.is_chatter(&manufacturer(&[0x0499])));
+        assert!(
+            !chatter.is_chatter(&DeviceEvent::ManufacturerDataAdvertisement {
+                device_id: device_id.clone(),
+                manufacturer_data: HashMap::new(),
+                rssi: None,
+            })
+        );
+
+        let chatter = Chatter::new(&ChatterConfig {
+            recognizers: vec!["hue".to_string()],
+            manufacturer_ids: vec![0x0499],
+            service_uuids: Vec::new(),
+        })
+        .unwrap();
+        assert!(!chatter.is_chatter(&manufacturer(&[0x004c])));
+        assert!(chatter.is_chatter(&manufacturer(&[0x0499])));
+
+        assert!(Chatter::new(&ChatterConfig {
+            recognizers: vec!["sonos".to_string()],
+            manufacturer_ids: Vec::new(),
+            service_uuids: Vec::new(),
+        })
+        .is_err());
+    }
+}
@@ -0,0 +1,471 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::actions::{spawn_commands, Actions};
+use crate::adapter::AdapterSelector;
+use crate::archive::{self, spawn_archive, Archive};
+use crate::config::{BrokerConfig, Config, EsphomeProxyConfig, OutputFormat};
+use crate::doctor::{self, DoctorArgs};
+use crate::encoding::PayloadFormat;
+use crate::exec::spawn_exec;
+use crate::exempt::EXEMPT;
+use crate::export::{self, ExportArgs};
+use crate::fanout::Fanout;
+use crate::forward::{self, ForwardArgs};
+use crate::generate::{self, GenerateConfigArgs};
+use crate::grafana::spawn_grafana;
+use crate::grpc::{self, spawn_grpc, BlueplugService};
+use crate::health::HEALTH;
+use crate::http::spawn_server;
+use crate::import::{self, ImportArgs};
+use crate::ingest::{self, ingest_channel, spawn_mqtt_ingest};
+use crate::keys::{self, KeysArgs};
+use crate::knx::spawn_knx;
+use crate::latest::{spawn_persistence, LatestReadings};
+use crate::mdns::spawn_mdns;
+use crate::modbus::spawn_modbus;
+use crate::mqtt::spawn_broker;
+use crate::names::{NameRules, TOPIC_NAMES};
+use crate::pair::{self, PairArgs};
+use crate::pcap::PcapWriter;
+use crate::pipeline::Blueplug;
+use crate::plugin::Plugin;
+use crate::postgres::spawn_postgres;
+use crate::preflight::preflight;
+use crate::presence::{spawn_presence, Presence};
+use crate::query::{self, QueryArgs};
+use crate::rules::{self, spawn_rules, Rules};
+use crate::service::{self, ServiceArgs};
+use crate::simulate::{simulate_stream, SimulateArgs};
+use crate::snmp::spawn_snmp;
+use crate::stats::DailyStats;
+use crate::supervisor::SUPERVISOR;
+use crate::watchdog::{spawn_watchdog, Watchdog};
+use crate::zabbix::{spawn_zabbix, Zabbix};
+use crate::{api, envelope};
+use clap::{Parser, Subcommand};
+use color_eyre::eyre::{eyre, Result};
+use futures_util::pin_mut;
+use futures_util::stream::StreamExt;
+use tokio::signal;
+use tokio::sync::broadcast;
+
+// How long tasks get on shutdown to write out what they buffered, such as the archive.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Parser, Debug)]
+#[command(args_conflicts_with_subcommands = true)]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[arg(short = 'i', long, requires = "mqtt_addr")]
+    client_id: Option<String>,
+    #[arg(short = 'a', long, requires = "client_id")]
+    mqtt_addr: Option<String>,
+    #[arg(short = 'p', long, default_value_t = 1883)]
+    mqtt_port: u16,
+    /// Payload schema and topic layout for --mqtt-addr: the flat format of earlier releases, the
+    /// versioned envelope, or theengs to mimic OpenMQTTGateway
+    #[arg(long, value_enum, default_value_t = OutputFormat::Flat)]
+    output_format: OutputFormat,
+    /// Serialization of payloads published to --mqtt-addr
+    #[arg(long, value_enum, default_value_t = PayloadFormat::Json)]
+    payload_format: PayloadFormat,
+    /// Publish retained messages to --mqtt-addr, republishing the latest readings on reconnect
+    #[arg(long)]
+    retain: bool,
+    /// Topic to publish counters of seen, decoded and published advertisements to every minute
+    #[arg(long)]
+    telemetry_topic: Option<String>,
+    /// TOML configuration file, e.g. for publishing to several brokers
+    #[arg(short = 'c', long)]
+    config: Option<PathBuf>,
+    /// Bluetooth adapter to scan with, by index, name (e.g. hci1) or MAC address
+    #[arg(long)]
+    adapter: Option<AdapterSelector>,
+    /// Don't scan with a local Bluetooth adapter, only decode what ESPHome proxies and forwarders
+    /// send, as on a host without Bluetooth
+    #[arg(long, conflicts_with = "adapter")]
+    no_local_scan: bool,
+    /// ESPHome Bluetooth proxy to receive advertisements from, host or host:port (may be repeated)
+    #[arg(long = "esphome-proxy")]
+    esphome_proxies: Vec<String>,
+    /// Experimental: load a WASM decoder plugin (may be repeated)
+    #[arg(long = "plugin")]
+    plugins: Vec<PathBuf>,
+    /// Seconds during which identical advertisements from a device are dropped (0 disables)
+    #[arg(long, default_value_t = 2)]
+    dedup_window: u64,
+    /// Seconds without any Bluetooth events, after there have been some, before scanning is
+    /// restarted (0 disables)
+    #[arg(long, default_value_t = 300)]
+    scan_stall_timeout: u64,
+    /// File to save the latest readings to, which are republished as stale after a restart
+    #[arg(long)]
+    state_file: Option<PathBuf>,
+    /// Address for the HTTP server, which serves a dashboard at /, the latest readings at
+    /// /readings, Prometheus metrics at /metrics and accepts advertisements from forwarders at
+    /// /forward
+    #[arg(long)]
+    listen: Option<SocketAddr>,
+    /// Write received advertisements to a PCAP file, for inspecting payloads in Wireshark
+    #[arg(long)]
+    capture: Option<PathBuf>,
+    /// Bytes the capture may take, rotating it to a .1 file once it reaches half of that
+    #[arg(long, requires = "capture")]
+    capture_max_size: Option<u64>,
+    /// Seconds advertisements are kept in the capture, rotating it to a .1 file once it is half
+    /// that old
+    #[arg(long, requires = "capture")]
+    capture_max_age: Option<u64>,
+    /// IANA time zone whose midnight starts a new day for daily stats and archive partitions,
+    /// e.g. Europe/Berlin (defaults to the system's)
+    #[arg(long)]
+    timezone: Option<String>,
+    /// Run as a service, as `blueplug service install` sets up: output goes to the platform's
+    /// log rather than a console, and blueplug is restarted after failing
+    #[arg(long)]
+    service: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Forward raw advertisements to a central blueplug instead of decoding them
+    Forward(ForwardArgs),
+    /// Print the JSON Schema of the envelope payload format
+    Schema,
+    /// Check the adapter, brokers, configuration and clock, and print a report
+    Doctor(DoctorArgs),
+    /// Check a configuration file without running anything, exiting non-zero if it is invalid
+    CheckConfig { file: PathBuf },
+    /// Print a commented example configuration, optionally listing the devices found by a scan
+    GenerateConfig(GenerateConfigArgs),
+    /// Add, list or remove the bind keys of devices that encrypt their advertisements
+    Keys(KeysArgs),
+    /// Run SQL over the Parquet archive with DuckDB and print the results
+    Query(QueryArgs),
+    /// Import historical readings from CSV, envelopes or a capture into the archive or Influx
+    /// endpoints, keeping their timestamps
+    Import(ImportArgs),
+    /// Export readings from the Parquet archive as CSV, envelopes or InfluxDB line protocol
+    Export(ExportArgs),
+    /// Pair with a device that requires bonding, such as a lock, answering passkey prompts on the
+    /// terminal
+    Pair(PairArgs),
+    /// Install or uninstall blueplug as a service starting at boot: a systemd unit, a launchd
+    /// agent or a Windows scheduled task
+    Service(ServiceArgs),
+    /// Deliver advertisements of made up devices to the configured sinks, for load testing
+    Simulate(SimulateArgs),
+}
+
+// run runs the blueplug command, with the arguments it was started with.
+pub async fn run() -> Result<()> {
+    let mut args = Args::parse();
+    if args.service && !service::is_supervised() {
+        return service::supervise();
+    }
+    let mut simulation = None;
+    match args.command.take() {
+        Some(Command::Forward(args)) => return forward::run(args).await,
+        Some(Command::Doctor(args)) => return doctor::run(args).await,
+        Some(Command::GenerateConfig(args)) => return generate::run(args).await,
+        Some(Command::Keys(args)) => return keys::run(args),
+        Some(Command::Query(args)) => return query::run(args),
+        Some(Command::Import(args)) => return import::run(args).await,
+        Some(Command::Export(args)) => return export::run(args),
+        Some(Command::Pair(args)) => return pair::run(args).await,
+        Some(Command::Service(args)) => return service::run(args),
+        Some(Command::CheckConfig { file }) => {
+            Config::load(&file)?.check()?;
+            println!("{}: OK", file.display());
+            return Ok(());
+        }
+        Some(Command::Schema) => {
+            print!("{}", envelope::SCHEMA);
+            return Ok(());
+        }
+        Some(Command::Simulate(simulate)) => {
+            args.config = Some(simulate.config.clone());
+            simulation = Some(simulate);
+        }
+        None => {}
+    }
+    let mut config = match &args.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    if args.timezone.is_some() {
+        config.time_zone = args.timezone;
+    }
+    let stats = config
+        .stats
+        .as_ref()
+        .map(|stats| DailyStats::new(stats, config.time_zone.as_deref()))
+        .transpose()?;
+    let presence = config
+        .presence
+        .as_ref()
+        .map(|config| Arc::new(Mutex::new(Presence::new(config, Instant::now()))));
+    let watchdog = Arc::new(Mutex::new(Watchdog::new(
+        config.watchdog.clone(),
+        config.availability.clone(),
+    )));
+    let (ingest, ingested) = ingest_channel();
+    let (sighted, watched) = (presence.clone(), watchdog.clone());
+    let mut blueplug = Blueplug::builder()
+        .pipeline(&config)
+        .scan(simulation.is_none())
+        .local_scan(!args.no_local_scan)
+        .dedup_window(Duration::from_secs(args.dedup_window))
+        .scan_stall_timeout(
+            Some(Duration::from_secs(args.scan_stall_timeout)).filter(|t| !t.is_zero()),
+        )
+        .source(ingested)
+        .observe(move |event| {
+            if let Some(presence) = &sighted {
+                presence.lock().unwrap().sighting(event, Instant::now());
+            }
+            if !event.device_id().device_name.is_empty() {
+                watched.lock().unwrap().sighting(event, Instant::now());
+            }
+        });
+    if let Some(adapter) = args.adapter.clone() {
+        blueplug = blueplug.adapter(adapter);
+    }
+    for address in &args.esphome_proxies {
+        blueplug = blueplug.esphome_proxy(EsphomeProxyConfig::from_address(address)?);
+    }
+    for path in &args.plugins {
+        blueplug = blueplug.decoder(Plugin::load(path)?);
+    }
+    if let Some(simulate) = &simulation {
+        println!(
+            "simulating {} devices, each advertising {}/s",
+            simulate.devices, simulate.rate.0
+        );
+        blueplug = blueplug.source(simulate_stream(simulate.devices, simulate.rate));
+    }
+    if let Some(path) = &args.capture {
+        let writer = PcapWriter::create(path)?.retention(
+            args.capture_max_size,
+            args.capture_max_age.map(Duration::from_secs),
+        );
+        blueplug = blueplug.capture(writer);
+    }
+    let device_readings = blueplug.build()?.readings();
+    // The pipeline keeps its topic names and exempt kinds to itself; the sinks the binary runs
+    // share the process-wide ones.
+    TOPIC_NAMES.set_rules(NameRules::new(&config.names)?);
+    TOPIC_NAMES.set_locations(config.locations.clone());
+    TOPIC_NAMES.set_labels(config.labels.clone());
+    EXEMPT.set_kinds(config.exempt_kinds.clone());
+
+    if simulation.is_none() && !args.no_local_scan {
+        preflight(args.adapter.as_ref()).await?;
+    }
+
+    let other_sinks = config.has_other_sinks();
+    let mut brokers = config.brokers;
+    if let (Some(client_id), Some(mqtt_addr)) = (args.client_id, args.mqtt_addr) {
+        let mut broker = BrokerConfig::new("default", mqtt_addr, args.mqtt_port, client_id);
+        broker.format = args.output_format;
+        broker.payload_format = args.payload_format;
+        broker.retain = args.retain;
+        broker.telemetry_topic = args.telemetry_topic;
+        broker.validate_payload_format()?;
+        brokers.push(broker);
+    }
+    if brokers.is_empty() {
+        if !other_sinks {
+            return Err(eyre!(
+                "nowhere to send readings, pass --client-id and --mqtt-addr or configure a \
+                 broker or another sink with --config"
+            ));
+        }
+        HEALTH.without_brokers();
+    }
+
+    let state_file = args.state_file.or(config.state_file);
+    let restored = match &state_file {
+        Some(path) => LatestReadings::load(path, &config.availability)?,
+        None => LatestReadings::default(),
+    };
+
+    let latest = Arc::new(Mutex::new(restored.clone()));
+    if let Some(path) = state_file {
+        spawn_persistence(path, latest.clone());
+    }
+
+    if let Some(snmp) = &config.snmp {
+        spawn_snmp(snmp, latest.clone()).await?;
+    }
+    if let Some(modbus) = &config.modbus {
+        spawn_modbus(modbus, latest.clone()).await?;
+    }
+
+    if let Some(commands) = &config.commands {
+        let broker = brokers
+            .iter()
+            .find(|b| b.name == commands.broker)
+            .ok_or_else(|| eyre!("commands refer to unknown broker {:?}", commands.broker))?;
+        let actions = Actions::new(&config.actions)?;
+        spawn_commands(
+            broker,
+            commands,
+            actions,
+            config.gatt.clone(),
+            args.adapter.clone(),
+        )?;
+    }
+
+    if let Some(name) = &config.ingest.broker {
+        let broker = brokers
+            .iter()
+            .find(|b| &b.name == name)
+            .ok_or_else(|| eyre!("ingest refers to unknown broker {:?}", name))?;
+        let topic_prefix = config
+            .ingest
+            .topic_prefix
+            .as_deref()
+            .unwrap_or(forward::DEFAULT_TOPIC_PREFIX);
+        spawn_mqtt_ingest(broker, topic_prefix, ingest.clone())?;
+    }
+    let listen = args.listen.or(config.ingest.listen);
+    if let Some(listen) = listen {
+        let router = ingest::router(ingest).merge(api::router(
+            latest.clone(),
+            config.archive.as_ref().map(|a| a.directory.clone()),
+        ));
+        spawn_server(listen, router).await?;
+    }
+    // Advertised for as long as blueplug runs.
+    let _mdns = match (&config.mdns, listen) {
+        (Some(mdns), Some(listen)) => Some(spawn_mdns(mdns, listen, config.archive.is_some())?),
+        (Some(_), None) => {
+            return Err(eyre!(
+                "[mdns] advertises the HTTP listener, which --listen or [ingest] listen sets"
+            ))
+        }
+        (None, _) => None,
+    };
+
+    let (presence_changes, _) = broadcast::channel(16);
+    if let Some(presence) = &presence {
+        spawn_presence(presence.clone(), presence_changes.clone());
+    }
+
+    let (actions, _) = broadcast::channel(16);
+
+    spawn_watchdog(watchdog);
+
+    let mut fanout = Fanout::new(config.routes);
+    for broker in brokers {
+        let readings = fanout.subscribe(&broker.name);
+        let latest = restored.filter(|reading| fanout.routed_to(&broker.name, reading));
+        let presence_changes = presence_changes.subscribe();
+        let actions = actions.subscribe();
+        spawn_broker(
+            &SUPERVISOR,
+            broker,
+            readings,
+            latest,
+            stats.clone(),
+            presence_changes,
+            actions,
+        )?;
+    }
+    if !config.rules.is_empty() {
+        let rules = Rules::new(&config.rules);
+        spawn_rules(rules, fanout.subscribe(rules::SINK_NAME), actions.clone());
+    }
+    for grafana in config.grafana {
+        let readings = fanout.subscribe(&grafana.name);
+        spawn_grafana(grafana, readings);
+    }
+    for postgres in config.postgres {
+        let readings = fanout.subscribe(&postgres.name);
+        spawn_postgres(postgres, readings);
+    }
+    for zabbix in config.zabbix {
+        let readings = fanout.subscribe(&zabbix.name);
+        spawn_zabbix(Zabbix::new(zabbix), readings);
+    }
+    for exec in config.exec {
+        let readings = fanout.subscribe(&exec.name);
+        spawn_exec(exec, readings);
+    }
+    if let Some(knx) = &config.knx {
+        spawn_knx(knx, fanout.subscribe("knx")).await?;
+    }
+    if let Some(socket) = &config.socket {
+        #[cfg(unix)]
+        crate::socket::spawn_socket(socket, fanout.subscribe(crate::socket::SINK_NAME))?;
+        #[cfg(not(unix))]
+        return Err(eyre!(
+            "[socket] {:?} needs Unix domain sockets",
+            socket.path
+        ));
+    }
+    if let Some(grpc_config) = &config.grpc {
+        let service = BlueplugService::new(latest.clone(), fanout.subscribe(grpc::SINK_NAME));
+        spawn_grpc(grpc_config, service).await?;
+    }
+    if let Some(archive_config) = &config.archive {
+        let archive = Archive::new(archive_config, config.time_zone.as_deref())?;
+        let interval = Duration::from_secs(archive_config.interval);
+        spawn_archive(archive, interval, fanout.subscribe(archive::SINK_NAME));
+    }
+    fanout.validate()?;
+
+    pin_mut!(device_readings);
+    let shutdown = shutdown_signal();
+    pin_mut!(shutdown);
+
+    loop {
+        tokio::select! {
+            reading = device_readings.next() => {
+                let Some(reading) = reading else {
+                    break;
+                };
+                let reading = Arc::new(reading);
+                latest.lock().unwrap().update(reading.clone());
+                fanout.send(reading);
+            }
+            result = &mut shutdown => {
+                result?;
+                println!("interrupted, shutting down");
+                SUPERVISOR.drain(SHUTDOWN_TIMEOUT).await;
+                return Ok(());
+            }
+            // A task that can't be restarted failed; exiting lets the service manager start
+            // blueplug again.
+            _ = SUPERVISOR.cancelled() => {
+                SUPERVISOR.drain(SHUTDOWN_TIMEOUT).await;
+                return Err(eyre!("a task failed, see above"));
+            }
+        }
+    }
+
+    SUPERVISOR.drain(SHUTDOWN_TIMEOUT).await;
+    Err(eyre!("bluetooth event stream ended"))
+}
+
+// shutdown_signal waits for Ctrl-C or, on Unix, SIGTERM, which service managers stop blueplug
+// with. Either one shuts down the same way, writing out the latest readings, the archive and the
+// brokers' queue files.
+async fn shutdown_signal() -> Result<()> {
+    #[cfg(unix)]
+    {
+        let mut terminate = signal::unix::signal(signal::unix::SignalKind::terminate())?;
+        tokio::select! {
+            result = signal::ctrl_c() => result?,
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    signal::ctrl_c().await?;
+    Ok(())
+}
@@ -0,0 +1,112 @@
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// A wall clock that moved this much more or less than the monotonic clock between two readings of
+// it was set, rather than drifting.
+const JUMP_THRESHOLD: Duration = Duration::from_secs(60);
+
+// CLOCK watches the wall clock for jumps. A Raspberry Pi has no real-time clock, so it boots with
+// the time it last shut down (or 1970) until NTP syncs. Readings stamped before then get their
+// timestamps corrected from the monotonic clock, which doesn't jump, if they haven't been sent
+// yet.
+pub static CLOCK: LazyLock<Clock> = LazyLock::new(Clock::default);
+
+#[derive(Default)]
+pub struct Clock {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    // Both clocks, as last read.
+    last: Option<(Instant, SystemTime)>,
+    // When the wall clock was last seen to have jumped.
+    jumped: Option<Instant>,
+}
+
+impl Clock {
+    // now reads both clocks, noting whether the wall clock jumped since the last time.
+    pub fn now(&self) -> (Instant, SystemTime) {
+        let now = (Instant::now(), SystemTime::now());
+        self.observe(now);
+        now
+    }
+
+    fn observe(&self, (instant, wall): (Instant, SystemTime)) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some((last_instant, last_wall)) = inner.last {
+            let expected = last_wall + instant.duration_since(last_instant);
+            let (jump, direction) = match wall.duration_since(expected) {
+                Ok(ahead) => (ahead, "forward"),
+                Err(behind) => (behind.duration(), "back"),
+            };
+            if jump > JUMP_THRESHOLD {
+                println!(
+                    "wall clock jumped {} by {}s, re-stamping unsent readings",
+                    direction,
+                    jump.as_secs()
+                );
+                inner.jumped = Some(instant);
+            }
+        }
+        inner.last = Some((instant, wall));
+    }
+
+    // restamp returns the timestamp of something stamped at the monotonic instant received. If
+    // the wall clock has jumped since, timestamp is replaced by the current wall time less how
+    // long ago that was.
+    pub fn restamp(&self, timestamp: u64, received: Instant) -> u64 {
+        let (instant, wall) = self.now();
+        let jumped = self.inner.lock().unwrap().jumped;
+        match jumped {
+            Some(jumped) if received < jumped => {
+                unix_seconds(wall).saturating_sub(instant.duration_since(received).as_secs())
+            }
+            _ => timestamp,
+        }
+    }
+}
+
+// unix_timestamp is the current time in seconds since the Unix epoch.
+pub fn unix_timestamp() -> u64 {
+    unix_seconds(CLOCK.now().1)
+}
+
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+    use crate::clock::Clock;
+
+    #[test]
+    fn test_restamp() {
+        let clock = Clock::default();
+        let boot = Instant::now() - Duration::from_secs(600);
+        // Booted with the time of the last shutdown, a year ago.
+        let stale = SystemTime::now() - Duration::from_secs(365 * 86400 + 600);
+        clock.observe((boot, stale));
+        let stale_timestamp = stale.duration_since(UNIX_EPOCH).unwrap().as_secs();
+        // Drift doesn't count as a jump.
+        clock.observe((
+            boot + Duration::from_secs(10),
+            stale + Duration::from_secs(11),
+        ));
+        assert!(clock.inner.lock().unwrap().jumped.is_none());
+
+        // NTP syncs on the next read of the clock.
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let restamped = clock.restamp(stale_timestamp, boot);
+        assert!(restamped.abs_diff(now - 600) <= 1);
+        // Things stamped after the sync are left alone.
+        assert_eq!(clock.restamp(now, Instant::now()), now);
+    }
+}
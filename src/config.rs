@@ -0,0 +1,1719 @@
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::actions::Actions;
+use crate::archive::Archive;
+use crate::battery::BatteryCurves;
+use crate::chatter::{recognizer_names, Chatter};
+use crate::derived::Derivations;
+use crate::encoding::PayloadFormat;
+use crate::error::BlueplugError;
+use crate::gatt::ValueFormat;
+use crate::keys::KeyStore;
+use crate::knx::{Dpt, Knx};
+use crate::modbus::{validate_registers, RegisterType};
+use crate::names::NameRules;
+use crate::rpa::Resolver;
+use crate::script::Scripts;
+use crate::secret::read_secret;
+use crate::snmp::parse_oid;
+use crate::stats::DailyStats;
+use crate::watchdog::Availability;
+
+// Labels blueplug sets itself, which configured labels can't override.
+const RESERVED_LABELS: &[&str] = &["device", "protocol", "location", "broker", "reason", "le"];
+
+// EXAMPLE is a commented example configuration, printed by `blueplug generate-config`.
+pub const EXAMPLE: &str = include_str!("../config/example.toml");
+
+// Config is the optional TOML configuration file. Anything that can't reasonably be expressed as
+// command line flags, such as several brokers with their own credentials, lives here. See
+// config/example.toml for every setting.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub brokers: Vec<BrokerConfig>,
+    #[serde(default)]
+    pub routes: Vec<RouteConfig>,
+    #[serde(default)]
+    pub mappings: Vec<MappingConfig>,
+    #[serde(default)]
+    pub suppress: Vec<SuppressConfig>,
+    // Measurement kinds published as soon as they are read, such as smoke_detected or leak, see
+    // exempt.rs.
+    #[serde(default)]
+    pub exempt_kinds: Vec<String>,
+    // The devices in each location, such as a room, by name or id as in routes.
+    #[serde(default)]
+    pub locations: BTreeMap<String, Vec<String>>,
+    // Labels of devices, passed on to every sink that can tag values.
+    #[serde(default)]
+    pub labels: Vec<LabelConfig>,
+    // Rules deriving display names and rooms from advertised names.
+    #[serde(default)]
+    pub names: Vec<NameConfig>,
+    // Battery percentage estimated from the voltage of devices that only report volts.
+    #[serde(default)]
+    pub battery: Vec<BatteryConfig>,
+    // Estimate the days left on batteries from how fast their level drops.
+    pub battery_drain: Option<BatteryDrainConfig>,
+    // Measurement kinds computed from other kinds of the same device, see derived.rs.
+    #[serde(default)]
+    pub derived: BTreeMap<String, String>,
+    // Rhai scripts transforming readings after derivations, see script.rs. Relative paths are
+    // relative to the configuration file.
+    #[serde(default)]
+    pub scripts: Vec<PathBuf>,
+    // Publish daily min/max/mean of each device's measurements.
+    pub stats: Option<StatsConfig>,
+    // Write readings to Parquet files for later analysis.
+    pub archive: Option<ArchiveConfig>,
+    // Push readings to Grafana Live for live dashboards.
+    #[serde(default)]
+    pub grafana: Vec<GrafanaConfig>,
+    // Write readings to PostgreSQL tables.
+    #[serde(default)]
+    pub postgres: Vec<PostgresConfig>,
+    // Send readings to Zabbix trapper items.
+    #[serde(default)]
+    pub zabbix: Vec<ZabbixConfig>,
+    // Pipe readings to commands.
+    #[serde(default)]
+    pub exec: Vec<ExecConfig>,
+    // Answer SNMP requests for the latest readings.
+    pub snmp: Option<SnmpConfig>,
+    // Serve the latest readings as Modbus TCP registers.
+    pub modbus: Option<ModbusConfig>,
+    // Write measurements to KNX group addresses.
+    pub knx: Option<KnxConfig>,
+    // Advertise the HTTP API on the local network.
+    pub mdns: Option<MdnsConfig>,
+    // Serve readings over gRPC.
+    pub grpc: Option<GrpcConfig>,
+    // Stream readings to local clients over a Unix domain socket.
+    pub socket: Option<SocketConfig>,
+    pub presence: Option<PresenceConfig>,
+    // Publish messages to automate other devices when readings meet conditions.
+    #[serde(default)]
+    pub rules: Vec<RuleConfig>,
+    // Ruuvi tags used as motion or opening sensors.
+    #[serde(default)]
+    pub motion: Vec<MotionConfig>,
+    #[serde(default)]
+    pub rpa: RpaConfig,
+    #[serde(default)]
+    pub chatter: ChatterConfig,
+    #[serde(default)]
+    pub watchdog: Vec<WatchdogConfig>,
+    // How matching devices are expected to advertise, see AvailabilityConfig.
+    #[serde(default)]
+    pub availability: Vec<AvailabilityConfig>,
+    #[serde(default)]
+    pub esphome_proxies: Vec<EsphomeProxyConfig>,
+    // Devices read over GATT connections rather than from their advertisements.
+    #[serde(default)]
+    pub gatt: Vec<GattConfig>,
+    // How many GATT devices may be connected at once, all of them by default.
+    pub gatt_connections: Option<usize>,
+    // Characteristic writes to GATT devices, triggered over MQTT.
+    pub commands: Option<CommandsConfig>,
+    #[serde(default)]
+    pub actions: Vec<ActionConfig>,
+    #[serde(default)]
+    pub ingest: IngestConfig,
+    // Where the latest readings are saved, so they can be republished after a restart.
+    pub state_file: Option<PathBuf>,
+    // Bind keys of devices that encrypt their advertisements, managed with `blueplug keys`.
+    pub keys_file: Option<PathBuf>,
+    // The IANA time zone, such as "Europe/Berlin", whose midnight starts a new day for daily
+    // stats and archive partitions. Defaults to the system's, which in a container is usually UTC.
+    pub time_zone: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct BrokerConfig {
+    pub name: String,
+    pub host: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    // Where to read the password from instead, see secret.rs.
+    pub password_file: Option<PathBuf>,
+    pub password_command: Option<String>,
+    pub tls: Option<TlsConfig>,
+    #[serde(default)]
+    pub format: OutputFormat,
+    // How payloads are serialized; cbor and msgpack are smaller than json on constrained links,
+    // and protobuf, with the envelope format, gives readings a typed schema.
+    #[serde(default)]
+    pub payload_format: PayloadFormat,
+    // Defaults to the format's usual prefix, see topic_prefix().
+    pub topic_prefix: Option<String>,
+    #[serde(default = "default_qos")]
+    pub qos: u8,
+    // QoS for alerts such as smoke or gas detected, which are also never batched or rate limited.
+    // Defaults to qos.
+    pub alert_qos: Option<u8>,
+    // Publish retained messages, and republish the latest readings whenever the connection to the
+    // broker is (re)established.
+    #[serde(default)]
+    pub retain: bool,
+    // Topic to publish the counters from metrics.rs to every minute, as JSON.
+    pub telemetry_topic: Option<String>,
+    // Topic for reports of advertisements that failed to decode, empty to disable them.
+    #[serde(default = "default_diagnostics_topic")]
+    pub diagnostics_topic: String,
+    // Seconds between MQTT keep-alive pings.
+    #[serde(default = "default_keep_alive")]
+    pub keep_alive: u64,
+    // Largest MQTT packet in bytes, sent or received. Batches are split to fit, and other messages
+    // that don't are dropped rather than break the connection.
+    #[serde(default = "default_max_packet_size")]
+    pub max_packet_size: usize,
+    // Connect with clean_session=false, so the broker keeps the session while blueplug restarts.
+    // The client_id must be stable, as it identifies the session.
+    #[serde(default)]
+    pub persistent_session: bool,
+    // Journal of QoS 1 and 2 publishes not yet acknowledged, sent again after a crash, see
+    // outbox.rs.
+    pub queue_file: Option<PathBuf>,
+    // Bytes the queue_file may grow to before acknowledged messages are pruned from it. If the
+    // messages still waiting take more than half of it, the oldest are dropped from the journal.
+    #[serde(default = "default_queue_file_max_size")]
+    pub queue_file_max_size: u64,
+    // Announce readings to Home Assistant with MQTT discovery.
+    pub homeassistant: Option<HomeAssistantConfig>,
+    // Seconds to collect readings for before publishing each device's as one array on
+    // `<topic_prefix>/batch/<device>`, saving packets on constrained links such as LTE. Not with
+    // rate_limit.
+    pub batch_interval: Option<u64>,
+    // Limits on how fast readings are published, see RateLimitConfig.
+    pub rate_limit: Option<RateLimitConfig>,
+}
+
+// RateLimitConfig limits the readings published to a broker to rate messages per second overall
+// and device_rate per device, allowing bursts of burst and device_burst messages (by default a
+// second's worth overall, and one per device). Readings over the limit are dropped or queued, as
+// policy says, up to queue_size of them. See ratelimit.rs.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub rate: Option<f64>,
+    pub burst: Option<f64>,
+    pub device_rate: Option<f64>,
+    pub device_burst: Option<f64>,
+    #[serde(default)]
+    pub policy: RateLimitPolicy,
+    #[serde(default = "default_rate_limit_queue_size")]
+    pub queue_size: usize,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RateLimitPolicy {
+    Drop,
+    #[default]
+    Queue,
+}
+
+fn default_rate_limit_queue_size() -> usize {
+    1000
+}
+
+// OutputFormat picks the payload schema and topic layout published to a broker. `flat` is the
+// format of earlier releases, and stays the default so existing consumers keep working;
+// `envelope` is the versioned format described by schema/reading.v1.json. `theengs` mimics
+// Theengs/OpenMQTTGateway, one object per device on `home/OMG/BTtoMQTT/<mac>` with keys such as
+// `tempc`, `hum` and `batt`, so blueplug can replace a gateway without touching its consumers.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    Envelope,
+    #[default]
+    #[serde(alias = "blueplug")]
+    Flat,
+    Theengs,
+}
+
+// HomeAssistantConfig publishes a retained discovery message under discovery_prefix for every
+// device and kind, with device_class and state_class picked from the kind so long-term statistics
+// work. Overrides set either class (empty to leave it out) for one kind of matching devices.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct HomeAssistantConfig {
+    #[serde(default = "default_discovery_prefix")]
+    pub discovery_prefix: String,
+    #[serde(default)]
+    pub overrides: Vec<HomeAssistantOverride>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct HomeAssistantOverride {
+    #[serde(default)]
+    pub devices: Vec<String>,
+    pub kind: String,
+    pub device_class: Option<String>,
+    pub state_class: Option<String>,
+}
+
+// TlsConfig enables TLS for a broker. Without a ca_file the platform's root certificates are
+// used; client_cert and client_key (PEM) enable mutual TLS.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    pub ca_file: Option<PathBuf>,
+    pub client_cert: Option<PathBuf>,
+    pub client_key: Option<PathBuf>,
+}
+
+// RouteConfig restricts the named sinks to readings from matching devices (by name, `*` acts as a
+// wildcard, or by id) and of matching measurement kinds. An empty list matches anything.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RouteConfig {
+    pub sinks: Vec<String>,
+    #[serde(default)]
+    pub devices: Vec<String>,
+    #[serde(default)]
+    pub kinds: Vec<String>,
+}
+
+// LabelConfig gives matching devices (by name or id, as in routes; every device if empty)
+// key/value labels, which sinks pass on as they tag values: envelope payloads as device.labels,
+// Grafana as tags and Prometheus as labels. Keys must be valid Prometheus label names, and not
+// one blueplug uses itself, and values can't be empty.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct LabelConfig {
+    #[serde(default)]
+    pub devices: Vec<String>,
+    pub labels: BTreeMap<String, String>,
+}
+
+// NameConfig derives a display name, used in topics and discovery, and a room, suggested to Home
+// Assistant as the device's area, from advertised names that match pattern, a regular expression
+// matched against the whole name. name and room are templates referring to its captures as `$1`
+// or `${room}`. Routes, mappings and other device lists still match the advertised name.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct NameConfig {
+    pub pattern: String,
+    pub name: Option<String>,
+    pub room: Option<String>,
+}
+
+// MappingConfig renames, or with ignore drops, one measurement kind of matching devices (by name
+// or id, as in routes). An empty devices list matches every device.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct MappingConfig {
+    #[serde(default)]
+    pub devices: Vec<String>,
+    pub kind: String,
+    pub rename: Option<String>,
+    #[serde(default)]
+    pub ignore: bool,
+}
+
+// SuppressConfig keeps the listed measurement kinds of matching devices (by name or id, as in
+// routes; all devices if empty) from being published. Unlike an ignore mapping it applies after
+// derivations, so a suppressed kind can still be used to compute another.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct SuppressConfig {
+    #[serde(default)]
+    pub devices: Vec<String>,
+    pub kinds: Vec<String>,
+}
+
+// BatteryConfig estimates a battery kind from the voltage of matching devices (by name or id, as in
+// routes; all devices if empty) with the discharge curve of a chemistry, or a curve of
+// [volts, percent] points with increasing volts. Percentages in between are interpolated.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct BatteryConfig {
+    #[serde(default)]
+    pub devices: Vec<String>,
+    pub chemistry: Option<Chemistry>,
+    pub curve: Option<Vec<[f64; 2]>>,
+}
+
+// BatteryDrainConfig publishes a battery_days reading of devices, by name or id as in routes, the
+// days until their battery runs out at the rate it dropped over the last window days. Estimates
+// start once the battery level has been seen for min_days. See battery.rs.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct BatteryDrainConfig {
+    #[serde(default)]
+    pub devices: Vec<String>,
+    #[serde(default = "default_drain_window")]
+    pub window: f64,
+    #[serde(default = "default_drain_min_days")]
+    pub min_days: f64,
+}
+
+fn default_drain_window() -> f64 {
+    30.0
+}
+
+fn default_drain_min_days() -> f64 {
+    3.0
+}
+
+// Chemistry is a battery with a known discharge curve: a lithium coin cell (CR2032, CR2477), or
+// two alkaline AA or AAA cells in series.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Chemistry {
+    Coin,
+    Alkaline,
+}
+
+// StatsConfig publishes retained `<topic_prefix>/<device>/stats/<kind>` summaries of the day so
+// far, for the listed kinds (all if empty). Days start at midnight in time_zone, an IANA name such
+// as "Europe/Berlin", defaulting to the top-level time_zone.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct StatsConfig {
+    #[serde(default)]
+    pub kinds: Vec<String>,
+    pub time_zone: Option<String>,
+    #[serde(default = "default_stats_topic_prefix")]
+    pub topic_prefix: String,
+}
+
+// ArchiveConfig writes readings to Parquet files under directory, partitioned by date (and hour
+// if hourly) and device, writing what was collected every interval seconds. Files older than
+// max_age seconds, and the oldest beyond max_size bytes, are deleted. Routes refer to it as the
+// sink "archive". See archive.rs.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ArchiveConfig {
+    pub directory: PathBuf,
+    #[serde(default)]
+    pub partition: ArchivePartition,
+    #[serde(default = "default_archive_interval")]
+    pub interval: u64,
+    pub max_age: Option<u64>,
+    pub max_size: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ArchivePartition {
+    #[default]
+    Daily,
+    Hourly,
+}
+
+// PresenceConfig publishes retained `home`/`away` states of devices such as phones and tags on
+// `<topic_prefix>/<name>`, identified by their BLE address or an identity from [rpa], and
+// announces them to Home Assistant as device trackers. Unlike sensors, devices count whether or
+// not they advertise a name. A device is home once it has been seen for home_after seconds, and
+// away once it hasn't been seen for away_after seconds. Advertisements received weaker than
+// min_rssi dBm aren't counted, where the source reports the RSSI, so a device just outside can be
+// told from one inside.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct PresenceConfig {
+    #[serde(default = "default_presence_topic_prefix")]
+    pub topic_prefix: String,
+    #[serde(default = "default_away_after")]
+    pub away_after: u64,
+    #[serde(default)]
+    pub home_after: u64,
+    pub min_rssi: Option<i16>,
+    pub devices: Vec<PresenceDevice>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct PresenceDevice {
+    pub name: String,
+    pub address: Option<String>,
+    pub identity: Option<String>,
+}
+
+// RuleConfig publishes on_payload to topic once all conditions have held for on_after seconds, and
+// off_payload, if any, once they haven't for off_after seconds. Payloads are published as they
+// are, whatever the brokers' payload format, to brokers, or every broker if empty. Routes refer to
+// the rules as the sink "rules". See rules.rs.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RuleConfig {
+    pub name: String,
+    pub conditions: Vec<ConditionConfig>,
+    #[serde(default)]
+    pub on_after: u64,
+    #[serde(default)]
+    pub off_after: u64,
+    pub topic: String,
+    pub on_payload: String,
+    pub off_payload: Option<String>,
+    #[serde(default)]
+    pub retain: bool,
+    #[serde(default)]
+    pub brokers: Vec<String>,
+}
+
+// ConditionConfig holds while the latest reading of kind of any of devices, by name or id as in
+// routes, is above and/or below the thresholds. Once it holds, a threshold has to be crossed back
+// by hysteresis before it stops.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ConditionConfig {
+    #[serde(default)]
+    pub devices: Vec<String>,
+    pub kind: String,
+    pub above: Option<f64>,
+    pub below: Option<f64>,
+    #[serde(default)]
+    pub hysteresis: f64,
+}
+
+// MotionConfig publishes a motion reading of the Ruuvi tags among devices, by name or id as in
+// routes, 1 as they start moving and 0 once they have been still for debounce seconds. A tag moves
+// when its movement counter goes up or its acceleration changes by more than threshold g between
+// advertisements. See motion.rs.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct MotionConfig {
+    #[serde(default)]
+    pub devices: Vec<String>,
+    #[serde(default = "default_motion_threshold")]
+    pub threshold: f64,
+    #[serde(default = "default_motion_debounce")]
+    pub debounce: u64,
+}
+
+fn default_motion_threshold() -> f64 {
+    0.1
+}
+
+fn default_motion_debounce() -> u64 {
+    30
+}
+
+// RpaConfig handles resolvable private addresses, which phones and some tags rotate every few
+// minutes. Addresses of the listed identities are resolved with their Identity Resolving Key (32
+// hex digits, most significant first) to a stable `irk/<name>` id.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct RpaConfig {
+    #[serde(default)]
+    pub anonymous: AnonymousAddresses,
+    #[serde(default)]
+    pub identities: Vec<IdentityConfig>,
+}
+
+// ChatterConfig drops the advertisements of devices that carry no readings, such as smart bulbs
+// and phones, before they are decoded, see chatter.rs. recognizers are the built-in ones to enable,
+// all by default; manufacturer_ids and service_uuids add 16-bit company ids and service UUIDs of
+// other devices.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ChatterConfig {
+    #[serde(default = "recognizer_names")]
+    pub recognizers: Vec<String>,
+    #[serde(default)]
+    pub manufacturer_ids: Vec<u16>,
+    #[serde(default)]
+    pub service_uuids: Vec<u16>,
+}
+
+impl Default for ChatterConfig {
+    fn default() -> Self {
+        ChatterConfig {
+            recognizers: recognizer_names(),
+            manufacturer_ids: Vec::new(),
+            service_uuids: Vec::new(),
+        }
+    }
+}
+
+// AnonymousAddresses is what to do with resolvable private addresses that no identity resolves:
+// keep them as they are, collapse them to one `rpa/<device name>` id per advertised name, or
+// ignore them.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AnonymousAddresses {
+    #[default]
+    Keep,
+    Collapse,
+    Ignore,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct IdentityConfig {
+    pub name: String,
+    pub irk: String,
+}
+
+// WatchdogConfig expects matching devices (by name or id, as in routes) to advertise at least every
+// interval seconds, and reports a warning on the diagnostics topic once one misses `missed`
+// intervals in a row.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct WatchdogConfig {
+    #[serde(default)]
+    pub devices: Vec<String>,
+    pub interval: u64,
+    #[serde(default = "default_missed")]
+    pub missed: u32,
+}
+
+// AvailabilityConfig sets the availability mode of matching devices (by name or id, as in routes;
+// all devices if empty), the first matching entry winning. Devices no entry matches advertise at
+// intervals. See watchdog.rs.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct AvailabilityConfig {
+    #[serde(default)]
+    pub devices: Vec<String>,
+    pub mode: Availability,
+}
+
+// EsphomeProxyConfig is an ESPHome Bluetooth proxy whose advertisements are decoded alongside
+// the local adapter's. The password is the `api:` password, if the proxy has one.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct EsphomeProxyConfig {
+    pub name: String,
+    pub host: String,
+    #[serde(default = "default_esphome_port")]
+    pub port: u16,
+    #[serde(default)]
+    pub password: String,
+    pub password_file: Option<PathBuf>,
+    pub password_command: Option<String>,
+}
+
+// GrafanaConfig pushes readings to url, a Grafana Live push endpoint such as
+// `http://grafana:3000/api/live/push/blueplug` or Grafana Cloud's Influx endpoint, see grafana.rs.
+// token is a service account token, or `<user id>:<API key>` for Grafana Cloud. name is the sink
+// name routes refer to.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct GrafanaConfig {
+    pub name: String,
+    pub url: String,
+    pub token: Option<String>,
+    pub token_file: Option<PathBuf>,
+    pub token_command: Option<String>,
+    // Write readings in batches of up to batch_size, at least every batch_interval seconds,
+    // rather than as they arrive, see grafana.rs.
+    pub batch_size: Option<usize>,
+    pub batch_interval: Option<u64>,
+}
+
+// PostgresConfig writes readings to table in the PostgreSQL database at url, a connection string
+// such as `host=db user=blueplug dbname=blueplug` or `postgresql://blueplug@db/blueplug`, in
+// batches of up to batch_size readings at least every batch_interval seconds, see postgres.rs. The
+// password can be in url, or given as for brokers. name is the sink name routes refer to.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct PostgresConfig {
+    pub name: String,
+    pub url: String,
+    #[serde(default = "default_postgres_table")]
+    pub table: String,
+    pub password: Option<String>,
+    pub password_file: Option<PathBuf>,
+    pub password_command: Option<String>,
+    #[serde(default = "default_postgres_batch_size")]
+    pub batch_size: usize,
+    #[serde(default = "default_postgres_batch_interval")]
+    pub batch_interval: u64,
+}
+
+// ZabbixConfig sends readings to trapper items of the Zabbix server or proxy at server every
+// interval seconds. host and key are templates of the host and item key, with `{device}`,
+// `{location}`, `{kind}`, `{address}` and `{id}` replaced by the reading's; hosts overrides the
+// host of devices by name. See zabbix.rs.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ZabbixConfig {
+    pub name: String,
+    pub server: String,
+    #[serde(default = "default_zabbix_port")]
+    pub port: u16,
+    #[serde(default = "default_zabbix_host")]
+    pub host: String,
+    #[serde(default = "default_zabbix_key")]
+    pub key: String,
+    #[serde(default)]
+    pub hosts: BTreeMap<String, String>,
+    #[serde(default = "default_zabbix_interval")]
+    pub interval: u64,
+}
+
+// ExecConfig runs command, a program and its arguments, writing readings to its stdin as NDJSON
+// envelopes and restarting it whenever it exits, see exec.rs. name is the sink name routes refer
+// to.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ExecConfig {
+    pub name: String,
+    pub command: Vec<String>,
+}
+
+// SnmpConfig runs a read-only SNMPv1/v2c agent on listen, answering requests with community for
+// the latest readings under oid, see snmp.rs and mib/BLUEPLUG-MIB.txt. The default oid is in
+// Net-SNMP's experimental playpen, which suits a private network; installations with their own
+// enterprise number can move it there.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct SnmpConfig {
+    pub listen: SocketAddr,
+    #[serde(default = "default_snmp_community")]
+    pub community: String,
+    #[serde(default = "default_snmp_oid")]
+    pub oid: String,
+}
+
+// ModbusConfig serves the latest readings as Modbus TCP registers on listen, see modbus.rs.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ModbusConfig {
+    pub listen: SocketAddr,
+    #[serde(default)]
+    pub registers: Vec<RegisterConfig>,
+}
+
+// RegisterConfig puts the latest reading of kind from device, by name or id, into the registers
+// starting at address, multiplied by scale.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RegisterConfig {
+    pub address: u16,
+    pub device: String,
+    pub kind: String,
+    #[serde(rename = "type")]
+    pub r#type: RegisterType,
+    #[serde(default = "default_scale")]
+    pub scale: f64,
+}
+
+// KnxConfig writes measurements to KNX group addresses by multicasting KNXnet/IP routing
+// telegrams to multicast_address, from individual_address, see knx.rs. Routes refer to it as the
+// sink "knx".
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct KnxConfig {
+    #[serde(default = "default_knx_multicast_address")]
+    pub multicast_address: SocketAddr,
+    pub individual_address: String,
+    // Seconds between writes to the same group address.
+    #[serde(default = "default_knx_min_interval")]
+    pub min_interval: u64,
+    #[serde(default)]
+    pub mappings: Vec<KnxMapping>,
+}
+
+// KnxMapping writes the readings of kind from device, by name or id, to group_address, a
+// three-level group address such as "3/1/10", as the datapoint type dpt, such as "9.001".
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct KnxMapping {
+    pub device: String,
+    pub kind: String,
+    pub group_address: String,
+    pub dpt: Dpt,
+}
+
+// MdnsConfig advertises the HTTP listener by mDNS as a `_blueplug._tcp` service named name, by
+// default "blueplug on <host name>", see mdns.rs.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct MdnsConfig {
+    pub name: Option<String>,
+}
+
+// GrpcConfig serves the gRPC API of proto/blueplug.proto on listen, see grpc.rs. Routes refer to
+// it as the sink "grpc", which limits what SubscribeReadings streams.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct GrpcConfig {
+    pub listen: SocketAddr,
+}
+
+// SocketConfig streams readings as NDJSON envelopes to the clients of a Unix domain socket at
+// path, created with the permissions mode, see socket.rs. Routes refer to it as the sink
+// "socket".
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct SocketConfig {
+    pub path: PathBuf,
+    #[serde(default = "default_socket_mode")]
+    pub mode: u32,
+}
+
+// GattConfig is a device polled over a GATT connection every poll_interval seconds, found by its
+// address among the devices the scan came across. A device that doesn't advertise, or only
+// rarely, say while it sleeps, is added to the adapter by address and address_type instead, see
+// gatt.rs.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct GattConfig {
+    pub name: String,
+    pub address: String,
+    pub protocol: GattProtocol,
+    #[serde(default = "default_poll_interval")]
+    pub poll_interval: u64,
+    // Seconds connecting, and each exchange with the device, may take.
+    #[serde(default = "default_gatt_timeout")]
+    pub timeout: u64,
+    // How many times a failed poll or connection is retried, backing off in between, before
+    // waiting for the next poll_interval.
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+    #[serde(default = "default_advertises")]
+    pub advertises: bool,
+    #[serde(default)]
+    pub address_type: AddressType,
+    // Whether the device requires bonding, done once with `blueplug pair`.
+    #[serde(default)]
+    pub bonded: bool,
+    // What the characteristics protocol reads.
+    #[serde(default)]
+    pub characteristics: Vec<CharacteristicConfig>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AddressType {
+    #[default]
+    Public,
+    Random,
+}
+
+// CharacteristicConfig reads a value of format from the characteristic uuid, multiplied by scale,
+// as a reading of kind in unit. Give a built-in kind its unit, e.g. "°C" for temperature, to have
+// it treated as that kind.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct CharacteristicConfig {
+    pub uuid: Uuid,
+    pub kind: String,
+    #[serde(default)]
+    pub unit: String,
+    pub format: ValueFormat,
+    #[serde(default = "default_scale")]
+    pub scale: f64,
+}
+
+// GattProtocol is how to talk to a GATT device. `renogy` reads Renogy solar charge controllers
+// through their BT-1 or BT-2 Bluetooth module, and `airthings_wave_plus` reads radon, CO2, VOC,
+// temperature, humidity and pressure from an Airthings Wave Plus. `heart_rate`, `cycling_power` and
+// `weight_scale` are standard profiles, and `bm2` the BM2 car battery monitor; these devices stay
+// connected and report every notification, and are reconnected the way a failed poll is retried
+// when they drop out. `characteristics` reads the characteristics configured for the device.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum GattProtocol {
+    Renogy,
+    AirthingsWavePlus,
+    HeartRate,
+    CyclingPower,
+    WeightScale,
+    Bm2,
+    Characteristics,
+}
+
+// CommandsConfig subscribes to `<topic_prefix>/<action>` on the named broker, running the action
+// with the parameters in the message, a JSON object, and reporting the outcome on
+// `<response_topic_prefix>/<action>`. See actions.rs.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct CommandsConfig {
+    pub broker: String,
+    #[serde(default = "default_command_topic_prefix")]
+    pub topic_prefix: String,
+    #[serde(default = "default_response_topic_prefix")]
+    pub response_topic_prefix: String,
+}
+
+// ActionConfig writes payload to the characteristic uuid of the [[gatt]] device named device.
+// payload is hex, with `{name:format}` placeholders filled in with the command's parameter name as
+// a value of format, e.g. "02{interval:u16}".
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ActionConfig {
+    pub name: String,
+    pub device: String,
+    pub characteristic: Uuid,
+    pub payload: String,
+    // Whether the device acknowledges the write.
+    #[serde(default = "default_with_response")]
+    pub with_response: bool,
+}
+
+// IngestConfig accepts advertisements from `blueplug forward` nodes, over HTTP at
+// `http://<listen>/forward` and/or by subscribing to `<topic_prefix>/+` on the named broker.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct IngestConfig {
+    pub listen: Option<SocketAddr>,
+    pub broker: Option<String>,
+    pub topic_prefix: Option<String>,
+}
+
+impl EsphomeProxyConfig {
+    // from_address takes `host` or `host:port`, naming the proxy after its host.
+    pub fn from_address(address: &str) -> Result<Self> {
+        let (host, port) = match address.rsplit_once(':') {
+            Some((host, port)) => (
+                host,
+                port.parse()
+                    .wrap_err_with(|| format!("invalid port in {:?}", address))?,
+            ),
+            None => (address, default_esphome_port()),
+        };
+        Ok(EsphomeProxyConfig {
+            name: host.to_string(),
+            host: host.to_string(),
+            port,
+            password: String::new(),
+            password_file: None,
+            password_command: None,
+        })
+    }
+}
+
+impl BrokerConfig {
+    // validate_payload_format checks that a protobuf payload has readings to type, which only
+    // the envelope format has a schema for.
+    pub fn validate_payload_format(&self) -> Result<()> {
+        if self.payload_format == PayloadFormat::Protobuf && self.format != OutputFormat::Envelope {
+            return Err(eyre!(
+                "broker {:?}: the protobuf payload format needs the envelope format",
+                self.name
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn new(name: &str, host: String, port: u16, client_id: String) -> Self {
+        BrokerConfig {
+            name: name.to_string(),
+            host,
+            port,
+            client_id,
+            username: None,
+            password: None,
+            password_file: None,
+            password_command: None,
+            tls: None,
+            format: OutputFormat::default(),
+            payload_format: PayloadFormat::default(),
+            topic_prefix: None,
+            qos: default_qos(),
+            alert_qos: None,
+            retain: false,
+            telemetry_topic: None,
+            diagnostics_topic: default_diagnostics_topic(),
+            keep_alive: default_keep_alive(),
+            max_packet_size: default_max_packet_size(),
+            persistent_session: false,
+            queue_file: None,
+            queue_file_max_size: default_queue_file_max_size(),
+            homeassistant: None,
+            batch_interval: None,
+            rate_limit: None,
+        }
+    }
+
+    pub fn topic_prefix(&self) -> &str {
+        match (&self.topic_prefix, self.format) {
+            (Some(prefix), _) => prefix,
+            (None, OutputFormat::Envelope | OutputFormat::Flat) => "device_reading",
+            (None, OutputFormat::Theengs) => crate::theengs::DEFAULT_TOPIC_PREFIX,
+        }
+    }
+}
+
+fn default_port() -> u16 {
+    1883
+}
+
+fn default_diagnostics_topic() -> String {
+    crate::diagnostics::DEFAULT_TOPIC.to_string()
+}
+
+fn default_stats_topic_prefix() -> String {
+    "blueplug".to_string()
+}
+
+fn default_presence_topic_prefix() -> String {
+    "blueplug/presence".to_string()
+}
+
+fn default_away_after() -> u64 {
+    180
+}
+
+fn default_missed() -> u32 {
+    3
+}
+
+fn default_poll_interval() -> u64 {
+    60
+}
+
+fn default_command_topic_prefix() -> String {
+    "blueplug/command".to_string()
+}
+
+fn default_response_topic_prefix() -> String {
+    "blueplug/response".to_string()
+}
+
+fn default_with_response() -> bool {
+    true
+}
+
+fn default_gatt_timeout() -> u64 {
+    15
+}
+
+fn default_retries() -> u32 {
+    2
+}
+
+fn default_advertises() -> bool {
+    true
+}
+
+fn default_esphome_port() -> u16 {
+    6053
+}
+
+fn default_qos() -> u8 {
+    1
+}
+
+fn default_discovery_prefix() -> String {
+    "homeassistant".to_string()
+}
+
+fn default_keep_alive() -> u64 {
+    5
+}
+
+// rumqttc's own default.
+fn default_max_packet_size() -> usize {
+    10 * 1024
+}
+
+fn default_knx_multicast_address() -> SocketAddr {
+    SocketAddr::from(([224, 0, 23, 12], 3671))
+}
+
+fn default_socket_mode() -> u32 {
+    0o660
+}
+
+fn default_knx_min_interval() -> u64 {
+    30
+}
+
+fn default_scale() -> f64 {
+    1.0
+}
+
+fn default_snmp_community() -> String {
+    "public".to_string()
+}
+
+fn default_snmp_oid() -> String {
+    "1.3.6.1.4.1.8072.9999.9999.1".to_string()
+}
+
+fn default_zabbix_port() -> u16 {
+    10051
+}
+
+fn default_zabbix_host() -> String {
+    "{device}".to_string()
+}
+
+fn default_zabbix_key() -> String {
+    "blueplug[{kind}]".to_string()
+}
+
+fn default_postgres_table() -> String {
+    "readings".to_string()
+}
+
+fn default_postgres_batch_size() -> usize {
+    1000
+}
+
+fn default_postgres_batch_interval() -> u64 {
+    10
+}
+
+fn default_zabbix_interval() -> u64 {
+    10
+}
+
+fn default_archive_interval() -> u64 {
+    3600
+}
+
+fn default_queue_file_max_size() -> u64 {
+    16 * 1024 * 1024
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, BlueplugError> {
+        let load = || -> Result<Self> {
+            let text = std::fs::read_to_string(path)
+                .wrap_err_with(|| format!("reading {}", path.display()))?;
+            let mut config =
+                Self::from_toml(&text).wrap_err_with(|| format!("in {}", path.display()))?;
+            config
+                .read_secrets()
+                .wrap_err_with(|| format!("in {}", path.display()))?;
+            if let Some(directory) = path.parent() {
+                for script in &mut config.scripts {
+                    *script = directory.join(&*script);
+                }
+            }
+            Ok(config)
+        };
+        load().map_err(|e| BlueplugError::Config(e.into()))
+    }
+
+    // read_secrets fills in the passwords and tokens given as a file or a command.
+    fn read_secrets(&mut self) -> Result<()> {
+        for broker in &mut self.brokers {
+            let password = read_secret(
+                broker.password_file.as_deref(),
+                broker.password_command.as_deref(),
+            )
+            .wrap_err_with(|| format!("broker {:?}: password", broker.name))?;
+            if password.is_some() {
+                broker.password = password;
+            }
+        }
+        for proxy in &mut self.esphome_proxies {
+            let password = read_secret(
+                proxy.password_file.as_deref(),
+                proxy.password_command.as_deref(),
+            )
+            .wrap_err_with(|| format!("ESPHome proxy {:?}: password", proxy.name))?;
+            if let Some(password) = password {
+                proxy.password = password;
+            }
+        }
+        for grafana in &mut self.grafana {
+            let token = read_secret(
+                grafana.token_file.as_deref(),
+                grafana.token_command.as_deref(),
+            )
+            .wrap_err_with(|| format!("grafana {:?}: token", grafana.name))?;
+            if token.is_some() {
+                grafana.token = token;
+            }
+        }
+        for postgres in &mut self.postgres {
+            let password = read_secret(
+                postgres.password_file.as_deref(),
+                postgres.password_command.as_deref(),
+            )
+            .wrap_err_with(|| format!("postgres {:?}: password", postgres.name))?;
+            if password.is_some() {
+                postgres.password = password;
+            }
+        }
+        Ok(())
+    }
+
+    // has_other_sinks tells whether readings go anywhere besides the MQTT brokers.
+    pub fn has_other_sinks(&self) -> bool {
+        self.archive.is_some()
+            || !self.grafana.is_empty()
+            || !self.postgres.is_empty()
+            || !self.zabbix.is_empty()
+            || !self.exec.is_empty()
+            || self.snmp.is_some()
+            || self.modbus.is_some()
+            || self.knx.is_some()
+            || self.grpc.is_some()
+            || self.socket.is_some()
+    }
+
+    pub fn parse(text: &str) -> Result<Self, BlueplugError> {
+        Self::from_toml(text).map_err(|e| BlueplugError::Config(e.into()))
+    }
+
+    fn from_toml(text: &str) -> Result<Self> {
+        let config: Config = toml::from_str(text)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<()> {
+        for entry in &self.labels {
+            for (key, value) in &entry.labels {
+                let valid = key.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+                    && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+                    && !key.starts_with("__");
+                if !valid || RESERVED_LABELS.contains(&key.as_str()) {
+                    return Err(eyre!(
+                        "invalid label {:?}, labels must be letters, digits and _, not start \
+                         with a digit or __, and not be one of {}",
+                        key,
+                        RESERVED_LABELS.join(", ")
+                    ));
+                }
+                // Grafana drops tags with empty values, and Prometheus treats them as unset.
+                if value.is_empty() {
+                    return Err(eyre!("label {:?} has an empty value", key));
+                }
+            }
+        }
+        for (location, devices) in &self.locations {
+            if devices.is_empty() {
+                return Err(eyre!("location {:?} lists no devices", location));
+            }
+        }
+        for (i, broker) in self.brokers.iter().enumerate() {
+            if self.brokers[..i].iter().any(|b| b.name == broker.name) {
+                return Err(eyre!("duplicate broker name {:?}", broker.name));
+            }
+            let passwords = [
+                broker.password.is_some(),
+                broker.password_file.is_some(),
+                broker.password_command.is_some(),
+            ];
+            if passwords.into_iter().filter(|given| *given).count() > 1 {
+                return Err(eyre!(
+                    "broker {:?}: only one of password, password_file and password_command can be \
+                     given",
+                    broker.name
+                ));
+            }
+            if broker.qos > 2 {
+                return Err(eyre!(
+                    "broker {:?}: qos must be 0, 1 or 2, not {}",
+                    broker.name,
+                    broker.qos
+                ));
+            }
+            if let Some(alert_qos) = broker.alert_qos.filter(|qos| *qos > 2) {
+                return Err(eyre!(
+                    "broker {:?}: alert_qos must be 0, 1 or 2, not {}",
+                    broker.name,
+                    alert_qos
+                ));
+            }
+            broker.validate_payload_format()?;
+            if broker.max_packet_size < 1024 {
+                return Err(eyre!(
+                    "broker {:?}: max_packet_size must be at least 1024",
+                    broker.name
+                ));
+            }
+            if let Some(homeassistant) = &broker.homeassistant {
+                if broker.format == OutputFormat::Theengs
+                    || broker.payload_format != PayloadFormat::Json
+                {
+                    return Err(eyre!(
+                        "broker {:?}: homeassistant needs the envelope or flat format, as JSON",
+                        broker.name
+                    ));
+                }
+                validate_topic(&homeassistant.discovery_prefix)
+                    .wrap_err_with(|| format!("broker {:?}", broker.name))?;
+            }
+            if let Some(batch_interval) = broker.batch_interval {
+                if batch_interval == 0 {
+                    return Err(eyre!(
+                        "broker {:?}: batch_interval must be at least 1",
+                        broker.name
+                    ));
+                }
+                // Theengs payloads are already one per device, and Home Assistant expects one
+                // reading per state topic.
+                if broker.format == OutputFormat::Theengs || broker.homeassistant.is_some() {
+                    return Err(eyre!(
+                        "broker {:?}: batch_interval can't be combined with the theengs format or \
+                         homeassistant",
+                        broker.name
+                    ));
+                }
+                // A batch is already one message per device per interval, and the rate limit
+                // would only see readings as they join it.
+                if broker.rate_limit.is_some() {
+                    return Err(eyre!(
+                        "broker {:?}: batch_interval can't be combined with rate_limit",
+                        broker.name
+                    ));
+                }
+            }
+            if let Some(limit) = &broker.rate_limit {
+                let rates = [limit.rate, limit.device_rate];
+                if rates.into_iter().flatten().any(|rate| rate <= 0.0) {
+                    return Err(eyre!(
+                        "broker {:?}: rate_limit rates must be positive",
+                        broker.name
+                    ));
+                }
+                let bursts = [limit.burst, limit.device_burst];
+                if bursts.into_iter().flatten().any(|burst| burst < 1.0) {
+                    return Err(eyre!(
+                        "broker {:?}: rate_limit bursts must be at least 1",
+                        broker.name
+                    ));
+                }
+                if limit.rate.is_none() && limit.device_rate.is_none() {
+                    return Err(eyre!(
+                        "broker {:?}: rate_limit needs rate or device_rate",
+                        broker.name
+                    ));
+                }
+            }
+            if broker.queue_file.is_some() && broker.qos == 0 {
+                return Err(eyre!(
+                    "broker {:?}: queue_file needs qos 1 or 2, QoS 0 is never acknowledged",
+                    broker.name
+                ));
+            }
+            let topics = [
+                broker.topic_prefix.as_deref(),
+                broker.telemetry_topic.as_deref(),
+                Some(broker.diagnostics_topic.as_str()).filter(|t| !t.is_empty()),
+            ];
+            for topic in topics.into_iter().flatten() {
+                validate_topic(topic).wrap_err_with(|| format!("broker {:?}", broker.name))?;
+            }
+            if let Some(tls) = &broker.tls {
+                if tls.client_cert.is_some() != tls.client_key.is_some() {
+                    return Err(eyre!(
+                        "broker {:?}: client_cert and client_key must be given together",
+                        broker.name
+                    ));
+                }
+            }
+        }
+        for (i, proxy) in self.esphome_proxies.iter().enumerate() {
+            if self.esphome_proxies[..i]
+                .iter()
+                .any(|p| p.name == proxy.name)
+            {
+                return Err(eyre!("duplicate ESPHome proxy name {:?}", proxy.name));
+            }
+            let passwords = [
+                !proxy.password.is_empty(),
+                proxy.password_file.is_some(),
+                proxy.password_command.is_some(),
+            ];
+            if passwords.into_iter().filter(|given| *given).count() > 1 {
+                return Err(eyre!(
+                    "ESPHome proxy {:?}: only one of password, password_file and password_command \
+                     can be given",
+                    proxy.name
+                ));
+            }
+        }
+        for grafana in &self.grafana {
+            let tokens = [
+                grafana.token.is_some(),
+                grafana.token_file.is_some(),
+                grafana.token_command.is_some(),
+            ];
+            if tokens.into_iter().filter(|given| *given).count() > 1 {
+                return Err(eyre!(
+                    "grafana {:?}: only one of token, token_file and token_command can be given",
+                    grafana.name
+                ));
+            }
+            if grafana.batch_size == Some(0) || grafana.batch_interval == Some(0) {
+                return Err(eyre!(
+                    "grafana {:?}: batch_size and batch_interval must be at least 1",
+                    grafana.name
+                ));
+            }
+        }
+        for postgres in &self.postgres {
+            postgres
+                .url
+                .parse::<tokio_postgres::Config>()
+                .wrap_err_with(|| format!("postgres {:?}: url", postgres.name))?;
+            // table is put into SQL as it is, so it must be a plain name, with a schema or not.
+            let identifier = |name: &str| {
+                name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+                    && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+            };
+            if !postgres.table.split('.').all(identifier) || postgres.table.split('.').count() > 2 {
+                return Err(eyre!(
+                    "postgres {:?}: invalid table {:?}, expected a name such as readings or \
+                     blueplug.readings",
+                    postgres.name,
+                    postgres.table
+                ));
+            }
+            let passwords = [
+                postgres.password.is_some(),
+                postgres.password_file.is_some(),
+                postgres.password_command.is_some(),
+            ];
+            if passwords.into_iter().filter(|given| *given).count() > 1 {
+                return Err(eyre!(
+                    "postgres {:?}: only one of password, password_file and password_command can \
+                     be given",
+                    postgres.name
+                ));
+            }
+            if postgres.batch_size == 0 || postgres.batch_interval == 0 {
+                return Err(eyre!(
+                    "postgres {:?}: batch_size and batch_interval must be at least 1",
+                    postgres.name
+                ));
+            }
+        }
+        for zabbix in &self.zabbix {
+            if zabbix.interval == 0 {
+                return Err(eyre!(
+                    "zabbix {:?}: interval must be at least 1",
+                    zabbix.name
+                ));
+            }
+        }
+        for exec in &self.exec {
+            if exec.command.is_empty() {
+                return Err(eyre!("exec {:?}: command is empty", exec.name));
+            }
+        }
+        if self.gatt_connections == Some(0) {
+            return Err(eyre!("gatt_connections must be at least 1"));
+        }
+        for device in &self.gatt {
+            if device.address.len() != 17 || device.address.split(':').count() != 6 {
+                return Err(eyre!(
+                    "gatt device {:?}: address must be a MAC address such as A4:C1:38:8F:2C:1A",
+                    device.name
+                ));
+            }
+            if device.poll_interval == 0 {
+                return Err(eyre!(
+                    "gatt device {:?}: poll_interval must be at least 1",
+                    device.name
+                ));
+            }
+            if device.timeout == 0 {
+                return Err(eyre!(
+                    "gatt device {:?}: timeout must be at least 1",
+                    device.name
+                ));
+            }
+            let reads_characteristics = device.protocol == GattProtocol::Characteristics;
+            let problem = match (reads_characteristics, device.characteristics.is_empty()) {
+                (true, true) => "the characteristics protocol needs characteristics",
+                (false, false) => "characteristics are only read by the characteristics protocol",
+                _ => "",
+            };
+            if !problem.is_empty() {
+                return Err(eyre!("gatt device {:?}: {}", device.name, problem));
+            }
+        }
+        for device in self.presence.iter().flat_map(|p| &p.devices) {
+            if device.address.is_some() == device.identity.is_some() {
+                return Err(eyre!(
+                    "presence device {:?}: exactly one of address and identity must be given",
+                    device.name
+                ));
+            }
+            if let Some(identity) = &device.identity {
+                if !self.rpa.identities.iter().any(|i| &i.name == identity) {
+                    return Err(eyre!(
+                        "presence device {:?} refers to unknown identity {:?}",
+                        device.name,
+                        identity
+                    ));
+                }
+            }
+        }
+        for rule in &self.rules {
+            if rule.conditions.is_empty() {
+                return Err(eyre!("rule {:?}: conditions are empty", rule.name));
+            }
+            for condition in &rule.conditions {
+                if condition.above.is_none() && condition.below.is_none() {
+                    return Err(eyre!(
+                        "rule {:?}: a condition on {:?} needs above or below",
+                        rule.name,
+                        condition.kind
+                    ));
+                }
+                if condition.hysteresis < 0.0 {
+                    return Err(eyre!("rule {:?}: hysteresis can't be negative", rule.name));
+                }
+            }
+            for broker in &rule.brokers {
+                if !self.brokers.iter().any(|b| &b.name == broker) {
+                    return Err(eyre!(
+                        "rule {:?} refers to unknown broker {:?}",
+                        rule.name,
+                        broker
+                    ));
+                }
+            }
+            validate_topic(&rule.topic).wrap_err_with(|| format!("rule {:?}", rule.name))?;
+        }
+        if let Some(drain) = &self.battery_drain {
+            if drain.window <= 0.0 || drain.min_days < 0.0 || drain.min_days > drain.window {
+                return Err(eyre!(
+                    "battery_drain: window must be positive and min_days between 0 and window"
+                ));
+            }
+        }
+        for motion in &self.motion {
+            if motion.threshold <= 0.0 {
+                return Err(eyre!(
+                    "motion of {:?}: threshold must be positive",
+                    motion.devices
+                ));
+            }
+        }
+        if self
+            .archive
+            .as_ref()
+            .is_some_and(|archive| archive.interval == 0)
+        {
+            return Err(eyre!("archive: interval must be at least 1"));
+        }
+        for mapping in &self.mappings {
+            if mapping.rename.is_some() == mapping.ignore {
+                return Err(eyre!(
+                    "mapping of {:?}: exactly one of rename and ignore must be given",
+                    mapping.kind
+                ));
+            }
+        }
+        if !self.actions.is_empty() && self.commands.is_none() {
+            return Err(eyre!("actions need [commands] to be triggered"));
+        }
+        for (i, action) in self.actions.iter().enumerate() {
+            if self.actions[..i].iter().any(|a| a.name == action.name) {
+                return Err(eyre!("duplicate action name {:?}", action.name));
+            }
+            if action.name.is_empty() || action.name.contains(['/', '+', '#']) {
+                return Err(eyre!(
+                    "action {:?}: name must be non-empty and not contain / + or #",
+                    action.name
+                ));
+            }
+            if !self.gatt.iter().any(|device| device.name == action.device) {
+                return Err(eyre!(
+                    "action {:?} refers to unknown gatt device {:?}",
+                    action.name,
+                    action.device
+                ));
+            }
+        }
+        // Responses published under the topic commands are subscribed to would be taken for
+        // commands themselves.
+        if let Some(commands) = &self.commands {
+            if commands.topic_prefix.trim_end_matches('/')
+                == commands.response_topic_prefix.trim_end_matches('/')
+            {
+                return Err(eyre!(
+                    "[commands] topic_prefix and response_topic_prefix must differ, both are {:?}",
+                    commands.topic_prefix
+                ));
+            }
+        }
+        let topics = [
+            self.ingest.topic_prefix.as_deref(),
+            self.commands.as_ref().map(|c| c.topic_prefix.as_str()),
+            self.commands
+                .as_ref()
+                .map(|c| c.response_topic_prefix.as_str()),
+            self.stats.as_ref().map(|s| s.topic_prefix.as_str()),
+            self.presence.as_ref().map(|p| p.topic_prefix.as_str()),
+        ];
+        for topic in topics.into_iter().flatten() {
+            validate_topic(topic)?;
+        }
+        Ok(())
+    }
+
+    // check runs the checks that otherwise only happen as blueplug starts up, such as parsing
+    // derived expressions, so that `check-config` and `doctor` catch them too.
+    pub fn check(&self) -> Result<(), BlueplugError> {
+        let check = || -> Result<()> {
+            Derivations::new(&self.derived)?;
+            Scripts::new(&self.scripts)?;
+            Resolver::new(&self.rpa)?;
+            Chatter::new(&self.chatter)?;
+            NameRules::new(&self.names)?;
+            BatteryCurves::new(&self.battery)?;
+            Actions::new(&self.actions)?;
+            if let Some(keys_file) = &self.keys_file {
+                KeyStore::load(keys_file)?;
+            }
+            if let Some(stats) = &self.stats {
+                DailyStats::new(stats, self.time_zone.as_deref())?;
+            }
+            if let Some(snmp) = &self.snmp {
+                parse_oid(&snmp.oid)?;
+            }
+            if let Some(modbus) = &self.modbus {
+                validate_registers(&modbus.registers)?;
+            }
+            if let Some(knx) = &self.knx {
+                Knx::new(knx)?;
+            }
+            if let Some(archive) = &self.archive {
+                Archive::new(archive, self.time_zone.as_deref())?;
+            }
+            Ok(())
+        };
+        check().map_err(|e| BlueplugError::Config(e.into()))
+    }
+}
+
+// validate_topic rejects topics that can't be published to.
+fn validate_topic(topic: &str) -> Result<()> {
+    if topic.is_empty() || topic.contains(['+', '#', '\0']) {
+        return Err(eyre!(
+            "invalid topic {:?}, topics must be non-empty and not contain + # or NUL",
+            topic
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{Config, EsphomeProxyConfig, OutputFormat, EXAMPLE};
+
+    #[test]
+    fn test_parse_brokers() {
+        let config = Config::parse(
+            r#"
+            [[brokers]]
+            name = "local"
+            host = "localhost"
+            client_id = "blueplug"
+
+            [[brokers]]
+            name = "cloud"
+            host = "mqtt.example.com"
+            port = 8883
+            client_id = "blueplug-home"
+            qos = 0
+            topic_prefix = "home/sensors"
+            tls = {}
+
+            [[brokers]]
+            name = "gateway"
+            host = "localhost"
+            client_id = "blueplug-omg"
+            format = "theengs"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.brokers.len(), 3);
+        assert_eq!(config.brokers[0].port, 1883);
+        assert_eq!(config.brokers[0].topic_prefix(), "device_reading");
+        assert!(config.brokers[0].tls.is_none());
+        assert_eq!(config.brokers[1].qos, 0);
+        assert!(config.brokers[1].tls.is_some());
+        assert_eq!(config.brokers[1].topic_prefix(), "home/sensors");
+        assert_eq!(config.brokers[2].format, OutputFormat::Theengs);
+        assert_eq!(config.brokers[2].topic_prefix(), "home/OMG/BTtoMQTT");
+        assert!(config.routes.is_empty());
+    }
+
+    #[test]
+    fn test_reject_invalid_brokers() {
+        assert!(Config::parse(
+            "[[brokers]]\nname = \"a\"\nhost = \"h\"\nclient_id = \"c\"\nqos = 3"
+        )
+        .is_err());
+        assert!(Config::parse("[[brokers]]\nname = \"a\"\nhost = \"h\"").is_err());
+        assert!(Config::parse("bogus = 1").is_err());
+        assert!(Config::parse("[archive]\ndirectory = \"/tmp\"\ninterval = 0").is_err());
+        assert!(Config::parse("[[labels]]\nlabels = { site = \"\" }").is_err());
+        assert!(Config::parse("[[labels]]\nlabels = { device = \"a\" }").is_err());
+        let commands = "[commands]\nbroker = \"a\"\ntopic_prefix = \"home/commands\"\n";
+        assert!(Config::parse(&format!(
+            "{}response_topic_prefix = \"home/commands\"",
+            commands
+        ))
+        .is_err());
+        let lock =
+            "[[gatt]]\nname = \"lock\"\naddress = \"A4:C1:38:12:34:56\"\nprotocol = \"bm2\"\n";
+        let action = "[[actions]]\nname = \"open\"\ndevice = \"lock\"\npayload = \"01\"\n\
+                      characteristic = \"0000fff1-0000-1000-8000-00805f9b34fb\"\n";
+        assert!(Config::parse(&format!("{}{}{}", commands, lock, action)).is_ok());
+        assert!(Config::parse(&format!("{}{}{}{}", commands, lock, action, action)).is_err());
+        assert!(Config::parse(
+            "[[brokers]]\nname = \"a\"\nhost = \"h\"\nclient_id = \"c\"\ntopic_prefix = \"home/#\""
+        )
+        .is_err());
+        let batched =
+            "[[brokers]]\nname = \"a\"\nhost = \"h\"\nclient_id = \"c\"\nbatch_interval = 30\n";
+        assert!(Config::parse(batched).is_ok());
+        assert!(Config::parse(&format!("{}rate_limit = {{ rate = 20 }}", batched)).is_err());
+        let grafana = "[[grafana]]\nname = \"g\"\nurl = \"http://grafana\"\ntoken = \"t\"\n";
+        assert!(Config::parse(grafana).is_ok());
+        assert!(Config::parse(&format!("{}token_command = \"pass grafana\"", grafana)).is_err());
+        let postgres = "[[postgres]]\nname = \"db\"\nurl = \"host=db user=blueplug\"\n";
+        assert!(Config::parse(&format!("{}table = \"blueplug.readings\"", postgres)).is_ok());
+        assert!(Config::parse(&format!("{}table = \"readings; DROP\"", postgres)).is_err());
+        assert!(Config::parse(&format!("{}batch_size = 0", postgres)).is_err());
+        // Expressions are only parsed by check.
+        let config = Config::parse("[derived]\nvpd = \"temperature *\"").unwrap();
+        assert!(config.check().is_err());
+    }
+
+    #[test]
+    fn test_example() {
+        Config::parse(EXAMPLE).unwrap();
+        // With every optional setting enabled.
+        let enabled: Vec<_> = EXAMPLE
+            .lines()
+            .map(|line| match line.strip_prefix('#') {
+                Some(setting) if !setting.is_empty() && !setting.starts_with(' ') => setting,
+                _ => line,
+            })
+            .collect();
+        let config = Config::parse(&enabled.join("\n")).unwrap();
+        assert!(config.presence.is_some());
+        assert_eq!(config.esphome_proxies.len(), 1);
+    }
+
+    #[test]
+    fn test_esphome_proxies() {
+        let config = Config::parse(
+            "[[esphome_proxies]]\nname = \"garage\"\nhost = \"garage.local\"\npassword = \"p\"",
+        )
+        .unwrap();
+        assert_eq!(config.esphome_proxies[0].port, 6053);
+
+        let proxy = EsphomeProxyConfig::from_address("10.0.0.5:6054").unwrap();
+        assert_eq!((proxy.host.as_str(), proxy.port), ("10.0.0.5", 6054));
+        assert_eq!(
+            EsphomeProxyConfig::from_address("attic").unwrap().port,
+            6053
+        );
+        assert!(EsphomeProxyConfig::from_address("attic:api").is_err());
+    }
+}
@@ -0,0 +1,293 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, Instant};
+
+use async_stream::stream;
+use btsensor::bthome::v2::{BtHomeV2, Element};
+use color_eyre::eyre::Result;
+use futures_core::stream::Stream;
+use ruuvi_sensor_protocol::{MacAddress, MeasurementSequenceNumber, SensorValues};
+
+use crate::error::BlueplugError;
+use crate::exempt::EXEMPT;
+use crate::metrics::{Stage, METRICS};
+use crate::{builtin_measurements, DeviceEvent};
+
+// How many recent sequence numbers to remember per device. More than one tolerates the same
+// frame arriving slightly out of order via several adapters.
+const RECENT_SEQUENCES: usize = 8;
+
+// How long a device's counters are remembered after it last advertised one. This is separate
+// from the dedup window, which may be zero to disable matching identical payloads.
+const SEQUENCE_TTL: Duration = Duration::from_secs(60);
+
+// How often entries that have expired are dropped, unless the window is longer.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+// Deduplicator remembers which (device, payload) pairs were seen recently so that the same
+// advertisement repeated many times per interval only produces one set of readings. Frames that
+// carry a packet/measurement counter (Ruuvi RAWv2, BTHome v2) are instead matched exactly on
+// that counter, for as long as the device keeps advertising within SEQUENCE_TTL. Repeats of an
+// advertisement decoding to an exempt kind (see exempt.rs) are let through within the window, so
+// a button pressed twice in a row isn't lost; a repeated counter is still the same frame.
+pub struct Deduplicator {
+    window: Duration,
+    exempt: Vec<String>,
+    seen: HashMap<(String, u64), Instant>,
+    // The recent counters of each device, and when it last advertised one.
+    sequences: HashMap<String, (Instant, VecDeque<u32>)>,
+    last_prune: Instant,
+}
+
+impl Deduplicator {
+    pub fn new(window: Duration) -> Self {
+        Deduplicator {
+            window,
+            exempt: EXEMPT.kinds(),
+            seen: HashMap::new(),
+            sequences: HashMap::new(),
+            last_prune: Instant::now(),
+        }
+    }
+
+    // Returns true if the event hasn't been seen within the window and should be processed.
+    pub fn check(&mut self, event: &DeviceEvent, now: Instant) -> bool {
+        // Devices come and go, and with private addresses change their address every few
+        // minutes, so what hasn't been seen for a while is forgotten.
+        if now.duration_since(self.last_prune) >= self.window.max(PRUNE_INTERVAL) {
+            let window = self.window;
+            self.seen
+                .retain(|_, seen| now.duration_since(*seen) < window);
+            self.sequences
+                .retain(|_, (seen, _)| now.duration_since(*seen) < SEQUENCE_TTL);
+            self.last_prune = now;
+        }
+
+        if let Some((key, sequence)) = sequence_number(event) {
+            let (seen, recent) = self
+                .sequences
+                .entry(key)
+                .or_insert_with(|| (now, VecDeque::new()));
+            *seen = now;
+            if recent.contains(&sequence) {
+                return false;
+            }
+            if recent.len() == RECENT_SEQUENCES {
+                recent.pop_front();
+            }
+            recent.push_back(sequence);
+            return true;
+        }
+
+        if self.window.is_zero() {
+            return true;
+        }
+
+        let key = (event.device_id().id.clone(), payload_hash(event));
+        match self.seen.get(&key) {
+            Some(seen) if now.duration_since(*seen) < self.window => self.is_exempt(event),
+            _ => {
+                self.seen.insert(key, now);
+                true
+            }
+        }
+    }
+}
+
+impl Deduplicator {
+    // is_exempt tells whether the built-in decoders find an exempt kind in the advertisement.
+    // Kinds that only plugins decode, or mappings rename to, aren't recognized.
+    fn is_exempt(&self, event: &DeviceEvent) -> bool {
+        !self.exempt.is_empty()
+            && builtin_measurements(event)
+                .iter()
+                .any(|measurement| self.exempt.iter().any(|kind| kind == measurement.kind()))
+    }
+}
+
+// sequence_number extracts a per-device frame counter, keyed on the most stable identity
+// available: the MAC embedded in Ruuvi frames, otherwise the advertised address.
+fn sequence_number(event: &DeviceEvent) -> Option<(String, u32)> {
+    match event {
+        DeviceEvent::ManufacturerDataAdvertisement {
+            device_id,
+            manufacturer_data,
+            ..
+        } => manufacturer_data.iter().find_map(|(id, data)| {
+            let parsed = SensorValues::from_manufacturer_specific_data(*id, data).ok()?;
+            let sequence = parsed.measurement_sequence_number()?;
+            let key = match parsed.mac_address() {
+                Some(mac) => format!("ruuvi/{}", hex(&mac)),
+                None => format!("ruuvi/{}", device_id.address),
+            };
+            Some((key, sequence))
+        }),
+        DeviceEvent::ServiceDataAdvertisement {
+            device_id,
+            service_data,
+            ..
+        } => {
+            let data = service_data.get(&btsensor::bthome::v2::UUID)?;
+            if data.is_empty() {
+                return None;
+            }
+            let decoded = BtHomeV2::decode(data).ok()?;
+            decoded.elements.iter().find_map(|e| match e {
+                Element::PacketId(id) => {
+                    Some((format!("bthome/{}", device_id.address), *id as u32))
+                }
+                _ => None,
+            })
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// HashMap iteration order is unspecified, so entries are sorted before hashing to make identical
+// payloads hash identically.
+fn payload_hash(event: &DeviceEvent) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    match event {
+        DeviceEvent::ManufacturerDataAdvertisement {
+            manufacturer_data, ..
+        } => {
+            0u8.hash(&mut hasher);
+            let mut entries: Vec<_> = manufacturer_data.iter().collect();
+            entries.sort();
+            entries.hash(&mut hasher);
+        }
+        DeviceEvent::ServiceDataAdvertisement { service_data, .. } => {
+            1u8.hash(&mut hasher);
+            let mut entries: Vec<_> = service_data.iter().collect();
+            entries.sort();
+            entries.hash(&mut hasher);
+        }
+    }
+    hasher.finish()
+}
+
+pub fn dedup_stream(
+    event_stream: impl Stream<Item = Result<DeviceEvent, BlueplugError>>,
+    window: Duration,
+) -> impl Stream<Item = Result<DeviceEvent, BlueplugError>> {
+    stream! {
+        let mut dedup = Deduplicator::new(window);
+        for await event in event_stream {
+            match event {
+                Ok(event) if dedup.check(&event, Instant::now()) => yield Ok(event),
+                Ok(event) => METRICS.advertisement(Stage::Throttled, &event),
+                Err(e) => yield Err(e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::{Duration, Instant};
+
+    use uuid::Uuid;
+
+    use crate::dedup::Deduplicator;
+    use crate::{DeviceEvent, DeviceId};
+
+    fn device_id(name: &str) -> DeviceId {
+        DeviceId {
+            id: format!("hci0/{}", name),
+            device_name: name.to_string(),
+            address: name.to_string(),
+            random_address: None,
+        }
+    }
+
+    fn event(name: &str, payload: Vec<u8>) -> DeviceEvent {
+        DeviceEvent::ManufacturerDataAdvertisement {
+            device_id: device_id(name),
+            manufacturer_data: HashMap::from([(0xffff, payload)]),
+            rssi: None,
+        }
+    }
+
+    fn bthome(name: &str, packet_id: u8, temperature: u8) -> DeviceEvent {
+        DeviceEvent::ServiceDataAdvertisement {
+            device_id: device_id(name),
+            service_data: HashMap::from([(
+                Uuid::from_u128(0x0000fcd2_0000_1000_8000_00805f9b34fb),
+                vec![0x40, 0x00, packet_id, 0x02, temperature, 0x07],
+            )]),
+            rssi: None,
+        }
+    }
+
+    #[test]
+    fn test_dedup_within_window() {
+        let mut dedup = Deduplicator::new(Duration::from_secs(2));
+        let start = Instant::now();
+
+        assert!(dedup.check(&event("a", vec![1, 2, 3]), start));
+        assert!(!dedup.check(&event("a", vec![1, 2, 3]), start + Duration::from_secs(1)));
+        assert!(dedup.check(&event("a", vec![1, 2, 4]), start + Duration::from_secs(1)));
+        assert!(dedup.check(&event("b", vec![1, 2, 3]), start + Duration::from_secs(1)));
+        assert!(dedup.check(&event("a", vec![1, 2, 3]), start + Duration::from_secs(3)));
+    }
+
+    #[test]
+    fn test_dedup_by_sequence_number() {
+        let mut dedup = Deduplicator::new(Duration::from_secs(2));
+        let start = Instant::now();
+
+        assert!(dedup.check(&bthome("a", 1, 0xc4), start));
+        // Same counter means the same frame, even if the payload differs.
+        assert!(!dedup.check(&bthome("a", 1, 0xc5), start));
+        // A new counter is processed immediately, even inside the time window.
+        assert!(dedup.check(&bthome("a", 2, 0xc4), start));
+        assert!(dedup.check(&bthome("b", 1, 0xc4), start));
+        // A device repeating a counter is remembered for as long as it keeps advertising.
+        assert!(!dedup.check(&bthome("a", 1, 0xc4), start + Duration::from_secs(1)));
+        assert!(!dedup.check(&bthome("a", 1, 0xc4), start + Duration::from_secs(2)));
+        assert!(!dedup.check(&bthome("a", 1, 0xc4), start + Duration::from_secs(3)));
+        // Once quiet for longer than SEQUENCE_TTL, it is forgotten, as is b.
+        assert!(dedup.check(&bthome("a", 1, 0xc4), start + Duration::from_secs(64)));
+        assert_eq!(dedup.sequences.len(), 1);
+    }
+
+    #[test]
+    fn test_dedup_by_sequence_number_without_window() {
+        let mut dedup = Deduplicator::new(Duration::ZERO);
+        let start = Instant::now();
+
+        assert!(dedup.check(&bthome("a", 1, 0xc4), start));
+        assert!(!dedup.check(&bthome("a", 1, 0xc4), start + Duration::from_secs(1)));
+        assert!(dedup.check(&bthome("a", 2, 0xc4), start + Duration::from_secs(2)));
+        // Identical payloads without a counter aren't deduplicated.
+        assert!(dedup.check(&event("b", vec![1, 2, 3]), start));
+        assert!(dedup.check(&event("b", vec![1, 2, 3]), start));
+    }
+
+    #[test]
+    fn test_dedup_exempt() {
+        let mut dedup = Deduplicator::new(Duration::from_secs(2));
+        dedup.exempt = vec!["button".to_string()];
+        let start = Instant::now();
+        // A press, without a packet id, repeated within the window.
+        let press = DeviceEvent::ServiceDataAdvertisement {
+            device_id: device_id("a"),
+            service_data: HashMap::from([(
+                Uuid::from_u128(0x0000fcd2_0000_1000_8000_00805f9b34fb),
+                vec![0x40, 0x3a, 0x01],
+            )]),
+            rssi: None,
+        };
+
+        assert!(dedup.check(&press, start));
+        assert!(dedup.check(&press, start + Duration::from_secs(1)));
+        // Other advertisements are still deduplicated.
+        assert!(dedup.check(&event("b", vec![1, 2, 3]), start));
+        assert!(!dedup.check(&event("b", vec![1, 2, 3]), start + Duration::from_secs(1)));
+    }
+}
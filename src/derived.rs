@@ -0,0 +1,363 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::iter::Peekable;
+use std::str::Chars;
+
+use async_stream::stream;
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use futures_core::stream::Stream;
+
+use crate::{DeviceReading, Measurement};
+
+// Derivation computes a new measurement kind from other kinds of the same device, e.g. vapor
+// pressure deficit from temperature and humidity. Configured as `[derived]` entries such as
+// `vpd = "0.6108 * exp(17.27 * temperature / (temperature + 237.3)) * (1 - humidity / 100)"`.
+struct Derivation {
+    kind: String,
+    expression: Expression,
+    variables: HashSet<String>,
+}
+
+pub struct Derivations {
+    derivations: Vec<Derivation>,
+    // The latest value of each kind, by device id.
+    values: HashMap<String, HashMap<String, f64>>,
+}
+
+impl Derivations {
+    pub fn new(derived: &BTreeMap<String, String>) -> Result<Self> {
+        let derivations = derived
+            .iter()
+            .map(|(kind, source)| {
+                let expression =
+                    Expression::parse(source).wrap_err_with(|| format!("derived {:?}", kind))?;
+                let mut variables = HashSet::new();
+                expression.variables(&mut variables);
+                Ok(Derivation {
+                    kind: kind.clone(),
+                    expression,
+                    variables,
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Derivations {
+            derivations,
+            values: HashMap::new(),
+        })
+    }
+
+    // derive remembers the reading and returns the measurements that depend on its kind, once
+    // every kind they reference has been seen from the device.
+    pub fn derive(&mut self, reading: &DeviceReading) -> Vec<Measurement> {
+        if self.derivations.is_empty() {
+            return Vec::new();
+        }
+        let kind = reading.measurement.kind();
+        let values = self.values.entry(reading.device_id.id.clone()).or_default();
+        values.insert(kind.to_string(), reading.measurement.value());
+        self.derivations
+            .iter()
+            .filter(|derivation| derivation.variables.contains(kind))
+            .filter_map(|derivation| {
+                let value = derivation.expression.eval(values)?;
+                value.is_finite().then(|| Measurement::Other {
+                    kind: derivation.kind.clone(),
+                    value,
+                    unit: String::new(),
+                })
+            })
+            .collect()
+    }
+}
+
+// derived_stream passes readings through, following each with the measurements derived from it.
+pub fn derived_stream(
+    readings: impl Stream<Item = DeviceReading>,
+    mut derivations: Derivations,
+) -> impl Stream<Item = DeviceReading> {
+    stream! {
+        for await reading in readings {
+            let derived = derivations.derive(&reading);
+            let device_id = reading.device_id.clone();
+            let advertisement = reading.advertisement;
+            yield reading;
+            for measurement in derived {
+                let device_id = device_id.clone();
+                yield DeviceReading { device_id, measurement, advertisement, quality: Vec::new() };
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Operator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Power,
+}
+
+// Expression is a parsed arithmetic expression over measurement kinds: numbers, kinds, + - * / ^,
+// parentheses and the functions in FUNCTIONS.
+#[derive(Debug, PartialEq)]
+enum Expression {
+    Number(f64),
+    Variable(String),
+    Negate(Box<Expression>),
+    Binary(Operator, Box<Expression>, Box<Expression>),
+    Call(String, Vec<Expression>),
+}
+
+// FUNCTIONS lists the supported functions and how many arguments each takes.
+const FUNCTIONS: [(&str, usize); 9] = [
+    ("abs", 1),
+    ("exp", 1),
+    ("ln", 1),
+    ("log10", 1),
+    ("sqrt", 1),
+    ("round", 1),
+    ("min", 2),
+    ("max", 2),
+    ("clamp", 3),
+];
+
+impl Expression {
+    fn parse(source: &str) -> Result<Self> {
+        let mut parser = Parser {
+            chars: source.chars().peekable(),
+        };
+        let expression = parser.sum()?;
+        parser.skip_whitespace();
+        match parser.chars.next() {
+            None => Ok(expression),
+            Some(c) => Err(eyre!("unexpected {:?}", c)),
+        }
+    }
+
+    fn variables(&self, variables: &mut HashSet<String>) {
+        match self {
+            Expression::Number(_) => {}
+            Expression::Variable(name) => {
+                variables.insert(name.clone());
+            }
+            Expression::Negate(e) => e.variables(variables),
+            Expression::Binary(_, a, b) => {
+                a.variables(variables);
+                b.variables(variables);
+            }
+            Expression::Call(_, args) => args.iter().for_each(|a| a.variables(variables)),
+        }
+    }
+
+    // eval returns None if a variable has no value.
+    fn eval(&self, values: &HashMap<String, f64>) -> Option<f64> {
+        Some(match self {
+            Expression::Number(n) => *n,
+            Expression::Variable(name) => *values.get(name)?,
+            Expression::Negate(e) => -e.eval(values)?,
+            Expression::Binary(operator, a, b) => {
+                let (a, b) = (a.eval(values)?, b.eval(values)?);
+                match operator {
+                    Operator::Add => a + b,
+                    Operator::Subtract => a - b,
+                    Operator::Multiply => a * b,
+                    Operator::Divide => a / b,
+                    Operator::Power => a.powf(b),
+                }
+            }
+            Expression::Call(function, args) => {
+                let args = args
+                    .iter()
+                    .map(|a| a.eval(values))
+                    .collect::<Option<Vec<_>>>()?;
+                match (function.as_str(), args.as_slice()) {
+                    ("abs", [x]) => x.abs(),
+                    ("exp", [x]) => x.exp(),
+                    ("ln", [x]) => x.ln(),
+                    ("log10", [x]) => x.log10(),
+                    ("sqrt", [x]) => x.sqrt(),
+                    ("round", [x]) => x.round(),
+                    ("min", [x, y]) => x.min(*y),
+                    ("max", [x, y]) => x.max(*y),
+                    ("clamp", [x, low, high]) => x.max(*low).min(*high),
+                    _ => return None,
+                }
+            }
+        })
+    }
+}
+
+// Parser is a recursive descent parser, one method per precedence level.
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+}
+
+impl Parser<'_> {
+    fn skip_whitespace(&mut self) {
+        while self.chars.next_if(|c| c.is_whitespace()).is_some() {}
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_whitespace();
+        self.chars.next_if_eq(&c).is_some()
+    }
+
+    fn sum(&mut self) -> Result<Expression> {
+        let mut expression = self.product()?;
+        loop {
+            let operator = if self.eat('+') {
+                Operator::Add
+            } else if self.eat('-') {
+                Operator::Subtract
+            } else {
+                return Ok(expression);
+            };
+            expression = Expression::Binary(operator, expression.into(), self.product()?.into());
+        }
+    }
+
+    fn product(&mut self) -> Result<Expression> {
+        let mut expression = self.power()?;
+        loop {
+            let operator = if self.eat('*') {
+                Operator::Multiply
+            } else if self.eat('/') {
+                Operator::Divide
+            } else {
+                return Ok(expression);
+            };
+            expression = Expression::Binary(operator, expression.into(), self.power()?.into());
+        }
+    }
+
+    // Exponentiation binds tighter than negation on its left, so -2^2 is -4, and is right
+    // associative.
+    fn power(&mut self) -> Result<Expression> {
+        if self.eat('-') {
+            return Ok(Expression::Negate(self.power()?.into()));
+        }
+        let base = self.primary()?;
+        if self.eat('^') {
+            let exponent = self.power()?;
+            return Ok(Expression::Binary(
+                Operator::Power,
+                base.into(),
+                exponent.into(),
+            ));
+        }
+        Ok(base)
+    }
+
+    fn primary(&mut self) -> Result<Expression> {
+        self.skip_whitespace();
+        if self.eat('(') {
+            let expression = self.sum()?;
+            if !self.eat(')') {
+                return Err(eyre!("missing )"));
+            }
+            return Ok(expression);
+        }
+        match self.chars.peek() {
+            Some(c) if c.is_ascii_digit() || *c == '.' => {
+                let number = self.take_while(|c| c.is_ascii_digit() || c == '.');
+                let number = number
+                    .parse()
+                    .map_err(|_| eyre!("invalid number {:?}", number))?;
+                Ok(Expression::Number(number))
+            }
+            Some(c) if c.is_alphabetic() || *c == '_' => {
+                let name = self.take_while(|c| c.is_alphanumeric() || c == '_');
+                if !self.eat('(') {
+                    return Ok(Expression::Variable(name));
+                }
+                let mut args = Vec::new();
+                if !self.eat(')') {
+                    loop {
+                        args.push(self.sum()?);
+                        if self.eat(')') {
+                            break;
+                        }
+                        if !self.eat(',') {
+                            return Err(eyre!("missing ) after arguments to {}", name));
+                        }
+                    }
+                }
+                match FUNCTIONS.iter().find(|(function, _)| *function == name) {
+                    Some((_, arity)) if *arity == args.len() => Ok(Expression::Call(name, args)),
+                    Some((_, arity)) => Err(eyre!("{} takes {} arguments", name, arity)),
+                    None => Err(eyre!("unknown function {}", name)),
+                }
+            }
+            Some(c) => Err(eyre!("unexpected {:?}", c)),
+            None => Err(eyre!("unexpected end of expression")),
+        }
+    }
+
+    fn take_while(&mut self, f: impl Fn(char) -> bool) -> String {
+        let mut taken = String::new();
+        while let Some(c) = self.chars.next_if(|c| f(*c)) {
+            taken.push(c);
+        }
+        taken
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, HashMap};
+
+    use crate::derived::{Derivations, Expression};
+    use crate::{DeviceId, DeviceReading, Measurement};
+
+    #[test]
+    fn test_expression() {
+        let values = HashMap::from([("temperature".to_string(), 25.0)]);
+        let eval = |source: &str| Expression::parse(source).unwrap().eval(&values);
+        assert_eq!(eval("1 + 2 * 3"), Some(7.0));
+        assert_eq!(eval("(1 + 2) * 3"), Some(9.0));
+        assert_eq!(eval("-2^2"), Some(-4.0));
+        assert_eq!(eval("2^3^2"), Some(512.0));
+        assert_eq!(eval("10 - 4 - 3"), Some(3.0));
+        assert_eq!(eval("max(temperature, 30) / 2"), Some(15.0));
+        assert_eq!(eval("temperature * 1.8 + 32"), Some(77.0));
+        assert_eq!(eval("humidity"), None);
+
+        assert!(Expression::parse("1 +").is_err());
+        assert!(Expression::parse("(1").is_err());
+        assert!(Expression::parse("nope(1)").is_err());
+        assert!(Expression::parse("max(1)").is_err());
+        assert!(Expression::parse("1 2").is_err());
+    }
+
+    #[test]
+    fn test_derive() {
+        let mut derivations = Derivations::new(&BTreeMap::from([(
+            "vpd".to_string(),
+            "0.6108 * exp(17.27 * temperature / (temperature + 237.3)) * (1 - humidity / 100)"
+                .to_string(),
+        )]))
+        .unwrap();
+        let reading = |measurement| DeviceReading {
+            device_id: DeviceId {
+                id: "hci0/a".to_string(),
+                device_name: "a".to_string(),
+                address: String::new(),
+                random_address: None,
+            },
+            measurement,
+            advertisement: None,
+            quality: Vec::new(),
+        };
+
+        assert!(derivations
+            .derive(&reading(Measurement::Temperature(25.0)))
+            .is_empty());
+        assert!(derivations
+            .derive(&reading(Measurement::Battery(90.0)))
+            .is_empty());
+        let derived = derivations.derive(&reading(Measurement::Humidity(50.0)));
+        assert_eq!(derived.len(), 1);
+        assert_eq!(derived[0].kind(), "vpd");
+        assert!((derived[0].value() - 1.584).abs() < 0.001);
+    }
+}
@@ -0,0 +1,177 @@
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use btsensor::bthome::v2::BtHomeV2;
+use ruuvi_sensor_protocol::SensorValues;
+use serde::Serialize;
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+use crate::metrics::{Stage, METRICS};
+use crate::watchdog::NotSeen;
+use crate::DeviceId;
+
+pub const DEFAULT_TOPIC: &str = "blueplug/diagnostics";
+
+const RUUVI_COMPANY_ID: u16 = 0x0499;
+
+// At most one diagnostic per device is published per interval, since a device that sends a
+// malformed frame usually keeps sending it.
+const DIAGNOSTIC_INTERVAL: Duration = Duration::from_secs(60);
+
+// Diagnostic describes an advertisement of a known protocol that failed to decode, with enough
+// detail to report the frame upstream.
+#[derive(Serialize, Debug, Clone)]
+pub struct Diagnostic {
+    #[serde(flatten)]
+    pub device_id: DeviceId,
+    pub protocol: &'static str,
+    pub error: String,
+    // The payload, hex encoded.
+    pub payload: String,
+}
+
+// Report is anything published on the diagnostics topic.
+#[derive(Serialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum Report {
+    Failed(Diagnostic),
+    NotSeen(NotSeen),
+}
+
+// DIAGNOSTICS collects decode failures and watchdog warnings for the brokers to publish.
+pub static DIAGNOSTICS: LazyLock<Diagnostics> = LazyLock::new(Diagnostics::new);
+
+pub struct Diagnostics {
+    sender: broadcast::Sender<Arc<Report>>,
+    last_sent: Mutex<HashMap<String, Instant>>,
+}
+
+impl Diagnostics {
+    fn new() -> Self {
+        let (sender, _) = broadcast::channel(16);
+        Diagnostics {
+            sender,
+            last_sent: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<Report>> {
+        self.sender.subscribe()
+    }
+
+    pub fn report(&self, diagnostic: Diagnostic) {
+        METRICS.reading(Stage::Failed, &diagnostic.device_id);
+        let now = Instant::now();
+        let mut last_sent = self.last_sent.lock().unwrap();
+        match last_sent.get(&diagnostic.device_id.id) {
+            Some(sent) if now.duration_since(*sent) < DIAGNOSTIC_INTERVAL => return,
+            _ => last_sent.insert(diagnostic.device_id.id.clone(), now),
+        };
+        println!(
+            "failed to decode {} advertisement from {}: {} ({})",
+            diagnostic.protocol,
+            diagnostic.device_id.device_name,
+            diagnostic.error,
+            diagnostic.payload
+        );
+        // Sending only fails when no broker is listening.
+        let _ = self.sender.send(Arc::new(Report::Failed(diagnostic)));
+    }
+
+    pub fn not_seen(&self, not_seen: NotSeen) {
+        METRICS.reading(Stage::Missed, &not_seen.device_id);
+        println!(
+            "{} hasn't been seen for {} intervals of {}s",
+            not_seen.device_id.device_name, not_seen.missed, not_seen.interval
+        );
+        let _ = self.sender.send(Arc::new(Report::NotSeen(not_seen)));
+    }
+}
+
+// diagnose_manufacturer_data explains why manufacturer data of a known protocol didn't decode.
+pub fn diagnose_manufacturer_data(
+    device_id: &DeviceId,
+    manufacturer_data: &HashMap<u16, Vec<u8>>,
+) -> Vec<Diagnostic> {
+    manufacturer_data
+        .iter()
+        .filter(|(id, _)| **id == RUUVI_COMPANY_ID)
+        .filter_map(|(id, data)| {
+            let error = SensorValues::from_manufacturer_specific_data(*id, data).err()?;
+            Some(diagnostic(device_id, "ruuvi", error.to_string(), data))
+        })
+        .collect()
+}
+
+// diagnose_service_data explains why service data of a known protocol didn't decode.
+pub fn diagnose_service_data(
+    device_id: &DeviceId,
+    service_data: &HashMap<Uuid, Vec<u8>>,
+) -> Vec<Diagnostic> {
+    let Some(data) = service_data.get(&btsensor::bthome::v2::UUID) else {
+        return Vec::new();
+    };
+    // BtHomeV2::decode panics on an empty payload.
+    let error = if data.is_empty() {
+        "empty payload".to_string()
+    } else {
+        match BtHomeV2::decode(data) {
+            Ok(_) => return Vec::new(),
+            Err(e) => e.to_string(),
+        }
+    };
+    vec![diagnostic(device_id, "bthome", error, data)]
+}
+
+fn diagnostic(
+    device_id: &DeviceId,
+    protocol: &'static str,
+    error: String,
+    data: &[u8],
+) -> Diagnostic {
+    Diagnostic {
+        device_id: device_id.clone(),
+        protocol,
+        error,
+        payload: data.iter().map(|b| format!("{:02x}", b)).collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::diagnostics::{diagnose_manufacturer_data, diagnose_service_data};
+    use crate::DeviceId;
+
+    #[test]
+    fn test_diagnose() {
+        let device_id = DeviceId {
+            id: "hci0/dev_A4_C1_38_8F_2C_1A".to_string(),
+            device_name: "sensor".to_string(),
+            address: "A4:C1:38:8F:2C:1A".to_string(),
+            random_address: None,
+        };
+
+        // A truncated Ruuvi RAWv2 frame.
+        let diagnostics =
+            diagnose_manufacturer_data(&device_id, &HashMap::from([(0x0499, vec![0x05, 0x12])]));
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].protocol, "ruuvi");
+        assert_eq!(diagnostics[0].payload, "0512");
+
+        // Other manufacturers aren't known protocols.
+        assert!(
+            diagnose_manufacturer_data(&device_id, &HashMap::from([(0xffff, vec![1])])).is_empty()
+        );
+
+        let diagnostics = diagnose_service_data(
+            &device_id,
+            &HashMap::from([(btsensor::bthome::v2::UUID, vec![0x40, 0x02, 0xc4])]),
+        );
+        assert_eq!(diagnostics[0].protocol, "bthome");
+        assert_eq!(diagnostics[0].error, "Premature end of data");
+    }
+}
@@ -0,0 +1,132 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use btleplug::api::{Central, ScanFilter};
+use btleplug::platform::Manager;
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use jiff::Timestamp;
+use rumqttc::{AsyncClient, Event, Packet};
+use tokio::net::TcpStream;
+use tokio::time;
+
+use crate::adapter::{select_adapter, AdapterSelector};
+use crate::config::{BrokerConfig, Config};
+use crate::mqtt::mqtt_options;
+use crate::preflight::{explain, preflight};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+// A clock before this is almost certainly unset, e.g. a board without a real time clock that
+// hasn't synced with NTP yet.
+const EARLIEST_PLAUSIBLE_TIME: &str = "2024-01-01T00:00:00Z";
+
+// DoctorArgs configures `blueplug doctor`, which checks everything blueplug depends on and prints
+// a report to include in support requests.
+#[derive(clap::Args, Debug)]
+pub struct DoctorArgs {
+    /// TOML configuration file whose brokers to check
+    #[arg(short = 'c', long)]
+    config: Option<PathBuf>,
+    /// Bluetooth adapter to check, by index, name (e.g. hci1) or MAC address
+    #[arg(long)]
+    adapter: Option<AdapterSelector>,
+}
+
+pub async fn run(args: DoctorArgs) -> Result<()> {
+    let mut failed = 0;
+    let mut report = |check: &str, result: Result<String>| match result {
+        Ok(detail) => println!("PASS {}: {}", check, detail),
+        Err(e) => {
+            failed += 1;
+            println!("FAIL {}: {:#}", check, e);
+        }
+    };
+
+    report("clock", check_clock());
+    let mut config = None;
+    if let Some(path) = &args.config {
+        let loaded = check_config(path).map(|loaded| {
+            config = Some(loaded);
+            path.display().to_string()
+        });
+        report("config", loaded);
+    }
+    report("adapter", check_adapter(args.adapter.as_ref()).await);
+    for broker in config.iter().flat_map(|config| &config.brokers) {
+        report(
+            &format!("broker {}", broker.name),
+            check_broker(broker).await,
+        );
+    }
+
+    if failed > 0 {
+        return Err(eyre!("{} check(s) failed", failed));
+    }
+    Ok(())
+}
+
+fn check_clock() -> Result<String> {
+    let now = Timestamp::now();
+    let earliest: Timestamp = EARLIEST_PLAUSIBLE_TIME.parse()?;
+    if now < earliest {
+        return Err(eyre!(
+            "the system clock says {}, which can't be right; is NTP running?",
+            now
+        ));
+    }
+    Ok(now.to_string())
+}
+
+fn check_config(path: &Path) -> Result<Config> {
+    let config = Config::load(path)?;
+    config.check()?;
+    Ok(config)
+}
+
+// check_adapter looks for the adapter and briefly starts scanning, which is where missing
+// permissions show up.
+async fn check_adapter(selector: Option<&AdapterSelector>) -> Result<String> {
+    preflight(selector).await?;
+    let manager = Manager::new().await.map_err(explain)?;
+    let central = select_adapter(&manager, selector).await?;
+    let info = central.adapter_info().await.map_err(explain)?;
+    central
+        .start_scan(ScanFilter::default())
+        .await
+        .map_err(explain)?;
+    central.stop_scan().await.map_err(explain)?;
+    Ok(format!("{}, scanning works", info))
+}
+
+// check_broker connects to the broker, first over TCP to tell network problems apart from MQTT
+// ones such as bad credentials.
+async fn check_broker(broker: &BrokerConfig) -> Result<String> {
+    let address = format!("{}:{}", broker.host, broker.port);
+    time::timeout(TIMEOUT, TcpStream::connect(&address))
+        .await
+        .map_err(|_| eyre!("timed out connecting to {}", address))?
+        .wrap_err_with(|| format!("connecting to {}", address))?;
+
+    let mut broker = broker.clone();
+    broker.client_id = format!("{}-doctor", broker.client_id);
+    let (client, mut eventloop) = AsyncClient::new(mqtt_options(&broker)?, 1);
+    let connected = time::timeout(TIMEOUT, async {
+        loop {
+            match eventloop.poll().await {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => return Ok(()),
+                Ok(_) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    })
+    .await
+    .map_err(|_| {
+        eyre!(
+            "{} accepted the connection but didn't answer CONNECT",
+            address
+        )
+    })?;
+    connected.wrap_err_with(|| format!("MQTT connection to {}", address))?;
+    let _ = client.try_disconnect();
+    Ok(format!("connected to {}", address))
+}
@@ -0,0 +1,75 @@
+use color_eyre::eyre::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::protobuf;
+
+// PayloadFormat is how a sink serializes what it publishes. The binary formats carry the same
+// structure as JSON in fewer bytes, for constrained links. Protobuf encodes reading envelopes with
+// the schema of proto/payload.proto, for stream processing that wants typed messages.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum PayloadFormat {
+    #[default]
+    Json,
+    Cbor,
+    Msgpack,
+    Protobuf,
+}
+
+impl PayloadFormat {
+    pub fn encode(&self, value: &impl Serialize) -> Result<Vec<u8>> {
+        Ok(match self {
+            PayloadFormat::Json => serde_json::to_vec(value)?,
+            PayloadFormat::Cbor => {
+                let mut bytes = Vec::new();
+                ciborium::into_writer(value, &mut bytes)?;
+                bytes
+            }
+            // Maps are encoded with their keys, so decoders don't need to know the field order.
+            PayloadFormat::Msgpack => rmp_serde::to_vec_named(value)?,
+            PayloadFormat::Protobuf => protobuf::encode(serde_json::to_value(value)?),
+        })
+    }
+
+    // element_overhead is how many bytes an array adds per element, at most: a separator, or in
+    // protobuf a field tag and length.
+    pub fn element_overhead(&self) -> usize {
+        match self {
+            PayloadFormat::Protobuf => 6,
+            _ => 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use prost::Message;
+
+    use crate::encoding::PayloadFormat;
+    use crate::protobuf::{Kind, ProtoValue};
+
+    #[test]
+    fn test_encode() {
+        let value = json!({"kind": "temperature", "value": 21.5});
+
+        let json = PayloadFormat::Json.encode(&value).unwrap();
+        assert_eq!(json, br#"{"kind":"temperature","value":21.5}"#);
+
+        let cbor = PayloadFormat::Cbor.encode(&value).unwrap();
+        assert_eq!(
+            ciborium::from_reader::<Value, _>(cbor.as_slice()).unwrap(),
+            value
+        );
+
+        let msgpack = PayloadFormat::Msgpack.encode(&value).unwrap();
+        assert_eq!(rmp_serde::from_slice::<Value>(&msgpack).unwrap(), value);
+        assert!(msgpack.len() < json.len());
+
+        // Not an envelope, so a google.protobuf.Value.
+        let protobuf = PayloadFormat::Protobuf.encode(&value).unwrap();
+        let decoded = ProtoValue::decode(&protobuf[..]).unwrap();
+        assert!(matches!(decoded.kind, Some(Kind::Struct(s)) if s.fields.len() == 2));
+    }
+}
@@ -0,0 +1,151 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::names::TOPIC_NAMES;
+use crate::{DeviceReading, Quality};
+
+pub const SCHEMA_VERSION: u32 = 1;
+
+// SCHEMA is the JSON Schema for Envelope, printed by `blueplug schema`.
+pub const SCHEMA: &str = include_str!("../schema/reading.v1.json");
+
+// Envelope is the versioned payload format. Fields may be added without bumping schema_version,
+// which only changes when fields are removed or change meaning.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Envelope {
+    pub schema_version: u32,
+    pub device: Device,
+    pub measurement: EnvelopeMeasurement,
+    // Seconds since the Unix epoch.
+    pub timestamp: u64,
+    // Shared by the readings decoded from one advertisement, see DeviceReading.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub advertisement: Option<u64>,
+    #[serde(default)]
+    pub meta: Map<String, Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Device {
+    pub id: String,
+    pub name: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub address: String,
+    // Where the device is, see TopicNames::location.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
+    // Labels configured for the device.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct EnvelopeMeasurement {
+    pub kind: String,
+    pub value: f64,
+    pub unit: String,
+}
+
+impl Envelope {
+    pub fn new(reading: &DeviceReading, timestamp: u64, mut meta: Map<String, Value>) -> Self {
+        for quality in &reading.quality {
+            flag(&mut meta, *quality);
+        }
+        Envelope {
+            schema_version: SCHEMA_VERSION,
+            device: Device {
+                id: reading.device_id.id.clone(),
+                name: reading.device_id.device_name.clone(),
+                address: reading.device_id.address.clone(),
+                location: TOPIC_NAMES.location(&reading.device_id),
+                labels: TOPIC_NAMES.labels(&reading.device_id),
+            },
+            measurement: EnvelopeMeasurement {
+                kind: reading.measurement.kind().to_string(),
+                value: reading.measurement.value(),
+                unit: reading.measurement.unit().to_string(),
+            },
+            timestamp,
+            advertisement: reading.advertisement,
+            meta,
+        }
+    }
+}
+
+// flag adds quality to the meta.quality flags of an envelope, unless it's there already.
+pub fn flag(meta: &mut Map<String, Value>, quality: Quality) {
+    let Ok(Value::String(name)) = serde_json::to_value(quality) else {
+        return;
+    };
+    let flags = meta
+        .entry("quality")
+        .or_insert_with(|| Value::Array(Vec::new()));
+    if let Value::Array(flags) = flags {
+        if !flags.iter().any(|flag| flag.as_str() == Some(&name)) {
+            flags.push(Value::String(name));
+        }
+    }
+}
+
+// ndjson is reading as an envelope on a line of its own, for streaming to local consumers.
+pub fn ndjson(reading: &DeviceReading, timestamp: u64) -> serde_json::Result<Vec<u8>> {
+    let mut line = serde_json::to_vec(&Envelope::new(reading, timestamp, Map::new()))?;
+    line.push(b'\n');
+    Ok(line)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{Map, Value};
+
+    use crate::envelope::{Envelope, SCHEMA, SCHEMA_VERSION};
+    use crate::{DeviceId, DeviceReading, Measurement, Quality};
+
+    #[test]
+    fn test_envelope_round_trip() {
+        let reading = DeviceReading {
+            device_id: DeviceId {
+                id: "hci0/dev_A4_C1_38_8F_2C_1A".to_string(),
+                device_name: "ATC_8F2C1A".to_string(),
+                address: "A4:C1:38:8F:2C:1A".to_string(),
+                random_address: None,
+            },
+            measurement: Measurement::Temperature(21.5),
+            advertisement: Some(42),
+            quality: vec![Quality::FromStaleCache],
+        };
+        let meta = Map::from_iter([("is_stale".to_string(), Value::Bool(true))]);
+        let envelope = Envelope::new(&reading, 1_700_000_000, meta);
+
+        let json = serde_json::to_value(&envelope).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "schema_version": 1,
+                "device": {
+                    "id": "hci0/dev_A4_C1_38_8F_2C_1A",
+                    "name": "ATC_8F2C1A",
+                    "address": "A4:C1:38:8F:2C:1A"
+                },
+                "measurement": {"kind": "temperature", "value": 21.5, "unit": "°C"},
+                "timestamp": 1_700_000_000,
+                "advertisement": 42,
+                "meta": {"is_stale": true, "quality": ["from_stale_cache"]}
+            })
+        );
+        assert_eq!(serde_json::from_value::<Envelope>(json).unwrap(), envelope);
+
+        // Every field the schema requires is present.
+        let schema: Value = serde_json::from_str(SCHEMA).unwrap();
+        assert_eq!(
+            schema["properties"]["schema_version"]["const"],
+            SCHEMA_VERSION
+        );
+        let envelope = serde_json::to_value(&envelope).unwrap();
+        for field in schema["required"].as_array().unwrap() {
+            assert!(envelope.get(field.as_str().unwrap()).is_some(), "{}", field);
+        }
+    }
+}
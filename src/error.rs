@@ -0,0 +1,154 @@
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::io;
+
+use color_eyre::eyre::Report;
+
+// BlueplugError is the error of the library's entry points: Blueplug, Config and the event
+// streams, so applications embedding blueplug can tell what kind of thing failed and react, say
+// retrying a missing adapter but reporting a bad configuration. Each variant keeps a Failure,
+// whose message follows the kind of failure and whose cause is the error's source.
+#[derive(Debug)]
+pub enum BlueplugError {
+    // The Bluetooth adapter or an ESPHome proxy couldn't be used or stopped delivering events.
+    Adapter(Failure),
+    // Data couldn't be decoded, such as a batch from a forwarder.
+    Decode(Failure),
+    // A sink, such as an MQTT broker, couldn't be started.
+    Sink { name: String, failure: Failure },
+    // The configuration is invalid.
+    Config(Failure),
+}
+
+// Failure describes what failed, from what was being done down to the underlying error, and
+// keeps that error as a Cause where it came from Bluetooth, the file system or a parser. Failures
+// blueplug detects itself, such as an invalid setting, have no cause.
+#[derive(Debug)]
+pub struct Failure {
+    pub message: String,
+    pub cause: Option<Cause>,
+}
+
+// Cause is the underlying error of a Failure. The larger errors are boxed to keep results small.
+#[derive(Debug)]
+pub enum Cause {
+    Bluetooth(Box<btleplug::Error>),
+    Io(io::Error),
+    Toml(Box<toml::de::Error>),
+    Json(serde_json::Error),
+}
+
+impl From<Report> for Failure {
+    fn from(report: Report) -> Self {
+        // The message stops short of the cause, which is the error's source, so printing the
+        // chain doesn't repeat it; unless the cause is all there is.
+        let mut message = report
+            .chain()
+            .take_while(|e| !is_cause(*e))
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(": ");
+        if message.is_empty() {
+            message = report.to_string();
+        }
+        Failure {
+            message,
+            cause: cause(report),
+        }
+    }
+}
+
+fn is_cause(e: &(dyn Error + 'static)) -> bool {
+    e.is::<btleplug::Error>()
+        || e.is::<io::Error>()
+        || e.is::<toml::de::Error>()
+        || e.is::<serde_json::Error>()
+}
+
+// cause takes the first error in report's chain blueplug can name the type of.
+fn cause(report: Report) -> Option<Cause> {
+    let report = match report.downcast::<btleplug::Error>() {
+        Ok(e) => return Some(Cause::Bluetooth(Box::new(e))),
+        Err(report) => report,
+    };
+    let report = match report.downcast::<io::Error>() {
+        Ok(e) => return Some(Cause::Io(e)),
+        Err(report) => report,
+    };
+    let report = match report.downcast::<toml::de::Error>() {
+        Ok(e) => return Some(Cause::Toml(Box::new(e))),
+        Err(report) => report,
+    };
+    report.downcast::<serde_json::Error>().ok().map(Cause::Json)
+}
+
+impl Display for BlueplugError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlueplugError::Adapter(failure) => write!(f, "bluetooth adapter: {}", failure.message),
+            BlueplugError::Decode(failure) => write!(f, "decoding: {}", failure.message),
+            BlueplugError::Sink { name, failure } => {
+                write!(f, "sink {:?}: {}", name, failure.message)
+            }
+            BlueplugError::Config(failure) => {
+                write!(f, "invalid configuration: {}", failure.message)
+            }
+        }
+    }
+}
+
+impl Error for BlueplugError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        let failure = match self {
+            BlueplugError::Adapter(failure)
+            | BlueplugError::Decode(failure)
+            | BlueplugError::Sink { failure, .. }
+            | BlueplugError::Config(failure) => failure,
+        };
+        match failure.cause.as_ref()? {
+            Cause::Bluetooth(e) => Some(e.as_ref()),
+            Cause::Io(e) => Some(e),
+            Cause::Toml(e) => Some(e.as_ref()),
+            Cause::Json(e) => Some(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+    use std::io;
+    use std::path::Path;
+
+    use crate::config::Config;
+    use crate::error::{BlueplugError, Cause, Failure};
+
+    #[test]
+    fn test_blueplug_error() {
+        let e = Config::parse("[[brokers]]\nname = \"a\"\nhost = \"h\"").unwrap_err();
+        assert!(matches!(
+            e,
+            BlueplugError::Config(Failure {
+                cause: Some(Cause::Toml(_)),
+                ..
+            })
+        ));
+        assert!(e.to_string().starts_with("invalid configuration: "));
+        assert!(e.to_string().contains("client_id"));
+
+        let e = Config::load(Path::new("/nonexistent/blueplug.toml")).unwrap_err();
+        assert!(e.to_string().contains("/nonexistent/blueplug.toml"));
+        assert!(!e.to_string().contains("os error"));
+        let source = e.source().unwrap().downcast_ref::<io::Error>().unwrap();
+        assert_eq!(source.kind(), io::ErrorKind::NotFound);
+
+        // Checks blueplug makes itself have no cause.
+        let broker = "[[brokers]]\nname = \"a\"\nhost = \"h\"\nclient_id = \"c\"\n";
+        let e = Config::parse(&broker.repeat(2)).unwrap_err();
+        assert!(matches!(
+            e,
+            BlueplugError::Config(Failure { cause: None, .. })
+        ));
+        assert!(e.source().is_none());
+    }
+}
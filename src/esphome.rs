@@ -0,0 +1,517 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use async_stream::stream;
+use btleplug::api::bleuuid::{uuid_from_u16, uuid_from_u32};
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use futures_core::stream::Stream;
+use prost::Message;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::time;
+use uuid::Uuid;
+
+use crate::config::EsphomeProxyConfig;
+use crate::error::BlueplugError;
+use crate::{DeviceEvent, DeviceId};
+
+// Proxies ping at least this often, so a quieter connection is dead.
+const READ_TIMEOUT: Duration = Duration::from_secs(90);
+const RECONNECT_DELAY: Duration = Duration::from_secs(10);
+
+// Proxies send raw advertisements in batches of a few hundred bytes, so a frame claiming to be
+// larger than this is garbage, and isn't allocated for.
+const MAX_FRAME: u64 = 64 * 1024;
+
+// Message types of the ESPHome native API, see api.proto in the ESPHome repository.
+const HELLO_REQUEST: u32 = 1;
+const HELLO_RESPONSE: u32 = 2;
+const CONNECT_REQUEST: u32 = 3;
+const CONNECT_RESPONSE: u32 = 4;
+const DISCONNECT_REQUEST: u32 = 5;
+const DISCONNECT_RESPONSE: u32 = 6;
+const PING_REQUEST: u32 = 7;
+const PING_RESPONSE: u32 = 8;
+const SUBSCRIBE_BLUETOOTH_LE_ADVERTISEMENTS_REQUEST: u32 = 66;
+const BLUETOOTH_LE_ADVERTISEMENT_RESPONSE: u32 = 67;
+const BLUETOOTH_LE_RAW_ADVERTISEMENTS_RESPONSE: u32 = 93;
+
+const SUBSCRIPTION_FLAG_RAW_ADVERTISEMENTS: u32 = 1;
+
+#[derive(Clone, PartialEq, Message)]
+struct HelloRequest {
+    #[prost(string, tag = "1")]
+    client_info: String,
+    #[prost(uint32, tag = "2")]
+    api_version_major: u32,
+    #[prost(uint32, tag = "3")]
+    api_version_minor: u32,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct HelloResponse {
+    #[prost(uint32, tag = "1")]
+    api_version_major: u32,
+    #[prost(uint32, tag = "2")]
+    api_version_minor: u32,
+    #[prost(string, tag = "3")]
+    server_info: String,
+    #[prost(string, tag = "4")]
+    name: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct ConnectRequest {
+    #[prost(string, tag = "1")]
+    password: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct ConnectResponse {
+    #[prost(bool, tag = "1")]
+    invalid_password: bool,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct SubscribeBluetoothLeAdvertisementsRequest {
+    #[prost(uint32, tag = "1")]
+    flags: u32,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct BluetoothServiceData {
+    #[prost(string, tag = "1")]
+    uuid: String,
+    #[prost(bytes = "vec", tag = "3")]
+    data: Vec<u8>,
+}
+
+// Sent by proxies that predate raw advertisements.
+#[derive(Clone, PartialEq, Message)]
+struct BluetoothLeAdvertisementResponse {
+    #[prost(uint64, tag = "1")]
+    address: u64,
+    #[prost(bytes = "vec", tag = "2")]
+    name: Vec<u8>,
+    #[prost(sint32, tag = "3")]
+    rssi: i32,
+    #[prost(message, repeated, tag = "5")]
+    service_data: Vec<BluetoothServiceData>,
+    #[prost(message, repeated, tag = "6")]
+    manufacturer_data: Vec<BluetoothServiceData>,
+    #[prost(uint32, tag = "7")]
+    address_type: u32,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct BluetoothLeRawAdvertisement {
+    #[prost(uint64, tag = "1")]
+    address: u64,
+    #[prost(sint32, tag = "2")]
+    rssi: i32,
+    #[prost(uint32, tag = "3")]
+    address_type: u32,
+    #[prost(bytes = "vec", tag = "4")]
+    data: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct BluetoothLeRawAdvertisementsResponse {
+    #[prost(message, repeated, tag = "1")]
+    advertisements: Vec<BluetoothLeRawAdvertisement>,
+}
+
+// Advertisement is what a proxy heard from one device, whichever message it arrived in.
+#[derive(Debug, Default, PartialEq)]
+struct Advertisement {
+    address: String,
+    name: Option<String>,
+    rssi: Option<i16>,
+    random_address: Option<bool>,
+    manufacturer_data: HashMap<u16, Vec<u8>>,
+    service_data: HashMap<Uuid, Vec<u8>>,
+}
+
+// esphome_stream receives the advertisements heard by an ESPHome Bluetooth proxy, extending
+// coverage to rooms the local radio can't reach. The proxy is the server in the native API, so
+// we connect to it the way Home Assistant does, reconnecting whenever the connection drops. Only
+// plaintext connections are supported, i.e. the proxy's `api:` block must not set an encryption
+// key.
+pub fn esphome_stream(
+    proxy: EsphomeProxyConfig,
+) -> impl Stream<Item = Result<DeviceEvent, BlueplugError>> {
+    stream! {
+        // Only some advertisements carry the device name, so it is remembered across them. Devices
+        // that haven't sent one yet are passed on without it, for presence.
+        let mut device_names = HashMap::<String, String>::new();
+        loop {
+            match connect(&proxy).await {
+                Ok(mut connection) => loop {
+                    match connection.next_advertisements().await {
+                        Ok(advertisements) => {
+                            for advertisement in advertisements {
+                                for event in device_events(&proxy.name, advertisement, &mut device_names) {
+                                    yield Ok(event);
+                                }
+                            }
+                        }
+                        Err(e) => {
+                            yield Err(BlueplugError::Adapter(e.wrap_err(format!("ESPHome proxy {}", proxy.name)).into()));
+                            break;
+                        }
+                    }
+                },
+                Err(e) => yield Err(BlueplugError::Adapter(e.wrap_err(format!("connecting to ESPHome proxy {}", proxy.name)).into())),
+            }
+            time::sleep(RECONNECT_DELAY).await;
+        }
+    }
+}
+
+fn device_events(
+    proxy: &str,
+    advertisement: Advertisement,
+    device_names: &mut HashMap<String, String>,
+) -> Vec<DeviceEvent> {
+    let Advertisement {
+        address,
+        name,
+        rssi,
+        random_address,
+        manufacturer_data,
+        service_data,
+    } = advertisement;
+    if let Some(name) = name {
+        device_names.insert(address.clone(), name);
+    }
+    let device_id = DeviceId {
+        id: format!("{}/{}", proxy, address),
+        device_name: device_names.get(&address).cloned().unwrap_or_default(),
+        address,
+        random_address,
+    };
+
+    let mut events = Vec::new();
+    if !manufacturer_data.is_empty() {
+        events.push(DeviceEvent::ManufacturerDataAdvertisement {
+            device_id: device_id.clone(),
+            manufacturer_data,
+            rssi,
+        });
+    }
+    if !service_data.is_empty() {
+        events.push(DeviceEvent::ServiceDataAdvertisement {
+            device_id,
+            service_data,
+            rssi,
+        });
+    }
+    events
+}
+
+struct Connection<S> {
+    stream: S,
+}
+
+async fn connect(proxy: &EsphomeProxyConfig) -> Result<Connection<BufReader<TcpStream>>> {
+    let stream = TcpStream::connect((proxy.host.as_str(), proxy.port)).await?;
+    let mut connection = Connection {
+        stream: BufReader::new(stream),
+    };
+    connection.handshake(&proxy.password).await?;
+    Ok(connection)
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Connection<S> {
+    async fn handshake(&mut self, password: &str) -> Result<()> {
+        self.send(
+            HELLO_REQUEST,
+            &HelloRequest {
+                client_info: "blueplug".to_string(),
+                api_version_major: 1,
+                api_version_minor: 10,
+            },
+        )
+        .await?;
+        let hello: HelloResponse = self.expect(HELLO_RESPONSE).await?;
+        println!(
+            "connected to ESPHome proxy {} ({})",
+            hello.name, hello.server_info
+        );
+
+        self.send(
+            CONNECT_REQUEST,
+            &ConnectRequest {
+                password: password.to_string(),
+            },
+        )
+        .await?;
+        let connected: ConnectResponse = self.expect(CONNECT_RESPONSE).await?;
+        if connected.invalid_password {
+            return Err(eyre!("invalid password"));
+        }
+
+        self.send(
+            SUBSCRIBE_BLUETOOTH_LE_ADVERTISEMENTS_REQUEST,
+            &SubscribeBluetoothLeAdvertisementsRequest {
+                flags: SUBSCRIPTION_FLAG_RAW_ADVERTISEMENTS,
+            },
+        )
+        .await
+    }
+
+    // next_advertisements waits for the next batch of advertisements, answering pings meanwhile.
+    async fn next_advertisements(&mut self) -> Result<Vec<Advertisement>> {
+        loop {
+            let (message_type, payload) = self.receive().await?;
+            match message_type {
+                PING_REQUEST => self.send(PING_RESPONSE, &()).await?,
+                DISCONNECT_REQUEST => {
+                    self.send(DISCONNECT_RESPONSE, &()).await?;
+                    return Err(eyre!("disconnected by the proxy"));
+                }
+                BLUETOOTH_LE_RAW_ADVERTISEMENTS_RESPONSE => {
+                    let response =
+                        BluetoothLeRawAdvertisementsResponse::decode(payload.as_slice())?;
+                    return Ok(response
+                        .advertisements
+                        .into_iter()
+                        .map(|raw| Advertisement {
+                            rssi: Some(raw.rssi as i16),
+                            random_address: Some(random_address(raw.address_type)),
+                            ..parse_advertising_data(mac_address(raw.address), &raw.data)
+                        })
+                        .collect());
+                }
+                BLUETOOTH_LE_ADVERTISEMENT_RESPONSE => {
+                    let response = BluetoothLeAdvertisementResponse::decode(payload.as_slice())?;
+                    return Ok(vec![advertisement_from_response(response)]);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    async fn expect<M: Message + Default>(&mut self, expected_type: u32) -> Result<M> {
+        let (message_type, payload) = self.receive().await?;
+        if message_type != expected_type {
+            return Err(eyre!(
+                "expected message type {}, got {}",
+                expected_type,
+                message_type
+            ));
+        }
+        Ok(M::decode(payload.as_slice())?)
+    }
+
+    // Plaintext frames are a zero byte, the payload length and message type as varints, then the
+    // protobuf payload.
+    async fn send(&mut self, message_type: u32, message: &impl Message) -> Result<()> {
+        let payload = message.encode_to_vec();
+        let mut frame = vec![0];
+        prost::encoding::encode_varint(payload.len() as u64, &mut frame);
+        prost::encoding::encode_varint(message_type as u64, &mut frame);
+        frame.extend(payload);
+        self.stream.write_all(&frame).await?;
+        Ok(())
+    }
+
+    async fn receive(&mut self) -> Result<(u32, Vec<u8>)> {
+        time::timeout(READ_TIMEOUT, self.read_frame())
+            .await
+            .wrap_err("timed out waiting for the proxy")?
+    }
+
+    async fn read_frame(&mut self) -> Result<(u32, Vec<u8>)> {
+        match self.stream.read_u8().await? {
+            0 => {}
+            1 => {
+                return Err(eyre!(
+                    "the proxy requires an encrypted connection, which isn't supported yet"
+                ))
+            }
+            preamble => return Err(eyre!("unexpected frame preamble {}", preamble)),
+        }
+        let len = self.read_varint().await?;
+        if len > MAX_FRAME {
+            return Err(eyre!("frame of {} bytes is too large", len));
+        }
+        let message_type = self.read_varint().await? as u32;
+        let mut payload = vec![0; len as usize];
+        self.stream.read_exact(&mut payload).await?;
+        Ok((message_type, payload))
+    }
+
+    async fn read_varint(&mut self) -> Result<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.stream.read_u8().await?;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(eyre!("varint too long"))
+    }
+}
+
+// The API packs the 48 bit address into an integer.
+fn mac_address(address: u64) -> String {
+    address.to_be_bytes()[2..]
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+// The API gives ESP-IDF's address types: public, random, and the two resolvable private ones.
+fn random_address(address_type: u32) -> bool {
+    address_type != 0
+}
+
+// parse_advertising_data picks the AD structures blueplug decodes out of a raw advertisement.
+fn parse_advertising_data(address: String, mut data: &[u8]) -> Advertisement {
+    let mut advertisement = Advertisement {
+        address,
+        ..Default::default()
+    };
+    while let Some((&len, rest)) = data.split_first() {
+        let len = len as usize;
+        if len == 0 || len > rest.len() {
+            break;
+        }
+        let (structure, rest) = rest.split_at(len);
+        data = rest;
+        let (ad_type, value) = (structure[0], &structure[1..]);
+        match ad_type {
+            // Shortened and complete local name.
+            0x08 | 0x09 => advertisement.name = Some(String::from_utf8_lossy(value).into_owned()),
+            // Service data with a 16, 32 and 128 bit UUID.
+            0x16 if value.len() >= 2 => {
+                let uuid = uuid_from_u16(u16::from_le_bytes([value[0], value[1]]));
+                advertisement.service_data.insert(uuid, value[2..].to_vec());
+            }
+            0x20 if value.len() >= 4 => {
+                let uuid =
+                    uuid_from_u32(u32::from_le_bytes([value[0], value[1], value[2], value[3]]));
+                advertisement.service_data.insert(uuid, value[4..].to_vec());
+            }
+            0x21 if value.len() >= 16 => {
+                let mut bytes = [0; 16];
+                bytes.copy_from_slice(&value[..16]);
+                bytes.reverse();
+                advertisement
+                    .service_data
+                    .insert(Uuid::from_bytes(bytes), value[16..].to_vec());
+            }
+            0xff if value.len() >= 2 => {
+                let company_id = u16::from_le_bytes([value[0], value[1]]);
+                advertisement
+                    .manufacturer_data
+                    .insert(company_id, value[2..].to_vec());
+            }
+            _ => {}
+        }
+    }
+    advertisement
+}
+
+fn advertisement_from_response(response: BluetoothLeAdvertisementResponse) -> Advertisement {
+    let name = String::from_utf8_lossy(&response.name).into_owned();
+    Advertisement {
+        address: mac_address(response.address),
+        name: (!name.is_empty()).then_some(name),
+        rssi: Some(response.rssi as i16),
+        random_address: Some(random_address(response.address_type)),
+        manufacturer_data: response
+            .manufacturer_data
+            .into_iter()
+            .filter_map(|d| Some((u16::try_from(parse_uuid(&d.uuid)?.1?).ok()?, d.data)))
+            .collect(),
+        service_data: response
+            .service_data
+            .into_iter()
+            .filter_map(|d| Some((parse_uuid(&d.uuid)?.0, d.data)))
+            .collect(),
+    }
+}
+
+// ESPHome formats short UUIDs as hex such as `0xFCD2`, and others in the usual long form. The
+// short value is returned too, since manufacturer data uses it for the company id.
+fn parse_uuid(uuid: &str) -> Option<(Uuid, Option<u32>)> {
+    match uuid.strip_prefix("0x") {
+        Some(short) => {
+            let short = u32::from_str_radix(short, 16).ok()?;
+            Some((uuid_from_u32(short), Some(short)))
+        }
+        None => Some((Uuid::parse_str(uuid).ok()?, None)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use btleplug::api::bleuuid::uuid_from_u16;
+    use tokio::io::AsyncWriteExt;
+
+    use crate::esphome::{
+        mac_address, parse_advertising_data, BluetoothLeRawAdvertisement,
+        BluetoothLeRawAdvertisementsResponse, Connection, PING_REQUEST, PING_RESPONSE,
+    };
+
+    #[test]
+    fn test_parse_advertising_data() {
+        assert_eq!(mac_address(0xa4c1388f2c1a), "A4:C1:38:8F:2C:1A");
+
+        let data = [
+            0x02, 0x01, 0x06, // flags
+            0x05, 0x09, b'A', b'T', b'C', b'1', // name
+            0x06, 0x16, 0xd2, 0xfc, 0x40, 0x01, 0x64, // BTHome service data
+            0x05, 0xff, 0x99, 0x04, 0x05, 0x12, // Ruuvi manufacturer data
+            0x09, 0xff, // truncated
+        ];
+        let advertisement = parse_advertising_data("A4:C1:38:8F:2C:1A".to_string(), &data);
+        assert_eq!(advertisement.name.as_deref(), Some("ATC1"));
+        assert_eq!(
+            advertisement.service_data[&uuid_from_u16(0xfcd2)],
+            vec![0x40, 0x01, 0x64]
+        );
+        assert_eq!(advertisement.manufacturer_data[&0x0499], vec![0x05, 0x12]);
+    }
+
+    #[tokio::test]
+    async fn test_connection() {
+        let (client, mut proxy) = tokio::io::duplex(1024);
+        let mut client = Connection { stream: client };
+        let mut proxy_side = Connection { stream: &mut proxy };
+        proxy_side.send(PING_REQUEST, &()).await.unwrap();
+        proxy_side
+            .send(
+                93,
+                &BluetoothLeRawAdvertisementsResponse {
+                    advertisements: vec![BluetoothLeRawAdvertisement {
+                        address: 0xa4c1388f2c1a,
+                        rssi: -67,
+                        address_type: 1,
+                        data: vec![0x03, 0x09, b'h', b'i'],
+                    }],
+                },
+            )
+            .await
+            .unwrap();
+
+        let advertisements = client.next_advertisements().await.unwrap();
+        assert_eq!(advertisements.len(), 1);
+        assert_eq!(advertisements[0].name.as_deref(), Some("hi"));
+        assert_eq!(advertisements[0].rssi, Some(-67));
+        assert_eq!(advertisements[0].random_address, Some(true));
+        let (message_type, _) = proxy_side.receive().await.unwrap();
+        assert_eq!(message_type, PING_RESPONSE);
+
+        // A frame claiming 4 GiB is rejected before anything is allocated for it.
+        proxy
+            .write_all(&[0x00, 0x80, 0x80, 0x80, 0x80, 0x10, 93])
+            .await
+            .unwrap();
+        assert!(client.next_advertisements().await.is_err());
+    }
+}
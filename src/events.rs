@@ -0,0 +1,151 @@
+use btsensor::bthome::events::{ButtonEventType, DimmerEventType, Event};
+use btsensor::bthome::v2::Element;
+
+use crate::Measurement;
+
+// How a button was pressed, by the value of its readings.
+pub const BUTTON_EVENTS: &[&str] = &[
+    "none",
+    "press",
+    "double_press",
+    "triple_press",
+    "long_press",
+    "long_double_press",
+    "long_triple_press",
+];
+pub const DIMMER_EVENTS: &[&str] = &["rotate_left", "rotate_right"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EventKind {
+    Button,
+    Dimmer,
+}
+
+// measurements turns the button and dimmer events of a BTHome v2 advertisement into readings.
+// A device with several buttons sends an event for each in order, "none" for those that weren't
+// pressed, so the first button's readings are of kind button, the second's button_2 and so on. A
+// button's value is how it was pressed, an index into BUTTON_EVENTS, and a dimmer's the steps it
+// was rotated by, negative to the left. Events are momentary: they trigger automations rather
+// than being a state, so they are never retained or republished.
+pub fn measurements(elements: &[Element]) -> Vec<Measurement> {
+    let (mut buttons, mut dimmers) = (0, 0);
+    let mut measurements = Vec::new();
+    for event in elements.iter().filter_map(Element::event) {
+        let (kind, index, value) = match event {
+            Event::Button(event_type) => {
+                buttons += 1;
+                let value = event_type.map(|event_type| u8::from(event_type) as f64);
+                ("button", buttons, value)
+            }
+            Event::Dimmer(event_type) => {
+                dimmers += 1;
+                let value = event_type.map(|event_type| match event_type {
+                    DimmerEventType::RotateLeft(steps) => -(steps as f64),
+                    DimmerEventType::RotateRight(steps) => steps as f64,
+                });
+                ("dimmer", dimmers, value)
+            }
+        };
+        let Some(value) = value else {
+            continue;
+        };
+        let kind = match index {
+            1 => kind.to_string(),
+            index => format!("{}_{}", kind, index),
+        };
+        measurements.push(Measurement::Other {
+            kind,
+            value,
+            unit: String::new(),
+        });
+    }
+    measurements
+}
+
+// event_kind tells whether readings of kind are events, and of which button or dimmer, from 1.
+pub fn event_kind(kind: &str) -> Option<(EventKind, usize)> {
+    let (name, index) = match kind.split_once('_') {
+        Some((name, index)) => (name, index.parse().ok().filter(|index| *index > 1)?),
+        None => (kind, 1),
+    };
+    match name {
+        "button" => Some((EventKind::Button, index)),
+        "dimmer" => Some((EventKind::Dimmer, index)),
+        _ => None,
+    }
+}
+
+pub fn is_event(measurement: &Measurement) -> bool {
+    event_kind(measurement.kind()).is_some()
+}
+
+impl EventKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            EventKind::Button => "button",
+            EventKind::Dimmer => "dimmer",
+        }
+    }
+
+    // events are the names of what the button or dimmer can do, as template renders them.
+    pub fn events(self) -> &'static [&'static str] {
+        match self {
+            EventKind::Button => &BUTTON_EVENTS[1..],
+            EventKind::Dimmer => DIMMER_EVENTS,
+        }
+    }
+
+    // template is a Home Assistant template turning the value, an expression such as
+    // `value_json.value`, into the name of the event.
+    pub fn template(self, value: &str) -> String {
+        match self {
+            EventKind::Button => format!(
+                "{{{{ {:?}[{} | int] | default('none') }}}}",
+                BUTTON_EVENTS, value
+            )
+            .replace('"', "'"),
+            EventKind::Dimmer => format!(
+                "{{{{ 'rotate_left' if ({} | int) < 0 else 'rotate_right' }}}}",
+                value
+            ),
+        }
+    }
+}
+
+// ButtonEventType and BUTTON_EVENTS have to agree.
+const _: () = assert!(ButtonEventType::LongTriplePress as usize == BUTTON_EVENTS.len() - 1);
+
+#[cfg(test)]
+mod tests {
+    use btsensor::bthome::v2::BtHomeV2;
+
+    use crate::events::{event_kind, measurements, EventKind};
+    use crate::Measurement;
+
+    #[test]
+    fn test_events() {
+        // No event on the first button, a double press on the second, and a dimmer turned 3 steps
+        // to the left.
+        let decoded = BtHomeV2::decode(&[0x44, 0x3a, 0x00, 0x3a, 0x02, 0x3c, 0x01, 0x03]).unwrap();
+        let event = |kind: &str, value| Measurement::Other {
+            kind: kind.to_string(),
+            value,
+            unit: String::new(),
+        };
+        assert_eq!(
+            measurements(&decoded.elements),
+            vec![event("button_2", 2.0), event("dimmer", -3.0)]
+        );
+
+        assert_eq!(event_kind("button"), Some((EventKind::Button, 1)));
+        assert_eq!(event_kind("dimmer_3"), Some((EventKind::Dimmer, 3)));
+        assert_eq!(event_kind("button_1"), None);
+        assert_eq!(event_kind("button_count"), None);
+        assert_eq!(event_kind("temperature"), None);
+        assert_eq!(
+            EventKind::Button.template("value_json.value"),
+            "{{ ['none', 'press', 'double_press', 'triple_press', 'long_press', \
+             'long_double_press', 'long_triple_press'][value_json.value | int] | default('none') }}"
+        );
+    }
+}
@@ -0,0 +1,150 @@
+use std::process::{ExitStatus, Stdio};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use color_eyre::eyre::{Result, WrapErr};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time;
+
+use crate::clock::unix_timestamp;
+use crate::config::ExecConfig;
+use crate::envelope::ndjson;
+use crate::mqtt::backoff;
+use crate::supervisor::SUPERVISOR;
+use crate::DeviceReading;
+
+// A command that ran at least this long before exiting is restarted without delay building up
+// from earlier exits.
+const STABLE_RUN: Duration = Duration::from_secs(60);
+
+// spawn_exec runs a command and writes readings to its stdin as NDJSON envelopes, so readings can
+// be processed by anything that reads lines without changing blueplug. Its stdout and stderr are
+// blueplug's. When it exits, or can't be started, it is started again after a backoff; readings
+// that arrive meanwhile are written once it is back, as far as the sink's queue holds them.
+pub fn spawn_exec(config: ExecConfig, mut readings: broadcast::Receiver<Arc<DeviceReading>>) {
+    SUPERVISOR.spawn_once(format!("exec {}", config.name), async move {
+        let mut failures = 0;
+        loop {
+            let started = Instant::now();
+            let exited = match run(&config, &mut readings).await {
+                Ok(Some(status)) => format!("exited with {}", status),
+                Ok(None) => return,
+                Err(e) => format!("failed: {:#}", e),
+            };
+            if started.elapsed() >= STABLE_RUN {
+                failures = 0;
+            }
+            failures += 1;
+            let delay = backoff(failures, fastrand::f64());
+            println!(
+                "{}: {} {}, restarting in {:?}",
+                config.name, config.command[0], exited, delay
+            );
+            time::sleep(delay).await;
+        }
+    });
+}
+
+// run starts the command and writes readings to it until it exits, returning its exit status, or
+// None once there are no more readings, after closing its stdin and waiting for it to finish.
+async fn run(
+    config: &ExecConfig,
+    readings: &mut broadcast::Receiver<Arc<DeviceReading>>,
+) -> Result<Option<ExitStatus>> {
+    let mut child = Command::new(&config.command[0])
+        .args(&config.command[1..])
+        .stdin(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .wrap_err("starting")?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    loop {
+        let reading = tokio::select! {
+            status = child.wait() => return Ok(Some(status?)),
+            reading = readings.recv() => reading,
+        };
+        match reading {
+            Ok(reading) => {
+                if stdin
+                    .write_all(&ndjson(&reading, unix_timestamp())?)
+                    .await
+                    .is_err()
+                {
+                    // It closed its stdin, so it's exiting or has exited.
+                    return Ok(Some(child.wait().await?));
+                }
+            }
+            Err(RecvError::Lagged(skipped)) => {
+                println!(
+                    "{}: falling behind, dropped {} readings",
+                    config.name, skipped
+                );
+            }
+            Err(RecvError::Closed) => {
+                drop(stdin);
+                child.wait().await?;
+                return Ok(None);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::sync::broadcast;
+
+    use crate::config::ExecConfig;
+    use crate::exec::run;
+    use crate::{DeviceId, DeviceReading, Measurement};
+
+    #[tokio::test]
+    async fn test_exec() {
+        let path = std::env::temp_dir().join(format!("blueplug-exec-{}", std::process::id()));
+        let config = ExecConfig {
+            name: "exec".to_string(),
+            command: vec![
+                "sh".to_string(),
+                "-c".to_string(),
+                r#"head -n 1 > "$0""#.to_string(),
+                path.to_string_lossy().into_owned(),
+            ],
+        };
+        let (sender, mut receiver) = broadcast::channel(16);
+        let reading = DeviceReading {
+            device_id: DeviceId {
+                id: "hci0/dev_A4_C1_38_00_00_0F".to_string(),
+                device_name: "ATC_EXEC".to_string(),
+                address: String::new(),
+                random_address: None,
+            },
+            measurement: Measurement::Humidity(40.0),
+            advertisement: None,
+            quality: Vec::new(),
+        };
+        assert!(sender.send(Arc::new(reading)).is_ok());
+
+        // The command exits after a line, and the sink sees it exit.
+        let status = run(&config, &mut receiver).await.unwrap().unwrap();
+        assert!(status.success());
+        let line = std::fs::read_to_string(&path).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(json["device"]["name"], "ATC_EXEC");
+        assert_eq!(json["measurement"]["kind"], "humidity");
+        std::fs::remove_file(&path).unwrap();
+
+        drop(sender);
+        assert!(run(&config, &mut receiver).await.unwrap().is_none());
+        let _ = std::fs::remove_file(&path);
+
+        let missing = ExecConfig {
+            name: "exec".to_string(),
+            command: vec!["/nonexistent/blueplug-consumer".to_string()],
+        };
+        assert!(run(&missing, &mut receiver).await.is_err());
+    }
+}
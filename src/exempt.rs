@@ -0,0 +1,50 @@
+use std::ptr;
+use std::sync::{LazyLock, Mutex};
+
+use crate::events::is_event;
+use crate::scope;
+use crate::Measurement;
+
+// EXEMPT holds the measurement kinds configured as exempt from throttling, such as
+// smoke_detected, leak or button, which must be published as soon as they are read. Repeats of
+// an advertisement that decodes to one of them aren't deduplicated, and their readings skip MQTT
+// batches, rate limits and Theengs aggregation, and KNX's min_interval. Events and alerts are
+// always published that way; configuring their kinds only exempts them from deduplication too.
+// An embedded Blueplug's tasks see the kinds it was built with instead.
+pub static EXEMPT: LazyLock<Exempt> = LazyLock::new(Exempt::default);
+
+#[derive(Default)]
+pub struct Exempt {
+    kinds: Mutex<Vec<String>>,
+}
+
+impl Exempt {
+    // with calls f with the kinds of the embedded Blueplug whose task is running, if any, in place
+    // of EXEMPT's.
+    fn with<R>(&self, f: impl FnOnce(&mut Vec<String>) -> R) -> R {
+        if ptr::eq(self, &*EXEMPT) {
+            if let Some(scope) = scope::current() {
+                return f(&mut scope.exempt.kinds.lock().unwrap());
+            }
+        }
+        f(&mut self.kinds.lock().unwrap())
+    }
+
+    pub fn set_kinds(&self, kinds: Vec<String>) {
+        self.with(|exempt| *exempt = kinds);
+    }
+
+    pub fn kinds(&self) -> Vec<String> {
+        self.with(|exempt| exempt.clone())
+    }
+
+    // contains tells whether kind was configured as exempt.
+    pub fn contains(&self, kind: &str) -> bool {
+        self.with(|exempt| exempt.iter().any(|exempt| exempt == kind))
+    }
+
+    // is_exempt tells whether a reading skips throttling, batching and aggregation.
+    pub fn is_exempt(&self, measurement: &Measurement) -> bool {
+        is_event(measurement) || measurement.is_alert() || self.contains(measurement.kind())
+    }
+}
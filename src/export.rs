@@ -0,0 +1,262 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use jiff::Timestamp;
+use parquet::file::reader::{FileReader, SerializedFileReader};
+use parquet::record::RowAccessor;
+
+use crate::config::Config;
+use crate::envelope::ndjson;
+use crate::grafana::lines;
+use crate::history::{files, parse_time};
+use crate::names::{NameRules, TOPIC_NAMES};
+use crate::{DeviceId, DeviceReading, Measurement};
+
+// ExportArgs configures `blueplug export`, which dumps readings from the Parquet archive (see
+// archive.rs) for analysis in tools that don't read Parquet, or for moving to another system.
+// Readings come out oldest first in the formats `blueplug import` reads, and in Influx line
+// protocol.
+#[derive(clap::Args, Debug)]
+pub struct ExportArgs {
+    /// Start of the range to export, in RFC 3339 or Unix seconds (defaults to the beginning)
+    #[arg(long)]
+    from: Option<String>,
+    /// End of the range to export, in RFC 3339 or Unix seconds (defaults to now)
+    #[arg(long)]
+    to: Option<String>,
+    #[arg(long, value_enum, default_value_t = ExportFormat::Csv)]
+    format: ExportFormat,
+    /// Device to export, by its name in topics (defaults to all of them)
+    #[arg(long)]
+    device: Option<String>,
+    /// File to write to, instead of standard output
+    #[arg(short = 'o', long)]
+    output: Option<PathBuf>,
+    /// Configuration file to take the [archive] directory, names, locations and labels from
+    #[arg(short = 'c', long)]
+    config: Option<PathBuf>,
+    /// Archive directory, instead of the one in --config
+    #[arg(long)]
+    directory: Option<PathBuf>,
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy)]
+pub enum ExportFormat {
+    /// CSV with the columns timestamp, device, kind, value, unit, id and address
+    Csv,
+    /// Envelopes, one per line
+    Jsonl,
+    /// InfluxDB line protocol, a line per device and advertisement, tagged with locations and
+    /// labels
+    Influx,
+}
+
+pub fn run(args: ExportArgs) -> Result<()> {
+    let config = match &args.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    TOPIC_NAMES.set_rules(NameRules::new(&config.names)?);
+    TOPIC_NAMES.set_locations(config.locations.clone());
+    TOPIC_NAMES.set_labels(config.labels.clone());
+    let directory = args
+        .directory
+        .or(config.archive.map(|archive| archive.directory))
+        .ok_or_else(|| {
+            eyre!("no archive to export, pass --directory or a --config with [archive]")
+        })?;
+    let from = args.from.as_deref().map(parse_time).transpose()?;
+    let to = args.to.as_deref().map(parse_time).transpose()?;
+    let readings = readings(
+        &directory,
+        args.device.as_deref(),
+        from.unwrap_or(0),
+        to.unwrap_or(Timestamp::now().as_millisecond()),
+    )?;
+
+    let count = readings.len();
+    match &args.output {
+        Some(path) => {
+            let file =
+                File::create(path).wrap_err_with(|| format!("creating {}", path.display()))?;
+            let mut output = BufWriter::new(file);
+            export(&readings, args.format, &mut output)?;
+            output.flush()?;
+            println!("{}: exported {} readings", path.display(), count);
+        }
+        None => export(&readings, args.format, &mut std::io::stdout().lock())?,
+    }
+    Ok(())
+}
+
+// readings reads the readings between from and to, in Unix milliseconds, oldest first.
+fn readings(
+    directory: &Path,
+    device: Option<&str>,
+    from: i64,
+    to: i64,
+) -> Result<Vec<(Timestamp, Arc<DeviceReading>)>> {
+    if from > to {
+        return Err(eyre!("from is after to"));
+    }
+    let mut readings = Vec::new();
+    for path in files(directory, device, from, to)? {
+        let reader = SerializedFileReader::new(
+            File::open(&path).wrap_err_with(|| format!("opening {}", path.display()))?,
+        )?;
+        for row in reader.get_row_iter(None)? {
+            let row = row?;
+            let timestamp = row.get_timestamp_millis(0)?;
+            if timestamp < from || timestamp > to {
+                continue;
+            }
+            let reading = DeviceReading {
+                device_id: DeviceId {
+                    id: row.get_string(1)?.clone(),
+                    device_name: row.get_string(2)?.clone(),
+                    address: row.get_string(3)?.clone(),
+                    random_address: None,
+                },
+                measurement: Measurement::new(
+                    row.get_string(4)?.clone(),
+                    row.get_double(5)?,
+                    row.get_string(6)?.clone(),
+                ),
+                advertisement: row.get_long(7).ok().map(|a| a as u64),
+                quality: Vec::new(),
+            };
+            readings.push((Timestamp::from_millisecond(timestamp)?, Arc::new(reading)));
+        }
+    }
+    readings.sort_by_key(|(timestamp, _)| *timestamp);
+    Ok(readings)
+}
+
+fn export(
+    readings: &[(Timestamp, Arc<DeviceReading>)],
+    format: ExportFormat,
+    output: &mut impl Write,
+) -> Result<()> {
+    match format {
+        ExportFormat::Csv => {
+            writeln!(output, "timestamp,device,kind,value,unit,id,address")?;
+            for (timestamp, reading) in readings {
+                let id = &reading.device_id;
+                let fields = [
+                    timestamp.to_string(),
+                    csv_field(&id.device_name),
+                    csv_field(reading.measurement.kind()),
+                    reading.measurement.value().to_string(),
+                    csv_field(reading.measurement.unit()),
+                    csv_field(&id.id),
+                    csv_field(&id.address),
+                ];
+                writeln!(output, "{}", fields.join(","))?;
+            }
+        }
+        ExportFormat::Jsonl => {
+            for (timestamp, reading) in readings {
+                output.write_all(&ndjson(reading, timestamp.as_second() as u64)?)?;
+            }
+        }
+        ExportFormat::Influx => {
+            let readings: Vec<_> = readings
+                .iter()
+                .map(|(timestamp, reading)| (reading.clone(), SystemTime::from(*timestamp)))
+                .collect();
+            output.write_all(lines(&readings).as_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+// csv_field quotes a field if it holds a comma, a quote or a line break.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use jiff::Timestamp;
+
+    use crate::archive::Archive;
+    use crate::config::{ArchiveConfig, ArchivePartition};
+    use crate::export::{export, readings, ExportFormat};
+    use crate::{DeviceId, DeviceReading, Measurement};
+
+    #[test]
+    fn test_export() {
+        let directory =
+            std::env::temp_dir().join(format!("blueplug-export-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        let mut archive = Archive::new(
+            &ArchiveConfig {
+                directory: directory.clone(),
+                partition: ArchivePartition::Hourly,
+                interval: 3600,
+                max_age: None,
+                max_size: None,
+            },
+            Some("UTC"),
+        )
+        .unwrap();
+        let reading = |name: &str, measurement| DeviceReading {
+            device_id: DeviceId {
+                id: format!("hci0/{}", name),
+                device_name: name.to_string(),
+                address: String::new(),
+                random_address: None,
+            },
+            measurement,
+            advertisement: Some(7),
+            quality: Vec::new(),
+        };
+        let start: Timestamp = "2024-06-01T20:59:00Z".parse().unwrap();
+        let later = start + jiff::SignedDuration::from_secs(120);
+        archive.push(&reading("Shed, north", Measurement::Humidity(40.0)), later);
+        archive.push(
+            &reading("ATC_EXPORT", Measurement::Temperature(21.5)),
+            start,
+        );
+        archive.push(
+            &reading("ATC_EXPORT", Measurement::Temperature(21.6)),
+            later,
+        );
+        archive.write(later).unwrap();
+
+        let found = readings(&directory, None, 0, later.as_millisecond()).unwrap();
+        let mut csv = Vec::new();
+        export(&found, ExportFormat::Csv, &mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "timestamp,device,kind,value,unit,id,address\n\
+             2024-06-01T20:59:00Z,ATC_EXPORT,temperature,21.5,°C,hci0/ATC_EXPORT,\n\
+             2024-06-01T21:01:00Z,ATC_EXPORT,temperature,21.6,°C,hci0/ATC_EXPORT,\n\
+             2024-06-01T21:01:00Z,\"Shed, north\",humidity,40,%,\"hci0/Shed, north\",\n"
+        );
+
+        // One device, from a minute in.
+        let found = readings(
+            &directory,
+            Some("ATC_EXPORT"),
+            start.as_millisecond() + 60_000,
+            later.as_millisecond(),
+        )
+        .unwrap();
+        let mut influx = Vec::new();
+        export(&found, ExportFormat::Influx, &mut influx).unwrap();
+        assert_eq!(
+            String::from_utf8(influx).unwrap(),
+            "ATC_EXPORT temperature=21.6 1717275660000000000\n"
+        );
+        assert!(readings(&directory, None, 1, 0).is_err());
+    }
+}
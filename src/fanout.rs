@@ -0,0 +1,222 @@
+use std::sync::Arc;
+
+use color_eyre::eyre::{eyre, Result};
+use tokio::sync::broadcast;
+
+use crate::config::RouteConfig;
+use crate::metrics::{Stage, METRICS};
+use crate::{DeviceId, DeviceReading};
+
+// How many readings each sink may fall behind before it starts dropping the oldest, by default.
+const SINK_CHANNEL_CAPACITY: usize = 256;
+
+// Fanout delivers readings to every sink. Each sink gets its own channel so a slow one can't hold
+// up the rest, and routes restrict which readings a sink is sent: a sink named by one or more
+// routes only receives readings matching at least one of them, other sinks receive everything.
+pub struct Fanout {
+    routes: Vec<RouteConfig>,
+    sinks: Vec<Sink>,
+    capacity: usize,
+}
+
+struct Sink {
+    name: String,
+    sender: broadcast::Sender<Arc<DeviceReading>>,
+}
+
+impl Fanout {
+    pub fn new(routes: Vec<RouteConfig>) -> Self {
+        Fanout::with_capacity(routes, SINK_CHANNEL_CAPACITY)
+    }
+
+    pub fn with_capacity(routes: Vec<RouteConfig>, capacity: usize) -> Self {
+        Fanout {
+            routes,
+            sinks: Vec::new(),
+            capacity,
+        }
+    }
+
+    pub fn subscribe(&mut self, name: &str) -> broadcast::Receiver<Arc<DeviceReading>> {
+        let (sender, receiver) = broadcast::channel(self.capacity);
+        self.sinks.push(Sink {
+            name: name.to_string(),
+            sender,
+        });
+        receiver
+    }
+
+    // Call once all sinks have subscribed, so typos in route sink names are caught at startup.
+    pub fn validate(&self) -> Result<()> {
+        for route in &self.routes {
+            for sink in &route.sinks {
+                if !self.sinks.iter().any(|s| &s.name == sink) {
+                    return Err(eyre!("route refers to unknown sink {:?}", sink));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // send counts a reading as filtered once, when routes kept it from every sink.
+    pub fn send(&self, reading: Arc<DeviceReading>) {
+        let mut routed = false;
+        for sink in &self.sinks {
+            if self.routed_to(&sink.name, &reading) {
+                routed = true;
+                // Sending only fails when the sink has stopped listening.
+                let _ = sink.sender.send(reading.clone());
+            }
+        }
+        if !routed && !self.sinks.is_empty() {
+            METRICS.reading(Stage::Filtered, &reading.device_id);
+        }
+    }
+
+    pub fn routed_to(&self, sink: &str, reading: &DeviceReading) -> bool {
+        let mut routes = self
+            .routes
+            .iter()
+            .filter(|route| route.sinks.iter().any(|s| s == sink))
+            .peekable();
+        if routes.peek().is_none() {
+            return true;
+        }
+        routes.any(|route| route.matches(reading))
+    }
+}
+
+impl RouteConfig {
+    pub fn matches(&self, reading: &DeviceReading) -> bool {
+        let devices_match = devices_match(&self.devices, &reading.device_id);
+        let kind = reading.measurement.kind().to_string();
+        let kinds_match = self.kinds.is_empty() || self.kinds.contains(&kind);
+        devices_match && kinds_match
+    }
+}
+
+// devices_match reports whether device matches any of patterns, by name or id. An empty list
+// matches every device.
+pub fn devices_match(patterns: &[String], device: &DeviceId) -> bool {
+    patterns.is_empty()
+        || patterns
+            .iter()
+            .any(|pattern| glob_match(pattern, &device.device_name) || *pattern == device.id)
+}
+
+// glob_match supports `*` as a wildcard for any run of characters, e.g. `ATC_*`.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = text.strip_prefix(first) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::config::RouteConfig;
+    use crate::fanout::{glob_match, Fanout};
+    use crate::metrics::METRICS;
+    use crate::{DeviceId, DeviceReading, Measurement};
+
+    fn reading(device_name: &str, measurement: Measurement) -> DeviceReading {
+        DeviceReading {
+            device_id: DeviceId {
+                id: device_name.to_string(),
+                device_name: device_name.to_string(),
+                address: String::new(),
+                random_address: None,
+            },
+            measurement,
+            advertisement: None,
+            quality: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("ATC_*", "ATC_8F2C1A"));
+        assert!(glob_match("*C1A", "ATC_8F2C1A"));
+        assert!(glob_match("A*C*A", "ATC_8F2C1A"));
+        assert!(glob_match("ATC_8F2C1A", "ATC_8F2C1A"));
+        assert!(!glob_match("ATC_", "ATC_8F2C1A"));
+        assert!(!glob_match("Ruuvi*", "ATC_8F2C1A"));
+    }
+
+    #[test]
+    fn test_routing() {
+        let mut fanout = Fanout::new(vec![RouteConfig {
+            sinks: vec!["temperatures".to_string()],
+            devices: vec![],
+            kinds: vec!["temperature".to_string()],
+        }]);
+        let mut temperatures = fanout.subscribe("temperatures");
+        let everything = fanout.subscribe("everything");
+        fanout.validate().unwrap();
+
+        fanout.send(Arc::new(reading("ATC_1", Measurement::Humidity(40.0))));
+        fanout.send(Arc::new(reading("ATC_1", Measurement::Temperature(20.0))));
+
+        assert_eq!(
+            temperatures
+                .try_recv()
+                .unwrap()
+                .measurement
+                .kind()
+                .to_string(),
+            "temperature"
+        );
+        assert!(temperatures.try_recv().is_err());
+        assert_eq!(everything.len(), 2);
+    }
+
+    #[test]
+    fn test_filtered_once_per_reading() {
+        let filtered = || {
+            METRICS
+                .snapshot()
+                .get("unknown")
+                .and_then(|devices| devices.get("ATC_FILTERED"))
+                .map_or(0, |counters| counters.filtered)
+        };
+        let route = |sink: &str| RouteConfig {
+            sinks: vec![sink.to_string()],
+            devices: vec![],
+            kinds: vec!["temperature".to_string()],
+        };
+        let mut fanout = Fanout::new(vec![route("local"), route("cloud")]);
+        let _local = fanout.subscribe("local");
+        let _cloud = fanout.subscribe("cloud");
+        let _everything = fanout.subscribe("everything");
+
+        // Kept from two sinks, but one still received it.
+        fanout.send(Arc::new(reading(
+            "ATC_FILTERED",
+            Measurement::Humidity(40.0),
+        )));
+        assert_eq!(filtered(), 0);
+
+        let mut fanout = Fanout::new(vec![route("local"), route("cloud")]);
+        let _local = fanout.subscribe("local");
+        let _cloud = fanout.subscribe("cloud");
+        fanout.send(Arc::new(reading(
+            "ATC_FILTERED",
+            Measurement::Humidity(40.0),
+        )));
+        assert_eq!(filtered(), 1);
+    }
+}
@@ -0,0 +1,140 @@
+use color_eyre::eyre::Result;
+use uuid::Uuid;
+
+use crate::gatt::Reader;
+use crate::Measurement;
+
+// The measurement characteristics of the standard Heart Rate (0x180D) and Cycling Power (0x1818)
+// services, which connectable sensors notify about once subscribed.
+pub const HEART_RATE_MEASUREMENT: Uuid = Uuid::from_u128(0x00002a37_0000_1000_8000_00805f9b34fb);
+pub const CYCLING_POWER_MEASUREMENT: Uuid = Uuid::from_u128(0x00002a63_0000_1000_8000_00805f9b34fb);
+
+// heart_rate decodes a Heart Rate Measurement. Nothing is returned while a sensor that can tell
+// reports it has lost contact with the skin.
+pub fn heart_rate(value: &[u8]) -> Result<Vec<Measurement>> {
+    let mut reader = Reader(value);
+    let flags = reader.u8()?;
+    let bpm = if flags & 0x01 != 0 {
+        reader.u16()?
+    } else {
+        reader.u8()? as u16
+    };
+    let contact_supported = flags & 0x04 != 0;
+    let contact_detected = flags & 0x02 != 0;
+    if contact_supported && !contact_detected {
+        return Ok(Vec::new());
+    }
+    let mut measurements = vec![Measurement::HeartRate(bpm as f64)];
+    if flags & 0x08 != 0 {
+        measurements.push(Measurement::Other {
+            kind: "energy_expended".to_string(),
+            value: reader.u16()? as f64,
+            unit: "kJ".to_string(),
+        });
+    }
+    // Of the RR-intervals since the last notification, the most recent is reported.
+    if flags & 0x10 != 0 {
+        let mut last = None;
+        while let Ok(interval) = reader.u16() {
+            last = Some(interval);
+        }
+        if let Some(interval) = last {
+            measurements.push(Measurement::Other {
+                kind: "rr_interval".to_string(),
+                value: (interval as f64 * 1000.0 / 1024.0).round(),
+                unit: "ms".to_string(),
+            });
+        }
+    }
+    Ok(measurements)
+}
+
+// CyclingPower decodes Cycling Power Measurements. Cadence needs two consecutive crank revolution
+// counts, so the previous one is kept.
+#[derive(Default)]
+pub struct CyclingPower {
+    // Cumulative crank revolutions and the time of the last crank event, in 1/1024 s.
+    previous_crank: Option<(u16, u16)>,
+}
+
+impl CyclingPower {
+    pub fn measurements(&mut self, value: &[u8]) -> Result<Vec<Measurement>> {
+        let mut reader = Reader(value);
+        let flags = reader.u16()?;
+        let power = reader.u16()? as i16;
+        let mut measurements = vec![Measurement::Power(power as f64)];
+        if flags & 0x0001 != 0 {
+            let balance = reader.u8()?;
+            measurements.push(Measurement::Other {
+                kind: "pedal_power_balance".to_string(),
+                value: balance as f64 / 2.0,
+                unit: "%".to_string(),
+            });
+        }
+        if flags & 0x0004 != 0 {
+            reader.skip(2)?; // accumulated torque
+        }
+        if flags & 0x0010 != 0 {
+            reader.skip(6)?; // wheel revolutions and last wheel event time
+        }
+        if flags & 0x0020 != 0 {
+            let crank = (reader.u16()?, reader.u16()?);
+            if let Some((revolutions, time)) = self.previous_crank {
+                let revolutions = crank.0.wrapping_sub(revolutions);
+                let elapsed = crank.1.wrapping_sub(time);
+                // Without a new crank event the cadence is unknown rather than zero, until the
+                // rider has clearly stopped pedalling.
+                if elapsed > 0 {
+                    let rpm = revolutions as f64 * 60.0 * 1024.0 / elapsed as f64;
+                    measurements.push(cadence(rpm));
+                } else if revolutions == 0 && power == 0 {
+                    measurements.push(cadence(0.0));
+                }
+            }
+            self.previous_crank = Some(crank);
+        }
+        Ok(measurements)
+    }
+}
+
+fn cadence(rpm: f64) -> Measurement {
+    Measurement::Other {
+        kind: "cadence".to_string(),
+        value: rpm.round(),
+        unit: "rpm".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fitness::{heart_rate, CyclingPower};
+    use crate::Measurement;
+
+    #[test]
+    fn test_fitness() {
+        assert_eq!(
+            heart_rate(&[0x00, 72]).unwrap(),
+            vec![Measurement::HeartRate(72.0)]
+        );
+        // Contact supported but not detected.
+        assert!(heart_rate(&[0x04, 72]).unwrap().is_empty());
+        // 16 bit rate, contact detected, two RR-intervals.
+        let measurements = heart_rate(&[0x17, 0x90, 0x00, 0x00, 0x04, 0x00, 0x03]).unwrap();
+        assert_eq!(measurements[0], Measurement::HeartRate(144.0));
+        assert_eq!(measurements[1].value(), 750.0);
+
+        let mut cycling_power = CyclingPower::default();
+        // Crank revolution data present: 10 revolutions at 1024/1024 s.
+        let first = cycling_power
+            .measurements(&[0x20, 0x00, 0xfa, 0x00, 0x0a, 0x00, 0x00, 0x04])
+            .unwrap();
+        assert_eq!(first, vec![Measurement::Power(250.0)]);
+        // One more revolution 2/3 s later: 90 rpm.
+        let second = cycling_power
+            .measurements(&[0x20, 0x00, 0x04, 0x01, 0x0b, 0x00, 0xab, 0x06])
+            .unwrap();
+        assert_eq!(second[0], Measurement::Power(260.0));
+        assert_eq!(second[1].kind(), "cadence");
+        assert_eq!(second[1].value(), 90.0);
+    }
+}
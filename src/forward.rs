@@ -0,0 +1,422 @@
+use std::collections::HashMap;
+use std::mem;
+use std::time::Duration;
+
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use futures_util::pin_mut;
+use futures_util::stream::StreamExt;
+use rumqttc::{AsyncClient, QoS};
+use serde::{Deserialize, Serialize};
+use tokio::time;
+use uuid::Uuid;
+
+use crate::adapter::AdapterSelector;
+use crate::compression::{decompress, Compression};
+use crate::config::BrokerConfig;
+use crate::dedup::dedup_stream;
+use crate::error::BlueplugError;
+use crate::mqtt::{mqtt_options, Connection, PUBLISH_OVERHEAD};
+use crate::preflight::preflight;
+use crate::supervisor::SUPERVISOR;
+use crate::{bt_stream, DeviceEvent, DeviceId, DEFAULT_SCAN_STALL_TIMEOUT};
+
+pub const DEFAULT_TOPIC_PREFIX: &str = "blueplug/forward";
+
+// ForwardArgs configures `blueplug forward`, which runs on edge nodes and ships raw advertisements
+// to a central instance for decoding, so the nodes need neither decoders nor sink configuration.
+#[derive(clap::Args, Debug)]
+pub struct ForwardArgs {
+    /// Name of this node, which the central instance prefixes device ids with
+    #[arg(long)]
+    node: String,
+    /// URL of the central instance's forward endpoint, e.g. http://central:8099/forward
+    #[arg(
+        long,
+        required_unless_present = "mqtt_addr",
+        conflicts_with = "mqtt_addr"
+    )]
+    url: Option<String>,
+    #[arg(short = 'i', long, requires = "mqtt_addr")]
+    client_id: Option<String>,
+    #[arg(short = 'a', long, requires = "client_id")]
+    mqtt_addr: Option<String>,
+    #[arg(short = 'p', long, default_value_t = 1883)]
+    mqtt_port: u16,
+    /// Batches are published to <topic-prefix>/<node>
+    #[arg(long, default_value = DEFAULT_TOPIC_PREFIX)]
+    topic_prefix: String,
+    /// Bluetooth adapter to scan with, by index, name (e.g. hci1) or MAC address
+    #[arg(long)]
+    adapter: Option<AdapterSelector>,
+    /// Seconds during which identical advertisements from a device are dropped (0 disables)
+    #[arg(long, default_value_t = 2)]
+    dedup_window: u64,
+    /// Milliseconds to collect advertisements for before sending them as one batch
+    #[arg(long, default_value_t = 1000, value_parser = clap::value_parser!(u64).range(1..))]
+    batch_interval: u64,
+    /// Compression of batches; zstd needs a central instance from this release on
+    #[arg(long, value_enum, default_value_t = Compression::Gzip)]
+    compression: Compression,
+}
+
+// ForwardBatch is what a forwarder sends: JSON, compressed with --compression, one per batch
+// interval.
+#[derive(Serialize, Deserialize, Debug)]
+struct ForwardBatch {
+    node: String,
+    events: Vec<ForwardedEvent>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ForwardedEvent {
+    id: String,
+    device_name: String,
+    address: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    random_address: Option<bool>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    manufacturer_data: HashMap<u16, Vec<u8>>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    service_data: HashMap<Uuid, Vec<u8>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    rssi: Option<i16>,
+}
+
+impl From<DeviceEvent> for ForwardedEvent {
+    fn from(event: DeviceEvent) -> Self {
+        let (device_id, manufacturer_data, service_data, rssi) = match event {
+            DeviceEvent::ManufacturerDataAdvertisement {
+                device_id,
+                manufacturer_data,
+                rssi,
+            } => (device_id, manufacturer_data, HashMap::new(), rssi),
+            DeviceEvent::ServiceDataAdvertisement {
+                device_id,
+                service_data,
+                rssi,
+            } => (device_id, HashMap::new(), service_data, rssi),
+        };
+        ForwardedEvent {
+            id: device_id.id,
+            device_name: device_id.device_name,
+            address: device_id.address,
+            random_address: device_id.random_address,
+            manufacturer_data,
+            service_data,
+            rssi,
+        }
+    }
+}
+
+impl ForwardedEvent {
+    // sighting keeps only which device advertised and how strongly, which is all presence needs.
+    fn sighting(event: DeviceEvent) -> Self {
+        ForwardedEvent {
+            manufacturer_data: HashMap::new(),
+            service_data: HashMap::new(),
+            ..ForwardedEvent::from(event)
+        }
+    }
+
+    fn into_events(self, node: &str) -> Vec<DeviceEvent> {
+        let device_id = DeviceId {
+            id: format!("{}/{}", node, self.id),
+            device_name: self.device_name,
+            address: self.address,
+            random_address: self.random_address,
+        };
+        // A sighting has neither, and is passed on with empty manufacturer data for presence to
+        // count and decoding to find nothing in.
+        let sighting = self.manufacturer_data.is_empty() && self.service_data.is_empty();
+        let mut events = Vec::new();
+        if !self.manufacturer_data.is_empty() || sighting {
+            events.push(DeviceEvent::ManufacturerDataAdvertisement {
+                device_id: device_id.clone(),
+                manufacturer_data: self.manufacturer_data,
+                rssi: self.rssi,
+            });
+        }
+        if !self.service_data.is_empty() {
+            events.push(DeviceEvent::ServiceDataAdvertisement {
+                device_id,
+                service_data: self.service_data,
+                rssi: self.rssi,
+            });
+        }
+        events
+    }
+}
+
+fn encode(batch: &ForwardBatch, compression: Compression) -> Result<Vec<u8>> {
+    compression.compress(&serde_json::to_vec(batch)?)
+}
+
+// encode_within encodes a node's events as batches of at most max_size bytes, halving them until
+// they fit, with the number of events in each. A single event too large on its own is dropped.
+fn encode_within(
+    node: &str,
+    events: Vec<ForwardedEvent>,
+    compression: Compression,
+    max_size: usize,
+) -> Result<Vec<(usize, Vec<u8>)>> {
+    let count = events.len();
+    let batch = ForwardBatch {
+        node: node.to_string(),
+        events,
+    };
+    let body = encode(&batch, compression)?;
+    if body.len() <= max_size {
+        return Ok(vec![(count, body)]);
+    }
+    if count == 1 {
+        println!("dropped an advertisement of {} bytes", body.len());
+        return Ok(Vec::new());
+    }
+    let mut first = batch.events;
+    let second = first.split_off(count / 2);
+    let mut bodies = encode_within(node, first, compression, max_size)?;
+    bodies.extend(encode_within(node, second, compression, max_size)?);
+    Ok(bodies)
+}
+
+// decode unpacks a batch received from a forwarder into the events it carries.
+pub fn decode(body: &[u8]) -> Result<Vec<DeviceEvent>, BlueplugError> {
+    let decode = || -> Result<Vec<DeviceEvent>> {
+        let json = decompress(body).wrap_err("decompressing forwarded batch")?;
+        let batch: ForwardBatch =
+            serde_json::from_slice(&json).wrap_err("parsing forwarded batch")?;
+        Ok(batch
+            .events
+            .into_iter()
+            .flat_map(|event| event.into_events(&batch.node))
+            .collect())
+    };
+    decode().map_err(|e| BlueplugError::Decode(e.into()))
+}
+
+enum Transport {
+    Http {
+        client: reqwest::Client,
+        url: String,
+        compression: Compression,
+    },
+    Mqtt {
+        client: AsyncClient,
+        topic: String,
+        max_packet_size: usize,
+    },
+}
+
+impl Transport {
+    fn new(args: &ForwardArgs) -> Result<Self> {
+        if let Some(url) = &args.url {
+            return Ok(Transport::Http {
+                client: reqwest::Client::new(),
+                url: url.clone(),
+                compression: args.compression,
+            });
+        }
+        let (Some(mqtt_addr), Some(client_id)) = (&args.mqtt_addr, &args.client_id) else {
+            return Err(eyre!("pass either --url or --mqtt-addr and --client-id"));
+        };
+        let broker = BrokerConfig::new(
+            "forward",
+            mqtt_addr.clone(),
+            args.mqtt_port,
+            client_id.clone(),
+        );
+        let (client, eventloop) = AsyncClient::new(mqtt_options(&broker)?, 10);
+        let mut connection = Connection::new("forward", eventloop);
+        SUPERVISOR.spawn_once("forward", async move {
+            loop {
+                connection.next().await;
+            }
+        });
+        Ok(Transport::Mqtt {
+            client,
+            topic: format!("{}/{}", args.topic_prefix, args.node),
+            max_packet_size: broker.max_packet_size,
+        })
+    }
+
+    // max_size is the largest body that can be sent, as batches are published in one MQTT packet.
+    fn max_size(&self) -> usize {
+        match self {
+            Transport::Http { .. } => usize::MAX,
+            Transport::Mqtt {
+                topic,
+                max_packet_size,
+                ..
+            } => max_packet_size.saturating_sub(PUBLISH_OVERHEAD + topic.len()),
+        }
+    }
+
+    async fn send(&self, body: Vec<u8>) -> Result<()> {
+        match self {
+            Transport::Http {
+                client,
+                url,
+                compression,
+            } => {
+                let mut request = client.post(url).header("Content-Type", "application/json");
+                if let Some(encoding) = compression.content_encoding() {
+                    request = request.header("Content-Encoding", encoding);
+                }
+                request.body(body).send().await?.error_for_status()?;
+            }
+            Transport::Mqtt { client, topic, .. } => {
+                client.publish(topic, QoS::AtLeastOnce, false, body).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// run scans and forwards advertisements undecoded until the Bluetooth event stream ends.
+pub async fn run(args: ForwardArgs) -> Result<()> {
+    preflight(args.adapter.as_ref()).await?;
+    let transport = Transport::new(&args)?;
+
+    let events = dedup_stream(
+        bt_stream(args.adapter.clone(), Some(DEFAULT_SCAN_STALL_TIMEOUT)),
+        Duration::from_secs(args.dedup_window),
+    );
+    pin_mut!(events);
+
+    let mut batch = Vec::new();
+    let mut sightings: HashMap<String, ForwardedEvent> = HashMap::new();
+    let mut interval = time::interval(Duration::from_millis(args.batch_interval));
+    loop {
+        tokio::select! {
+            event = events.next() => match event {
+                // Devices without a name only matter to presence, so rather than every
+                // advertisement of every phone in range, each is forwarded once per batch as a
+                // sighting at the strongest RSSI it was received with.
+                Some(Ok(event)) if event.device_id().device_name.is_empty() => {
+                    let sighting = ForwardedEvent::sighting(event);
+                    match sightings.get(&sighting.address) {
+                        Some(seen) if seen.rssi >= sighting.rssi => {}
+                        _ => {
+                            sightings.insert(sighting.address.clone(), sighting);
+                        }
+                    }
+                }
+                Some(Ok(event)) => batch.push(ForwardedEvent::from(event)),
+                Some(Err(e)) => println!("received error! {:?}", e.to_string()),
+                None => return Err(eyre!("bluetooth event stream ended")),
+            },
+            _ = interval.tick() => {
+                batch.extend(sightings.drain().map(|(_, sighting)| sighting));
+                if batch.is_empty() {
+                    continue;
+                }
+                let bodies = encode_within(
+                    &args.node,
+                    mem::take(&mut batch),
+                    args.compression,
+                    transport.max_size(),
+                )?;
+                for (count, body) in bodies {
+                    match transport.send(body).await {
+                        Ok(()) => println!("forwarded {} advertisements", count),
+                        Err(e) => println!("forwarding {} advertisements failed: {:?}", count, e),
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::compression::Compression;
+    use crate::forward::{decode, encode, encode_within, ForwardBatch, ForwardedEvent};
+    use crate::{DeviceEvent, DeviceId};
+
+    #[test]
+    fn test_forward_round_trip() {
+        let event = DeviceEvent::ManufacturerDataAdvertisement {
+            device_id: DeviceId {
+                id: "hci0/dev_A4_C1_38_8F_2C_1A".to_string(),
+                device_name: "Ruuvi 2C1A".to_string(),
+                address: "A4:C1:38:8F:2C:1A".to_string(),
+                random_address: None,
+            },
+            manufacturer_data: HashMap::from([(0x0499, vec![5, 18, 252])]),
+            rssi: Some(-71),
+        };
+        let batch = ForwardBatch {
+            node: "garage".to_string(),
+            events: vec![ForwardedEvent::from(event)],
+        };
+
+        let events = decode(&encode(&batch, Compression::Zstd).unwrap()).unwrap();
+        assert_eq!(events.len(), 1);
+        let DeviceEvent::ManufacturerDataAdvertisement {
+            device_id,
+            manufacturer_data,
+            rssi: Some(-71),
+        } = &events[0]
+        else {
+            panic!("expected manufacturer data");
+        };
+        assert_eq!(device_id.id, "garage/hci0/dev_A4_C1_38_8F_2C_1A");
+        assert_eq!(device_id.address, "A4:C1:38:8F:2C:1A");
+        assert_eq!(manufacturer_data[&0x0499], vec![5, 18, 252]);
+
+        assert!(decode(b"not gzip").is_err());
+
+        // A phone without a name is forwarded as a sighting, without its payload.
+        let sighting = ForwardedEvent::sighting(DeviceEvent::ManufacturerDataAdvertisement {
+            device_id: DeviceId {
+                id: "hci0/dev_5A_11_22_33_44_55".to_string(),
+                device_name: String::new(),
+                address: "5A:11:22:33:44:55".to_string(),
+                random_address: Some(true),
+            },
+            manufacturer_data: HashMap::from([(0x004c, vec![0x10, 0x05, 0x01])]),
+            rssi: Some(-60),
+        });
+        let batch = ForwardBatch {
+            node: "garage".to_string(),
+            events: vec![sighting],
+        };
+        let events = decode(&encode(&batch, Compression::Gzip).unwrap()).unwrap();
+        let [DeviceEvent::ManufacturerDataAdvertisement {
+            device_id,
+            manufacturer_data,
+            rssi: Some(-60),
+        }] = &events[..]
+        else {
+            panic!("expected one sighting");
+        };
+        assert_eq!(device_id.address, "5A:11:22:33:44:55");
+        assert!(device_id.device_name.is_empty());
+        assert!(manufacturer_data.is_empty());
+
+        // Batches too large for an MQTT packet are split into ones that fit.
+        let events = (0..200)
+            .map(|i| {
+                ForwardedEvent::from(DeviceEvent::ManufacturerDataAdvertisement {
+                    device_id: DeviceId {
+                        id: format!("hci0/dev_{}", i),
+                        device_name: format!("Sensor {}", i),
+                        address: String::new(),
+                        random_address: None,
+                    },
+                    manufacturer_data: HashMap::from([(0x0499, vec![i as u8; 20])]),
+                    rssi: None,
+                })
+            })
+            .collect();
+        let bodies = encode_within("garage", events, Compression::None, 1024).unwrap();
+        assert!(bodies.len() > 1);
+        assert_eq!(bodies.iter().map(|(count, _)| count).sum::<usize>(), 200);
+        for (count, body) in bodies {
+            assert!(body.len() <= 1024);
+            assert_eq!(decode(&body).unwrap().len(), count);
+        }
+    }
+}
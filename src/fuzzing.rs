@@ -0,0 +1,60 @@
+use std::collections::HashMap;
+
+use btleplug::api::bleuuid::uuid_from_u16;
+
+use crate::diagnostics::{diagnose_manufacturer_data, diagnose_service_data};
+use crate::scale::MiScales;
+use crate::{airthings, bm2, fitness, renogy, scale};
+use crate::{measurements_from_manufacturer_data, measurements_from_service_data};
+use crate::{DeviceId, Measurement};
+
+// Entry points for the cargo-fuzz targets in fuzz/, only built with the fuzzing feature. They take
+// raw bytes, as received over the air, and run the decoders the way the pipeline does, but
+// without decode's catch_unwind, so a panic is found rather than logged.
+
+fn device_id() -> DeviceId {
+    DeviceId {
+        id: "fuzz/dev_00_00_00_00_00_00".to_string(),
+        device_name: "fuzz".to_string(),
+        address: "00:00:00:00:00:00".to_string(),
+        random_address: None,
+    }
+}
+
+// manufacturer_data decodes data as manufacturer data of company id, and diagnoses it if it
+// doesn't decode.
+pub fn manufacturer_data(id: u16, data: &[u8]) -> Vec<Measurement> {
+    let manufacturer_data = HashMap::from([(id, data.to_vec())]);
+    let measurements = measurements_from_manufacturer_data(&manufacturer_data);
+    if measurements.is_empty() {
+        diagnose_manufacturer_data(&device_id(), &manufacturer_data);
+    }
+    measurements
+}
+
+// service_data decodes data as service data of a 16 bit service UUID, including as a Mi Scale's,
+// and diagnoses it if it doesn't decode.
+pub fn service_data(uuid: u16, data: &[u8]) -> Vec<Measurement> {
+    let service_data = HashMap::from([(uuid_from_u16(uuid), data.to_vec())]);
+    let mut measurements = measurements_from_service_data(&service_data);
+    measurements.extend(MiScales::default().measurements(&device_id(), &service_data));
+    if measurements.is_empty() {
+        diagnose_service_data(&device_id(), &service_data);
+    }
+    measurements
+}
+
+// gatt decodes value as a notification or read of every GATT device, for the decoders that don't
+// keep state, and then as a series of cycling power measurements.
+pub fn gatt(value: &[u8]) {
+    let _ = airthings::wave_plus(value);
+    let _ = bm2::measurements(value);
+    let _ = renogy::measurements(value);
+    let _ = scale::weight_measurement(value);
+    let _ = scale::body_composition(value);
+    let _ = fitness::heart_rate(value);
+    let mut cycling_power = fitness::CyclingPower::default();
+    for chunk in value.chunks(8) {
+        let _ = cycling_power.measurements(chunk);
+    }
+}
@@ -0,0 +1,470 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_stream::stream;
+use btleplug::api::{Central, Characteristic, Peripheral as _, WriteType};
+use btleplug::platform::{Adapter, Manager, Peripheral};
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use futures_core::stream::Stream;
+use futures_util::stream::StreamExt;
+use serde::Deserialize;
+use tokio::sync::{mpsc, Semaphore};
+use tokio::time::{self, MissedTickBehavior};
+use uuid::Uuid;
+
+use crate::adapter::{select_adapter, AdapterSelector};
+use crate::config::{GattConfig, GattProtocol};
+use crate::fitness::{self, CyclingPower};
+use crate::metrics::{Stage, METRICS};
+use crate::mqtt::backoff;
+use crate::preflight::{add_device, explain, paired};
+use crate::supervisor::Supervisor;
+use crate::{
+    airthings, bm2, next_advertisement, renogy, scale, DeviceId, DeviceReading, Measurement,
+};
+
+// gatt_stream reads devices that only share their data over a GATT connection, yielding their
+// readings alongside the decoded advertisements. Each device has its own task. Polled devices, such
+// as solar charge controllers and air quality monitors, are connected to every poll_interval and
+// disconnected again so others can connect in between, e.g. the vendor's app. Devices configured as
+// not advertising are added to the adapter by address, as a scan may never come across them.
+// Devices that notify, such as heart rate monitors, scales and battery monitors, stay connected. At
+// most max_connections devices are connected at once, as every connection takes air time from the
+// scan and adapters only manage a handful; the others wait their turn.
+pub fn gatt_stream(
+    supervisor: Supervisor,
+    adapter: Option<AdapterSelector>,
+    devices: Vec<GattConfig>,
+    max_connections: Option<usize>,
+) -> impl Stream<Item = DeviceReading> {
+    let (sender, mut receiver) = mpsc::channel(16);
+    supervisor.clone().spawn_once("gatt", async move {
+        if devices.is_empty() {
+            return;
+        }
+        let manager = match Manager::new().await.map_err(explain) {
+            Ok(manager) => manager,
+            Err(e) => return println!("gatt: {:?}", e),
+        };
+        let central = match select_adapter(&manager, adapter.as_ref()).await {
+            Ok(central) => central,
+            Err(e) => return println!("gatt: {:?}", e),
+        };
+        let connections = Arc::new(Semaphore::new(
+            max_connections.unwrap_or(Semaphore::MAX_PERMITS).max(1),
+        ));
+        for device in devices {
+            let central = central.clone();
+            let sender = sender.clone();
+            let connections = connections.clone();
+            supervisor.spawn(device.name.clone(), move || {
+                poll_device(
+                    central.clone(),
+                    device.clone(),
+                    sender.clone(),
+                    connections.clone(),
+                )
+            });
+        }
+    });
+    stream! {
+        while let Some(reading) = receiver.recv().await {
+            yield reading;
+        }
+    }
+}
+
+// poll_device reads the device every poll_interval, or reconnects poll_interval after a device
+// that notifies dropped out. A failed attempt is retried up to retries times, backing off in
+// between, before waiting for the next poll_interval.
+async fn poll_device(
+    central: Adapter,
+    device: GattConfig,
+    sender: mpsc::Sender<DeviceReading>,
+    connections: Arc<Semaphore>,
+) {
+    let notifies = matches!(
+        device.protocol,
+        GattProtocol::HeartRate
+            | GattProtocol::CyclingPower
+            | GattProtocol::WeightScale
+            | GattProtocol::Bm2
+    );
+    let mut interval = time::interval(Duration::from_secs(device.poll_interval));
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        let mut failures = 0;
+        loop {
+            // A subscribed device holds its permit for as long as it stays connected.
+            let Ok(permit) = connections.acquire().await else {
+                return;
+            };
+            let result = if notifies {
+                subscribe(&central, &device, &sender).await
+            } else {
+                poll(&central, &device, &sender).await
+            };
+            drop(permit);
+            let Err(e) = result else {
+                break;
+            };
+            println!("{}: {:?}", device.name, e);
+            if failures >= device.retries || sender.is_closed() {
+                break;
+            }
+            failures += 1;
+            time::sleep(backoff(failures, fastrand::f64())).await;
+        }
+        if sender.is_closed() {
+            return;
+        }
+        // A connection that lasted longer than poll_interval has missed the tick, which would
+        // otherwise reconnect straight away.
+        if notifies {
+            interval.reset();
+        }
+    }
+}
+
+// poll connects to the device, which the scan must have come across, and reads its measurements.
+async fn poll(
+    central: &Adapter,
+    device: &GattConfig,
+    sender: &mpsc::Sender<DeviceReading>,
+) -> Result<()> {
+    let (peripheral, device_id) = connect(central, device).await?;
+    let measurements = time::timeout(timeout(device), exchange(&peripheral, device))
+        .await
+        .map_err(|_| eyre!("polling timed out"))
+        .and_then(|measurements| measurements);
+    let _ = peripheral.disconnect().await;
+    send(sender, &device_id, measurements?).await;
+    Ok(())
+}
+
+// subscribe connects to a device that notifies about every new measurement, and reports them
+// until it disconnects.
+async fn subscribe(
+    central: &Adapter,
+    device: &GattConfig,
+    sender: &mpsc::Sender<DeviceReading>,
+) -> Result<()> {
+    let (peripheral, device_id) = connect(central, device).await?;
+    // The first characteristic is required, the others are subscribed to if the device has them.
+    let uuids = match device.protocol {
+        GattProtocol::CyclingPower => vec![fitness::CYCLING_POWER_MEASUREMENT],
+        GattProtocol::WeightScale => vec![
+            scale::WEIGHT_MEASUREMENT,
+            scale::BODY_COMPOSITION_MEASUREMENT,
+        ],
+        GattProtocol::Bm2 => vec![bm2::NOTIFY_CHARACTERISTIC],
+        _ => vec![fitness::HEART_RATE_MEASUREMENT],
+    };
+    let subscribed = async {
+        peripheral.discover_services().await?;
+        let notifications = peripheral.notifications().await?;
+        peripheral
+            .subscribe(&characteristic(&peripheral, uuids[0])?)
+            .await?;
+        for uuid in &uuids[1..] {
+            if let Ok(characteristic) = characteristic(&peripheral, *uuid) {
+                peripheral.subscribe(&characteristic).await?;
+            }
+        }
+        Ok::<_, color_eyre::Report>(notifications)
+    };
+    let mut notifications = match time::timeout(timeout(device), subscribed).await {
+        Ok(Ok(notifications)) => notifications,
+        Ok(Err(e)) => {
+            let _ = peripheral.disconnect().await;
+            return Err(e);
+        }
+        Err(_) => {
+            let _ = peripheral.disconnect().await;
+            return Err(eyre!("subscribing timed out"));
+        }
+    };
+    println!("{}: connected", device.name);
+    let mut cycling_power = CyclingPower::default();
+    while let Some(notification) = notifications.next().await {
+        let value = &notification.value;
+        let measurements = match notification.uuid {
+            fitness::HEART_RATE_MEASUREMENT => fitness::heart_rate(value),
+            fitness::CYCLING_POWER_MEASUREMENT => cycling_power.measurements(value),
+            scale::WEIGHT_MEASUREMENT => scale::weight_measurement(value),
+            scale::BODY_COMPOSITION_MEASUREMENT => scale::body_composition(value),
+            bm2::NOTIFY_CHARACTERISTIC => bm2::measurements(value),
+            _ => continue,
+        };
+        match measurements {
+            Ok(measurements) => send(sender, &device_id, measurements).await,
+            Err(e) => println!("{}: {:?}", device.name, e),
+        }
+    }
+    Err(eyre!("disconnected"))
+}
+
+async fn send(
+    sender: &mpsc::Sender<DeviceReading>,
+    device_id: &DeviceId,
+    measurements: Vec<Measurement>,
+) {
+    if measurements.is_empty() {
+        return;
+    }
+    METRICS.reading(Stage::Decoded, device_id);
+    let advertisement = Some(next_advertisement());
+    for measurement in measurements {
+        let device_id = device_id.clone();
+        let _ = sender
+            .send(DeviceReading {
+                device_id,
+                measurement,
+                advertisement,
+                quality: Vec::new(),
+            })
+            .await;
+    }
+}
+
+// connect connects to the device, which the scan must have come across unless it doesn't
+// advertise. A bonded device connects with the keys kept from pairing.
+pub async fn connect(central: &Adapter, device: &GattConfig) -> Result<(Peripheral, DeviceId)> {
+    let peripheral = find(central, device).await?;
+    check_bonded(central, device).await?;
+    let device_id = DeviceId {
+        id: peripheral.id().to_string(),
+        device_name: device.name.clone(),
+        address: device.address.clone(),
+        random_address: None,
+    };
+    time::timeout(timeout(device), peripheral.connect())
+        .await
+        .map_err(|_| eyre!("timed out connecting"))??;
+    Ok((peripheral, device_id))
+}
+
+// timeout is how long connecting, and each exchange with the device, may take.
+fn timeout(device: &GattConfig) -> Duration {
+    Duration::from_secs(device.timeout)
+}
+
+// find looks the device up among those the adapter knows, adding a device that doesn't advertise
+// if it isn't, e.g. after a restart of the Bluetooth stack.
+pub async fn find(central: &Adapter, device: &GattConfig) -> Result<Peripheral> {
+    if let Some(peripheral) = lookup(central, &device.address).await? {
+        return Ok(peripheral);
+    }
+    if device.advertises {
+        return Err(eyre!("{} hasn't been seen by the scan yet", device.address));
+    }
+    // BlueZ describes an adapter as its name followed by its modalias.
+    let info = central.adapter_info().await?;
+    let adapter = info.split_whitespace().next().unwrap_or_default();
+    add_device(adapter, &device.address, device.address_type).await?;
+    lookup(central, &device.address).await?.ok_or_else(|| {
+        eyre!(
+            "{} was added but isn't listed by the adapter",
+            device.address
+        )
+    })
+}
+
+// check_bonded makes sure a device that requires bonding has been paired with, rather than
+// connecting only to be refused its characteristics.
+async fn check_bonded(central: &Adapter, device: &GattConfig) -> Result<()> {
+    if !device.bonded {
+        return Ok(());
+    }
+    let info = central.adapter_info().await?;
+    let adapter = info.split_whitespace().next().unwrap_or_default();
+    if !paired(adapter, &device.address).await? {
+        return Err(eyre!(
+            "{} requires bonding, pair with it using `blueplug pair {}`",
+            device.address,
+            device.name
+        ));
+    }
+    Ok(())
+}
+
+// lookup finds the device among those the adapter knows.
+pub async fn lookup(central: &Adapter, address: &str) -> Result<Option<Peripheral>> {
+    for peripheral in central.peripherals().await? {
+        let properties = peripheral.properties().await?;
+        if properties.is_some_and(|p| p.address.to_string().eq_ignore_ascii_case(address)) {
+            return Ok(Some(peripheral));
+        }
+    }
+    Ok(None)
+}
+
+async fn exchange(peripheral: &Peripheral, device: &GattConfig) -> Result<Vec<Measurement>> {
+    peripheral.discover_services().await?;
+    let protocol = device.protocol;
+    match protocol {
+        GattProtocol::HeartRate
+        | GattProtocol::CyclingPower
+        | GattProtocol::WeightScale
+        | GattProtocol::Bm2 => Err(eyre!("{:?} is subscribed to, not polled", protocol)),
+        GattProtocol::AirthingsWavePlus => {
+            let current_values = characteristic(peripheral, airthings::CURRENT_VALUES)?;
+            airthings::wave_plus(&peripheral.read(&current_values).await?)
+        }
+        GattProtocol::Renogy => {
+            let write = characteristic(peripheral, renogy::WRITE_CHARACTERISTIC)?;
+            let notify = characteristic(peripheral, renogy::NOTIFY_CHARACTERISTIC)?;
+            let mut notifications = peripheral.notifications().await?;
+            peripheral.subscribe(&notify).await?;
+            peripheral
+                .write(&write, &renogy::request(), WriteType::WithoutResponse)
+                .await?;
+            // Responses longer than the MTU arrive in several notifications.
+            let mut frame = Vec::new();
+            while frame.len() < renogy::response_len(&frame) {
+                match notifications.next().await {
+                    Some(notification) if notification.uuid == notify.uuid => {
+                        frame.extend(notification.value)
+                    }
+                    Some(_) => {}
+                    None => return Err(eyre!("disconnected")),
+                }
+            }
+            renogy::measurements(&frame)
+        }
+        GattProtocol::Characteristics => {
+            let mut measurements = Vec::new();
+            for read in &device.characteristics {
+                let value = peripheral
+                    .read(&characteristic(peripheral, read.uuid)?)
+                    .await?;
+                let value = read
+                    .format
+                    .decode(&value)
+                    .wrap_err_with(|| format!("characteristic {}", read.uuid))?;
+                measurements.push(Measurement::new(
+                    read.kind.clone(),
+                    value * read.scale,
+                    read.unit.clone(),
+                ));
+            }
+            Ok(measurements)
+        }
+    }
+}
+
+pub fn characteristic(peripheral: &Peripheral, uuid: Uuid) -> Result<Characteristic> {
+    peripheral
+        .characteristics()
+        .into_iter()
+        .find(|c| c.uuid == uuid)
+        .ok_or_else(|| eyre!("no characteristic {}", uuid))
+}
+
+// Reader reads the little-endian fields of a characteristic value in order.
+pub struct Reader<'a>(pub &'a [u8]);
+
+impl Reader<'_> {
+    pub fn take(&mut self, len: usize) -> Result<&[u8]> {
+        if self.0.len() < len {
+            return Err(eyre!("value too short"));
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    pub fn skip(&mut self, len: usize) -> Result<()> {
+        self.take(len).map(|_| ())
+    }
+
+    pub fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn u32(&mut self) -> Result<u32> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+}
+
+// ValueFormat is how a configured characteristic holds its value, little-endian like the
+// standard ones.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ValueFormat {
+    U8,
+    I8,
+    U16,
+    I16,
+    U32,
+    I32,
+    F32,
+}
+
+impl ValueFormat {
+    pub fn decode(self, value: &[u8]) -> Result<f64> {
+        let mut reader = Reader(value);
+        Ok(match self {
+            ValueFormat::U8 => reader.u8()? as f64,
+            ValueFormat::I8 => reader.u8()? as i8 as f64,
+            ValueFormat::U16 => reader.u16()? as f64,
+            ValueFormat::I16 => reader.u16()? as i16 as f64,
+            ValueFormat::U32 => reader.u32()? as f64,
+            ValueFormat::I32 => reader.u32()? as i32 as f64,
+            ValueFormat::F32 => f32::from_bits(reader.u32()?) as f64,
+        })
+    }
+
+    // encode is the reverse of decode, for writing value, which must fit the format.
+    pub fn encode(self, value: f64) -> Result<Vec<u8>> {
+        let (min, max) = match self {
+            ValueFormat::U8 => (0.0, u8::MAX as f64),
+            ValueFormat::I8 => (i8::MIN as f64, i8::MAX as f64),
+            ValueFormat::U16 => (0.0, u16::MAX as f64),
+            ValueFormat::I16 => (i16::MIN as f64, i16::MAX as f64),
+            ValueFormat::U32 => (0.0, u32::MAX as f64),
+            ValueFormat::I32 => (i32::MIN as f64, i32::MAX as f64),
+            ValueFormat::F32 => (f32::MIN as f64, f32::MAX as f64),
+        };
+        if !(min..=max).contains(&value) {
+            return Err(eyre!("{} doesn't fit {:?}", value, self));
+        }
+        let value = if self == ValueFormat::F32 {
+            value
+        } else {
+            value.round()
+        };
+        Ok(match self {
+            ValueFormat::U8 => vec![value as u8],
+            ValueFormat::I8 => (value as i8).to_le_bytes().to_vec(),
+            ValueFormat::U16 => (value as u16).to_le_bytes().to_vec(),
+            ValueFormat::I16 => (value as i16).to_le_bytes().to_vec(),
+            ValueFormat::U32 => (value as u32).to_le_bytes().to_vec(),
+            ValueFormat::I32 => (value as i32).to_le_bytes().to_vec(),
+            ValueFormat::F32 => (value as f32).to_le_bytes().to_vec(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::gatt::ValueFormat;
+
+    #[test]
+    fn test_value_format() {
+        // 21.5°C in hundredths, as the Environmental Sensing temperature characteristic has it.
+        assert_eq!(ValueFormat::I16.decode(&[0x66, 0x08]).unwrap(), 2150.0);
+        assert_eq!(ValueFormat::I16.decode(&[0x9a, 0xf7]).unwrap(), -2150.0);
+        assert_eq!(ValueFormat::U8.decode(&[0xfe, 0x00]).unwrap(), 254.0);
+        assert_eq!(ValueFormat::I32.decode(&[0xff; 4]).unwrap(), -1.0);
+        assert_eq!(ValueFormat::F32.decode(&1.5f32.to_le_bytes()).unwrap(), 1.5);
+        assert!(ValueFormat::U32.decode(&[1, 2, 3]).is_err());
+    }
+}
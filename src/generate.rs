@@ -0,0 +1,109 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use color_eyre::eyre::Result;
+use futures_util::pin_mut;
+use futures_util::stream::StreamExt;
+use tokio::time;
+
+use crate::adapter::AdapterSelector;
+use crate::config::EXAMPLE;
+use crate::metrics::protocol;
+use crate::preflight::preflight;
+use crate::{bt_stream, DeviceId};
+
+// GenerateConfigArgs configures `blueplug generate-config`, which prints config/example.toml as a
+// starting point, optionally followed by the devices found by a short scan.
+#[derive(clap::Args, Debug)]
+pub struct GenerateConfigArgs {
+    /// Scan for this many seconds and list the devices found
+    #[arg(long)]
+    scan: Option<u64>,
+    /// Bluetooth adapter to scan with, by index, name (e.g. hci1) or MAC address
+    #[arg(long)]
+    adapter: Option<AdapterSelector>,
+}
+
+pub async fn run(args: GenerateConfigArgs) -> Result<()> {
+    let devices = match args.scan {
+        Some(seconds) => Some(scan(args.adapter, Duration::from_secs(seconds)).await?),
+        None => None,
+    };
+    print!("{}", EXAMPLE);
+    if let Some(devices) = devices {
+        print!("{}", device_list(&devices));
+    }
+    Ok(())
+}
+
+// scan collects the devices advertising during duration, with the protocol each advertised last.
+async fn scan(
+    adapter: Option<AdapterSelector>,
+    duration: Duration,
+) -> Result<BTreeMap<String, (DeviceId, &'static str)>> {
+    preflight(adapter.as_ref()).await?;
+    // Scans are short, so a stall just shows as few devices.
+    let events = bt_stream(adapter, None);
+    pin_mut!(events);
+    let mut devices = BTreeMap::new();
+    let deadline = time::sleep(duration);
+    pin_mut!(deadline);
+    loop {
+        tokio::select! {
+            event = events.next() => match event {
+                Some(event) => {
+                    let event = event?;
+                    if event.device_id().device_name.is_empty() {
+                        continue;
+                    }
+                    let device_id = event.device_id().clone();
+                    devices.insert(device_id.id.clone(), (device_id, protocol(&event)));
+                }
+                None => break,
+            },
+            _ = &mut deadline => break,
+        }
+    }
+    Ok(devices)
+}
+
+// device_list renders scanned devices as comments, with presence entries to copy from.
+fn device_list(devices: &BTreeMap<String, (DeviceId, &'static str)>) -> String {
+    let mut list = String::from(
+        "\n# Devices found by scanning, to refer to by name or id in routes, mappings and \
+         presence.\n",
+    );
+    if devices.is_empty() {
+        list.push_str("# None were found.\n");
+    }
+    for (device_id, protocol) in devices.values() {
+        list.push_str(&format!(
+            "#\n# {} ({}), id {}\n#{{ name = \"{}\", address = \"{}\" }}\n",
+            device_id.device_name, protocol, device_id.id, device_id.device_name, device_id.address
+        ));
+    }
+    list
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use crate::generate::device_list;
+    use crate::DeviceId;
+
+    #[test]
+    fn test_device_list() {
+        let device_id = DeviceId {
+            id: "hci0/dev_A4_C1_38_8F_2C_1A".to_string(),
+            device_name: "ATC_8F2C1A".to_string(),
+            address: "A4:C1:38:8F:2C:1A".to_string(),
+            random_address: None,
+        };
+        let devices = BTreeMap::from([(device_id.id.clone(), (device_id, "bthome"))]);
+        assert!(device_list(&devices).ends_with(
+            "#\n# ATC_8F2C1A (bthome), id hci0/dev_A4_C1_38_8F_2C_1A\n\
+             #{ name = \"ATC_8F2C1A\", address = \"A4:C1:38:8F:2C:1A\" }\n"
+        ));
+    }
+}
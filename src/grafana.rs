@@ -0,0 +1,428 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use reqwest::StatusCode;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
+use tokio::time;
+
+use crate::config::GrafanaConfig;
+use crate::mqtt::backoff;
+use crate::names::TOPIC_NAMES;
+use crate::supervisor::SUPERVISOR;
+use crate::DeviceReading;
+
+// A push that takes longer than this is abandoned, so a stalled Grafana doesn't hold up readings
+// that arrive meanwhile for long.
+const PUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Defaults of batched writes: InfluxDB suggests writing 5000 lines at a time.
+const DEFAULT_BATCH_SIZE: usize = 5000;
+const DEFAULT_BATCH_INTERVAL: u64 = 10;
+// How many batches' worth of readings are kept while writes fail, before the oldest are dropped.
+const BUFFERED_BATCHES: usize = 10;
+
+// spawn_grafana pushes readings as InfluxDB line protocol to a Grafana Live push endpoint,
+// `<grafana>/api/live/push/<stream>`, where each device becomes the channel
+// `stream/<stream>/<device>` that live panels subscribe to, or to Grafana Cloud's Influx endpoint.
+// A reading is pushed as soon as it arrives, along with any others that arrived while the
+// previous push was in flight, so panels update without a database in between. Readings that
+// can't be pushed are dropped; live panels only show the latest anyway. With batch_size or
+// batch_interval set, as for an Influx database, readings are written in batches instead, see
+// spawn_batched.
+pub fn spawn_grafana(config: GrafanaConfig, readings: broadcast::Receiver<Arc<DeviceReading>>) {
+    if config.batch_size.is_some() || config.batch_interval.is_some() {
+        return spawn_batched(config, readings);
+    }
+    // The receiver is shared with every start of the task, so one restarted after a panic
+    // carries on with the readings that arrived meanwhile.
+    let readings = Arc::new(tokio::sync::Mutex::new(readings));
+    SUPERVISOR.spawn(format!("grafana {}", config.name), move || {
+        let config = config.clone();
+        let readings = readings.clone();
+        async move {
+            let readings = &mut *readings.lock().await;
+            let client = reqwest::Client::new();
+            let mut failures = 0;
+            loop {
+                let Some(batch) = next_batch(&config.name, readings).await else {
+                    return;
+                };
+                match push(&client, &config, lines(&batch)).await {
+                    Ok(_) => failures = 0,
+                    Err(e) => {
+                        failures += 1;
+                        let delay = backoff(failures, fastrand::f64());
+                        println!(
+                            "{}: pushing {} readings failed, retrying in {:?}: {}",
+                            config.name,
+                            batch.len(),
+                            delay,
+                            e
+                        );
+                        time::sleep(delay).await;
+                    }
+                }
+            }
+        }
+    });
+}
+
+// spawn_batched collects readings until batch_size of them arrived or batch_interval seconds
+// passed, then writes them in one request, saving the database writes, which matters for large
+// fleets and databases on SD cards. A batch that fails to write is kept, along with what arrives
+// meanwhile up to BUFFERED_BATCHES batches' worth, and retried with a backoff. A batch too large
+// for the endpoint is split in half, and later batches kept to that size. One the endpoint
+// rejects as malformed is dropped, as is what InfluxDB rejects of a partial write, since writing
+// it again would fail again or duplicate the lines that were written. What is left is written on
+// shutdown.
+fn spawn_batched(config: GrafanaConfig, mut readings: broadcast::Receiver<Arc<DeviceReading>>) {
+    SUPERVISOR.spawn_graceful(format!("grafana {}", config.name), async move {
+        let client = reqwest::Client::new();
+        let mut size = config.batch_size.unwrap_or(DEFAULT_BATCH_SIZE);
+        let capacity = size * BUFFERED_BATCHES;
+        let interval = config.batch_interval.unwrap_or(DEFAULT_BATCH_INTERVAL);
+        let mut flush = time::interval(Duration::from_secs(interval));
+        let mut buffered = VecDeque::new();
+        let mut failures = 0;
+        let mut retry_at = None;
+        loop {
+            // Full batches are written as soon as they are, everything on the interval.
+            let everything = tokio::select! {
+                received = readings.recv() => {
+                    match received {
+                        Ok(reading) => {
+                            let time = stamp(buffered.back(), &reading);
+                            buffered.push_back((reading, time));
+                        }
+                        Err(RecvError::Lagged(skipped)) => {
+                            println!(
+                                "{}: falling behind, dropped {} readings",
+                                config.name, skipped
+                            );
+                        }
+                        Err(RecvError::Closed) => return,
+                    }
+                    if buffered.len() > capacity {
+                        let dropped = buffered.len() - capacity;
+                        buffered.drain(..dropped);
+                        println!(
+                            "{}: buffer full, dropped the oldest {} readings",
+                            config.name, dropped
+                        );
+                    }
+                    false
+                }
+                _ = flush.tick() => true,
+                _ = SUPERVISOR.cancelled() => {
+                    return drain(&client, &config, buffered, size).await;
+                }
+            };
+            if retry_at.is_some_and(|retry_at| Instant::now() < retry_at) {
+                continue;
+            }
+            while buffered.len() >= size || (everything && !buffered.is_empty()) {
+                let count = buffered.len().min(size);
+                let batch: Vec<_> = buffered.range(..count).cloned().collect();
+                match push(&client, &config, lines(&batch)).await {
+                    Ok(()) => {
+                        buffered.drain(..count);
+                        failures = 0;
+                        retry_at = None;
+                    }
+                    Err(e) if e.status() == Some(StatusCode::PAYLOAD_TOO_LARGE) && count > 1 => {
+                        size = count / 2;
+                        println!(
+                            "{}: batch too large, writing {} readings at a time",
+                            config.name, size
+                        );
+                    }
+                    Err(e) if e.status().is_some_and(is_rejected) => {
+                        buffered.drain(..count);
+                        println!(
+                            "{}: {} readings rejected, dropped: {}",
+                            config.name, count, e
+                        );
+                    }
+                    Err(e) => {
+                        failures += 1;
+                        let delay = backoff(failures, fastrand::f64());
+                        retry_at = Some(Instant::now() + delay);
+                        println!(
+                            "{}: writing {} readings failed, retrying in {:?}: {}",
+                            config.name, count, delay, e
+                        );
+                        break;
+                    }
+                }
+            }
+        }
+    });
+}
+
+// next_batch waits for a reading, then takes the others that arrived meanwhile. It returns None
+// once there are no more.
+async fn next_batch(
+    name: &str,
+    readings: &mut broadcast::Receiver<Arc<DeviceReading>>,
+) -> Option<Vec<(Arc<DeviceReading>, SystemTime)>> {
+    let mut batch: Vec<(Arc<DeviceReading>, SystemTime)> = Vec::new();
+    loop {
+        match readings.recv().await {
+            Ok(reading) => {
+                batch.push((reading, SystemTime::now()));
+                break;
+            }
+            Err(RecvError::Lagged(skipped)) => {
+                println!("{}: falling behind, dropped {} readings", name, skipped);
+            }
+            Err(RecvError::Closed) => return None,
+        }
+    }
+    loop {
+        match readings.try_recv() {
+            Ok(reading) => {
+                let time = stamp(batch.last(), &reading);
+                batch.push((reading, time));
+            }
+            Err(TryRecvError::Lagged(_)) => continue,
+            Err(TryRecvError::Empty | TryRecvError::Closed) => break,
+        }
+    }
+    Some(batch)
+}
+
+// stamp gives when reading was received: now, or when the reading before it was if both are of
+// one advertisement, so lines keeps what was measured together on one line.
+fn stamp(
+    previous: Option<&(Arc<DeviceReading>, SystemTime)>,
+    reading: &DeviceReading,
+) -> SystemTime {
+    match previous {
+        Some((previous, time))
+            if previous.device_id.id == reading.device_id.id
+                && previous.advertisement.is_some()
+                && previous.advertisement == reading.advertisement =>
+        {
+            *time
+        }
+        _ => SystemTime::now(),
+    }
+}
+
+// drain writes the readings left on shutdown, dropping them if that fails rather than retrying.
+async fn drain(
+    client: &reqwest::Client,
+    config: &GrafanaConfig,
+    mut buffered: VecDeque<(Arc<DeviceReading>, SystemTime)>,
+    mut size: usize,
+) {
+    while !buffered.is_empty() {
+        let count = buffered.len().min(size);
+        let batch: Vec<_> = buffered.range(..count).cloned().collect();
+        match push(client, config, lines(&batch)).await {
+            Ok(()) => {}
+            Err(e) if e.status() == Some(StatusCode::PAYLOAD_TOO_LARGE) && count > 1 => {
+                size = count / 2;
+                continue;
+            }
+            Err(e) if e.status().is_some_and(is_rejected) => {
+                println!(
+                    "{}: {} readings rejected, dropped: {}",
+                    config.name, count, e
+                );
+            }
+            Err(e) => {
+                println!(
+                    "{}: writing {} readings failed, dropped: {}",
+                    config.name,
+                    buffered.len(),
+                    e
+                );
+                return;
+            }
+        }
+        buffered.drain(..count);
+    }
+}
+
+// is_rejected tells whether a write failed because of what was written, so would fail again:
+// malformed lines, or the lines InfluxDB didn't take of a partial write.
+fn is_rejected(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::BAD_REQUEST | StatusCode::UNPROCESSABLE_ENTITY
+    )
+}
+
+// push sends lines to the endpoint of config.
+pub async fn push(
+    client: &reqwest::Client,
+    config: &GrafanaConfig,
+    lines: String,
+) -> reqwest::Result<()> {
+    let mut request = client.post(&config.url).timeout(PUSH_TIMEOUT).body(lines);
+    if let Some(token) = &config.token {
+        request = request.bearer_auth(token);
+    }
+    request.send().await?.error_for_status()?;
+    Ok(())
+}
+
+// lines renders readings, with when they were received, in InfluxDB line protocol, a line per
+// device and advertisement with a field per measurement kind, so values measured together stay
+// together in a frame. Devices are tagged with their location and labels. Line protocol has no
+// NaN or infinity, which would fail the whole batch, so such values are left out.
+pub fn lines(readings: &[(Arc<DeviceReading>, SystemTime)]) -> String {
+    let nanos = |time: SystemTime| {
+        time.duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos()
+    };
+    let mut lines = String::new();
+    let mut previous: Option<&(Arc<DeviceReading>, SystemTime)> = None;
+    for entry in readings {
+        let (reading, time) = entry;
+        if !reading.measurement.value().is_finite() {
+            continue;
+        }
+        let same_line = previous.is_some_and(|(previous, previous_time)| {
+            previous.device_id.id == reading.device_id.id
+                && previous.advertisement.is_some()
+                && previous.advertisement == reading.advertisement
+                && previous_time == time
+        });
+        if same_line {
+            lines.push(',');
+        } else {
+            if let Some((_, previous_time)) = previous {
+                lines.push_str(&format!(" {}\n", nanos(*previous_time)));
+            }
+            lines.push_str(&escape(&TOPIC_NAMES.topic_name(&reading.device_id), ", "));
+            for (key, value) in TOPIC_NAMES.tags(&reading.device_id) {
+                lines.push_str(&format!(",{}={}", key, escape(&value, ",= ")));
+            }
+            lines.push(' ');
+        }
+        lines.push_str(&format!(
+            "{}={}",
+            escape(reading.measurement.kind(), ",= "),
+            reading.measurement.value()
+        ));
+        previous = Some(entry);
+    }
+    if let Some((_, previous_time)) = previous {
+        lines.push_str(&format!(" {}\n", nanos(*previous_time)));
+    }
+    lines
+}
+
+// escape backslash-escapes the characters line protocol gives a meaning to in a name.
+fn escape(name: &str, special: &str) -> String {
+    let mut escaped = String::new();
+    for c in name.chars() {
+        if special.contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use tokio::sync::broadcast;
+
+    use crate::grafana::{lines, next_batch};
+    use crate::{DeviceId, DeviceReading, Measurement};
+
+    #[test]
+    fn test_lines() {
+        let reading = |name: &str, measurement, advertisement| {
+            Arc::new(DeviceReading {
+                device_id: DeviceId {
+                    id: format!("hci0/{}", name),
+                    device_name: name.to_string(),
+                    address: String::new(),
+                    random_address: None,
+                },
+                measurement,
+                advertisement,
+                quality: Vec::new(),
+            })
+        };
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let later = now + Duration::from_secs(1);
+        let readings = [
+            (
+                reading("Green house", Measurement::Temperature(21.5), Some(1)),
+                now,
+            ),
+            (
+                reading("Green house", Measurement::Humidity(40.0), Some(1)),
+                now,
+            ),
+            (
+                reading("Green house", Measurement::Temperature(21.6), Some(2)),
+                now,
+            ),
+            (
+                reading("Green house", Measurement::Humidity(f64::NAN), Some(2)),
+                now,
+            ),
+            (
+                reading("ATC_GRAFANA", Measurement::Battery(90.0), None),
+                later,
+            ),
+            (
+                reading("ATC_GRAFANA", Measurement::Voltage(f64::INFINITY), None),
+                later,
+            ),
+        ];
+        assert_eq!(
+            lines(&readings),
+            "Green\\ house temperature=21.5,humidity=40 1700000000000000000\n\
+             Green\\ house temperature=21.6 1700000000000000000\n\
+             ATC_GRAFANA battery=90 1700000001000000000\n"
+        );
+    }
+    // Readings of one advertisement share a line however far apart they arrive.
+    #[tokio::test]
+    async fn test_next_batch() {
+        let reading = |measurement, advertisement| {
+            Arc::new(DeviceReading {
+                device_id: DeviceId {
+                    id: "hci0/dev_A4_C1_38_00_00_14".to_string(),
+                    device_name: "ATC_LIVE".to_string(),
+                    address: "A4:C1:38:00:00:14".to_string(),
+                    random_address: None,
+                },
+                measurement,
+                advertisement,
+                quality: Vec::new(),
+            })
+        };
+        let (sender, mut receiver) = broadcast::channel(16);
+        for (measurement, advertisement) in [
+            (Measurement::Temperature(21.5), Some(1)),
+            (Measurement::Humidity(40.0), Some(1)),
+            (Measurement::Temperature(21.6), Some(2)),
+        ] {
+            assert!(sender.send(reading(measurement, advertisement)).is_ok());
+            std::thread::sleep(Duration::from_millis(2));
+        }
+        let batch = next_batch("grafana", &mut receiver).await.unwrap();
+        let lines = lines(&batch);
+        let lines: Vec<_> = lines.lines().collect();
+        assert_eq!(lines.len(), 2, "{:?}", lines);
+        assert!(lines[0].starts_with("ATC_LIVE temperature=21.5,humidity=40 "));
+        assert!(lines[1].starts_with("ATC_LIVE temperature=21.6 "));
+
+        drop(sender);
+        assert!(next_batch("grafana", &mut receiver).await.is_none());
+    }
+}
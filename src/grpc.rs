@@ -0,0 +1,355 @@
+use std::sync::{Arc, Mutex};
+
+use async_stream::stream;
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use prost::Message;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tonic::codec::ProstCodec;
+use tonic::codegen::{
+    empty_body, http, Body, BoxFuture, BoxStream, Context, Poll, Service, StdError,
+};
+use tonic::server::{Grpc, NamedService, ServerStreamingService, UnaryService};
+use tonic::{Request, Response, Status};
+
+use crate::api::summarize;
+use crate::clock::unix_timestamp;
+use crate::config::GrpcConfig;
+use crate::fanout::devices_match;
+use crate::latest::{LatestReading, LatestReadings};
+use crate::supervisor::SUPERVISOR;
+use crate::DeviceReading;
+
+// The name routes refer to the gRPC service by.
+pub const SINK_NAME: &str = "grpc";
+
+// The messages of proto/blueplug.proto, which clients generate their code from.
+
+#[derive(Clone, PartialEq, Message)]
+pub struct SubscribeReadingsRequest {
+    #[prost(string, repeated, tag = "1")]
+    pub devices: Vec<String>,
+    #[prost(string, repeated, tag = "2")]
+    pub kinds: Vec<String>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ListDevicesRequest {}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ListDevicesResponse {
+    #[prost(message, repeated, tag = "1")]
+    pub devices: Vec<Device>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct GetLatestRequest {
+    #[prost(string, repeated, tag = "1")]
+    pub devices: Vec<String>,
+    #[prost(string, repeated, tag = "2")]
+    pub kinds: Vec<String>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct GetLatestResponse {
+    #[prost(message, repeated, tag = "1")]
+    pub readings: Vec<Reading>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Device {
+    #[prost(string, tag = "1")]
+    pub id: String,
+    #[prost(string, tag = "2")]
+    pub name: String,
+    #[prost(string, tag = "3")]
+    pub topic: String,
+    #[prost(string, tag = "4")]
+    pub location: String,
+    #[prost(string, tag = "5")]
+    pub address: String,
+    #[prost(uint64, tag = "6")]
+    pub last_seen: u64,
+    #[prost(bool, tag = "7")]
+    pub stale: bool,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Reading {
+    #[prost(string, tag = "1")]
+    pub device_id: String,
+    #[prost(string, tag = "2")]
+    pub device_name: String,
+    #[prost(string, tag = "3")]
+    pub kind: String,
+    #[prost(double, tag = "4")]
+    pub value: f64,
+    #[prost(string, tag = "5")]
+    pub unit: String,
+    #[prost(uint64, tag = "6")]
+    pub timestamp: u64,
+    #[prost(bool, tag = "7")]
+    pub stale: bool,
+}
+
+impl Reading {
+    fn new(reading: &DeviceReading, timestamp: u64, stale: bool) -> Self {
+        Reading {
+            device_id: reading.device_id.id.clone(),
+            device_name: reading.device_id.device_name.clone(),
+            kind: reading.measurement.kind().to_string(),
+            value: reading.measurement.value(),
+            unit: reading.measurement.unit().to_string(),
+            timestamp,
+            stale,
+        }
+    }
+}
+
+fn kinds_match(kinds: &[String], reading: &DeviceReading) -> bool {
+    kinds.is_empty() || kinds.iter().any(|kind| kind == reading.measurement.kind())
+}
+
+// BlueplugService serves the Blueplug service of proto/blueplug.proto. It is written out rather
+// than generated by tonic-build, like the other protobuf code here, so building doesn't need
+// protoc.
+#[derive(Clone)]
+pub struct BlueplugService {
+    latest: Arc<Mutex<LatestReadings>>,
+    readings: Arc<broadcast::Receiver<Arc<DeviceReading>>>,
+}
+
+impl BlueplugService {
+    pub fn new(
+        latest: Arc<Mutex<LatestReadings>>,
+        readings: broadcast::Receiver<Arc<DeviceReading>>,
+    ) -> Self {
+        BlueplugService {
+            latest,
+            readings: Arc::new(readings),
+        }
+    }
+
+    fn list_devices(&self) -> ListDevicesResponse {
+        let readings = self.latest.lock().unwrap().readings();
+        let devices = summarize(&readings)
+            .into_iter()
+            .map(|device| Device {
+                id: device.id,
+                name: device.name,
+                topic: device.topic,
+                location: device.location.unwrap_or_default(),
+                address: device.address,
+                last_seen: device.last_seen,
+                stale: device.is_stale,
+            })
+            .collect();
+        ListDevicesResponse { devices }
+    }
+
+    fn get_latest(&self, request: &GetLatestRequest) -> GetLatestResponse {
+        let readings = self.latest.lock().unwrap().readings();
+        let readings = readings
+            .iter()
+            .filter(|latest| {
+                devices_match(&request.devices, &latest.reading.device_id)
+                    && kinds_match(&request.kinds, &latest.reading)
+            })
+            .map(
+                |LatestReading {
+                     reading,
+                     timestamp,
+                     is_stale,
+                     ..
+                 }| { Reading::new(reading, *timestamp, *is_stale) },
+            )
+            .collect();
+        GetLatestResponse { readings }
+    }
+
+    // subscribe_readings streams the matching readings decoded from now on. A client that can't
+    // keep up misses readings rather than holding up the others.
+    fn subscribe_readings(&self, request: SubscribeReadingsRequest) -> BoxStream<Reading> {
+        let mut readings = self.readings.resubscribe();
+        Box::pin(stream! {
+            loop {
+                match readings.recv().await {
+                    Ok(reading) => {
+                        if devices_match(&request.devices, &reading.device_id)
+                            && kinds_match(&request.kinds, &reading)
+                        {
+                            yield Ok(Reading::new(&reading, unix_timestamp(), false));
+                        }
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        println!("grpc: subscriber falling behind, dropped {} readings", skipped);
+                    }
+                    Err(RecvError::Closed) => return,
+                }
+            }
+        })
+    }
+}
+
+struct Unary<F>(F);
+
+impl<Req, Res, F> UnaryService<Req> for Unary<F>
+where
+    F: FnMut(Req) -> Res,
+    Res: Send + 'static,
+{
+    type Response = Res;
+    type Future = BoxFuture<Response<Res>, Status>;
+
+    fn call(&mut self, request: Request<Req>) -> Self::Future {
+        let response = (self.0)(request.into_inner());
+        Box::pin(async move { Ok(Response::new(response)) })
+    }
+}
+
+struct Subscribe(BlueplugService);
+
+impl ServerStreamingService<SubscribeReadingsRequest> for Subscribe {
+    type Response = Reading;
+    type ResponseStream = BoxStream<Reading>;
+    type Future = BoxFuture<Response<Self::ResponseStream>, Status>;
+
+    fn call(&mut self, request: Request<SubscribeReadingsRequest>) -> Self::Future {
+        let stream = self.0.subscribe_readings(request.into_inner());
+        Box::pin(async move { Ok(Response::new(stream)) })
+    }
+}
+
+impl NamedService for BlueplugService {
+    const NAME: &'static str = "blueplug.v1.Blueplug";
+}
+
+impl<B> Service<http::Request<B>> for BlueplugService
+where
+    B: Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+{
+    type Response = http::Response<tonic::body::BoxBody>;
+    type Error = std::convert::Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        let service = self.clone();
+        match request.uri().path() {
+            "/blueplug.v1.Blueplug/SubscribeReadings" => Box::pin(async move {
+                let mut grpc = Grpc::new(ProstCodec::default());
+                Ok(grpc.server_streaming(Subscribe(service), request).await)
+            }),
+            "/blueplug.v1.Blueplug/ListDevices" => Box::pin(async move {
+                let method = Unary(move |_: ListDevicesRequest| service.list_devices());
+                Ok(Grpc::new(ProstCodec::default())
+                    .unary(method, request)
+                    .await)
+            }),
+            "/blueplug.v1.Blueplug/GetLatest" => Box::pin(async move {
+                let method = Unary(move |request: GetLatestRequest| service.get_latest(&request));
+                Ok(Grpc::new(ProstCodec::default())
+                    .unary(method, request)
+                    .await)
+            }),
+            _ => Box::pin(async move {
+                let mut response = http::Response::new(empty_body());
+                let headers = response.headers_mut();
+                headers.insert(
+                    Status::GRPC_STATUS,
+                    (tonic::Code::Unimplemented as i32).into(),
+                );
+                headers.insert(
+                    http::header::CONTENT_TYPE,
+                    tonic::metadata::GRPC_CONTENT_TYPE,
+                );
+                Ok(response)
+            }),
+        }
+    }
+}
+
+pub async fn spawn_grpc(config: &GrpcConfig, service: BlueplugService) -> Result<()> {
+    let listener = tokio::net::TcpListener::bind(config.listen)
+        .await
+        .wrap_err_with(|| format!("listening on {}", config.listen))?;
+    println!("gRPC server listening on {}", config.listen);
+    let incoming = tonic::transport::server::TcpIncoming::from_listener(listener, true, None)
+        .map_err(|e| eyre!("gRPC listener: {}", e))?;
+    SUPERVISOR.spawn_once("grpc", async move {
+        let server = tonic::transport::Server::builder()
+            .add_service(service)
+            .serve_with_incoming(incoming);
+        if let Err(e) = server.await {
+            println!("grpc: error {:?}", e);
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use futures_util::StreamExt;
+    use tokio::sync::broadcast;
+
+    use crate::grpc::{BlueplugService, GetLatestRequest, SubscribeReadingsRequest};
+    use crate::latest::LatestReadings;
+    use crate::{DeviceId, DeviceReading, Measurement};
+
+    #[tokio::test]
+    async fn test_grpc() {
+        let reading = |name: &str, measurement| {
+            Arc::new(DeviceReading {
+                device_id: DeviceId {
+                    id: format!("hci0/{}", name),
+                    device_name: name.to_string(),
+                    address: String::new(),
+                    random_address: None,
+                },
+                measurement,
+                advertisement: None,
+                quality: Vec::new(),
+            })
+        };
+        let mut latest = LatestReadings::default();
+        latest.update(reading("ATC_GRPC_1", Measurement::Temperature(21.5)));
+        latest.update(reading("ATC_GRPC_1", Measurement::Humidity(40.0)));
+        latest.update(reading("ATC_GRPC_2", Measurement::Temperature(19.0)));
+        let (sender, receiver) = broadcast::channel(16);
+        let service = BlueplugService::new(Arc::new(Mutex::new(latest)), receiver);
+
+        let devices = service.list_devices().devices;
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[1].name, "ATC_GRPC_2");
+
+        let latest = service
+            .get_latest(&GetLatestRequest {
+                devices: vec!["ATC_GRPC_*".to_string()],
+                kinds: vec!["temperature".to_string()],
+            })
+            .readings;
+        assert_eq!(latest.len(), 2);
+        assert_eq!(latest[0].value, 21.5);
+        assert_eq!(latest[0].unit, "°C");
+
+        let mut stream = service.subscribe_readings(SubscribeReadingsRequest {
+            devices: vec!["ATC_GRPC_2".to_string()],
+            kinds: Vec::new(),
+        });
+        assert!(sender
+            .send(reading("ATC_GRPC_1", Measurement::Battery(90.0)))
+            .is_ok());
+        assert!(sender
+            .send(reading("ATC_GRPC_2", Measurement::Battery(80.0)))
+            .is_ok());
+        let streamed = stream.next().await.unwrap().unwrap();
+        assert_eq!(streamed.device_name, "ATC_GRPC_2");
+        assert_eq!(streamed.value, 80.0);
+    }
+}
@@ -0,0 +1,199 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::clock::unix_timestamp;
+use crate::metrics::METRICS;
+
+// After this many failed connection attempts in a row a broker's circuit opens: publishes are
+// buffered locally instead of queueing up in the MQTT client until it reconnects.
+pub const CIRCUIT_THRESHOLD: u32 = 5;
+
+// HEALTH tracks the connection of every broker, and of the sessions ingest, commands and the
+// forwarder open of their own, for the health endpoint and telemetry.
+pub static HEALTH: LazyLock<Health> = LazyLock::new(Health::default);
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum BrokerState {
+    #[default]
+    Connecting,
+    Connected,
+    // Failing to connect, waiting to retry.
+    Retrying,
+    // Failing to connect for a while, buffering publishes.
+    CircuitOpen,
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct BrokerHealth {
+    pub state: BrokerState,
+    // When the broker entered its state, in seconds since the Unix epoch.
+    pub since: u64,
+    // Failed connection attempts in a row.
+    pub failures: u32,
+    // Seconds until the next attempt.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_in: Option<u64>,
+    // Publishes waiting for the broker to come back.
+    pub buffered: usize,
+    // Everything not yet written to the broker: buffered publishes and those waiting in the MQTT
+    // client.
+    pub queued: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<LastError>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct LastError {
+    pub message: String,
+    // Seconds since the Unix epoch.
+    pub at: u64,
+}
+
+impl BrokerHealth {
+    fn enter(&mut self, state: BrokerState) {
+        if self.state != state || self.since == 0 {
+            self.state = state;
+            self.since = unix_timestamp();
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Health {
+    brokers: Mutex<BTreeMap<String, BrokerHealth>>,
+    // Whether readings only go to other sinks, so no broker is ever registered.
+    brokerless: AtomicBool,
+}
+
+impl Health {
+    pub fn without_brokers(&self) {
+        self.brokerless.store(true, Ordering::Relaxed);
+    }
+
+    // connecting registers a broker before its first connection attempt, so it is reported as
+    // connecting rather than missing.
+    pub fn connecting(&self, broker: &str) {
+        let mut brokers = self.brokers.lock().unwrap();
+        brokers
+            .entry(broker.to_string())
+            .or_default()
+            .enter(BrokerState::Connecting);
+    }
+
+    pub fn connected(&self, broker: &str) {
+        let mut brokers = self.brokers.lock().unwrap();
+        let health = brokers.entry(broker.to_string()).or_default();
+        health.enter(BrokerState::Connected);
+        health.failures = 0;
+        health.retry_in = None;
+    }
+
+    pub fn failed(&self, broker: &str, failures: u32, retry_in: Duration, error: String) {
+        let mut brokers = self.brokers.lock().unwrap();
+        let health = brokers.entry(broker.to_string()).or_default();
+        health.enter(if failures >= CIRCUIT_THRESHOLD {
+            BrokerState::CircuitOpen
+        } else {
+            BrokerState::Retrying
+        });
+        health.failures = failures;
+        health.retry_in = Some(retry_in.as_secs());
+        health.last_error = Some(LastError {
+            message: error,
+            at: unix_timestamp(),
+        });
+    }
+
+    // error records a failure to publish that didn't affect the connection.
+    pub fn error(&self, broker: &str, error: String) {
+        let mut brokers = self.brokers.lock().unwrap();
+        brokers.entry(broker.to_string()).or_default().last_error = Some(LastError {
+            message: error,
+            at: unix_timestamp(),
+        });
+    }
+
+    pub fn buffered(&self, broker: &str, buffered: usize) {
+        let mut brokers = self.brokers.lock().unwrap();
+        brokers.entry(broker.to_string()).or_default().buffered = buffered;
+    }
+
+    pub fn is_open(&self, broker: &str) -> bool {
+        let brokers = self.brokers.lock().unwrap();
+        brokers
+            .get(broker)
+            .is_some_and(|health| health.state == BrokerState::CircuitOpen)
+    }
+
+    pub fn buffered_count(&self, broker: &str) -> usize {
+        let brokers = self.brokers.lock().unwrap();
+        brokers.get(broker).map_or(0, |health| health.buffered)
+    }
+
+    // is_healthy is whether every broker is connected. Until there is a broker, nothing is
+    // published, so that isn't healthy either, unless blueplug runs without brokers.
+    pub fn is_healthy(&self) -> bool {
+        let brokers = self.brokers.lock().unwrap();
+        (!brokers.is_empty() || self.brokerless.load(Ordering::Relaxed))
+            && brokers
+                .values()
+                .all(|health| health.state == BrokerState::Connected)
+    }
+
+    pub fn snapshot(&self) -> BTreeMap<String, BrokerHealth> {
+        let mut brokers = self.brokers.lock().unwrap().clone();
+        let publishing = METRICS.publishing();
+        for (broker, health) in &mut brokers {
+            // A broker that hasn't been handed anything yet has nothing queued in its client.
+            health.queued = publishing
+                .get(broker)
+                .map_or(health.buffered, |publishing| publishing.queued);
+        }
+        brokers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::health::{BrokerState, Health};
+
+    #[test]
+    fn test_health() {
+        let health = Health::default();
+        assert!(!health.is_healthy());
+        health.connecting("local");
+        assert!(!health.is_healthy());
+        assert_eq!(health.snapshot()["local"].state, BrokerState::Connecting);
+        health.connected("local");
+        assert!(health.is_healthy());
+
+        health.failed("cloud", 5, Duration::from_secs(30), "refused".to_string());
+        health.buffered("cloud", 3);
+        assert!(!health.is_healthy());
+        let snapshot = health.snapshot();
+        let cloud = &snapshot["cloud"];
+        assert_eq!(cloud.state, BrokerState::CircuitOpen);
+        assert!(cloud.since > 0);
+        assert_eq!(cloud.queued, 3);
+        assert_eq!(cloud.last_error.as_ref().unwrap().message, "refused");
+
+        // Staying in a state keeps when it was entered.
+        let since = cloud.since;
+        health.failed("cloud", 6, Duration::from_secs(60), "refused".to_string());
+        assert_eq!(health.snapshot()["cloud"].since, since);
+    }
+
+    #[test]
+    fn test_health_without_brokers() {
+        let health = Health::default();
+        health.without_brokers();
+        assert!(health.is_healthy());
+    }
+}
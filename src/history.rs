@@ -0,0 +1,448 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use jiff::civil::Date;
+use jiff::tz::TimeZone;
+use jiff::Timestamp;
+use parquet::file::reader::{FileReader, SerializedFileReader};
+use parquet::record::RowAccessor;
+use serde::{Deserialize, Serialize};
+
+use crate::archive::device_partition;
+
+// Points returned when the query doesn't give a limit, and the most it may ask for.
+const DEFAULT_LIMIT: usize = 10_000;
+const MAX_LIMIT: usize = 100_000;
+
+// The range queried when the query doesn't give from, and the longest it may ask for.
+const DEFAULT_RANGE_MS: i64 = 24 * 60 * 60 * 1000;
+const MAX_RANGE_MS: i64 = 31 * DEFAULT_RANGE_MS;
+
+// HistoryQuery is the query string of `/devices/{device}/history`. from and to are RFC 3339
+// timestamps or Unix seconds, the last 24 hours by default and at most 31 days apart; downsample
+// is a bucket width in seconds.
+#[derive(Deserialize, Debug, Default)]
+pub struct HistoryQuery {
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub kind: Option<String>,
+    pub downsample: Option<u64>,
+    pub limit: Option<usize>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct History {
+    pub device: String,
+    pub series: Vec<Series>,
+    // When the page was cut short by the limit, the to of the next page, which holds older points.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next: Option<String>,
+}
+
+// Series is one measurement kind's points, as [Unix milliseconds, value], oldest first. When
+// downsampled, each point is the mean of a bucket, at the bucket's start.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Series {
+    pub kind: String,
+    pub unit: String,
+    pub points: Vec<(i64, f64)>,
+}
+
+struct Point {
+    timestamp: i64,
+    kind: String,
+    unit: String,
+    value: f64,
+}
+
+// history reads a device's readings between from and to out of the Parquet archive in
+// directory, where device is its `device=` partition, i.e. its topic name. A page holds the
+// newest points in the range, up to the limit; a client pages back through a long range by
+// querying again up to next. Files are read newest first, and only until the page is complete.
+pub fn history(
+    directory: &Path,
+    device: &str,
+    query: &HistoryQuery,
+    now: Timestamp,
+) -> Result<History> {
+    let to = match &query.to {
+        Some(to) => parse_time(to)?,
+        None => now.as_millisecond(),
+    };
+    let from = match &query.from {
+        Some(from) => parse_time(from)?,
+        None => to - DEFAULT_RANGE_MS,
+    };
+    if from > to {
+        return Err(eyre!("from is after to"));
+    }
+    if to - from > MAX_RANGE_MS {
+        return Err(eyre!("from and to are more than 31 days apart"));
+    }
+    // device is joined into the archive's paths, so it must be a single directory name.
+    if device.is_empty() || device.contains(['/', '\\']) || device == "." || device == ".." {
+        return Err(eyre!("invalid device {:?}", device));
+    }
+    let bucket = match query.downsample {
+        Some(0) => return Err(eyre!("downsample must be at least 1 second")),
+        Some(seconds) => Some(seconds as i64 * 1000),
+        None => None,
+    };
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+
+    let mut files = files(directory, Some(device), from, to)?;
+    files.sort_by_key(|path| std::cmp::Reverse(written(path)));
+    let mut points = Vec::new();
+    let mut exhausted = true;
+    for (i, path) in files.iter().enumerate() {
+        let reader = SerializedFileReader::new(
+            File::open(path).wrap_err_with(|| format!("opening {}", path.display()))?,
+        )?;
+        for row in reader.get_row_iter(None)? {
+            let row = row?;
+            let timestamp = row.get_timestamp_millis(0)?;
+            let kind = row.get_string(4)?;
+            if timestamp < from || timestamp > to || query.kind.as_ref().is_some_and(|k| k != kind)
+            {
+                continue;
+            }
+            points.push(Point {
+                timestamp,
+                kind: kind.clone(),
+                unit: row.get_string(6)?.clone(),
+                value: row.get_double(5)?,
+            });
+        }
+        // A file only holds readings from before it was written, so the files left can't add to
+        // the points, or buckets, after the next of them was written. Once there are more of
+        // those than the page holds, the page is complete.
+        let Some(older) = files.get(i + 1).and_then(|path| written(path)) else {
+            continue;
+        };
+        let complete = (older + 1).saturating_mul(1000);
+        let complete = match bucket {
+            Some(bucket) => {
+                let mut buckets: Vec<(&str, i64)> = points
+                    .iter()
+                    .filter(|p| p.timestamp.div_euclid(bucket) * bucket >= complete)
+                    .map(|p| (p.kind.as_str(), p.timestamp.div_euclid(bucket)))
+                    .collect();
+                buckets.sort_unstable();
+                buckets.dedup();
+                buckets.len()
+            }
+            None => points.iter().filter(|p| p.timestamp >= complete).count(),
+        };
+        if complete > limit {
+            exhausted = false;
+            break;
+        }
+    }
+    if let Some(bucket) = bucket {
+        points = downsample(points, bucket);
+    }
+    points.sort_by(|a, b| a.timestamp.cmp(&b.timestamp).then(a.kind.cmp(&b.kind)));
+
+    let mut next = None;
+    if points.len() > limit {
+        // A page starts after the timestamp of the newest point it can't hold, where the next
+        // page ends, so no point is returned twice. If every point it could hold shares that
+        // timestamp, it holds all the points at it instead, going over the limit, so that paging
+        // always moves on.
+        let cut = points[points.len() - limit - 1].timestamp;
+        let start = if points[points.len() - 1].timestamp > cut {
+            points.partition_point(|p| p.timestamp <= cut)
+        } else {
+            points.partition_point(|p| p.timestamp < cut)
+        };
+        // Points older than any read yet may be left in files not read.
+        let end = match start {
+            0 if exhausted => None,
+            0 => Some(cut - 1),
+            _ => Some(points[start - 1].timestamp),
+        };
+        if let Some(end) = end {
+            next = Some(Timestamp::from_millisecond(end)?.to_string());
+        }
+        points.drain(..start);
+    }
+
+    let mut series: BTreeMap<String, Series> = BTreeMap::new();
+    for point in points {
+        series
+            .entry(point.kind.clone())
+            .or_insert_with(|| Series {
+                kind: point.kind,
+                unit: point.unit,
+                points: Vec::new(),
+            })
+            .points
+            .push((point.timestamp, point.value));
+    }
+    Ok(History {
+        device: device.to_string(),
+        series: series.into_values().collect(),
+        next,
+    })
+}
+
+// parse_time parses an RFC 3339 timestamp or Unix seconds into Unix milliseconds.
+pub fn parse_time(time: &str) -> Result<i64> {
+    if let Ok(seconds) = time.parse::<i64>() {
+        return Ok(seconds.saturating_mul(1000));
+    }
+    let timestamp: Timestamp = time
+        .parse()
+        .wrap_err_with(|| format!("invalid time {:?}", time))?;
+    Ok(timestamp.as_millisecond())
+}
+
+// files lists the archive files of device, or of every device, that may hold readings between
+// from and to. Partition dates are in the archive's time zone, so a day either side is included;
+// files are named after when they were written, so those written before from are skipped.
+pub fn files(directory: &Path, device: Option<&str>, from: i64, to: i64) -> Result<Vec<PathBuf>> {
+    let date = |ms: i64| -> Result<Date> {
+        Ok(Timestamp::from_millisecond(ms)?
+            .to_zoned(TimeZone::UTC)
+            .date())
+    };
+    let first = date(from)?.yesterday()?;
+    let last = date(to)?.tomorrow()?;
+
+    let mut files = Vec::new();
+    for date_dir in subdirectories(directory)? {
+        let in_range = partition_value(&date_dir, "date")
+            .and_then(|d| d.parse::<Date>().ok())
+            .is_some_and(|d| first <= d && d <= last);
+        if !in_range {
+            continue;
+        }
+        let mut parents = vec![date_dir.clone()];
+        for hour_dir in subdirectories(&date_dir)? {
+            if partition_value(&hour_dir, "hour").is_some() {
+                parents.push(hour_dir);
+            }
+        }
+        let mut device_dirs = Vec::new();
+        for parent in parents {
+            match device {
+                Some(device) => device_dirs.push(parent.join(device_partition(device))),
+                None => device_dirs.extend(
+                    subdirectories(&parent)?
+                        .into_iter()
+                        .filter(|dir| partition_value(dir, "device").is_some()),
+                ),
+            }
+        }
+        for device_dir in device_dirs {
+            let Ok(entries) = std::fs::read_dir(&device_dir) else {
+                continue;
+            };
+            for entry in entries {
+                let path = entry?.path();
+                if written(&path).is_some_and(|written| written.saturating_mul(1000) >= from) {
+                    files.push(path);
+                }
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+// written is when an archive file was written, in Unix seconds, which it is named after.
+fn written(path: &Path) -> Option<i64> {
+    path.file_name()?
+        .to_str()?
+        .strip_suffix(".parquet")?
+        .parse()
+        .ok()
+}
+
+fn subdirectories(directory: &Path) -> Result<Vec<PathBuf>> {
+    let entries = match std::fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).wrap_err_with(|| format!("reading {}", directory.display())),
+    };
+    let mut directories = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            directories.push(entry.path());
+        }
+    }
+    Ok(directories)
+}
+
+// partition_value is the value of a Hive partition directory such as `date=2024-06-01`.
+fn partition_value(path: &Path, key: &str) -> Option<String> {
+    let name = path.file_name()?.to_str()?;
+    Some(name.strip_prefix(key)?.strip_prefix('=')?.to_string())
+}
+
+// downsample replaces points with the mean of each kind's points in every bucket of width ms,
+// aligned to the Unix epoch.
+fn downsample(points: Vec<Point>, width: i64) -> Vec<Point> {
+    let mut buckets: BTreeMap<(String, i64), (String, f64, u32)> = BTreeMap::new();
+    for point in points {
+        let start = point.timestamp.div_euclid(width) * width;
+        let bucket = buckets
+            .entry((point.kind, start))
+            .or_insert((point.unit, 0.0, 0));
+        bucket.1 += point.value;
+        bucket.2 += 1;
+    }
+    buckets
+        .into_iter()
+        .map(|((kind, timestamp), (unit, sum, count))| Point {
+            timestamp,
+            kind,
+            unit,
+            value: sum / count as f64,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use jiff::Timestamp;
+
+    use crate::archive::Archive;
+    use crate::config::{ArchiveConfig, ArchivePartition};
+    use crate::history::{history, HistoryQuery, Series};
+    use crate::{DeviceId, DeviceReading, Measurement};
+
+    #[test]
+    fn test_history() {
+        let directory =
+            std::env::temp_dir().join(format!("blueplug-history-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        let mut archive = Archive::new(
+            &ArchiveConfig {
+                directory: directory.clone(),
+                partition: ArchivePartition::Daily,
+                interval: 3600,
+                max_age: None,
+                max_size: None,
+            },
+            Some("UTC"),
+        )
+        .unwrap();
+        let reading = |measurement| DeviceReading {
+            device_id: DeviceId {
+                id: "hci0/dev_A4_C1_38_00_00_0B".to_string(),
+                device_name: "ATC_HISTORY".to_string(),
+                address: "A4:C1:38:00:00:0B".to_string(),
+                random_address: None,
+            },
+            measurement,
+            advertisement: None,
+            quality: Vec::new(),
+        };
+        let start: Timestamp = "2024-06-01T23:58:00Z".parse().unwrap();
+        for (minute, temperature) in [20.0, 21.0, 22.0, 23.0].into_iter().enumerate() {
+            let now = start + jiff::SignedDuration::from_secs(60 * minute as i64);
+            archive.push(&reading(Measurement::Temperature(temperature)), now);
+            archive.push(&reading(Measurement::Humidity(40.0)), now);
+            // A file a minute, across midnight.
+            archive.write(now).unwrap();
+        }
+        let now: Timestamp = "2024-06-02T00:10:00Z".parse().unwrap();
+
+        let query = |from: &str, kind: Option<&str>, downsample, limit| HistoryQuery {
+            from: Some(from.to_string()),
+            to: None,
+            kind: kind.map(str::to_string),
+            downsample,
+            limit,
+        };
+        let found = history(
+            &directory,
+            "ATC_HISTORY",
+            &query("2024-06-01T23:59:00Z", Some("temperature"), Some(120), None),
+            now,
+        )
+        .unwrap();
+        // 23:58-00:00 holds 23:59 only, 00:00-00:02 holds 00:00 and 00:01.
+        assert_eq!(
+            found.series,
+            [Series {
+                kind: "temperature".to_string(),
+                unit: "°C".to_string(),
+                points: vec![(1717286280000, 21.0), (1717286400000, 22.5)],
+            }]
+        );
+        assert_eq!(found.next, None);
+
+        let page = history(
+            &directory,
+            "ATC_HISTORY",
+            &query("1717286280", None, None, Some(3)),
+            now,
+        )
+        .unwrap();
+        // Both kinds at 00:01; 00:00's temperature would overflow, so the page stops after it.
+        assert_eq!(page.series.len(), 2);
+        assert_eq!(page.series[0].points, [(1717286460000, 40.0)]);
+        assert_eq!(page.next.as_deref(), Some("2024-06-02T00:00:00Z"));
+
+        // The next page ends where the last one started.
+        let page = history(
+            &directory,
+            "ATC_HISTORY",
+            &HistoryQuery {
+                to: page.next,
+                ..query("1717286280", Some("temperature"), None, Some(2))
+            },
+            now,
+        )
+        .unwrap();
+        assert_eq!(
+            page.series[0].points,
+            [(1717286340000, 21.0), (1717286400000, 22.0)]
+        );
+        assert_eq!(page.next.as_deref(), Some("2024-06-01T23:58:00Z"));
+
+        // With more points at one timestamp than the limit, the page holds them all and the next
+        // ends before them.
+        let page = history(
+            &directory,
+            "ATC_HISTORY",
+            &query("1717286280", None, None, Some(1)),
+            now,
+        )
+        .unwrap();
+        assert_eq!(page.series.len(), 2);
+        assert_eq!(page.series[1].points, [(1717286460000, 23.0)]);
+        assert_eq!(page.next.as_deref(), Some("2024-06-02T00:00:59.999Z"));
+
+        // The whole archive can't be asked for at once.
+        assert!(history(
+            &directory,
+            "ATC_HISTORY",
+            &query("0", None, None, None),
+            now
+        )
+        .is_err());
+
+        assert!(
+            history(&directory, "ATC_OTHER", &HistoryQuery::default(), now)
+                .unwrap()
+                .series
+                .is_empty()
+        );
+        assert!(history(
+            &directory,
+            "ATC_HISTORY",
+            &query("yesterday", None, None, None),
+            now
+        )
+        .is_err());
+        assert!(history(&directory, "../ATC_HISTORY", &HistoryQuery::default(), now).is_err());
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
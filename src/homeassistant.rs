@@ -0,0 +1,496 @@
+use std::collections::HashSet;
+
+use serde_json::{json, Map, Value};
+
+use crate::config::{HomeAssistantConfig, HomeAssistantOverride, OutputFormat};
+use crate::events::{event_kind, EventKind};
+use crate::fanout::devices_match;
+use crate::metrics::METRICS;
+use crate::mqtt::Message;
+use crate::names::TOPIC_NAMES;
+use crate::presence::PresenceChange;
+use crate::{DeviceId, DeviceReading};
+
+// Discovery announces every device and measurement kind published to a broker as a Home Assistant
+// MQTT sensor, the first time one is published. Buttons and dimmers are announced as device
+// triggers instead, one for each way they can be used, to be picked in automations, and devices
+// tracked by [presence] as device trackers.
+pub struct Discovery {
+    prefix: String,
+    overrides: Vec<HomeAssistantOverride>,
+    announced: HashSet<(String, String)>,
+}
+
+impl Discovery {
+    pub fn new(config: &HomeAssistantConfig) -> Self {
+        Discovery {
+            prefix: config.discovery_prefix.clone(),
+            overrides: config.overrides.clone(),
+            announced: HashSet::new(),
+        }
+    }
+
+    // reset forgets what was announced, for when a restarted broker may have lost the retained
+    // discovery messages.
+    pub fn reset(&mut self) {
+        self.announced.clear();
+    }
+
+    // announce returns the discovery messages for a reading published on state_topic, unless its
+    // device and kind were already announced.
+    pub fn announce(
+        &mut self,
+        reading: &DeviceReading,
+        state_topic: &str,
+        format: OutputFormat,
+    ) -> Vec<Message> {
+        let kind = reading.measurement.kind();
+        let key = (identifier(&reading.device_id).to_string(), kind.to_string());
+        if self.announced.contains(&key) {
+            return Vec::new();
+        }
+        let value = match format {
+            OutputFormat::Envelope => "value_json.measurement.value",
+            OutputFormat::Flat => "value_json.value",
+            OutputFormat::Theengs => return Vec::new(),
+        };
+        self.announced.insert(key);
+        if let Some((event_kind, index)) = event_kind(kind) {
+            return triggers(reading, state_topic, event_kind, index, value, &self.prefix);
+        }
+        // Vibration, gas, smoke and carbon monoxide are either detected or not, which Home
+        // Assistant shows as binary sensors.
+        let (component, value_template) = match kind {
+            "vibration"
+            | "gas_detected"
+            | "smoke_detected"
+            | "carbon_monoxide_detected"
+            | "leak" => (
+                "binary_sensor",
+                format!("{{{{ 'ON' if ({} | float) > 0 else 'OFF' }}}}", value),
+            ),
+            _ => ("sensor", format!("{{{{ {} }}}}", value)),
+        };
+
+        let unique_id = object_id(&format!(
+            "blueplug_{}_{}",
+            identifier(&reading.device_id),
+            kind
+        ));
+        let mut payload = Map::new();
+        // The entity is named after the kind, and shown with the device's name in front.
+        payload.insert("name".to_string(), kind.into());
+        payload.insert("unique_id".to_string(), unique_id.clone().into());
+        payload.insert("state_topic".to_string(), state_topic.into());
+        payload.insert("value_template".to_string(), value_template.into());
+        let unit = reading.measurement.unit();
+        if !unit.is_empty() && component == "sensor" {
+            payload.insert("unit_of_measurement".to_string(), unit.into());
+        }
+        let (device_class, state_class) = self.classes(reading);
+        if !device_class.is_empty() {
+            payload.insert("device_class".to_string(), device_class.into());
+        }
+        if !state_class.is_empty() && component == "sensor" {
+            payload.insert("state_class".to_string(), state_class.into());
+        }
+
+        let protocol = METRICS.protocol(&reading.device_id);
+        payload.insert("device".to_string(), device(&reading.device_id, protocol));
+
+        vec![Message {
+            device_id: reading.device_id.clone(),
+            topic: format!("{}/{}/{}/config", self.prefix, component, unique_id),
+            payload: Value::Object(payload),
+        }]
+    }
+
+    // presence returns the discovery message of the device tracker a presence change is published
+    // for, unless it was already announced. Its attributes include when it was last seen.
+    pub fn presence(&mut self, change: &PresenceChange) -> Option<(String, Value)> {
+        let key = ("presence".to_string(), change.name.clone());
+        if !self.announced.insert(key) {
+            return None;
+        }
+        let unique_id = object_id(&format!("blueplug_presence_{}", change.name));
+        let payload = json!({
+            "name": null,
+            "unique_id": unique_id,
+            "state_topic": change.topic,
+            "value_template": "{{ value_json.state }}",
+            "payload_home": "home",
+            "payload_not_home": "away",
+            "json_attributes_topic": change.topic,
+            "source_type": "bluetooth_le",
+            "device": {
+                "identifiers": [unique_id],
+                "name": change.name,
+            },
+        });
+        let topic = format!("{}/device_tracker/{}/config", self.prefix, unique_id);
+        Some((topic, payload))
+    }
+
+    // classes returns the device_class and state_class of a reading, either of which may be empty,
+    // from the first matching override or else from its kind.
+    fn classes(&self, reading: &DeviceReading) -> (String, String) {
+        let kind = reading.measurement.kind();
+        let (device_class, state_class) = classes(kind);
+        let mut device_class = device_class.to_string();
+        let mut state_class = state_class.to_string();
+        let matching = self
+            .overrides
+            .iter()
+            .find(|o| o.kind == kind && devices_match(&o.devices, &reading.device_id));
+        if let Some(matching) = matching {
+            if let Some(class) = &matching.device_class {
+                device_class = class.clone();
+            }
+            if let Some(class) = &matching.state_class {
+                state_class = class.clone();
+            }
+        }
+        (device_class, state_class)
+    }
+}
+
+// triggers are the device_automation messages for each event of a button or dimmer, e.g. type
+// button_double_press of subtype button_1, which fire when the value template renders the event's
+// name.
+fn triggers(
+    reading: &DeviceReading,
+    state_topic: &str,
+    event_kind: EventKind,
+    index: usize,
+    value: &str,
+    prefix: &str,
+) -> Vec<Message> {
+    let protocol = METRICS.protocol(&reading.device_id);
+    let subtype = format!("{}_{}", event_kind.name(), index);
+    event_kind
+        .events()
+        .iter()
+        .map(|event| {
+            let unique_id = object_id(&format!(
+                "blueplug_{}_{}_{}",
+                identifier(&reading.device_id),
+                reading.measurement.kind(),
+                event
+            ));
+            let trigger_type = match event_kind {
+                EventKind::Button => format!("button_{}", event),
+                EventKind::Dimmer => event.to_string(),
+            };
+            Message {
+                device_id: reading.device_id.clone(),
+                topic: format!("{}/device_automation/{}/config", prefix, unique_id),
+                payload: json!({
+                    "automation_type": "trigger",
+                    "topic": state_topic,
+                    "type": trigger_type,
+                    "subtype": subtype,
+                    "payload": event,
+                    "value_template": event_kind.template(value),
+                    "device": device(&reading.device_id, protocol),
+                }),
+            }
+        })
+        .collect()
+}
+
+// identifier identifies a device in discovery: its address, which is the same whichever adapter or
+// proxy heard it, or its id where the platform doesn't reveal the address. Entities keyed on it
+// don't come in pairs for a device heard twice over.
+fn identifier(device_id: &DeviceId) -> &str {
+    match device_id.address.as_str() {
+        "" => &device_id.id,
+        address => address,
+    }
+}
+
+// device describes the physical device, so Home Assistant groups all its entities under it. The
+// address identifies it across adapters where the platform reveals it. Passive scanning never
+// reads the Device Information Service, so the protocol stands in for the model and
+// manufacturer, and there's no firmware version.
+fn device(device_id: &DeviceId, protocol: &str) -> Value {
+    let mut device = Map::new();
+    device.insert(
+        "identifiers".to_string(),
+        json!([object_id(&format!("blueplug_{}", identifier(device_id)))]),
+    );
+    if !device_id.address.is_empty() {
+        device.insert(
+            "connections".to_string(),
+            json!([["mac", device_id.address.to_lowercase()]]),
+        );
+    }
+    device.insert(
+        "name".to_string(),
+        TOPIC_NAMES.display_name(device_id).into(),
+    );
+    if let Some(location) = TOPIC_NAMES.location(device_id) {
+        device.insert("suggested_area".to_string(), location.into());
+    }
+    if protocol != "unknown" {
+        device.insert("model".to_string(), protocol.into());
+    }
+    let manufacturer = match protocol {
+        "ruuvi" => Some("Ruuvi Innovations"),
+        "xiaomi" | "xiaomi_scale" => Some("Xiaomi"),
+        _ => None,
+    };
+    if let Some(manufacturer) = manufacturer {
+        device.insert("manufacturer".to_string(), manufacturer.into());
+    }
+    Value::Object(device)
+}
+
+// classes maps measurement kinds to Home Assistant's sensor device and state classes, or a binary
+// sensor's device class for vibration and alerts. Every kind is a measurement except counters that
+// only grow, which long-term statistics sum instead. Tilt and rotation angles have no device class.
+fn classes(kind: &str) -> (&'static str, &'static str) {
+    let device_class = match kind {
+        "temperature" | "dewpoint" => "temperature",
+        "humidity" => "humidity",
+        "moisture" | "soil_moisture" => "moisture",
+        "battery" => "battery",
+        "voltage" | "voltage_ac" => "voltage",
+        "current" => "current",
+        "power" => "power",
+        "energy" => "energy",
+        "pressure" => "atmospheric_pressure",
+        "illuminance" => "illuminance",
+        "co2" | "carbon_dioxide" => "carbon_dioxide",
+        "pm25" | "pm2_5" => "pm25",
+        "pm10" => "pm10",
+        "tvoc" | "voc" => "volatile_organic_compounds_parts",
+        "weight" | "mass" => "weight",
+        "rssi" => "signal_strength",
+        "rr_interval" => "duration",
+        "vibration" => "vibration",
+        "gas_detected" => "gas",
+        "leak" => "moisture",
+        "smoke_detected" => "smoke",
+        "carbon_monoxide_detected" | "carbon_monoxide" => "carbon_monoxide",
+        "wind_speed" => "wind_speed",
+        "rainfall" | "precipitation" => "precipitation",
+        _ => "",
+    };
+    let state_class = match kind {
+        "energy" | "count" | "gas" | "water" => "total_increasing",
+        _ => "measurement",
+    };
+    (device_class, state_class)
+}
+
+// object_id keeps the characters Home Assistant allows in discovery topics and unique ids.
+fn object_id(id: &str) -> String {
+    id.chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '-' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::config::{HomeAssistantConfig, HomeAssistantOverride, OutputFormat};
+    use crate::homeassistant::Discovery;
+    use crate::presence::{PresenceChange, State};
+    use crate::{DeviceId, DeviceReading, Measurement};
+
+    #[test]
+    fn test_announce() {
+        let mut discovery = Discovery::new(&HomeAssistantConfig {
+            discovery_prefix: "homeassistant".to_string(),
+            overrides: vec![HomeAssistantOverride {
+                devices: vec!["Soil_*".to_string()],
+                kind: "humidity".to_string(),
+                device_class: Some("moisture".to_string()),
+                state_class: None,
+            }],
+        });
+        let reading = DeviceReading {
+            device_id: DeviceId {
+                id: "hci0/dev_A4_C1_38_8F_2C_1A".to_string(),
+                device_name: "ATC_8F2C1A".to_string(),
+                address: "A4:C1:38:8F:2C:1A".to_string(),
+                random_address: None,
+            },
+            measurement: Measurement::Temperature(21.5),
+            advertisement: None,
+            quality: Vec::new(),
+        };
+        let message = discovery
+            .announce(
+                &reading,
+                "device_reading/temperature/ATC_8F2C1A",
+                OutputFormat::Envelope,
+            )
+            .remove(0);
+        assert_eq!(
+            message.topic,
+            "homeassistant/sensor/blueplug_A4_C1_38_8F_2C_1A_temperature/config"
+        );
+        assert_eq!(
+            message.payload,
+            json!({
+                "name": "temperature",
+                "unique_id": "blueplug_A4_C1_38_8F_2C_1A_temperature",
+                "state_topic": "device_reading/temperature/ATC_8F2C1A",
+                "value_template": "{{ value_json.measurement.value }}",
+                "unit_of_measurement": "°C",
+                "device_class": "temperature",
+                "state_class": "measurement",
+                "device": {
+                    "identifiers": ["blueplug_A4_C1_38_8F_2C_1A"],
+                    "connections": [["mac", "a4:c1:38:8f:2c:1a"]],
+                    "name": "ATC_8F2C1A",
+                },
+            })
+        );
+        assert!(discovery
+            .announce(
+                &reading,
+                "device_reading/temperature/ATC_8F2C1A",
+                OutputFormat::Envelope
+            )
+            .is_empty());
+        // Nor when heard through another adapter or proxy.
+        let proxied = DeviceReading {
+            device_id: DeviceId {
+                id: "kitchen-proxy/A4:C1:38:8F:2C:1A".to_string(),
+                ..reading.device_id.clone()
+            },
+            ..reading
+        };
+        assert!(discovery
+            .announce(
+                &proxied,
+                "device_reading/temperature/ATC_8F2C1A",
+                OutputFormat::Envelope
+            )
+            .is_empty());
+
+        let soil = DeviceReading {
+            device_id: DeviceId {
+                id: "hci0/dev_C4_7C_8D_6A_3E_11".to_string(),
+                device_name: "Soil_6A3E11".to_string(),
+                address: String::new(),
+                random_address: None,
+            },
+            measurement: Measurement::Humidity(40.0),
+            advertisement: None,
+            quality: Vec::new(),
+        };
+        let message = discovery
+            .announce(
+                &soil,
+                "device_reading/humidity/Soil_6A3E11",
+                OutputFormat::Flat,
+            )
+            .remove(0);
+        assert_eq!(message.payload["device_class"], "moisture");
+        assert_eq!(message.payload["value_template"], "{{ value_json.value }}");
+
+        let vibration = DeviceReading {
+            measurement: Measurement::Vibration(1.0),
+            ..soil
+        };
+        let message = discovery
+            .announce(
+                &vibration,
+                "device_reading/vibration/Soil_6A3E11",
+                OutputFormat::Envelope,
+            )
+            .remove(0);
+        assert_eq!(
+            message.topic,
+            "homeassistant/binary_sensor/blueplug_hci0_dev_C4_7C_8D_6A_3E_11_vibration/config"
+        );
+        assert_eq!(message.payload["device_class"], "vibration");
+        assert!(message.payload.get("state_class").is_none());
+
+        let smoke = DeviceReading {
+            measurement: Measurement::SmokeDetected(0.0),
+            ..vibration
+        };
+        let message = discovery
+            .announce(
+                &smoke,
+                "device_reading/smoke_detected/Soil_6A3E11",
+                OutputFormat::Envelope,
+            )
+            .remove(0);
+        assert!(message.topic.starts_with("homeassistant/binary_sensor/"));
+        assert_eq!(message.payload["device_class"], "smoke");
+
+        let energy = DeviceReading {
+            measurement: Measurement::Energy(12.3),
+            ..smoke
+        };
+        let message = discovery
+            .announce(
+                &energy,
+                "device_reading/energy/Soil_6A3E11",
+                OutputFormat::Envelope,
+            )
+            .remove(0);
+        assert_eq!(message.payload["device_class"], "energy");
+        assert_eq!(message.payload["state_class"], "total_increasing");
+        assert_eq!(message.payload["unit_of_measurement"], "kWh");
+
+        let button = DeviceReading {
+            device_id: DeviceId {
+                id: "hci0/dev_3C_2E_F5_00_11_22".to_string(),
+                device_name: "SBBT-002C".to_string(),
+                address: String::new(),
+                random_address: None,
+            },
+            measurement: Measurement::Other {
+                kind: "button_2".to_string(),
+                value: 2.0,
+                unit: String::new(),
+            },
+            advertisement: None,
+            quality: Vec::new(),
+        };
+        let messages = discovery.announce(
+            &button,
+            "device_reading/button_2/SBBT-002C",
+            OutputFormat::Envelope,
+        );
+        assert_eq!(messages.len(), 6);
+        assert_eq!(
+            messages[1].topic,
+            "homeassistant/device_automation/blueplug_hci0_dev_3C_2E_F5_00_11_22_button_2_double_press/config"
+        );
+        assert_eq!(messages[1].payload["type"], "button_double_press");
+        assert_eq!(messages[1].payload["subtype"], "button_2");
+        assert_eq!(messages[1].payload["payload"], "double_press");
+        assert_eq!(
+            messages[1].payload["topic"],
+            "device_reading/button_2/SBBT-002C"
+        );
+
+        // Presence devices are device trackers, named after the device.
+        let change = PresenceChange {
+            name: "alice".to_string(),
+            topic: "blueplug/presence/alice".to_string(),
+            state: State::Home,
+            last_seen: Some(1_700_000_000),
+        };
+        let (topic, payload) = discovery.presence(&change).unwrap();
+        assert_eq!(
+            topic,
+            "homeassistant/device_tracker/blueplug_presence_alice/config"
+        );
+        assert_eq!(payload["state_topic"], "blueplug/presence/alice");
+        assert_eq!(payload["payload_not_home"], "away");
+        assert_eq!(payload["device"]["name"], "alice");
+        assert!(discovery.presence(&change).is_none());
+    }
+}
@@ -0,0 +1,22 @@
+use std::net::SocketAddr;
+
+use axum::Router;
+use color_eyre::eyre::{Result, WrapErr};
+use tokio::net::TcpListener;
+
+use crate::supervisor::SUPERVISOR;
+
+// spawn_server serves the HTTP endpoints. Binding happens before returning so a port that's in
+// use is reported at startup.
+pub async fn spawn_server(listen: SocketAddr, router: Router) -> Result<()> {
+    let listener = TcpListener::bind(listen)
+        .await
+        .wrap_err_with(|| format!("listening on {}", listen))?;
+    println!("listening on http://{}", listen);
+    SUPERVISOR.spawn_once("http", async move {
+        if let Err(e) = axum::serve(listener, router).await {
+            println!("http: error {:?}", e);
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,355 @@
+use std::cell::Cell;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use futures_util::pin_mut;
+use futures_util::stream::{self, StreamExt};
+use jiff::Timestamp;
+
+use crate::archive::{self, Archive};
+use crate::config::Config;
+use crate::envelope::Envelope;
+use crate::grafana;
+use crate::keys::KeyStore;
+use crate::names::{NameRules, TOPIC_NAMES};
+use crate::pcap::read_capture;
+use crate::plugin::PluginHost;
+use crate::{device_reading_stream, DeviceId, DeviceReading, Measurement};
+
+// Readings pushed to an endpoint per request.
+const PUSH_CHUNK: usize = 5000;
+
+// ImportArgs configures `blueplug import`, which backfills readings recorded before, e.g. by
+// another bridge before migrating to blueplug, into the sinks that keep history: the Parquet
+// archive and Influx endpoints such as Grafana Cloud's (a Grafana Live push endpoint only shows
+// them in passing). Readings keep their original timestamps, and go to the sinks as they are,
+// without the mappings, derivations and scripts of the pipeline.
+#[derive(clap::Args, Debug)]
+pub struct ImportArgs {
+    /// File to import
+    file: PathBuf,
+    #[arg(long, value_enum)]
+    format: ImportFormat,
+    /// Configuration file with the [archive] and [[grafana]] endpoints to import into
+    #[arg(short = 'c', long)]
+    config: PathBuf,
+    /// Sink to import into, archive or the name of a [[grafana]] endpoint (may be repeated,
+    /// defaults to all of them)
+    #[arg(long = "sink")]
+    sinks: Vec<String>,
+    /// Experimental: load a WASM decoder plugin to decode a capture with (may be repeated)
+    #[arg(long = "plugin")]
+    plugins: Vec<PathBuf>,
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy)]
+enum ImportFormat {
+    /// CSV with a header naming the timestamp, device, kind and value columns, and optionally
+    /// unit, id and address; timestamps in RFC 3339 or seconds since the Unix epoch
+    Csv,
+    /// Envelopes, one per line, as blueplug publishes them
+    Jsonl,
+    /// A PCAP file written by --capture, or btmon, decoded as blueplug decodes advertisements now
+    Capture,
+}
+
+pub async fn run(args: ImportArgs) -> Result<()> {
+    let config = Config::load(&args.config)?;
+    TOPIC_NAMES.set_rules(NameRules::new(&config.names)?);
+    TOPIC_NAMES.set_locations(config.locations.clone());
+    TOPIC_NAMES.set_labels(config.labels.clone());
+    for sink in &args.sinks {
+        if sink != archive::SINK_NAME && !config.grafana.iter().any(|g| &g.name == sink) {
+            return Err(eyre!(
+                "unknown sink {:?}, there are {} and the [[grafana]] endpoints",
+                sink,
+                archive::SINK_NAME
+            ));
+        }
+    }
+    let wanted = |name: &str| args.sinks.is_empty() || args.sinks.iter().any(|s| s == name);
+    let archive = config
+        .archive
+        .as_ref()
+        .filter(|_| wanted(archive::SINK_NAME))
+        .map(|archive| Archive::new(archive, config.time_zone.as_deref()))
+        .transpose()?;
+    let endpoints: Vec<_> = config.grafana.iter().filter(|g| wanted(&g.name)).collect();
+    if archive.is_none() && endpoints.is_empty() {
+        return Err(eyre!(
+            "nothing to import into, configure [archive] or [[grafana]]"
+        ));
+    }
+
+    let readings = match args.format {
+        ImportFormat::Csv => csv_readings(&read(&args.file)?),
+        ImportFormat::Jsonl => jsonl_readings(&read(&args.file)?),
+        ImportFormat::Capture => {
+            let keys = match &config.keys_file {
+                Some(path) => KeyStore::load(path)?,
+                None => KeyStore::default(),
+            };
+            capture_readings(&args.file, PluginHost::load(&args.plugins)?, keys).await
+        }
+    }
+    .wrap_err_with(|| format!("importing {}", args.file.display()))?;
+
+    if let Some(mut archive) = archive {
+        for (timestamp, reading) in &readings {
+            archive.push(reading, *timestamp);
+        }
+        archive.write(Timestamp::now())?;
+        println!(
+            "{}: imported {} readings",
+            archive::SINK_NAME,
+            readings.len()
+        );
+    }
+    let readings: Vec<_> = readings
+        .into_iter()
+        .map(|(timestamp, reading)| (Arc::new(reading), SystemTime::from(timestamp)))
+        .collect();
+    let client = reqwest::Client::new();
+    for endpoint in endpoints {
+        for chunk in readings.chunks(PUSH_CHUNK) {
+            grafana::push(&client, endpoint, grafana::lines(chunk))
+                .await
+                .wrap_err_with(|| format!("pushing to {}", endpoint.name))?;
+        }
+        println!("{}: imported {} readings", endpoint.name, readings.len());
+    }
+    Ok(())
+}
+
+fn read(path: &Path) -> Result<String> {
+    std::fs::read_to_string(path).wrap_err_with(|| format!("reading {}", path.display()))
+}
+
+// csv_readings parses CSV with a header naming its columns, in any order. Devices without an id
+// column get the id `import/<device>`, and readings without a unit that of their kind.
+fn csv_readings(text: &str) -> Result<Vec<(Timestamp, DeviceReading)>> {
+    let mut lines = text
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty());
+    let Some((_, header)) = lines.next() else {
+        return Ok(Vec::new());
+    };
+    let header = fields(header);
+    let column = |name: &str| header.iter().position(|h| h.eq_ignore_ascii_case(name));
+    let required = |name: &str| column(name).ok_or_else(|| eyre!("no {} column", name));
+    let (timestamp, device, kind, value) = (
+        required("timestamp")?,
+        required("device")?,
+        required("kind")?,
+        required("value")?,
+    );
+    let (unit, id, address) = (column("unit"), column("id"), column("address"));
+
+    lines
+        .map(|(number, line)| {
+            let fields = fields(line);
+            let field = |column: usize| fields.get(column).map(String::as_str).unwrap_or_default();
+            let optional = |column: Option<usize>| column.map(field).unwrap_or_default();
+            let reading = || -> Result<(Timestamp, DeviceReading)> {
+                let kind = field(kind).to_string();
+                let value = field(value)
+                    .parse()
+                    .map_err(|_| eyre!("value {:?} isn't a number", field(value)))?;
+                let unit = match optional(unit) {
+                    "" => kind_unit(&kind),
+                    unit => unit.to_string(),
+                };
+                let device_id = DeviceId {
+                    id: match optional(id) {
+                        "" => format!("import/{}", field(device)),
+                        id => id.to_string(),
+                    },
+                    device_name: field(device).to_string(),
+                    address: optional(address).to_string(),
+                    random_address: None,
+                };
+                let reading = DeviceReading {
+                    device_id,
+                    measurement: Measurement::new(kind, value, unit),
+                    advertisement: None,
+                    quality: Vec::new(),
+                };
+                Ok((parse_timestamp(field(timestamp))?, reading))
+            };
+            reading().wrap_err_with(|| format!("line {}", number + 1))
+        })
+        .collect()
+}
+
+// fields splits a line of CSV. Fields may be in double quotes, within which "" is a quote.
+fn fields(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::take(&mut field).trim().to_string()),
+            c => field.push(c),
+        }
+    }
+    fields.push(field.trim().to_string());
+    fields
+}
+
+// parse_timestamp takes RFC 3339, or seconds since the Unix epoch.
+fn parse_timestamp(text: &str) -> Result<Timestamp> {
+    if let Ok(seconds) = text.parse::<f64>() {
+        return Ok(Timestamp::from_millisecond(
+            (seconds * 1000.0).round() as i64
+        )?);
+    }
+    text.parse()
+        .map_err(|_| eyre!("timestamp {:?} isn't RFC 3339 or seconds", text))
+}
+
+// kind_unit is the unit of a built-in kind, and nothing for others.
+fn kind_unit(kind: &str) -> String {
+    [
+        Measurement::Humidity(0.0),
+        Measurement::Temperature(0.0),
+        Measurement::Battery(0.0),
+        Measurement::Voltage(0.0),
+        Measurement::HeartRate(0.0),
+        Measurement::Power(0.0),
+        Measurement::Energy(0.0),
+        Measurement::Current(0.0),
+        Measurement::VoltageAc(0.0),
+        Measurement::Vibration(0.0),
+        Measurement::Tilt(0.0),
+        Measurement::Rotation(0.0),
+        Measurement::WindSpeed(0.0),
+        Measurement::WindDirection(0.0),
+        Measurement::Rainfall(0.0),
+        Measurement::UvIndex(0.0),
+        Measurement::GasDetected(0.0),
+        Measurement::SmokeDetected(0.0),
+        Measurement::CarbonMonoxideDetected(0.0),
+        Measurement::CarbonMonoxide(0.0),
+        Measurement::Leak(0.0),
+    ]
+    .iter()
+    .find(|measurement| measurement.kind() == kind)
+    .map(|measurement| measurement.unit().to_string())
+    .unwrap_or_default()
+}
+
+// jsonl_readings parses envelopes, such as those of a topic saved with mosquitto_sub, keeping
+// their quality flags.
+fn jsonl_readings(text: &str) -> Result<Vec<(Timestamp, DeviceReading)>> {
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(number, line)| {
+            let reading = || -> Result<(Timestamp, DeviceReading)> {
+                let envelope: Envelope = serde_json::from_str(line)?;
+                let quality = envelope
+                    .meta
+                    .get("quality")
+                    .and_then(|quality| serde_json::from_value(quality.clone()).ok())
+                    .unwrap_or_default();
+                let reading = DeviceReading {
+                    device_id: DeviceId {
+                        id: envelope.device.id,
+                        device_name: envelope.device.name,
+                        address: envelope.device.address,
+                        random_address: None,
+                    },
+                    measurement: Measurement::new(
+                        envelope.measurement.kind,
+                        envelope.measurement.value,
+                        envelope.measurement.unit,
+                    ),
+                    advertisement: envelope.advertisement,
+                    quality,
+                };
+                Ok((Timestamp::from_second(envelope.timestamp as i64)?, reading))
+            };
+            reading().wrap_err_with(|| format!("line {}", number + 1))
+        })
+        .collect()
+}
+
+// capture_readings decodes the advertisements of a capture, each reading stamped with when its
+// advertisement was received. device_reading_stream decodes an advertisement only once the next
+// reading is asked for, so the readings that follow taking an advertisement are its.
+async fn capture_readings(
+    path: &Path,
+    plugins: PluginHost,
+    keys: KeyStore,
+) -> Result<Vec<(Timestamp, DeviceReading)>> {
+    let advertisements = read_capture(path)?;
+    let received = Rc::new(Cell::new(UNIX_EPOCH));
+    let events = {
+        let received = received.clone();
+        stream::iter(advertisements).map(move |(time, event)| {
+            received.set(time);
+            Ok(event)
+        })
+    };
+    let readings = device_reading_stream(events, plugins, keys);
+    pin_mut!(readings);
+    let mut imported = Vec::new();
+    while let Some(reading) = readings.next().await {
+        imported.push((Timestamp::try_from(received.get())?, reading));
+    }
+    Ok(imported)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::import::{csv_readings, jsonl_readings};
+    use crate::{Measurement, Quality};
+
+    #[test]
+    fn test_import() {
+        let csv = "\
+            Timestamp,Device,Kind,Value,Unit\n\
+            2024-06-01T20:30:00Z,ATC_IMPORT,temperature,21.5,\n\
+            \n\
+            1717273860.5,\"Shed, north\",temperature_f,70.7,°F\n";
+        let readings = csv_readings(csv).unwrap();
+        assert_eq!(readings.len(), 2);
+        let (timestamp, reading) = &readings[0];
+        assert_eq!(timestamp.as_second(), 1717273800);
+        assert_eq!(reading.device_id.id, "import/ATC_IMPORT");
+        assert_eq!(reading.measurement, Measurement::Temperature(21.5));
+        let (timestamp, reading) = &readings[1];
+        assert_eq!(timestamp.as_millisecond(), 1717273860500);
+        assert_eq!(reading.device_id.device_name, "Shed, north");
+        assert_eq!(reading.measurement.unit(), "°F");
+
+        let Err(error) =
+            csv_readings("timestamp,device,kind,value\nyesterday,ATC_IMPORT,humidity,40\n")
+        else {
+            panic!("imported a timestamp of yesterday");
+        };
+        assert_eq!(
+            format!("{:#}", error),
+            "line 2: timestamp \"yesterday\" isn't RFC 3339 or seconds"
+        );
+        assert!(csv_readings("time,device,kind,value\n").is_err());
+
+        let jsonl = r#"{"schema_version": 1, "device": {"id": "hci0/dev_A4_C1_38_00_00_0B", "name": "ATC_IMPORT"}, "measurement": {"kind": "battery_days", "value": 120.5, "unit": "d"}, "timestamp": 1717273800, "meta": {"quality": ["extrapolated"]}}"#;
+        let readings = jsonl_readings(jsonl).unwrap();
+        let (timestamp, reading) = &readings[0];
+        assert_eq!(timestamp.as_second(), 1717273800);
+        assert_eq!(reading.device_id.id, "hci0/dev_A4_C1_38_00_00_0B");
+        assert_eq!(reading.measurement.kind(), "battery_days");
+        assert_eq!(reading.quality, vec![Quality::Extrapolated]);
+    }
+}
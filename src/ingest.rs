@@ -0,0 +1,100 @@
+use async_stream::stream;
+use axum::body::Bytes;
+use axum::extract::{DefaultBodyLimit, State};
+use axum::http::StatusCode;
+use axum::routing::post;
+use axum::Router;
+use color_eyre::eyre::Result;
+use futures_core::stream::Stream;
+use rumqttc::{AsyncClient, Event, Packet, QoS};
+use tokio::sync::mpsc;
+
+use crate::config::BrokerConfig;
+use crate::error::BlueplugError;
+use crate::forward::decode;
+use crate::mqtt::{mqtt_options, Connection};
+use crate::supervisor::SUPERVISOR;
+use crate::DeviceEvent;
+
+const INGEST_CHANNEL_CAPACITY: usize = 1024;
+
+// Bytes a forwarded batch may take as sent; decompressed it is limited by MAX_DECOMPRESSED.
+const MAX_FORWARD_BODY: usize = 4 * 1024 * 1024;
+
+// ingest_channel carries advertisements sent by `blueplug forward` nodes into the local pipeline,
+// however they arrive.
+pub fn ingest_channel() -> (
+    mpsc::Sender<DeviceEvent>,
+    impl Stream<Item = Result<DeviceEvent, BlueplugError>>,
+) {
+    let (sender, mut receiver) = mpsc::channel(INGEST_CHANNEL_CAPACITY);
+    let events = stream! {
+        while let Some(event) = receiver.recv().await {
+            yield Ok(event);
+        }
+    };
+    (sender, events)
+}
+
+// router accepts forwarded batches at POST /forward.
+pub fn router(sender: mpsc::Sender<DeviceEvent>) -> Router {
+    Router::new()
+        .route("/forward", post(forwarded))
+        .layer(DefaultBodyLimit::max(MAX_FORWARD_BODY))
+        .with_state(sender)
+}
+
+async fn forwarded(State(sender): State<mpsc::Sender<DeviceEvent>>, body: Bytes) -> StatusCode {
+    let events = match decode(&body) {
+        Ok(events) => events,
+        Err(e) => {
+            println!("ingest: rejected batch: {:?}", e);
+            return StatusCode::BAD_REQUEST;
+        }
+    };
+    for event in events {
+        if sender.send(event).await.is_err() {
+            return StatusCode::SERVICE_UNAVAILABLE;
+        }
+    }
+    StatusCode::NO_CONTENT
+}
+
+// spawn_mqtt_ingest subscribes to batches forwarded over MQTT, using its own session on the broker
+// so it doesn't interfere with publishing.
+pub fn spawn_mqtt_ingest(
+    broker: &BrokerConfig,
+    topic_prefix: &str,
+    sender: mpsc::Sender<DeviceEvent>,
+) -> Result<()> {
+    let mut broker = broker.clone();
+    broker.client_id = format!("{}-ingest", broker.client_id);
+    let (client, eventloop) = AsyncClient::new(mqtt_options(&broker)?, 10);
+    let topic = format!("{}/+", topic_prefix);
+    let mut connection = Connection::new(&format!("{} ingest", broker.name), eventloop);
+
+    SUPERVISOR.spawn_once(format!("{} ingest", broker.name), async move {
+        loop {
+            match connection.next().await {
+                // Subscriptions don't survive reconnecting with a clean session.
+                Event::Incoming(Packet::ConnAck(_)) => {
+                    if let Err(e) = client.subscribe(&topic, QoS::AtLeastOnce).await {
+                        println!("{}: ingest subscribe failed {:?}", broker.name, e);
+                    }
+                }
+                Event::Incoming(Packet::Publish(publish)) => match decode(&publish.payload) {
+                    Ok(events) => {
+                        for event in events {
+                            if sender.send(event).await.is_err() {
+                                return;
+                            }
+                        }
+                    }
+                    Err(e) => println!("{}: ingest rejected batch: {:?}", broker.name, e),
+                },
+                _ => {}
+            }
+        }
+    });
+    Ok(())
+}
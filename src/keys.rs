@@ -0,0 +1,309 @@
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::OpenOptions;
+use std::io::Write;
+#[cfg(unix)]
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::{bthome, mibeacon};
+
+// KeysArgs configures `blueplug keys`, which manages the bind keys of devices that encrypt their
+// advertisements, such as BTHome v2 and newer Xiaomi sensors.
+#[derive(clap::Args, Debug)]
+pub struct KeysArgs {
+    /// File the keys are kept in, readable only by its owner. Defaults to the keys_file of
+    /// --config, or /etc/blueplug/keys.toml
+    #[arg(long)]
+    file: Option<PathBuf>,
+    /// Configuration file whose keys_file to manage
+    #[arg(short = 'c', long, conflicts_with = "file")]
+    config: Option<PathBuf>,
+    #[command(subcommand)]
+    command: KeysCommand,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum KeysCommand {
+    /// Add or replace the bind key of a device, by MAC address
+    Add { device: String, key: String },
+    /// List the devices with a bind key, without the keys themselves
+    List,
+    /// Remove the bind key of a device
+    Remove { device: String },
+}
+
+const DEFAULT_KEYS_FILE: &str = "/etc/blueplug/keys.toml";
+
+pub fn run(args: KeysArgs) -> Result<()> {
+    let file = match (args.file, args.config) {
+        (Some(file), _) => file,
+        (None, Some(config)) => Config::load(&config)?
+            .keys_file
+            .ok_or_else(|| eyre!("{} has no keys_file", config.display()))?,
+        (None, None) => PathBuf::from(DEFAULT_KEYS_FILE),
+    };
+    let mut keys = KeyStore::load(&file)?;
+    match args.command {
+        KeysCommand::Add { device, key } => {
+            let address = keys.add(&device, &key)?;
+            keys.save()?;
+            println!("added key for {}", address);
+        }
+        KeysCommand::List => {
+            // Not even part of a key is printed, only how long it is.
+            for (address, key) in &keys.keys {
+                println!("{} {}-bit key", address, key.len() * 4);
+            }
+        }
+        KeysCommand::Remove { device } => {
+            let address = normalize_address(&device)?;
+            if keys.keys.remove(&address).is_none() {
+                return Err(eyre!("no key for {}", address));
+            }
+            keys.save()?;
+            println!("removed key for {}", address);
+        }
+    }
+    Ok(())
+}
+
+// KeyStore is the bind keys of devices by MAC address, kept as a TOML table of address to key in
+// hex. Keys are secrets, so the file is created readable only by its owner, and one that anyone
+// else can read is refused rather than used.
+#[derive(Debug, Default)]
+pub struct KeyStore {
+    path: PathBuf,
+    keys: BTreeMap<String, String>,
+}
+
+impl KeyStore {
+    // load reads the keys at path, which need not exist yet.
+    pub fn load(path: &Path) -> Result<Self> {
+        let keys = match std::fs::metadata(path) {
+            Ok(metadata) => {
+                #[cfg(unix)]
+                check_mode(path, &metadata)?;
+                let text = std::fs::read_to_string(path)
+                    .wrap_err_with(|| format!("reading {}", path.display()))?;
+                toml::from_str(&text).wrap_err_with(|| format!("in {}", path.display()))?
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e).wrap_err_with(|| format!("reading {}", path.display())),
+        };
+        let store = KeyStore {
+            path: path.to_path_buf(),
+            keys,
+        };
+        for (address, key) in &store.keys {
+            parse_key(key)
+                .wrap_err_with(|| format!("key for {} in {}", address, path.display()))?;
+        }
+        Ok(store)
+    }
+
+    // key is the bind key of the device at address, in the form device ids use.
+    pub fn key(&self, address: &str) -> Option<Vec<u8>> {
+        parse_key(self.keys.get(address)?).ok()
+    }
+
+    // decrypt replaces the encrypted BTHome and MiBeacon service data of the device at address with
+    // the plain payloads they carry, if it has a key. Payloads that don't decrypt, with a wrong key
+    // or corrupted, are left as they are, and decode to nothing.
+    pub fn decrypt<'a>(
+        &self,
+        address: &str,
+        service_data: &'a HashMap<Uuid, Vec<u8>>,
+    ) -> Cow<'a, HashMap<Uuid, Vec<u8>>> {
+        let Some(key) = self.key(address) else {
+            return Cow::Borrowed(service_data);
+        };
+        let Some(mac) = mac_bytes(address) else {
+            return Cow::Borrowed(service_data);
+        };
+        let mut decrypted = Cow::Borrowed(service_data);
+        for (uuid, decrypt) in [
+            (
+                btsensor::bthome::v2::UUID,
+                bthome::decrypt as fn(&_, &_, &_) -> _,
+            ),
+            (mibeacon::UUID, mibeacon::decrypt),
+        ] {
+            if let Some(plain) = service_data
+                .get(&uuid)
+                .and_then(|data| decrypt(data, &mac, &key))
+            {
+                decrypted.to_mut().insert(uuid, plain);
+            }
+        }
+        decrypted
+    }
+
+    // add sets the key of device, returning its normalized address.
+    fn add(&mut self, device: &str, key: &str) -> Result<String> {
+        let address = normalize_address(device)?;
+        parse_key(key)?;
+        self.keys.insert(address.clone(), key.to_lowercase());
+        Ok(address)
+    }
+
+    // save replaces the file with the keys, through a temporary file created with mode 600 so the
+    // keys are never readable by anyone else.
+    fn save(&self) -> Result<()> {
+        if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir).wrap_err_with(|| format!("creating {}", dir.display()))?;
+        }
+        let tmp = self.path.with_extension("tmp");
+        let _ = std::fs::remove_file(&tmp);
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        let mut file = options
+            .open(&tmp)
+            .wrap_err_with(|| format!("writing {}", tmp.display()))?;
+        file.write_all(toml::to_string(&self.keys)?.as_bytes())
+            .wrap_err_with(|| format!("writing {}", tmp.display()))?;
+        std::fs::rename(&tmp, &self.path)
+            .wrap_err_with(|| format!("writing {}", self.path.display()))?;
+        Ok(())
+    }
+}
+
+// check_mode refuses a keys file that anyone but its owner can read. Elsewhere, the file inherits
+// the permissions of its directory.
+#[cfg(unix)]
+fn check_mode(path: &Path, metadata: &std::fs::Metadata) -> Result<()> {
+    let mode = metadata.permissions().mode() & 0o777;
+    if mode & 0o077 != 0 {
+        return Err(eyre!(
+            "{} has mode {:o}, run chmod 600 on it so only its owner can read the keys",
+            path.display(),
+            mode
+        ));
+    }
+    Ok(())
+}
+
+// normalize_address checks device is a MAC address and returns it in the upper case form device
+// ids use.
+fn normalize_address(device: &str) -> Result<String> {
+    let octets: Vec<&str> = device.split([':', '-']).collect();
+    let valid = octets.len() == 6
+        && octets
+            .iter()
+            .all(|octet| octet.len() == 2 && octet.chars().all(|c| c.is_ascii_hexdigit()));
+    if !valid {
+        return Err(eyre!(
+            "invalid device {:?}, expected a MAC address such as A4:C1:38:12:34:56",
+            device
+        ));
+    }
+    Ok(octets.join(":").to_uppercase())
+}
+
+// mac_bytes is the octets of an address in the form device ids use.
+fn mac_bytes(address: &str) -> Option<[u8; 6]> {
+    let octets: Vec<u8> = address
+        .split(':')
+        .map(|octet| u8::from_str_radix(octet, 16).ok())
+        .collect::<Option<_>>()?;
+    octets.try_into().ok()
+}
+
+// parse_key decodes a bind key: 32 hex digits, or 24 for Xiaomi devices using MiBeacon v2/v3.
+fn parse_key(key: &str) -> Result<Vec<u8>> {
+    if !matches!(key.len(), 24 | 32) || !key.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(eyre!("invalid key, expected 32 (or 24) hex digits"));
+    }
+    Ok((0..key.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&key[i..i + 2], 16).unwrap())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    #[cfg(unix)]
+    use std::os::unix::fs::PermissionsExt;
+
+    use btleplug::api::bleuuid::uuid_from_u16;
+
+    use crate::keys::KeyStore;
+    use crate::{bthome, mibeacon, Measurement};
+
+    #[test]
+    fn test_key_store() {
+        let path = std::env::temp_dir().join(format!("blueplug-keys-{}.toml", std::process::id()));
+        let mut keys = KeyStore::load(&path).unwrap();
+        assert!(keys.add("a4-c1-38-12-34-56", "not a key").is_err());
+        assert!(keys
+            .add("kitchen", "231d39c1d7cc1ab1aee224cd096db932")
+            .is_err());
+        let address = keys
+            .add("a4-c1-38-12-34-56", "231D39C1D7CC1AB1AEE224CD096DB932")
+            .unwrap();
+        assert_eq!(address, "A4:C1:38:12:34:56");
+        keys.save().unwrap();
+
+        #[cfg(unix)]
+        {
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        let keys = KeyStore::load(&path).unwrap();
+        assert_eq!(
+            keys.keys["A4:C1:38:12:34:56"],
+            "231d39c1d7cc1ab1aee224cd096db932"
+        );
+
+        #[cfg(unix)]
+        {
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+            assert!(KeyStore::load(&path).is_err());
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_decrypt() {
+        let mut keys = KeyStore::default();
+        keys.add("54:48:E6:8F:80:A5", "231d39c1d7cc1ab1aee224cd096db932")
+            .unwrap();
+        keys.add("C4:7C:8D:6A:3E:11", "814aac74c4f17b6c1581e1ab87816b99")
+            .unwrap();
+        let hex = |s: &str| -> Vec<u8> {
+            (0..s.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+                .collect()
+        };
+
+        // BTHome v2 with a temperature of 25.06 and humidity of 50.55, and its counter and MIC.
+        let bthome =
+            HashMap::from([(uuid_from_u16(0xfcd2), hex("41a47266c95f730011223378237214"))]);
+        let plain = keys.decrypt("54:48:E6:8F:80:A5", &bthome);
+        assert_eq!(plain[&uuid_from_u16(0xfcd2)], hex("4002ca0903bf13"));
+        // Without a key, or with another device's, the payload is left encrypted.
+        assert_eq!(*keys.decrypt("54:48:E6:8F:80:A6", &bthome), bthome);
+        let mut corrupted = bthome.clone();
+        corrupted.get_mut(&uuid_from_u16(0xfcd2)).unwrap()[2] ^= 1;
+        assert_eq!(*keys.decrypt("54:48:E6:8F:80:A5", &corrupted), corrupted);
+
+        // MiBeacon v5 of a leak sensor, with the leak and battery objects encrypted.
+        let frame = hex("58588b0d12113e6a8d7cc489e37357bbbe3f47010000e403d8cf");
+        assert!(mibeacon::measurements(&frame).is_empty());
+        let service_data = HashMap::from([(mibeacon::UUID, frame)]);
+        let plain = keys.decrypt("C4:7C:8D:6A:3E:11", &service_data);
+        assert_eq!(
+            mibeacon::measurements(&plain[&mibeacon::UUID]),
+            vec![Measurement::Leak(1.0), Measurement::Battery(95.0)]
+        );
+        assert!(bthome::decrypt(&hex("4002ca0903bf13"), &[0; 6], &[0; 16]).is_none());
+    }
+}
@@ -0,0 +1,291 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use serde::Deserialize;
+use tokio::net::UdpSocket;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::config::{KnxConfig, KnxMapping};
+use crate::exempt::EXEMPT;
+use crate::supervisor::SUPERVISOR;
+use crate::DeviceReading;
+
+// KNXnet/IP header: header length, protocol version 1.0, then the service type.
+const ROUTING_INDICATION: [u8; 4] = [0x06, 0x10, 0x05, 0x30];
+const L_DATA_IND: u8 = 0x29;
+// Standard frame, no repeat, broadcast, low priority.
+const CONTROL_1: u8 = 0xbc;
+// Group destination, hop count 6.
+const CONTROL_2: u8 = 0xe0;
+const GROUP_VALUE_WRITE: u8 = 0x80;
+
+// Dpt is the datapoint type a measurement is sent to a group address as, named by the KNX DPT
+// number such as "9.001". Every subtype of a main type is encoded the same way, so "9.001"
+// (temperature), "9.004" (lux) and "9.007" (humidity) are all 2-byte floats; only 5.001, a
+// percentage scaled to 0-255, differs from the rest of 5.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(try_from = "String")]
+pub enum Dpt {
+    // 1.x, on if the value isn't 0.
+    Boolean,
+    // 5.001, 0 to 100 %.
+    Percent,
+    // 5.x, 0 to 255.
+    Unsigned8,
+    // 9.x, the KNX 16-bit float.
+    Float16,
+    // 14.x, an IEEE 754 float.
+    Float32,
+}
+
+impl TryFrom<String> for Dpt {
+    type Error = String;
+
+    fn try_from(dpt: String) -> Result<Self, String> {
+        let main = dpt.split('.').next().unwrap_or_default();
+        match (main, dpt.as_str()) {
+            (_, "5.001") => Ok(Dpt::Percent),
+            ("1", _) => Ok(Dpt::Boolean),
+            ("5", _) => Ok(Dpt::Unsigned8),
+            ("9", _) => Ok(Dpt::Float16),
+            ("14", _) => Ok(Dpt::Float32),
+            _ => Err(format!(
+                "unsupported datapoint type {:?}, expected 1.x, 5.x, 9.x or 14.x",
+                dpt
+            )),
+        }
+    }
+}
+
+impl Dpt {
+    fn encode(self, value: f64) -> Vec<u8> {
+        match self {
+            Dpt::Boolean => vec![(value != 0.0) as u8],
+            Dpt::Percent => vec![(value * 255.0 / 100.0).round() as u8],
+            Dpt::Unsigned8 => vec![value.round() as u8],
+            Dpt::Float16 => float16(value).to_be_bytes().to_vec(),
+            Dpt::Float32 => (value as f32).to_be_bytes().to_vec(),
+        }
+    }
+}
+
+// float16 encodes value as DPT 9, 0.01 × M × 2^E with an 11-bit mantissa and its sign, saturating
+// at the type's range of ±670760.96.
+fn float16(value: f64) -> u16 {
+    let mut mantissa = (value * 100.0).round();
+    let mut exponent = 0;
+    while !(-2048.0..=2047.0).contains(&mantissa) && exponent < 15 {
+        mantissa = (mantissa / 2.0).round();
+        exponent += 1;
+    }
+    let mantissa = mantissa.clamp(-2048.0, 2047.0) as i16;
+    let sign = if mantissa < 0 { 0x8000 } else { 0 };
+    sign | (exponent << 11) | (mantissa as u16 & 0x07ff)
+}
+
+// parse_address parses a KNX address: an individual address such as "1.1.250", or a three-level
+// group address such as "3/1/10".
+pub fn parse_address(address: &str, separator: char) -> Result<u16> {
+    let parts: Vec<&str> = address.split(separator).collect();
+    let limits: [u16; 3] = if separator == '/' {
+        [31, 7, 255]
+    } else {
+        [15, 15, 255]
+    };
+    let shifts = if separator == '/' {
+        [11, 8, 0]
+    } else {
+        [12, 8, 0]
+    };
+    if parts.len() != 3 {
+        return Err(eyre!("invalid KNX address {:?}", address));
+    }
+    let mut parsed = 0;
+    for ((part, limit), shift) in parts.iter().zip(limits).zip(shifts) {
+        let n: u16 = part
+            .parse()
+            .ok()
+            .filter(|n| *n <= limit)
+            .ok_or_else(|| eyre!("invalid KNX address {:?}", address))?;
+        parsed |= n << shift;
+    }
+    Ok(parsed)
+}
+
+// telegram is a KNXnet/IP routing indication writing value to group as dpt.
+fn telegram(source: u16, group: u16, dpt: Dpt, value: f64) -> Vec<u8> {
+    let mut cemi = vec![L_DATA_IND, 0, CONTROL_1, CONTROL_2];
+    cemi.extend(source.to_be_bytes());
+    cemi.extend(group.to_be_bytes());
+    let data = dpt.encode(value);
+    if dpt == Dpt::Boolean {
+        // Types of up to 6 bits are carried in the APCI octet.
+        cemi.extend([1, 0, GROUP_VALUE_WRITE | data[0]]);
+    } else {
+        cemi.push(data.len() as u8 + 1);
+        cemi.extend([0, GROUP_VALUE_WRITE]);
+        cemi.extend(data);
+    }
+    let mut packet = ROUTING_INDICATION.to_vec();
+    packet.extend(((cemi.len() + 6) as u16).to_be_bytes());
+    packet.extend(cemi);
+    packet
+}
+
+// Knx writes measurements to KNX group addresses by KNXnet/IP routing, multicasting to the KNX IP
+// routers on the network, which put the telegrams on the bus. Each mapping sends the readings of
+// kind from a device, by name or id, to a group address, at most once every min_interval seconds
+// so fast advertisers can't flood the bus, unless the kind is exempt (see exempt.rs). Tunnelling
+// through a KNX IP interface isn't supported.
+pub struct Knx {
+    source: u16,
+    mappings: Vec<(KnxMapping, u16)>,
+    min_interval: Duration,
+    // When each group address was last written.
+    sent: HashMap<u16, Instant>,
+}
+
+impl Knx {
+    pub fn new(config: &KnxConfig) -> Result<Self> {
+        let source =
+            parse_address(&config.individual_address, '.').wrap_err("knx individual_address")?;
+        let mappings = config
+            .mappings
+            .iter()
+            .map(|mapping| Ok((mapping.clone(), parse_address(&mapping.group_address, '/')?)))
+            .collect::<Result<_>>()?;
+        Ok(Knx {
+            source,
+            mappings,
+            min_interval: Duration::from_secs(config.min_interval),
+            sent: HashMap::new(),
+        })
+    }
+
+    // telegrams returns the telegrams to send for reading.
+    fn telegrams(&mut self, reading: &DeviceReading, now: Instant) -> Vec<Vec<u8>> {
+        let mut telegrams = Vec::new();
+        for (mapping, group) in &self.mappings {
+            let device = &reading.device_id;
+            if (mapping.device != device.device_name && mapping.device != device.id)
+                || mapping.kind != reading.measurement.kind()
+            {
+                continue;
+            }
+            if let Some(sent) = self.sent.get(group) {
+                if now.duration_since(*sent) < self.min_interval
+                    && !EXEMPT.is_exempt(&reading.measurement)
+                {
+                    continue;
+                }
+            }
+            self.sent.insert(*group, now);
+            let value = reading.measurement.value();
+            telegrams.push(telegram(self.source, *group, mapping.dpt, value));
+        }
+        telegrams
+    }
+}
+
+pub async fn spawn_knx(
+    config: &KnxConfig,
+    mut readings: broadcast::Receiver<Arc<DeviceReading>>,
+) -> Result<()> {
+    let mut knx = Knx::new(config)?;
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    socket.set_multicast_ttl_v4(16)?;
+    let address = config.multicast_address;
+    SUPERVISOR.spawn_once("knx", async move {
+        loop {
+            let reading = match readings.recv().await {
+                Ok(reading) => reading,
+                Err(RecvError::Lagged(skipped)) => {
+                    println!("knx: falling behind, dropped {} readings", skipped);
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            };
+            for telegram in knx.telegrams(&reading, Instant::now()) {
+                if let Err(e) = socket.send_to(&telegram, address).await {
+                    println!("knx: sending to {} failed: {:?}", address, e);
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::config::{KnxConfig, KnxMapping};
+    use crate::knx::{float16, parse_address, Dpt, Knx};
+    use crate::{DeviceId, DeviceReading, Measurement};
+
+    #[test]
+    fn test_knx() {
+        assert_eq!(float16(21.5), 0x0c33);
+        assert_eq!(float16(-30.0), 0x8a24);
+        assert_eq!(float16(0.0), 0);
+        assert_eq!(parse_address("3/1/10", '/').unwrap(), 0x190a);
+        assert_eq!(parse_address("1.1.250", '.').unwrap(), 0x11fa);
+        assert!(parse_address("32/0/0", '/').is_err());
+        assert!(Dpt::try_from("7.001".to_string()).is_err());
+
+        let mapping = |kind: &str, group_address: &str, dpt: &str| KnxMapping {
+            device: "Greenhouse".to_string(),
+            kind: kind.to_string(),
+            group_address: group_address.to_string(),
+            dpt: Dpt::try_from(dpt.to_string()).unwrap(),
+        };
+        let mut knx = Knx::new(&KnxConfig {
+            multicast_address: "224.0.23.12:3671".parse().unwrap(),
+            individual_address: "1.1.250".to_string(),
+            min_interval: 30,
+            mappings: vec![
+                mapping("temperature", "3/1/10", "9.001"),
+                mapping("battery", "3/1/11", "5.001"),
+            ],
+        })
+        .unwrap();
+        let reading = |measurement| DeviceReading {
+            device_id: DeviceId {
+                id: "hci0/knx".to_string(),
+                device_name: "Greenhouse".to_string(),
+                address: String::new(),
+                random_address: None,
+            },
+            measurement,
+            advertisement: None,
+            quality: Vec::new(),
+        };
+        let now = Instant::now();
+        assert_eq!(
+            knx.telegrams(&reading(Measurement::Temperature(21.5)), now),
+            [vec![
+                0x06, 0x10, 0x05, 0x30, 0x00, 0x13, 0x29, 0x00, 0xbc, 0xe0, 0x11, 0xfa, 0x19, 0x0a,
+                0x03, 0x00, 0x80, 0x0c, 0x33
+            ]]
+        );
+        assert_eq!(
+            knx.telegrams(&reading(Measurement::Battery(100.0)), now)[0][14..],
+            [0x02, 0x00, 0x80, 0xff]
+        );
+        // Throttled until min_interval has passed.
+        assert!(knx
+            .telegrams(&reading(Measurement::Temperature(21.6)), now)
+            .is_empty());
+        assert_eq!(
+            knx.telegrams(
+                &reading(Measurement::Temperature(21.6)),
+                now + Duration::from_secs(30)
+            )
+            .len(),
+            1
+        );
+    }
+}
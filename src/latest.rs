@@ -0,0 +1,223 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use color_eyre::eyre::{Result, WrapErr};
+use serde::{Deserialize, Serialize};
+use tokio::time;
+
+use crate::clock::{unix_timestamp, CLOCK};
+use crate::config::AvailabilityConfig;
+use crate::events::is_event;
+use crate::supervisor::SUPERVISOR;
+use crate::watchdog::{self, Availability};
+use crate::{DeviceReading, Quality};
+
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+// LatestReading is a reading as last seen, with when it was seen. Readings restored from disk
+// after a restart are stale until the device is heard from again.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct LatestReading {
+    #[serde(flatten)]
+    pub reading: Arc<DeviceReading>,
+    // Seconds since the Unix epoch.
+    pub timestamp: u64,
+    #[serde(default)]
+    pub is_stale: bool,
+    // When the reading was seen by the monotonic clock, to correct timestamp if the wall clock
+    // jumps. Unknown for readings restored from disk.
+    #[serde(skip)]
+    pub received: Option<Instant>,
+}
+
+// LatestReadings remembers the most recent reading of each kind from each device.
+#[derive(Default, Clone)]
+pub struct LatestReadings {
+    readings: HashMap<(String, String), LatestReading>,
+}
+
+impl LatestReadings {
+    pub fn update(&mut self, reading: Arc<DeviceReading>) {
+        // Events are over as soon as they happen, so there is nothing to remember.
+        if is_event(&reading.measurement) {
+            return;
+        }
+        self.insert(LatestReading {
+            reading,
+            timestamp: unix_timestamp(),
+            is_stale: false,
+            received: Some(Instant::now()),
+        });
+    }
+
+    fn insert(&mut self, latest: LatestReading) {
+        let key = (
+            latest.reading.device_id.id.clone(),
+            latest.reading.measurement.kind().to_string(),
+        );
+        self.readings.insert(key, latest);
+    }
+
+    // readings returns the latest readings ordered by device and kind, which keeps the readings of
+    // one device together. Timestamps are corrected for any jump of the wall clock.
+    pub fn readings(&self) -> Vec<LatestReading> {
+        let mut readings: Vec<_> = self.readings.iter().collect();
+        readings.sort_by_key(|(key, _)| *key);
+        readings
+            .into_iter()
+            .map(|(_, latest)| {
+                let mut latest = latest.clone();
+                if let Some(received) = latest.received {
+                    latest.timestamp = CLOCK.restamp(latest.timestamp, received);
+                }
+                latest
+            })
+            .collect()
+    }
+
+    // filter keeps only the readings for which keep returns true.
+    pub fn filter(&self, keep: impl Fn(&DeviceReading) -> bool) -> Self {
+        let readings = self
+            .readings
+            .iter()
+            .filter(|(_, latest)| keep(&latest.reading))
+            .map(|(key, latest)| (key.clone(), latest.clone()))
+            .collect();
+        LatestReadings { readings }
+    }
+
+    // load restores readings saved by a previous run, marking them stale unless their device only
+    // advertises on events, when the state it last reported still holds. Advertisements are
+    // numbered afresh every run, so the restored readings lose theirs. A missing file is simply an
+    // empty state, as on the first run.
+    pub fn load(path: &Path, availability: &[AvailabilityConfig]) -> Result<Self> {
+        let mut latest = LatestReadings::default();
+        let json = match std::fs::read(path) {
+            Ok(json) => json,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(latest),
+            Err(e) => return Err(e).wrap_err_with(|| format!("reading {}", path.display())),
+        };
+        let readings: Vec<LatestReading> =
+            serde_json::from_slice(&json).wrap_err_with(|| format!("in {}", path.display()))?;
+        for mut reading in readings {
+            reading.is_stale = watchdog::availability(availability, &reading.reading.device_id)
+                != Availability::EventOnly;
+            if let Some(restored) = Arc::get_mut(&mut reading.reading) {
+                restored.advertisement = None;
+                if !restored.quality.contains(&Quality::FromStaleCache) {
+                    restored.quality.push(Quality::FromStaleCache);
+                }
+            }
+            latest.insert(reading);
+        }
+        Ok(latest)
+    }
+
+    // save writes the readings to a temporary file first, so a crash mid-write can't lose the
+    // previous state.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let json = serde_json::to_vec(&self.readings())?;
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, json).wrap_err_with(|| format!("writing {}", tmp.display()))?;
+        std::fs::rename(&tmp, path).wrap_err_with(|| format!("writing {}", path.display()))?;
+        Ok(())
+    }
+}
+
+// spawn_persistence saves the readings to path every SAVE_INTERVAL, and a last time on shutdown.
+pub fn spawn_persistence(path: PathBuf, latest: Arc<Mutex<LatestReadings>>) {
+    SUPERVISOR.spawn_graceful("persistence", async move {
+        let mut interval = time::interval(SAVE_INTERVAL);
+        interval.tick().await;
+        loop {
+            let shutdown = tokio::select! {
+                _ = interval.tick() => false,
+                _ = SUPERVISOR.cancelled() => true,
+            };
+            let snapshot = latest.lock().unwrap().clone();
+            if let Err(e) = snapshot.save(&path) {
+                println!("saving state failed: {:?}", e);
+            }
+            if shutdown {
+                return;
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::latest::LatestReadings;
+    use crate::{DeviceId, DeviceReading, Measurement, Quality};
+
+    fn reading(id: &str, measurement: Measurement) -> Arc<DeviceReading> {
+        Arc::new(DeviceReading {
+            device_id: DeviceId {
+                id: id.to_string(),
+                device_name: id.to_string(),
+                address: String::new(),
+                random_address: None,
+            },
+            measurement,
+            advertisement: None,
+            quality: Vec::new(),
+        })
+    }
+
+    #[test]
+    fn test_latest_readings() {
+        let mut latest = LatestReadings::default();
+        latest.update(reading("b", Measurement::Temperature(20.0)));
+        latest.update(reading("a", Measurement::Temperature(19.0)));
+        latest.update(reading("b", Measurement::Humidity(40.0)));
+        latest.update(reading("b", Measurement::Temperature(21.0)));
+
+        let readings: Vec<_> = latest
+            .readings()
+            .iter()
+            .map(|l| {
+                (
+                    l.reading.device_id.id.clone(),
+                    l.reading.measurement.value(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            readings,
+            vec![
+                ("a".to_string(), 19.0),
+                ("b".to_string(), 40.0),
+                ("b".to_string(), 21.0)
+            ]
+        );
+        assert_eq!(latest.filter(|r| r.device_id.id == "a").readings().len(), 1);
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let path = std::env::temp_dir().join(format!("blueplug-state-{}.json", std::process::id()));
+        let mut latest = LatestReadings::default();
+        let mut saved = reading("a", Measurement::Temperature(19.5));
+        Arc::get_mut(&mut saved).unwrap().advertisement = Some(7);
+        latest.update(saved);
+        latest.save(&path).unwrap();
+
+        let loaded = LatestReadings::load(&path, &[]).unwrap().readings();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert!(loaded[0].is_stale);
+        assert_eq!(loaded[0].timestamp, latest.readings()[0].timestamp);
+        assert_eq!(loaded[0].reading.measurement.value(), 19.5);
+        assert_eq!(loaded[0].reading.quality, vec![Quality::FromStaleCache]);
+        assert_eq!(loaded[0].reading.advertisement, None);
+
+        assert!(LatestReadings::load(&path, &[])
+            .unwrap()
+            .readings()
+            .is_empty());
+    }
+}
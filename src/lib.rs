@@ -0,0 +1,1115 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use async_stream::{stream, try_stream};
+use btleplug::api::bleuuid::BleUuid;
+use btleplug::api::{AddressType, Central, CentralEvent, Peripheral, ScanFilter};
+use btleplug::platform::Manager;
+use btsensor::bthome::v2::Element;
+use btsensor::Reading;
+use color_eyre::eyre::Result;
+use futures_core::stream::Stream;
+use futures_util::stream::StreamExt;
+use ruuvi_sensor_protocol::{BatteryPotential, Humidity, SensorValues, Temperature};
+use serde::{Deserialize, Serialize};
+use tokio::time;
+use uuid::Uuid;
+
+use crate::adapter::select_adapter;
+use crate::diagnostics::{diagnose_manufacturer_data, diagnose_service_data, DIAGNOSTICS};
+use crate::keys::KeyStore;
+use crate::metrics::{Stage, METRICS};
+use crate::plugin::PluginHost;
+use crate::preflight::explain;
+use crate::scale::MiScales;
+
+mod actions;
+mod adapter;
+mod airthings;
+mod api;
+mod archive;
+mod batch;
+mod battery;
+#[cfg(feature = "bench")]
+pub mod bench;
+mod bm2;
+mod bthome;
+mod chatter;
+pub mod cli;
+mod clock;
+mod compression;
+pub mod config;
+mod dedup;
+mod derived;
+mod diagnostics;
+mod doctor;
+mod encoding;
+mod envelope;
+mod error;
+mod esphome;
+mod events;
+mod exec;
+mod exempt;
+mod export;
+mod fanout;
+mod fitness;
+mod forward;
+#[cfg(feature = "fuzzing")]
+pub mod fuzzing;
+mod gatt;
+mod generate;
+mod grafana;
+mod grpc;
+mod health;
+mod history;
+mod homeassistant;
+mod http;
+mod import;
+mod ingest;
+mod keys;
+mod knx;
+mod latest;
+mod mapping;
+mod mdns;
+mod metrics;
+mod mibeacon;
+mod modbus;
+mod motion;
+mod mqtt;
+mod names;
+mod outbox;
+mod pair;
+mod pcap;
+mod pipeline;
+mod plugin;
+mod postgres;
+mod preflight;
+mod presence;
+mod protobuf;
+mod query;
+mod ratelimit;
+mod renogy;
+mod rpa;
+mod rules;
+mod ruuvi;
+mod scale;
+mod scope;
+mod script;
+mod secret;
+mod service;
+mod simulate;
+mod snmp;
+#[cfg(unix)]
+mod socket;
+mod stats;
+mod supervisor;
+mod theengs;
+mod watchdog;
+mod zabbix;
+
+pub use crate::adapter::AdapterSelector;
+pub use crate::error::{BlueplugError, Cause, Failure};
+pub use crate::metrics::Counters;
+pub use crate::pipeline::{Blueplug, BlueplugBuilder, BlueplugHandle};
+pub use crate::plugin::Plugin;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub struct DeviceId {
+    id: String,
+    device_name: String,
+    // The BLE address, which unlike the platform id is the same across adapters.
+    #[serde(skip)]
+    address: String,
+    // Whether the address is random rather than public, where the platform says.
+    #[serde(skip)]
+    random_address: Option<bool>,
+}
+
+// DeviceEvent is an advertisement of a device. Devices that haven't advertised a name yet have an
+// empty device name, and only presence looks at them. The RSSI is in dBm, if the source reports it.
+pub enum DeviceEvent {
+    ManufacturerDataAdvertisement {
+        device_id: DeviceId,
+        manufacturer_data: HashMap<u16, Vec<u8>>,
+        rssi: Option<i16>,
+    },
+
+    ServiceDataAdvertisement {
+        device_id: DeviceId,
+        service_data: HashMap<Uuid, Vec<u8>>,
+        rssi: Option<i16>,
+    },
+}
+
+impl DeviceId {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn device_name(&self) -> &str {
+        &self.device_name
+    }
+
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+}
+
+// The most advertising data a legacy advertisement carries; longer advertisements are sent with
+// Bluetooth 5 extended advertising.
+pub const LEGACY_ADVERTISING_DATA_LEN: usize = 31;
+
+impl DeviceEvent {
+    pub fn device_id(&self) -> &DeviceId {
+        match self {
+            DeviceEvent::ManufacturerDataAdvertisement { device_id, .. } => device_id,
+            DeviceEvent::ServiceDataAdvertisement { device_id, .. } => device_id,
+        }
+    }
+
+    pub fn rssi(&self) -> Option<i16> {
+        match self {
+            DeviceEvent::ManufacturerDataAdvertisement { rssi, .. } => *rssi,
+            DeviceEvent::ServiceDataAdvertisement { rssi, .. } => *rssi,
+        }
+    }
+
+    // advertising_data_len is how many bytes of advertising data the event's manufacturer or
+    // service data took, as AD structures with the shortest UUIDs that fit. Platforms hand over
+    // the name and the other AD structures separately, if at all, so the advertisement may have
+    // been longer.
+    pub fn advertising_data_len(&self) -> usize {
+        match self {
+            DeviceEvent::ManufacturerDataAdvertisement {
+                manufacturer_data, ..
+            } => manufacturer_data.values().map(|data| 4 + data.len()).sum(),
+            DeviceEvent::ServiceDataAdvertisement { service_data, .. } => service_data
+                .iter()
+                .map(|(uuid, data)| {
+                    let uuid_len = if uuid.to_ble_u16().is_some() {
+                        2
+                    } else if uuid.to_ble_u32().is_some() {
+                        4
+                    } else {
+                        16
+                    };
+                    2 + uuid_len + data.len()
+                })
+                .sum(),
+        }
+    }
+
+    // is_extended reports whether the advertisement must have been sent with extended
+    // advertising, its data being too long for a legacy one. No platform blueplug supports
+    // reports the advertising type or PHY, so shorter extended advertisements aren't told apart.
+    pub fn is_extended(&self) -> bool {
+        self.advertising_data_len() > LEGACY_ADVERTISING_DATA_LEN
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "value", rename_all = "lowercase")]
+pub enum Measurement {
+    Humidity(f64),
+    Temperature(f64),
+    Battery(f64),
+    Voltage(f64),
+    // Beats per minute.
+    #[serde(rename = "heart_rate")]
+    HeartRate(f64),
+    // Watts.
+    Power(f64),
+    // Kilowatt hours used since the meter was last reset.
+    Energy(f64),
+    // Amperes.
+    Current(f64),
+    // Volts of mains power, as metered by smart plugs, unlike the DC voltage of batteries.
+    #[serde(rename = "voltage_ac")]
+    VoltageAc(f64),
+    // 1 while vibration is detected, 0 otherwise.
+    Vibration(f64),
+    // Degrees from level.
+    Tilt(f64),
+    // Degrees, e.g. of a valve or knob.
+    Rotation(f64),
+    // Metres per second.
+    #[serde(rename = "wind_speed")]
+    WindSpeed(f64),
+    // Degrees clockwise from north the wind blows from.
+    #[serde(rename = "wind_direction")]
+    WindDirection(f64),
+    // Millimetres.
+    Rainfall(f64),
+    #[serde(rename = "uv_index")]
+    UvIndex(f64),
+    // 1 while gas is detected, 0 otherwise. Like smoke and carbon monoxide, an alert.
+    #[serde(rename = "gas_detected")]
+    GasDetected(f64),
+    // 1 while smoke is detected, 0 otherwise.
+    #[serde(rename = "smoke_detected")]
+    SmokeDetected(f64),
+    // 1 while carbon monoxide is detected, 0 otherwise.
+    #[serde(rename = "carbon_monoxide_detected")]
+    CarbonMonoxideDetected(f64),
+    // Parts per million of carbon monoxide.
+    #[serde(rename = "carbon_monoxide")]
+    CarbonMonoxide(f64),
+    // 1 while a water leak sensor is wet, 0 otherwise.
+    Leak(f64),
+    // Any other kind, as emitted by plugins or renamed by a mapping.
+    #[serde(untagged)]
+    Other {
+        kind: String,
+        value: f64,
+        #[serde(default, skip_serializing_if = "String::is_empty")]
+        unit: String,
+    },
+}
+
+impl Display for Measurement {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Measurement::Humidity(v) => f.write_fmt(format_args!("humidity {}%", v)),
+            Measurement::Temperature(v) => f.write_fmt(format_args!("temperature {}°C", v)),
+            Measurement::Battery(v) => f.write_fmt(format_args!("battery {}%", v)),
+            Measurement::Voltage(v) => f.write_fmt(format_args!("voltage {}V", v)),
+            Measurement::HeartRate(v) => f.write_fmt(format_args!("heart rate {}bpm", v)),
+            Measurement::Power(v) => f.write_fmt(format_args!("power {}W", v)),
+            Measurement::Energy(v) => f.write_fmt(format_args!("energy {}kWh", v)),
+            Measurement::Current(v) => f.write_fmt(format_args!("current {}A", v)),
+            Measurement::VoltageAc(v) => f.write_fmt(format_args!("voltage {}V AC", v)),
+            Measurement::Vibration(v) if *v > 0.0 => f.write_str("vibration detected"),
+            Measurement::Vibration(_) => f.write_str("no vibration"),
+            Measurement::Tilt(v) => f.write_fmt(format_args!("tilt {}°", v)),
+            Measurement::Rotation(v) => f.write_fmt(format_args!("rotation {}°", v)),
+            Measurement::WindSpeed(v) => f.write_fmt(format_args!("wind speed {}m/s", v)),
+            Measurement::WindDirection(v) => f.write_fmt(format_args!("wind direction {}°", v)),
+            Measurement::Rainfall(v) => f.write_fmt(format_args!("rainfall {}mm", v)),
+            Measurement::UvIndex(v) => f.write_fmt(format_args!("UV index {}", v)),
+            Measurement::GasDetected(v) if *v > 0.0 => f.write_str("gas detected"),
+            Measurement::GasDetected(_) => f.write_str("no gas"),
+            Measurement::SmokeDetected(v) if *v > 0.0 => f.write_str("smoke detected"),
+            Measurement::SmokeDetected(_) => f.write_str("no smoke"),
+            Measurement::CarbonMonoxideDetected(v) if *v > 0.0 => {
+                f.write_str("carbon monoxide detected")
+            }
+            Measurement::CarbonMonoxideDetected(_) => f.write_str("no carbon monoxide"),
+            Measurement::CarbonMonoxide(v) => f.write_fmt(format_args!("carbon monoxide {}ppm", v)),
+            Measurement::Leak(v) if *v > 0.0 => f.write_str("leak detected"),
+            Measurement::Leak(_) => f.write_str("no leak"),
+            Measurement::Other { kind, value, unit } => {
+                f.write_fmt(format_args!("{} {}{}", kind, value, unit))
+            }
+        }
+    }
+}
+
+impl Measurement {
+    // new is the built-in kind if kind is one and unit is its unit, and otherwise Other.
+    pub fn new(kind: String, value: f64, unit: String) -> Self {
+        let built_in = match kind.as_str() {
+            "humidity" => Some(Measurement::Humidity(value)),
+            "temperature" => Some(Measurement::Temperature(value)),
+            "battery" => Some(Measurement::Battery(value)),
+            "voltage" => Some(Measurement::Voltage(value)),
+            "heart_rate" => Some(Measurement::HeartRate(value)),
+            "power" => Some(Measurement::Power(value)),
+            "energy" => Some(Measurement::Energy(value)),
+            "current" => Some(Measurement::Current(value)),
+            "voltage_ac" => Some(Measurement::VoltageAc(value)),
+            "vibration" => Some(Measurement::Vibration(value)),
+            "tilt" => Some(Measurement::Tilt(value)),
+            "rotation" => Some(Measurement::Rotation(value)),
+            "wind_speed" => Some(Measurement::WindSpeed(value)),
+            "wind_direction" => Some(Measurement::WindDirection(value)),
+            "rainfall" => Some(Measurement::Rainfall(value)),
+            "uv_index" => Some(Measurement::UvIndex(value)),
+            "gas_detected" => Some(Measurement::GasDetected(value)),
+            "smoke_detected" => Some(Measurement::SmokeDetected(value)),
+            "carbon_monoxide_detected" => Some(Measurement::CarbonMonoxideDetected(value)),
+            "carbon_monoxide" => Some(Measurement::CarbonMonoxide(value)),
+            "leak" => Some(Measurement::Leak(value)),
+            _ => None,
+        };
+        match built_in {
+            Some(measurement) if measurement.unit() == unit => measurement,
+            _ => Measurement::Other { kind, value, unit },
+        }
+    }
+
+    pub fn kind(&self) -> &str {
+        match self {
+            Measurement::Humidity(_) => "humidity",
+            Measurement::Temperature(_) => "temperature",
+            Measurement::Battery(_) => "battery",
+            Measurement::Voltage(_) => "voltage",
+            Measurement::HeartRate(_) => "heart_rate",
+            Measurement::Power(_) => "power",
+            Measurement::Energy(_) => "energy",
+            Measurement::Current(_) => "current",
+            Measurement::VoltageAc(_) => "voltage_ac",
+            Measurement::Vibration(_) => "vibration",
+            Measurement::Tilt(_) => "tilt",
+            Measurement::Rotation(_) => "rotation",
+            Measurement::WindSpeed(_) => "wind_speed",
+            Measurement::WindDirection(_) => "wind_direction",
+            Measurement::Rainfall(_) => "rainfall",
+            Measurement::UvIndex(_) => "uv_index",
+            Measurement::GasDetected(_) => "gas_detected",
+            Measurement::SmokeDetected(_) => "smoke_detected",
+            Measurement::CarbonMonoxideDetected(_) => "carbon_monoxide_detected",
+            Measurement::CarbonMonoxide(_) => "carbon_monoxide",
+            Measurement::Leak(_) => "leak",
+            Measurement::Other { kind, .. } => kind,
+        }
+    }
+
+    pub fn unit(&self) -> &str {
+        match self {
+            Measurement::Humidity(_) => "%",
+            Measurement::Temperature(_) => "°C",
+            Measurement::Battery(_) => "%",
+            Measurement::Voltage(_) => "V",
+            Measurement::HeartRate(_) => "bpm",
+            Measurement::Power(_) => "W",
+            Measurement::Energy(_) => "kWh",
+            Measurement::Current(_) => "A",
+            Measurement::VoltageAc(_) => "V",
+            Measurement::Vibration(_) => "",
+            Measurement::Tilt(_) => "°",
+            Measurement::Rotation(_) => "°",
+            Measurement::WindSpeed(_) => "m/s",
+            Measurement::WindDirection(_) => "°",
+            Measurement::Rainfall(_) => "mm",
+            Measurement::UvIndex(_) => "",
+            Measurement::GasDetected(_) => "",
+            Measurement::SmokeDetected(_) => "",
+            Measurement::CarbonMonoxideDetected(_) => "",
+            Measurement::CarbonMonoxide(_) => "ppm",
+            Measurement::Leak(_) => "",
+            Measurement::Other { unit, .. } => unit,
+        }
+    }
+
+    pub fn value(&self) -> f64 {
+        match self {
+            Measurement::Humidity(v) => *v,
+            Measurement::Temperature(v) => *v,
+            Measurement::Battery(v) => *v,
+            Measurement::Voltage(v) => *v,
+            Measurement::HeartRate(v) => *v,
+            Measurement::Power(v) => *v,
+            Measurement::Energy(v) => *v,
+            Measurement::Current(v) => *v,
+            Measurement::VoltageAc(v) => *v,
+            Measurement::Vibration(v) => *v,
+            Measurement::Tilt(v) => *v,
+            Measurement::Rotation(v) => *v,
+            Measurement::WindSpeed(v) => *v,
+            Measurement::WindDirection(v) => *v,
+            Measurement::Rainfall(v) => *v,
+            Measurement::UvIndex(v) => *v,
+            Measurement::GasDetected(v) => *v,
+            Measurement::SmokeDetected(v) => *v,
+            Measurement::CarbonMonoxideDetected(v) => *v,
+            Measurement::CarbonMonoxide(v) => *v,
+            Measurement::Leak(v) => *v,
+            Measurement::Other { value, .. } => *value,
+        }
+    }
+
+    // is_alert tells whether the reading warns of danger, so must reach the broker without delay.
+    pub fn is_alert(&self) -> bool {
+        matches!(
+            self,
+            Measurement::GasDetected(_)
+                | Measurement::SmokeDetected(_)
+                | Measurement::CarbonMonoxideDetected(_)
+                | Measurement::CarbonMonoxide(_)
+                | Measurement::Leak(_)
+        )
+    }
+
+    // is_sparse tells whether the reading comes from sensors that only advertise when it changes,
+    // so the last one stays the state for however long that takes. Such readings are always
+    // retained, so consumers that subscribe later still learn the state.
+    pub fn is_sparse(&self) -> bool {
+        matches!(self, Measurement::Leak(_))
+    }
+}
+
+// Kind names a kind of measurement, for asking for readings of one kind. There is one for every
+// built-in Measurement; Other covers the kinds decoded by plugins, mapped or derived, by name.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Kind {
+    Humidity,
+    Temperature,
+    Battery,
+    Voltage,
+    HeartRate,
+    Power,
+    Energy,
+    Current,
+    VoltageAc,
+    Vibration,
+    Tilt,
+    Rotation,
+    WindSpeed,
+    WindDirection,
+    Rainfall,
+    UvIndex,
+    GasDetected,
+    SmokeDetected,
+    CarbonMonoxideDetected,
+    CarbonMonoxide,
+    Leak,
+    Other(String),
+}
+
+impl Kind {
+    pub fn name(&self) -> &str {
+        match self {
+            Kind::Humidity => "humidity",
+            Kind::Temperature => "temperature",
+            Kind::Battery => "battery",
+            Kind::Voltage => "voltage",
+            Kind::HeartRate => "heart_rate",
+            Kind::Power => "power",
+            Kind::Energy => "energy",
+            Kind::Current => "current",
+            Kind::VoltageAc => "voltage_ac",
+            Kind::Vibration => "vibration",
+            Kind::Tilt => "tilt",
+            Kind::Rotation => "rotation",
+            Kind::WindSpeed => "wind_speed",
+            Kind::WindDirection => "wind_direction",
+            Kind::Rainfall => "rainfall",
+            Kind::UvIndex => "uv_index",
+            Kind::GasDetected => "gas_detected",
+            Kind::SmokeDetected => "smoke_detected",
+            Kind::CarbonMonoxideDetected => "carbon_monoxide_detected",
+            Kind::CarbonMonoxide => "carbon_monoxide",
+            Kind::Leak => "leak",
+            Kind::Other(kind) => kind,
+        }
+    }
+}
+
+impl From<&Measurement> for Kind {
+    fn from(measurement: &Measurement) -> Self {
+        match measurement {
+            Measurement::Humidity(_) => Kind::Humidity,
+            Measurement::Temperature(_) => Kind::Temperature,
+            Measurement::Battery(_) => Kind::Battery,
+            Measurement::Voltage(_) => Kind::Voltage,
+            Measurement::HeartRate(_) => Kind::HeartRate,
+            Measurement::Power(_) => Kind::Power,
+            Measurement::Energy(_) => Kind::Energy,
+            Measurement::Current(_) => Kind::Current,
+            Measurement::VoltageAc(_) => Kind::VoltageAc,
+            Measurement::Vibration(_) => Kind::Vibration,
+            Measurement::Tilt(_) => Kind::Tilt,
+            Measurement::Rotation(_) => Kind::Rotation,
+            Measurement::WindSpeed(_) => Kind::WindSpeed,
+            Measurement::WindDirection(_) => Kind::WindDirection,
+            Measurement::Rainfall(_) => Kind::Rainfall,
+            Measurement::UvIndex(_) => Kind::UvIndex,
+            Measurement::GasDetected(_) => Kind::GasDetected,
+            Measurement::SmokeDetected(_) => Kind::SmokeDetected,
+            Measurement::CarbonMonoxideDetected(_) => Kind::CarbonMonoxideDetected,
+            Measurement::CarbonMonoxide(_) => Kind::CarbonMonoxide,
+            Measurement::Leak(_) => Kind::Leak,
+            Measurement::Other { kind, .. } => Kind::Other(kind.clone()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub struct DeviceReading {
+    #[serde(flatten)]
+    device_id: DeviceId,
+    #[serde(flatten)]
+    measurement: Measurement,
+    // The advertisement, or GATT notification, the reading was decoded from. Readings decoded
+    // from the same one share it, so consumers can tell which values were measured together.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    advertisement: Option<u64>,
+    // Why consumers might not take the value at face value, see Quality.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    quality: Vec<Quality>,
+}
+
+// Quality flags a reading whose value or timestamp is less trustworthy than a fresh measurement,
+// so consumers can decide how much to rely on it. Envelopes carry the flags as meta.quality.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Quality {
+    // Projected beyond what was measured, such as the days a battery has left.
+    Extrapolated,
+    // Restored from the state file after a restart, the device not heard from since.
+    FromStaleCache,
+    // Stamped before the wall clock jumped, and re-stamped from the monotonic clock.
+    AfterClockJump,
+}
+
+// next_advertisement numbers advertisements and notifications as they are decoded.
+pub fn next_advertisement() -> u64 {
+    static NEXT: AtomicU64 = AtomicU64::new(1);
+    NEXT.fetch_add(1, Ordering::Relaxed)
+}
+
+impl DeviceReading {
+    pub fn device_id(&self) -> &DeviceId {
+        &self.device_id
+    }
+
+    pub fn measurement(&self) -> &Measurement {
+        &self.measurement
+    }
+
+    pub fn advertisement(&self) -> Option<u64> {
+        self.advertisement
+    }
+
+    pub fn quality(&self) -> &[Quality] {
+        &self.quality
+    }
+}
+
+impl Display for DeviceReading {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("{:?} -> {}", self.device_id, self.measurement))
+    }
+}
+
+// How long the adapter may go without delivering an event, after it has been, before scanning is
+// restarted.
+pub const DEFAULT_SCAN_STALL_TIMEOUT: Duration = Duration::from_secs(300);
+
+// bt_stream builds a stream of DeviceEvents, which are CentralEvents of interest augmented with
+// device names rather than IDs, and the RSSI. On macOS and Windows the platform's event stream can
+// stop delivering events without an error, so if none arrive for stall_timeout after some had,
+// scanning is restarted. It isn't restarted again until events arrive, so a quiet room doesn't
+// keep restarting it.
+pub fn bt_stream(
+    adapter: Option<AdapterSelector>,
+    stall_timeout: Option<Duration>,
+) -> impl Stream<Item = Result<DeviceEvent, BlueplugError>> {
+    scan(adapter, stall_timeout).map(|event| event.map_err(|e| BlueplugError::Adapter(e.into())))
+}
+
+fn scan(
+    adapter: Option<AdapterSelector>,
+    stall_timeout: Option<Duration>,
+) -> impl Stream<Item = Result<DeviceEvent>> {
+    try_stream! {
+        let manager = Manager::new().await.map_err(explain)?;
+        let central = select_adapter(&manager, adapter.as_ref()).await?;
+        let mut events = central.events().await.map_err(explain)?;
+        let mut devices = HashMap::<String, (DeviceId, Option<i16>)>::new();
+        central.start_scan(ScanFilter::default()).await.map_err(explain)?;
+        let mut active = false;
+
+        loop {
+            let event = match stall_timeout.filter(|_| active) {
+                Some(timeout) => match time::timeout(timeout, events.next()).await {
+                    Ok(event) => event,
+                    Err(_) => {
+                        println!("no Bluetooth events for {:?}, restarting the scan", timeout);
+                        if let Err(e) = central.stop_scan().await {
+                            println!("stopping the scan failed: {:?}", e);
+                        }
+                        events = central.events().await.map_err(explain)?;
+                        central.start_scan(ScanFilter::default()).await.map_err(explain)?;
+                        active = false;
+                        continue;
+                    }
+                },
+                None => events.next().await,
+            };
+            let Some(event) = event else {
+                break;
+            };
+            active = true;
+            match event {
+                CentralEvent::DeviceDiscovered(id) => {
+                    let peripheral = central.peripheral(&id).await?;
+                    let id = id.to_string();
+                    if let Some(prop) = peripheral.properties().await? {
+                        let device_name = prop.local_name.unwrap_or_default();
+                        let address = prop.address.to_string();
+                        let random_address = prop.address_type.map(|t| t == AddressType::Random);
+                        let device_id = DeviceId {
+                            id: id.clone(),
+                            device_name,
+                            address,
+                            random_address,
+                        };
+                        devices.insert(id, (device_id, prop.rssi));
+                    }
+                }
+                // Updates bring the RSSI of the latest advertisement, and the name of devices that
+                // only send it in scan responses. The device may be gone by then.
+                CentralEvent::DeviceUpdated(id) => {
+                    let Ok(peripheral) = central.peripheral(&id).await else {
+                        continue;
+                    };
+                    if let (Some((device_id, rssi)), Ok(Some(prop))) =
+                        (devices.get_mut(&id.to_string()), peripheral.properties().await)
+                    {
+                        if let Some(device_name) = prop.local_name {
+                            device_id.device_name = device_name;
+                        }
+                        *rssi = prop.rssi.or(*rssi);
+                    }
+                }
+                 CentralEvent::ServiceDataAdvertisement { id, service_data } => {
+                    let id = id.to_string();
+                     if let Some((device_id, rssi)) = devices.get(&id) {
+                        let (device_id, rssi) = (device_id.clone(), *rssi);
+                        yield DeviceEvent::ServiceDataAdvertisement {device_id, service_data, rssi };
+                    }
+                }
+                CentralEvent::ManufacturerDataAdvertisement { id, manufacturer_data } => {
+                    let id = id.to_string();
+                     if let Some((device_id, rssi)) = devices.get(&id) {
+                        let (device_id, rssi) = (device_id.clone(), *rssi);
+                        yield DeviceEvent::ManufacturerDataAdvertisement {device_id, manufacturer_data, rssi };
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+pub fn device_reading_stream(
+    event_stream: impl Stream<Item = Result<DeviceEvent, BlueplugError>>,
+    mut plugins: PluginHost,
+    keys: KeyStore,
+) -> impl Stream<Item = DeviceReading> {
+    stream! {
+        let mut scales = MiScales::default();
+        for await event in event_stream {
+            match event {
+                Ok(DeviceEvent::ServiceDataAdvertisement { device_id, service_data, .. }) => {
+                    let service_data = keys.decrypt(device_id.address(), &service_data);
+                    let payload = || {
+                        service_data
+                            .iter()
+                            .map(|(uuid, data)| format!("{}: {}", uuid, hex(data)))
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    };
+                    let measurements = decode(&device_id, payload, || {
+                        let mut measurements = plugins.measurements_from_service_data(&service_data);
+                        measurements.extend(measurements_from_service_data(&service_data));
+                        measurements.extend(scales.measurements(&device_id, &service_data));
+                        measurements
+                    });
+                    if !measurements.is_empty() {
+                        METRICS.reading(Stage::Decoded, &device_id);
+                    } else {
+                        for diagnostic in diagnose_service_data(&device_id, &service_data) {
+                            DIAGNOSTICS.report(diagnostic);
+                        }
+                    }
+                    let advertisement = Some(next_advertisement());
+                    for measurement in measurements {
+                        let device_id = device_id.clone();
+                        yield DeviceReading{device_id, measurement, advertisement, quality: Vec::new()}
+                    }
+                }
+                Ok(DeviceEvent::ManufacturerDataAdvertisement { device_id, manufacturer_data, .. }) => {
+                    let payload = || {
+                        manufacturer_data
+                            .iter()
+                            .map(|(id, data)| format!("{:#06x}: {}", id, hex(data)))
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    };
+                    let measurements = decode(&device_id, payload, || {
+                        let mut measurements = plugins.measurements_from_manufacturer_data(&manufacturer_data);
+                        measurements.extend(measurements_from_manufacturer_data(&manufacturer_data));
+                        measurements
+                    });
+                    if !measurements.is_empty() {
+                        METRICS.reading(Stage::Decoded, &device_id);
+                    } else {
+                        for diagnostic in diagnose_manufacturer_data(&device_id, &manufacturer_data) {
+                            DIAGNOSTICS.report(diagnostic);
+                        }
+                    }
+                    let advertisement = Some(next_advertisement());
+                    for measurement in measurements {
+                        let device_id = device_id.clone();
+                        yield DeviceReading{device_id, measurement, advertisement, quality: Vec::new()}
+                    }
+                }
+                Err(e) => {
+                    println!("received error! {:?}", e.to_string())
+                }
+            }
+        }
+    }
+}
+
+// decode runs the decoders of one advertisement, catching a panic so a malformed payload only
+// loses that advertisement rather than taking down the bridge. The payload is logged in hex, to
+// help fix the decoder.
+fn decode(
+    device_id: &DeviceId,
+    payload: impl Fn() -> String,
+    decoders: impl FnOnce() -> Vec<Measurement>,
+) -> Vec<Measurement> {
+    match panic::catch_unwind(AssertUnwindSafe(decoders)) {
+        Ok(measurements) => measurements,
+        Err(_) => {
+            METRICS.reading(Stage::Panicked, device_id);
+            println!(
+                "{}: decoding panicked on payload {}",
+                device_id.device_name,
+                payload()
+            );
+            Vec::new()
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// builtin_measurements decodes an advertisement with the built-in decoders only, for a look at
+// it ahead of decoding proper. Panics are caught quietly; decoding reports them later.
+pub(crate) fn builtin_measurements(event: &DeviceEvent) -> Vec<Measurement> {
+    let decoded = panic::catch_unwind(AssertUnwindSafe(|| match event {
+        DeviceEvent::ServiceDataAdvertisement { service_data, .. } => {
+            measurements_from_service_data(service_data)
+        }
+        DeviceEvent::ManufacturerDataAdvertisement {
+            manufacturer_data, ..
+        } => measurements_from_manufacturer_data(manufacturer_data),
+    }));
+    decoded.unwrap_or_default()
+}
+
+fn measurements_from_manufacturer_data(
+    manufacturer_data: &HashMap<u16, Vec<u8>>,
+) -> Vec<Measurement> {
+    manufacturer_data
+        .iter()
+        .flat_map(|(id, data)| {
+            let mut measurements: Vec<Measurement> = vec![];
+            if let Ok(parsed) = SensorValues::from_manufacturer_specific_data(*id, data) {
+                if let Some(humidity) = parsed.humidity_as_ppm() {
+                    measurements.push(Measurement::Humidity(humidity as f64 / 10000.0));
+                }
+
+                if let Some(temp) = parsed.temperature_as_millicelsius() {
+                    measurements.push(Measurement::Temperature(temp as f64 / 1000.0));
+                }
+
+                if let Some(batt) = parsed.battery_potential_as_millivolts() {
+                    measurements.push(Measurement::Voltage(batt as f64 / 1000.0));
+                }
+            }
+            measurements
+        })
+        .collect()
+}
+
+fn measurements_from_service_data(service_data: &HashMap<Uuid, Vec<u8>>) -> Vec<Measurement> {
+    let mut measurements = measurements_from_known_service_data(service_data);
+    // Xiaomi devices may advertise MiBeacon next to another format, e.g. with custom firmware, so
+    // its objects only add the kinds the other didn't decode.
+    if let Some(data) = service_data.get(&mibeacon::UUID) {
+        for measurement in mibeacon::measurements(data) {
+            if !measurements.iter().any(|m| m.kind() == measurement.kind()) {
+                measurements.push(measurement);
+            }
+        }
+    }
+    measurements
+}
+
+fn measurements_from_known_service_data(service_data: &HashMap<Uuid, Vec<u8>>) -> Vec<Measurement> {
+    // BtHomeV2::decode panics on an empty payload.
+    if service_data
+        .get(&btsensor::bthome::v2::UUID)
+        .is_some_and(Vec::is_empty)
+    {
+        return Vec::new();
+    }
+    if let Some(data) = service_data.get(&ruuvi::EDDYSTONE_UUID) {
+        return ruuvi::url_measurements(data);
+    }
+    // Weather stations send objects too new for btsensor, which are decoded here instead.
+    let mut newer = Vec::new();
+    let known;
+    let mut service_data = service_data;
+    if let Some((data, measurements)) = service_data
+        .get(&btsensor::bthome::v2::UUID)
+        .and_then(|data| bthome::split_newer_objects(data))
+    {
+        let mut replaced = service_data.clone();
+        replaced.insert(btsensor::bthome::v2::UUID, data);
+        known = replaced;
+        service_data = &known;
+        newer = measurements;
+    }
+    if let Some(decoded) = Reading::decode(service_data) {
+        match decoded {
+            Reading::BtHomeV2(v2) => {
+                let mut measurements: Vec<Measurement> = v2
+                    .elements
+                    .iter()
+                    .filter_map(|e| match e.name() {
+                        // Of BTHome's two voltages, smart plugs send mains in the coarser one, to
+                        // a tenth of a volt, and batteries use the one in millivolts.
+                        "voltage" if matches!(e, Element::Voltage(_)) => {
+                            Some(Measurement::VoltageAc(e.value_float().unwrap_or(0f64)))
+                        }
+                        "voltage" => Some(Measurement::Voltage(e.value_float().unwrap_or(0f64))),
+                        "power" => Some(Measurement::Power(e.value_float().unwrap_or(0f64))),
+                        "energy" => Some(Measurement::Energy(e.value_float().unwrap_or(0f64))),
+                        "current" => Some(Measurement::Current(e.value_float().unwrap_or(0f64))),
+                        "humidity" => Some(Measurement::Humidity(e.value_float().unwrap_or(0f64))),
+                        "temperature" => {
+                            Some(Measurement::Temperature(e.value_float().unwrap_or(0f64)))
+                        }
+                        "battery" => {
+                            Some(Measurement::Battery(e.value_int().unwrap_or(0i64) as f64))
+                        }
+                        "rotation" => Some(Measurement::Rotation(e.value_float().unwrap_or(0f64))),
+                        // BTHome's only speed is what weather stations send wind speed in.
+                        "speed" => Some(Measurement::WindSpeed(e.value_float().unwrap_or(0f64))),
+                        "UV index" => Some(Measurement::UvIndex(e.value_float().unwrap_or(0f64))),
+                        "vibration detected" => Some(Measurement::Vibration(
+                            e.value_bool().map_or(0.0, |detected| detected as u8 as f64),
+                        )),
+                        "gas detected" => Some(Measurement::GasDetected(
+                            e.value_bool().map_or(0.0, |detected| detected as u8 as f64),
+                        )),
+                        "smoke detected" => Some(Measurement::SmokeDetected(
+                            e.value_bool().map_or(0.0, |detected| detected as u8 as f64),
+                        )),
+                        "carbon monoxide detected" => Some(Measurement::CarbonMonoxideDetected(
+                            e.value_bool().map_or(0.0, |detected| detected as u8 as f64),
+                        )),
+                        // Leak sensors running BTHome firmware report whether they are wet.
+                        "wet" => Some(Measurement::Leak(
+                            e.value_bool().map_or(0.0, |wet| wet as u8 as f64),
+                        )),
+                        &_ => None,
+                    })
+                    .collect();
+                measurements.extend(events::measurements(&v2.elements));
+                measurements.extend(newer);
+                return measurements;
+            }
+
+            Reading::Atc(_) => {}
+            Reading::BtHomeV1(_) => {}
+        }
+    }
+    Vec::new()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use btleplug::api::bleuuid::uuid_from_u16;
+    use proptest::prelude::*;
+
+    use crate::metrics::METRICS;
+    use crate::scale::MiScales;
+    use crate::{
+        decode, measurements_from_manufacturer_data, measurements_from_service_data, DeviceId,
+        Kind, Measurement,
+    };
+
+    // The advertisements of fixtures/advertisements, by file.
+    const FIXTURES: &[(&str, &str)] = &[
+        (
+            "ruuvi.hex",
+            include_str!("../fixtures/advertisements/ruuvi.hex"),
+        ),
+        (
+            "bthome.hex",
+            include_str!("../fixtures/advertisements/bthome.hex"),
+        ),
+        (
+            "atc.hex",
+            include_str!("../fixtures/advertisements/atc.hex"),
+        ),
+        (
+            "mi_scale.hex",
+            include_str!("../fixtures/advertisements/mi_scale.hex"),
+        ),
+        (
+            "mibeacon.hex",
+            include_str!("../fixtures/advertisements/mibeacon.hex"),
+        ),
+    ];
+
+    // decode_advertisement runs the built-in decoders on manufacturer data of company ids, or
+    // service data of 16 bit UUIDs, as device_reading_stream does.
+    fn decode_advertisement(source: &str, ids: &[u16], data: Vec<Vec<u8>>) -> Vec<Measurement> {
+        if source == "manufacturer_data" {
+            return measurements_from_manufacturer_data(&ids.iter().copied().zip(data).collect());
+        }
+        let device_id = DeviceId {
+            id: "hci0/dev_C8_47_8C_10_22_33".to_string(),
+            device_name: "ATC_FIXTURE".to_string(),
+            address: "C8:47:8C:10:22:33".to_string(),
+            random_address: None,
+        };
+        let service_data = ids.iter().map(|id| uuid_from_u16(*id)).zip(data).collect();
+        let mut measurements = measurements_from_service_data(&service_data);
+        measurements.extend(MiScales::default().measurements(&device_id, &service_data));
+        measurements
+    }
+
+    #[test]
+    fn test_fixtures() {
+        for (file, fixtures) in FIXTURES {
+            let mut source = None;
+            for (number, line) in fixtures.lines().enumerate() {
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                let (payload, expected) = line.split_once(' ').unwrap_or((line, ""));
+                // A source may name several ids, whose payloads are then joined with +.
+                if payload == "manufacturer_data" || payload == "service_data" {
+                    let ids: Vec<u16> = expected
+                        .split(' ')
+                        .map(|id| u16::from_str_radix(id.trim_start_matches("0x"), 16).unwrap())
+                        .collect();
+                    source = Some((payload, ids));
+                    continue;
+                }
+                let (source, ids) = source.as_ref().expect("a source before the payloads");
+                let data = payload
+                    .split('+')
+                    .map(|payload| match payload {
+                        "(empty)" => Vec::new(),
+                        _ => (0..payload.len())
+                            .step_by(2)
+                            .map(|i| u8::from_str_radix(&payload[i..i + 2], 16).unwrap())
+                            .collect(),
+                    })
+                    .collect();
+                let decoded: Vec<String> = decode_advertisement(source, ids, data)
+                    .iter()
+                    .map(|measurement| format!("{}={}", measurement.kind(), measurement.value()))
+                    .collect();
+                assert_eq!(decoded.join(" "), expected, "{}:{}", file, number + 1);
+            }
+        }
+    }
+
+    proptest! {
+        // Whatever a device in range advertises, decoding it doesn't panic.
+        #[test]
+        fn test_decode_arbitrary(
+            id in prop::sample::select(vec![0x0499u16, 0xfcd2, 0xfe95, 0xfeaa, 0x181a, 0x181b, 0x181d]),
+            data in prop::collection::vec(any::<u8>(), 0..32),
+        ) {
+            decode_advertisement("manufacturer_data", &[id], vec![data.clone()]);
+            decode_advertisement("service_data", &[id], vec![data]);
+        }
+
+        #[test]
+        fn test_bthome_round_trip(battery in 0u8..=100, temperature: i16, humidity: u16) {
+            let mut data = vec![0x40, 0x01, battery, 0x02];
+            data.extend(temperature.to_le_bytes());
+            data.push(0x03);
+            data.extend(humidity.to_le_bytes());
+            let measurements = decode_advertisement("service_data", &[0xfcd2], vec![data]);
+            prop_assert_eq!(measurements.len(), 3);
+            prop_assert_eq!(&measurements[0], &Measurement::Battery(battery as f64));
+            prop_assert_eq!(measurements[1].kind(), "temperature");
+            prop_assert!((measurements[1].value() - temperature as f64 / 100.0).abs() < 1e-9);
+            prop_assert_eq!(measurements[2].kind(), "humidity");
+            prop_assert!((measurements[2].value() - humidity as f64 / 100.0).abs() < 1e-9);
+        }
+
+        // Ruuvi RAWv2, over the ranges the specification gives for a valid reading.
+        #[test]
+        fn test_ruuvi_round_trip(
+            temperature in -32767i16..=32767,
+            humidity in 0u16..=40000,
+            millivolts in 1600u16..=3646,
+        ) {
+            let mut data = vec![0x05];
+            data.extend(temperature.to_be_bytes());
+            data.extend(humidity.to_be_bytes());
+            data.extend([0xc3, 0x7c, 0x00, 0x04, 0xff, 0xfc, 0x04, 0x0c]);
+            data.extend((((millivolts - 1600) << 5) | 0x16).to_be_bytes());
+            data.extend([0x42, 0x00, 0xcd, 0xcb, 0xb8, 0x33, 0x4c, 0x88, 0x4f]);
+            let measurements = decode_advertisement("manufacturer_data", &[0x0499], vec![data]);
+            prop_assert_eq!(measurements.len(), 3);
+            prop_assert!((measurements[0].value() - humidity as f64 * 0.0025).abs() < 1e-9);
+            prop_assert!((measurements[1].value() - temperature as f64 * 0.005).abs() < 1e-9);
+            prop_assert!((measurements[2].value() - millivolts as f64 / 1000.0).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_kind() {
+        let measurements = [
+            Measurement::Humidity(1.0),
+            Measurement::Temperature(1.0),
+            Measurement::Battery(1.0),
+            Measurement::Voltage(1.0),
+            Measurement::HeartRate(1.0),
+            Measurement::Power(1.0),
+            Measurement::Energy(1.0),
+            Measurement::Current(1.0),
+            Measurement::VoltageAc(1.0),
+            Measurement::Vibration(1.0),
+            Measurement::Tilt(1.0),
+            Measurement::Rotation(1.0),
+            Measurement::WindSpeed(1.0),
+            Measurement::WindDirection(1.0),
+            Measurement::Rainfall(1.0),
+            Measurement::UvIndex(1.0),
+            Measurement::GasDetected(1.0),
+            Measurement::SmokeDetected(1.0),
+            Measurement::CarbonMonoxideDetected(1.0),
+            Measurement::CarbonMonoxide(1.0),
+            Measurement::Leak(1.0),
+        ];
+        for measurement in measurements {
+            let kind = Kind::from(&measurement);
+            assert!(!matches!(kind, Kind::Other(_)), "{}", measurement);
+            assert_eq!(kind.name(), measurement.kind());
+        }
+        let mapped = Measurement::new("dew_point".to_string(), 12.0, "°C".to_string());
+        assert_eq!(Kind::from(&mapped), Kind::Other("dew_point".to_string()));
+    }
+
+    #[test]
+    fn test_decode_panic() {
+        let device_id = DeviceId {
+            id: "hci0/dev_C4_7C_8D_6A_3E_11".to_string(),
+            device_name: "panicky".to_string(),
+            address: "C4:7C:8D:6A:3E:11".to_string(),
+            random_address: None,
+        };
+        let data = [0x01u8];
+        let measurements = decode(
+            &device_id,
+            || "01".to_string(),
+            || vec![Measurement::Battery(data[data.len()] as f64)],
+        );
+        assert!(measurements.is_empty());
+        assert_eq!(METRICS.snapshot()["unknown"]["panicky"].panicked, 1);
+    }
+}
@@ -0,0 +1,138 @@
+use async_stream::stream;
+use futures_core::stream::Stream;
+
+use crate::config::{MappingConfig, SuppressConfig};
+use crate::fanout::devices_match;
+use crate::{DeviceReading, Measurement};
+
+// map applies the first mapping matching the reading's device and kind, returning None if the
+// reading should be dropped.
+pub fn map(mappings: &[MappingConfig], mut reading: DeviceReading) -> Option<DeviceReading> {
+    let mapping = mappings.iter().find(|mapping| {
+        mapping.kind == reading.measurement.kind()
+            && devices_match(&mapping.devices, &reading.device_id)
+    });
+    match mapping {
+        None => Some(reading),
+        Some(mapping) if mapping.ignore => None,
+        Some(mapping) => {
+            if let Some(kind) = &mapping.rename {
+                reading.measurement = Measurement::Other {
+                    kind: kind.clone(),
+                    value: reading.measurement.value(),
+                    unit: reading.measurement.unit().to_string(),
+                };
+            }
+            Some(reading)
+        }
+    }
+}
+
+pub fn mapping_stream(
+    readings: impl Stream<Item = DeviceReading>,
+    mappings: Vec<MappingConfig>,
+) -> impl Stream<Item = DeviceReading> {
+    stream! {
+        for await reading in readings {
+            if let Some(reading) = map(&mappings, reading) {
+                yield reading;
+            }
+        }
+    }
+}
+
+// suppressed is whether any of suppress keeps the reading from being published.
+pub fn suppressed(suppress: &[SuppressConfig], reading: &DeviceReading) -> bool {
+    let kind = reading.measurement.kind();
+    suppress.iter().any(|suppress| {
+        suppress.kinds.iter().any(|k| *k == kind)
+            && devices_match(&suppress.devices, &reading.device_id)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{MappingConfig, SuppressConfig};
+    use crate::mapping::{map, suppressed};
+    use crate::{DeviceId, DeviceReading, Measurement};
+
+    fn reading(device_name: &str, measurement: Measurement) -> DeviceReading {
+        DeviceReading {
+            device_id: DeviceId {
+                id: format!("hci0/{}", device_name),
+                device_name: device_name.to_string(),
+                address: String::new(),
+                random_address: None,
+            },
+            measurement,
+            advertisement: None,
+            quality: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_map() {
+        let mappings = vec![
+            MappingConfig {
+                devices: vec!["Soil_*".to_string()],
+                kind: "humidity".to_string(),
+                rename: Some("soil_moisture".to_string()),
+                ignore: false,
+            },
+            MappingConfig {
+                devices: vec!["ATC_1".to_string()],
+                kind: "humidity".to_string(),
+                rename: None,
+                ignore: true,
+            },
+        ];
+
+        let renamed = map(&mappings, reading("Soil_1", Measurement::Humidity(35.0))).unwrap();
+        assert_eq!(renamed.measurement.kind(), "soil_moisture");
+        assert_eq!(renamed.measurement.unit(), "%");
+        assert_eq!(
+            serde_json::to_value(&renamed).unwrap(),
+            serde_json::json!({
+                "id": "hci0/Soil_1",
+                "device_name": "Soil_1",
+                "kind": "soil_moisture",
+                "value": 35.0,
+                "unit": "%"
+            })
+        );
+
+        assert!(map(&mappings, reading("ATC_1", Measurement::Humidity(40.0))).is_none());
+        let kept = map(&mappings, reading("ATC_1", Measurement::Temperature(20.0))).unwrap();
+        assert_eq!(kept.measurement, Measurement::Temperature(20.0));
+    }
+
+    #[test]
+    fn test_suppressed() {
+        let suppress = vec![
+            SuppressConfig {
+                devices: Vec::new(),
+                kinds: vec!["humidity".to_string()],
+            },
+            SuppressConfig {
+                devices: vec!["ATC_*".to_string()],
+                kinds: vec!["voltage".to_string()],
+            },
+        ];
+        assert!(suppressed(
+            &suppress,
+            &reading("Soil_1", Measurement::Humidity(35.0))
+        ));
+        assert!(suppressed(
+            &suppress,
+            &reading("ATC_1", Measurement::Voltage(2.9))
+        ));
+        assert!(!suppressed(
+            &suppress,
+            &reading("Soil_1", Measurement::Voltage(2.9))
+        ));
+        assert!(!suppressed(
+            &suppress,
+            &reading("ATC_1", Measurement::Battery(80.0))
+        ));
+    }
+}
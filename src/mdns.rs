@@ -0,0 +1,115 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use color_eyre::eyre::{Result, WrapErr};
+use mdns_sd::{ServiceDaemon, ServiceInfo};
+
+use crate::config::MdnsConfig;
+
+pub const SERVICE_TYPE: &str = "_blueplug._tcp.local.";
+
+// service_info describes the HTTP listener on listen. Its TXT record has the version and the paths
+// of the API, so a client can tell what it may ask for without probing.
+fn service_info(
+    config: &MdnsConfig,
+    host_name: &str,
+    listen: SocketAddr,
+    history: bool,
+) -> Result<ServiceInfo> {
+    let name = config
+        .name
+        .clone()
+        .unwrap_or_else(|| format!("blueplug on {}", host_name));
+    let mut properties = HashMap::from([
+        ("version".to_string(), env!("CARGO_PKG_VERSION").to_string()),
+        ("dashboard".to_string(), "/".to_string()),
+        ("readings".to_string(), "/readings".to_string()),
+        ("devices".to_string(), "/devices".to_string()),
+        ("metrics".to_string(), "/metrics".to_string()),
+    ]);
+    if history {
+        properties.insert(
+            "history".to_string(),
+            "/devices/{device}/history".to_string(),
+        );
+    }
+    let host = format!("{}.local.", host_name);
+    let info = if listen.ip().is_unspecified() {
+        ServiceInfo::new(SERVICE_TYPE, &name, &host, (), listen.port(), properties)?
+            .enable_addr_auto()
+    } else {
+        ServiceInfo::new(
+            SERVICE_TYPE,
+            &name,
+            &host,
+            listen.ip(),
+            listen.port(),
+            properties,
+        )?
+    };
+    Ok(info)
+}
+
+// host_name is this host's name as an mDNS label, letters, digits and hyphens.
+fn host_name() -> String {
+    let name = gethostname::gethostname().to_string_lossy().into_owned();
+    let name = name.split('.').next().unwrap_or_default();
+    let label: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect();
+    if label.is_empty() {
+        "blueplug".to_string()
+    } else {
+        label
+    }
+}
+
+// spawn_mdns advertises the HTTP listener on listen, on every interface's addresses when it is
+// bound to all of them, until the returned daemon is dropped.
+pub fn spawn_mdns(config: &MdnsConfig, listen: SocketAddr, history: bool) -> Result<ServiceDaemon> {
+    let daemon = ServiceDaemon::new().wrap_err("starting mDNS")?;
+    let info = service_info(config, &host_name(), listen, history)?;
+    println!("advertising {} by mDNS", info.get_fullname());
+    daemon.register(info).wrap_err("registering mDNS service")?;
+    Ok(daemon)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::MdnsConfig;
+    use crate::mdns::service_info;
+
+    #[test]
+    fn test_service_info() {
+        let info = service_info(
+            &MdnsConfig::default(),
+            "greenhouse-pi",
+            "0.0.0.0:8099".parse().unwrap(),
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            info.get_fullname(),
+            "blueplug on greenhouse-pi._blueplug._tcp.local."
+        );
+        assert_eq!(info.get_hostname(), "greenhouse-pi.local.");
+        assert_eq!(info.get_port(), 8099);
+        assert!(info.is_addr_auto());
+        assert_eq!(info.get_property_val_str("readings"), Some("/readings"));
+        assert_eq!(info.get_property_val_str("history"), None);
+
+        let info = service_info(
+            &MdnsConfig {
+                name: Some("Greenhouse".to_string()),
+            },
+            "greenhouse-pi",
+            "192.168.1.20:8099".parse().unwrap(),
+            true,
+        )
+        .unwrap();
+        assert_eq!(info.get_fullname(), "Greenhouse._blueplug._tcp.local.");
+        assert!(!info.is_addr_auto());
+        assert!(info.get_property_val_str("history").is_some());
+    }
+}
@@ -0,0 +1,771 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt::Write;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use btleplug::api::bleuuid::uuid_from_u16;
+use serde::Serialize;
+
+use crate::health::HEALTH;
+use crate::names::TOPIC_NAMES;
+use crate::scope;
+use crate::{DeviceEvent, DeviceId};
+
+// Upper bounds in seconds of the buckets of the publish latency histogram.
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+// Messages handed to an MQTT client that never reach the connection, say QoS 0 messages the
+// client dropped while disconnected, are forgotten past this many, so they can't skew the
+// latency of later ones for long.
+const HANDED_OFF_LIMIT: usize = 64;
+
+// The counters of devices not heard for this long are forgotten, as their topic names are, so
+// rotating private addresses and passers-by don't pile up, each with series of its own.
+const DEVICE_EXPIRY: Duration = Duration::from_secs(3600);
+
+// METRICS counts what happens to advertisements and readings on their way through blueplug, to
+// help answer "why isn't my sensor showing up?".
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::default);
+
+// in_scope counts into the counters of the embedded Blueplug whose task is running, if any, so
+// that each instance reports only what it saw.
+fn in_scope(count: impl FnOnce(&Metrics)) {
+    if let Some(scope) = scope::current() {
+        count(&scope.metrics);
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Stage {
+    // An advertisement was received from any source.
+    Seen,
+    // An advertisement was dropped as a duplicate.
+    Throttled,
+    // An advertisement was dropped as chatter of a device that carries no readings.
+    Ignored,
+    // An advertisement was too long for legacy advertising, so was sent with extended advertising.
+    Extended,
+    // An advertisement decoded into at least one reading.
+    Decoded,
+    // An advertisement of a known protocol failed to decode.
+    Failed,
+    // A decoder panicked on an advertisement.
+    Panicked,
+    // Routes kept a reading from every sink.
+    Filtered,
+    // A device missed the advertising intervals its watchdog expects.
+    Missed,
+    // A message was handed to a broker.
+    Published,
+}
+
+#[derive(Default, Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct Counters {
+    pub seen: u64,
+    pub throttled: u64,
+    pub ignored: u64,
+    pub extended: u64,
+    pub decoded: u64,
+    pub failed: u64,
+    pub panicked: u64,
+    pub filtered: u64,
+    pub missed: u64,
+    pub published: u64,
+}
+
+impl Counters {
+    fn get(&self, stage: Stage) -> u64 {
+        match stage {
+            Stage::Seen => self.seen,
+            Stage::Throttled => self.throttled,
+            Stage::Ignored => self.ignored,
+            Stage::Extended => self.extended,
+            Stage::Decoded => self.decoded,
+            Stage::Failed => self.failed,
+            Stage::Panicked => self.panicked,
+            Stage::Filtered => self.filtered,
+            Stage::Missed => self.missed,
+            Stage::Published => self.published,
+        }
+    }
+
+    fn get_mut(&mut self, stage: Stage) -> &mut u64 {
+        match stage {
+            Stage::Seen => &mut self.seen,
+            Stage::Throttled => &mut self.throttled,
+            Stage::Ignored => &mut self.ignored,
+            Stage::Extended => &mut self.extended,
+            Stage::Decoded => &mut self.decoded,
+            Stage::Failed => &mut self.failed,
+            Stage::Panicked => &mut self.panicked,
+            Stage::Filtered => &mut self.filtered,
+            Stage::Missed => &mut self.missed,
+            Stage::Published => &mut self.published,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    inner: Mutex<Inner>,
+    // The publish path of each broker.
+    publishing: Mutex<BTreeMap<String, Publishing>>,
+    // Messages pruned from each broker's queue_file, see outbox.rs.
+    pruned: Mutex<BTreeMap<(String, Pruned), u64>>,
+    // Files and bytes deleted by the retention of the capture and the archive.
+    store_pruned: Mutex<BTreeMap<String, (u64, u64)>>,
+    // Readings the archive dropped while its writes failed, see archive.rs.
+    archive_dropped: Mutex<u64>,
+    // Readings held back by each broker's rate_limit, see ratelimit.rs.
+    rate_limited: Mutex<BTreeMap<(String, RateLimited), u64>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Pruned {
+    // The broker acknowledged the message.
+    Acknowledged,
+    // The message was still waiting, but the queue_file was full.
+    Dropped,
+}
+
+impl Pruned {
+    fn name(self) -> &'static str {
+        match self {
+            Pruned::Acknowledged => "acknowledged",
+            Pruned::Dropped => "dropped",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RateLimited {
+    // The reading was queued, to be published once the limit allows.
+    Queued,
+    // The reading, or with a full queue the oldest queued one, was dropped.
+    Dropped,
+}
+
+impl RateLimited {
+    fn name(self) -> &'static str {
+        match self {
+            RateLimited::Queued => "queued",
+            RateLimited::Dropped => "dropped",
+        }
+    }
+}
+
+// Publishing follows messages to a broker: handed to the MQTT client, written to the connection
+// and, at QoS 1 or 2, acknowledged. The client writes messages in the order they were handed to
+// it, so the first write of a packet id belongs to the oldest message not yet written. Latency is
+// measured from hand-off to the acknowledgement, or to the write at QoS 0, so a slow or
+// overloaded broker shows up as latency and in-flight messages, and a broker that can't keep up
+// with the radio as queued ones.
+#[derive(Default)]
+struct Publishing {
+    // When each message in the client's queue was handed to it, oldest first.
+    handed_off: VecDeque<Instant>,
+    // When each message awaiting acknowledgement was handed off, by packet id.
+    in_flight: HashMap<u16, Instant>,
+    latency: Histogram,
+}
+
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct Histogram {
+    // Counts of latencies up to each of LATENCY_BUCKETS, and then of longer ones.
+    pub buckets: Vec<u64>,
+    pub count: u64,
+    // Seconds.
+    pub sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        if self.buckets.is_empty() {
+            self.buckets = vec![0; LATENCY_BUCKETS.len() + 1];
+        }
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.sum += seconds;
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct PublishStats {
+    // Messages waiting in the MQTT client, plus those buffered while the broker is unreachable.
+    pub queued: usize,
+    // Messages written but not yet acknowledged.
+    pub in_flight: usize,
+    pub latency: Histogram,
+}
+
+#[derive(Default)]
+struct Inner {
+    // Counters by protocol, then device name.
+    counters: BTreeMap<String, BTreeMap<String, Counters>>,
+    // When each device name was last counted.
+    counted: HashMap<String, Instant>,
+    // The protocol each device id last advertised with, so readings can be attributed to one, and
+    // when the device was last heard.
+    protocols: HashMap<String, (&'static str, Instant)>,
+    last_prune: Option<Instant>,
+}
+
+impl Metrics {
+    pub fn advertisement(&self, stage: Stage, event: &DeviceEvent) {
+        let protocol = protocol(event);
+        let device_id = event.device_id();
+        let now = Instant::now();
+        self.inner
+            .lock()
+            .unwrap()
+            .advertisement(protocol, device_id, stage, now);
+        in_scope(|scope| {
+            scope
+                .inner
+                .lock()
+                .unwrap()
+                .advertisement(protocol, device_id, stage, now)
+        });
+    }
+
+    pub fn reading(&self, stage: Stage, device_id: &DeviceId) {
+        let now = Instant::now();
+        self.inner.lock().unwrap().reading(device_id, stage, now);
+        in_scope(|scope| scope.inner.lock().unwrap().reading(device_id, stage, now));
+    }
+
+    // protocol is the protocol the device last advertised with.
+    pub fn protocol(&self, device_id: &DeviceId) -> &'static str {
+        self.inner.lock().unwrap().protocol(device_id)
+    }
+
+    pub fn snapshot(&self) -> BTreeMap<String, BTreeMap<String, Counters>> {
+        self.inner.lock().unwrap().counters.clone()
+    }
+
+    // handed_off notes a message was handed to broker's MQTT client.
+    pub fn handed_off(&self, broker: &str) {
+        let mut publishing = self.publishing.lock().unwrap();
+        let publishing = publishing.entry(broker.to_string()).or_default();
+        if publishing.handed_off.len() == HANDED_OFF_LIMIT {
+            publishing.handed_off.pop_front();
+        }
+        publishing.handed_off.push_back(Instant::now());
+    }
+
+    // written notes the client wrote a message to the connection. Packet id 0 is QoS 0, which is
+    // never acknowledged. A packet id already in flight is a retransmission after reconnecting.
+    pub fn written(&self, broker: &str, pkid: u16) {
+        let mut publishing = self.publishing.lock().unwrap();
+        let publishing = publishing.entry(broker.to_string()).or_default();
+        if publishing.in_flight.contains_key(&pkid) {
+            return;
+        }
+        let Some(handed_off) = publishing.handed_off.pop_front() else {
+            return;
+        };
+        if pkid == 0 {
+            publishing
+                .latency
+                .observe(handed_off.elapsed().as_secs_f64());
+        } else {
+            publishing.in_flight.insert(pkid, handed_off);
+        }
+    }
+
+    pub fn acknowledged(&self, broker: &str, pkid: u16) {
+        let mut publishing = self.publishing.lock().unwrap();
+        let publishing = publishing.entry(broker.to_string()).or_default();
+        if let Some(handed_off) = publishing.in_flight.remove(&pkid) {
+            publishing
+                .latency
+                .observe(handed_off.elapsed().as_secs_f64());
+        }
+    }
+
+    // pruned counts messages pruned from broker's queue_file.
+    pub fn pruned(&self, broker: &str, reason: Pruned, count: u64) {
+        *self
+            .pruned
+            .lock()
+            .unwrap()
+            .entry((broker.to_string(), reason))
+            .or_default() += count;
+    }
+
+    // store_pruned counts a file of bytes deleted from store by its retention.
+    pub fn store_pruned(&self, store: &str, bytes: u64) {
+        let mut store_pruned = self.store_pruned.lock().unwrap();
+        let (files, total) = store_pruned.entry(store.to_string()).or_default();
+        *files += 1;
+        *total += bytes;
+    }
+
+    // archive_dropped counts a reading the archive had no room for.
+    pub fn archive_dropped(&self) {
+        *self.archive_dropped.lock().unwrap() += 1;
+    }
+
+    // rate_limited counts readings held back by broker's rate_limit.
+    pub fn rate_limited(&self, broker: &str, outcome: RateLimited) {
+        *self
+            .rate_limited
+            .lock()
+            .unwrap()
+            .entry((broker.to_string(), outcome))
+            .or_default() += 1;
+    }
+
+    // publishing returns the publish path of each broker.
+    pub fn publishing(&self) -> BTreeMap<String, PublishStats> {
+        let publishing = self.publishing.lock().unwrap();
+        publishing
+            .iter()
+            .map(|(broker, publishing)| {
+                let buffered = HEALTH.buffered_count(broker);
+                let stats = PublishStats {
+                    queued: publishing.handed_off.len() + buffered,
+                    in_flight: publishing.in_flight.len(),
+                    latency: publishing.latency.clone(),
+                };
+                (broker.clone(), stats)
+            })
+            .collect()
+    }
+
+    // prometheus renders the counters in the Prometheus text exposition format.
+    pub fn prometheus(&self) -> String {
+        let snapshot = self.snapshot();
+        let mut text = String::new();
+        let stages = [
+            (Stage::Seen, "seen", "Advertisements received"),
+            (
+                Stage::Throttled,
+                "throttled",
+                "Advertisements dropped as duplicates",
+            ),
+            (
+                Stage::Ignored,
+                "ignored",
+                "Advertisements dropped as chatter of non-sensor devices",
+            ),
+            (
+                Stage::Extended,
+                "extended",
+                "Advertisements too long for legacy advertising",
+            ),
+            (
+                Stage::Decoded,
+                "decoded",
+                "Advertisements decoded into readings",
+            ),
+            (
+                Stage::Failed,
+                "failed",
+                "Advertisements of known protocols that failed to decode",
+            ),
+            (
+                Stage::Panicked,
+                "panicked",
+                "Advertisements whose decoder panicked",
+            ),
+            (
+                Stage::Missed,
+                "missed",
+                "Warnings of devices missing their expected intervals",
+            ),
+            (
+                Stage::Filtered,
+                "filtered",
+                "Readings kept from every sink by routes",
+            ),
+            (
+                Stage::Published,
+                "published",
+                "Messages published to brokers",
+            ),
+        ];
+        for (stage, stage_name, help) in stages {
+            let name = format!("blueplug_{}_total", stage_name);
+            let _ = writeln!(text, "# HELP {} {}", name, help);
+            let _ = writeln!(text, "# TYPE {} counter", name);
+            for (protocol, devices) in &snapshot {
+                for (device, counters) in devices {
+                    let _ = writeln!(
+                        text,
+                        "{}{{protocol=\"{}\",device=\"{}\"{}}} {}",
+                        name,
+                        escape(protocol),
+                        escape(device),
+                        tag_labels(device),
+                        counters.get(stage)
+                    );
+                }
+            }
+        }
+
+        let publishing = self.publishing();
+        let gauges = [
+            (
+                "blueplug_publish_queued",
+                "Messages waiting to be written to a broker",
+                publishing
+                    .values()
+                    .map(|stats| stats.queued)
+                    .collect::<Vec<_>>(),
+            ),
+            (
+                "blueplug_publish_in_flight",
+                "Messages written to a broker and not yet acknowledged",
+                publishing.values().map(|stats| stats.in_flight).collect(),
+            ),
+        ];
+        for (name, help, values) in gauges {
+            let _ = writeln!(text, "# HELP {} {}", name, help);
+            let _ = writeln!(text, "# TYPE {} gauge", name);
+            for (broker, value) in publishing.keys().zip(values) {
+                let _ = writeln!(text, "{}{{broker=\"{}\"}} {}", name, escape(broker), value);
+            }
+        }
+        let name = "blueplug_publish_latency_seconds";
+        let _ = writeln!(
+            text,
+            "# HELP {} Seconds from handing a message to the MQTT client until the broker acknowledged it, or it was written at QoS 0",
+            name
+        );
+        let _ = writeln!(text, "# TYPE {} histogram", name);
+        for (broker, stats) in &publishing {
+            let broker = escape(broker);
+            let latency = &stats.latency;
+            let mut cumulative = 0;
+            for (i, bound) in LATENCY_BUCKETS.iter().enumerate() {
+                cumulative += latency.buckets.get(i).copied().unwrap_or(0);
+                let _ = writeln!(
+                    text,
+                    "{}_bucket{{broker=\"{}\",le=\"{}\"}} {}",
+                    name, broker, bound, cumulative
+                );
+            }
+            let _ = writeln!(
+                text,
+                "{}_bucket{{broker=\"{}\",le=\"+Inf\"}} {}",
+                name, broker, latency.count
+            );
+            let _ = writeln!(
+                text,
+                "{}_sum{{broker=\"{}\"}} {}",
+                name, broker, latency.sum
+            );
+            let _ = writeln!(
+                text,
+                "{}_count{{broker=\"{}\"}} {}",
+                name, broker, latency.count
+            );
+        }
+
+        let name = "blueplug_queue_pruned_total";
+        let _ = writeln!(
+            text,
+            "# HELP {} Messages pruned from queue files, once acknowledged or dropped when full",
+            name
+        );
+        let _ = writeln!(text, "# TYPE {} counter", name);
+        for ((broker, reason), count) in self.pruned.lock().unwrap().iter() {
+            let _ = writeln!(
+                text,
+                "{}{{broker=\"{}\",reason=\"{}\"}} {}",
+                name,
+                escape(broker),
+                reason.name(),
+                count
+            );
+        }
+
+        let store_pruned = self.store_pruned.lock().unwrap();
+        for (name, help, bytes) in [
+            ("blueplug_store_pruned_files_total", "Files", false),
+            ("blueplug_store_pruned_bytes_total", "Bytes", true),
+        ] {
+            let _ = writeln!(
+                text,
+                "# HELP {} {} deleted from the capture and archive by their retention",
+                name, help
+            );
+            let _ = writeln!(text, "# TYPE {} counter", name);
+            for (store, (files, total)) in store_pruned.iter() {
+                let count = if bytes { total } else { files };
+                let _ = writeln!(text, "{}{{store=\"{}\"}} {}", name, store, count);
+            }
+        }
+        drop(store_pruned);
+
+        let name = "blueplug_archive_dropped_total";
+        let _ = writeln!(
+            text,
+            "# HELP {} Readings dropped while archive writes kept failing",
+            name
+        );
+        let _ = writeln!(text, "# TYPE {} counter", name);
+        let _ = writeln!(text, "{} {}", name, self.archive_dropped.lock().unwrap());
+
+        let name = "blueplug_rate_limited_total";
+        let _ = writeln!(
+            text,
+            "# HELP {} Readings over a broker's rate limit, queued or dropped",
+            name
+        );
+        let _ = writeln!(text, "# TYPE {} counter", name);
+        for ((broker, outcome), count) in self.rate_limited.lock().unwrap().iter() {
+            let _ = writeln!(
+                text,
+                "{}{{broker=\"{}\",outcome=\"{}\"}} {}",
+                name,
+                escape(broker),
+                outcome.name(),
+                count
+            );
+        }
+        text
+    }
+}
+
+impl Inner {
+    fn advertisement(
+        &mut self,
+        protocol: &'static str,
+        device_id: &DeviceId,
+        stage: Stage,
+        now: Instant,
+    ) {
+        self.protocols.insert(device_id.id.clone(), (protocol, now));
+        self.count(protocol, &device_id.device_name, stage, now);
+    }
+
+    fn reading(&mut self, device_id: &DeviceId, stage: Stage, now: Instant) {
+        let protocol = match self.protocols.get_mut(&device_id.id) {
+            Some((protocol, heard)) => {
+                *heard = now;
+                *protocol
+            }
+            None => "unknown",
+        };
+        self.count(protocol, &device_id.device_name, stage, now);
+    }
+
+    fn protocol(&self, device_id: &DeviceId) -> &'static str {
+        self.protocols
+            .get(&device_id.id)
+            .map_or("unknown", |(protocol, _)| *protocol)
+    }
+
+    fn count(&mut self, protocol: &str, device: &str, stage: Stage, now: Instant) {
+        if self
+            .last_prune
+            .is_none_or(|last| now.duration_since(last) >= DEVICE_EXPIRY)
+        {
+            self.prune(now);
+        }
+        let counters = self
+            .counters
+            .entry(protocol.to_string())
+            .or_default()
+            .entry(device.to_string())
+            .or_default();
+        *counters.get_mut(stage) += 1;
+        self.counted.insert(device.to_string(), now);
+    }
+
+    // prune forgets the counters and protocols of devices not heard for DEVICE_EXPIRY.
+    fn prune(&mut self, now: Instant) {
+        let expired = |heard: &Instant| now.duration_since(*heard) >= DEVICE_EXPIRY;
+        self.counted.retain(|_, counted| !expired(counted));
+        for devices in self.counters.values_mut() {
+            devices.retain(|device, _| self.counted.contains_key(device));
+        }
+        self.counters.retain(|_, devices| !devices.is_empty());
+        self.protocols.retain(|_, (_, heard)| !expired(heard));
+        self.last_prune = Some(now);
+    }
+}
+
+// protocol guesses the protocol of an advertisement from its company id or service UUID.
+pub fn protocol(event: &DeviceEvent) -> &'static str {
+    match event {
+        DeviceEvent::ManufacturerDataAdvertisement {
+            manufacturer_data, ..
+        } => {
+            if manufacturer_data.contains_key(&0x0499) {
+                "ruuvi"
+            } else {
+                "unknown"
+            }
+        }
+        DeviceEvent::ServiceDataAdvertisement { service_data, .. } => {
+            let has = |uuid: u16| service_data.contains_key(&uuid_from_u16(uuid));
+            if has(0xfcd2) || has(0x181c) || has(0x181e) {
+                "bthome"
+            } else if has(0x181a) {
+                "atc"
+            } else if has(0xfe95) {
+                "xiaomi"
+            } else if has(0x181b) || has(0x181d) {
+                "xiaomi_scale"
+            } else {
+                "unknown"
+            }
+        }
+    }
+}
+
+// tag_labels are the location and labels of a device's counters, which are kept by name, so
+// locations and labels configured by id don't apply.
+fn tag_labels(device_name: &str) -> String {
+    let device_id = DeviceId {
+        id: String::new(),
+        device_name: device_name.to_string(),
+        address: String::new(),
+        random_address: None,
+    };
+    TOPIC_NAMES
+        .tags(&device_id)
+        .iter()
+        .map(|(key, value)| format!(",{}=\"{}\"", key, escape(value)))
+        .collect()
+}
+
+fn escape(label: &str) -> String {
+    label
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::time::Instant;
+
+    use btleplug::api::bleuuid::uuid_from_u16;
+
+    use crate::metrics::{Counters, Inner, Metrics, Stage, DEVICE_EXPIRY};
+    use crate::scope::{self, Scope};
+    use crate::{DeviceEvent, DeviceId};
+
+    #[test]
+    fn test_metrics() {
+        let device_id = DeviceId {
+            id: "hci0/dev_A4_C1_38_8F_2C_1A".to_string(),
+            device_name: "ATC \"kitchen\"".to_string(),
+            address: "A4:C1:38:8F:2C:1A".to_string(),
+            random_address: None,
+        };
+        let event = DeviceEvent::ServiceDataAdvertisement {
+            device_id: device_id.clone(),
+            service_data: HashMap::from([(uuid_from_u16(0xfcd2), vec![0x40])]),
+            rssi: None,
+        };
+
+        let metrics = Metrics::default();
+        metrics.advertisement(Stage::Seen, &event);
+        metrics.advertisement(Stage::Seen, &event);
+        metrics.advertisement(Stage::Throttled, &event);
+        metrics.reading(Stage::Published, &device_id);
+
+        assert_eq!(
+            metrics.snapshot()["bthome"]["ATC \"kitchen\""],
+            Counters {
+                seen: 2,
+                throttled: 1,
+                published: 1,
+                ..Default::default()
+            }
+        );
+        assert!(metrics.prometheus().contains(
+            "blueplug_seen_total{protocol=\"bthome\",device=\"ATC \\\"kitchen\\\"\"} 2\n"
+        ));
+
+        // Within a scope, counters are also kept in the scope's own metrics.
+        let scoped = Arc::new(Scope::default());
+        scope::sync_scope(Some(scoped.clone()), || {
+            metrics.advertisement(Stage::Seen, &event);
+            metrics.reading(Stage::Decoded, &device_id);
+        });
+        assert_eq!(metrics.snapshot()["bthome"]["ATC \"kitchen\""].seen, 3);
+        assert_eq!(
+            scoped.metrics.snapshot()["bthome"]["ATC \"kitchen\""],
+            Counters {
+                seen: 1,
+                decoded: 1,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_forget_silent_devices() {
+        let device_id = |name: &str| DeviceId {
+            id: format!("hci0/{}", name),
+            device_name: name.to_string(),
+            address: String::new(),
+            random_address: None,
+        };
+        let mut inner = Inner::default();
+        let start = Instant::now();
+        inner.advertisement("bthome", &device_id("gone"), Stage::Seen, start);
+        inner.advertisement("bthome", &device_id("here"), Stage::Seen, start);
+        inner.reading(
+            &device_id("here"),
+            Stage::Decoded,
+            start + DEVICE_EXPIRY / 2,
+        );
+        inner.advertisement(
+            "ruuvi",
+            &device_id("new"),
+            Stage::Seen,
+            start + DEVICE_EXPIRY,
+        );
+
+        assert_eq!(
+            inner.counters["bthome"].keys().collect::<Vec<_>>(),
+            ["here"]
+        );
+        assert_eq!(inner.counters["ruuvi"]["new"].seen, 1);
+        assert_eq!(inner.protocol(&device_id("gone")), "unknown");
+        assert_eq!(inner.protocol(&device_id("here")), "bthome");
+    }
+
+    #[test]
+    fn test_publishing() {
+        let metrics = Metrics::default();
+        for _ in 0..3 {
+            metrics.handed_off("local");
+        }
+        metrics.written("local", 0);
+        metrics.written("local", 7);
+        // Retransmitted after reconnecting.
+        metrics.written("local", 7);
+
+        let stats = &metrics.publishing()["local"];
+        assert_eq!((stats.queued, stats.in_flight), (1, 1));
+        assert_eq!(stats.latency.count, 1);
+
+        metrics.acknowledged("local", 7);
+        let stats = &metrics.publishing()["local"];
+        assert_eq!((stats.queued, stats.in_flight), (1, 0));
+        assert_eq!(stats.latency.count, 2);
+        assert_eq!(stats.latency.buckets[0], 2);
+
+        let text = metrics.prometheus();
+        assert!(text.contains("blueplug_publish_queued{broker=\"local\"} 1\n"));
+        assert!(text
+            .contains("blueplug_publish_latency_seconds_bucket{broker=\"local\",le=\"+Inf\"} 2\n"));
+        assert!(text.contains("blueplug_publish_latency_seconds_count{broker=\"local\"} 2\n"));
+    }
+}
@@ -0,0 +1,109 @@
+use aes::Aes128;
+use ccm::aead::generic_array::GenericArray;
+use ccm::aead::{Aead, Payload};
+use ccm::consts::{U12, U4};
+use ccm::{Ccm, KeyInit};
+use uuid::Uuid;
+
+use crate::Measurement;
+
+// Xiaomi's MiBeacon advertisements are service data of this UUID.
+pub const UUID: Uuid = Uuid::from_u128(0x0000fe95_0000_1000_8000_00805f9b34fb);
+
+// Frame control flags.
+const ENCRYPTED: u16 = 0x0008;
+const MAC_INCLUDED: u16 = 0x0010;
+const CAPABILITY_INCLUDED: u16 = 0x0020;
+const OBJECT_INCLUDED: u16 = 0x0040;
+// Set in the capability byte when two bytes of IO capability follow it.
+const IO_CAPABILITY: u8 = 0x20;
+// The MiBeacon version is the top 4 bits of the frame control.
+const VERSION_SHIFT: u16 = 12;
+
+// Object ids.
+const BATTERY: u16 = 0x100a;
+const WATER_LEAK: u16 = 0x1014;
+
+// measurements decodes MiBeacon advertisements of Xiaomi and Linptech water leak sensors. A frame
+// is the frame control, the product id and a frame counter, then optionally the device's MAC
+// address and its capabilities, then objects of an id, a length and a value, all little endian.
+// Only the leak and battery objects are decoded, and encrypted frames decode to nothing until
+// decrypt has been through them.
+pub fn measurements(data: &[u8]) -> Vec<Measurement> {
+    let Some(control) = frame_control(data) else {
+        return Vec::new();
+    };
+    if control & ENCRYPTED != 0 || control & OBJECT_INCLUDED == 0 {
+        return Vec::new();
+    }
+    let Some(mut i) = objects_start(data, control) else {
+        return Vec::new();
+    };
+    let mut measurements = Vec::new();
+    while let Some(header) = data.get(i..i + 3) {
+        let id = u16::from_le_bytes([header[0], header[1]]);
+        let Some(value) = data.get(i + 3..i + 3 + header[2] as usize) else {
+            break;
+        };
+        match (id, value) {
+            (WATER_LEAK, [leak]) => measurements.push(Measurement::Leak((*leak != 0) as u8 as f64)),
+            (BATTERY, [battery]) => measurements.push(Measurement::Battery(*battery as f64)),
+            _ => {}
+        }
+        i += 3 + value.len();
+    }
+    measurements
+}
+
+// decrypt returns the plain frame of an encrypted MiBeacon v4 or v5 frame, with the encrypted flag
+// cleared for measurements. Its objects are encrypted with AES-CCM and followed by 3 more bytes
+// of frame counter and a 4 byte MIC, with a nonce of the MAC address, the product id and the
+// frame counter, and 0x11 as associated data. It is None if the frame isn't encrypted, is of an
+// older version, whose 12 byte keys aren't supported, or doesn't authenticate with key.
+pub fn decrypt(data: &[u8], mac: &[u8; 6], key: &[u8]) -> Option<Vec<u8>> {
+    let control = frame_control(data)?;
+    if control & ENCRYPTED == 0 || control >> VERSION_SHIFT < 4 {
+        return None;
+    }
+    let start = objects_start(data, control)?;
+    let (objects, trailer) = data
+        .get(start..)?
+        .split_at(data.len().checked_sub(start + 7)?);
+    let (counter, mic) = trailer.split_at(3);
+    let mac: Vec<u8> = mac.iter().rev().copied().collect();
+    let nonce = [&mac, &data[2..5], counter].concat();
+    let plain = Ccm::<Aes128, U4, U12>::new_from_slice(key)
+        .ok()?
+        .decrypt(
+            GenericArray::from_slice(&nonce),
+            Payload {
+                msg: &[objects, mic].concat(),
+                aad: &[0x11],
+            },
+        )
+        .ok()?;
+    let mut frame = data[..start].to_vec();
+    frame[..2].copy_from_slice(&(control & !ENCRYPTED).to_le_bytes());
+    frame.extend(plain);
+    Some(frame)
+}
+
+fn frame_control(data: &[u8]) -> Option<u16> {
+    data.get(..2).map(|c| u16::from_le_bytes([c[0], c[1]]))
+}
+
+// objects_start is where the objects of a frame begin, after the MAC address and capabilities.
+fn objects_start(data: &[u8], control: u16) -> Option<usize> {
+    let mut i = 5;
+    if control & MAC_INCLUDED != 0 {
+        i += 6;
+    }
+    if control & CAPABILITY_INCLUDED != 0 {
+        i += if data.get(i)? & IO_CAPABILITY != 0 {
+            3
+        } else {
+            1
+        };
+    }
+    Some(i)
+}
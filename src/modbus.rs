@@ -0,0 +1,255 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use serde::Deserialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::config::{ModbusConfig, RegisterConfig};
+use crate::latest::LatestReadings;
+use crate::supervisor::SUPERVISOR;
+
+const READ_HOLDING_REGISTERS: u8 = 0x03;
+const READ_INPUT_REGISTERS: u8 = 0x04;
+
+const ILLEGAL_FUNCTION: u8 = 0x01;
+const ILLEGAL_DATA_ADDRESS: u8 = 0x02;
+const ILLEGAL_DATA_VALUE: u8 = 0x03;
+
+// The most registers one request may read, per the Modbus specification.
+const MAX_REGISTERS: u16 = 125;
+
+// RegisterType is how a reading's value is put into registers. Scaled integers are rounded and
+// saturate at their range; 32-bit types take two registers, high word first. A reading that
+// hasn't been received reads as the type's "no value": i16::MIN, u16::MAX, i32::MIN or NaN.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RegisterType {
+    I16,
+    U16,
+    I32,
+    F32,
+}
+
+impl RegisterType {
+    pub fn width(self) -> u16 {
+        match self {
+            RegisterType::I16 | RegisterType::U16 => 1,
+            RegisterType::I32 | RegisterType::F32 => 2,
+        }
+    }
+
+    fn encode(self, value: Option<f64>) -> Vec<u16> {
+        let words = |n: u32| vec![(n >> 16) as u16, n as u16];
+        match (self, value) {
+            (RegisterType::I16, Some(v)) => vec![v.round() as i16 as u16],
+            (RegisterType::I16, None) => vec![i16::MIN as u16],
+            (RegisterType::U16, Some(v)) => vec![v.round() as u16],
+            (RegisterType::U16, None) => vec![u16::MAX],
+            (RegisterType::I32, Some(v)) => words(v.round() as i32 as u32),
+            (RegisterType::I32, None) => words(i32::MIN as u32),
+            (RegisterType::F32, Some(v)) => words((v as f32).to_bits()),
+            (RegisterType::F32, None) => words(f32::NAN.to_bits()),
+        }
+    }
+}
+
+// spawn_modbus serves the latest readings as Modbus TCP holding registers, and the same values
+// as input registers, laid out by config.registers, so building management systems and PLCs can
+// poll them. Registers are read-only. Reads of registers between mapped ones return 0, but a read
+// of no mapped register at all is an illegal data address.
+pub async fn spawn_modbus(config: &ModbusConfig, latest: Arc<Mutex<LatestReadings>>) -> Result<()> {
+    let listener = TcpListener::bind(config.listen)
+        .await
+        .wrap_err_with(|| format!("listening on {}", config.listen))?;
+    println!("Modbus TCP server listening on {}", config.listen);
+    let map = Arc::new(config.registers.clone());
+    SUPERVISOR.spawn_once("modbus", async move {
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    println!("modbus: accepting failed: {:?}", e);
+                    continue;
+                }
+            };
+            let map = map.clone();
+            let latest = latest.clone();
+            // A connection that fails only ends itself.
+            SUPERVISOR
+                .child()
+                .spawn_once(format!("modbus {}", peer), async move {
+                    if let Err(e) = serve(stream, &map, &latest).await {
+                        println!("modbus: {}: {:?}", peer, e);
+                    }
+                });
+        }
+    });
+    Ok(())
+}
+
+// serve answers the requests of one connection until it closes.
+async fn serve(
+    mut stream: TcpStream,
+    map: &[RegisterConfig],
+    latest: &Mutex<LatestReadings>,
+) -> Result<()> {
+    loop {
+        // MBAP header: transaction id, protocol id (0), length of what follows, unit id.
+        let mut header = [0; 7];
+        match stream.read_exact(&mut header).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+        let length = u16::from_be_bytes([header[4], header[5]]);
+        if header[2..4] != [0, 0] || !(2..=254).contains(&length) {
+            return Err(eyre!("not a Modbus TCP request"));
+        }
+        let mut pdu = vec![0; length as usize - 1];
+        stream.read_exact(&mut pdu).await?;
+
+        let registers = registers(map, &latest.lock().unwrap());
+        let response = respond(&pdu, &registers);
+        let mut frame = header[..4].to_vec();
+        frame.extend(((response.len() + 1) as u16).to_be_bytes());
+        frame.push(header[6]);
+        frame.extend(response);
+        stream.write_all(&frame).await?;
+    }
+}
+
+// registers returns the value of every mapped register.
+fn registers(map: &[RegisterConfig], latest: &LatestReadings) -> BTreeMap<u16, u16> {
+    let readings = latest.readings();
+    let mut registers = BTreeMap::new();
+    for register in map {
+        // The most recent, should the device be heard through several adapters.
+        let value = readings
+            .iter()
+            .filter(|latest| {
+                let reading = &latest.reading;
+                (reading.device_id.device_name == register.device
+                    || reading.device_id.id == register.device)
+                    && reading.measurement.kind() == register.kind
+            })
+            .max_by_key(|latest| latest.timestamp)
+            .map(|latest| latest.reading.measurement.value() * register.scale);
+        for (i, word) in register.r#type.encode(value).into_iter().enumerate() {
+            registers.insert(register.address + i as u16, word);
+        }
+    }
+    registers
+}
+
+// respond answers the request pdu, with an exception response if it can't be.
+fn respond(pdu: &[u8], registers: &BTreeMap<u16, u16>) -> Vec<u8> {
+    let function = pdu.first().copied().unwrap_or_default();
+    let exception = |code| vec![function | 0x80, code];
+    if function != READ_HOLDING_REGISTERS && function != READ_INPUT_REGISTERS {
+        return exception(ILLEGAL_FUNCTION);
+    }
+    let [_, a, b, c, d] = pdu else {
+        return exception(ILLEGAL_DATA_VALUE);
+    };
+    let start = u16::from_be_bytes([*a, *b]);
+    let count = u16::from_be_bytes([*c, *d]);
+    if !(1..=MAX_REGISTERS).contains(&count) {
+        return exception(ILLEGAL_DATA_VALUE);
+    }
+    let Some(end) = start.checked_add(count - 1) else {
+        return exception(ILLEGAL_DATA_ADDRESS);
+    };
+    if registers.range(start..=end).next().is_none() {
+        return exception(ILLEGAL_DATA_ADDRESS);
+    }
+    let mut response = vec![function, (count * 2) as u8];
+    for address in start..=end {
+        let word = registers.get(&address).copied().unwrap_or_default();
+        response.extend(word.to_be_bytes());
+    }
+    response
+}
+
+// validate_registers rejects register maps in which registers overlap.
+pub fn validate_registers(map: &[RegisterConfig]) -> Result<()> {
+    let mut used = BTreeMap::new();
+    for register in map {
+        for i in 0..register.r#type.width() {
+            let address = register
+                .address
+                .checked_add(i)
+                .ok_or_else(|| eyre!("register {} is past the last one", register.address))?;
+            if let Some(other) = used.insert(address, register) {
+                return Err(eyre!(
+                    "register {} is used by both {} {} and {} {}",
+                    address,
+                    other.device,
+                    other.kind,
+                    register.device,
+                    register.kind
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::config::RegisterConfig;
+    use crate::latest::LatestReadings;
+    use crate::modbus::{registers, respond, validate_registers, RegisterType};
+    use crate::{DeviceId, DeviceReading, Measurement};
+
+    #[test]
+    fn test_modbus() {
+        let register = |address, kind: &str, r#type, scale| RegisterConfig {
+            address,
+            device: "Greenhouse".to_string(),
+            kind: kind.to_string(),
+            r#type,
+            scale,
+        };
+        let map = [
+            register(0, "temperature", RegisterType::I16, 10.0),
+            register(1, "humidity", RegisterType::U16, 1.0),
+            register(4, "temperature", RegisterType::F32, 1.0),
+        ];
+        validate_registers(&map).unwrap();
+        assert!(validate_registers(&[
+            register(0, "temperature", RegisterType::I32, 1.0),
+            register(1, "humidity", RegisterType::U16, 1.0),
+        ])
+        .is_err());
+
+        let mut latest = LatestReadings::default();
+        latest.update(Arc::new(DeviceReading {
+            device_id: DeviceId {
+                id: "hci0/modbus".to_string(),
+                device_name: "Greenhouse".to_string(),
+                address: String::new(),
+                random_address: None,
+            },
+            measurement: Measurement::Temperature(-2.5),
+            advertisement: None,
+            quality: Vec::new(),
+        }));
+        let registers = registers(&map, &latest);
+
+        // Registers 0 to 5: -25, no humidity yet, two unmapped, then -2.5 as f32.
+        assert_eq!(
+            respond(&[0x03, 0, 0, 0, 6], &registers),
+            [0x03, 12, 0xff, 0xe7, 0xff, 0xff, 0, 0, 0, 0, 0xc0, 0x20, 0, 0]
+        );
+        assert_eq!(
+            respond(&[0x04, 0, 1, 0, 1], &registers),
+            [0x04, 2, 0xff, 0xff]
+        );
+        assert_eq!(respond(&[0x03, 0, 100, 0, 1], &registers), [0x83, 0x02]);
+        assert_eq!(respond(&[0x06, 0, 0, 0, 1], &registers), [0x86, 0x01]);
+    }
+}
@@ -0,0 +1,218 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use async_stream::stream;
+use futures_core::stream::Stream;
+use ruuvi_sensor_protocol::{Acceleration, AccelerationVector, MovementCounter, SensorValues};
+use tokio::sync::mpsc;
+
+use crate::config::MotionConfig;
+use crate::error::BlueplugError;
+use crate::fanout::devices_match;
+use crate::{DeviceEvent, DeviceReading, Measurement};
+
+// The kind of the readings, 1 as a tag starts moving and 0 once it has been still.
+pub const KIND: &str = "motion";
+
+struct Tag {
+    acceleration: AccelerationVector,
+    counter: Option<u32>,
+    moving: bool,
+    moved: Option<Instant>,
+}
+
+// Motion turns Ruuvi tags into motion or opening sensors, e.g. on a door or a mailbox. A tag moves
+// when its movement counter goes up or its acceleration changes by more than the threshold from
+// one advertisement to the next. It's in motion from then until it has been still for debounce
+// seconds, which is noticed on its next advertisement since tags keep advertising while still.
+pub struct Motion {
+    configs: Vec<MotionConfig>,
+    // By device id.
+    tags: HashMap<String, Tag>,
+}
+
+impl Motion {
+    pub fn new(configs: &[MotionConfig]) -> Self {
+        Motion {
+            configs: configs.to_vec(),
+            tags: HashMap::new(),
+        }
+    }
+
+    // advertisement returns a motion reading if the advertisement is of a configured Ruuvi tag
+    // that started or stopped moving.
+    pub fn advertisement(&mut self, event: &DeviceEvent, now: Instant) -> Option<DeviceReading> {
+        let DeviceEvent::ManufacturerDataAdvertisement {
+            device_id,
+            manufacturer_data,
+            ..
+        } = event
+        else {
+            return None;
+        };
+        let config = self
+            .configs
+            .iter()
+            .find(|config| devices_match(&config.devices, device_id))?;
+        let values = manufacturer_data
+            .iter()
+            .find_map(|(id, data)| SensorValues::from_manufacturer_specific_data(*id, data).ok())?;
+        let acceleration = values.acceleration_vector_as_milli_g()?;
+        let counter = values.movement_counter();
+        let Some(tag) = self.tags.get_mut(&device_id.id) else {
+            let tag = Tag {
+                acceleration,
+                counter,
+                moving: false,
+                moved: None,
+            };
+            self.tags.insert(device_id.id.clone(), tag);
+            return None;
+        };
+
+        let AccelerationVector(x, y, z) = acceleration;
+        let AccelerationVector(last_x, last_y, last_z) = tag.acceleration;
+        let delta = [(x, last_x), (y, last_y), (z, last_z)]
+            .iter()
+            .map(|(a, b)| ((*a as f64 - *b as f64) / 1000.0).powi(2))
+            .sum::<f64>()
+            .sqrt();
+        let moved = delta > config.threshold || (counter.is_some() && counter != tag.counter);
+        tag.acceleration = acceleration;
+        tag.counter = counter;
+        if moved {
+            tag.moved = Some(now);
+        }
+        let debounce = Duration::from_secs(config.debounce);
+        let moving = tag
+            .moved
+            .is_some_and(|moved| now.duration_since(moved) < debounce);
+        if moving == tag.moving {
+            return None;
+        }
+        tag.moving = moving;
+        Some(DeviceReading {
+            device_id: device_id.clone(),
+            measurement: Measurement::Other {
+                kind: KIND.to_string(),
+                value: if moving { 1.0 } else { 0.0 },
+                unit: String::new(),
+            },
+            advertisement: None,
+            quality: Vec::new(),
+        })
+    }
+}
+
+// motion_stream passes advertisements through, sending the motion readings of the Ruuvi tags
+// among them to sender.
+pub fn motion_stream(
+    event_stream: impl Stream<Item = Result<DeviceEvent, BlueplugError>>,
+    mut motion: Motion,
+    sender: mpsc::UnboundedSender<DeviceReading>,
+) -> impl Stream<Item = Result<DeviceEvent, BlueplugError>> {
+    stream! {
+        for await event in event_stream {
+            if let Ok(event) = &event {
+                if let Some(reading) = motion.advertisement(event, Instant::now()) {
+                    // Sending only fails once the readings are no longer wanted.
+                    let _ = sender.send(reading);
+                }
+            }
+            yield event;
+        }
+    }
+}
+
+// motion_readings streams the readings motion_stream sends, to select alongside the decoded ones.
+pub fn motion_readings(
+    mut receiver: mpsc::UnboundedReceiver<DeviceReading>,
+) -> impl Stream<Item = DeviceReading> {
+    stream! {
+        while let Some(reading) = receiver.recv().await {
+            yield reading;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::{Duration, Instant};
+
+    use crate::config::MotionConfig;
+    use crate::motion::Motion;
+    use crate::{DeviceEvent, DeviceId};
+
+    #[test]
+    fn test_motion() {
+        let mut motion = Motion::new(&[MotionConfig {
+            devices: vec!["Ruuvi_MOTION".to_string()],
+            threshold: 0.1,
+            debounce: 30,
+        }]);
+        // A RAWv2 frame with acceleration in mG and the movement counter.
+        let advertisement = |name: &str, z: i16, counter: u8| {
+            let mut data = vec![0x05, 0x12, 0xfc, 0x53, 0x94, 0xc3, 0x7c, 0, 0, 0, 0];
+            data.extend(z.to_be_bytes());
+            data.extend([
+                0xac, 0x36, counter, 0x00, 0xcd, 0xcb, 0xb8, 0x33, 0x4c, 0x88, 0x4f,
+            ]);
+            DeviceEvent::ManufacturerDataAdvertisement {
+                device_id: DeviceId {
+                    id: format!("hci0/{}", name),
+                    device_name: name.to_string(),
+                    address: String::new(),
+                    random_address: None,
+                },
+                manufacturer_data: HashMap::from([(0x0499, data)]),
+                rssi: None,
+            }
+        };
+        let start = Instant::now();
+        let at = |seconds| start + Duration::from_secs(seconds);
+        let value = |reading: Option<crate::DeviceReading>| reading.map(|r| r.measurement.value());
+
+        assert_eq!(
+            value(motion.advertisement(&advertisement("Ruuvi_MOTION", 1000, 7), at(0))),
+            None
+        );
+        // Jitter below the threshold isn't movement.
+        assert_eq!(
+            value(motion.advertisement(&advertisement("Ruuvi_MOTION", 1040, 7), at(1))),
+            None
+        );
+        // The door opens.
+        assert_eq!(
+            value(motion.advertisement(&advertisement("Ruuvi_MOTION", 1300, 7), at(2))),
+            Some(1.0)
+        );
+        assert_eq!(
+            value(motion.advertisement(&advertisement("Ruuvi_MOTION", 1300, 8), at(20))),
+            None
+        );
+        assert_eq!(
+            value(motion.advertisement(&advertisement("Ruuvi_MOTION", 1300, 8), at(49))),
+            None
+        );
+        assert_eq!(
+            value(motion.advertisement(&advertisement("Ruuvi_MOTION", 1300, 8), at(50))),
+            Some(0.0)
+        );
+        // The movement counter alone counts too.
+        assert_eq!(
+            value(motion.advertisement(&advertisement("Ruuvi_MOTION", 1300, 9), at(60))),
+            Some(1.0)
+        );
+
+        // Other tags aren't watched.
+        assert_eq!(
+            value(motion.advertisement(&advertisement("Ruuvi_OTHER", 0, 0), at(0))),
+            None
+        );
+        assert_eq!(
+            value(motion.advertisement(&advertisement("Ruuvi_OTHER", 2000, 1), at(1))),
+            None
+        );
+    }
+}
@@ -0,0 +1,1009 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use jiff::Timestamp;
+use rumqttc::{
+    AsyncClient, ClientError, Event, EventLoop, Key, MqttOptions, Outgoing, Packet, PubAck,
+    PubComp, QoS, TlsConfiguration, Transport,
+};
+use serde_json::{Map, Value};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, Notify};
+use tokio::time::{self, MissedTickBehavior};
+
+use crate::batch::Batcher;
+use crate::clock::{unix_timestamp, CLOCK};
+use crate::config::{BrokerConfig, OutputFormat, TlsConfig};
+use crate::diagnostics::DIAGNOSTICS;
+use crate::encoding::PayloadFormat;
+use crate::envelope::{flag, Envelope};
+use crate::events::is_event;
+use crate::exempt::EXEMPT;
+use crate::health::HEALTH;
+use crate::homeassistant::Discovery;
+use crate::latest::{LatestReading, LatestReadings};
+use crate::metrics::{RateLimited, Stage, METRICS};
+use crate::names::TOPIC_NAMES;
+use crate::outbox::{Outbox, Pending};
+use crate::presence::PresenceChange;
+use crate::ratelimit::{Admitted, RateLimiter};
+use crate::rules::Action;
+use crate::stats::DailyStats;
+use crate::supervisor::Supervisor;
+use crate::theengs::TheengsAggregator;
+use crate::{DeviceId, DeviceReading, Measurement, Quality};
+
+// Readings decoded from one advertisement arrive together; once none have arrived for this long
+// the device's Theengs message is complete.
+const THEENGS_FLUSH_DELAY: Duration = Duration::from_millis(50);
+
+const TELEMETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+const STATS_INTERVAL: Duration = Duration::from_secs(60);
+
+// Reconnection attempts back off exponentially between these delays.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+// Publishes buffered while a broker is unreachable, beyond which the oldest are dropped.
+const BUFFER_CAPACITY: usize = 1000;
+
+// How often readings queued by a rate limit are checked for release.
+const RATE_LIMIT_TICK: Duration = Duration::from_millis(100);
+
+// Bytes a publish takes besides its topic and payload: the fixed header with the longest remaining
+// length, the topic's length and the packet id.
+pub const PUBLISH_OVERHEAD: usize = 9;
+
+// How often buffered messages are offered to the client again while nothing else is sent.
+const DRAIN_INTERVAL: Duration = Duration::from_millis(100);
+
+// spawn_broker starts publishing readings to a single broker. Every broker has its own client,
+// event loop and receiver, so one that is unreachable only falls behind on its own readings.
+pub fn spawn_broker(
+    supervisor: &Supervisor,
+    broker: BrokerConfig,
+    mut readings: broadcast::Receiver<Arc<DeviceReading>>,
+    mut latest: LatestReadings,
+    stats: Option<DailyStats>,
+    mut presence: broadcast::Receiver<Arc<PresenceChange>>,
+    mut actions: broadcast::Receiver<Arc<Action>>,
+) -> Result<()> {
+    let (client, eventloop) = AsyncClient::new(mqtt_options(&broker)?, 10);
+    let (outbox, restored) = match &broker.queue_file {
+        Some(path) => {
+            let (outbox, restored) = Outbox::open(&broker.name, path, broker.queue_file_max_size)
+                .wrap_err_with(|| format!("broker {}", broker.name))?;
+            (Some(outbox), restored)
+        }
+        None => (None, Vec::new()),
+    };
+
+    // The connection is shared with every start of its task, so one restarted after a panic
+    // carries on with the same event loop.
+    let connection = Arc::new(tokio::sync::Mutex::new(Connection::new(
+        &broker.name,
+        eventloop,
+    )));
+    let connected = Arc::new(Notify::new());
+    let name = broker.name.clone();
+    let notify = connected.clone();
+    let journal = outbox.clone();
+    supervisor.spawn(format!("{} connection", name), move || {
+        let connection = connection.clone();
+        let name = name.clone();
+        let notify = notify.clone();
+        let journal = journal.clone();
+        async move {
+            let mut connection = connection.lock().await;
+            loop {
+                match connection.next().await {
+                    Event::Incoming(Packet::ConnAck(_)) => notify.notify_one(),
+                    Event::Outgoing(Outgoing::Publish(pkid)) => {
+                        METRICS.written(&name, pkid);
+                        if let Some(journal) = &journal {
+                            journal.lock().unwrap().outgoing(pkid);
+                        }
+                    }
+                    Event::Incoming(
+                        Packet::PubAck(PubAck { pkid, .. }) | Packet::PubComp(PubComp { pkid, .. }),
+                    ) => {
+                        METRICS.acknowledged(&name, pkid);
+                        if let Some(journal) = &journal {
+                            if let Err(e) = journal.lock().unwrap().acknowledged(pkid) {
+                                println!("{}: journaling failed: {:?}", name, e);
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+    });
+
+    let mut publisher = Publisher::new(client, &broker, stats, outbox, restored);
+    let mut telemetry = time::interval(TELEMETRY_INTERVAL);
+    let mut stats_interval = time::interval(STATS_INTERVAL);
+    let mut diagnostics = DIAGNOSTICS.subscribe();
+    let mut batch_interval =
+        time::interval(Duration::from_secs(broker.batch_interval.unwrap_or(1)));
+    let mut rate_limit_interval = time::interval(RATE_LIMIT_TICK);
+    rate_limit_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut drain_interval = time::interval(DRAIN_INTERVAL);
+    drain_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let shutdown = supervisor.clone();
+    supervisor.spawn_graceful(broker.name.clone(), async move {
+        loop {
+            tokio::select! {
+                received = readings.recv() => {
+                    let reading = match received {
+                        Ok(reading) => reading,
+                        Err(RecvError::Lagged(skipped)) => {
+                            println!(
+                                "{}: falling behind, dropped {} readings",
+                                broker.name, skipped
+                            );
+                            continue;
+                        }
+                        Err(RecvError::Closed) => break,
+                    };
+                    publisher.live(&reading).await;
+                    latest.update(reading);
+                }
+                _ = time::sleep(THEENGS_FLUSH_DELAY), if publisher.is_pending() => {
+                    publisher.flush().await;
+                }
+                _ = rate_limit_interval.tick(), if publisher.is_rate_limited() => {
+                    publisher.release().await;
+                }
+                Ok(diagnostic) = diagnostics.recv(), if !broker.diagnostics_topic.is_empty() => {
+                    if let Ok(payload) = serde_json::to_value(diagnostic.as_ref()) {
+                        publisher.send(broker.diagnostics_topic.clone(), payload, false).await;
+                    }
+                }
+                Ok(change) = presence.recv() => {
+                    publisher.presence(&change).await;
+                }
+                Ok(action) = actions.recv() => {
+                    if action.brokers.is_empty() || action.brokers.contains(&broker.name) {
+                        publisher
+                            .send_raw(action.topic.clone(), action.payload.clone(), action.retain)
+                            .await;
+                    }
+                }
+                _ = batch_interval.tick(), if publisher.batch.is_some() => {
+                    publisher.flush_batch().await;
+                }
+                _ = drain_interval.tick(), if publisher.is_buffered() => {
+                    publisher.drain();
+                }
+                _ = stats_interval.tick(), if publisher.stats.is_some() => {
+                    publisher.stats().await;
+                }
+                _ = telemetry.tick(), if broker.telemetry_topic.is_some() => {
+                    publisher.telemetry(broker.telemetry_topic.as_deref().unwrap_or_default()).await;
+                }
+                // (Re)connecting first sends what was buffered while the broker was unreachable.
+                // A broker that restarted may have lost retained state, so it then announces
+                // devices to Home Assistant again, and republishes the latest reading of every
+                // device and kind, or without retain, of the sparse kinds that are retained anyway.
+                _ = connected.notified() => {
+                    publisher.resume().await;
+                    if let Some(discovery) = &mut publisher.discovery {
+                        discovery.reset();
+                    }
+                    for reading in latest.readings() {
+                        if broker.retain || reading.reading.measurement.is_sparse() {
+                            publisher.latest(&reading).await;
+                        }
+                    }
+                    publisher.flush().await;
+                }
+                // Readings still held in a Theengs message or a batch are handed off on shutdown,
+                // so that those at QoS 1 or 2 are journaled to the queue_file.
+                _ = shutdown.cancelled() => {
+                    publisher.flush().await;
+                    publisher.flush_batch().await;
+                    break;
+                }
+            }
+        }
+    });
+
+    Ok(())
+}
+
+// Message is a payload ready to publish, and the device it describes. The payload is encoded in
+// the broker's payload format when it is sent.
+pub struct Message {
+    pub device_id: DeviceId,
+    pub topic: String,
+    pub payload: Value,
+}
+
+// Publisher turns readings into messages in the broker's output format.
+struct Publisher {
+    client: AsyncClient,
+    name: String,
+    qos: QoS,
+    alert_qos: u8,
+    retain: bool,
+    max_packet_size: usize,
+    format: OutputFormat,
+    payload_format: PayloadFormat,
+    topic_prefix: String,
+    theengs: TheengsAggregator,
+    stats: Option<DailyStats>,
+    buffer: VecDeque<Pending>,
+    outbox: Option<Arc<Mutex<Outbox>>>,
+    discovery: Option<Discovery>,
+    batch: Option<Batcher>,
+    limiter: Option<RateLimiter<(Message, Option<Instant>)>>,
+}
+
+impl Publisher {
+    fn new(
+        client: AsyncClient,
+        broker: &BrokerConfig,
+        stats: Option<DailyStats>,
+        outbox: Option<Arc<Mutex<Outbox>>>,
+        restored: Vec<Pending>,
+    ) -> Self {
+        if !restored.is_empty() {
+            println!(
+                "{}: {} messages weren't acknowledged before the last shutdown",
+                broker.name,
+                restored.len()
+            );
+            HEALTH.buffered(&broker.name, restored.len());
+        }
+        Publisher {
+            client,
+            name: broker.name.clone(),
+            qos: qos(broker.qos),
+            alert_qos: broker.alert_qos.unwrap_or(broker.qos),
+            retain: broker.retain,
+            max_packet_size: broker.max_packet_size,
+            format: broker.format,
+            payload_format: broker.payload_format,
+            topic_prefix: broker.topic_prefix().to_string(),
+            theengs: TheengsAggregator::default(),
+            stats,
+            buffer: restored.into(),
+            outbox,
+            discovery: broker.homeassistant.as_ref().map(Discovery::new),
+            batch: broker.batch_interval.map(|_| Batcher::default()),
+            limiter: broker.rate_limit.as_ref().map(RateLimiter::new),
+        }
+    }
+
+    fn is_pending(&self) -> bool {
+        self.theengs.is_pending()
+    }
+
+    fn is_buffered(&self) -> bool {
+        !self.buffer.is_empty()
+    }
+
+    fn is_rate_limited(&self) -> bool {
+        self.limiter.as_ref().is_some_and(RateLimiter::is_queued)
+    }
+
+    // live publishes a reading as it arrives, counting it towards the daily stats. Readings
+    // republished on reconnecting were counted when they arrived.
+    async fn live(&mut self, reading: &DeviceReading) {
+        if let Some(stats) = &mut self.stats {
+            stats.update(reading, Timestamp::now());
+        }
+        self.reading(reading).await;
+    }
+
+    async fn reading(&mut self, reading: &DeviceReading) {
+        // Only envelopes carry a timestamp.
+        let stamped = (self.format == OutputFormat::Envelope).then(Instant::now);
+        let message = match self.format {
+            OutputFormat::Envelope => {
+                let envelope = Envelope::new(reading, unix_timestamp(), Map::new());
+                serde_json::to_value(&envelope)
+                    .ok()
+                    .map(|payload| self.message(reading, payload))
+            }
+            OutputFormat::Flat => serde_json::to_value(reading)
+                .ok()
+                .map(|payload| self.message(reading, payload)),
+            OutputFormat::Theengs => self.theengs.push(&self.topic_prefix, reading),
+        };
+        let urgency = self.urgency(&reading.measurement);
+        if let Some(message) = message {
+            self.announce(reading, &message.topic).await;
+            match urgency {
+                Some((retain, qos)) if self.format != OutputFormat::Theengs => {
+                    self.send_urgent(message, stamped, retain, qos).await
+                }
+                _ => self.publish(message, stamped).await,
+            }
+        }
+        // A Theengs message holds all of a device's readings so far, which an urgent one sends
+        // right away instead of waiting for the next device's readings.
+        if let (Some((retain, qos)), OutputFormat::Theengs) = (urgency, self.format) {
+            if let Some(message) = self.theengs.flush(&self.topic_prefix) {
+                self.send_urgent(message, None, retain, qos).await;
+            }
+        }
+    }
+
+    // urgency tells whether a reading is sent as soon as it is read, neither held up in a batch
+    // nor by the rate limit, and if so whether it is retained and at which QoS. An event triggers
+    // automations as it happens, so it is never retained. Alerts are sent at the broker's
+    // alert_qos, and retained anyway if sparse, and other exempt kinds like any reading.
+    fn urgency(&self, measurement: &Measurement) -> Option<(bool, Option<u8>)> {
+        if is_event(measurement) {
+            Some((false, None))
+        } else if measurement.is_alert() {
+            let retain = self.retain || measurement.is_sparse();
+            Some((retain, Some(self.alert_qos)))
+        } else {
+            EXEMPT
+                .contains(measurement.kind())
+                .then_some((self.retain, None))
+        }
+    }
+
+    async fn send_urgent(
+        &mut self,
+        message: Message,
+        stamped: Option<Instant>,
+        retain: bool,
+        qos: Option<u8>,
+    ) {
+        let readings = Some((message.device_id, 1));
+        self.send_stamped(
+            message.topic,
+            message.payload,
+            retain,
+            stamped,
+            qos,
+            readings,
+        )
+        .await;
+    }
+
+    // latest republishes a remembered reading, along with when it was seen and whether it predates
+    // a restart.
+    async fn latest(&mut self, latest: &LatestReading) {
+        let payload = match self.format {
+            OutputFormat::Envelope => {
+                let meta = Map::from_iter([("is_stale".to_string(), latest.is_stale.into())]);
+                serde_json::to_value(Envelope::new(&latest.reading, latest.timestamp, meta))
+            }
+            OutputFormat::Flat => serde_json::to_value(latest),
+            OutputFormat::Theengs => return self.reading(&latest.reading).await,
+        };
+        if let Ok(payload) = payload {
+            let message = self.message(&latest.reading, payload);
+            self.announce(&latest.reading, &message.topic).await;
+            let stamped = latest
+                .received
+                .filter(|_| self.format == OutputFormat::Envelope);
+            match self.urgency(&latest.reading.measurement) {
+                Some((retain, qos)) => self.send_urgent(message, stamped, retain, qos).await,
+                None => self.publish(message, stamped).await,
+            }
+        }
+    }
+
+    // announce publishes the Home Assistant discovery message for a reading's device and kind,
+    // ahead of its first reading.
+    async fn announce(&mut self, reading: &DeviceReading, state_topic: &str) {
+        let messages = match &mut self.discovery {
+            Some(discovery) => discovery.announce(reading, state_topic, self.format),
+            None => return,
+        };
+        for message in messages {
+            self.send(message.topic, message.payload, true).await;
+        }
+    }
+
+    // presence publishes a device's presence, announcing it to Home Assistant first.
+    async fn presence(&mut self, change: &PresenceChange) {
+        let announcement = match &mut self.discovery {
+            Some(discovery) => discovery.presence(change),
+            None => None,
+        };
+        if let Some((topic, payload)) = announcement {
+            self.send(topic, payload, true).await;
+        }
+        if let Ok(payload) = serde_json::to_value(change) {
+            self.send(change.topic.clone(), payload, true).await;
+        }
+    }
+
+    fn message(&self, reading: &DeviceReading, payload: Value) -> Message {
+        let topic = format!(
+            "{}/{}/{}",
+            TOPIC_NAMES.expand_location(&self.topic_prefix, &reading.device_id),
+            reading.measurement.kind(),
+            TOPIC_NAMES.topic_name(&reading.device_id)
+        );
+        Message {
+            device_id: reading.device_id.clone(),
+            topic,
+            payload,
+        }
+    }
+
+    async fn flush(&mut self) {
+        if let Some(message) = self.theengs.flush(&self.topic_prefix) {
+            self.publish(message, None).await;
+        }
+    }
+
+    // flush_batch publishes each device's batch of readings.
+    async fn flush_batch(&mut self) {
+        let messages = match &mut self.batch {
+            Some(batch) => batch.flush(
+                &self.topic_prefix,
+                self.max_packet_size,
+                self.payload_format,
+            ),
+            None => return,
+        };
+        for message in messages {
+            let readings = message.payload.as_array().map_or(1, Vec::len);
+            let readings = Some((message.device_id, readings));
+            self.send_stamped(
+                message.topic,
+                message.payload,
+                self.retain,
+                None,
+                None,
+                readings,
+            )
+            .await;
+        }
+    }
+
+    // stats publishes the daily stats that changed since it was last called. They are always
+    // retained, so dashboards show the day so far as soon as they subscribe.
+    async fn stats(&mut self) {
+        let messages = match &mut self.stats {
+            Some(stats) => stats.messages(),
+            None => return,
+        };
+        for message in messages {
+            self.send(message.topic, message.payload, true).await;
+        }
+    }
+
+    // telemetry publishes the counters, the health of every broker on {topic}/health, and their
+    // publish queues and latency on {topic}/publishing.
+    async fn telemetry(&mut self, topic: &str) {
+        if let Ok(payload) = serde_json::to_value(METRICS.snapshot()) {
+            self.send(topic.to_string(), payload, false).await;
+        }
+        if let Ok(payload) = serde_json::to_value(HEALTH.snapshot()) {
+            self.send(format!("{}/health", topic), payload, false).await;
+        }
+        if let Ok(payload) = serde_json::to_value(METRICS.publishing()) {
+            self.send(format!("{}/publishing", topic), payload, false)
+                .await;
+        }
+    }
+
+    // publish sends a reading's message, or adds it to the device's batch. An envelope's payload
+    // is stamped with the monotonic instant its timestamp was taken, so it can be corrected while
+    // buffered.
+    async fn publish(&mut self, message: Message, stamped: Option<Instant>) {
+        if let Some(batch) = &mut self.batch {
+            return batch.push(message);
+        }
+        let (message, stamped) = match &mut self.limiter {
+            Some(limiter) => {
+                let device = message.device_id.id.clone();
+                match limiter.admit(&device, (message, stamped), Instant::now()) {
+                    Admitted::Send(admitted) => admitted,
+                    Admitted::Queued => {
+                        return METRICS.rate_limited(&self.name, RateLimited::Queued)
+                    }
+                    Admitted::Dropped => {
+                        return METRICS.rate_limited(&self.name, RateLimited::Dropped)
+                    }
+                }
+            }
+            None => (message, stamped),
+        };
+        self.send_reading(message, stamped).await;
+    }
+
+    // release publishes the readings a rate limit queued that it now allows.
+    async fn release(&mut self) {
+        let released = match &mut self.limiter {
+            Some(limiter) => limiter.release(Instant::now()),
+            None => return,
+        };
+        for (message, stamped) in released {
+            self.send_reading(message, stamped).await;
+        }
+    }
+
+    async fn send_reading(&mut self, message: Message, stamped: Option<Instant>) {
+        let readings = Some((message.device_id, 1));
+        self.send_stamped(
+            message.topic,
+            message.payload,
+            self.retain,
+            stamped,
+            None,
+            readings,
+        )
+        .await;
+    }
+
+    // send encodes payload in the broker's payload format and publishes it. While the broker's
+    // circuit is open, or the client's queue is full because it is waiting to reconnect, the
+    // message is buffered instead, behind anything buffered earlier.
+    async fn send(&mut self, topic: String, payload: Value, retain: bool) {
+        self.send_stamped(topic, payload, retain, None, None, None)
+            .await
+    }
+
+    // send_stamped sends a reading taken at stamped, at qos if it isn't the broker's. Messages
+    // carrying readings count them as published once they are handed to the client.
+    async fn send_stamped(
+        &mut self,
+        topic: String,
+        payload: Value,
+        retain: bool,
+        stamped: Option<Instant>,
+        qos: Option<u8>,
+        readings: Option<(DeviceId, usize)>,
+    ) {
+        let bytes = match self.payload_format.encode(&payload) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                println!("{}: encoding {} failed: {:?}", self.name, topic, e);
+                return;
+            }
+        };
+        let shown = payload.to_string();
+        let message = Pending {
+            seq: None,
+            topic,
+            retain,
+            qos,
+            payload: bytes,
+            stamped: None,
+            readings,
+        };
+        self.enqueue(message, &shown, stamped.map(|stamped| (payload, stamped)));
+    }
+
+    // send_raw publishes payload as it is, whatever the broker's payload format, for messages
+    // meant for other devices rather than for consumers of readings.
+    async fn send_raw(&mut self, topic: String, payload: String, retain: bool) {
+        let message = Pending {
+            seq: None,
+            topic,
+            retain,
+            qos: None,
+            payload: payload.clone().into_bytes(),
+            stamped: None,
+            readings: None,
+        };
+        self.enqueue(message, &payload, None);
+    }
+
+    // enqueue publishes an encoded message, shown as shown in the log, or buffers it. Whatever was
+    // buffered earlier is offered to the client first, so one full queue doesn't leave every
+    // later message buffered until the broker reconnects.
+    fn enqueue(&mut self, mut message: Pending, shown: &str, stamped: Option<(Value, Instant)>) {
+        // The client would give up on the connection over a packet the broker may not take.
+        let size = PUBLISH_OVERHEAD + message.topic.len() + message.payload.len();
+        if size > self.max_packet_size {
+            println!(
+                "{}: dropped {}, {} bytes is more than max_packet_size",
+                self.name, message.topic, size
+            );
+            return HEALTH.error(&self.name, format!("dropped a {} byte message", size));
+        }
+        if let (Some(outbox), false) = (&self.outbox, self.qos_of(&message) == QoS::AtMostOnce) {
+            if let Err(e) = outbox.lock().unwrap().add(&mut message) {
+                println!(
+                    "{}: journaling {} failed: {:?}",
+                    self.name, message.topic, e
+                );
+                HEALTH.error(&self.name, format!("journaling failed: {}", e));
+            }
+        }
+        self.drain();
+        if self.buffer.is_empty() && !HEALTH.is_open(&self.name) {
+            match self.try_publish(&message) {
+                Ok(()) => return println!("{}: published {}", self.name, shown),
+                Err(ClientError::TryRequest(_)) => {}
+                Err(e) => return HEALTH.error(&self.name, format!("publishing failed: {}", e)),
+            }
+        }
+        if self.buffer.len() == BUFFER_CAPACITY {
+            if let Some(seq) = self.buffer.pop_front().and_then(|dropped| dropped.seq) {
+                self.done(seq);
+            }
+            HEALTH.error(
+                &self.name,
+                "buffer full, dropped the oldest message".to_string(),
+            );
+        }
+        message.stamped = stamped;
+        self.buffer.push_back(message);
+        HEALTH.buffered(&self.name, self.buffer.len());
+    }
+
+    // drain publishes what was buffered, oldest first, for as long as the client has room for it.
+    fn drain(&mut self) {
+        if self.buffer.is_empty() || HEALTH.is_open(&self.name) {
+            return;
+        }
+        while let Some(mut message) = self.buffer.pop_front() {
+            self.restamp(&mut message);
+            if self.try_publish(&message).is_err() {
+                self.buffer.push_front(message);
+                break;
+            }
+        }
+        HEALTH.buffered(&self.name, self.buffer.len());
+    }
+
+    // try_publish hands a message to the client unless its queue is full.
+    fn try_publish(&self, message: &Pending) -> Result<(), ClientError> {
+        self.queued(message, true);
+        let published = self.client.try_publish(
+            message.topic.clone(),
+            self.qos_of(message),
+            message.retain,
+            message.payload.clone(),
+        );
+        match published {
+            Ok(()) => self.handed_off(message),
+            Err(_) => self.queued(message, false),
+        }
+        published
+    }
+
+    // handed_off counts a message handed to the client, and the readings it carries as published.
+    fn handed_off(&self, message: &Pending) {
+        METRICS.handed_off(&self.name);
+        if let Some((device_id, readings)) = &message.readings {
+            for _ in 0..*readings {
+                METRICS.reading(Stage::Published, device_id);
+            }
+        }
+    }
+
+    // resume publishes what was buffered, oldest first.
+    async fn resume(&mut self) {
+        if !self.buffer.is_empty() {
+            println!(
+                "{}: sending {} buffered messages",
+                self.name,
+                self.buffer.len()
+            );
+        }
+        while let Some(mut message) = self.buffer.pop_front() {
+            self.restamp(&mut message);
+            self.queued(&message, true);
+            let published = self
+                .client
+                .publish(
+                    message.topic.clone(),
+                    self.qos_of(&message),
+                    message.retain,
+                    message.payload.clone(),
+                )
+                .await;
+            if published.is_err() {
+                self.queued(&message, false);
+                self.buffer.push_front(message);
+                break;
+            }
+            self.handed_off(&message);
+        }
+        HEALTH.buffered(&self.name, self.buffer.len());
+    }
+
+    // restamp corrects the timestamp of a buffered envelope if the wall clock jumped since it was
+    // taken, e.g. when NTP first synced after booting.
+    fn restamp(&self, message: &mut Pending) {
+        let Some((payload, stamped)) = &mut message.stamped else {
+            return;
+        };
+        let Some(timestamp) = payload.get("timestamp").and_then(Value::as_u64) else {
+            return;
+        };
+        let restamped = CLOCK.restamp(timestamp, *stamped);
+        if restamped == timestamp {
+            return;
+        }
+        payload["timestamp"] = restamped.into();
+        if let Some(meta) = payload.get_mut("meta").and_then(Value::as_object_mut) {
+            flag(meta, Quality::AfterClockJump);
+        }
+        match self.payload_format.encode(payload) {
+            Ok(bytes) => message.payload = bytes,
+            Err(e) => println!("{}: encoding {} failed: {:?}", self.name, message.topic, e),
+        }
+    }
+
+    // qos_of is the QoS a message goes out at.
+    fn qos_of(&self, message: &Pending) -> QoS {
+        message.qos.map_or(self.qos, qos)
+    }
+
+    // queued tells the outbox a journaled message is about to be handed to the client, or with
+    // false that the client didn't take it after all.
+    fn queued(&self, message: &Pending, queued: bool) {
+        if let (Some(outbox), Some(seq)) = (&self.outbox, message.seq) {
+            let mut outbox = outbox.lock().unwrap();
+            if queued {
+                outbox.queued(seq);
+            } else {
+                outbox.unqueued();
+            }
+        }
+    }
+
+    fn done(&self, seq: u64) {
+        if let Some(outbox) = &self.outbox {
+            if let Err(e) = outbox.lock().unwrap().done(seq) {
+                println!("{}: journaling failed: {:?}", self.name, e);
+            }
+        }
+    }
+}
+
+pub fn mqtt_options(broker: &BrokerConfig) -> Result<MqttOptions> {
+    let mut mqttoptions = MqttOptions::new(&broker.client_id, &broker.host, broker.port);
+    mqttoptions.set_keep_alive(Duration::from_secs(broker.keep_alive));
+    mqttoptions.set_clean_session(!broker.persistent_session);
+    mqttoptions.set_max_packet_size(broker.max_packet_size, broker.max_packet_size);
+    if let Some(username) = &broker.username {
+        mqttoptions.set_credentials(username, broker.password.clone().unwrap_or_default());
+    }
+    if let Some(tls) = &broker.tls {
+        mqttoptions.set_transport(Transport::tls_with_config(
+            tls_configuration(tls).wrap_err_with(|| format!("broker {}", broker.name))?,
+        ));
+    }
+    Ok(mqttoptions)
+}
+
+// Connection drives a client's event loop, reporting its state to HEALTH under name. A failed
+// connection is retried after a backoff rather than straight away, so an unreachable broker isn't
+// hammered with attempts.
+pub struct Connection {
+    name: String,
+    eventloop: EventLoop,
+    failures: u32,
+}
+
+impl Connection {
+    pub fn new(name: &str, eventloop: EventLoop) -> Self {
+        HEALTH.connecting(name);
+        Connection {
+            name: name.to_string(),
+            eventloop,
+            failures: 0,
+        }
+    }
+
+    // next returns the next event, reconnecting as often as it takes.
+    pub async fn next(&mut self) -> Event {
+        loop {
+            match self.eventloop.poll().await {
+                Ok(event) => {
+                    if let Event::Incoming(Packet::ConnAck(_)) = event {
+                        if self.failures > 0 {
+                            println!(
+                                "{}: reconnected after {} attempts",
+                                self.name, self.failures
+                            );
+                        }
+                        self.failures = 0;
+                        HEALTH.connected(&self.name);
+                    }
+                    return event;
+                }
+                Err(e) => {
+                    self.failures += 1;
+                    let delay = backoff(self.failures, fastrand::f64());
+                    HEALTH.failed(&self.name, self.failures, delay, e.to_string());
+                    println!("{}: error {:?}, retrying in {:?}", self.name, e, delay);
+                    time::sleep(delay).await;
+                }
+            }
+        }
+    }
+}
+
+// backoff is the delay before attempt failures + 1, e.g. to reconnect: exponential up to
+// MAX_BACKOFF, with jitter (0..1) spreading it over its upper half so clients don't retry in
+// lockstep.
+pub fn backoff(failures: u32, jitter: f64) -> Duration {
+    let delay = INITIAL_BACKOFF
+        .saturating_mul(2u32.saturating_pow(failures.saturating_sub(1)))
+        .min(MAX_BACKOFF);
+    delay.mul_f64(0.5 + jitter / 2.0)
+}
+
+fn qos(qos: u8) -> QoS {
+    match qos {
+        0 => QoS::AtMostOnce,
+        2 => QoS::ExactlyOnce,
+        _ => QoS::AtLeastOnce,
+    }
+}
+
+fn tls_configuration(tls: &TlsConfig) -> Result<TlsConfiguration> {
+    let Some(ca_file) = &tls.ca_file else {
+        if tls.client_cert.is_some() {
+            return Err(eyre!("client certificates require an explicit ca_file"));
+        }
+        return Ok(TlsConfiguration::default());
+    };
+
+    let ca = std::fs::read(ca_file).wrap_err_with(|| format!("reading {}", ca_file.display()))?;
+    let client_auth = match (&tls.client_cert, &tls.client_key) {
+        (Some(cert), Some(key)) => Some((
+            std::fs::read(cert).wrap_err_with(|| format!("reading {}", cert.display()))?,
+            private_key(std::fs::read(key).wrap_err_with(|| format!("reading {}", key.display()))?),
+        )),
+        _ => None,
+    };
+
+    Ok(TlsConfiguration::Simple {
+        ca,
+        alpn: None,
+        client_auth,
+    })
+}
+
+// rumqttc reads Key::RSA as PKCS#1 and Key::ECC as PKCS#8, which the PEM header tells apart.
+fn private_key(pem: Vec<u8>) -> Key {
+    if String::from_utf8_lossy(&pem).contains("BEGIN RSA PRIVATE KEY") {
+        Key::RSA(pem)
+    } else {
+        Key::ECC(pem)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use rumqttc::{AsyncClient, Request};
+    use serde_json::json;
+    use tokio::time;
+
+    use crate::config::{BrokerConfig, StatsConfig};
+    use crate::health::{BrokerState, HEALTH};
+    use crate::latest::LatestReadings;
+    use crate::mqtt::{backoff, mqtt_options, Connection, Publisher};
+    use crate::stats::DailyStats;
+    use crate::{DeviceId, DeviceReading, Measurement};
+
+    #[tokio::test]
+    async fn test_buffer_drains() {
+        let broker: BrokerConfig =
+            toml::from_str("name = \"drain\"\nhost = \"localhost\"\nclient_id = \"drain\"")
+                .unwrap();
+        // A client whose queue holds a single request, which the test takes off it in place of
+        // the event loop.
+        let (requests, queue) = flume::bounded(1);
+        let mut publisher = Publisher::new(
+            AsyncClient::from_senders(requests),
+            &broker,
+            None,
+            None,
+            Vec::new(),
+        );
+        let sent = || match queue.try_recv() {
+            Ok(Request::Publish(publish)) => Some(publish.topic),
+            _ => None,
+        };
+
+        for topic in ["a", "b", "c"] {
+            publisher.send(topic.to_string(), json!({}), false).await;
+        }
+        assert_eq!(publisher.buffer.len(), 2);
+        assert_eq!(sent().as_deref(), Some("a"));
+        assert_eq!(sent(), None);
+
+        // Once the queue has room again, the next message sends the oldest buffered one first.
+        publisher.send("d".to_string(), json!({}), false).await;
+        assert_eq!(sent().as_deref(), Some("b"));
+        assert_eq!(publisher.buffer.len(), 2);
+
+        // And with nothing else to send, draining gets the rest out without a reconnect.
+        publisher.drain();
+        assert_eq!(sent().as_deref(), Some("c"));
+        publisher.drain();
+        assert_eq!(sent().as_deref(), Some("d"));
+        assert!(publisher.buffer.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_theengs_reconnect_keeps_stats() {
+        let broker: BrokerConfig = toml::from_str(
+            "name = \"stats\"\nhost = \"localhost\"\nclient_id = \"stats\"\nformat = \"theengs\"",
+        )
+        .unwrap();
+        let stats = DailyStats::new(
+            &StatsConfig {
+                kinds: Vec::new(),
+                time_zone: None,
+                topic_prefix: "blueplug".to_string(),
+            },
+            Some("UTC"),
+        )
+        .unwrap();
+        let (requests, _queue) = flume::bounded(10);
+        let mut publisher = Publisher::new(
+            AsyncClient::from_senders(requests),
+            &broker,
+            Some(stats),
+            None,
+            Vec::new(),
+        );
+        let reading = Arc::new(DeviceReading {
+            device_id: DeviceId {
+                id: "hci0/a".to_string(),
+                device_name: "ATC_1".to_string(),
+                address: String::new(),
+                random_address: None,
+            },
+            measurement: Measurement::Temperature(21.0),
+            advertisement: None,
+            quality: Vec::new(),
+        });
+        let mut latest = LatestReadings::default();
+
+        publisher.live(&reading).await;
+        latest.update(reading);
+        let messages = publisher.stats.as_mut().unwrap().messages();
+        assert_eq!(messages[0].payload["count"], 1);
+
+        // Republishing the latest readings on reconnecting leaves the stats as they were.
+        for reading in latest.readings() {
+            publisher.latest(&reading).await;
+        }
+        assert!(publisher.stats.as_mut().unwrap().messages().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_connection_backs_off() {
+        let broker: BrokerConfig = toml::from_str(
+            "name = \"refused\"\nhost = \"127.0.0.1\"\nport = 1\nclient_id = \"refused\"",
+        )
+        .unwrap();
+        let (_client, eventloop) = AsyncClient::new(mqtt_options(&broker).unwrap(), 10);
+        let mut connection = Connection::new(&broker.name, eventloop);
+
+        // Nothing listens on the port, so after the first refusal the connection waits at least
+        // half a second before trying again.
+        let next = time::timeout(Duration::from_millis(300), connection.next()).await;
+        assert!(next.is_err());
+        let health = &HEALTH.snapshot()["refused"];
+        assert_eq!(health.state, BrokerState::Retrying);
+        assert_eq!(health.failures, 1);
+    }
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(1, 1.0), Duration::from_secs(1));
+        assert_eq!(backoff(3, 1.0), Duration::from_secs(4));
+        assert_eq!(backoff(3, 0.0), Duration::from_secs(2));
+        assert_eq!(backoff(10, 1.0), Duration::from_secs(60));
+        assert_eq!(backoff(100, 0.5), Duration::from_secs(45));
+    }
+}
@@ -0,0 +1,398 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ptr;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use regex::Regex;
+
+use crate::config::{LabelConfig, NameConfig};
+use crate::fanout::devices_match;
+use crate::scope;
+use crate::DeviceId;
+
+// TOPIC_NAMES gives each device the name its topics are built from. Devices are usually named
+// after part of their address, but two can still share a name, say two sensors given the same
+// alias or models that all advertise one default name, and their readings would interleave on
+// the same topics. The first device heard keeps the name; later ones get the last six hex digits
+// of their address appended, with a warning. Which device is first can change across restarts.
+// A device heard through several adapters or proxies has an id for each, but one address, and
+// keeps one name. Name rules may first derive a cleaner name from the advertised one. Names of
+// devices not heard for NAME_EXPIRY are forgotten, so rotating private addresses don't pile up.
+// An embedded Blueplug's tasks name devices with names of their own, see Scope.
+pub static TOPIC_NAMES: LazyLock<TopicNames> = LazyLock::new(TopicNames::default);
+
+#[derive(Default)]
+pub struct TopicNames {
+    inner: Mutex<Inner>,
+}
+
+// NAME_EXPIRY is how long a device keeps its topic name after it was last heard.
+const NAME_EXPIRY: Duration = Duration::from_secs(3600);
+
+#[derive(Default)]
+struct Inner {
+    // Topic name by device id, and when it was last asked for.
+    names: HashMap<String, (String, Instant)>,
+    last_prune: Option<Instant>,
+    // The device, as its id and address, by topic name.
+    owners: HashMap<String, (String, String)>,
+    rules: NameRules,
+    // The devices, by name pattern or id, in each location.
+    locations: BTreeMap<String, Vec<String>>,
+    labels: Vec<LabelConfig>,
+}
+
+// LOCATION_UNASSIGNED stands in for the location of devices that have none, in topics.
+pub const LOCATION_UNASSIGNED: &str = "unassigned";
+
+// NameRules derive a display name and a room from the names devices advertise, for firmwares
+// that put the MAC address or the sensor's role in it, such as `ATC_AABBCC` or `Kitchen-TH`. The
+// first rule whose pattern matches the whole name applies, its name and room templates expanded
+// with the pattern's captures (`$1`, `${room}`).
+#[derive(Default)]
+pub struct NameRules {
+    rules: Vec<(Regex, NameConfig)>,
+}
+
+impl NameRules {
+    pub fn new(config: &[NameConfig]) -> Result<Self> {
+        let rules = config
+            .iter()
+            .map(|rule| {
+                if rule.name.is_none() && rule.room.is_none() {
+                    return Err(eyre!(
+                        "name rule {:?}: at least one of name and room must be given",
+                        rule.pattern
+                    ));
+                }
+                let pattern = Regex::new(&format!("^(?:{})$", rule.pattern))
+                    .wrap_err_with(|| format!("name rule {:?}", rule.pattern))?;
+                Ok((pattern, rule.clone()))
+            })
+            .collect::<Result<_>>()?;
+        Ok(NameRules { rules })
+    }
+
+    // apply returns the name and room the first matching rule gives device_name. Either is None
+    // if the rule doesn't give it or it expands to nothing.
+    fn apply(&self, device_name: &str) -> (Option<String>, Option<String>) {
+        for (pattern, rule) in &self.rules {
+            let Some(captures) = pattern.captures(device_name) else {
+                continue;
+            };
+            let expand = |template: &Option<String>| {
+                let mut expanded = String::new();
+                captures.expand(template.as_deref()?, &mut expanded);
+                (!expanded.is_empty()).then_some(expanded)
+            };
+            return (expand(&rule.name), expand(&rule.room));
+        }
+        (None, None)
+    }
+}
+
+impl TopicNames {
+    // with calls f with the names of the embedded Blueplug whose task is running, if any, in place
+    // of TOPIC_NAMES', so that instances don't rename each other's devices.
+    fn with<R>(&self, f: impl FnOnce(&mut Inner) -> R) -> R {
+        if ptr::eq(self, &*TOPIC_NAMES) {
+            if let Some(scope) = scope::current() {
+                return f(&mut scope.names.inner.lock().unwrap());
+            }
+        }
+        f(&mut self.inner.lock().unwrap())
+    }
+
+    // set_rules sets the name rules, before any topic name is given out.
+    pub fn set_rules(&self, rules: NameRules) {
+        self.with(|inner| inner.rules = rules);
+    }
+
+    // display_name returns the name the rules give device_id, or else the name it advertises.
+    pub fn display_name(&self, device_id: &DeviceId) -> String {
+        let (name, _) = self.with(|inner| inner.rules.apply(&device_id.device_name));
+        name.unwrap_or_else(|| device_id.device_name.clone())
+    }
+
+    // set_locations sets the devices in each location.
+    pub fn set_locations(&self, locations: BTreeMap<String, Vec<String>>) {
+        self.with(|inner| inner.locations = locations);
+    }
+
+    // location returns the location device_id is configured in, or else the room the rules give
+    // it, if any.
+    pub fn location(&self, device_id: &DeviceId) -> Option<String> {
+        self.with(|inner| {
+            let configured = inner
+                .locations
+                .iter()
+                .find(|(_, devices)| !devices.is_empty() && devices_match(devices, device_id));
+            match configured {
+                Some((location, _)) => Some(location.clone()),
+                None => inner.rules.apply(&device_id.device_name).1,
+            }
+        })
+    }
+
+    // set_labels sets the labels of devices.
+    pub fn set_labels(&self, labels: Vec<LabelConfig>) {
+        self.with(|inner| inner.labels = labels);
+    }
+
+    // labels returns the labels configured for device_id. Where several entries match and give
+    // the same label, the last one wins.
+    pub fn labels(&self, device_id: &DeviceId) -> BTreeMap<String, String> {
+        self.with(|inner| {
+            let mut labels = BTreeMap::new();
+            for entry in &inner.labels {
+                if devices_match(&entry.devices, device_id) {
+                    labels.extend(entry.labels.clone());
+                }
+            }
+            labels
+        })
+    }
+
+    // tags returns device_id's labels and location, for sinks that tag values.
+    pub fn tags(&self, device_id: &DeviceId) -> BTreeMap<String, String> {
+        let mut tags = self.labels(device_id);
+        if let Some(location) = self.location(device_id) {
+            tags.insert("location".to_string(), location);
+        }
+        tags
+    }
+
+    // expand_location replaces `{location}` in a topic prefix with device_id's location.
+    pub fn expand_location(&self, topic_prefix: &str, device_id: &DeviceId) -> String {
+        if !topic_prefix.contains("{location}") {
+            return topic_prefix.to_string();
+        }
+        let location = self
+            .location(device_id)
+            .unwrap_or_else(|| LOCATION_UNASSIGNED.to_string());
+        topic_prefix.replace("{location}", &location.replace(['/', '+', '#'], "_"))
+    }
+
+    // topic_name returns the name to use for device_id in topics.
+    pub fn topic_name(&self, device_id: &DeviceId) -> String {
+        self.topic_name_at(device_id, Instant::now())
+    }
+
+    fn topic_name_at(&self, device_id: &DeviceId, now: Instant) -> String {
+        self.with(|inner| inner.topic_name(device_id, now))
+    }
+}
+
+impl Inner {
+    // topic_name names device_id, heard at now.
+    fn topic_name(&mut self, device_id: &DeviceId, now: Instant) -> String {
+        if self
+            .last_prune
+            .is_none_or(|last| now.duration_since(last) >= NAME_EXPIRY)
+        {
+            self.prune(now);
+        }
+        if let Some((name, seen)) = self.names.get_mut(&device_id.id) {
+            *seen = now;
+            return name.clone();
+        }
+        let (name, _) = self.rules.apply(&device_id.device_name);
+        let mut name = name.unwrap_or_else(|| device_id.device_name.clone());
+        let owned_by_another = |owners: &HashMap<String, (String, String)>, name: &String| {
+            owners.get(name).is_some_and(|(id, address)| {
+                *id != device_id.id && (address.is_empty() || *address != device_id.address)
+            })
+        };
+        if owned_by_another(&self.owners, &name) {
+            let suffixed = format!("{}_{}", name, short_id(device_id));
+            name = if owned_by_another(&self.owners, &suffixed) {
+                device_id.id.replace(['/', ':', '+', '#'], "_")
+            } else {
+                suffixed
+            };
+            println!(
+                "{} is also named {:?}, publishing it as {:?}",
+                device_id.id, device_id.device_name, name
+            );
+        }
+        self.owners
+            .entry(name.clone())
+            .or_insert_with(|| (device_id.id.clone(), device_id.address.clone()));
+        self.names.insert(device_id.id.clone(), (name.clone(), now));
+        name
+    }
+
+    // prune forgets the names of devices not heard for NAME_EXPIRY, and frees names no device
+    // uses anymore for others to take.
+    fn prune(&mut self, now: Instant) {
+        self.names
+            .retain(|_, (_, seen)| now.duration_since(*seen) < NAME_EXPIRY);
+        let in_use: HashSet<&String> = self.names.values().map(|(name, _)| name).collect();
+        self.owners.retain(|name, _| in_use.contains(name));
+        self.last_prune = Some(now);
+    }
+}
+
+// short_id is the last six hex digits of the device's address, or of its id if it has none.
+fn short_id(device_id: &DeviceId) -> String {
+    let source = if device_id.address.is_empty() {
+        &device_id.id
+    } else {
+        &device_id.address
+    };
+    let digits: Vec<char> = source.chars().filter(char::is_ascii_alphanumeric).collect();
+    digits[digits.len().saturating_sub(6)..]
+        .iter()
+        .collect::<String>()
+        .to_uppercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::time::Instant;
+
+    use crate::config::{LabelConfig, NameConfig};
+    use crate::names::{NameRules, TopicNames, NAME_EXPIRY};
+    use crate::DeviceId;
+
+    fn device_id(adapter: &str, address: &str) -> DeviceId {
+        DeviceId {
+            id: format!("{}/{}", adapter, address),
+            device_name: "ATC_8F2C1A".to_string(),
+            address: address.to_string(),
+            random_address: None,
+        }
+    }
+
+    #[test]
+    fn test_topic_name() {
+        let names = TopicNames::default();
+        let first = device_id("hci0", "A4:C1:38:8F:2C:1A");
+        let second = device_id("hci0", "A4:C1:38:12:34:56");
+        assert_eq!(names.topic_name(&first), "ATC_8F2C1A");
+        assert_eq!(names.topic_name(&second), "ATC_8F2C1A_123456");
+        assert_eq!(names.topic_name(&first), "ATC_8F2C1A");
+        assert_eq!(names.topic_name(&second), "ATC_8F2C1A_123456");
+        // The same device through another adapter keeps its name.
+        assert_eq!(
+            names.topic_name(&device_id("hci1", "A4:C1:38:8F:2C:1A")),
+            "ATC_8F2C1A"
+        );
+    }
+
+    #[test]
+    fn test_topic_name_expiry() {
+        let names = TopicNames::default();
+        let start = Instant::now();
+        let first = device_id("hci0", "A4:C1:38:8F:2C:1A");
+        let second = device_id("hci0", "A4:C1:38:12:34:56");
+        assert_eq!(names.topic_name_at(&first, start), "ATC_8F2C1A");
+        assert_eq!(names.topic_name_at(&second, start), "ATC_8F2C1A_123456");
+        // Still heard, the second device keeps its name.
+        let later = start + NAME_EXPIRY / 2;
+        assert_eq!(names.topic_name_at(&second, later), "ATC_8F2C1A_123456");
+        // The first device went quiet, so its name is free again.
+        let third = device_id("hci0", "A4:C1:38:AB:CD:EF");
+        let expired = start + NAME_EXPIRY;
+        assert_eq!(names.topic_name_at(&third, expired), "ATC_8F2C1A");
+        assert_eq!(names.topic_name_at(&second, expired), "ATC_8F2C1A_123456");
+        assert_eq!(names.inner.lock().unwrap().names.len(), 2);
+    }
+
+    #[test]
+    fn test_name_rules() {
+        let rule = |pattern: &str, name: Option<&str>, room: Option<&str>| NameConfig {
+            pattern: pattern.to_string(),
+            name: name.map(str::to_string),
+            room: room.map(str::to_string),
+        };
+        let names = TopicNames::default();
+        names.set_rules(
+            NameRules::new(&[
+                rule("ATC_(?P<mac>[0-9A-F]{6})", Some("atc_${mac}"), None),
+                rule(r"(\w+)-TH", Some("${1}_climate"), Some("$1")),
+            ])
+            .unwrap(),
+        );
+        let device_id = |name: &str, address: &str| DeviceId {
+            id: format!("hci0/{}", address),
+            device_name: name.to_string(),
+            address: address.to_string(),
+            random_address: None,
+        };
+        let atc = device_id("ATC_8F2C1A", "A4:C1:38:8F:2C:1A");
+        assert_eq!(names.topic_name(&atc), "atc_8F2C1A");
+        assert_eq!(names.location(&atc), None);
+        let kitchen = device_id("Kitchen-TH", "A4:C1:38:12:34:56");
+        assert_eq!(names.topic_name(&kitchen), "Kitchen_climate");
+        assert_eq!(names.display_name(&kitchen), "Kitchen_climate");
+        assert_eq!(names.location(&kitchen).as_deref(), Some("Kitchen"));
+        // Patterns match whole names.
+        let other = device_id("My Kitchen-TH sensor", "A4:C1:38:AB:CD:EF");
+        assert_eq!(names.topic_name(&other), "My Kitchen-TH sensor");
+
+        assert!(NameRules::new(&[rule("ATC_.*", None, None)]).is_err());
+        assert!(NameRules::new(&[rule("ATC_(", Some("atc"), None)]).is_err());
+    }
+
+    #[test]
+    fn test_locations() {
+        let names = TopicNames::default();
+        names.set_locations(BTreeMap::from([(
+            "garden".to_string(),
+            vec!["Soil_*".to_string(), "hci0/greenhouse".to_string()],
+        )]));
+        let device_id = |id: &str, name: &str| DeviceId {
+            id: id.to_string(),
+            device_name: name.to_string(),
+            address: String::new(),
+            random_address: None,
+        };
+        let soil = device_id("hci0/soil", "Soil_1");
+        assert_eq!(names.location(&soil).as_deref(), Some("garden"));
+        assert_eq!(
+            names.expand_location("home/{location}/sensors", &soil),
+            "home/garden/sensors"
+        );
+        let greenhouse = device_id("hci0/greenhouse", "Greenhouse");
+        assert_eq!(names.location(&greenhouse).as_deref(), Some("garden"));
+        let other = device_id("hci0/other", "ATC_LOCATION");
+        assert_eq!(names.location(&other), None);
+        assert_eq!(
+            names.expand_location("home/{location}/sensors", &other),
+            "home/unassigned/sensors"
+        );
+        assert_eq!(
+            names.expand_location("device_reading", &other),
+            "device_reading"
+        );
+
+        names.set_labels(vec![
+            LabelConfig {
+                devices: Vec::new(),
+                labels: BTreeMap::from([("site".to_string(), "home".to_string())]),
+            },
+            LabelConfig {
+                devices: vec!["Soil_*".to_string()],
+                labels: BTreeMap::from([
+                    ("site".to_string(), "allotment".to_string()),
+                    ("bed".to_string(), "3".to_string()),
+                ]),
+            },
+        ]);
+        assert_eq!(
+            names.tags(&soil),
+            BTreeMap::from([
+                ("bed".to_string(), "3".to_string()),
+                ("location".to_string(), "garden".to_string()),
+                ("site".to_string(), "allotment".to_string()),
+            ])
+        );
+        assert_eq!(
+            names.tags(&other),
+            BTreeMap::from([("site".to_string(), "home".to_string())])
+        );
+    }
+}
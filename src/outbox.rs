@@ -0,0 +1,432 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Instant;
+
+use color_eyre::eyre::{Result, WrapErr};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::metrics::{Pruned, METRICS};
+use crate::DeviceId;
+
+// Pending is an encoded publish that hasn't been acknowledged by the broker yet.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Pending {
+    // Set once the message is journaled in an outbox.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+    pub topic: String,
+    pub retain: bool,
+    // Set when the message goes out at another QoS than the broker's, as alerts may.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub qos: Option<u8>,
+    pub payload: Vec<u8>,
+    // A reading's payload before encoding and when it was stamped, so it can be re-stamped if the
+    // wall clock jumps while it waits to be sent. Not journaled.
+    #[serde(skip)]
+    pub stamped: Option<(Value, Instant)>,
+    // The device whose readings the message carries and how many, counted as published once it is
+    // handed to the client. Not journaled.
+    #[serde(skip)]
+    pub readings: Option<(DeviceId, usize)>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Entry {
+    Add(Pending),
+    Done(u64),
+}
+
+// Outbox journals QoS 1 and 2 publishes to a file until the broker acknowledges them, so messages
+// in flight or buffered when blueplug crashes or is restarted are sent again by the next run.
+// Delivery is at least once: a message acknowledged just before a crash may be sent twice.
+//
+// rumqttc assigns packet ids inside its event loop, so the outbox pairs them up with messages by
+// order: the client sends requests in the order they were queued, and the first outgoing publish
+// with a packet id not yet in flight belongs to the oldest message handed to the client.
+//
+// Acknowledged messages stay in the journal until it reaches max_size, when it is compacted to
+// the messages still waiting. So that a broker that stays away can't fill the disk either, those
+// are then trimmed, oldest first, to half of max_size. They are still sent if this run gets to,
+// but no longer after a restart. Compaction rewrites the journal on a blocking thread, while
+// publishing goes on appending to the old one; what was appended meanwhile is carried over.
+pub struct Outbox {
+    broker: String,
+    path: PathBuf,
+    max_size: u64,
+    file: File,
+    // Bytes in the journal.
+    size: u64,
+    next_seq: u64,
+    // Handed to the client, oldest first, but not sent yet.
+    queued: VecDeque<u64>,
+    // Sent and waiting for an acknowledgement, by packet id.
+    in_flight: HashMap<u16, u64>,
+    // The messages in the journal not yet done, which compaction keeps.
+    pending: BTreeMap<u64, Pending>,
+    // Messages the journal adds, done or not, to count those compaction prunes.
+    added: usize,
+    // While the journal is compacted, the entries appended since, and how many of them are adds.
+    appended: Option<(Vec<u8>, usize)>,
+    // The outbox itself, for compaction to come back to.
+    this: Weak<Mutex<Outbox>>,
+}
+
+// Compacted is a journal written to a temporary file by compaction, to replace the outbox's.
+struct Compacted {
+    tmp: PathBuf,
+    size: u64,
+    // Messages written, and those trimmed to fit.
+    kept: usize,
+    dropped: Vec<u64>,
+}
+
+impl Outbox {
+    // open returns broker's outbox at path with the messages a previous run didn't get
+    // acknowledged, oldest first. The journal is compacted to just those.
+    pub fn open(
+        broker: &str,
+        path: &Path,
+        max_size: u64,
+    ) -> Result<(Arc<Mutex<Self>>, Vec<Pending>)> {
+        let journal = match std::fs::read(path) {
+            Ok(journal) => journal,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e).wrap_err_with(|| format!("reading {}", path.display())),
+        };
+        let (mut pending, added) = replay(&journal);
+        let compacted = compact(broker, path, max_size, &pending, added)?;
+        for seq in &compacted.dropped {
+            pending.remove(seq);
+        }
+        std::fs::rename(&compacted.tmp, path)
+            .wrap_err_with(|| format!("writing {}", path.display()))?;
+        let file = open_append(path)?;
+
+        let next_seq = pending.keys().next_back().map_or(0, |seq| seq + 1);
+        let restored = pending.values().cloned().collect();
+        let outbox = Arc::new_cyclic(|this| {
+            Mutex::new(Outbox {
+                broker: broker.to_string(),
+                path: path.to_path_buf(),
+                max_size,
+                file,
+                size: compacted.size,
+                next_seq,
+                queued: VecDeque::new(),
+                in_flight: HashMap::new(),
+                pending,
+                added: compacted.kept,
+                appended: None,
+                this: this.clone(),
+            })
+        });
+        Ok((outbox, restored))
+    }
+
+    // add journals a message before it is handed to the client or buffered.
+    pub fn add(&mut self, pending: &mut Pending) -> Result<()> {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        pending.seq = Some(seq);
+        self.pending.insert(seq, pending.clone());
+        self.added += 1;
+        self.append(&Entry::Add(pending.clone()))
+    }
+
+    // queued is called just before a journaled message is handed to the client, and unqueued if
+    // the client didn't take it after all.
+    pub fn queued(&mut self, seq: u64) {
+        self.queued.push_back(seq);
+    }
+
+    pub fn unqueued(&mut self) {
+        self.queued.pop_back();
+    }
+
+    // outgoing is called for every publish the event loop sends. Retransmissions keep their
+    // packet id, and QoS 0 publishes have none.
+    pub fn outgoing(&mut self, pkid: u16) {
+        if pkid == 0 || self.in_flight.contains_key(&pkid) {
+            return;
+        }
+        if let Some(seq) = self.queued.pop_front() {
+            self.in_flight.insert(pkid, seq);
+        }
+    }
+
+    pub fn acknowledged(&mut self, pkid: u16) -> Result<()> {
+        match self.in_flight.remove(&pkid) {
+            Some(seq) => self.done(seq),
+            None => Ok(()),
+        }
+    }
+
+    // done drops a message from the journal, once acknowledged or given up on.
+    pub fn done(&mut self, seq: u64) -> Result<()> {
+        self.pending.remove(&seq);
+        self.append(&Entry::Done(seq))
+    }
+
+    // Entries are written whole and unbuffered, so the journal survives blueplug being killed.
+    fn append(&mut self, entry: &Entry) -> Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        self.size += line.len() as u64;
+        match &mut self.appended {
+            Some((appended, adds)) => {
+                appended.extend(&line);
+                *adds += matches!(entry, Entry::Add(_)) as usize;
+            }
+            None if self.size >= self.max_size => self.start_compaction(),
+            None => {}
+        }
+        Ok(())
+    }
+
+    // start_compaction writes the messages still waiting to a new journal on a blocking thread.
+    fn start_compaction(&mut self) {
+        let Some(this) = self.this.upgrade() else {
+            return;
+        };
+        self.appended = Some((Vec::new(), 0));
+        let (broker, path, max_size) = (self.broker.clone(), self.path.clone(), self.max_size);
+        let (pending, added) = (self.pending.clone(), self.added);
+        tokio::task::spawn_blocking(move || {
+            let compacted = compact(&broker, &path, max_size, &pending, added);
+            let mut outbox = this.lock().unwrap();
+            if let Err(e) = compacted.and_then(|compacted| outbox.finish_compaction(compacted)) {
+                outbox.appended = None;
+                println!("{}: compacting {} failed: {:?}", broker, path.display(), e);
+            }
+        });
+    }
+
+    // finish_compaction appends what was journaled meanwhile to the compacted journal and puts it
+    // in place of the outbox's.
+    fn finish_compaction(&mut self, compacted: Compacted) -> Result<()> {
+        let (appended, adds) = self.appended.take().unwrap_or_default();
+        let mut file = open_append(&compacted.tmp)?;
+        file.write_all(&appended)?;
+        std::fs::rename(&compacted.tmp, &self.path)
+            .wrap_err_with(|| format!("writing {}", self.path.display()))?;
+        self.file = open_append(&self.path)?;
+        self.size = compacted.size + appended.len() as u64;
+        self.added = compacted.kept + adds;
+        for seq in compacted.dropped {
+            self.pending.remove(&seq);
+        }
+        Ok(())
+    }
+}
+
+// compact writes the pending messages, trimmed to half of max_size, to a temporary journal next
+// to path. Of the added messages, those not pending were acknowledged.
+fn compact(
+    broker: &str,
+    path: &Path,
+    max_size: u64,
+    pending: &BTreeMap<u64, Pending>,
+    added: usize,
+) -> Result<Compacted> {
+    let mut lines = Vec::new();
+    for pending in pending.values() {
+        let mut line = serde_json::to_vec(&Entry::Add(pending.clone()))?;
+        line.push(b'\n');
+        lines.push(line);
+    }
+    let mut size: u64 = lines.iter().map(|line| line.len() as u64).sum();
+    let mut dropped = Vec::new();
+    for (seq, line) in pending.keys().zip(&lines) {
+        if size <= max_size / 2 {
+            break;
+        }
+        size -= line.len() as u64;
+        dropped.push(*seq);
+    }
+    if !dropped.is_empty() {
+        println!(
+            "{}: {} is full, dropped the {} oldest unacknowledged messages from it",
+            broker,
+            path.display(),
+            dropped.len()
+        );
+    }
+    let acknowledged = added.saturating_sub(pending.len());
+    METRICS.pruned(broker, Pruned::Acknowledged, acknowledged as u64);
+    METRICS.pruned(broker, Pruned::Dropped, dropped.len() as u64);
+
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, lines[dropped.len()..].concat())
+        .wrap_err_with(|| format!("writing {}", tmp.display()))?;
+    Ok(Compacted {
+        tmp,
+        size,
+        kept: lines.len() - dropped.len(),
+        dropped,
+    })
+}
+
+fn open_append(path: &Path) -> Result<File> {
+    OpenOptions::new()
+        .append(true)
+        .open(path)
+        .wrap_err_with(|| format!("opening {}", path.display()))
+}
+
+// replay returns the messages the journal adds and doesn't mark done, and how many it adds. A
+// line cut short by a crash is skipped, even in the middle of a character.
+fn replay(journal: &[u8]) -> (BTreeMap<u64, Pending>, usize) {
+    let mut pending = BTreeMap::new();
+    let mut added = 0;
+    for line in journal.split(|&b| b == b'\n') {
+        match serde_json::from_slice(line) {
+            Ok(Entry::Add(message)) => {
+                added += 1;
+                if let Some(seq) = message.seq {
+                    pending.insert(seq, message);
+                }
+            }
+            Ok(Entry::Done(seq)) => {
+                pending.remove(&seq);
+            }
+            Err(_) => {}
+        }
+    }
+    (pending, added)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::outbox::{Outbox, Pending};
+
+    fn pending(topic: &str) -> Pending {
+        Pending {
+            seq: None,
+            topic: topic.to_string(),
+            retain: false,
+            qos: None,
+            payload: b"{}".to_vec(),
+            stamped: None,
+            readings: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_outbox() {
+        let path = std::env::temp_dir().join(format!("blueplug-outbox-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let (outbox, restored) = Outbox::open("local", &path, 1 << 20).unwrap();
+        assert!(restored.is_empty());
+        let mut messages = [pending("a"), pending("b"), pending("c")];
+        {
+            let mut outbox = outbox.lock().unwrap();
+            for message in &mut messages {
+                outbox.add(message).unwrap();
+                outbox.queued(message.seq.unwrap());
+            }
+            outbox.outgoing(1);
+            outbox.outgoing(2);
+            // A retransmission of packet 1 doesn't take another message.
+            outbox.outgoing(1);
+            outbox.acknowledged(2).unwrap();
+        }
+        drop(outbox);
+
+        let (outbox, restored) = Outbox::open("local", &path, 1 << 20).unwrap();
+        let topics: Vec<_> = restored
+            .iter()
+            .map(|message| message.topic.as_str())
+            .collect();
+        assert_eq!(topics, ["a", "c"]);
+        let mut message = pending("d");
+        outbox.lock().unwrap().add(&mut message).unwrap();
+        assert_eq!(message.seq, Some(3));
+        drop(outbox);
+
+        // A full journal is compacted, keeping the newest messages still waiting.
+        let (outbox, _) = Outbox::open("local", &path, 1000).unwrap();
+        for i in 0..100 {
+            let mut message = pending(&i.to_string());
+            outbox.lock().unwrap().add(&mut message).unwrap();
+            while outbox.lock().unwrap().appended.is_some() {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+            assert!(std::fs::metadata(&path).unwrap().len() < 1000);
+        }
+        drop(outbox);
+        let (_, restored) = Outbox::open("local", &path, 1000).unwrap();
+        assert!(!restored.is_empty());
+        assert_eq!(restored.last().unwrap().topic, "99");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_outbox_appends_while_compacting() {
+        let path =
+            std::env::temp_dir().join(format!("blueplug-outbox-busy-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let (outbox, _) = Outbox::open("local", &path, 1000).unwrap();
+        {
+            // Compaction waits for the lock, so everything added here is carried over.
+            let mut outbox = outbox.lock().unwrap();
+            for i in 0..40 {
+                outbox.add(&mut pending(&i.to_string())).unwrap();
+            }
+            assert!(outbox.appended.is_some());
+            outbox.done(39).unwrap();
+        }
+        while outbox.lock().unwrap().appended.is_some() {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        drop(outbox);
+
+        let (_, restored) = Outbox::open("local", &path, 1 << 20).unwrap();
+        let topics: Vec<_> = restored
+            .iter()
+            .map(|message| message.topic.as_str())
+            .collect();
+        assert_eq!(topics.last(), Some(&"38"));
+        assert!(topics.len() > 10);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_outbox_truncated_mid_character() {
+        let path = std::env::temp_dir().join(format!("blueplug-outbox-cut-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let (outbox, _) = Outbox::open("local", &path, 1 << 20).unwrap();
+        outbox.lock().unwrap().add(&mut pending("kitchen")).unwrap();
+        outbox.lock().unwrap().add(&mut pending("küche")).unwrap();
+        drop(outbox);
+        // Cut the journal inside the ü of the second message.
+        let journal = std::fs::read(&path).unwrap();
+        let cut = journal
+            .windows(2)
+            .position(|w| w == "ü".as_bytes())
+            .unwrap()
+            + 1;
+        std::fs::write(&path, &journal[..cut]).unwrap();
+
+        let (_, restored) = Outbox::open("local", &path, 1 << 20).unwrap();
+        let topics: Vec<_> = restored
+            .iter()
+            .map(|message| message.topic.as_str())
+            .collect();
+        assert_eq!(topics, ["kitchen"]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
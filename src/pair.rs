@@ -0,0 +1,257 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use btleplug::api::{Central, ScanFilter};
+use btleplug::platform::Manager;
+use color_eyre::eyre::{eyre, Result};
+use tokio::time;
+
+use crate::adapter::{select_adapter, AdapterSelector};
+use crate::config::{AddressType, Config};
+use crate::gatt::lookup;
+use crate::preflight::{add_device, explain, preflight};
+
+// How long to scan for the device before giving up.
+const SCAN_TIMEOUT: Duration = Duration::from_secs(30);
+
+// PairArgs configures `blueplug pair`, which pairs with a device that requires bonding before it
+// sends notifications or lets its characteristics be read, such as locks and some thermostats.
+// Passkeys and confirmations are asked for on the terminal. The bond is kept by the Bluetooth
+// stack and the device marked as trusted, so [[gatt]] devices with `bonded = true` connect with it
+// from then on. Only BlueZ can be asked to pair; macOS pairs by itself when a device asks to.
+#[derive(clap::Args, Debug)]
+pub struct PairArgs {
+    /// Device to pair with, by the name of a [[gatt]] device in --config or by MAC address
+    device: String,
+    /// Configuration file whose [[gatt]] devices to look the device up in
+    #[arg(short = 'c', long)]
+    config: Option<PathBuf>,
+    /// Bluetooth adapter to pair through, by index, name (e.g. hci1) or MAC address
+    #[arg(long)]
+    adapter: Option<AdapterSelector>,
+}
+
+pub async fn run(args: PairArgs) -> Result<()> {
+    let config = match &args.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    let (address, advertises, address_type) =
+        match config.gatt.iter().find(|device| device.name == args.device) {
+            Some(device) => (
+                device.address.clone(),
+                device.advertises,
+                device.address_type,
+            ),
+            None if args.device.len() == 17 && args.device.split(':').count() == 6 => {
+                (args.device.to_uppercase(), true, AddressType::Public)
+            }
+            None => {
+                return Err(eyre!(
+                    "{:?} is neither a [[gatt]] device in --config nor a MAC address",
+                    args.device
+                ))
+            }
+        };
+
+    preflight(args.adapter.as_ref()).await?;
+    let manager = Manager::new().await.map_err(explain)?;
+    let central = select_adapter(&manager, args.adapter.as_ref()).await?;
+    // BlueZ describes an adapter as its name followed by its modalias.
+    let info = central.adapter_info().await.map_err(explain)?;
+    let adapter = info
+        .split_whitespace()
+        .next()
+        .unwrap_or_default()
+        .to_string();
+
+    if advertises {
+        println!("scanning for {}", address);
+        central
+            .start_scan(ScanFilter::default())
+            .await
+            .map_err(explain)?;
+        let found = time::timeout(SCAN_TIMEOUT, async {
+            while lookup(&central, &address).await?.is_none() {
+                time::sleep(Duration::from_secs(1)).await;
+            }
+            Ok::<_, color_eyre::Report>(())
+        })
+        .await;
+        central.stop_scan().await.map_err(explain)?;
+        found.map_err(|_| eyre!("{} wasn't found, is it nearby and awake?", address))??;
+    } else {
+        add_device(&adapter, &address, address_type).await?;
+    }
+
+    pair(adapter, address.clone()).await?;
+    println!("paired with {}", address);
+    Ok(())
+}
+
+async fn pair(adapter: String, address: String) -> Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        tokio::task::spawn_blocking(move || bluez::pair(&adapter, &address)).await?
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = adapter;
+        Err(eyre!(
+            "only Linux can pair with {} here; macOS pairs when the device asks to",
+            address
+        ))
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod bluez {
+    use std::ffi::CString;
+    use std::io::Write;
+    use std::time::{Duration, Instant};
+
+    use color_eyre::eyre::{eyre, Result};
+    use dbus::blocking::stdintf::org_freedesktop_dbus::Properties;
+    use dbus::blocking::Connection;
+    use dbus::message::MessageType;
+    use dbus::strings::ErrorName;
+    use dbus::{Message, Path};
+
+    use crate::preflight::bluez::device_path;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+    // Pairing waits on the user typing in passkeys, so it may take a while.
+    const PAIR_TIMEOUT: Duration = Duration::from_secs(90);
+    const AGENT_PATH: &str = "/io/github/hagmonk/blueplug/agent";
+    const AGENT_INTERFACE: &str = "org.bluez.Agent1";
+
+    // pair asks BlueZ to pair with the device, answering its questions as an agent that prompts on
+    // the terminal, then trusts the device so BlueZ reconnects with the bond.
+    pub fn pair(adapter: &str, address: &str) -> Result<()> {
+        let connection = Connection::new_system()?;
+        let device = device_path(adapter, address);
+        let device_proxy = connection.with_proxy("org.bluez", &device, TIMEOUT);
+        let paired: bool = device_proxy.get("org.bluez.Device1", "Paired")?;
+        if !paired {
+            let manager = connection.with_proxy("org.bluez", "/org/bluez", TIMEOUT);
+            let agent = Path::from(AGENT_PATH);
+            manager.method_call::<(), _, _, _>(
+                "org.bluez.AgentManager1",
+                "RegisterAgent",
+                (agent.clone(), "KeyboardDisplay"),
+            )?;
+            let paired = pair_device(&connection, &device);
+            let _ = manager.method_call::<(), _, _, _>(
+                "org.bluez.AgentManager1",
+                "UnregisterAgent",
+                (agent,),
+            );
+            paired?;
+        }
+        device_proxy.set("org.bluez.Device1", "Trusted", true)?;
+        Ok(())
+    }
+
+    // pair_device calls Pair without blocking on the reply, as BlueZ calls back into the agent
+    // before answering.
+    fn pair_device(connection: &Connection, device: &Path) -> Result<()> {
+        let call = Message::new_method_call("org.bluez", device, "org.bluez.Device1", "Pair")
+            .map_err(|e| eyre!(e))?;
+        let channel = connection.channel();
+        let serial = channel
+            .send(call)
+            .map_err(|_| eyre!("can't send to BlueZ"))?;
+        let deadline = Instant::now() + PAIR_TIMEOUT;
+        while Instant::now() < deadline {
+            channel
+                .read_write(Some(Duration::from_millis(100)))
+                .map_err(|_| eyre!("lost the connection to BlueZ"))?;
+            while let Some(mut message) = channel.pop_message() {
+                if message.get_reply_serial() == Some(serial) {
+                    return match message.as_result() {
+                        Ok(_) => Ok(()),
+                        Err(e) => Err(eyre!(
+                            "pairing failed: {}",
+                            e.message().or(e.name()).unwrap_or_default()
+                        )),
+                    };
+                }
+                if message.msg_type() == MessageType::MethodCall {
+                    let reply = answer(&message);
+                    let _ = channel.send(reply);
+                }
+            }
+        }
+        Err(eyre!("pairing timed out"))
+    }
+
+    // answer handles a call to the agent, asking the user where BlueZ needs them to.
+    fn answer(call: &Message) -> Message {
+        let interface = call.interface();
+        if interface.as_deref() != Some(AGENT_INTERFACE) {
+            return reject(call, "org.freedesktop.DBus.Error.UnknownMethod");
+        }
+        let member = call.member();
+        match member.as_deref().unwrap_or_default() {
+            "RequestPinCode" => match prompt("PIN code: ") {
+                Some(pin) => call.method_return().append1(pin),
+                None => reject(call, "org.bluez.Error.Canceled"),
+            },
+            "RequestPasskey" => match prompt("passkey: ").and_then(|p| p.parse::<u32>().ok()) {
+                Some(passkey) => call.method_return().append1(passkey),
+                None => reject(call, "org.bluez.Error.Canceled"),
+            },
+            "DisplayPinCode" => {
+                if let Ok((_, pin)) = call.read2::<Path, &str>() {
+                    println!("enter PIN code {} on the device", pin);
+                }
+                call.method_return()
+            }
+            "DisplayPasskey" => {
+                if let Ok((_, passkey)) = call.read2::<Path, u32>() {
+                    println!("enter passkey {:06} on the device", passkey);
+                }
+                call.method_return()
+            }
+            "RequestConfirmation" => {
+                let passkey = call
+                    .read2::<Path, u32>()
+                    .map(|(_, p)| p)
+                    .unwrap_or_default();
+                confirm(
+                    call,
+                    &format!("does the device show {:06}? [y/N] ", passkey),
+                )
+            }
+            "RequestAuthorization" => confirm(call, "pair with the device? [y/N] "),
+            "Cancel" => {
+                println!("pairing was cancelled");
+                call.method_return()
+            }
+            // AuthorizeService and Release need nothing from us.
+            _ => call.method_return(),
+        }
+    }
+
+    fn confirm(call: &Message, question: &str) -> Message {
+        match prompt(question) {
+            Some(answer) if answer.eq_ignore_ascii_case("y") => call.method_return(),
+            _ => reject(call, "org.bluez.Error.Rejected"),
+        }
+    }
+
+    fn reject(call: &Message, name: &'static str) -> Message {
+        call.error(
+            &ErrorName::from(name),
+            &CString::new("rejected by blueplug").unwrap_or_default(),
+        )
+    }
+
+    fn prompt(question: &str) -> Option<String> {
+        print!("{}", question);
+        std::io::stdout().flush().ok()?;
+        let mut answer = String::new();
+        std::io::stdin().read_line(&mut answer).ok()?;
+        Some(answer.trim().to_string()).filter(|answer| !answer.is_empty())
+    }
+}
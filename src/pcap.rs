@@ -0,0 +1,525 @@
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_stream::stream;
+use btleplug::api::bleuuid::{uuid_from_u16, BleUuid};
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use futures_core::stream::Stream;
+use uuid::Uuid;
+
+use crate::error::BlueplugError;
+use crate::metrics::METRICS;
+use crate::{DeviceEvent, DeviceId, LEGACY_ADVERTISING_DATA_LEN};
+
+const MAGIC: u32 = 0xa1b2c3d4;
+
+// LINKTYPE_BLUETOOTH_HCI_H4_WITH_PHDR: HCI packets preceded by a 4 byte direction.
+const LINKTYPE: u32 = 201;
+const DIRECTION_RECEIVED: u32 = 1;
+
+const HCI_EVENT_PACKET: u8 = 0x04;
+const LE_META_EVENT: u8 = 0x3e;
+const LE_ADVERTISING_REPORT: u8 = 0x02;
+const LE_EXTENDED_ADVERTISING_REPORT: u8 = 0x0d;
+const ADV_IND: u8 = 0x00;
+// Extended, non-connectable and non-scannable, with complete data.
+const EXTENDED_ADV: [u8; 2] = [0x00, 0x00];
+const PUBLIC_ADDRESS: u8 = 0x00;
+const RANDOM_ADDRESS: u8 = 0x01;
+const PHY_LE_1M: u8 = 0x01;
+const NO_ADVERTISING_SID: u8 = 0xff;
+const TX_POWER_UNAVAILABLE: u8 = 0x7f;
+const RSSI_UNAVAILABLE: u8 = 0x7f;
+
+// The HCI event's parameters, including the rest of an extended report, must fit in 255 bytes.
+const MAX_DATA_LEN: usize = 255 - 26;
+
+const AD_SHORTENED_LOCAL_NAME: u8 = 0x08;
+const AD_COMPLETE_LOCAL_NAME: u8 = 0x09;
+const AD_SERVICE_DATA_16: u8 = 0x16;
+const AD_SERVICE_DATA_128: u8 = 0x21;
+const AD_MANUFACTURER_DATA: u8 = 0xff;
+
+// PcapWriter records advertisements as HCI LE Advertising Report events in a PCAP file, which
+// Wireshark decodes, to help with reverse engineering a new sensor's payload. Platforms hand
+// blueplug advertisements already parsed, so the advertising data is rebuilt from the device name
+// and the manufacturer and service data. Addresses not known to be random are written as public.
+// Data too long for a legacy advertisement is written as an extended advertising report.
+//
+// With a max_size or max_age, the capture is rotated once it reaches half of either: it is
+// renamed with a `.1` before its extension, replacing the previous one, and a new capture is
+// started. So the two files together stay within max_size and hold nothing older than max_age.
+pub struct PcapWriter {
+    path: PathBuf,
+    file: File,
+    // Bytes in the capture, and when it was started.
+    size: u64,
+    created: SystemTime,
+    max_size: Option<u64>,
+    max_age: Option<Duration>,
+}
+
+impl PcapWriter {
+    pub fn create(path: &Path) -> Result<Self> {
+        let (file, size) = create(path)?;
+        Ok(PcapWriter {
+            path: path.to_path_buf(),
+            file,
+            size,
+            created: SystemTime::now(),
+            max_size: None,
+            max_age: None,
+        })
+    }
+
+    // retention rotates the capture to keep it within max_size bytes and max_age.
+    pub fn retention(mut self, max_size: Option<u64>, max_age: Option<Duration>) -> Self {
+        self.max_size = max_size;
+        self.max_age = max_age;
+        self
+    }
+
+    pub fn write(&mut self, event: &DeviceEvent, time: SystemTime) -> Result<()> {
+        let packet = packet(event);
+        let record_len = 16 + packet.len() as u64;
+        let full = self
+            .max_size
+            .is_some_and(|max_size| self.size + record_len > max_size / 2);
+        let old = self.max_age.is_some_and(|max_age| {
+            time.duration_since(self.created).unwrap_or_default() >= max_age / 2
+        });
+        if full || old {
+            self.rotate(time)?;
+        }
+        let time = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let mut record = Vec::with_capacity(16 + packet.len());
+        record.extend((time.as_secs() as u32).to_le_bytes());
+        record.extend(time.subsec_micros().to_le_bytes());
+        record.extend((packet.len() as u32).to_le_bytes());
+        record.extend((packet.len() as u32).to_le_bytes());
+        record.extend(packet);
+        // Records are written whole and unbuffered, so the capture is readable even if blueplug
+        // is killed.
+        self.file.write_all(&record)?;
+        self.size += record_len;
+        Ok(())
+    }
+
+    // rotate moves the capture aside, deleting the one moved aside before, and starts a new one.
+    fn rotate(&mut self, now: SystemTime) -> Result<()> {
+        let rotated = rotated_path(&self.path);
+        if let Ok(metadata) = std::fs::metadata(&rotated) {
+            METRICS.store_pruned("capture", metadata.len());
+        }
+        std::fs::rename(&self.path, &rotated)
+            .wrap_err_with(|| format!("moving {} aside", self.path.display()))?;
+        (self.file, self.size) = create(&self.path)?;
+        self.created = now;
+        Ok(())
+    }
+}
+
+// create starts a capture at path and returns it with its size.
+fn create(path: &Path) -> Result<(File, u64)> {
+    let mut file = File::create(path).wrap_err_with(|| format!("creating {}", path.display()))?;
+    let mut header = Vec::with_capacity(24);
+    header.extend(MAGIC.to_le_bytes());
+    header.extend(2u16.to_le_bytes());
+    header.extend(4u16.to_le_bytes());
+    header.extend(0i32.to_le_bytes());
+    header.extend(0u32.to_le_bytes());
+    header.extend(65535u32.to_le_bytes());
+    header.extend(LINKTYPE.to_le_bytes());
+    file.write_all(&header)?;
+    Ok((file, header.len() as u64))
+}
+
+// rotated_path is where a capture at path is moved aside to, e.g. ble.1.pcap for ble.pcap.
+fn rotated_path(path: &Path) -> PathBuf {
+    match path.extension() {
+        Some(extension) => {
+            let mut rotated = OsString::from("1.");
+            rotated.push(extension);
+            path.with_extension(rotated)
+        }
+        None => path.with_extension("1"),
+    }
+}
+
+// packet builds the direction pseudo-header and HCI event for an advertisement.
+fn packet(event: &DeviceEvent) -> Vec<u8> {
+    let device_id = event.device_id();
+    let mut data = Vec::new();
+    ad_structure(
+        &mut data,
+        AD_COMPLETE_LOCAL_NAME,
+        device_id.device_name.as_bytes(),
+    );
+    match event {
+        DeviceEvent::ManufacturerDataAdvertisement {
+            manufacturer_data, ..
+        } => {
+            let mut entries: Vec<_> = manufacturer_data.iter().collect();
+            entries.sort();
+            for (company_id, payload) in entries {
+                let value = [&company_id.to_le_bytes()[..], payload].concat();
+                ad_structure(&mut data, AD_MANUFACTURER_DATA, &value);
+            }
+        }
+        DeviceEvent::ServiceDataAdvertisement { service_data, .. } => {
+            let mut entries: Vec<_> = service_data.iter().collect();
+            entries.sort();
+            for (uuid, payload) in entries {
+                match uuid.to_ble_u16() {
+                    Some(short) => {
+                        let value = [&short.to_le_bytes()[..], payload].concat();
+                        ad_structure(&mut data, AD_SERVICE_DATA_16, &value);
+                    }
+                    None => {
+                        let value = [&uuid.as_u128().to_le_bytes()[..], payload].concat();
+                        ad_structure(&mut data, AD_SERVICE_DATA_128, &value);
+                    }
+                }
+            }
+        }
+    }
+
+    let rssi = event
+        .rssi()
+        .map_or(RSSI_UNAVAILABLE, |rssi| rssi.clamp(-127, 20) as i8 as u8);
+    let address_type = match device_id.random_address {
+        Some(true) => RANDOM_ADDRESS,
+        _ => PUBLIC_ADDRESS,
+    };
+    let report = if data.len() > LEGACY_ADVERTISING_DATA_LEN {
+        extended_report(&device_id.address, address_type, &data, rssi)
+    } else {
+        let mut report = vec![LE_ADVERTISING_REPORT, 1, ADV_IND, address_type];
+        report.extend(address(&device_id.address));
+        report.push(data.len() as u8);
+        report.extend(data);
+        report.push(rssi);
+        report
+    };
+
+    let mut packet = DIRECTION_RECEIVED.to_be_bytes().to_vec();
+    packet.extend([HCI_EVENT_PACKET, LE_META_EVENT, report.len() as u8]);
+    packet.extend(report);
+    packet
+}
+
+// extended_report is an LE Extended Advertising Report, for data too long for a legacy one. The
+// PHYs aren't known, so both are given as LE 1M.
+fn extended_report(address_text: &str, address_type: u8, data: &[u8], rssi: u8) -> Vec<u8> {
+    let mut report = vec![LE_EXTENDED_ADVERTISING_REPORT, 1];
+    report.extend(EXTENDED_ADV);
+    report.push(address_type);
+    report.extend(address(address_text));
+    report.extend([
+        PHY_LE_1M,
+        PHY_LE_1M,
+        NO_ADVERTISING_SID,
+        TX_POWER_UNAVAILABLE,
+        rssi,
+    ]);
+    // No periodic advertising, and not directed.
+    report.extend([0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    report.push(data.len() as u8);
+    report.extend(data);
+    report
+}
+
+fn ad_structure(data: &mut Vec<u8>, ad_type: u8, value: &[u8]) {
+    if value.is_empty() || data.len() + 2 + value.len() > MAX_DATA_LEN {
+        return;
+    }
+    data.push(value.len() as u8 + 1);
+    data.push(ad_type);
+    data.extend(value);
+}
+
+// HCI carries addresses least significant byte first. Platforms that hide addresses get zeros.
+fn address(address: &str) -> [u8; 6] {
+    let mut bytes = [0u8; 6];
+    let parts: Vec<_> = address.split(':').collect();
+    if parts.len() == 6 {
+        for (byte, part) in bytes.iter_mut().zip(parts.iter().rev()) {
+            *byte = u8::from_str_radix(part, 16).unwrap_or_default();
+        }
+    }
+    bytes
+}
+
+// read_capture reads the advertisements in a PCAP file of HCI packets, such as one written by
+// PcapWriter or by btmon, with when each was received. The platform id isn't recorded, so devices
+// are identified as `capture/<address>`, and named after their address if they didn't advertise a
+// name. Packets other than LE advertising reports are skipped, as are all but the first report of
+// an event carrying several.
+pub fn read_capture(path: &Path) -> Result<Vec<(SystemTime, DeviceEvent)>> {
+    let bytes = std::fs::read(path).wrap_err_with(|| format!("reading {}", path.display()))?;
+    let field = |offset: usize| {
+        bytes
+            .get(offset..offset + 4)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    };
+    if field(0) != Some(MAGIC) {
+        return Err(eyre!(
+            "{} isn't a little-endian PCAP file with microsecond timestamps",
+            path.display()
+        ));
+    }
+    if field(20) != Some(LINKTYPE) {
+        return Err(eyre!(
+            "{} doesn't hold HCI packets with direction headers (link type {})",
+            path.display(),
+            LINKTYPE
+        ));
+    }
+    let mut advertisements = Vec::new();
+    let mut offset = 24;
+    while let (Some(seconds), Some(micros), Some(len)) =
+        (field(offset), field(offset + 4), field(offset + 8))
+    {
+        let start = offset + 16;
+        let packet = bytes
+            .get(start..start + len as usize)
+            .ok_or_else(|| eyre!("{} ends within a packet", path.display()))?;
+        let time = UNIX_EPOCH + Duration::new(seconds as u64, micros * 1000);
+        for event in events(packet) {
+            advertisements.push((time, event));
+        }
+        offset = start + len as usize;
+    }
+    Ok(advertisements)
+}
+
+// events parses the advertisement in an LE Advertising Report or LE Extended Advertising Report,
+// an event for the manufacturer data and one for the service data.
+fn events(packet: &[u8]) -> Vec<DeviceEvent> {
+    let Some(&[HCI_EVENT_PACKET, LE_META_EVENT, _, subevent, ref report @ ..]) = packet.get(4..)
+    else {
+        return Vec::new();
+    };
+    let (address_at, data_at) = match subevent {
+        LE_ADVERTISING_REPORT => (3, 9),
+        LE_EXTENDED_ADVERTISING_REPORT => (4, 24),
+        _ => return Vec::new(),
+    };
+    let data = report
+        .get(data_at)
+        .and_then(|len| report.get(data_at + 1..data_at + 1 + *len as usize));
+    // A legacy report ends with the RSSI, an extended one has it ahead of the data.
+    let rssi_at = match (subevent, data) {
+        (LE_ADVERTISING_REPORT, Some(data)) => data_at + 1 + data.len(),
+        _ => 14,
+    };
+    let rssi = report
+        .get(rssi_at)
+        .filter(|rssi| **rssi != RSSI_UNAVAILABLE)
+        .map(|rssi| *rssi as i8 as i16);
+    let (Some(address), Some(mut data)) = (report.get(address_at..address_at + 6), data) else {
+        return Vec::new();
+    };
+    let address = address
+        .iter()
+        .rev()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(":");
+
+    let mut name = None;
+    let mut manufacturer_data = HashMap::new();
+    let mut service_data = HashMap::new();
+    while let [len, rest @ ..] = data {
+        let len = *len as usize;
+        if len == 0 || rest.len() < len {
+            break;
+        }
+        let (structure, next) = rest.split_at(len);
+        data = next;
+        match structure {
+            [AD_COMPLETE_LOCAL_NAME | AD_SHORTENED_LOCAL_NAME, value @ ..] => {
+                name = Some(String::from_utf8_lossy(value).into_owned());
+            }
+            [AD_MANUFACTURER_DATA, a, b, payload @ ..] => {
+                manufacturer_data.insert(u16::from_le_bytes([*a, *b]), payload.to_vec());
+            }
+            [AD_SERVICE_DATA_16, a, b, payload @ ..] => {
+                service_data.insert(
+                    uuid_from_u16(u16::from_le_bytes([*a, *b])),
+                    payload.to_vec(),
+                );
+            }
+            [AD_SERVICE_DATA_128, value @ ..] if value.len() >= 16 => {
+                let (uuid, payload) = value.split_at(16);
+                let uuid = u128::from_le_bytes(uuid.try_into().unwrap_or_default());
+                service_data.insert(Uuid::from_u128(uuid), payload.to_vec());
+            }
+            _ => {}
+        }
+    }
+
+    let device_id = DeviceId {
+        id: format!("capture/{}", address),
+        device_name: name.unwrap_or_else(|| address.clone()),
+        address,
+        // Odd address types are random.
+        random_address: report.get(address_at - 1).map(|t| t & RANDOM_ADDRESS != 0),
+    };
+    let mut events = Vec::new();
+    if !manufacturer_data.is_empty() {
+        events.push(DeviceEvent::ManufacturerDataAdvertisement {
+            device_id: device_id.clone(),
+            manufacturer_data,
+            rssi,
+        });
+    }
+    if !service_data.is_empty() {
+        events.push(DeviceEvent::ServiceDataAdvertisement {
+            device_id,
+            service_data,
+            rssi,
+        });
+    }
+    events
+}
+
+// capture_stream writes every event to writer on its way past.
+pub fn capture_stream(
+    event_stream: impl Stream<Item = Result<DeviceEvent, BlueplugError>>,
+    mut writer: PcapWriter,
+) -> impl Stream<Item = Result<DeviceEvent, BlueplugError>> {
+    stream! {
+        for await event in event_stream {
+            if let Ok(event) = &event {
+                if let Err(e) = writer.write(event, SystemTime::now()) {
+                    println!("capture failed: {:?}", e);
+                }
+            }
+            yield event;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::{Duration, SystemTime};
+
+    use btleplug::api::bleuuid::uuid_from_u16;
+
+    use crate::pcap::{events, packet, PcapWriter};
+    use crate::{DeviceEvent, DeviceId};
+
+    #[test]
+    fn test_packet() {
+        let event = DeviceEvent::ServiceDataAdvertisement {
+            device_id: DeviceId {
+                id: "hci0/dev_A4_C1_38_8F_2C_1A".to_string(),
+                device_name: "ATC".to_string(),
+                address: "A4:C1:38:8F:2C:1A".to_string(),
+                random_address: None,
+            },
+            service_data: HashMap::from([(uuid_from_u16(0xfcd2), vec![0x40, 0x02, 0xc4, 0x09])]),
+            rssi: None,
+        };
+        assert_eq!(
+            packet(&event),
+            vec![
+                0, 0, 0, 1, // received
+                0x04, 0x3e, 25, // HCI LE meta event
+                0x02, 1, 0x00, 0x00, // one advertising report, ADV_IND, public
+                0x1a, 0x2c, 0x8f, 0x38, 0xc1, 0xa4, // address
+                13,   // data length
+                4, 0x09, b'A', b'T', b'C', // name
+                7, 0x16, 0xd2, 0xfc, 0x40, 0x02, 0xc4, 0x09, // service data
+                0x7f, // RSSI
+            ]
+        );
+        // Read back, it's the same advertisement, the platform id aside.
+        let [DeviceEvent::ServiceDataAdvertisement {
+            device_id,
+            service_data,
+            rssi: None,
+        }] = &events(&packet(&event))[..]
+        else {
+            panic!("expected one service data advertisement");
+        };
+        assert_eq!(device_id.id, "capture/A4:C1:38:8F:2C:1A");
+        assert_eq!(device_id.device_name, "ATC");
+        assert_eq!(
+            service_data,
+            &HashMap::from([(uuid_from_u16(0xfcd2), vec![0x40, 0x02, 0xc4, 0x09])])
+        );
+
+        let event = DeviceEvent::ManufacturerDataAdvertisement {
+            device_id: DeviceId {
+                id: "hci0/dev_A4_C1_38_8F_2C_1B".to_string(),
+                device_name: "ATC".to_string(),
+                address: "A4:C1:38:8F:2C:1B".to_string(),
+                random_address: Some(true),
+            },
+            manufacturer_data: HashMap::from([(0x0499, vec![0x05; 40])]),
+            rssi: Some(-60),
+        };
+        assert!(event.is_extended());
+        let packet = packet(&event);
+        // An extended report, with 49 bytes of data: the name and manufacturer data.
+        assert_eq!(packet[4..9], [0x04, 0x3e, 75, 0x0d, 1]);
+        assert_eq!(packet[32], 49);
+        assert_eq!(packet.len(), 33 + 49);
+        let [DeviceEvent::ManufacturerDataAdvertisement {
+            device_id,
+            manufacturer_data,
+            rssi: Some(-60),
+        }] = &events(&packet)[..]
+        else {
+            panic!("expected one manufacturer data advertisement");
+        };
+        assert_eq!(device_id.random_address, Some(true));
+        assert_eq!(manufacturer_data[&0x0499], vec![0x05; 40]);
+    }
+
+    #[test]
+    fn test_capture_rotation() {
+        let directory =
+            std::env::temp_dir().join(format!("blueplug-capture-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("ble.pcap");
+        let rotated = directory.join("ble.1.pcap");
+        let event = DeviceEvent::ServiceDataAdvertisement {
+            device_id: DeviceId {
+                id: "hci0/dev_A4_C1_38_8F_2C_1A".to_string(),
+                device_name: "ATC".to_string(),
+                address: "A4:C1:38:8F:2C:1A".to_string(),
+                random_address: None,
+            },
+            service_data: HashMap::from([(uuid_from_u16(0xfcd2), vec![0x40, 0x02, 0xc4, 0x09])]),
+            rssi: None,
+        };
+        // Records are 16 + 32 bytes after the 24 byte header, so two fit in half of 250.
+        let mut writer = PcapWriter::create(&path)
+            .unwrap()
+            .retention(Some(250), Some(Duration::from_secs(3600)));
+        let start = SystemTime::now();
+        for _ in 0..5 {
+            writer.write(&event, start).unwrap();
+        }
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 24 + 48);
+        assert_eq!(std::fs::metadata(&rotated).unwrap().len(), 24 + 2 * 48);
+
+        // Half an hour on, the capture is rotated however small.
+        writer
+            .write(&event, start + Duration::from_secs(1800))
+            .unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 24 + 48);
+        assert_eq!(std::fs::metadata(&rotated).unwrap().len(), 24 + 48);
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,732 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use color_eyre::eyre::{eyre, Result};
+use futures_core::stream::Stream;
+use futures_util::stream::{select, select_all, StreamExt};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::JoinHandle;
+
+use crate::adapter::AdapterSelector;
+use crate::battery::{battery_stream, BatteryCurves, BatteryDrain};
+use crate::chatter::{chatter_stream, Chatter};
+use crate::config::{
+    BatteryConfig, BatteryDrainConfig, BrokerConfig, ChatterConfig, Config, EsphomeProxyConfig,
+    GattConfig, LabelConfig, MappingConfig, MotionConfig, NameConfig, RouteConfig, RpaConfig,
+};
+use crate::dedup::dedup_stream;
+use crate::derived::{derived_stream, Derivations};
+use crate::error::{BlueplugError, Failure};
+use crate::esphome::esphome_stream;
+use crate::fanout::{devices_match, Fanout};
+use crate::gatt::gatt_stream;
+use crate::keys::KeyStore;
+use crate::latest::LatestReadings;
+use crate::mapping::{mapping_stream, suppressed};
+use crate::metrics::{Counters, Stage, METRICS};
+use crate::motion::{motion_readings, motion_stream, Motion};
+use crate::mqtt::spawn_broker;
+use crate::names::NameRules;
+use crate::pcap::{capture_stream, PcapWriter};
+use crate::plugin::{Plugin, PluginHost};
+use crate::rpa::{rpa_stream, Resolver};
+use crate::scope::{scope_stream, Scope};
+use crate::script::{script_stream, Scripts};
+use crate::supervisor::{Supervisor, SUPERVISOR};
+use crate::{
+    bt_stream, device_reading_stream, DeviceEvent, DeviceReading, Kind, DEFAULT_SCAN_STALL_TIMEOUT,
+};
+
+// Identical advertisements from a device within this long are dropped, as the binary does by
+// default.
+const DEFAULT_DEDUP_WINDOW: Duration = Duration::from_secs(2);
+
+const DEFAULT_CHANNEL_CAPACITY: usize = 256;
+
+type Filter = Box<dyn Fn(&DeviceReading) -> bool + Send>;
+type Observer = Box<dyn Fn(&DeviceEvent) + Send>;
+type SinkFn = Box<dyn FnMut(Arc<DeviceReading>) + Send>;
+type EventSource = Pin<Box<dyn Stream<Item = Result<DeviceEvent, BlueplugError>> + Send>>;
+type ReadingStream = Pin<Box<dyn Stream<Item = DeviceReading> + Send>>;
+
+// Blueplug decodes readings for applications embedding blueplug, such as home automation daemons.
+// It scans an adapter and any ESPHome proxies, reads GATT devices and applies mappings and
+// derivations, then either returns the readings as a stream or delivers them to sinks: MQTT
+// brokers and the application's own callbacks.
+pub struct Blueplug {
+    scan: bool,
+    local_scan: bool,
+    adapter: Option<AdapterSelector>,
+    esphome_proxies: Vec<EsphomeProxyConfig>,
+    sources: Vec<EventSource>,
+    capture: Option<PcapWriter>,
+    observers: Vec<Observer>,
+    gatt: Vec<GattConfig>,
+    gatt_connections: Option<usize>,
+    motion: Motion,
+    mappings: Vec<MappingConfig>,
+    battery_curves: BatteryCurves,
+    battery_drain: BatteryDrain,
+    derivations: Derivations,
+    scripts: Scripts,
+    resolver: Resolver,
+    chatter: Chatter,
+    plugins: PluginHost,
+    keys: KeyStore,
+    filters: Vec<Filter>,
+    brokers: Vec<BrokerConfig>,
+    routes: Vec<RouteConfig>,
+    sinks: Vec<(String, SinkFn)>,
+    channel_capacity: usize,
+    dedup_window: Duration,
+    scan_stall_timeout: Option<Duration>,
+    supervisor: Supervisor,
+    scope: Arc<Scope>,
+}
+
+impl Blueplug {
+    pub fn builder() -> BlueplugBuilder {
+        BlueplugBuilder::default()
+    }
+
+    // readings starts scanning and returns every decoded reading that passes the filters. Errors
+    // from the adapter or proxies are logged, and the stream ends if scanning stops. Sinks are
+    // only delivered to by spawn. The binary runs this same pipeline.
+    pub fn readings(self) -> impl Stream<Item = DeviceReading> {
+        let mut sources = self.sources;
+        let mut gatt = self.gatt;
+        if self.scan && !self.local_scan && !gatt.is_empty() {
+            println!("not reading GATT devices, which need the local adapter");
+            gatt.clear();
+        }
+        if self.scan {
+            if self.local_scan {
+                sources.push(Box::pin(bt_stream(
+                    self.adapter.clone(),
+                    self.scan_stall_timeout,
+                )));
+            }
+            for proxy in self.esphome_proxies {
+                sources.push(Box::pin(esphome_stream(proxy)));
+            }
+        } else {
+            gatt.clear();
+        }
+        let mut events: EventSource = Box::pin(select_all(sources));
+        if let Some(writer) = self.capture {
+            events = Box::pin(capture_stream(events, writer));
+        }
+        let observers = self.observers;
+        // Observers see every advertisement, decoding only those of devices that have advertised
+        // a name.
+        let events = rpa_stream(events, self.resolver)
+            .inspect(move |event| {
+                if let Ok(event) = event {
+                    for observe in &observers {
+                        observe(event);
+                    }
+                }
+            })
+            .filter(|event| {
+                std::future::ready(
+                    event
+                        .as_ref()
+                        .map_or(true, |event| !event.device_id().device_name.is_empty()),
+                )
+            })
+            .inspect(|event| {
+                if let Ok(event) = event {
+                    METRICS.advertisement(Stage::Seen, event);
+                    if event.is_extended() {
+                        METRICS.advertisement(Stage::Extended, event);
+                    }
+                }
+            });
+        let events = chatter_stream(events, self.chatter);
+        let events = dedup_stream(events, self.dedup_window);
+        let (motion_sender, motion_receiver) = mpsc::unbounded_channel();
+        let events = motion_stream(events, self.motion, motion_sender);
+
+        let readings = device_reading_stream(events, self.plugins, self.keys);
+        let readings = select(
+            readings,
+            gatt_stream(
+                self.supervisor.clone(),
+                self.adapter,
+                gatt,
+                self.gatt_connections,
+            ),
+        );
+        let readings = select(readings, motion_readings(motion_receiver));
+        let readings = mapping_stream(readings, self.mappings);
+        let readings = battery_stream(readings, self.battery_curves, self.battery_drain);
+        let readings = derived_stream(readings, self.derivations);
+        let filters = self.filters;
+        let readings = script_stream(readings, self.scripts).filter(move |reading| {
+            std::future::ready(filters.iter().all(|filter| filter(reading)))
+        });
+        // The stream is polled by the caller's tasks, which must see the instance's scope too.
+        scope_stream(Some(self.scope), readings)
+    }
+
+    // readings_for returns the readings of the devices matching pattern, by id or by name with `*`
+    // as a wildcard, as in routes.
+    pub fn readings_for(self, pattern: &str) -> impl Stream<Item = DeviceReading> {
+        let patterns = vec![pattern.to_string()];
+        self.readings()
+            .filter(move |reading| std::future::ready(devices_match(&patterns, &reading.device_id)))
+    }
+
+    // readings_of_kind returns the readings of one kind of measurement, after mappings and
+    // derivations, so a derived kind such as dew_point can be asked for too. Kinds are compared by
+    // name, as a built-in kind in another unit is Other.
+    pub fn readings_of_kind(self, kind: Kind) -> impl Stream<Item = DeviceReading> {
+        self.readings().filter(move |reading| {
+            std::future::ready(Kind::from(&reading.measurement).name() == kind.name())
+        })
+    }
+
+    // spawn starts delivering readings to the brokers and sinks in the background, following the
+    // routes. It must be called within a Tokio runtime.
+    pub fn spawn(mut self) -> Result<BlueplugHandle, BlueplugError> {
+        let routes = std::mem::take(&mut self.routes);
+        let mut fanout = Fanout::with_capacity(routes, self.channel_capacity);
+        let brokers: Vec<_> = std::mem::take(&mut self.brokers)
+            .into_iter()
+            .map(|broker| (fanout.subscribe(&broker.name), broker))
+            .collect();
+        let sinks: Vec<_> = std::mem::take(&mut self.sinks)
+            .into_iter()
+            .map(|(name, sink)| (fanout.subscribe(&name), name, sink))
+            .collect();
+        fanout
+            .validate()
+            .map_err(|e| BlueplugError::Config(e.into()))?;
+
+        // Embedded instances don't track presence or run rules, but brokers still expect to be
+        // told of changes and actions.
+        let (presence_changes, _) = broadcast::channel(1);
+        let (actions, _) = broadcast::channel(1);
+        let supervisor = self.supervisor.clone();
+        let scope = self.scope.clone();
+        for (readings, broker) in brokers {
+            let presence = presence_changes.subscribe();
+            let name = broker.name.clone();
+            let latest = LatestReadings::default();
+            spawn_broker(
+                &supervisor,
+                broker,
+                readings,
+                latest,
+                None,
+                presence,
+                actions.subscribe(),
+            )
+            .map_err(|e| BlueplugError::Sink {
+                name,
+                failure: e.into(),
+            })?;
+        }
+        // Tasks are restarted after a panic, such as one in a sink, carrying on with the state
+        // they share with every start of theirs.
+        for (readings, name, sink) in sinks {
+            let state = Arc::new(tokio::sync::Mutex::new((readings, sink)));
+            supervisor.spawn(name.clone(), move || {
+                let state = state.clone();
+                let name = name.clone();
+                async move {
+                    let (readings, sink) = &mut *state.lock().await;
+                    loop {
+                        match readings.recv().await {
+                            Ok(reading) => sink(reading),
+                            Err(RecvError::Lagged(skipped)) => {
+                                println!("{}: falling behind, dropped {} readings", name, skipped);
+                            }
+                            Err(RecvError::Closed) => break,
+                        }
+                    }
+                }
+            });
+        }
+
+        let readings: ReadingStream = Box::pin(self.readings());
+        let state = Arc::new(tokio::sync::Mutex::new((readings, fanout)));
+        let task = supervisor.spawn("blueplug", move || {
+            // The brokers' channels stay open as long as the task may run.
+            let _channels = (&presence_changes, &actions);
+            let state = state.clone();
+            async move {
+                let (readings, fanout) = &mut *state.lock().await;
+                while let Some(reading) = readings.next().await {
+                    fanout.send(Arc::new(reading));
+                }
+                println!("bluetooth event stream ended");
+            }
+        });
+        Ok(BlueplugHandle {
+            supervisor,
+            task,
+            scope,
+        })
+    }
+}
+
+// BlueplugHandle controls a spawned Blueplug.
+pub struct BlueplugHandle {
+    supervisor: Supervisor,
+    task: JoinHandle<()>,
+    scope: Arc<Scope>,
+}
+
+impl BlueplugHandle {
+    // shutdown stops scanning, GATT connections, the brokers and the sinks of this instance.
+    pub fn shutdown(&self) {
+        self.supervisor.shutdown();
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.task.is_finished()
+    }
+
+    // stats returns the counters of advertisements and readings this instance has seen so far,
+    // by protocol and device, like those served at /metrics.
+    pub fn stats(&self) -> BTreeMap<String, BTreeMap<String, Counters>> {
+        self.scope.metrics.snapshot()
+    }
+}
+
+// BlueplugBuilder configures a Blueplug, either from a configuration file's sections or piece by
+// piece.
+pub struct BlueplugBuilder {
+    scan: bool,
+    local_scan: bool,
+    adapter: Option<AdapterSelector>,
+    esphome_proxies: Vec<EsphomeProxyConfig>,
+    sources: Vec<EventSource>,
+    capture: Option<PcapWriter>,
+    observers: Vec<Observer>,
+    gatt: Vec<GattConfig>,
+    gatt_connections: Option<usize>,
+    motion: Vec<MotionConfig>,
+    exempt_kinds: Vec<String>,
+    mappings: Vec<MappingConfig>,
+    battery: Vec<BatteryConfig>,
+    battery_drain: Option<BatteryDrainConfig>,
+    derived: BTreeMap<String, String>,
+    scripts: Vec<PathBuf>,
+    rpa: RpaConfig,
+    chatter: ChatterConfig,
+    names: Vec<NameConfig>,
+    locations: BTreeMap<String, Vec<String>>,
+    labels: Vec<LabelConfig>,
+    plugins: PluginHost,
+    keys_file: Option<PathBuf>,
+    filters: Vec<Filter>,
+    brokers: Vec<BrokerConfig>,
+    routes: Vec<RouteConfig>,
+    sinks: Vec<(String, SinkFn)>,
+    channel_capacity: usize,
+    dedup_window: Duration,
+    scan_stall_timeout: Option<Duration>,
+    unsupported: Vec<&'static str>,
+}
+
+impl Default for BlueplugBuilder {
+    fn default() -> Self {
+        BlueplugBuilder {
+            scan: true,
+            local_scan: true,
+            adapter: None,
+            esphome_proxies: Vec::new(),
+            sources: Vec::new(),
+            capture: None,
+            observers: Vec::new(),
+            gatt: Vec::new(),
+            gatt_connections: None,
+            motion: Vec::new(),
+            exempt_kinds: Vec::new(),
+            mappings: Vec::new(),
+            battery: Vec::new(),
+            battery_drain: None,
+            derived: BTreeMap::new(),
+            scripts: Vec::new(),
+            rpa: RpaConfig::default(),
+            chatter: ChatterConfig::default(),
+            names: Vec::new(),
+            locations: BTreeMap::new(),
+            labels: Vec::new(),
+            plugins: PluginHost::default(),
+            keys_file: None,
+            filters: Vec::new(),
+            brokers: Vec::new(),
+            routes: Vec::new(),
+            sinks: Vec::new(),
+            channel_capacity: DEFAULT_CHANNEL_CAPACITY,
+            dedup_window: DEFAULT_DEDUP_WINDOW,
+            scan_stall_timeout: Some(DEFAULT_SCAN_STALL_TIMEOUT),
+            unsupported: Vec::new(),
+        }
+    }
+}
+
+impl BlueplugBuilder {
+    // config adds the sources, brokers, routes, mappings, motion tags, exempt kinds, battery curves
+    // and drain, derivations, scripts, suppressed kinds, RPA keys, chatter recognizers, name rules,
+    // locations, labels and bind keys of a configuration file. The other sections are run by the
+    // binary alone, so build fails if any of them are set rather than silently ignoring them.
+    pub fn config(mut self, config: &Config) -> Self {
+        self.unsupported.extend(unsupported(config));
+        self.pipeline(config)
+    }
+
+    // pipeline adds the sections of config that config does, leaving the others to the binary.
+    pub(crate) fn pipeline(mut self, config: &Config) -> Self {
+        self.esphome_proxies.extend(config.esphome_proxies.clone());
+        self.gatt.extend(config.gatt.clone());
+        self.gatt_connections = config.gatt_connections;
+        self.motion.extend(config.motion.clone());
+        self.exempt_kinds.extend(config.exempt_kinds.clone());
+        self.mappings.extend(config.mappings.clone());
+        self.battery.extend(config.battery.clone());
+        self.battery_drain = config.battery_drain.clone();
+        let suppress = config.suppress.clone();
+        self.filters
+            .push(Box::new(move |reading| !suppressed(&suppress, reading)));
+        self.derived.extend(config.derived.clone());
+        self.scripts.extend(config.scripts.clone());
+        self.rpa = config.rpa.clone();
+        self.chatter = config.chatter.clone();
+        self.names.extend(config.names.clone());
+        self.locations.extend(config.locations.clone());
+        self.labels.extend(config.labels.clone());
+        if config.keys_file.is_some() {
+            self.keys_file = config.keys_file.clone();
+        }
+        self.brokers.extend(config.brokers.clone());
+        self.routes.extend(config.routes.clone());
+        self
+    }
+
+    // adapter selects the Bluetooth adapter to scan with, the first one by default.
+    pub fn adapter(mut self, adapter: AdapterSelector) -> Self {
+        self.adapter = Some(adapter);
+        self
+    }
+
+    // scan turns scanning the adapter and ESPHome proxies, and reading GATT devices, on or off.
+    // Without it, only the added sources are decoded.
+    pub fn scan(mut self, scan: bool) -> Self {
+        self.scan = scan;
+        self
+    }
+
+    // local_scan turns scanning the local adapter on or off, for hosts without Bluetooth that only
+    // receive advertisements from ESPHome proxies or forwarders. GATT devices are read through
+    // the local adapter, so they aren't read without it.
+    pub fn local_scan(mut self, local_scan: bool) -> Self {
+        self.local_scan = local_scan;
+        self
+    }
+
+    pub fn esphome_proxy(mut self, proxy: EsphomeProxyConfig) -> Self {
+        self.esphome_proxies.push(proxy);
+        self
+    }
+
+    // source adds a stream of advertisements decoded alongside the scanned ones, such as those
+    // forwarded by other instances.
+    pub fn source(
+        mut self,
+        events: impl Stream<Item = Result<DeviceEvent, BlueplugError>> + Send + 'static,
+    ) -> Self {
+        self.sources.push(Box::pin(events));
+        self
+    }
+
+    // capture writes every advertisement received to a PCAP file.
+    pub(crate) fn capture(mut self, writer: PcapWriter) -> Self {
+        self.capture = Some(writer);
+        self
+    }
+
+    // observe calls sighting with every advertisement received, before it is deduplicated, even
+    // those of devices that haven't advertised a name, which have an empty device name.
+    pub fn observe(mut self, sighting: impl Fn(&DeviceEvent) + Send + 'static) -> Self {
+        self.observers.push(Box::new(sighting));
+        self
+    }
+
+    pub fn gatt(mut self, device: GattConfig) -> Self {
+        self.gatt.push(device);
+        self
+    }
+
+    // gatt_connections caps how many GATT devices are connected at once.
+    pub fn gatt_connections(mut self, connections: usize) -> Self {
+        self.gatt_connections = Some(connections);
+        self
+    }
+
+    // decoder adds a WASM decoder plugin, tried on every advertisement alongside the built-in
+    // decoders.
+    pub fn decoder(mut self, plugin: Plugin) -> Self {
+        self.plugins.push(plugin);
+        self
+    }
+
+    // keys_file decrypts the advertisements of devices with a bind key in the file, as managed by
+    // `blueplug keys`.
+    pub fn keys_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.keys_file = Some(path.into());
+        self
+    }
+
+    pub fn mapping(mut self, mapping: MappingConfig) -> Self {
+        self.mappings.push(mapping);
+        self
+    }
+
+    // derived adds a kind computed from other kinds of the same device, as in `[derived]`.
+    pub fn derived(mut self, kind: impl Into<String>, expression: impl Into<String>) -> Self {
+        self.derived.insert(kind.into(), expression.into());
+        self
+    }
+
+    // script adds a Rhai script transforming readings, as in `scripts`, run after derivations.
+    pub fn script(mut self, path: impl Into<PathBuf>) -> Self {
+        self.scripts.push(path.into());
+        self
+    }
+
+    // filter drops the readings for which keep returns false, before they reach any sink.
+    pub fn filter(mut self, keep: impl Fn(&DeviceReading) -> bool + Send + 'static) -> Self {
+        self.filters.push(Box::new(keep));
+        self
+    }
+
+    pub fn broker(mut self, broker: BrokerConfig) -> Self {
+        self.brokers.push(broker);
+        self
+    }
+
+    pub fn route(mut self, route: RouteConfig) -> Self {
+        self.routes.push(route);
+        self
+    }
+
+    // sink calls deliver with every reading routed to name, on a task of its own.
+    pub fn sink(
+        mut self,
+        name: impl Into<String>,
+        deliver: impl FnMut(Arc<DeviceReading>) + Send + 'static,
+    ) -> Self {
+        self.sinks.push((name.into(), Box::new(deliver)));
+        self
+    }
+
+    // channel_capacity is how many readings each sink may fall behind before it starts dropping
+    // the oldest.
+    pub fn channel_capacity(mut self, capacity: usize) -> Self {
+        self.channel_capacity = capacity;
+        self
+    }
+
+    pub fn dedup_window(mut self, window: Duration) -> Self {
+        self.dedup_window = window;
+        self
+    }
+
+    // scan_stall_timeout is how long the adapter may go without events, after it has had some,
+    // before scanning is restarted, 5 minutes by default. None never restarts it.
+    pub fn scan_stall_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.scan_stall_timeout = timeout;
+        self
+    }
+
+    pub fn build(self) -> Result<Blueplug, BlueplugError> {
+        if !self.unsupported.is_empty() {
+            return Err(BlueplugError::Config(Failure::from(eyre!(
+                "{} only supported by the blueplug binary",
+                self.unsupported.join(", ")
+            ))));
+        }
+        let battery_curves =
+            BatteryCurves::new(&self.battery).map_err(|e| BlueplugError::Config(e.into()))?;
+        let derivations =
+            Derivations::new(&self.derived).map_err(|e| BlueplugError::Config(e.into()))?;
+        let scripts = Scripts::new(&self.scripts).map_err(|e| BlueplugError::Config(e.into()))?;
+        let resolver = Resolver::new(&self.rpa).map_err(|e| BlueplugError::Config(e.into()))?;
+        let chatter = Chatter::new(&self.chatter).map_err(|e| BlueplugError::Config(e.into()))?;
+        let names = NameRules::new(&self.names).map_err(|e| BlueplugError::Config(e.into()))?;
+        let keys = match &self.keys_file {
+            Some(path) => KeyStore::load(path).map_err(|e| BlueplugError::Config(e.into()))?,
+            None => KeyStore::default(),
+        };
+        // Topic names and exempt kinds are the instance's own, so another instance built in the
+        // same process doesn't change them.
+        let scope = Arc::new(Scope::default());
+        scope.names.set_rules(names);
+        scope.names.set_locations(self.locations);
+        scope.names.set_labels(self.labels);
+        scope.exempt.set_kinds(self.exempt_kinds);
+        Ok(Blueplug {
+            scan: self.scan,
+            local_scan: self.local_scan,
+            adapter: self.adapter,
+            esphome_proxies: self.esphome_proxies,
+            sources: self.sources,
+            capture: self.capture,
+            observers: self.observers,
+            gatt: self.gatt,
+            gatt_connections: self.gatt_connections,
+            motion: Motion::new(&self.motion),
+            mappings: self.mappings,
+            battery_curves,
+            battery_drain: BatteryDrain::new(self.battery_drain.as_ref()),
+            derivations,
+            scripts,
+            resolver,
+            chatter,
+            plugins: self.plugins,
+            keys,
+            filters: self.filters,
+            brokers: self.brokers,
+            routes: self.routes,
+            sinks: self.sinks,
+            channel_capacity: self.channel_capacity,
+            dedup_window: self.dedup_window,
+            scan_stall_timeout: self.scan_stall_timeout,
+            supervisor: SUPERVISOR.child().with_scope(scope.clone()),
+            scope,
+        })
+    }
+}
+
+// unsupported lists the sections of config that only the binary runs.
+fn unsupported(config: &Config) -> Vec<&'static str> {
+    let sections = [
+        ("[stats]", config.stats.is_some()),
+        ("[archive]", config.archive.is_some()),
+        ("[[grafana]]", !config.grafana.is_empty()),
+        ("[[zabbix]]", !config.zabbix.is_empty()),
+        ("[[postgres]]", !config.postgres.is_empty()),
+        ("[[exec]]", !config.exec.is_empty()),
+        ("[snmp]", config.snmp.is_some()),
+        ("[modbus]", config.modbus.is_some()),
+        ("[knx]", config.knx.is_some()),
+        ("[mdns]", config.mdns.is_some()),
+        ("[grpc]", config.grpc.is_some()),
+        ("[socket]", config.socket.is_some()),
+        ("[presence]", config.presence.is_some()),
+        ("[[rules]]", !config.rules.is_empty()),
+        ("[[watchdog]]", !config.watchdog.is_empty()),
+        ("[[availability]]", !config.availability.is_empty()),
+        ("[commands]", config.commands.is_some()),
+        ("[[actions]]", !config.actions.is_empty()),
+        (
+            "[ingest]",
+            config.ingest.listen.is_some() || config.ingest.broker.is_some(),
+        ),
+        ("state_file", config.state_file.is_some()),
+    ];
+    sections
+        .into_iter()
+        .filter(|(_, set)| *set)
+        .map(|(section, _)| section)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use crate::config::{Config, IngestConfig, RouteConfig};
+    use crate::error::BlueplugError;
+    use crate::exempt::EXEMPT;
+    use crate::names::TOPIC_NAMES;
+    use crate::scope;
+    use crate::{Blueplug, DeviceId};
+
+    #[test]
+    fn test_builder() {
+        assert!(Blueplug::builder()
+            .derived("f", "temperature *")
+            .build()
+            .is_err());
+
+        // Routes are checked against the sinks before anything is started.
+        let blueplug = Blueplug::builder()
+            .sink("app", |_| {})
+            .route(RouteConfig {
+                sinks: vec!["ap".to_string()],
+                devices: Vec::new(),
+                kinds: vec!["temperature".to_string()],
+            })
+            .build()
+            .unwrap();
+        let error = blueplug.spawn().err().unwrap();
+        assert!(matches!(error, BlueplugError::Config(_)));
+        assert_eq!(
+            error.to_string(),
+            "invalid configuration: route refers to unknown sink \"ap\""
+        );
+
+        // Sections only the binary runs aren't silently ignored.
+        let config = Config {
+            ingest: IngestConfig {
+                broker: Some("home".to_string()),
+                ..IngestConfig::default()
+            },
+            state_file: Some("latest.json".into()),
+            ..Config::default()
+        };
+        let error = Blueplug::builder().config(&config).build().err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "invalid configuration: [ingest], state_file only supported by the blueplug binary"
+        );
+        assert!(Blueplug::builder().pipeline(&config).build().is_ok());
+        let config = Config::parse("[[postgres]]\nname = \"db\"\nurl = \"host=db\"\n").unwrap();
+        let error = Blueplug::builder().config(&config).build().err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "invalid configuration: [[postgres]] only supported by the blueplug binary"
+        );
+    }
+
+    #[test]
+    fn test_instances_keep_their_naming() {
+        let config = |kind: &str, location: &str| Config {
+            exempt_kinds: vec![kind.to_string()],
+            locations: BTreeMap::from([(location.to_string(), vec!["ATC_*".to_string()])]),
+            ..Config::default()
+        };
+        let kitchen = Blueplug::builder()
+            .pipeline(&config("leak", "kitchen"))
+            .build()
+            .unwrap();
+        let garage = Blueplug::builder()
+            .pipeline(&config("button", "garage"))
+            .build()
+            .unwrap();
+        let device_id = DeviceId {
+            id: "hci0/A4:C1:38:8F:2C:1A".to_string(),
+            device_name: "ATC_8F2C1A".to_string(),
+            address: "A4:C1:38:8F:2C:1A".to_string(),
+            random_address: None,
+        };
+        let naming = |blueplug: &Blueplug| {
+            scope::sync_scope(Some(blueplug.scope.clone()), || {
+                (EXEMPT.contains("leak"), TOPIC_NAMES.location(&device_id))
+            })
+        };
+        assert_eq!(naming(&kitchen), (true, Some("kitchen".to_string())));
+        assert_eq!(naming(&garage), (false, Some("garage".to_string())));
+        // Building them left the process-wide ones alone.
+        assert!(!EXEMPT.contains("leak"));
+    }
+}
@@ -0,0 +1,365 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use uuid::Uuid;
+use wasmi::{
+    Caller, Config, Engine, Linker, Memory, Module, Store, StoreLimits, StoreLimitsBuilder,
+    TypedFunc,
+};
+
+use crate::Measurement;
+
+// Generous enough for any sane decoder, small enough to bound a runaway loop.
+const FUEL_PER_CALL: u64 = 10_000_000;
+// Plenty for parsing an advertisement, and keeps a plugin from growing into gigabytes of memory.
+const MEMORY_LIMIT: usize = 16 << 20;
+
+// Plugin is an experimental decoder compiled to WebAssembly, which lets users decode proprietary
+// advertisements without forking blueplug. It is a `.wasm` module exporting:
+//
+// - `memory`: the plugin's linear memory.
+// - `blueplug_alloc(len: i32) -> i32`: reserves `len` bytes and returns a pointer to them.
+// - `blueplug_free(ptr: i32, len: i32)` (optional): releases memory handed out by
+//   `blueplug_alloc` or returned from a decode call.
+// - `blueplug_decode_manufacturer_data(company_id: i32, ptr: i32, len: i32) -> i64` and/or
+//   `blueplug_decode_service_data(uuid_ptr: i32, ptr: i32, len: i32) -> i64`, where `uuid_ptr`
+//   points at the 16 big-endian bytes of the service UUID.
+//
+// Decode calls return `(ptr << 32) | len` locating a UTF-8 JSON array of measurements in the
+// plugin's memory, e.g. `[{"kind":"temperature","value":21.5}]`, or 0 when the payload isn't
+// one the plugin understands. Kinds other than the built-in ones may carry a `unit`, e.g.
+// `{"kind":"moisture","value":40,"unit":"%"}`.
+//
+// The host exposes a single import, `blueplug.log(ptr: i32, len: i32)`, which prints a UTF-8
+// message for debugging. Each call is metered so a misbehaving plugin can't stall the bridge, and
+// its memory is capped at 16 MiB; growing past that traps the call.
+pub struct Plugin {
+    name: String,
+    store: Store<StoreLimits>,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    free: Option<TypedFunc<(i32, i32), ()>>,
+    decode_manufacturer_data: Option<TypedFunc<(i32, i32, i32), i64>>,
+    decode_service_data: Option<TypedFunc<(i32, i32, i32), i64>>,
+    // Whether the last decode failed, so a plugin failing on every input is only logged once.
+    failing: bool,
+}
+
+impl Plugin {
+    pub fn load(path: &Path) -> Result<Self> {
+        let wasm = std::fs::read(path).wrap_err_with(|| format!("reading {}", path.display()))?;
+        Self::from_bytes(path.display().to_string(), &wasm)
+    }
+
+    pub fn from_bytes(name: impl Into<String>, wasm: &[u8]) -> Result<Self> {
+        let name = name.into();
+        let mut config = Config::default();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config);
+        let module = Module::new(&engine, wasm).map_err(|e| eyre!("{name}: {e}"))?;
+        let limits = StoreLimitsBuilder::new()
+            .memory_size(MEMORY_LIMIT)
+            .instances(1)
+            .trap_on_grow_failure(true)
+            .build();
+        let mut store = Store::new(&engine, limits);
+        store.limiter(|limits| limits);
+
+        let mut linker = Linker::<StoreLimits>::new(&engine);
+        let log_name = name.clone();
+        linker
+            .func_wrap(
+                "blueplug",
+                "log",
+                move |caller: Caller<'_, StoreLimits>, ptr: i32, len: i32| {
+                    let memory = caller.get_export("memory").and_then(|e| e.into_memory());
+                    if let Some(bytes) = memory.and_then(|m| {
+                        m.data(&caller)
+                            .get(ptr as usize..(ptr as usize).saturating_add(len as usize))
+                    }) {
+                        println!("plugin {}: {}", log_name, String::from_utf8_lossy(bytes));
+                    }
+                },
+            )
+            .map_err(|e| eyre!("{name}: {e}"))?;
+
+        store
+            .set_fuel(FUEL_PER_CALL)
+            .map_err(|e| eyre!("{name}: {e}"))?;
+        let instance = linker
+            .instantiate(&mut store, &module)
+            .and_then(|pre| pre.start(&mut store))
+            .map_err(|e| eyre!("{name}: {e}"))?;
+
+        let memory = instance
+            .get_memory(&store, "memory")
+            .ok_or(eyre!("{name}: plugin does not export memory"))?;
+        let alloc = instance
+            .get_typed_func(&store, "blueplug_alloc")
+            .map_err(|e| eyre!("{name}: blueplug_alloc: {e}"))?;
+        let free = instance.get_typed_func(&store, "blueplug_free").ok();
+        let decode_manufacturer_data = instance
+            .get_typed_func(&store, "blueplug_decode_manufacturer_data")
+            .ok();
+        let decode_service_data = instance
+            .get_typed_func(&store, "blueplug_decode_service_data")
+            .ok();
+
+        if decode_manufacturer_data.is_none() && decode_service_data.is_none() {
+            return Err(eyre!("{name}: plugin exports no decode functions"));
+        }
+
+        Ok(Plugin {
+            name,
+            store,
+            memory,
+            alloc,
+            free,
+            decode_manufacturer_data,
+            decode_service_data,
+            failing: false,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // report logs the first of a run of failed decodes, and the decode that ends it.
+    fn report<T>(&mut self, result: Result<T>) -> Option<T> {
+        match result {
+            Ok(value) => {
+                if std::mem::take(&mut self.failing) {
+                    println!("plugin {} is decoding again", self.name);
+                }
+                Some(value)
+            }
+            Err(e) => {
+                if !std::mem::replace(&mut self.failing, true) {
+                    println!(
+                        "plugin {} failed, not logging further failures until it recovers: {:?}",
+                        self.name,
+                        e.to_string()
+                    );
+                }
+                None
+            }
+        }
+    }
+
+    pub fn decode_manufacturer_data(
+        &mut self,
+        company_id: u16,
+        data: &[u8],
+    ) -> Result<Vec<Measurement>> {
+        let Some(decode) = self.decode_manufacturer_data else {
+            return Ok(Vec::new());
+        };
+        self.store
+            .set_fuel(FUEL_PER_CALL)
+            .map_err(|e| eyre!("{e}"))?;
+        let ptr = self.write(data)?;
+        let packed = decode
+            .call(&mut self.store, (company_id as i32, ptr, data.len() as i32))
+            .map_err(|e| eyre!("{e}"));
+        self.release(ptr, data.len() as i32);
+        self.read_measurements(packed?)
+    }
+
+    pub fn decode_service_data(&mut self, uuid: &Uuid, data: &[u8]) -> Result<Vec<Measurement>> {
+        let Some(decode) = self.decode_service_data else {
+            return Ok(Vec::new());
+        };
+        self.store
+            .set_fuel(FUEL_PER_CALL)
+            .map_err(|e| eyre!("{e}"))?;
+        let uuid_ptr = self.write(uuid.as_bytes())?;
+        let ptr = self.write(data)?;
+        let packed = decode
+            .call(&mut self.store, (uuid_ptr, ptr, data.len() as i32))
+            .map_err(|e| eyre!("{e}"));
+        self.release(uuid_ptr, 16);
+        self.release(ptr, data.len() as i32);
+        self.read_measurements(packed?)
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<i32> {
+        let ptr = self
+            .alloc
+            .call(&mut self.store, bytes.len() as i32)
+            .map_err(|e| eyre!("blueplug_alloc: {e}"))?;
+        self.memory
+            .write(&mut self.store, ptr as usize, bytes)
+            .map_err(|e| eyre!("writing to plugin memory: {e}"))?;
+        Ok(ptr)
+    }
+
+    fn release(&mut self, ptr: i32, len: i32) {
+        if let Some(free) = self.free {
+            // A failing free only leaks plugin memory; the decode result is still usable.
+            let _ = free.call(&mut self.store, (ptr, len));
+        }
+    }
+
+    fn read_measurements(&mut self, packed: i64) -> Result<Vec<Measurement>> {
+        if packed == 0 {
+            return Ok(Vec::new());
+        }
+        let ptr = (packed >> 32) as u32 as usize;
+        let len = packed as u32 as usize;
+        // The result is parsed where it lies, so a bogus length can't make the host allocate.
+        let data = self.memory.data(&self.store);
+        let result = ptr
+            .checked_add(len)
+            .and_then(|end| data.get(ptr..end))
+            .ok_or_else(|| eyre!("plugin result of {len} bytes at {ptr} is outside its memory"))?;
+        let measurements = serde_json::from_slice(result).wrap_err("parsing plugin result");
+        self.release(ptr as i32, len as i32);
+        measurements
+    }
+}
+
+#[derive(Default)]
+pub struct PluginHost {
+    plugins: Vec<Plugin>,
+}
+
+impl PluginHost {
+    pub fn load(paths: &[PathBuf]) -> Result<Self> {
+        let plugins = paths
+            .iter()
+            .map(|path| Plugin::load(path))
+            .collect::<Result<Vec<_>>>()?;
+        for plugin in &plugins {
+            println!("loaded decoder plugin {}", plugin.name());
+        }
+        Ok(PluginHost { plugins })
+    }
+
+    pub fn push(&mut self, plugin: Plugin) {
+        self.plugins.push(plugin);
+    }
+
+    pub fn measurements_from_manufacturer_data(
+        &mut self,
+        manufacturer_data: &HashMap<u16, Vec<u8>>,
+    ) -> Vec<Measurement> {
+        let mut measurements = Vec::new();
+        for plugin in self.plugins.iter_mut() {
+            for (id, data) in manufacturer_data {
+                let decoded = plugin.decode_manufacturer_data(*id, data);
+                measurements.extend(plugin.report(decoded).unwrap_or_default());
+            }
+        }
+        measurements
+    }
+
+    pub fn measurements_from_service_data(
+        &mut self,
+        service_data: &HashMap<Uuid, Vec<u8>>,
+    ) -> Vec<Measurement> {
+        let mut measurements = Vec::new();
+        for plugin in self.plugins.iter_mut() {
+            for (uuid, data) in service_data {
+                let decoded = plugin.decode_service_data(uuid, data);
+                measurements.extend(plugin.report(decoded).unwrap_or_default());
+            }
+        }
+        measurements
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::plugin::Plugin;
+    use crate::Measurement;
+
+    // Answers any 0xffff manufacturer payload of at least two bytes with a fixed reading.
+    const FIXED_READING: &str = r#"
+        (module
+          (memory (export "memory") 1)
+          (global $next (mut i32) (i32.const 1024))
+          (data (i32.const 0) "[{\"kind\":\"temperature\",\"value\":21.5}]")
+          (func (export "blueplug_alloc") (param $len i32) (result i32)
+            (local $ptr i32)
+            (local.set $ptr (global.get $next))
+            (global.set $next (i32.add (global.get $next) (local.get $len)))
+            (local.get $ptr))
+          (func (export "blueplug_decode_manufacturer_data")
+                (param $company i32) (param $ptr i32) (param $len i32) (result i64)
+            (if (result i64)
+              (i32.and
+                (i32.eq (local.get $company) (i32.const 0xffff))
+                (i32.ge_u (local.get $len) (i32.const 2)))
+              (then (i64.const 37))
+              (else (i64.const 0)))))
+    "#;
+
+    const SPIN: &str = r#"
+        (module
+          (memory (export "memory") 1)
+          (func (export "blueplug_alloc") (param i32) (result i32) (i32.const 0))
+          (func (export "blueplug_decode_manufacturer_data")
+                (param i32 i32 i32) (result i64)
+            (loop $forever (br $forever))
+            (i64.const 0)))
+    "#;
+
+    // Claims a 4 GiB result, far beyond its one page of memory.
+    const OVERSIZED: &str = r#"
+        (module
+          (memory (export "memory") 1)
+          (func (export "blueplug_alloc") (param i32) (result i32) (i32.const 0))
+          (func (export "blueplug_decode_manufacturer_data")
+                (param i32 i32 i32) (result i64)
+            (i64.const 0xffffffff)))
+    "#;
+
+    // Grows its memory by 512 pages, 32 MiB, before answering.
+    const GREEDY: &str = r#"
+        (module
+          (memory (export "memory") 1)
+          (func (export "blueplug_alloc") (param i32) (result i32) (i32.const 0))
+          (func (export "blueplug_decode_manufacturer_data")
+                (param i32 i32 i32) (result i64)
+            (drop (memory.grow (i32.const 512)))
+            (i64.const 0)))
+    "#;
+
+    #[test]
+    fn test_plugin_decodes_manufacturer_data() {
+        let wasm = wat::parse_str(FIXED_READING).unwrap();
+        let mut plugin = Plugin::from_bytes("fixed", &wasm).unwrap();
+
+        let measurements = plugin.decode_manufacturer_data(0xffff, &[1, 2]).unwrap();
+        assert!(matches!(measurements[..], [Measurement::Temperature(v)] if v == 21.5));
+
+        assert!(plugin
+            .decode_manufacturer_data(0x0499, &[1, 2])
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_plugin_runaway_loop_is_stopped() {
+        let wasm = wat::parse_str(SPIN).unwrap();
+        let mut plugin = Plugin::from_bytes("spin", &wasm).unwrap();
+        assert!(plugin.decode_manufacturer_data(0xffff, &[]).is_err());
+    }
+
+    #[test]
+    fn test_plugin_result_out_of_range() {
+        let wasm = wat::parse_str(OVERSIZED).unwrap();
+        let mut plugin = Plugin::from_bytes("oversized", &wasm).unwrap();
+        let error = plugin.decode_manufacturer_data(0xffff, &[]).unwrap_err();
+        assert!(error.to_string().contains("outside its memory"));
+    }
+
+    #[test]
+    fn test_plugin_memory_is_capped() {
+        let wasm = wat::parse_str(GREEDY).unwrap();
+        let mut plugin = Plugin::from_bytes("greedy", &wasm).unwrap();
+        assert!(plugin.decode_manufacturer_data(0xffff, &[]).is_err());
+    }
+}
@@ -0,0 +1,581 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time;
+use tokio_postgres::{Client, NoTls};
+
+use crate::config::PostgresConfig;
+use crate::mqtt::backoff;
+use crate::names::TOPIC_NAMES;
+use crate::supervisor::SUPERVISOR;
+use crate::DeviceReading;
+
+// How many batches' worth of readings are kept while writes fail, before the oldest are dropped.
+const BUFFERED_BATCHES: usize = 10;
+
+// A write that takes longer than this is abandoned and retried on a new connection.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+
+// spawn_postgres writes readings to a table in PostgreSQL, or TimescaleDB, creating it if need
+// be. Readings are collected until batch_size of them arrived or batch_interval seconds passed,
+// then inserted in one statement, so the database sees one write per batch rather than one per
+// reading, which matters for large fleets and databases on SD cards. What is left is written on
+// shutdown. See Batches for what happens when a write fails.
+pub fn spawn_postgres(
+    config: PostgresConfig,
+    mut readings: broadcast::Receiver<Arc<DeviceReading>>,
+) {
+    SUPERVISOR.spawn_graceful(format!("postgres {}", config.name), async move {
+        let mut flush = time::interval(Duration::from_secs(config.batch_interval));
+        let mut batches = Batches::new(&config.name, config.batch_size);
+        let mut connection = Connection {
+            config: &config,
+            client: None,
+        };
+        loop {
+            // Full batches are written as soon as they are, everything on the interval.
+            let everything = tokio::select! {
+                received = readings.recv() => {
+                    match received {
+                        Ok(reading) => batches.push(reading, SystemTime::now()),
+                        Err(RecvError::Lagged(skipped)) => {
+                            println!(
+                                "{}: falling behind, dropped {} readings",
+                                config.name, skipped
+                            );
+                        }
+                        Err(RecvError::Closed) => return,
+                    }
+                    false
+                }
+                _ = flush.tick() => true,
+                _ = SUPERVISOR.cancelled() => {
+                    batches.drain(&mut connection).await;
+                    return;
+                }
+            };
+            batches
+                .flush(&mut connection, everything, Instant::now())
+                .await;
+        }
+    });
+}
+
+// Batches holds the readings waiting to be written. A batch is inserted whole or not at all. One
+// that fails to write is kept, along with what arrives meanwhile up to BUFFERED_BATCHES batches'
+// worth, and retried with a backoff. One the database rejects for its data, such as a value
+// violating a constraint added to the table, is split in half until the rejected readings are
+// found alone, and only those are dropped, since writing them again would fail again.
+struct Batches {
+    name: String,
+    readings: VecDeque<(Arc<DeviceReading>, SystemTime)>,
+    size: usize,
+    capacity: usize,
+    failures: u32,
+    retry_at: Option<Instant>,
+    // Whether readings are being dropped, to say so once rather than for every one.
+    dropping: bool,
+}
+
+// Writer inserts readings, with when they were received, into the database.
+trait Writer {
+    async fn write(
+        &mut self,
+        readings: &[(Arc<DeviceReading>, SystemTime)],
+    ) -> Result<(), WriteError>;
+}
+
+impl Batches {
+    fn new(name: &str, size: usize) -> Self {
+        Batches {
+            name: name.to_string(),
+            readings: VecDeque::new(),
+            size,
+            capacity: size * BUFFERED_BATCHES,
+            failures: 0,
+            retry_at: None,
+            dropping: false,
+        }
+    }
+
+    // push adds a reading received at time, dropping the oldest beyond the capacity.
+    fn push(&mut self, reading: Arc<DeviceReading>, time: SystemTime) {
+        self.readings.push_back((reading, time));
+        if self.readings.len() > self.capacity {
+            let dropped = self.readings.len() - self.capacity;
+            self.readings.drain(..dropped);
+            if !self.dropping {
+                self.dropping = true;
+                println!(
+                    "{}: buffer full, dropping the oldest readings until writes succeed",
+                    self.name
+                );
+            }
+        }
+    }
+
+    // flush writes the full batches, or with everything all readings, unless a failed write is
+    // waiting for its retry at now.
+    async fn flush(&mut self, writer: &mut impl Writer, everything: bool, now: Instant) {
+        if self.retry_at.is_some_and(|retry_at| now < retry_at) {
+            return;
+        }
+        while self.readings.len() >= self.size || (everything && !self.readings.is_empty()) {
+            let count = self.readings.len().min(self.size);
+            let batch: Vec<_> = self.readings.range(..count).cloned().collect();
+            let (written, result) = self.write(writer, &batch).await;
+            self.readings.drain(..written);
+            if let Err(e) = result {
+                self.failures += 1;
+                let delay = backoff(self.failures, fastrand::f64());
+                self.retry_at = Some(now + delay);
+                println!(
+                    "{}: writing {} readings failed, retrying in {:?}: {}",
+                    self.name,
+                    count - written,
+                    delay,
+                    e
+                );
+                return;
+            }
+            self.failures = 0;
+            self.retry_at = None;
+            self.dropping = false;
+        }
+    }
+
+    // drain writes all readings on shutdown, dropping them if that fails rather than retrying.
+    async fn drain(&mut self, writer: &mut impl Writer) {
+        while !self.readings.is_empty() {
+            let count = self.readings.len().min(self.size);
+            let batch: Vec<_> = self.readings.drain(..count).collect();
+            let (written, result) = self.write(writer, &batch).await;
+            if let Err(e) = result {
+                println!(
+                    "{}: writing {} readings failed, dropped: {}",
+                    self.name,
+                    count - written + self.readings.len(),
+                    e
+                );
+                self.readings.clear();
+            }
+        }
+    }
+
+    // write writes batch, leaving out the readings the database rejects, and returns how many of
+    // it are done with, written or rejected, which are its first ones, along with why the rest
+    // couldn't be written.
+    async fn write(
+        &self,
+        writer: &mut impl Writer,
+        batch: &[(Arc<DeviceReading>, SystemTime)],
+    ) -> (usize, Result<(), WriteError>) {
+        // Parts of batch still to write, the next one last, so they are written in order.
+        let mut parts = Vec::new();
+        parts.push(0..batch.len());
+        let mut rejected = Vec::new();
+        let mut result = (batch.len(), Ok(()));
+        while let Some(part) = parts.pop() {
+            match writer.write(&batch[part.clone()]).await {
+                Ok(()) => {}
+                Err(e) if is_rejected(&e) && part.len() == 1 => rejected.push(e),
+                Err(e) if is_rejected(&e) => {
+                    let middle = part.start + part.len() / 2;
+                    parts.push(middle..part.end);
+                    parts.push(part.start..middle);
+                }
+                Err(e) => {
+                    result = (part.start, Err(e));
+                    break;
+                }
+            }
+        }
+        if let Some(e) = rejected.first() {
+            println!(
+                "{}: {} readings rejected, dropped: {}",
+                self.name,
+                rejected.len(),
+                e
+            );
+        }
+        result
+    }
+}
+
+#[derive(Debug)]
+enum WriteError {
+    Postgres(tokio_postgres::Error),
+    // The database's message for what it refused of what was written.
+    Rejected(String),
+    Timeout,
+}
+
+impl std::fmt::Display for WriteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            // The database's own message rather than "db error", and why connecting failed.
+            WriteError::Postgres(e) => match (e.as_db_error(), std::error::Error::source(e)) {
+                (Some(e), _) => e.fmt(f),
+                (None, Some(source)) => f.write_fmt(format_args!("{}: {}", e, source)),
+                (None, None) => e.fmt(f),
+            },
+            WriteError::Rejected(message) => f.write_str(message),
+            WriteError::Timeout => f.write_str("timed out"),
+        }
+    }
+}
+
+// Errors because of what was written, so would recur, are Rejected: a value out of range or
+// violating a constraint, SQLSTATE classes 22 and 23.
+impl From<tokio_postgres::Error> for WriteError {
+    fn from(e: tokio_postgres::Error) -> Self {
+        match e.as_db_error() {
+            Some(db)
+                if db.code().code().starts_with("22") || db.code().code().starts_with("23") =>
+            {
+                WriteError::Rejected(db.to_string())
+            }
+            _ => WriteError::Postgres(e),
+        }
+    }
+}
+
+fn is_rejected(e: &WriteError) -> bool {
+    matches!(e, WriteError::Rejected(_))
+}
+
+// Connection writes to the database of config, connecting first if need be, and again after a
+// write fails.
+struct Connection<'a> {
+    config: &'a PostgresConfig,
+    client: Option<Client>,
+}
+
+impl Writer for Connection<'_> {
+    async fn write(
+        &mut self,
+        readings: &[(Arc<DeviceReading>, SystemTime)],
+    ) -> Result<(), WriteError> {
+        let result = write(&mut self.client, self.config, readings).await;
+        if result.as_ref().is_err_and(|e| !is_rejected(e)) {
+            self.client = None;
+        }
+        result
+    }
+}
+
+// write inserts readings, with when they were received, connecting first if need be.
+async fn write(
+    client: &mut Option<Client>,
+    config: &PostgresConfig,
+    readings: &[(Arc<DeviceReading>, SystemTime)],
+) -> Result<(), WriteError> {
+    let write = async {
+        let client = match client {
+            Some(client) => client,
+            None => client.insert(connect(config).await?),
+        };
+        let mut times = Vec::new();
+        let mut devices = Vec::new();
+        let mut device_ids = Vec::new();
+        let mut device_names = Vec::new();
+        let mut addresses = Vec::new();
+        let mut kinds = Vec::new();
+        let mut values = Vec::new();
+        let mut units = Vec::new();
+        let mut advertisements = Vec::new();
+        for (reading, time) in readings {
+            times.push(*time);
+            devices.push(TOPIC_NAMES.topic_name(&reading.device_id));
+            device_ids.push(reading.device_id.id.as_str());
+            device_names.push(reading.device_id.device_name.as_str());
+            addresses.push(reading.device_id.address.as_str());
+            kinds.push(reading.measurement.kind());
+            values.push(reading.measurement.value());
+            units.push(reading.measurement.unit());
+            advertisements.push(reading.advertisement.map(|a| a as i64));
+        }
+        client
+            .execute(
+                &insert(&config.table),
+                &[
+                    &times,
+                    &devices,
+                    &device_ids,
+                    &device_names,
+                    &addresses,
+                    &kinds,
+                    &values,
+                    &units,
+                    &advertisements,
+                ],
+            )
+            .await?;
+        Ok(())
+    };
+    time::timeout(WRITE_TIMEOUT, write)
+        .await
+        .unwrap_or(Err(WriteError::Timeout))
+}
+
+// connect opens a connection to the database and creates the table if it doesn't exist.
+async fn connect(config: &PostgresConfig) -> Result<Client, tokio_postgres::Error> {
+    let mut postgres: tokio_postgres::Config = config.url.parse()?;
+    if let Some(password) = &config.password {
+        postgres.password(password);
+    }
+    let (client, connection) = postgres.connect(NoTls).await?;
+    let name = config.name.clone();
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            println!("{}: connection closed: {}", name, WriteError::Postgres(e));
+        }
+    });
+    client.batch_execute(&create_table(&config.table)).await?;
+    Ok(client)
+}
+
+// create_table creates table with a row per reading, the same columns as the Parquet archive's
+// plus the device's topic name, and an index for querying a device's history. On TimescaleDB,
+// create_hypertable(table, 'time') turns it into a hypertable.
+fn create_table(table: &str) -> String {
+    let index = table.rsplit('.').next().unwrap_or(table);
+    format!(
+        "CREATE TABLE IF NOT EXISTS {table} (
+            time timestamptz NOT NULL,
+            device text NOT NULL,
+            device_id text NOT NULL,
+            device_name text NOT NULL,
+            address text NOT NULL,
+            kind text NOT NULL,
+            value double precision NOT NULL,
+            unit text NOT NULL,
+            advertisement bigint
+        );
+        CREATE INDEX IF NOT EXISTS {index}_device_time ON {table} (device, time);"
+    )
+}
+
+// insert inserts a batch of readings in one statement, a column of them per parameter.
+fn insert(table: &str) -> String {
+    format!(
+        "INSERT INTO {table} \
+         (time, device, device_id, device_name, address, kind, value, unit, advertisement) \
+         SELECT * FROM UNNEST($1::timestamptz[], $2::text[], $3::text[], $4::text[], \
+         $5::text[], $6::text[], $7::float8[], $8::text[], $9::int8[])"
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use std::time::Instant;
+
+    use crate::config::PostgresConfig;
+    use crate::postgres::{
+        connect, is_rejected, write, Batches, Connection, WriteError, Writer, BUFFERED_BATCHES,
+    };
+    use crate::{DeviceId, DeviceReading, Measurement};
+
+    // FakeWriter takes batches like a table with a CHECK (value < 100) does, unless told to fail.
+    #[derive(Default)]
+    struct FakeWriter {
+        written: Vec<f64>,
+        writes: usize,
+        failing: bool,
+    }
+
+    impl Writer for FakeWriter {
+        async fn write(
+            &mut self,
+            readings: &[(Arc<DeviceReading>, SystemTime)],
+        ) -> Result<(), WriteError> {
+            self.writes += 1;
+            if self.failing {
+                return Err(WriteError::Timeout);
+            }
+            let values = readings
+                .iter()
+                .map(|(reading, _)| reading.measurement.value());
+            if values.clone().any(|value| value >= 100.0) {
+                return Err(WriteError::Rejected(
+                    "violates check constraint".to_string(),
+                ));
+            }
+            self.written.extend(values);
+            Ok(())
+        }
+    }
+
+    fn reading(value: f64) -> Arc<DeviceReading> {
+        Arc::new(DeviceReading {
+            device_id: DeviceId {
+                id: "hci0/dev_A4_C1_38_00_00_12".to_string(),
+                device_name: "ATC_POSTGRES".to_string(),
+                address: "A4:C1:38:00:00:12".to_string(),
+                random_address: None,
+            },
+            measurement: Measurement::Temperature(value),
+            advertisement: None,
+            quality: Vec::new(),
+        })
+    }
+
+    #[tokio::test]
+    async fn test_batches() {
+        let mut batches = Batches::new("postgres", 4);
+        let mut writer = FakeWriter::default();
+        let now = Instant::now();
+        for value in [1.0, 2.0, 100.0, 3.0, 4.0, 5.0] {
+            batches.push(reading(value), SystemTime::now());
+        }
+        // Only full batches are written until the interval, and only the rejected reading of one
+        // is dropped.
+        batches.flush(&mut writer, false, now).await;
+        assert_eq!(writer.written, [1.0, 2.0, 3.0]);
+        assert_eq!(batches.readings.len(), 2);
+        batches.flush(&mut writer, true, now).await;
+        assert_eq!(writer.written, [1.0, 2.0, 3.0, 4.0, 5.0]);
+        assert!(batches.readings.is_empty());
+
+        // A failed batch is kept and retried after a backoff.
+        writer.failing = true;
+        batches.push(reading(6.0), SystemTime::now());
+        batches.flush(&mut writer, true, now).await;
+        assert_eq!(batches.readings.len(), 1);
+        let retry_at = batches.retry_at.unwrap();
+        writer.failing = false;
+        let writes = writer.writes;
+        batches.flush(&mut writer, true, now).await;
+        assert_eq!(writer.writes, writes);
+        batches.flush(&mut writer, true, retry_at).await;
+        assert_eq!(writer.written.last(), Some(&6.0));
+        assert_eq!(batches.retry_at, None);
+
+        // The oldest readings are dropped beyond BUFFERED_BATCHES batches.
+        for value in 0..4 * BUFFERED_BATCHES + 1 {
+            batches.push(reading(value as f64), SystemTime::now());
+        }
+        assert_eq!(batches.readings.len(), 4 * BUFFERED_BATCHES);
+        assert_eq!(batches.readings[0].0.measurement.value(), 1.0);
+
+        // On shutdown, whatever is left is written.
+        batches.drain(&mut writer).await;
+        assert!(batches.readings.is_empty());
+        assert_eq!(writer.written.len(), 6 + 4 * BUFFERED_BATCHES);
+    }
+
+    // Writes to the database of BLUEPLUG_TEST_POSTGRES, a connection string, where it's set.
+    #[tokio::test]
+    async fn test_write() {
+        let Ok(url) = std::env::var("BLUEPLUG_TEST_POSTGRES") else {
+            println!("BLUEPLUG_TEST_POSTGRES isn't set, skipping");
+            return;
+        };
+        let table = format!("blueplug_test_{}", std::process::id());
+        let config = PostgresConfig {
+            name: "postgres".to_string(),
+            url,
+            table: table.clone(),
+            password: None,
+            password_file: None,
+            password_command: None,
+            batch_size: 100,
+            batch_interval: 10,
+        };
+        let new_reading = |measurement, advertisement| {
+            Arc::new(DeviceReading {
+                device_id: DeviceId {
+                    id: "hci0/dev_A4_C1_38_00_00_12".to_string(),
+                    device_name: "ATC_POSTGRES".to_string(),
+                    address: "A4:C1:38:00:00:12".to_string(),
+                    random_address: None,
+                },
+                measurement,
+                advertisement,
+                quality: Vec::new(),
+            })
+        };
+        let now = UNIX_EPOCH + Duration::from_secs(1_717_273_800);
+        let mut client = None;
+        write(
+            &mut client,
+            &config,
+            &[
+                (new_reading(Measurement::Temperature(21.5), Some(1)), now),
+                (new_reading(Measurement::Humidity(40.0), Some(1)), now),
+            ],
+        )
+        .await
+        .unwrap();
+        // A second connection finds the table already there.
+        let other = connect(&config).await.unwrap();
+
+        let rows = other
+            .query(
+                &format!(
+                    "SELECT time, device, kind, value, unit, advertisement FROM {} ORDER BY kind",
+                    table
+                ),
+                &[],
+            )
+            .await
+            .unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].get::<_, SystemTime>(0), now);
+        assert_eq!(rows[0].get::<_, &str>(1), "ATC_POSTGRES");
+        assert_eq!(rows[0].get::<_, &str>(2), "humidity");
+        assert_eq!(rows[1].get::<_, f64>(3), 21.5);
+        assert_eq!(rows[1].get::<_, &str>(4), "°C");
+        assert_eq!(rows[1].get::<_, Option<i64>>(5), Some(1));
+
+        // write rejects a batch the table's constraints refuse as a whole.
+        other
+            .batch_execute(&format!("ALTER TABLE {} ADD CHECK (value < 100)", table))
+            .await
+            .unwrap();
+        let e = write(
+            &mut client,
+            &config,
+            &[
+                (new_reading(Measurement::Temperature(22.0), None), now),
+                (new_reading(Measurement::Battery(100.0), None), now),
+            ],
+        )
+        .await
+        .unwrap_err();
+        assert!(is_rejected(&e), "{}", e);
+        let count: i64 = other
+            .query_one(&format!("SELECT count(*) FROM {}", table), &[])
+            .await
+            .unwrap()
+            .get(0);
+        assert_eq!(count, 2);
+        // Batches writes the rest of it.
+        let mut batches = Batches::new("postgres", 100);
+        batches.push(new_reading(Measurement::Temperature(22.0), None), now);
+        batches.push(new_reading(Measurement::Battery(100.0), None), now);
+        let mut connection = Connection {
+            config: &config,
+            client,
+        };
+        batches.flush(&mut connection, true, Instant::now()).await;
+        assert!(batches.readings.is_empty());
+        let count: i64 = other
+            .query_one(&format!("SELECT count(*) FROM {}", table), &[])
+            .await
+            .unwrap()
+            .get(0);
+        assert_eq!(count, 3);
+
+        other
+            .batch_execute(&format!("DROP TABLE {}", table))
+            .await
+            .unwrap();
+    }
+}
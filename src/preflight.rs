@@ -0,0 +1,291 @@
+use color_eyre::eyre::{eyre, Report, Result};
+
+use crate::adapter::AdapterSelector;
+use crate::config::AddressType;
+
+// preflight looks for the usual reasons scanning fails before we try, so users get an actionable
+// error instead of `No BT Adapter` or a scan that silently never yields anything. Where an adapter
+// is merely powered off it is switched on.
+pub async fn preflight(selector: Option<&AdapterSelector>) -> Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        let selector = selector.cloned();
+        tokio::task::spawn_blocking(move || bluez::preflight(selector.as_ref())).await?
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = selector;
+        Ok(())
+    }
+}
+
+// adapter_address looks up the MAC address of an adapter by name, where the platform allows.
+pub async fn adapter_address(name: &str) -> Option<String> {
+    #[cfg(target_os = "linux")]
+    {
+        let name = name.to_string();
+        tokio::task::spawn_blocking(move || bluez::adapter_address(&name))
+            .await
+            .ok()
+            .flatten()
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = name;
+        None
+    }
+}
+
+// add_device adds a device that doesn't advertise to the adapter by address, so it can be
+// connected to without being scanned first. Only BlueZ allows it, and only with its experimental
+// interfaces enabled.
+pub async fn add_device(adapter: &str, address: &str, address_type: AddressType) -> Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        let (adapter, address) = (adapter.to_string(), address.to_string());
+        tokio::task::spawn_blocking(move || bluez::add_device(&adapter, &address, address_type))
+            .await?
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = (adapter, address_type);
+        Err(eyre!(
+            "{} hasn't been seen by a scan, and only Linux can add devices that don't advertise",
+            address
+        ))
+    }
+}
+
+// paired tells whether the device has been paired with, e.g. by `blueplug pair`. Elsewhere than
+// on BlueZ the platform pairs by itself when the device asks to, so devices count as paired.
+pub async fn paired(adapter: &str, address: &str) -> Result<bool> {
+    #[cfg(target_os = "linux")]
+    {
+        let (adapter, address) = (adapter.to_string(), address.to_string());
+        tokio::task::spawn_blocking(move || bluez::paired(&adapter, &address)).await?
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = (adapter, address);
+        Ok(true)
+    }
+}
+
+// explain turns btleplug errors into something a user can act on.
+pub fn explain(error: btleplug::Error) -> Report {
+    match error {
+        btleplug::Error::PermissionDenied => eyre!(
+            "permission to use Bluetooth was denied. {}",
+            PERMISSION_HINT
+        ),
+        btleplug::Error::Other(e) if is_not_ready(&e.to_string()) => eyre!(
+            "the Bluetooth adapter isn't ready, it is probably powered off ({})",
+            e
+        ),
+        error => Report::new(error),
+    }
+}
+
+fn is_not_ready(message: &str) -> bool {
+    message.contains("org.bluez.Error.NotReady") || message.contains("Resource Not Ready")
+}
+
+pub fn no_adapter() -> Report {
+    eyre!("no Bluetooth adapter found. {}", NO_ADAPTER_HINT)
+}
+
+#[cfg(target_os = "linux")]
+const PERMISSION_HINT: &str = "Run blueplug as a user in the `bluetooth` group, or allow it to \
+    talk to org.bluez in the D-Bus policy (/etc/dbus-1/system.d/bluetooth.conf).";
+#[cfg(target_os = "macos")]
+const PERMISSION_HINT: &str = "Grant Bluetooth access to the terminal or service running \
+    blueplug under System Settings > Privacy & Security > Bluetooth.";
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+const PERMISSION_HINT: &str = "Check that this application is allowed to use Bluetooth.";
+
+#[cfg(target_os = "linux")]
+const NO_ADAPTER_HINT: &str = "Check `bluetoothctl list` and `rfkill list`, and that any USB \
+    dongle is plugged in and has firmware loaded (see `dmesg`).";
+#[cfg(not(target_os = "linux"))]
+const NO_ADAPTER_HINT: &str = "Check that Bluetooth is enabled in the system settings.";
+
+#[cfg(target_os = "linux")]
+pub(crate) mod bluez {
+    use std::time::Duration;
+
+    use color_eyre::eyre::{eyre, Result};
+    use dbus::arg::{PropMap, RefArg, Variant};
+    use dbus::blocking::stdintf::org_freedesktop_dbus::{ObjectManager, Properties};
+    use dbus::blocking::Connection;
+
+    use super::{no_adapter, PERMISSION_HINT};
+    use crate::adapter::AdapterSelector;
+    use crate::config::AddressType;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+    const ADAPTER_INTERFACE: &str = "org.bluez.Adapter1";
+
+    pub fn preflight(selector: Option<&AdapterSelector>) -> Result<()> {
+        let connection = Connection::new_system().map_err(|e| {
+            eyre!(
+                "can't connect to the D-Bus system bus, which BlueZ needs ({}). Is dbus running?",
+                e.message().unwrap_or_default()
+            )
+        })?;
+
+        let bus = connection.with_proxy("org.freedesktop.DBus", "/org/freedesktop/DBus", TIMEOUT);
+        let (bluez_running,): (bool,) =
+            bus.method_call("org.freedesktop.DBus", "NameHasOwner", ("org.bluez",))?;
+        if !bluez_running {
+            return Err(eyre!(
+                "BlueZ isn't running. Start it with `systemctl start bluetooth`."
+            ));
+        }
+
+        let bluez = connection.with_proxy("org.bluez", "/", TIMEOUT);
+        let objects = bluez.get_managed_objects().map_err(|e| match e.name() {
+            Some("org.freedesktop.DBus.Error.AccessDenied") => {
+                eyre!("not allowed to talk to BlueZ. {}", PERMISSION_HINT)
+            }
+            _ => eyre!(
+                "can't list Bluetooth adapters: {}",
+                e.message().unwrap_or_default()
+            ),
+        })?;
+
+        let mut adapters: Vec<_> = objects
+            .iter()
+            .filter_map(|(path, interfaces)| Some((path, interfaces.get(ADAPTER_INTERFACE)?)))
+            .collect();
+        if adapters.is_empty() {
+            return Err(no_adapter());
+        }
+        adapters.sort_by_key(|(path, _)| *path);
+        if let Some(selector) = selector {
+            let names: Vec<_> = adapters.iter().map(|(path, _)| name(path)).collect();
+            adapters = adapters
+                .into_iter()
+                .enumerate()
+                .filter(|(i, (path, properties))| {
+                    selector.matches(*i, name(path), address(properties).as_deref())
+                })
+                .map(|(_, adapter)| adapter)
+                .collect();
+            if adapters.is_empty() {
+                return Err(eyre!(
+                    "no Bluetooth adapter matches {}, available adapters are: {}",
+                    selector,
+                    names.join(", ")
+                ));
+            }
+        }
+
+        let mut problems = Vec::new();
+        for (path, properties) in &adapters {
+            if powered(properties) {
+                continue;
+            }
+            let adapter = connection.with_proxy("org.bluez", (*path).clone(), TIMEOUT);
+            match adapter.set(ADAPTER_INTERFACE, "Powered", true) {
+                Ok(()) => println!("powered on Bluetooth adapter {}", path),
+                Err(e) => {
+                    let hint = match e.name() {
+                        Some("org.bluez.Error.Blocked") | Some("org.bluez.Error.Failed") => {
+                            "It may be blocked by rfkill, try `rfkill unblock bluetooth`."
+                        }
+                        Some("org.freedesktop.DBus.Error.AccessDenied") => PERMISSION_HINT,
+                        _ => "Try `bluetoothctl power on`.",
+                    };
+                    problems.push(format!(
+                        "Bluetooth adapter {} is powered off and couldn't be powered on ({}). {}",
+                        path,
+                        e.message().unwrap_or_default(),
+                        hint
+                    ));
+                }
+            }
+        }
+
+        if problems.len() == adapters.len() {
+            return Err(eyre!(problems.join("\n")));
+        }
+        for problem in problems {
+            println!("{}", problem);
+        }
+        Ok(())
+    }
+
+    pub fn adapter_address(name: &str) -> Option<String> {
+        let connection = Connection::new_system().ok()?;
+        let adapter = connection.with_proxy("org.bluez", format!("/org/bluez/{}", name), TIMEOUT);
+        adapter.get(ADAPTER_INTERFACE, "Address").ok()
+    }
+
+    pub fn add_device(adapter: &str, address: &str, address_type: AddressType) -> Result<()> {
+        let connection = Connection::new_system()?;
+        let adapter =
+            connection.with_proxy("org.bluez", format!("/org/bluez/{}", adapter), TIMEOUT);
+        let address_type = match address_type {
+            AddressType::Public => "public",
+            AddressType::Random => "random",
+        };
+        let mut properties = PropMap::new();
+        properties.insert(
+            "Address".to_string(),
+            Variant(Box::new(address.to_string())),
+        );
+        properties.insert(
+            "AddressType".to_string(),
+            Variant(Box::new(address_type.to_string())),
+        );
+        let added: Result<(dbus::Path,), dbus::Error> =
+            adapter.method_call(ADAPTER_INTERFACE, "ConnectDevice", (properties,));
+        match added {
+            Ok(_) => Ok(()),
+            Err(e) if e.name() == Some("org.bluez.Error.AlreadyExists") => Ok(()),
+            Err(e) if e.name() == Some("org.freedesktop.DBus.Error.UnknownMethod") => Err(eyre!(
+                "adding {} needs BlueZ's experimental interfaces, run bluetoothd with --experimental",
+                address
+            )),
+            Err(e) => Err(eyre!(
+                "adding {} failed: {}",
+                address,
+                e.message().unwrap_or_default()
+            )),
+        }
+    }
+
+    pub fn paired(adapter: &str, address: &str) -> Result<bool> {
+        let connection = Connection::new_system()?;
+        let device = connection.with_proxy("org.bluez", device_path(adapter, address), TIMEOUT);
+        Ok(device.get("org.bluez.Device1", "Paired").unwrap_or(false))
+    }
+
+    // device_path is where BlueZ lists a device it knows, /org/bluez/<adapter>/dev_<address>.
+    pub fn device_path(adapter: &str, address: &str) -> dbus::Path<'static> {
+        dbus::Path::from(format!(
+            "/org/bluez/{}/dev_{}",
+            adapter,
+            address.to_uppercase().replace(':', "_")
+        ))
+    }
+
+    // Adapters live at /org/bluez/<name>.
+    fn name<'a>(path: &'a dbus::Path) -> &'a str {
+        path.rsplit('/').next().unwrap_or_default()
+    }
+
+    fn address(properties: &PropMap) -> Option<String> {
+        properties
+            .get("Address")
+            .and_then(|v| v.0.as_str())
+            .map(|s| s.to_string())
+    }
+
+    fn powered(properties: &PropMap) -> bool {
+        properties
+            .get("Powered")
+            .and_then(|v| v.0.as_i64())
+            .is_some_and(|v| v != 0)
+    }
+}
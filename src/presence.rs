@@ -0,0 +1,281 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use tokio::sync::broadcast;
+use tokio::time;
+
+use crate::clock::unix_timestamp;
+use crate::config::PresenceConfig;
+use crate::supervisor::SUPERVISOR;
+use crate::DeviceEvent;
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum State {
+    Home,
+    Away,
+}
+
+// PresenceChange is published retained on `<topic_prefix>/<name>` whenever a device comes home
+// or goes away.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct PresenceChange {
+    #[serde(skip)]
+    pub name: String,
+    #[serde(skip)]
+    pub topic: String,
+    pub state: State,
+    // Seconds since the Unix epoch, absent if the device hasn't been seen since startup.
+    pub last_seen: Option<u64>,
+}
+
+struct Tracked {
+    name: String,
+    // The device's address, or its resolved id.
+    address: Option<String>,
+    id: Option<String>,
+    state: Option<State>,
+    // When the device was first seen without a gap of away_after since.
+    arrived: Option<Instant>,
+    last_seen: Option<(Instant, u64)>,
+}
+
+impl Tracked {
+    fn seen_within(&self, period: Duration, now: Instant) -> bool {
+        self.last_seen
+            .is_some_and(|(seen, _)| now.duration_since(seen) < period)
+    }
+}
+
+// Presence treats configured devices, such as phones and tags, as presence sensors: a device is
+// home once it has been seen for home_after, and away once it hasn't been seen for away_after.
+// Sightings weaker than min_rssi don't count.
+pub struct Presence {
+    topic_prefix: String,
+    away_after: Duration,
+    home_after: Duration,
+    min_rssi: Option<i16>,
+    started: Instant,
+    devices: Vec<Tracked>,
+}
+
+impl Presence {
+    pub fn new(config: &PresenceConfig, now: Instant) -> Self {
+        let devices = config
+            .devices
+            .iter()
+            .map(|device| Tracked {
+                name: device.name.clone(),
+                address: device.address.as_ref().map(|a| a.to_uppercase()),
+                id: device.identity.as_ref().map(|i| format!("irk/{}", i)),
+                state: None,
+                arrived: None,
+                last_seen: None,
+            })
+            .collect();
+        Presence {
+            topic_prefix: config.topic_prefix.clone(),
+            away_after: Duration::from_secs(config.away_after),
+            home_after: Duration::from_secs(config.home_after),
+            min_rssi: config.min_rssi,
+            started: now,
+            devices,
+        }
+    }
+
+    pub fn sighting(&mut self, event: &DeviceEvent, now: Instant) {
+        if let (Some(rssi), Some(min_rssi)) = (event.rssi(), self.min_rssi) {
+            if rssi < min_rssi {
+                return;
+            }
+        }
+        let device_id = event.device_id();
+        let address = device_id.address.to_uppercase();
+        for device in &mut self.devices {
+            if device.address.as_ref() == Some(&address)
+                || device.id.as_ref() == Some(&device_id.id)
+            {
+                if !device.seen_within(self.away_after, now) {
+                    device.arrived = Some(now);
+                }
+                device.last_seen = Some((now, unix_timestamp()));
+            }
+        }
+    }
+
+    // changes returns the devices whose state changed since the last call.
+    pub fn changes(&mut self, now: Instant) -> Vec<PresenceChange> {
+        let mut changes = Vec::new();
+        for device in &mut self.devices {
+            let state = if device.seen_within(self.away_after, now) {
+                match device.arrived {
+                    Some(arrived) if now.duration_since(arrived) >= self.home_after => State::Home,
+                    // Arriving, but not seen for long enough yet to say it's home.
+                    _ => continue,
+                }
+            } else if device.last_seen.is_none()
+                && now.duration_since(self.started) < self.away_after
+            {
+                // Not seen yet, but it's too soon after startup to say it's away.
+                continue;
+            } else {
+                State::Away
+            };
+            if device.state != Some(state) {
+                device.state = Some(state);
+                changes.push(PresenceChange {
+                    name: device.name.clone(),
+                    topic: format!("{}/{}", self.topic_prefix, device.name),
+                    state,
+                    last_seen: device.last_seen.map(|(_, timestamp)| timestamp),
+                });
+            }
+        }
+        changes
+    }
+}
+
+// spawn_presence checks every CHECK_INTERVAL for devices that came home or went away, and sends
+// the changes to the brokers.
+pub fn spawn_presence(
+    presence: Arc<Mutex<Presence>>,
+    sender: broadcast::Sender<Arc<PresenceChange>>,
+) {
+    SUPERVISOR.spawn("presence", move || {
+        let presence = presence.clone();
+        let sender = sender.clone();
+        async move {
+            let mut interval = time::interval(CHECK_INTERVAL);
+            loop {
+                interval.tick().await;
+                let changes = presence.lock().unwrap().changes(Instant::now());
+                for change in changes {
+                    println!("{} is {:?}", change.name, change.state);
+                    // Sending only fails when no broker is listening.
+                    let _ = sender.send(Arc::new(change));
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::{Duration, Instant};
+
+    use crate::config::{PresenceConfig, PresenceDevice};
+    use crate::presence::{Presence, State};
+    use crate::{DeviceEvent, DeviceId};
+
+    #[test]
+    fn test_presence() {
+        let start = Instant::now();
+        let mut presence = Presence::new(
+            &PresenceConfig {
+                topic_prefix: "blueplug/presence".to_string(),
+                away_after: 60,
+                home_after: 0,
+                min_rssi: None,
+                devices: vec![
+                    PresenceDevice {
+                        name: "alice".to_string(),
+                        address: Some("aa:bb:cc:dd:ee:ff".to_string()),
+                        identity: None,
+                    },
+                    PresenceDevice {
+                        name: "bob".to_string(),
+                        address: None,
+                        identity: Some("bobs_phone".to_string()),
+                    },
+                ],
+            },
+            start,
+        );
+        let phone = DeviceEvent::ManufacturerDataAdvertisement {
+            device_id: DeviceId {
+                id: "hci0/dev_AA_BB_CC_DD_EE_FF".to_string(),
+                device_name: "iPhone".to_string(),
+                address: "AA:BB:CC:DD:EE:FF".to_string(),
+                random_address: None,
+            },
+            manufacturer_data: HashMap::from([(0x004c, vec![0x10, 0x05])]),
+            rssi: None,
+        };
+
+        assert!(presence.changes(start).is_empty());
+        presence.sighting(&phone, start + Duration::from_secs(10));
+        let changes = presence.changes(start + Duration::from_secs(10));
+        assert_eq!(changes.len(), 1);
+        assert_eq!(
+            (changes[0].name.as_str(), changes[0].state),
+            ("alice", State::Home)
+        );
+        assert_eq!(changes[0].topic, "blueplug/presence/alice");
+
+        // Bob was never seen, so is away once the grace period after startup has passed.
+        let changes = presence.changes(start + Duration::from_secs(60));
+        assert_eq!(changes.len(), 1);
+        assert_eq!(
+            (changes[0].name.as_str(), changes[0].state),
+            ("bob", State::Away)
+        );
+
+        let changes = presence.changes(start + Duration::from_secs(70));
+        assert_eq!(
+            (changes[0].name.as_str(), changes[0].state),
+            ("alice", State::Away)
+        );
+        assert!(changes[0].last_seen.is_some());
+    }
+
+    #[test]
+    fn test_presence_home_after_and_rssi() {
+        let start = Instant::now();
+        let mut presence = Presence::new(
+            &PresenceConfig {
+                topic_prefix: "blueplug/presence".to_string(),
+                away_after: 60,
+                home_after: 30,
+                min_rssi: Some(-80),
+                devices: vec![PresenceDevice {
+                    name: "alice".to_string(),
+                    address: Some("aa:bb:cc:dd:ee:ff".to_string()),
+                    identity: None,
+                }],
+            },
+            start,
+        );
+        // Phones rarely advertise a name, and are followed by address all the same.
+        let phone = |rssi| DeviceEvent::ManufacturerDataAdvertisement {
+            device_id: DeviceId {
+                id: "hci0/dev_AA_BB_CC_DD_EE_FF".to_string(),
+                device_name: String::new(),
+                address: "AA:BB:CC:DD:EE:FF".to_string(),
+                random_address: None,
+            },
+            manufacturer_data: HashMap::from([(0x004c, vec![0x10, 0x05])]),
+            rssi: Some(rssi),
+        };
+        let at = |seconds| start + Duration::from_secs(seconds);
+
+        // Passing by outside is too weak to count.
+        presence.sighting(&phone(-90), at(5));
+        presence.sighting(&phone(-70), at(10));
+        presence.sighting(&phone(-70), at(30));
+        assert!(presence.changes(at(30)).is_empty());
+        presence.sighting(&phone(-70), at(40));
+        let changes = presence.changes(at(40));
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].state, State::Home);
+
+        let changes = presence.changes(at(100));
+        assert_eq!(changes[0].state, State::Away);
+        // Coming back takes home_after again.
+        presence.sighting(&phone(-70), at(110));
+        assert!(presence.changes(at(110)).is_empty());
+    }
+}
@@ -0,0 +1,200 @@
+use std::collections::BTreeMap;
+
+use prost::{Message, Oneof};
+use serde_json::{Map, Value};
+
+use crate::envelope;
+
+// The messages of proto/payload.proto. Struct, ListValue and ProtoValue are google.protobuf's
+// well-known Struct, ListValue and Value, so consumers can decode them with their own.
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Envelope {
+    #[prost(uint32, tag = "1")]
+    pub schema_version: u32,
+    #[prost(message, optional, tag = "2")]
+    pub device: Option<EnvelopeDevice>,
+    #[prost(message, optional, tag = "3")]
+    pub measurement: Option<EnvelopeMeasurement>,
+    #[prost(uint64, tag = "4")]
+    pub timestamp: u64,
+    #[prost(uint64, optional, tag = "5")]
+    pub advertisement: Option<u64>,
+    #[prost(message, optional, tag = "6")]
+    pub meta: Option<Struct>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct EnvelopeDevice {
+    #[prost(string, tag = "1")]
+    pub id: String,
+    #[prost(string, tag = "2")]
+    pub name: String,
+    #[prost(string, tag = "3")]
+    pub address: String,
+    #[prost(string, optional, tag = "4")]
+    pub location: Option<String>,
+    #[prost(btree_map = "string, string", tag = "5")]
+    pub labels: BTreeMap<String, String>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct EnvelopeMeasurement {
+    #[prost(string, tag = "1")]
+    pub kind: String,
+    #[prost(double, tag = "2")]
+    pub value: f64,
+    #[prost(string, tag = "3")]
+    pub unit: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct EnvelopeBatch {
+    #[prost(message, repeated, tag = "1")]
+    pub envelopes: Vec<Envelope>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Struct {
+    #[prost(btree_map = "string, message", tag = "1")]
+    pub fields: BTreeMap<String, ProtoValue>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ListValue {
+    #[prost(message, repeated, tag = "1")]
+    pub values: Vec<ProtoValue>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ProtoValue {
+    #[prost(oneof = "Kind", tags = "1, 2, 3, 4, 5, 6")]
+    pub kind: Option<Kind>,
+}
+
+#[derive(Clone, PartialEq, Oneof)]
+pub enum Kind {
+    // google.protobuf.NullValue, whose only value is 0.
+    #[prost(int32, tag = "1")]
+    Null(i32),
+    #[prost(double, tag = "2")]
+    Number(f64),
+    #[prost(string, tag = "3")]
+    String(String),
+    #[prost(bool, tag = "4")]
+    Bool(bool),
+    #[prost(message, tag = "5")]
+    Struct(Struct),
+    #[prost(message, tag = "6")]
+    List(ListValue),
+}
+
+// encode encodes a reading envelope as an Envelope, an array of them as an EnvelopeBatch and any
+// other payload as a google.protobuf.Value.
+pub fn encode(payload: Value) -> Vec<u8> {
+    match payload {
+        Value::Array(values) if values.iter().all(is_envelope) => {
+            let envelopes = values.into_iter().filter_map(to_envelope).collect();
+            EnvelopeBatch { envelopes }.encode_to_vec()
+        }
+        payload if is_envelope(&payload) => match to_envelope(payload.clone()) {
+            Some(envelope) => envelope.encode_to_vec(),
+            None => to_value(payload).encode_to_vec(),
+        },
+        payload => to_value(payload).encode_to_vec(),
+    }
+}
+
+fn is_envelope(value: &Value) -> bool {
+    value.get("schema_version").is_some() && value.get("measurement").is_some()
+}
+
+fn to_envelope(value: Value) -> Option<Envelope> {
+    let envelope: envelope::Envelope = serde_json::from_value(value).ok()?;
+    Some(Envelope {
+        schema_version: envelope.schema_version,
+        device: Some(EnvelopeDevice {
+            id: envelope.device.id,
+            name: envelope.device.name,
+            address: envelope.device.address,
+            location: envelope.device.location,
+            labels: envelope.device.labels,
+        }),
+        measurement: Some(EnvelopeMeasurement {
+            kind: envelope.measurement.kind,
+            value: envelope.measurement.value,
+            unit: envelope.measurement.unit,
+        }),
+        timestamp: envelope.timestamp,
+        advertisement: envelope.advertisement,
+        meta: Some(to_struct(envelope.meta)),
+    })
+}
+
+fn to_struct(map: Map<String, Value>) -> Struct {
+    Struct {
+        fields: map
+            .into_iter()
+            .map(|(key, value)| (key, to_value(value)))
+            .collect(),
+    }
+}
+
+fn to_value(value: Value) -> ProtoValue {
+    let kind = match value {
+        Value::Null => Kind::Null(0),
+        Value::Bool(b) => Kind::Bool(b),
+        // Like JSON numbers in protobuf's own mapping, integers beyond 2^53 lose precision.
+        Value::Number(n) => Kind::Number(n.as_f64().unwrap_or_default()),
+        Value::String(s) => Kind::String(s),
+        Value::Array(values) => Kind::List(ListValue {
+            values: values.into_iter().map(to_value).collect(),
+        }),
+        Value::Object(map) => Kind::Struct(to_struct(map)),
+    };
+    ProtoValue { kind: Some(kind) }
+}
+
+#[cfg(test)]
+mod tests {
+    use prost::Message;
+    use serde_json::json;
+
+    use crate::protobuf::{encode, Envelope, EnvelopeBatch, Kind, ProtoValue};
+
+    #[test]
+    fn test_encode() {
+        let envelope = json!({
+            "schema_version": 1,
+            "device": {"id": "hci0/dev_A4_C1_38_8F_2C_1A", "name": "ATC_8F2C1A"},
+            "measurement": {"kind": "temperature", "value": 21.5, "unit": "°C"},
+            "timestamp": 1717273800,
+            "meta": {"quality": ["stale"]},
+        });
+
+        let decoded = Envelope::decode(&encode(envelope.clone())[..]).unwrap();
+        assert_eq!(decoded.device.unwrap().name, "ATC_8F2C1A");
+        assert_eq!(decoded.measurement.unwrap().value, 21.5);
+        assert_eq!(decoded.timestamp, 1717273800);
+        assert_eq!(decoded.advertisement, None);
+        let quality = &decoded.meta.unwrap().fields["quality"];
+        let Some(Kind::List(quality)) = &quality.kind else {
+            panic!("expected a list, got {:?}", quality);
+        };
+        assert_eq!(
+            quality.values[0].kind,
+            Some(Kind::String("stale".to_string()))
+        );
+
+        let batch = json!([envelope.clone(), envelope]);
+        let decoded = EnvelopeBatch::decode(&encode(batch)[..]).unwrap();
+        assert_eq!(decoded.envelopes.len(), 2);
+
+        // Anything else is a google.protobuf.Value.
+        let decoded = ProtoValue::decode(&encode(json!({"uptime": 60}))[..]).unwrap();
+        let Some(Kind::Struct(telemetry)) = decoded.kind else {
+            panic!("expected a struct, got {:?}", decoded);
+        };
+        assert_eq!(telemetry.fields["uptime"].kind, Some(Kind::Number(60.0)));
+    }
+}
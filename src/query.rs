@@ -0,0 +1,206 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use color_eyre::eyre::{eyre, Result, WrapErr};
+
+use crate::config::Config;
+
+// QueryArgs configures `blueplug query`, which runs SQL over the Parquet archive (see archive.rs)
+// with the DuckDB command line tool, e.g. `blueplug query -c blueplug.toml "SELECT min(value)
+// FROM readings WHERE device = 'Greenhouse' AND kind = 'temperature' AND timestamp > now() -
+// INTERVAL 12 HOURS"`. Embedding DuckDB would add a C++ build of the whole database to blueplug,
+// so the duckdb executable has to be installed.
+#[derive(clap::Args, Debug)]
+pub struct QueryArgs {
+    /// SQL to run, with the archive as the view `readings`
+    sql: String,
+    /// Configuration file to take the [archive] directory and time_zone from
+    #[arg(short = 'c', long)]
+    config: Option<PathBuf>,
+    /// Archive directory, instead of the one in --config
+    #[arg(long)]
+    directory: Option<PathBuf>,
+    #[arg(long, value_enum, default_value_t = QueryFormat::Table)]
+    format: QueryFormat,
+    /// The DuckDB command line tool
+    #[arg(long, default_value = "duckdb")]
+    duckdb: PathBuf,
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy)]
+enum QueryFormat {
+    Table,
+    Csv,
+    Json,
+}
+
+impl QueryFormat {
+    fn flag(self) -> &'static str {
+        match self {
+            QueryFormat::Table => "-box",
+            QueryFormat::Csv => "-csv",
+            QueryFormat::Json => "-json",
+        }
+    }
+}
+
+pub fn run(args: QueryArgs) -> Result<()> {
+    let config = match &args.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    let directory = args
+        .directory
+        .or(config.archive.map(|archive| archive.directory))
+        .ok_or_else(|| {
+            eyre!("no archive to query, pass --directory or a --config with [archive]")
+        })?;
+    let status = command(
+        &args.duckdb,
+        args.format,
+        &directory,
+        config.time_zone.as_deref(),
+        &args.sql,
+    )
+    .status()
+    .wrap_err_with(|| {
+        format!(
+            "running {}, is DuckDB installed? See https://duckdb.org",
+            args.duckdb.display()
+        )
+    })?;
+    if !status.success() {
+        return Err(eyre!("query failed: duckdb {}", status));
+    }
+    Ok(())
+}
+
+// command runs sql over the archive in directory with duckdb.
+fn command(
+    duckdb: &Path,
+    format: QueryFormat,
+    directory: &Path,
+    time_zone: Option<&str>,
+    sql: &str,
+) -> Command {
+    let mut command = Command::new(duckdb);
+    command
+        .arg(format.flag())
+        .arg("-c")
+        .arg(script(directory, time_zone, sql));
+    command
+}
+
+// script defines the view readings over the archive in directory and then runs sql. Timestamps
+// are stored in UTC and shown in time_zone, if given, else the system's, as the archive's
+// partitions are. Hive partitions add the date, device and, if hourly, hour columns; date and
+// device are typed so a device named like a number stays a string.
+fn script(directory: &Path, time_zone: Option<&str>, sql: &str) -> String {
+    let mut script = String::new();
+    if let Some(time_zone) = time_zone {
+        script.push_str(&format!("SET TimeZone = {};\n", quote(time_zone)));
+    }
+    let files = format!("{}/**/*.parquet", directory.display());
+    script.push_str(&format!(
+        "CREATE VIEW readings AS SELECT * FROM read_parquet({}, hive_partitioning = true, hive_types = {{'date': DATE, 'device': VARCHAR}}, union_by_name = true);\n",
+        quote(&files)
+    ));
+    script.push_str(sql);
+    script
+}
+
+// quote makes s a SQL string literal.
+fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::process::Command;
+
+    use jiff::Timestamp;
+
+    use crate::archive::Archive;
+    use crate::config::{ArchiveConfig, ArchivePartition};
+    use crate::query::{command, script, QueryFormat};
+    use crate::{DeviceId, DeviceReading, Measurement};
+
+    #[test]
+    fn test_script() {
+        assert_eq!(
+            script(
+                Path::new("/var/lib/blueplug/bob's archive"),
+                Some("Europe/Berlin"),
+                "SELECT count(*) FROM readings"
+            ),
+            "SET TimeZone = 'Europe/Berlin';\n\
+             CREATE VIEW readings AS SELECT * FROM read_parquet('/var/lib/blueplug/bob''s archive/**/*.parquet', hive_partitioning = true, hive_types = {'date': DATE, 'device': VARCHAR}, union_by_name = true);\n\
+             SELECT count(*) FROM readings"
+        );
+    }
+
+    // Runs a query over a real archive, where the DuckDB command line tool is installed.
+    #[test]
+    fn test_query_archive() {
+        if Command::new("duckdb").arg("-version").output().is_err() {
+            println!("duckdb isn't installed, skipping");
+            return;
+        }
+        let directory = std::env::temp_dir().join(format!("blueplug-query-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        let mut archive = Archive::new(
+            &ArchiveConfig {
+                directory: directory.clone(),
+                partition: ArchivePartition::Hourly,
+                interval: 3600,
+                max_age: None,
+                max_size: None,
+            },
+            Some("Europe/Berlin"),
+        )
+        .unwrap();
+        let reading = DeviceReading {
+            device_id: DeviceId {
+                id: "hci0/dev_A4_C1_38_00_00_11".to_string(),
+                device_name: "ATC_QUERY".to_string(),
+                address: "A4:C1:38:00:00:11".to_string(),
+                random_address: None,
+            },
+            measurement: Measurement::Temperature(21.5),
+            advertisement: None,
+            quality: Vec::new(),
+        };
+        // Already the next day in Berlin.
+        let now: Timestamp = "2024-06-01T22:30:00Z".parse().unwrap();
+        archive.push(&reading, now);
+        archive.write(now).unwrap();
+
+        let output = command(
+            Path::new("duckdb"),
+            QueryFormat::Csv,
+            &directory,
+            Some("Europe/Berlin"),
+            "SELECT device, date, CAST(hour AS INTEGER) AS hour, kind, value, \
+             strftime(timestamp, '%Y-%m-%d %H:%M') AS local FROM readings",
+        )
+        .output()
+        .unwrap();
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        let lines: Vec<&str> = stdout.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "device,date,hour,kind,value,local",
+                "ATC_QUERY,2024-06-02,0,temperature,21.5,2024-06-02 00:30",
+            ]
+        );
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
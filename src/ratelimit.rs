@@ -0,0 +1,209 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use crate::config::{RateLimitConfig, RateLimitPolicy};
+
+// How often the buckets of devices that have refilled are dropped. A full bucket is no different
+// from a new one, so the map only holds devices heard from lately.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+// TokenBucket allows bursts of up to burst messages, refilling at rate messages per second.
+struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, burst: f64, now: Instant) -> Self {
+        TokenBucket {
+            rate,
+            burst,
+            tokens: burst,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.updated = now;
+    }
+
+    fn has_token(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= 1.0
+    }
+
+    fn take(&mut self) {
+        self.tokens -= 1.0;
+    }
+
+    fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.burst
+    }
+}
+
+// Admitted is what the limiter made of a message.
+#[derive(Debug, PartialEq)]
+pub enum Admitted<T> {
+    Send(T),
+    Queued,
+    // Dropped, either the message itself or, with a full queue, the oldest queued one.
+    Dropped,
+}
+
+// RateLimiter keeps the messages to a broker within a rate overall and for each device, so a room
+// full of sensors can't overwhelm a small broker. Messages over the limit are dropped, or queued
+// and released in order as the buckets refill, the oldest dropped once the queue is full.
+pub struct RateLimiter<T> {
+    config: RateLimitConfig,
+    global: Option<TokenBucket>,
+    // By device id.
+    devices: HashMap<String, TokenBucket>,
+    queue: VecDeque<(String, T)>,
+    pruned: Instant,
+}
+
+impl<T> RateLimiter<T> {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        let global = config.rate.map(|rate| {
+            TokenBucket::new(rate, config.burst.unwrap_or(rate.max(1.0)), Instant::now())
+        });
+        RateLimiter {
+            config: config.clone(),
+            global,
+            devices: HashMap::new(),
+            queue: VecDeque::new(),
+            pruned: Instant::now(),
+        }
+    }
+
+    pub fn is_queued(&self) -> bool {
+        !self.queue.is_empty()
+    }
+
+    // admit decides whether a device's message can be sent now. Once anything is queued, later
+    // messages queue behind it, so they aren't sent out of order.
+    pub fn admit(&mut self, device: &str, message: T, now: Instant) -> Admitted<T> {
+        self.prune(now);
+        if self.queue.is_empty() && self.try_take(device, now) {
+            return Admitted::Send(message);
+        }
+        match self.config.policy {
+            RateLimitPolicy::Drop => Admitted::Dropped,
+            RateLimitPolicy::Queue => {
+                self.queue.push_back((device.to_string(), message));
+                if self.queue.len() > self.config.queue_size {
+                    self.queue.pop_front();
+                    return Admitted::Dropped;
+                }
+                Admitted::Queued
+            }
+        }
+    }
+
+    // release returns the queued messages that can be sent now, oldest first. A device out of
+    // tokens doesn't hold up the messages of other devices behind it.
+    pub fn release(&mut self, now: Instant) -> Vec<T> {
+        let mut released = Vec::new();
+        let mut kept = VecDeque::new();
+        while let Some((device, message)) = self.queue.pop_front() {
+            if self.global.as_mut().is_some_and(|g| !g.has_token(now)) {
+                kept.push_back((device, message));
+                kept.append(&mut self.queue);
+                break;
+            }
+            if self.try_take(&device, now) {
+                released.push(message);
+            } else {
+                kept.push_back((device, message));
+            }
+        }
+        self.queue = kept;
+        released
+    }
+
+    fn prune(&mut self, now: Instant) {
+        if now.saturating_duration_since(self.pruned) < PRUNE_INTERVAL {
+            return;
+        }
+        self.devices.retain(|_, bucket| !bucket.is_full(now));
+        self.pruned = now;
+    }
+
+    fn try_take(&mut self, device: &str, now: Instant) -> bool {
+        if self.global.as_mut().is_some_and(|g| !g.has_token(now)) {
+            return false;
+        }
+        if let Some(rate) = self.config.device_rate {
+            let burst = self.config.device_burst.unwrap_or(1.0);
+            let bucket = self
+                .devices
+                .entry(device.to_string())
+                .or_insert_with(|| TokenBucket::new(rate, burst, now));
+            if !bucket.has_token(now) {
+                return false;
+            }
+            bucket.take();
+        }
+        if let Some(global) = &mut self.global {
+            global.take();
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::config::{RateLimitConfig, RateLimitPolicy};
+    use crate::ratelimit::{Admitted, RateLimiter};
+
+    #[test]
+    fn test_rate_limiter() {
+        let config = RateLimitConfig {
+            rate: Some(10.0),
+            burst: Some(2.0),
+            device_rate: Some(1.0),
+            device_burst: None,
+            policy: RateLimitPolicy::Queue,
+            queue_size: 3,
+        };
+        let mut limiter = RateLimiter::new(&config);
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+
+        assert_eq!(limiter.admit("a", 1, at(0)), Admitted::Send(1));
+        // a is out of tokens for a second, and then b queues behind it.
+        assert_eq!(limiter.admit("a", 2, at(0)), Admitted::Queued);
+        assert_eq!(limiter.admit("b", 3, at(0)), Admitted::Queued);
+        assert_eq!(limiter.release(at(0)), vec![3]);
+        assert_eq!(limiter.admit("a", 4, at(10)), Admitted::Queued);
+        assert_eq!(limiter.admit("c", 5, at(10)), Admitted::Queued);
+        // The queue holds 3, so the oldest goes.
+        assert_eq!(limiter.admit("d", 6, at(10)), Admitted::Dropped);
+        // Two tokens overall, at 10 a second.
+        assert_eq!(limiter.release(at(100)), vec![5]);
+        assert_eq!(limiter.release(at(300)), vec![6]);
+        assert_eq!(limiter.release(at(1000)), vec![4]);
+        assert!(!limiter.is_queued());
+
+        let mut limiter = RateLimiter::new(&RateLimitConfig {
+            policy: RateLimitPolicy::Drop,
+            ..config
+        });
+        assert_eq!(limiter.admit("a", 1, at(0)), Admitted::Send(1));
+        assert_eq!(limiter.admit("a", 2, at(0)), Admitted::Dropped);
+        assert_eq!(limiter.admit("a", 3, at(1000)), Admitted::Send(3));
+
+        // Buckets that refilled are pruned.
+        assert_eq!(limiter.admit("b", 4, at(1000)), Admitted::Send(4));
+        assert_eq!(limiter.devices.len(), 2);
+        assert_eq!(limiter.admit("c", 5, at(61_000)), Admitted::Send(5));
+        assert_eq!(limiter.devices.len(), 1);
+    }
+}
@@ -0,0 +1,168 @@
+use color_eyre::eyre::{eyre, Result};
+use uuid::Uuid;
+
+use crate::Measurement;
+
+// Renogy charge controllers speak Modbus RTU through a BT-1 or BT-2 module: requests are written
+// to one characteristic and responses arrive as notifications on another.
+pub const WRITE_CHARACTERISTIC: Uuid = Uuid::from_u128(0x0000ffd1_0000_1000_8000_00805f9b34fb);
+pub const NOTIFY_CHARACTERISTIC: Uuid = Uuid::from_u128(0x0000fff1_0000_1000_8000_00805f9b34fb);
+
+// The module answers to Modbus address 0xff regardless of the controller's own.
+const DEVICE_ADDRESS: u8 = 0xff;
+const READ_HOLDING_REGISTERS: u8 = 0x03;
+// Set in the function code of a response reporting an exception instead of the registers.
+const EXCEPTION: u8 = 0x80;
+
+// The live data registers, from battery state of charge at 0x0100 to the charging state at 0x0120.
+const FIRST_REGISTER: u16 = 0x0100;
+const REGISTER_COUNT: u16 = 0x22;
+
+// RESPONSE_LEN is the length of the answer to request(): address, function, byte count, the
+// registers and the CRC. An exception is only the address, function, exception code and CRC.
+const RESPONSE_LEN: usize = 3 + 2 * REGISTER_COUNT as usize + 2;
+const EXCEPTION_LEN: usize = 5;
+
+// response_len is the length of the response that frame starts, which is shorter for an
+// exception, once its function code has arrived.
+pub fn response_len(frame: &[u8]) -> usize {
+    match frame.get(1) {
+        Some(function) if function & EXCEPTION != 0 => EXCEPTION_LEN,
+        _ => RESPONSE_LEN,
+    }
+}
+
+// request reads the live data registers.
+pub fn request() -> Vec<u8> {
+    let mut frame = vec![DEVICE_ADDRESS, READ_HOLDING_REGISTERS];
+    frame.extend(FIRST_REGISTER.to_be_bytes());
+    frame.extend(REGISTER_COUNT.to_be_bytes());
+    frame.extend(crc(&frame).to_le_bytes());
+    frame
+}
+
+// measurements decodes the response to request().
+pub fn measurements(frame: &[u8]) -> Result<Vec<Measurement>> {
+    if frame.len() != response_len(frame) {
+        return Err(eyre!(
+            "expected {} bytes, got {}",
+            response_len(frame),
+            frame.len()
+        ));
+    }
+    let (body, checksum) = frame.split_at(frame.len() - 2);
+    if crc(body).to_le_bytes() != checksum {
+        return Err(eyre!("bad CRC"));
+    }
+    if body[1] == READ_HOLDING_REGISTERS | EXCEPTION {
+        return Err(eyre!("Modbus exception {:#04x}", body[2]));
+    }
+    if body[1] != READ_HOLDING_REGISTERS {
+        return Err(eyre!("unexpected Modbus function {:#04x}", body[1]));
+    }
+    let register = |offset: usize| u16::from_be_bytes([body[3 + 2 * offset], body[4 + 2 * offset]]);
+    let other = |kind: &str, value: f64, unit: &str| Measurement::Other {
+        kind: kind.to_string(),
+        value,
+        unit: unit.to_string(),
+    };
+    let [controller_temperature, battery_temperature] = register(0x03).to_be_bytes();
+    Ok(vec![
+        Measurement::Battery(register(0x00) as f64),
+        Measurement::Voltage(register(0x01) as f64 / 10.0),
+        other("charging_current", register(0x02) as f64 / 100.0, "A"),
+        other(
+            "controller_temperature",
+            sign_magnitude(controller_temperature),
+            "°C",
+        ),
+        other(
+            "battery_temperature",
+            sign_magnitude(battery_temperature),
+            "°C",
+        ),
+        other("load_voltage", register(0x04) as f64 / 10.0, "V"),
+        other("load_current", register(0x05) as f64 / 100.0, "A"),
+        other("load_power", register(0x06) as f64, "W"),
+        other("pv_voltage", register(0x07) as f64 / 10.0, "V"),
+        other("pv_current", register(0x08) as f64 / 100.0, "A"),
+        other("pv_power", register(0x09) as f64, "W"),
+        // 0 deactivated, 1 activated, 2 MPPT, 3 equalizing, 4 boost, 5 floating, 6 current
+        // limiting.
+        other("charge_state", (register(0x20) & 0xff) as f64, ""),
+    ])
+}
+
+// Temperatures are a sign bit followed by seven bits of degrees.
+fn sign_magnitude(byte: u8) -> f64 {
+    let degrees = (byte & 0x7f) as f64;
+    if byte & 0x80 != 0 {
+        -degrees
+    } else {
+        degrees
+    }
+}
+
+// crc is Modbus' CRC-16, sent least significant byte first.
+fn crc(data: &[u8]) -> u16 {
+    let mut crc = 0xffffu16;
+    for byte in data {
+        crc ^= *byte as u16;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xa001
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::renogy::{crc, measurements, request, response_len, RESPONSE_LEN};
+    use crate::Measurement;
+
+    #[test]
+    fn test_measurements() {
+        assert_eq!(
+            request(),
+            vec![0xff, 0x03, 0x01, 0x00, 0x00, 0x22, 0xd1, 0xf1]
+        );
+
+        let mut frame = vec![0xff, 0x03, 0x44];
+        let mut registers = [0u16; 0x22];
+        registers[0x00] = 87;
+        registers[0x01] = 132;
+        registers[0x02] = 245;
+        registers[0x03] = 0x1985;
+        registers[0x09] = 58;
+        registers[0x20] = 0x0002;
+        for register in registers {
+            frame.extend(register.to_be_bytes());
+        }
+        frame.extend(crc(&frame).to_le_bytes());
+        assert_eq!(frame.len(), RESPONSE_LEN);
+
+        let decoded = measurements(&frame).unwrap();
+        assert_eq!(decoded[0], Measurement::Battery(87.0));
+        assert_eq!(decoded[1], Measurement::Voltage(13.2));
+        assert_eq!(decoded[2].value(), 2.45);
+        assert_eq!(decoded[3].value(), 25.0);
+        assert_eq!(decoded[4].value(), -5.0);
+        assert_eq!(decoded[10].kind(), "pv_power");
+        assert_eq!(decoded[10].value(), 58.0);
+        assert_eq!(decoded[11].value(), 2.0);
+
+        frame[10] ^= 1;
+        assert!(measurements(&frame).is_err());
+
+        // An exception is complete after five bytes, rather than waiting for the registers.
+        let mut exception = vec![0xff, 0x83, 0x02];
+        assert_eq!(response_len(&exception), 5);
+        exception.extend(crc(&exception).to_le_bytes());
+        let error = measurements(&exception).unwrap_err();
+        assert_eq!(error.to_string(), "Modbus exception 0x02");
+    }
+}
@@ -0,0 +1,207 @@
+use aes::cipher::{BlockEncrypt, KeyInit};
+use aes::Aes128;
+use async_stream::stream;
+use color_eyre::eyre::{eyre, Result};
+use futures_core::stream::Stream;
+
+use crate::config::{AnonymousAddresses, RpaConfig};
+use crate::error::BlueplugError;
+use crate::{DeviceEvent, DeviceId};
+
+// Identity is an owned device whose resolvable private addresses can be resolved with its
+// Identity Resolving Key.
+struct Identity {
+    name: String,
+    cipher: Aes128,
+}
+
+// Resolver deals with resolvable private addresses (RPAs), which devices such as phones rotate
+// every few minutes. Addresses of known identities are resolved to `irk/<name>`, so they keep one
+// id. Other RPAs are kept, collapsed to one id per device name, or ignored.
+pub struct Resolver {
+    anonymous: AnonymousAddresses,
+    identities: Vec<Identity>,
+}
+
+impl Resolver {
+    pub fn new(config: &RpaConfig) -> Result<Self> {
+        let identities = config
+            .identities
+            .iter()
+            .map(|identity| {
+                let irk = parse_irk(&identity.irk).ok_or_else(|| {
+                    eyre!("identity {:?}: irk must be 32 hex digits", identity.name)
+                })?;
+                Ok(Identity {
+                    name: identity.name.clone(),
+                    cipher: Aes128::new(&irk.into()),
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Resolver {
+            anonymous: config.anonymous,
+            identities,
+        })
+    }
+
+    // resolve returns the device id to use for an advertisement from device_id, or None if it
+    // should be ignored.
+    pub fn resolve(&self, device_id: &DeviceId) -> Option<DeviceId> {
+        let Some(address) = resolvable_private_address(device_id) else {
+            return Some(device_id.clone());
+        };
+        if let Some(identity) = self.identities.iter().find(|i| i.resolves(&address)) {
+            return Some(DeviceId {
+                id: format!("irk/{}", identity.name),
+                device_name: identity.name.clone(),
+                address: device_id.address.clone(),
+                random_address: device_id.random_address,
+            });
+        }
+        match self.anonymous {
+            AnonymousAddresses::Keep => Some(device_id.clone()),
+            AnonymousAddresses::Collapse => Some(DeviceId {
+                id: format!("rpa/{}", device_id.device_name),
+                ..device_id.clone()
+            }),
+            AnonymousAddresses::Ignore => None,
+        }
+    }
+}
+
+impl Identity {
+    // resolves implements the random address hash function ah() from the Bluetooth Core
+    // specification (Vol 3, Part H, 2.2.2): the low 24 bits of the address are a hash of the high
+    // 24 bits under the IRK.
+    fn resolves(&self, address: &[u8; 6]) -> bool {
+        let mut block = [0u8; 16];
+        block[13..].copy_from_slice(&address[..3]);
+        let mut block = block.into();
+        self.cipher.encrypt_block(&mut block);
+        block[13..] == address[3..]
+    }
+}
+
+// resolvable_private_address parses the address of device_id, returning it only if it is an RPA:
+// a random address whose two most significant bits are 0b01. Public addresses, such as those of
+// Qingping's 58:2D:34 range, can have those bits too, so the bits alone only count where the
+// platform doesn't say whether the address is random.
+fn resolvable_private_address(device_id: &DeviceId) -> Option<[u8; 6]> {
+    if device_id.random_address == Some(false) {
+        return None;
+    }
+    let mut bytes = [0u8; 6];
+    let mut parts = device_id.address.split(':');
+    for byte in &mut bytes {
+        *byte = u8::from_str_radix(parts.next()?, 16).ok()?;
+    }
+    if parts.next().is_some() || bytes[0] >> 6 != 0b01 {
+        return None;
+    }
+    Some(bytes)
+}
+
+fn parse_irk(irk: &str) -> Option<[u8; 16]> {
+    if irk.len() != 32 {
+        return None;
+    }
+    let mut bytes = [0u8; 16];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(irk.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(bytes)
+}
+
+pub fn rpa_stream(
+    event_stream: impl Stream<Item = Result<DeviceEvent, BlueplugError>>,
+    resolver: Resolver,
+) -> impl Stream<Item = Result<DeviceEvent, BlueplugError>> {
+    stream! {
+        for await event in event_stream {
+            match event {
+                Ok(DeviceEvent::ManufacturerDataAdvertisement { device_id, manufacturer_data, rssi }) => {
+                    if let Some(device_id) = resolver.resolve(&device_id) {
+                        yield Ok(DeviceEvent::ManufacturerDataAdvertisement { device_id, manufacturer_data, rssi });
+                    }
+                }
+                Ok(DeviceEvent::ServiceDataAdvertisement { device_id, service_data, rssi }) => {
+                    if let Some(device_id) = resolver.resolve(&device_id) {
+                        yield Ok(DeviceEvent::ServiceDataAdvertisement { device_id, service_data, rssi });
+                    }
+                }
+                Err(e) => yield Err(e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{AnonymousAddresses, IdentityConfig, RpaConfig};
+    use crate::rpa::Resolver;
+    use crate::DeviceId;
+
+    fn device_id(address: &str) -> DeviceId {
+        DeviceId {
+            id: format!("hci0/dev_{}", address.replace(':', "_")),
+            device_name: "Phone".to_string(),
+            address: address.to_string(),
+            random_address: None,
+        }
+    }
+
+    #[test]
+    fn test_resolve() {
+        // The sample data from the Bluetooth Core specification (Vol 3, Part H, D.7).
+        let resolver = Resolver::new(&RpaConfig {
+            anonymous: AnonymousAddresses::Ignore,
+            identities: vec![IdentityConfig {
+                name: "alice".to_string(),
+                irk: "ec0234a357c8ad05341010a60a397d9b".to_string(),
+            }],
+        })
+        .unwrap();
+
+        let resolved = resolver.resolve(&device_id("70:81:94:0D:FB:AA")).unwrap();
+        assert_eq!(resolved.id, "irk/alice");
+        assert_eq!(resolved.device_name, "alice");
+
+        // Another RPA, which doesn't resolve.
+        assert!(resolver.resolve(&device_id("70:81:94:0D:FB:AB")).is_none());
+        // Not an RPA.
+        let public = device_id("A4:C1:38:8F:2C:1A");
+        assert_eq!(resolver.resolve(&public).unwrap().id, public.id);
+        // A public address with the bits of one, which the platform says is public.
+        let public = DeviceId {
+            random_address: Some(false),
+            ..device_id("58:2D:34:12:34:56")
+        };
+        assert_eq!(resolver.resolve(&public).unwrap().id, public.id);
+        let random = DeviceId {
+            random_address: Some(true),
+            ..device_id("58:2D:34:12:34:56")
+        };
+        assert!(resolver.resolve(&random).is_none());
+
+        let collapsing = Resolver::new(&RpaConfig {
+            anonymous: AnonymousAddresses::Collapse,
+            identities: vec![],
+        })
+        .unwrap();
+        assert_eq!(
+            collapsing
+                .resolve(&device_id("70:81:94:0D:FB:AB"))
+                .unwrap()
+                .id,
+            "rpa/Phone"
+        );
+        assert!(Resolver::new(&RpaConfig {
+            anonymous: AnonymousAddresses::Keep,
+            identities: vec![IdentityConfig {
+                name: "bob".to_string(),
+                irk: "nope".to_string(),
+            }],
+        })
+        .is_err());
+    }
+}
@@ -0,0 +1,266 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time;
+
+use crate::config::{ConditionConfig, RuleConfig};
+use crate::fanout::devices_match;
+use crate::supervisor::SUPERVISOR;
+use crate::DeviceReading;
+
+// The name routes refer to the rules by.
+pub const SINK_NAME: &str = "rules";
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// Action is a message a rule publishes as it turns on or off, sent to the brokers as it is.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Action {
+    pub rule: String,
+    pub on: bool,
+    pub topic: String,
+    pub payload: String,
+    pub retain: bool,
+    // The brokers to publish to, every broker if empty.
+    pub brokers: Vec<String>,
+}
+
+struct Condition {
+    config: ConditionConfig,
+    // Whether the latest reading of each matching device, by id, meets the condition.
+    holding: HashMap<String, bool>,
+}
+
+impl Condition {
+    // update takes a reading of a matching device into account. Once met, a threshold has to be
+    // crossed back by the hysteresis before the condition stops holding for that device, so a
+    // value hovering around it doesn't turn the rule on and off.
+    fn update(&mut self, reading: &DeviceReading) {
+        if reading.measurement.kind() != self.config.kind
+            || !devices_match(&self.config.devices, &reading.device_id)
+        {
+            return;
+        }
+        let value = reading.measurement.value();
+        let was_holding = self.holding.get(&reading.device_id.id) == Some(&true);
+        let margin = if was_holding {
+            self.config.hysteresis
+        } else {
+            0.0
+        };
+        let holds = self.config.above.is_none_or(|above| value > above - margin)
+            && self.config.below.is_none_or(|below| value < below + margin);
+        self.holding.insert(reading.device_id.id.clone(), holds);
+    }
+
+    // holds reports whether any matching device meets the condition.
+    fn holds(&self) -> bool {
+        self.holding.values().any(|holds| *holds)
+    }
+}
+
+struct Rule {
+    config: RuleConfig,
+    conditions: Vec<Condition>,
+    on: bool,
+    // Since when the conditions disagree with whether the rule is on.
+    changing: Option<Instant>,
+}
+
+impl Rule {
+    fn step(&mut self, now: Instant) -> Option<Action> {
+        let holds = self.conditions.iter().all(Condition::holds);
+        if holds == self.on {
+            self.changing = None;
+            return None;
+        }
+        let since = *self.changing.get_or_insert(now);
+        let after = if holds {
+            self.config.on_after
+        } else {
+            self.config.off_after
+        };
+        if now.duration_since(since) < Duration::from_secs(after) {
+            return None;
+        }
+        self.on = holds;
+        self.changing = None;
+        let payload = if holds {
+            Some(&self.config.on_payload)
+        } else {
+            self.config.off_payload.as_ref()
+        };
+        payload.map(|payload| Action {
+            rule: self.config.name.clone(),
+            on: holds,
+            topic: self.config.topic.clone(),
+            payload: payload.clone(),
+            retain: self.config.retain,
+            brokers: self.config.brokers.clone(),
+        })
+    }
+}
+
+// Rules automate other devices from readings: a rule turns on once all of its conditions have
+// held for on_after seconds, publishing its on_payload, and turns off once they haven't for
+// off_after seconds, publishing its off_payload. A condition compares the latest reading of one
+// kind of the matching devices against thresholds, and holds while any of the devices meets it.
+// Rules start off, and only publish as they change.
+pub struct Rules {
+    rules: Vec<Rule>,
+}
+
+impl Rules {
+    pub fn new(configs: &[RuleConfig]) -> Self {
+        let rules = configs
+            .iter()
+            .map(|config| Rule {
+                config: config.clone(),
+                conditions: config
+                    .conditions
+                    .iter()
+                    .map(|config| Condition {
+                        config: config.clone(),
+                        holding: HashMap::new(),
+                    })
+                    .collect(),
+                on: false,
+                changing: None,
+            })
+            .collect();
+        Rules { rules }
+    }
+
+    pub fn reading(&mut self, reading: &DeviceReading, now: Instant) -> Vec<Action> {
+        for rule in &mut self.rules {
+            for condition in &mut rule.conditions {
+                condition.update(reading);
+            }
+        }
+        self.tick(now)
+    }
+
+    // tick returns the actions of rules whose conditions have held, or not, for long enough.
+    pub fn tick(&mut self, now: Instant) -> Vec<Action> {
+        self.rules
+            .iter_mut()
+            .filter_map(|rule| rule.step(now))
+            .collect()
+    }
+}
+
+// spawn_rules runs the rules on readings, checking every CHECK_INTERVAL for rules whose timers
+// have run out, and sends their actions to the brokers.
+pub fn spawn_rules(
+    mut rules: Rules,
+    mut readings: broadcast::Receiver<Arc<DeviceReading>>,
+    sender: broadcast::Sender<Arc<Action>>,
+) {
+    SUPERVISOR.spawn_once("rules", async move {
+        let mut interval = time::interval(CHECK_INTERVAL);
+        loop {
+            let actions = tokio::select! {
+                received = readings.recv() => match received {
+                    Ok(reading) => rules.reading(&reading, Instant::now()),
+                    Err(RecvError::Lagged(skipped)) => {
+                        println!("rules: falling behind, dropped {} readings", skipped);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => return,
+                },
+                _ = interval.tick() => rules.tick(Instant::now()),
+            };
+            for action in actions {
+                println!(
+                    "rule {} turned {}",
+                    action.rule,
+                    if action.on { "on" } else { "off" }
+                );
+                // Sending only fails when no broker is listening.
+                let _ = sender.send(Arc::new(action));
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::config::{ConditionConfig, RuleConfig};
+    use crate::rules::Rules;
+    use crate::{DeviceId, DeviceReading, Measurement};
+
+    #[test]
+    fn test_rules() {
+        let mut rules = Rules::new(&[RuleConfig {
+            name: "dehumidifier".to_string(),
+            conditions: vec![
+                ConditionConfig {
+                    devices: vec!["ATC_RULES_BATH".to_string()],
+                    kind: "humidity".to_string(),
+                    above: Some(70.0),
+                    below: None,
+                    hysteresis: 5.0,
+                },
+                ConditionConfig {
+                    devices: vec!["ATC_RULES_HALL".to_string()],
+                    kind: "temperature".to_string(),
+                    above: Some(10.0),
+                    below: None,
+                    hysteresis: 0.0,
+                },
+            ],
+            on_after: 600,
+            off_after: 0,
+            topic: "home/dehumidifier/set".to_string(),
+            on_payload: "ON".to_string(),
+            off_payload: Some("OFF".to_string()),
+            retain: false,
+            brokers: Vec::new(),
+        }]);
+        let reading = |name: &str, measurement| DeviceReading {
+            device_id: DeviceId {
+                id: format!("hci0/{}", name),
+                device_name: name.to_string(),
+                address: String::new(),
+                random_address: None,
+            },
+            measurement,
+            advertisement: None,
+            quality: Vec::new(),
+        };
+        let bath = |humidity| reading("ATC_RULES_BATH", Measurement::Humidity(humidity));
+        let start = Instant::now();
+        let at = |seconds| start + Duration::from_secs(seconds);
+
+        assert!(rules.reading(&bath(75.0), at(0)).is_empty());
+        // Both conditions have to hold, for ten minutes.
+        let hall = reading("ATC_RULES_HALL", Measurement::Temperature(20.0));
+        assert!(rules.reading(&hall, at(10)).is_empty());
+        assert!(rules.tick(at(609)).is_empty());
+        let actions = rules.tick(at(610));
+        assert_eq!(actions.len(), 1);
+        assert!(actions[0].on);
+        assert_eq!(actions[0].topic, "home/dehumidifier/set");
+        assert_eq!(actions[0].payload, "ON");
+        assert!(rules.tick(at(700)).is_empty());
+
+        // Within the hysteresis it stays on.
+        assert!(rules.reading(&bath(68.0), at(800)).is_empty());
+        let actions = rules.reading(&bath(64.0), at(900));
+        assert_eq!(actions.len(), 1);
+        assert!(!actions[0].on);
+        assert_eq!(actions[0].payload, "OFF");
+
+        // Dipping below before the time is up starts it over.
+        assert!(rules.reading(&bath(72.0), at(1000)).is_empty());
+        assert!(rules.reading(&bath(60.0), at(1300)).is_empty());
+        assert!(rules.reading(&bath(72.0), at(1400)).is_empty());
+        assert!(rules.tick(at(1700)).is_empty());
+        assert_eq!(rules.tick(at(2000)).len(), 1);
+    }
+}
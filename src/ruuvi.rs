@@ -0,0 +1,53 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use uuid::Uuid;
+
+use crate::Measurement;
+
+// Eddystone advertisements are service data of this UUID.
+pub const EDDYSTONE_UUID: Uuid = Uuid::from_u128(0x0000feaa_0000_1000_8000_00805f9b34fb);
+
+const EDDYSTONE_URL: u8 = 0x10;
+
+// What every Ruuvi URL starts with, after the Eddystone URL scheme byte.
+const URL_PREFIX: &[u8] = b"ruu.vi/#";
+
+// url_measurements decodes the Eddystone URL frames of RuuviTags running the original weather
+// station firmware, data formats 2 and 4. The frame is the frame type, the transmit power, the URL
+// scheme, then ruu.vi/# and six bytes in URL safe base64: the format, humidity in half percents,
+// the whole degrees Celsius with the sign in the top bit, hundredths of a degree, and the pressure.
+// Format 4 adds a character identifying the tag, which is ignored. Other Eddystone frames and URLs
+// decode to nothing.
+pub fn url_measurements(data: &[u8]) -> Vec<Measurement> {
+    let Some(encoded) = data
+        .strip_prefix(&[EDDYSTONE_URL])
+        .and_then(|frame| frame.get(2..))
+        .and_then(|url| url.strip_prefix(URL_PREFIX))
+    else {
+        return Vec::new();
+    };
+    // Early firmware used the standard alphabet.
+    let encoded: Vec<u8> = encoded
+        .iter()
+        .take(8)
+        .map(|c| match c {
+            b'+' => b'-',
+            b'/' => b'_',
+            c => *c,
+        })
+        .collect();
+    let decoded = match URL_SAFE_NO_PAD.decode(encoded) {
+        Ok(decoded) if decoded.len() == 6 && matches!(decoded[0], 2 | 4) => decoded,
+        _ => return Vec::new(),
+    };
+    let magnitude = (decoded[2] & 0x7f) as f64 + decoded[3] as f64 / 100.0;
+    let temperature = if decoded[2] & 0x80 != 0 {
+        -magnitude
+    } else {
+        magnitude
+    };
+    vec![
+        Measurement::Humidity(decoded[1] as f64 / 2.0),
+        Measurement::Temperature(temperature),
+    ]
+}
@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+
+use btleplug::api::bleuuid::uuid_from_u16;
+use color_eyre::eyre::Result;
+use uuid::Uuid;
+
+use crate::gatt::Reader;
+use crate::{DeviceId, Measurement};
+
+// The Weight Scale (0x181D) and Body Composition (0x181B) services' measurement characteristics,
+// which scales indicate once a weighing is complete.
+pub const WEIGHT_MEASUREMENT: Uuid = Uuid::from_u128(0x00002a9d_0000_1000_8000_00805f9b34fb);
+pub const BODY_COMPOSITION_MEASUREMENT: Uuid =
+    Uuid::from_u128(0x00002a9c_0000_1000_8000_00805f9b34fb);
+
+const POUND: f64 = 0.45359237;
+const JIN: f64 = 0.5;
+
+// weight_measurement decodes a Weight Measurement, in kilograms.
+pub fn weight_measurement(value: &[u8]) -> Result<Vec<Measurement>> {
+    let mut reader = Reader(value);
+    let flags = reader.u8()?;
+    let weight = reader.u16()? as f64;
+    Ok(vec![weight_kg(if flags & 0x01 != 0 {
+        weight * 0.01 * POUND
+    } else {
+        weight * 0.005
+    })])
+}
+
+// body_composition decodes a Body Composition Measurement: body fat, and impedance and weight when
+// the scale includes them.
+pub fn body_composition(value: &[u8]) -> Result<Vec<Measurement>> {
+    let mut reader = Reader(value);
+    let flags = reader.u16()?;
+    let mut measurements = vec![other("body_fat", reader.u16()? as f64 / 10.0, "%")];
+    // Timestamp, user id, then basal metabolism, muscle percentage, muscle mass, fat free mass,
+    // soft lean mass and body water mass, none of which are reported.
+    for (bit, len) in [
+        (1, 7),
+        (2, 1),
+        (3, 2),
+        (4, 2),
+        (5, 2),
+        (6, 2),
+        (7, 2),
+        (8, 2),
+    ] {
+        if flags & (1 << bit) != 0 {
+            reader.skip(len)?;
+        }
+    }
+    if flags & (1 << 9) != 0 {
+        measurements.push(other("impedance", reader.u16()? as f64 / 10.0, "Ω"));
+    }
+    if flags & (1 << 10) != 0 {
+        let weight = reader.u16()? as f64;
+        measurements.push(weight_kg(if flags & 0x01 != 0 {
+            weight * 0.01 * POUND
+        } else {
+            weight * 0.005
+        }));
+    }
+    Ok(measurements)
+}
+
+// MiScales decodes the advertisements of Xiaomi Mi Scales, the original (service data 0x181D) and
+// the Body Composition Scale (0x181B). A scale advertises while the weight settles and then
+// repeats the final weighing for a while, so only stabilized weighings are decoded, and each only
+// once.
+#[derive(Default)]
+pub struct MiScales {
+    // The last weighing decoded from each device, by id.
+    last: HashMap<String, Vec<u8>>,
+}
+
+impl MiScales {
+    pub fn measurements(
+        &mut self,
+        device_id: &DeviceId,
+        service_data: &HashMap<Uuid, Vec<u8>>,
+    ) -> Vec<Measurement> {
+        let decoded = if let Some(data) = service_data.get(&uuid_from_u16(0x181b)) {
+            body_composition_scale(data).map(|measurements| (data, measurements))
+        } else if let Some(data) = service_data.get(&uuid_from_u16(0x181d)) {
+            scale(data).map(|measurements| (data, measurements))
+        } else {
+            None
+        };
+        let Some((data, measurements)) = decoded else {
+            return Vec::new();
+        };
+        if self.last.get(&device_id.id) == Some(data) {
+            return Vec::new();
+        }
+        self.last.insert(device_id.id.clone(), data.clone());
+        measurements
+    }
+}
+
+fn scale(data: &[u8]) -> Option<Vec<Measurement>> {
+    if data.len() != 10 {
+        return None;
+    }
+    let control = data[0];
+    let stabilized = control & 0x20 != 0;
+    let removed = control & 0x80 != 0;
+    if !stabilized || removed {
+        return None;
+    }
+    let weight = u16::from_le_bytes([data[1], data[2]]) as f64;
+    Some(vec![weight_kg(if control & 0x01 != 0 {
+        weight / 100.0 * POUND
+    } else if control & 0x10 != 0 {
+        weight / 100.0 * JIN
+    } else {
+        weight / 200.0
+    })])
+}
+
+fn body_composition_scale(data: &[u8]) -> Option<Vec<Measurement>> {
+    if data.len() != 13 {
+        return None;
+    }
+    let stabilized = data[1] & 0x20 != 0;
+    let removed = data[1] & 0x80 != 0;
+    if !stabilized || removed {
+        return None;
+    }
+    let weight = u16::from_le_bytes([data[11], data[12]]) as f64;
+    let mut measurements = vec![weight_kg(if data[0] & 0x01 != 0 {
+        weight / 100.0 * POUND
+    } else if data[0] & 0x40 != 0 {
+        weight / 100.0 * JIN
+    } else {
+        weight / 200.0
+    })];
+    let impedance = u16::from_le_bytes([data[9], data[10]]);
+    // Impedance is measured a little after the weight settles, and only while standing barefoot.
+    if data[1] & 0x02 != 0 && impedance > 0 && impedance < 3000 {
+        measurements.push(other("impedance", impedance as f64, "Ω"));
+    }
+    Some(measurements)
+}
+
+fn weight_kg(kg: f64) -> Measurement {
+    other("weight", (kg * 100.0).round() / 100.0, "kg")
+}
+
+fn other(kind: &str, value: f64, unit: &str) -> Measurement {
+    Measurement::Other {
+        kind: kind.to_string(),
+        value,
+        unit: unit.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use btleplug::api::bleuuid::uuid_from_u16;
+
+    use crate::scale::{weight_measurement, MiScales};
+    use crate::DeviceId;
+
+    #[test]
+    fn test_mi_scale() {
+        let device_id = DeviceId {
+            id: "hci0/dev_C8_47_8C_10_22_33".to_string(),
+            device_name: "MIBFS".to_string(),
+            address: "C8:47:8C:10:22:33".to_string(),
+            random_address: None,
+        };
+        let mut scales = MiScales::default();
+        let advertisement = |control: u8| {
+            // 72.5 kg (14500 / 200) and 500 Ω.
+            let data = vec![
+                0x02, control, 0xe8, 0x07, 0x05, 0x0e, 0x08, 0x1e, 0x00, 0xf4, 0x01, 0xa4, 0x38,
+            ];
+            HashMap::from([(uuid_from_u16(0x181b), data)])
+        };
+
+        // Still settling.
+        assert!(scales
+            .measurements(&device_id, &advertisement(0x00))
+            .is_empty());
+        let measurements = scales.measurements(&device_id, &advertisement(0x26));
+        assert_eq!(measurements.len(), 2);
+        assert_eq!(measurements[0].kind(), "weight");
+        assert_eq!(measurements[0].value(), 72.5);
+        assert_eq!(measurements[1].value(), 500.0);
+        // The same weighing, repeated.
+        assert!(scales
+            .measurements(&device_id, &advertisement(0x26))
+            .is_empty());
+
+        // A standard Weight Measurement of 72.5 kg, at a resolution of 5 g.
+        assert_eq!(
+            weight_measurement(&[0x00, 0xa4, 0x38]).unwrap()[0].value(),
+            72.5
+        );
+    }
+}
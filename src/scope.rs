@@ -0,0 +1,47 @@
+use std::future::Future;
+use std::sync::Arc;
+
+use futures_core::stream::Stream;
+use futures_util::stream::{self, StreamExt};
+
+use crate::exempt::Exempt;
+use crate::metrics::Metrics;
+use crate::names::TopicNames;
+
+// Scope is what an embedded Blueplug keeps to itself, so that instances in one process don't see
+// each other's: the counters its tasks count into as well as METRICS, and the topic names and
+// exempt kinds that stand in for TOPIC_NAMES' and EXEMPT's within its tasks.
+#[derive(Default)]
+pub struct Scope {
+    pub metrics: Metrics,
+    pub names: TopicNames,
+    pub exempt: Exempt,
+}
+
+tokio::task_local! {
+    static SCOPE: Option<Arc<Scope>>;
+}
+
+// scope runs future within scope.
+pub async fn scope<F: Future>(scope: Option<Arc<Scope>>, future: F) -> F::Output {
+    SCOPE.scope(scope, future).await
+}
+
+// sync_scope calls f within scope.
+pub fn sync_scope<R>(scope: Option<Arc<Scope>>, f: impl FnOnce() -> R) -> R {
+    SCOPE.sync_scope(scope, f)
+}
+
+// scope_stream polls stream within scope, for streams polled by tasks of the caller's.
+pub fn scope_stream<S: Stream>(
+    scope: Option<Arc<Scope>>,
+    stream: S,
+) -> impl Stream<Item = S::Item> {
+    let mut stream = Box::pin(stream);
+    stream::poll_fn(move |cx| sync_scope(scope.clone(), || stream.poll_next_unpin(cx)))
+}
+
+// current is the scope of the running task, if it has one.
+pub fn current() -> Option<Arc<Scope>> {
+    SCOPE.try_with(Clone::clone).ok().flatten()
+}
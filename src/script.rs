@@ -0,0 +1,280 @@
+use std::path::{Path, PathBuf};
+
+use async_stream::stream;
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use futures_core::stream::Stream;
+use rhai::{Array, CallFnOptions, Dynamic, Engine, Map, Scope, AST};
+
+use crate::{DeviceId, DeviceReading, Measurement};
+
+// The hook every script defines.
+const HOOK: &str = "on_reading";
+
+// Operations a script may run per reading, so a runaway loop can't stall the pipeline.
+const MAX_OPERATIONS: u64 = 100_000;
+
+struct Script {
+    path: PathBuf,
+    ast: AST,
+    // The script's `this`, a map kept from one reading to the next.
+    state: Dynamic,
+}
+
+// Scripts are Rhai scripts that transform readings, for logic mappings and derivations can't
+// express. Each defines `fn on_reading(reading)`, called with every reading as a map of id, name,
+// address, kind, value and unit, which returns:
+//
+// - nothing, to pass the reading on unchanged;
+// - a map, usually the reading changed, to pass on instead;
+// - an array of maps, to pass on instead, so `[]` drops the reading and `[reading, other]` adds
+//   one.
+//
+// Fields left out of a returned map are the reading's. `this` is a map the script can keep state
+// in between readings, e.g. to combine the readings of several devices. Scripts run in the order
+// configured, each on what the one before returned. A script that fails on a reading is logged and
+// the reading passed on unchanged.
+pub struct Scripts {
+    engine: Engine,
+    scripts: Vec<Script>,
+}
+
+impl Scripts {
+    pub fn new(paths: &[PathBuf]) -> Result<Self> {
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        let scripts = paths
+            .iter()
+            .map(|path| {
+                let ast = compile(&engine, path)
+                    .wrap_err_with(|| format!("script {}", path.display()))?;
+                Ok(Script {
+                    path: path.clone(),
+                    ast,
+                    state: Dynamic::from_map(Map::new()),
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Scripts { engine, scripts })
+    }
+
+    pub fn apply(&mut self, reading: DeviceReading) -> Vec<DeviceReading> {
+        let mut readings = vec![reading];
+        for script in &mut self.scripts {
+            readings = readings
+                .into_iter()
+                .flat_map(|reading| match run(&self.engine, script, &reading) {
+                    Ok(Some(readings)) => readings,
+                    Ok(None) => vec![reading],
+                    Err(e) => {
+                        println!("script {}: {:#}", script.path.display(), e);
+                        vec![reading]
+                    }
+                })
+                .collect();
+        }
+        readings
+    }
+}
+
+fn compile(engine: &Engine, path: &Path) -> Result<AST> {
+    let ast = engine.compile_file(path.to_path_buf())?;
+    if !ast
+        .iter_functions()
+        .any(|f| f.name == HOOK && f.params.len() == 1)
+    {
+        return Err(eyre!("doesn't define fn {}(reading)", HOOK));
+    }
+    Ok(ast)
+}
+
+// run calls a script's hook with reading, returning what to pass on instead of it, or None to pass
+// it on unchanged.
+fn run(
+    engine: &Engine,
+    script: &mut Script,
+    reading: &DeviceReading,
+) -> Result<Option<Vec<DeviceReading>>> {
+    let mut map = Map::new();
+    let device_id = &reading.device_id;
+    map.insert("id".into(), device_id.id.clone().into());
+    map.insert("name".into(), device_id.device_name.clone().into());
+    map.insert("address".into(), device_id.address.clone().into());
+    map.insert("kind".into(), reading.measurement.kind().into());
+    map.insert("value".into(), reading.measurement.value().into());
+    map.insert("unit".into(), reading.measurement.unit().into());
+
+    let options = CallFnOptions::new()
+        .eval_ast(false)
+        .bind_this_ptr(&mut script.state);
+    let returned: Dynamic = engine
+        .call_fn_with_options(options, &mut Scope::new(), &script.ast, HOOK, (map,))
+        .map_err(|e| eyre!("{}", e))?;
+
+    if returned.is_unit() {
+        return Ok(None);
+    }
+    if returned.is_array() {
+        return returned
+            .cast::<Array>()
+            .into_iter()
+            .map(|item| to_reading(reading, item))
+            .collect::<Result<_>>()
+            .map(Some);
+    }
+    Ok(Some(vec![to_reading(reading, returned)?]))
+}
+
+// to_reading makes a reading of a map returned by a script, taking what it leaves out from
+// original.
+fn to_reading(original: &DeviceReading, value: Dynamic) -> Result<DeviceReading> {
+    let type_name = value.type_name();
+    let map = value
+        .try_cast::<Map>()
+        .ok_or_else(|| eyre!("{} returned {}, not a reading", HOOK, type_name))?;
+    let string = |key: &str, default: &str| -> Result<String> {
+        match map.get(key) {
+            None => Ok(default.to_string()),
+            Some(value) => value
+                .clone()
+                .into_string()
+                .map_err(|t| eyre!("{} must be a string, not {}", key, t)),
+        }
+    };
+    let device_id = DeviceId {
+        id: string("id", &original.device_id.id)?,
+        device_name: string("name", &original.device_id.device_name)?,
+        address: string("address", &original.device_id.address)?,
+        random_address: None,
+    };
+    let kind = string("kind", original.measurement.kind())?;
+    let unit = string("unit", original.measurement.unit())?;
+    let value = match map.get("value") {
+        None => original.measurement.value(),
+        Some(value) => value
+            .as_float()
+            .or_else(|_| value.as_int().map(|i| i as f64))
+            .map_err(|t| eyre!("value must be a number, not {}", t))?,
+    };
+    Ok(DeviceReading {
+        device_id,
+        measurement: Measurement::new(kind, value, unit),
+        advertisement: original.advertisement,
+        quality: original.quality.clone(),
+    })
+}
+
+pub fn script_stream(
+    readings: impl Stream<Item = DeviceReading>,
+    mut scripts: Scripts,
+) -> impl Stream<Item = DeviceReading> {
+    stream! {
+        for await reading in readings {
+            if scripts.scripts.is_empty() {
+                yield reading;
+                continue;
+            }
+            for reading in scripts.apply(reading) {
+                yield reading;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::script::Scripts;
+    use crate::{DeviceId, DeviceReading, Measurement};
+
+    #[test]
+    fn test_scripts() {
+        let directory =
+            std::env::temp_dir().join(format!("blueplug-scripts-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let script = |name: &str, source: &str| {
+            let path = directory.join(name);
+            std::fs::write(&path, source).unwrap();
+            path
+        };
+        let convert = script(
+            "convert.rhai",
+            r#"
+            fn on_reading(reading) {
+                if reading.kind == "temperature" {
+                    reading.kind = "temperature_f";
+                    reading.value = reading.value * 9.0 / 5.0 + 32.0;
+                    reading.unit = "°F";
+                    return reading;
+                }
+                if reading.kind == "rssi" { return []; }
+            }
+            "#,
+        );
+        let combine = script(
+            "combine.rhai",
+            r#"
+            fn on_reading(reading) {
+                if reading.kind != "power" { return; }
+                this[reading.id] = reading.value;
+                let total = 0.0;
+                for value in this.values() { total += value; }
+                [reading, #{ id: "total", name: "Total", kind: "power", value: total }]
+            }
+            "#,
+        );
+        let mut scripts = Scripts::new(&[convert, combine]).unwrap();
+        let reading = |id: &str, measurement| DeviceReading {
+            device_id: DeviceId {
+                id: id.to_string(),
+                device_name: "ATC_SCRIPT".to_string(),
+                address: String::new(),
+                random_address: None,
+            },
+            measurement,
+            advertisement: Some(7),
+            quality: Vec::new(),
+        };
+
+        let converted = scripts.apply(reading("a", Measurement::Temperature(20.0)));
+        assert_eq!(converted.len(), 1);
+        assert_eq!(
+            converted[0].measurement,
+            Measurement::Other {
+                kind: "temperature_f".to_string(),
+                value: 68.0,
+                unit: "°F".to_string()
+            }
+        );
+        let unchanged = scripts.apply(reading("a", Measurement::Humidity(40.0)));
+        assert_eq!(unchanged[0].measurement, Measurement::Humidity(40.0));
+        assert!(scripts
+            .apply(reading(
+                "a",
+                Measurement::Other {
+                    kind: "rssi".to_string(),
+                    value: -60.0,
+                    unit: "dBm".to_string()
+                }
+            ))
+            .is_empty());
+
+        scripts.apply(reading("plug1", Measurement::Power(100.0)));
+        let combined = scripts.apply(reading("plug2", Measurement::Power(50.0)));
+        assert_eq!(combined.len(), 2);
+        assert_eq!(combined[1].device_id.id, "total");
+        assert_eq!(combined[1].device_id.device_name, "Total");
+        assert_eq!(combined[1].measurement, Measurement::Power(150.0));
+        assert_eq!(combined[1].advertisement, Some(7));
+
+        let broken = script("broken.rhai", "fn transform(reading) { reading }");
+        assert!(Scripts::new(&[broken]).is_err());
+        let looping = script("loop.rhai", "fn on_reading(reading) { loop {} }");
+        let mut scripts = Scripts::new(&[looping]).unwrap();
+        // Stopped, and passed on unchanged.
+        assert_eq!(
+            scripts.apply(reading("a", Measurement::Battery(90.0)))[0].measurement,
+            Measurement::Battery(90.0)
+        );
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
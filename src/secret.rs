@@ -0,0 +1,71 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use color_eyre::eyre::{eyre, Result, WrapErr};
+
+// read_secret returns a secret kept outside the configuration: the contents of file, or what
+// command prints, less trailing newlines. A relative file is looked for in the directory systemd
+// passes credentials in, if there is one, so `LoadCredential=mqtt-password` can be used as
+// `password_file = "mqtt-password"`. Docker secrets and Vault agent templates are plain files, and
+// anything else can be reached with a command, run with `sh -c`.
+pub fn read_secret(file: Option<&Path>, command: Option<&str>) -> Result<Option<String>> {
+    let secret = match (file, command) {
+        (Some(_), Some(_)) => return Err(eyre!("only one of a file and a command can be given")),
+        (Some(file), None) => {
+            let path = credential_path(file);
+            std::fs::read_to_string(&path)
+                .wrap_err_with(|| format!("reading secret from {}", path.display()))?
+        }
+        (None, Some(command)) => {
+            let output = Command::new("sh")
+                .args(["-c", command])
+                .output()
+                .wrap_err_with(|| format!("running {:?}", command))?;
+            if !output.status.success() {
+                return Err(eyre!(
+                    "{:?} failed with {}: {}",
+                    command,
+                    output.status,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ));
+            }
+            String::from_utf8(output.stdout)
+                .wrap_err_with(|| format!("output of {:?} isn't UTF-8", command))?
+        }
+        (None, None) => return Ok(None),
+    };
+    Ok(Some(secret.trim_end_matches(['\r', '\n']).to_string()))
+}
+
+fn credential_path(file: &Path) -> PathBuf {
+    match std::env::var_os("CREDENTIALS_DIRECTORY") {
+        Some(dir) if file.is_relative() => Path::new(&dir).join(file),
+        _ => file.to_path_buf(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::secret::read_secret;
+
+    #[test]
+    fn test_read_secret() {
+        let path = std::env::temp_dir().join(format!("blueplug-secret-{}", std::process::id()));
+        std::fs::write(&path, "hunter2\n").unwrap();
+        assert_eq!(
+            read_secret(Some(&path), None).unwrap().as_deref(),
+            Some("hunter2")
+        );
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            read_secret(None, Some("printf 'hunter2\\n'"))
+                .unwrap()
+                .as_deref(),
+            Some("hunter2")
+        );
+        assert!(read_secret(None, Some("echo denied >&2; exit 1")).is_err());
+        assert!(read_secret(Some(&path), Some("true")).is_err());
+        assert_eq!(read_secret(None, None).unwrap(), None);
+    }
+}
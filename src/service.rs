@@ -0,0 +1,366 @@
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+use clap::Subcommand;
+use color_eyre::eyre::{eyre, Result, WrapErr};
+
+// Set in the environment of the blueplug that a service runs, see supervise.
+const CHILD_ENV: &str = "BLUEPLUG_SERVICE_CHILD";
+
+// How long after failing blueplug is started again.
+const RESTART_DELAY: Duration = Duration::from_secs(10);
+
+const NAME: &str = "blueplug";
+const UNIT_PATH: &str = "/etc/systemd/system/blueplug.service";
+const LAUNCHD_LABEL: &str = "io.github.hagmonk.blueplug";
+
+// CREATE_NO_WINDOW, so neither blueplug nor the commands it logs with open a console.
+#[cfg(windows)]
+const CREATE_NO_WINDOW: u32 = 0x08000000;
+
+// ServiceArgs configures `blueplug service`, which installs blueplug to start at boot, with
+// --service, as a systemd unit on Linux, a launchd agent of the user on macOS, so it can be
+// granted Bluetooth access, and a Task Scheduler task running as SYSTEM on Windows. A Windows
+// service proper has to answer the service control manager, which blueplug doesn't, so a task
+// stands in for one.
+#[derive(clap::Args, Debug)]
+pub struct ServiceArgs {
+    #[command(subcommand)]
+    command: ServiceCommand,
+}
+
+#[derive(Subcommand, Debug)]
+enum ServiceCommand {
+    /// Install and start the service, e.g. `blueplug service install -- -c /etc/blueplug.toml`
+    Install {
+        /// Arguments to run blueplug with; paths should be absolute
+        #[arg(last = true)]
+        args: Vec<String>,
+    },
+    /// Stop and remove the service
+    Uninstall,
+}
+
+pub fn run(args: ServiceArgs) -> Result<()> {
+    let exe = std::env::current_exe().wrap_err("finding the blueplug executable")?;
+    match args.command {
+        ServiceCommand::Install { args } => install(&exe, &args),
+        ServiceCommand::Uninstall => uninstall(),
+    }
+}
+
+fn install(exe: &Path, args: &[String]) -> Result<()> {
+    if cfg!(target_os = "linux") {
+        write(Path::new(UNIT_PATH), &unit(exe, args))?;
+        command("systemctl", &["daemon-reload"])?;
+        command("systemctl", &["enable", "--now", NAME])?;
+        println!("installed {}, see `journalctl -u {}`", UNIT_PATH, NAME);
+    } else if cfg!(target_os = "macos") {
+        let path = plist_path()?;
+        write(&path, &plist(exe, args))?;
+        command("launchctl", &["load", "-w", &path.to_string_lossy()])?;
+        println!(
+            "installed {}, see `log stream --process logger`",
+            path.display()
+        );
+    } else if cfg!(windows) {
+        command(
+            "schtasks",
+            &[
+                "/Create",
+                "/F",
+                "/TN",
+                NAME,
+                "/SC",
+                "ONSTART",
+                "/RU",
+                "SYSTEM",
+                "/TR",
+                &task_command(exe, args),
+            ],
+        )?;
+        command("schtasks", &["/Run", "/TN", NAME])?;
+        println!("installed the {} task, see the Application event log", NAME);
+    } else {
+        return Err(eyre!(
+            "installing a service isn't supported on this platform"
+        ));
+    }
+    Ok(())
+}
+
+fn uninstall() -> Result<()> {
+    if cfg!(target_os = "linux") {
+        command("systemctl", &["disable", "--now", NAME])?;
+        remove(Path::new(UNIT_PATH))?;
+        command("systemctl", &["daemon-reload"])?;
+    } else if cfg!(target_os = "macos") {
+        let path = plist_path()?;
+        command("launchctl", &["unload", "-w", &path.to_string_lossy()])?;
+        remove(&path)?;
+    } else if cfg!(windows) {
+        // Ending fails if the task isn't running, which is fine.
+        let _ = command("schtasks", &["/End", "/TN", NAME]);
+        command("schtasks", &["/Delete", "/F", "/TN", NAME])?;
+    } else {
+        return Err(eyre!(
+            "installing a service isn't supported on this platform"
+        ));
+    }
+    println!("uninstalled {}", NAME);
+    Ok(())
+}
+
+fn plist_path() -> Result<PathBuf> {
+    let home = std::env::var_os("HOME").ok_or_else(|| eyre!("HOME isn't set"))?;
+    Ok(PathBuf::from(home)
+        .join("Library/LaunchAgents")
+        .join(format!("{}.plist", LAUNCHD_LABEL)))
+}
+
+fn write(path: &Path, contents: &str) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .wrap_err_with(|| format!("creating {}", parent.display()))?;
+    }
+    std::fs::write(path, contents).wrap_err_with(|| format!("writing {}", path.display()))
+}
+
+fn remove(path: &Path) -> Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            Err(e).wrap_err_with(|| format!("removing {}", path.display()))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn command(program: &str, args: &[&str]) -> Result<()> {
+    let status = Command::new(program)
+        .args(args)
+        .status()
+        .wrap_err_with(|| format!("running {}", program))?;
+    if !status.success() {
+        return Err(eyre!("{} {} failed: {}", program, args.join(" "), status));
+    }
+    Ok(())
+}
+
+// unit is a systemd unit running blueplug, which systemd restarts should its supervisor fail.
+fn unit(exe: &Path, args: &[String]) -> String {
+    let quote = |arg: &str| {
+        if arg.is_empty() || arg.contains([' ', '"', '\\', '\'', '$', '%']) {
+            let escaped = arg
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('$', "$$")
+                .replace('%', "%%");
+            format!("\"{}\"", escaped)
+        } else {
+            arg.to_string()
+        }
+    };
+    let command: Vec<_> = [exe.to_string_lossy().as_ref(), "--service"]
+        .into_iter()
+        .chain(args.iter().map(String::as_str))
+        .map(quote)
+        .collect();
+    format!(
+        "[Unit]\n\
+         Description=Bluetooth LE sensors to MQTT\n\
+         Wants=network-online.target\n\
+         After=network-online.target bluetooth.target\n\
+         \n\
+         [Service]\n\
+         ExecStart={}\n\
+         Restart=on-failure\n\
+         \n\
+         [Install]\n\
+         WantedBy=multi-user.target\n",
+        command.join(" ")
+    )
+}
+
+// plist is a launchd agent running blueplug at login, kept alive should its supervisor fail.
+fn plist(exe: &Path, args: &[String]) -> String {
+    let escape = |text: &str| {
+        text.replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+    };
+    let arguments: String = [exe.to_string_lossy().as_ref(), "--service"]
+        .into_iter()
+        .chain(args.iter().map(String::as_str))
+        .map(|arg| format!("        <string>{}</string>\n", escape(arg)))
+        .collect();
+    format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <!DOCTYPE plist PUBLIC \"-//Apple//DTD PLIST 1.0//EN\" \
+         \"http://www.apple.com/DTDs/PropertyList-1.0.dtd\">\n\
+         <plist version=\"1.0\">\n\
+         <dict>\n\
+         \x20   <key>Label</key>\n\
+         \x20   <string>{}</string>\n\
+         \x20   <key>ProgramArguments</key>\n\
+         \x20   <array>\n\
+         {}\
+         \x20   </array>\n\
+         \x20   <key>RunAtLoad</key>\n\
+         \x20   <true/>\n\
+         \x20   <key>KeepAlive</key>\n\
+         \x20   <dict>\n\
+         \x20       <key>SuccessfulExit</key>\n\
+         \x20       <false/>\n\
+         \x20   </dict>\n\
+         </dict>\n\
+         </plist>\n",
+        LAUNCHD_LABEL, arguments
+    )
+}
+
+// task_command is the command line of the Task Scheduler task, each argument quoted as Windows
+// programs split them.
+fn task_command(exe: &Path, args: &[String]) -> String {
+    [exe.to_string_lossy().as_ref(), "--service"]
+        .into_iter()
+        .chain(args.iter().map(String::as_str))
+        .map(|arg| {
+            if arg.is_empty() || arg.contains([' ', '\t', '"']) {
+                format!("\"{}\"", arg.replace('"', "\\\""))
+            } else {
+                arg.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// is_supervised reports whether this blueplug was started by supervise.
+pub fn is_supervised() -> bool {
+    std::env::var_os(CHILD_ENV).is_some()
+}
+
+// supervise runs blueplug again, with the same arguments, sending what it prints to the platform's
+// log: unified logging on macOS, through logger, and the Application event log on Windows, where
+// it runs without a console. On Linux the journal already collects what a unit prints. blueplug
+// is started again RESTART_DELAY after failing, and supervise returns once it exits cleanly.
+pub fn supervise() -> Result<()> {
+    let exe = std::env::current_exe().wrap_err("finding the blueplug executable")?;
+    loop {
+        let mut command = Command::new(&exe);
+        command
+            .args(std::env::args_os().skip(1))
+            .env(CHILD_ENV, "1")
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        #[cfg(windows)]
+        {
+            use std::os::windows::process::CommandExt;
+            command.creation_flags(CREATE_NO_WINDOW);
+        }
+        let mut child = command.spawn().wrap_err("starting blueplug")?;
+        let outputs: [(Option<Box<dyn Read + Send>>, bool); 2] = [
+            (child.stdout.take().map(|o| Box::new(o) as _), false),
+            (child.stderr.take().map(|o| Box::new(o) as _), true),
+        ];
+        let forwarders: Vec<_> = outputs
+            .into_iter()
+            .filter_map(|(output, error)| {
+                let output = output?;
+                Some(thread::spawn(move || {
+                    for line in BufReader::new(output).lines().map_while(|line| line.ok()) {
+                        log(&line, error);
+                    }
+                }))
+            })
+            .collect();
+        let status = child.wait()?;
+        for forwarder in forwarders {
+            let _ = forwarder.join();
+        }
+        if status.success() {
+            return Ok(());
+        }
+        log(
+            &format!("blueplug {}, restarting in {:?}", status, RESTART_DELAY),
+            true,
+        );
+        thread::sleep(RESTART_DELAY);
+    }
+}
+
+// log sends a line to the platform's log, or prints it where that's what the service manager
+// collects, or logging failed.
+fn log(line: &str, error: bool) {
+    let logged = if cfg!(target_os = "macos") {
+        let priority = if error { "user.error" } else { "user.notice" };
+        Command::new("logger")
+            .args(["-t", NAME, "-p", priority, line])
+            .status()
+            .is_ok_and(|status| status.success())
+    } else if cfg!(windows) {
+        let mut command = Command::new("eventcreate");
+        command.args([
+            "/L",
+            "APPLICATION",
+            "/SO",
+            NAME,
+            "/ID",
+            "1",
+            "/T",
+            if error { "ERROR" } else { "INFORMATION" },
+            "/D",
+            line,
+        ]);
+        #[cfg(windows)]
+        {
+            use std::os::windows::process::CommandExt;
+            command.creation_flags(CREATE_NO_WINDOW);
+        }
+        command
+            .stdout(Stdio::null())
+            .status()
+            .is_ok_and(|status| status.success())
+    } else {
+        false
+    };
+    if !logged {
+        if error {
+            eprintln!("{}", line);
+        } else {
+            println!("{}", line);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::service::{plist, task_command, unit};
+
+    #[test]
+    fn test_service_definitions() {
+        let exe = Path::new("/usr/local/bin/blueplug");
+        let args = ["-c".to_string(), "/etc/blue plug/config.toml".to_string()];
+        assert!(unit(exe, &args).contains(
+            "ExecStart=/usr/local/bin/blueplug --service -c \"/etc/blue plug/config.toml\"\n"
+        ));
+        assert!(
+            plist(exe, &["--listen".to_string(), "0.0.0.0:8080".to_string()]).contains(
+                "        <string>/usr/local/bin/blueplug</string>\n\
+             \x20       <string>--service</string>\n\
+             \x20       <string>--listen</string>\n\
+             \x20       <string>0.0.0.0:8080</string>\n"
+            )
+        );
+        assert_eq!(
+            task_command(Path::new("C:\\Program Files\\blueplug.exe"), &args),
+            "\"C:\\Program Files\\blueplug.exe\" --service -c \"/etc/blue plug/config.toml\""
+        );
+    }
+}
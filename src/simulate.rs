@@ -0,0 +1,210 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use async_stream::stream;
+use futures_core::stream::Stream;
+use tokio::time;
+use uuid::Uuid;
+
+use crate::error::BlueplugError;
+use crate::{DeviceEvent, DeviceId};
+
+const RUUVI_MANUFACTURER_ID: u16 = 0x0499;
+const BTHOME_UUID: Uuid = Uuid::from_u128(0x0000fcd2_0000_1000_8000_00805f9b34fb);
+
+// How often due advertisements are sent, in bursts, since timers can't fire for each one at high
+// rates.
+const TICK: Duration = Duration::from_millis(10);
+
+// SimulateArgs configures `blueplug simulate`, which runs blueplug with the sinks of a
+// configuration file on advertisements of made up devices instead of the adapter and proxies, to
+// benchmark sinks and brokers without hardware.
+#[derive(clap::Args, Debug)]
+pub struct SimulateArgs {
+    /// TOML configuration file with the brokers and sinks to deliver to
+    #[arg(short = 'c', long)]
+    pub config: PathBuf,
+    /// Number of devices, alternately Ruuvi tags and BTHome sensors
+    #[arg(long, default_value_t = 100)]
+    pub devices: usize,
+    /// Advertisements of each device, per second, minute or hour, e.g. 5/s
+    #[arg(long, default_value = "1/s")]
+    pub rate: Rate,
+}
+
+// Rate is a number of events per second.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rate(pub f64);
+
+impl FromStr for Rate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (count, unit) = s.split_once('/').unwrap_or((s, "s"));
+        let seconds = match unit {
+            "s" => 1.0,
+            "m" => 60.0,
+            "h" => 3600.0,
+            _ => return Err(format!("unknown unit {:?}, expected s, m or h", unit)),
+        };
+        match count.parse::<f64>() {
+            Ok(count) if count > 0.0 && count.is_finite() => Ok(Rate(count / seconds)),
+            _ => Err(format!("{:?} isn't a positive number", count)),
+        }
+    }
+}
+
+// Simulated is a made up device, whose readings wander a little with every advertisement.
+struct Simulated {
+    device_id: DeviceId,
+    bthome: bool,
+    temperature: f64,
+    humidity: f64,
+    battery: f64,
+    sequence: u16,
+}
+
+impl Simulated {
+    fn new(index: usize) -> Self {
+        let bthome = index % 2 == 1;
+        let address = format!("F2:00:00:00:{:02X}:{:02X}", index >> 8 & 0xff, index & 0xff);
+        let device_name = if bthome {
+            format!("SIM_BTHOME_{:04}", index)
+        } else {
+            format!("SIM_RUUVI_{:04}", index)
+        };
+        Simulated {
+            device_id: DeviceId {
+                id: format!("sim/dev_{}", address.replace(':', "_")),
+                device_name,
+                address,
+                random_address: None,
+            },
+            bthome,
+            temperature: 15.0 + fastrand::f64() * 10.0,
+            humidity: 30.0 + fastrand::f64() * 40.0,
+            battery: 50.0 + fastrand::f64() * 50.0,
+            sequence: fastrand::u16(..),
+        }
+    }
+
+    fn advertisement(&mut self) -> DeviceEvent {
+        self.temperature = (self.temperature + fastrand::f64() * 0.2 - 0.1).clamp(-40.0, 85.0);
+        self.humidity = (self.humidity + fastrand::f64() * 0.4 - 0.2).clamp(0.0, 100.0);
+        self.sequence = self.sequence.wrapping_add(1);
+        let device_id = self.device_id.clone();
+        if self.bthome {
+            let service_data = HashMap::from([(BTHOME_UUID, self.bthome_data())]);
+            DeviceEvent::ServiceDataAdvertisement {
+                device_id,
+                service_data,
+                rssi: None,
+            }
+        } else {
+            let manufacturer_data = HashMap::from([(RUUVI_MANUFACTURER_ID, self.ruuvi_data())]);
+            DeviceEvent::ManufacturerDataAdvertisement {
+                device_id,
+                manufacturer_data,
+                rssi: None,
+            }
+        }
+    }
+
+    // bthome_data is a BTHome v2 payload, unencrypted, of a packet id, battery, temperature and
+    // humidity.
+    fn bthome_data(&self) -> Vec<u8> {
+        let mut data = vec![
+            0x40,
+            0x00,
+            self.sequence as u8,
+            0x01,
+            self.battery as u8,
+            0x02,
+        ];
+        data.extend(((self.temperature * 100.0).round() as i16).to_le_bytes());
+        data.push(0x03);
+        data.extend(((self.humidity * 100.0).round() as u16).to_le_bytes());
+        data
+    }
+
+    // ruuvi_data is a Ruuvi RAWv2 (data format 5) payload, with the device standing still.
+    fn ruuvi_data(&self) -> Vec<u8> {
+        let mut data = vec![0x05];
+        data.extend(((self.temperature / 0.005).round() as i16).to_be_bytes());
+        data.extend(((self.humidity / 0.0025).round() as u16).to_be_bytes());
+        // 1013.25 hPa, less 50000 Pa.
+        data.extend(51325u16.to_be_bytes());
+        // 1 g down.
+        data.extend([0, 0, 0, 0, 0x03, 0xe8]);
+        // 3 V, less 1600 mV, in the top 11 bits, and +4 dBm of transmit power.
+        data.extend(((1400u16 << 5) | 22).to_be_bytes());
+        data.push(0);
+        data.extend(self.sequence.to_be_bytes());
+        let address = self.device_id.address.split(':');
+        data.extend(address.map(|b| u8::from_str_radix(b, 16).unwrap_or_default()));
+        data
+    }
+}
+
+// simulate_stream sends the advertisements of a number of made up devices, each rate times a
+// second, taking turns.
+pub fn simulate_stream(
+    devices: usize,
+    rate: Rate,
+) -> impl Stream<Item = Result<DeviceEvent, BlueplugError>> {
+    let mut simulated: Vec<Simulated> = (0..devices).map(Simulated::new).collect();
+    let per_second = rate.0 * devices as f64;
+    stream! {
+        if simulated.is_empty() {
+            return;
+        }
+        let started = Instant::now();
+        let mut sent: u64 = 0;
+        let mut next = 0;
+        let mut interval = time::interval(TICK);
+        loop {
+            interval.tick().await;
+            let due = (started.elapsed().as_secs_f64() * per_second) as u64;
+            while sent < due {
+                yield Ok(simulated[next].advertisement());
+                next = (next + 1) % simulated.len();
+                sent += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::pin_mut;
+    use futures_util::stream::StreamExt;
+
+    use crate::simulate::{simulate_stream, Rate};
+    use crate::{device_reading_stream, Measurement};
+
+    #[tokio::test]
+    async fn test_simulate() {
+        assert_eq!("5/s".parse(), Ok(Rate(5.0)));
+        assert_eq!("120/m".parse(), Ok(Rate(2.0)));
+        assert_eq!("2".parse(), Ok(Rate(2.0)));
+        assert!("0/s".parse::<Rate>().is_err());
+        assert!("5/d".parse::<Rate>().is_err());
+
+        let events = simulate_stream(2, Rate(1000.0));
+        let readings = device_reading_stream(events, Default::default(), Default::default());
+        pin_mut!(readings);
+        let mut names = Vec::new();
+        // Three from the Ruuvi tag, then three from the BTHome sensor.
+        for _ in 0..6 {
+            let reading = readings.next().await.unwrap();
+            if let Measurement::Temperature(temperature) = reading.measurement {
+                assert!((15.0..25.5).contains(&temperature));
+            }
+            names.push(reading.device_id.device_name.clone());
+        }
+        assert_eq!(names[0], "SIM_RUUVI_0000");
+        assert_eq!(names[5], "SIM_BTHOME_0001");
+    }
+}
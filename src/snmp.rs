@@ -0,0 +1,416 @@
+use std::sync::{Arc, Mutex};
+
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use tokio::net::UdpSocket;
+
+use crate::clock::unix_timestamp;
+use crate::config::SnmpConfig;
+use crate::latest::LatestReadings;
+use crate::names::TOPIC_NAMES;
+use crate::supervisor::SUPERVISOR;
+
+// BER tags of the types blueplug's MIB and SNMP messages use.
+const INTEGER: u8 = 0x02;
+const OCTET_STRING: u8 = 0x04;
+const NULL: u8 = 0x05;
+const OBJECT_IDENTIFIER: u8 = 0x06;
+const SEQUENCE: u8 = 0x30;
+const GAUGE32: u8 = 0x42;
+const NO_SUCH_OBJECT: u8 = 0x80;
+const END_OF_MIB_VIEW: u8 = 0x82;
+
+const GET_REQUEST: u8 = 0xa0;
+const GET_NEXT_REQUEST: u8 = 0xa1;
+const RESPONSE: u8 = 0xa2;
+const GET_BULK_REQUEST: u8 = 0xa5;
+
+const VERSION_1: i64 = 0;
+const VERSION_2C: i64 = 1;
+
+// SNMPv1's error for an OID with no value, which SNMPv2c reports per variable instead.
+const NO_SUCH_NAME: i64 = 2;
+
+// Repetitions of a GetBulk request beyond this are ignored, keeping the response in one datagram.
+const MAX_REPETITIONS: i64 = 32;
+
+// Value is a value in blueplug's MIB.
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Integer(i64),
+    String(String),
+    Gauge(u32),
+}
+
+// spawn_snmp answers SNMPv1 and v2c Get, GetNext and GetBulk requests for the latest readings,
+// read-only, with the objects of mib/BLUEPLUG-MIB.txt under config.oid. Rows of the reading
+// table are numbered in order of device and kind, so a row's index changes as devices appear;
+// the device and kind columns say which reading a row is.
+pub async fn spawn_snmp(config: &SnmpConfig, latest: Arc<Mutex<LatestReadings>>) -> Result<()> {
+    let base = parse_oid(&config.oid)?;
+    let socket = UdpSocket::bind(config.listen)
+        .await
+        .wrap_err_with(|| format!("listening on {}", config.listen))?;
+    println!("SNMP agent listening on {}", config.listen);
+    let community = config.community.clone();
+    SUPERVISOR.spawn_once("snmp", async move {
+        let mut buf = vec![0; 65535];
+        loop {
+            let (len, peer) = match socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(e) => {
+                    println!("snmp: receiving failed: {:?}", e);
+                    continue;
+                }
+            };
+            let objects = objects(&base, &latest.lock().unwrap());
+            // Requests that can't be parsed, or with the wrong community, go unanswered, as
+            // agents usually do.
+            if let Ok(Some(response)) = respond(&buf[..len], &community, &objects) {
+                if let Err(e) = socket.send_to(&response, peer).await {
+                    println!("snmp: responding to {} failed: {:?}", peer, e);
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+// objects lists the MIB's objects with the latest readings, ordered by OID.
+fn objects(base: &[u32], latest: &LatestReadings) -> Vec<(Vec<u32>, Value)> {
+    let readings = latest.readings();
+    let now = unix_timestamp();
+    let oid = |suffix: &[u32]| [base, suffix].concat();
+    let mut objects = Vec::new();
+    // blueplugReadingTable.blueplugReadingEntry.<column>.<row>
+    for column in 1..=8 {
+        for (i, latest) in readings.iter().enumerate() {
+            let reading = &latest.reading;
+            let row = i as u32 + 1;
+            let value = match column {
+                1 => Value::Integer(row as i64),
+                2 => Value::String(TOPIC_NAMES.topic_name(&reading.device_id)),
+                3 => Value::String(reading.measurement.kind().to_string()),
+                4 => Value::String(reading.measurement.value().to_string()),
+                5 => Value::Integer((reading.measurement.value() * 100.0).round() as i64),
+                6 => Value::String(reading.measurement.unit().to_string()),
+                7 => Value::Gauge(now.saturating_sub(latest.timestamp) as u32),
+                // TruthValue, true(1) or false(2).
+                _ => Value::Integer(if latest.is_stale { 1 } else { 2 }),
+            };
+            objects.push((oid(&[1, 1, column, row]), value));
+        }
+    }
+    // blueplugReadingCount.0
+    objects.push((oid(&[2, 0]), Value::Gauge(readings.len() as u32)));
+    objects
+}
+
+// respond answers request, or returns None if it isn't for community.
+fn respond(
+    request: &[u8],
+    community: &str,
+    objects: &[(Vec<u32>, Value)],
+) -> Result<Option<Vec<u8>>> {
+    let mut message = Reader::new(request).expect(SEQUENCE)?;
+    let version = message.integer()?;
+    if version != VERSION_1 && version != VERSION_2C {
+        return Ok(None);
+    }
+    if message.expect(OCTET_STRING)?.data != community.as_bytes() {
+        return Ok(None);
+    }
+    let (pdu_type, mut pdu) = message.tlv()?;
+    let request_id = pdu.integer()?;
+    let (non_repeaters, max_repetitions) = (pdu.integer()?, pdu.integer()?);
+    let mut list = pdu.expect(SEQUENCE)?;
+    let mut oids = Vec::new();
+    while !list.data.is_empty() {
+        let mut binding = list.expect(SEQUENCE)?;
+        oids.push(decode_oid(binding.expect(OBJECT_IDENTIFIER)?.data)?);
+    }
+
+    let get = |oid: &[u32]| objects.iter().find(|(o, _)| o == oid);
+    let next = |oid: &[u32]| objects.iter().find(|(o, _)| o.as_slice() > oid);
+    let mut bindings: Vec<(Vec<u32>, Option<&Value>, u8)> = Vec::new();
+    let mut error = None;
+    match pdu_type {
+        GET_REQUEST => {
+            for (i, oid) in oids.iter().enumerate() {
+                match get(oid) {
+                    Some((_, value)) => bindings.push((oid.clone(), Some(value), 0)),
+                    None => {
+                        error.get_or_insert(i);
+                        bindings.push((oid.clone(), None, NO_SUCH_OBJECT));
+                    }
+                }
+            }
+        }
+        GET_NEXT_REQUEST => {
+            for (i, oid) in oids.iter().enumerate() {
+                match next(oid) {
+                    Some((next, value)) => bindings.push((next.clone(), Some(value), 0)),
+                    None => {
+                        error.get_or_insert(i);
+                        bindings.push((oid.clone(), None, END_OF_MIB_VIEW));
+                    }
+                }
+            }
+        }
+        GET_BULK_REQUEST if version == VERSION_2C => {
+            let non_repeaters = (non_repeaters.max(0) as usize).min(oids.len());
+            for oid in &oids[..non_repeaters] {
+                match next(oid) {
+                    Some((next, value)) => bindings.push((next.clone(), Some(value), 0)),
+                    None => bindings.push((oid.clone(), None, END_OF_MIB_VIEW)),
+                }
+            }
+            let mut repeaters: Vec<Vec<u32>> = oids[non_repeaters..].to_vec();
+            for _ in 0..max_repetitions.clamp(0, MAX_REPETITIONS) {
+                for oid in &mut repeaters {
+                    match next(oid) {
+                        Some((next, value)) => {
+                            bindings.push((next.clone(), Some(value), 0));
+                            *oid = next.clone();
+                        }
+                        None => bindings.push((oid.clone(), None, END_OF_MIB_VIEW)),
+                    }
+                }
+            }
+        }
+        _ => return Err(eyre!("unsupported PDU type {:#x}", pdu_type)),
+    }
+
+    let mut list = Vec::new();
+    for (oid, value, exception) in &bindings {
+        let mut binding = encode_oid(oid);
+        binding.extend(match (value, version) {
+            (Some(value), _) => encode_value(value),
+            // SNMPv1 has no exceptions; the error status says which variable had no value.
+            (None, VERSION_1) => tlv(NULL, &[]),
+            (None, _) => tlv(*exception, &[]),
+        });
+        list.extend(tlv(SEQUENCE, &binding));
+    }
+    let (status, index) = match (error, version) {
+        (Some(i), VERSION_1) => (NO_SUCH_NAME, i as i64 + 1),
+        _ => (0, 0),
+    };
+    let mut pdu = encode_integer(request_id);
+    pdu.extend(encode_integer(status));
+    pdu.extend(encode_integer(index));
+    pdu.extend(tlv(SEQUENCE, &list));
+    let mut message = encode_integer(version);
+    message.extend(tlv(OCTET_STRING, community.as_bytes()));
+    message.extend(tlv(RESPONSE, &pdu));
+    Ok(Some(tlv(SEQUENCE, &message)))
+}
+
+// Reader reads BER encoded values one after another.
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Reader { data }
+    }
+
+    // tlv reads the next value, returning its tag and a reader of its contents.
+    fn tlv(&mut self) -> Result<(u8, Reader<'a>)> {
+        let [tag, first, rest @ ..] = self.data else {
+            return Err(eyre!("truncated"));
+        };
+        let (len, rest) = if first & 0x80 == 0 {
+            (*first as usize, rest)
+        } else {
+            let octets = (first & 0x7f) as usize;
+            if octets == 0 || octets > 4 || rest.len() < octets {
+                return Err(eyre!("bad length"));
+            }
+            let len = rest[..octets]
+                .iter()
+                .fold(0, |len, b| (len << 8) | *b as usize);
+            (len, &rest[octets..])
+        };
+        if rest.len() < len {
+            return Err(eyre!("truncated"));
+        }
+        self.data = &rest[len..];
+        Ok((*tag, Reader::new(&rest[..len])))
+    }
+
+    fn expect(&mut self, tag: u8) -> Result<Reader<'a>> {
+        match self.tlv()? {
+            (t, reader) if t == tag => Ok(reader),
+            (t, _) => Err(eyre!("expected tag {:#x}, found {:#x}", tag, t)),
+        }
+    }
+
+    fn integer(&mut self) -> Result<i64> {
+        let data = self.expect(INTEGER)?.data;
+        if data.is_empty() || data.len() > 8 {
+            return Err(eyre!("bad integer"));
+        }
+        // Sign-extend from the first octet.
+        let first = data[0] as i8 as i64;
+        Ok(data[1..].iter().fold(first, |n, b| (n << 8) | *b as i64))
+    }
+}
+
+fn tlv(tag: u8, value: &[u8]) -> Vec<u8> {
+    let mut encoded = vec![tag];
+    let len = value.len();
+    if len < 0x80 {
+        encoded.push(len as u8);
+    } else {
+        let octets: Vec<u8> = len
+            .to_be_bytes()
+            .into_iter()
+            .skip_while(|b| *b == 0)
+            .collect();
+        encoded.push(0x80 | octets.len() as u8);
+        encoded.extend(octets);
+    }
+    encoded.extend_from_slice(value);
+    encoded
+}
+
+// encode_integer encodes n in the fewest octets of two's complement.
+fn encode_integer(n: i64) -> Vec<u8> {
+    let bytes = n.to_be_bytes();
+    let mut start = 0;
+    while start < 7 {
+        let redundant = (bytes[start] == 0x00 && bytes[start + 1] & 0x80 == 0)
+            || (bytes[start] == 0xff && bytes[start + 1] & 0x80 != 0);
+        if !redundant {
+            break;
+        }
+        start += 1;
+    }
+    tlv(INTEGER, &bytes[start..])
+}
+
+fn encode_value(value: &Value) -> Vec<u8> {
+    match value {
+        Value::Integer(n) => encode_integer(*n),
+        Value::String(s) => tlv(OCTET_STRING, s.as_bytes()),
+        Value::Gauge(n) => {
+            // Unsigned, so a leading zero octet keeps the top bit from reading as a sign.
+            let mut encoded = encode_integer(*n as i64);
+            encoded[0] = GAUGE32;
+            encoded
+        }
+    }
+}
+
+fn encode_oid(oid: &[u32]) -> Vec<u8> {
+    let mut data = Vec::new();
+    if let [first, second, rest @ ..] = oid {
+        data.push((first * 40 + second) as u8);
+        for arc in rest {
+            let mut octets = vec![(*arc & 0x7f) as u8];
+            let mut arc = *arc >> 7;
+            while arc > 0 {
+                octets.push((arc & 0x7f) as u8 | 0x80);
+                arc >>= 7;
+            }
+            data.extend(octets.into_iter().rev());
+        }
+    }
+    tlv(OBJECT_IDENTIFIER, &data)
+}
+
+fn decode_oid(data: &[u8]) -> Result<Vec<u32>> {
+    let Some((first, rest)) = data.split_first() else {
+        return Ok(Vec::new());
+    };
+    let mut oid = vec![(*first / 40) as u32, (*first % 40) as u32];
+    let mut arc: u32 = 0;
+    for b in rest {
+        arc = arc
+            .checked_mul(128)
+            .ok_or_else(|| eyre!("OID arc too large"))?
+            | (b & 0x7f) as u32;
+        if b & 0x80 == 0 {
+            oid.push(arc);
+            arc = 0;
+        }
+    }
+    Ok(oid)
+}
+
+// parse_oid parses a dotted OID such as "1.3.6.1.4.1".
+pub fn parse_oid(oid: &str) -> Result<Vec<u32>> {
+    let arcs = oid
+        .trim_start_matches('.')
+        .split('.')
+        .map(|arc| arc.parse())
+        .collect::<Result<Vec<u32>, _>>()
+        .wrap_err_with(|| format!("invalid OID {:?}", oid))?;
+    if arcs.len() < 2 || arcs[0] > 2 || arcs[1] >= 40 {
+        return Err(eyre!("invalid OID {:?}", oid));
+    }
+    Ok(arcs)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::latest::LatestReadings;
+    use crate::snmp::{
+        encode_integer, encode_oid, objects, respond, tlv, Reader, Value, GET_NEXT_REQUEST,
+        OBJECT_IDENTIFIER, OCTET_STRING, SEQUENCE,
+    };
+    use crate::{DeviceId, DeviceReading, Measurement};
+
+    #[test]
+    fn test_snmp() {
+        let mut latest = LatestReadings::default();
+        latest.update(Arc::new(DeviceReading {
+            device_id: DeviceId {
+                id: "hci0/snmp".to_string(),
+                device_name: "ATC_SNMP".to_string(),
+                address: String::new(),
+                random_address: None,
+            },
+            measurement: Measurement::Temperature(21.57),
+            advertisement: None,
+            quality: Vec::new(),
+        }));
+        let base = [1, 3, 6, 1, 4, 1, 8072, 9999, 9999, 1];
+        let objects = objects(&base, &latest);
+
+        // GetNext of the table's value column finds the first row.
+        let column = [&base[..], &[1, 1, 5]].concat();
+        let mut binding = encode_oid(&column);
+        binding.extend(tlv(0x05, &[]));
+        let mut pdu = encode_integer(1234);
+        pdu.extend(encode_integer(0));
+        pdu.extend(encode_integer(0));
+        pdu.extend(tlv(SEQUENCE, &tlv(SEQUENCE, &binding)));
+        let mut message = encode_integer(1);
+        message.extend(tlv(OCTET_STRING, b"public"));
+        message.extend(tlv(GET_NEXT_REQUEST, &pdu));
+        let request = tlv(SEQUENCE, &message);
+
+        assert_eq!(respond(&request, "private", &objects).unwrap(), None);
+        let response = respond(&request, "public", &objects).unwrap().unwrap();
+        let mut message = Reader::new(&response).expect(SEQUENCE).unwrap();
+        assert_eq!(message.integer().unwrap(), 1);
+        message.expect(OCTET_STRING).unwrap();
+        let (_, mut pdu) = message.tlv().unwrap();
+        assert_eq!(pdu.integer().unwrap(), 1234);
+        assert_eq!(pdu.integer().unwrap(), 0);
+        pdu.integer().unwrap();
+        let mut binding = pdu.expect(SEQUENCE).unwrap().expect(SEQUENCE).unwrap();
+        let oid = binding.expect(OBJECT_IDENTIFIER).unwrap().data;
+        assert_eq!(oid, &encode_oid(&[&column[..], &[1]].concat())[2..]);
+        assert_eq!(binding.integer().unwrap(), 2157);
+
+        let count = objects.last().unwrap();
+        assert_eq!(count.1, Value::Gauge(1));
+        assert_eq!(encode_integer(-129), [0x02, 0x02, 0xff, 0x7f]);
+        assert_eq!(encode_integer(128), [0x02, 0x02, 0x00, 0x80]);
+    }
+}
@@ -0,0 +1,140 @@
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::Path;
+use std::sync::Arc;
+
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::clock::unix_timestamp;
+use crate::config::SocketConfig;
+use crate::envelope::ndjson;
+use crate::supervisor::SUPERVISOR;
+use crate::DeviceReading;
+
+// The name routes refer to the socket by.
+pub const SINK_NAME: &str = "socket";
+
+// bind listens on path, replacing the socket of an earlier run that wasn't cleaned up, but not
+// anything else that is there.
+fn bind(path: &Path, mode: u32) -> Result<UnixListener> {
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(eyre!("{} exists and isn't a socket", path.display()));
+        }
+        std::fs::remove_file(path)?;
+    }
+    let listener =
+        UnixListener::bind(path).wrap_err_with(|| format!("listening on {}", path.display()))?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    Ok(listener)
+}
+
+// spawn_socket streams readings as NDJSON to every client connected to a Unix domain socket, for
+// consumers on the same host that would rather not run a broker. Clients only receive; they get
+// the readings decoded after they connect, and one that can't keep up misses readings rather
+// than holding up the others.
+pub fn spawn_socket(
+    config: &SocketConfig,
+    readings: broadcast::Receiver<Arc<DeviceReading>>,
+) -> Result<()> {
+    let listener = bind(&config.path, config.mode)?;
+    println!("streaming readings on {}", config.path.display());
+    SUPERVISOR.spawn_once("socket", async move {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    println!("socket: accepting failed: {:?}", e);
+                    continue;
+                }
+            };
+            let readings = readings.resubscribe();
+            // A connection that fails only ends itself.
+            SUPERVISOR.child().spawn_once("socket client", async move {
+                if let Err(e) = serve(stream, readings).await {
+                    println!("socket: client: {:?}", e);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+// serve writes readings to one client until it disconnects.
+async fn serve(
+    mut stream: UnixStream,
+    mut readings: broadcast::Receiver<Arc<DeviceReading>>,
+) -> Result<()> {
+    loop {
+        let reading = match readings.recv().await {
+            Ok(reading) => reading,
+            Err(RecvError::Lagged(skipped)) => {
+                println!(
+                    "socket: client falling behind, dropped {} readings",
+                    skipped
+                );
+                continue;
+            }
+            Err(RecvError::Closed) => return Ok(()),
+        };
+        match stream.write_all(&ndjson(&reading, unix_timestamp())?).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::io::{AsyncBufReadExt, BufReader};
+    use tokio::net::UnixStream;
+    use tokio::sync::broadcast;
+
+    use crate::config::SocketConfig;
+    use crate::socket::spawn_socket;
+    use crate::{DeviceId, DeviceReading, Measurement};
+
+    #[tokio::test]
+    async fn test_socket() {
+        let path = std::env::temp_dir().join(format!("blueplug-{}.sock", std::process::id()));
+        let (sender, receiver) = broadcast::channel(16);
+        let config = SocketConfig {
+            path: path.clone(),
+            mode: 0o600,
+        };
+        spawn_socket(&config, receiver).unwrap();
+
+        let mut lines = BufReader::new(UnixStream::connect(&path).await.unwrap()).lines();
+        // Accepted by the time a reading is sent.
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        let reading = DeviceReading {
+            device_id: DeviceId {
+                id: "hci0/dev_A4_C1_38_00_00_0E".to_string(),
+                device_name: "ATC_SOCKET".to_string(),
+                address: String::new(),
+                random_address: None,
+            },
+            measurement: Measurement::Temperature(21.5),
+            advertisement: None,
+            quality: Vec::new(),
+        };
+        assert!(sender.send(Arc::new(reading)).is_ok());
+        let line = lines.next_line().await.unwrap().unwrap();
+        let json: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(json["device"]["name"], "ATC_SOCKET");
+        assert_eq!(json["measurement"]["value"], 21.5);
+
+        // A socket left behind is replaced, anything else isn't.
+        spawn_socket(&config, sender.subscribe()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        std::fs::write(&path, "").unwrap();
+        assert!(spawn_socket(&config, sender.subscribe()).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
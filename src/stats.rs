@@ -0,0 +1,185 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use color_eyre::eyre::{Result, WrapErr};
+use jiff::civil::Date;
+use jiff::tz::TimeZone;
+use jiff::Timestamp;
+use serde::Serialize;
+
+use crate::config::StatsConfig;
+use crate::mqtt::Message;
+use crate::names::TOPIC_NAMES;
+use crate::{DeviceId, DeviceReading};
+
+// Stats summarizes one kind of measurement from one device over a day.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Stats {
+    pub date: String,
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    pub count: u64,
+}
+
+#[derive(Clone)]
+struct Entry {
+    device_id: DeviceId,
+    stats: Stats,
+    sum: f64,
+}
+
+// DailyStats aggregates readings per device and kind, starting afresh at midnight in the
+// configured time zone, for dashboards that want day summaries without a time series database.
+#[derive(Clone)]
+pub struct DailyStats {
+    kinds: Vec<String>,
+    time_zone: TimeZone,
+    topic_prefix: String,
+    date: Option<Date>,
+    // Keyed by device id, then kind.
+    stats: BTreeMap<(String, String), Entry>,
+    changed: BTreeSet<(String, String)>,
+}
+
+impl DailyStats {
+    // new starts days in the stats' own time zone, falling back to time_zone and then the
+    // system's.
+    pub fn new(config: &StatsConfig, time_zone: Option<&str>) -> Result<Self> {
+        let time_zone = match config.time_zone.as_deref().or(time_zone) {
+            Some(name) => {
+                TimeZone::get(name).wrap_err_with(|| format!("stats time zone {}", name))?
+            }
+            None => TimeZone::system(),
+        };
+        Ok(DailyStats {
+            kinds: config.kinds.clone(),
+            time_zone,
+            topic_prefix: config.topic_prefix.clone(),
+            date: None,
+            stats: BTreeMap::new(),
+            changed: BTreeSet::new(),
+        })
+    }
+
+    pub fn update(&mut self, reading: &DeviceReading, now: Timestamp) {
+        let kind = reading.measurement.kind();
+        if !self.kinds.is_empty() && !self.kinds.iter().any(|k| k == kind) {
+            return;
+        }
+        let date = self.roll_over(now);
+        let value = reading.measurement.value();
+        let key = (reading.device_id.id.clone(), kind.to_string());
+        let entry = self.stats.entry(key.clone()).or_insert_with(|| Entry {
+            device_id: reading.device_id.clone(),
+            stats: Stats {
+                date: date.to_string(),
+                min: value,
+                max: value,
+                mean: value,
+                count: 0,
+            },
+            sum: 0.0,
+        });
+        let stats = &mut entry.stats;
+        stats.min = stats.min.min(value);
+        stats.max = stats.max.max(value);
+        stats.count += 1;
+        entry.sum += value;
+        stats.mean = entry.sum / stats.count as f64;
+        self.changed.insert(key);
+    }
+
+    // messages returns the stats that changed since the last call.
+    pub fn messages(&mut self) -> Vec<Message> {
+        let changed = std::mem::take(&mut self.changed);
+        changed
+            .into_iter()
+            .filter_map(|key| {
+                let entry = self.stats.get(&key)?;
+                let topic = format!(
+                    "{}/{}/stats/{}",
+                    self.topic_prefix,
+                    TOPIC_NAMES.topic_name(&entry.device_id),
+                    key.1
+                );
+                Some(Message {
+                    device_id: entry.device_id.clone(),
+                    topic,
+                    payload: serde_json::to_value(&entry.stats).ok()?,
+                })
+            })
+            .collect()
+    }
+
+    // roll_over forgets the previous day's stats once the date changes.
+    fn roll_over(&mut self, now: Timestamp) -> Date {
+        let date = now.to_zoned(self.time_zone.clone()).date();
+        if self.date != Some(date) {
+            self.date = Some(date);
+            self.stats.clear();
+            self.changed.clear();
+        }
+        date
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use jiff::Timestamp;
+
+    use crate::config::StatsConfig;
+    use crate::stats::{DailyStats, Stats};
+    use crate::{DeviceId, DeviceReading, Measurement};
+
+    #[test]
+    fn test_daily_stats() {
+        let mut stats = DailyStats::new(
+            &StatsConfig {
+                kinds: vec!["temperature".to_string()],
+                time_zone: None,
+                topic_prefix: "blueplug".to_string(),
+            },
+            Some("Europe/Berlin"),
+        )
+        .unwrap();
+        let reading = |measurement| DeviceReading {
+            device_id: DeviceId {
+                id: "hci0/a".to_string(),
+                device_name: "ATC_1".to_string(),
+                address: String::new(),
+                random_address: None,
+            },
+            measurement,
+            advertisement: None,
+            quality: Vec::new(),
+        };
+        // 22:30 and 23:30 in Berlin, then 00:30 the next day.
+        let evening: Timestamp = "2024-06-01T20:30:00Z".parse().unwrap();
+        let late: Timestamp = "2024-06-01T21:30:00Z".parse().unwrap();
+        let after_midnight: Timestamp = "2024-06-01T22:30:00Z".parse().unwrap();
+
+        stats.update(&reading(Measurement::Temperature(20.0)), evening);
+        stats.update(&reading(Measurement::Humidity(40.0)), evening);
+        stats.update(&reading(Measurement::Temperature(23.0)), late);
+        let messages = stats.messages();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].topic, "blueplug/ATC_1/stats/temperature");
+        assert_eq!(
+            messages[0].payload,
+            serde_json::to_value(Stats {
+                date: "2024-06-01".to_string(),
+                min: 20.0,
+                max: 23.0,
+                mean: 21.5,
+                count: 2,
+            })
+            .unwrap()
+        );
+        assert!(stats.messages().is_empty());
+
+        stats.update(&reading(Measurement::Temperature(18.0)), after_midnight);
+        let messages = stats.messages();
+        assert_eq!(messages[0].payload["date"], "2024-06-02");
+        assert_eq!(messages[0].payload["count"], 1);
+    }
+}
@@ -0,0 +1,227 @@
+use std::any::Any;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::mem;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use futures_util::future::join_all;
+use tokio::task::{self, JoinError, JoinHandle};
+use tokio::time;
+use tokio_util::sync::CancellationToken;
+
+use crate::mqtt::backoff;
+use crate::scope::{self, Scope};
+
+// A task restarted this many times within CRASH_LOOP_WINDOW is reported as crash looping.
+const CRASH_LOOP_RESTARTS: usize = 5;
+const CRASH_LOOP_WINDOW: Duration = Duration::from_secs(300);
+
+// SUPERVISOR runs blueplug's background tasks, so a panic in one is reported and the task
+// restarted, or blueplug shut down, rather than silently ending it, and all of them stop on
+// shutdown.
+pub static SUPERVISOR: LazyLock<Supervisor> = LazyLock::new(Supervisor::default);
+
+#[derive(Default, Clone)]
+pub struct Supervisor {
+    token: CancellationToken,
+    // Tasks that finish what they hold once shut down, which drain waits for.
+    graceful: Arc<Mutex<Vec<JoinHandle<()>>>>,
+    // The scope of the embedded Blueplug the tasks belong to, if any.
+    scope: Option<Arc<Scope>>,
+}
+
+impl Supervisor {
+    // child returns a supervisor whose tasks stop when it or this one shuts down.
+    pub fn child(&self) -> Supervisor {
+        Supervisor {
+            token: self.token.child_token(),
+            graceful: Arc::default(),
+            scope: self.scope.clone(),
+        }
+    }
+
+    // with_scope runs the tasks, and those of children, within scope.
+    pub fn with_scope(mut self, scope: Arc<Scope>) -> Supervisor {
+        self.scope = Some(scope);
+        self
+    }
+
+    pub fn shutdown(&self) {
+        self.token.cancel();
+    }
+
+    // drain shuts down, then waits up to timeout for the graceful tasks to finish.
+    pub async fn drain(&self, timeout: Duration) {
+        self.shutdown();
+        let tasks = mem::take(&mut *self.graceful.lock().unwrap());
+        if time::timeout(timeout, join_all(tasks)).await.is_err() {
+            println!("gave up waiting for tasks to finish after {:?}", timeout);
+        }
+    }
+
+    pub async fn cancelled(&self) {
+        self.token.cancelled().await
+    }
+
+    // spawn runs a task made by start until it returns or the supervisor shuts down. A task that
+    // panics is started again after a backoff, so start must be able to make it again from
+    // shared state.
+    pub fn spawn<F, Fut>(&self, name: impl Into<String>, mut start: F) -> JoinHandle<()>
+    where
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let name = name.into();
+        let token = self.token.clone();
+        let scope = self.scope.clone();
+        task::spawn(async move {
+            let mut restarts = VecDeque::new();
+            loop {
+                let task = task::spawn(scope::scope(scope.clone(), start()));
+                let Some(e) = supervise(&token, task).await else {
+                    return;
+                };
+                println!("{}: task panicked: {}", name, panic_message(e));
+                let now = Instant::now();
+                restarts.retain(|restart| now.duration_since(*restart) < CRASH_LOOP_WINDOW);
+                restarts.push_back(now);
+                if restarts.len() >= CRASH_LOOP_RESTARTS {
+                    println!(
+                        "{}: crash loop, restarted {} times in {:?}",
+                        name,
+                        restarts.len(),
+                        CRASH_LOOP_WINDOW
+                    );
+                }
+                let delay = backoff(restarts.len() as u32, fastrand::f64());
+                println!("{}: restarting in {:?}", name, delay);
+                tokio::select! {
+                    _ = token.cancelled() => return,
+                    _ = time::sleep(delay) => {}
+                }
+            }
+        })
+    }
+
+    // spawn_once runs a task that owns its state, such as a network connection, and so can't be
+    // restarted. A panic shuts the supervisor down, so that blueplug, whose task watches for that,
+    // exits for its service manager to start it again, rather than running on without the task.
+    // Tasks that only serve one client run under a child supervisor of their own.
+    pub fn spawn_once(
+        &self,
+        name: impl Into<String>,
+        future: impl Future<Output = ()> + Send + 'static,
+    ) -> JoinHandle<()> {
+        let name = name.into();
+        let token = self.token.clone();
+        let future = scope::scope(self.scope.clone(), future);
+        task::spawn(async move {
+            if let Some(e) = supervise(&token, task::spawn(future)).await {
+                println!(
+                    "{}: task panicked, shutting down: {}",
+                    name,
+                    panic_message(e)
+                );
+                token.cancel();
+            }
+        })
+    }
+
+    // spawn_graceful runs a task like spawn_once, except that it isn't aborted on shutdown. It
+    // must return soon after cancelled() does, having written out whatever it buffered.
+    pub fn spawn_graceful(
+        &self,
+        name: impl Into<String>,
+        future: impl Future<Output = ()> + Send + 'static,
+    ) {
+        let name = name.into();
+        let token = self.token.clone();
+        let handle = task::spawn(scope::scope(self.scope.clone(), future));
+        let task = task::spawn(async move {
+            if let Some(e) = handle.await.err().filter(|e| e.is_panic()) {
+                println!(
+                    "{}: task panicked, shutting down: {}",
+                    name,
+                    panic_message(e)
+                );
+                token.cancel();
+            }
+        });
+        self.graceful.lock().unwrap().push(task);
+    }
+}
+
+// supervise waits for a task, aborting it on shutdown, and returns why it failed if it panicked.
+async fn supervise(token: &CancellationToken, mut handle: JoinHandle<()>) -> Option<JoinError> {
+    tokio::select! {
+        _ = token.cancelled() => {
+            handle.abort();
+            None
+        }
+        result = &mut handle => result.err().filter(|e| e.is_panic()),
+    }
+}
+
+fn panic_message(e: JoinError) -> String {
+    let payload: Box<dyn Any + Send> = e.into_panic();
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+
+    use crate::supervisor::Supervisor;
+
+    #[tokio::test]
+    async fn test_supervisor() {
+        let supervisor = Supervisor::default();
+        let starts = Arc::new(AtomicU32::new(0));
+        let counter = starts.clone();
+        let handle = supervisor.spawn("flaky", move || {
+            let starts = counter.fetch_add(1, Ordering::SeqCst) + 1;
+            async move {
+                if starts < 2 {
+                    panic!("start {}", starts);
+                }
+            }
+        });
+        handle.await.unwrap();
+        assert_eq!(starts.load(Ordering::SeqCst), 2);
+
+        let handle = supervisor.spawn_once("forever", std::future::pending());
+        let finished = Arc::new(AtomicU32::new(0));
+        let counter = finished.clone();
+        let graceful = supervisor.clone();
+        supervisor.spawn_graceful("graceful", async move {
+            graceful.cancelled().await;
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        supervisor.drain(std::time::Duration::from_secs(1)).await;
+        handle.await.unwrap();
+        assert_eq!(finished.load(Ordering::SeqCst), 1);
+
+        // A task that can't be restarted shuts its supervisor down when it panics, and only that.
+        let supervisor = Supervisor::default();
+        let child = supervisor.child();
+        child
+            .spawn_once("client", async { panic!("client") })
+            .await
+            .unwrap();
+        child.cancelled().await;
+        assert!(!supervisor.token.is_cancelled());
+        supervisor
+            .spawn_once("once", async { panic!("once") })
+            .await
+            .unwrap();
+        supervisor.cancelled().await;
+    }
+}
@@ -0,0 +1,211 @@
+use serde_json::{Map, Value};
+
+use crate::mqtt::Message;
+use crate::names::TOPIC_NAMES;
+use crate::{DeviceId, DeviceReading, Measurement};
+
+pub const DEFAULT_TOPIC_PREFIX: &str = "home/OMG/BTtoMQTT";
+
+// TheengsAggregator mimics the Theengs/OpenMQTTGateway output, one JSON object per advertisement
+// on `home/OMG/BTtoMQTT/<mac>` using their key names, so blueplug can stand in for a gateway in
+// existing setups. Readings from one advertisement arrive back to back, so they are merged until a
+// reading from another device or advertisement arrives, or the caller flushes.
+#[derive(Default)]
+pub struct TheengsAggregator {
+    pending: Option<(DeviceId, Option<u64>, Map<String, Value>)>,
+}
+
+impl TheengsAggregator {
+    pub fn is_pending(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    // push adds a reading, returning the previous device's message if this one starts a new one.
+    pub fn push(&mut self, topic_prefix: &str, reading: &DeviceReading) -> Option<Message> {
+        let flushed = match &self.pending {
+            Some((device_id, advertisement, _))
+                if device_id.id != reading.device_id.id
+                    || (advertisement.is_some() && *advertisement != reading.advertisement) =>
+            {
+                self.flush(topic_prefix)
+            }
+            _ => None,
+        };
+
+        let (_, _, fields) = self.pending.get_or_insert_with(|| {
+            let mut fields = Map::new();
+            fields.insert("id".to_string(), mac(&reading.device_id).into());
+            fields.insert(
+                "name".to_string(),
+                reading.device_id.device_name.clone().into(),
+            );
+            (reading.device_id.clone(), reading.advertisement, fields)
+        });
+        match &reading.measurement {
+            Measurement::Temperature(c) => {
+                fields.insert("tempc".to_string(), (*c).into());
+                fields.insert("tempf".to_string(), (c * 1.8 + 32.0).into());
+            }
+            Measurement::Humidity(v) => {
+                fields.insert("hum".to_string(), (*v).into());
+            }
+            Measurement::Battery(v) => {
+                fields.insert("batt".to_string(), (*v).into());
+            }
+            Measurement::Voltage(v) => {
+                fields.insert("volt".to_string(), (*v).into());
+            }
+            Measurement::HeartRate(v) => {
+                fields.insert("heart_rate".to_string(), (*v).into());
+            }
+            Measurement::Power(v) => {
+                fields.insert("power".to_string(), (*v).into());
+            }
+            Measurement::Energy(v) => {
+                fields.insert("energy".to_string(), (*v).into());
+            }
+            Measurement::Current(v) => {
+                fields.insert("current".to_string(), (*v).into());
+            }
+            Measurement::VoltageAc(v) => {
+                fields.insert("volt".to_string(), (*v).into());
+            }
+            Measurement::Vibration(v) => {
+                fields.insert("vibration".to_string(), (*v).into());
+            }
+            Measurement::Tilt(v) => {
+                fields.insert("tilt".to_string(), (*v).into());
+            }
+            Measurement::Rotation(v) => {
+                fields.insert("rotation".to_string(), (*v).into());
+            }
+            Measurement::WindSpeed(v) => {
+                fields.insert("wind".to_string(), (*v).into());
+            }
+            Measurement::WindDirection(v) => {
+                fields.insert("winddir".to_string(), (*v).into());
+            }
+            Measurement::Rainfall(v) => {
+                fields.insert("rain".to_string(), (*v).into());
+            }
+            Measurement::UvIndex(v) => {
+                fields.insert("uv".to_string(), (*v).into());
+            }
+            Measurement::GasDetected(v) => {
+                fields.insert("gas".to_string(), (*v).into());
+            }
+            Measurement::SmokeDetected(v) => {
+                fields.insert("smoke".to_string(), (*v).into());
+            }
+            Measurement::CarbonMonoxideDetected(v) => {
+                fields.insert("co_detected".to_string(), (*v).into());
+            }
+            Measurement::CarbonMonoxide(v) => {
+                fields.insert("co".to_string(), (*v).into());
+            }
+            Measurement::Leak(v) => {
+                fields.insert("wet".to_string(), (*v).into());
+            }
+            Measurement::Other { kind, value, .. } => {
+                fields.insert(kind.clone(), (*value).into());
+            }
+        }
+        flushed
+    }
+
+    pub fn flush(&mut self, topic_prefix: &str) -> Option<Message> {
+        let (device_id, _, fields) = self.pending.take()?;
+        let topic = format!(
+            "{}/{}",
+            TOPIC_NAMES.expand_location(topic_prefix, &device_id),
+            mac(&device_id).replace(':', "")
+        );
+        Some(Message {
+            device_id,
+            topic,
+            payload: Value::Object(fields),
+        })
+    }
+}
+
+// Gateways identify devices by MAC; fall back to the platform id where no address is known.
+fn mac(device_id: &DeviceId) -> String {
+    if device_id.address.is_empty() {
+        device_id.id.clone()
+    } else {
+        device_id.address.to_uppercase()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::theengs::{TheengsAggregator, DEFAULT_TOPIC_PREFIX};
+    use crate::{DeviceId, DeviceReading, Measurement};
+
+    fn reading(address: &str, measurement: Measurement) -> DeviceReading {
+        DeviceReading {
+            device_id: DeviceId {
+                id: format!("hci0/dev_{}", address.replace(':', "_")),
+                device_name: "ATC_8F2C1A".to_string(),
+                address: address.to_string(),
+                random_address: None,
+            },
+            measurement,
+            advertisement: None,
+            quality: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_theengs_aggregation() {
+        let mut aggregator = TheengsAggregator::default();
+        let a = "A4:C1:38:8F:2C:1A";
+        assert!(aggregator
+            .push(
+                DEFAULT_TOPIC_PREFIX,
+                &reading(a, Measurement::Temperature(20.0))
+            )
+            .is_none());
+        assert!(aggregator
+            .push(
+                DEFAULT_TOPIC_PREFIX,
+                &reading(a, Measurement::Humidity(41.5))
+            )
+            .is_none());
+
+        let message = aggregator
+            .push(
+                DEFAULT_TOPIC_PREFIX,
+                &reading("A4:C1:38:00:00:01", Measurement::Battery(90.0)),
+            )
+            .unwrap();
+        assert_eq!(message.topic, "home/OMG/BTtoMQTT/A4C1388F2C1A");
+        assert_eq!(
+            message.payload.to_string(),
+            r#"{"hum":41.5,"id":"A4:C1:38:8F:2C:1A","name":"ATC_8F2C1A","tempc":20.0,"tempf":68.0}"#
+        );
+
+        let message = aggregator.flush(DEFAULT_TOPIC_PREFIX).unwrap();
+        assert_eq!(message.topic, "home/OMG/BTtoMQTT/A4C138000001");
+        assert!(!aggregator.is_pending());
+
+        // The next advertisement from the same device starts a new message.
+        let numbered = |advertisement, measurement| DeviceReading {
+            advertisement: Some(advertisement),
+            ..reading(a, measurement)
+        };
+        assert!(aggregator
+            .push(
+                DEFAULT_TOPIC_PREFIX,
+                &numbered(1, Measurement::Temperature(20.0))
+            )
+            .is_none());
+        let message = aggregator
+            .push(
+                DEFAULT_TOPIC_PREFIX,
+                &numbered(2, Measurement::Temperature(20.5)),
+            )
+            .unwrap();
+        assert_eq!(message.payload["tempc"], 20.0);
+    }
+}
@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use tokio::time;
+
+use crate::clock::unix_timestamp;
+use crate::config::{AvailabilityConfig, WatchdogConfig};
+use crate::diagnostics::DIAGNOSTICS;
+use crate::fanout::devices_match;
+use crate::supervisor::SUPERVISOR;
+use crate::{DeviceEvent, DeviceId};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// Availability is how a device is expected to advertise, and so what its silence means.
+// `interval` devices advertise regularly: watchdog rules warn when they miss their intervals, and
+// readings restored after a restart are stale until they are heard from. `event-only` devices,
+// such as leak sensors and buttons, only advertise when something happens, so their silence is
+// normal and the state they last reported holds: they are never warned about, and restored
+// readings aren't stale. `never` devices are never warned about either, without vouching for
+// their restored readings, for devices whose comings and goings don't matter.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Availability {
+    #[default]
+    Interval,
+    EventOnly,
+    Never,
+}
+
+// availability is the mode of the first entry matching device_id.
+pub fn availability(entries: &[AvailabilityConfig], device_id: &DeviceId) -> Availability {
+    entries
+        .iter()
+        .find(|entry| devices_match(&entry.devices, device_id))
+        .map_or(Availability::Interval, |entry| entry.mode)
+}
+
+// NotSeen warns that a device has missed several of its expected advertising intervals.
+#[derive(Serialize, Debug, Clone)]
+pub struct NotSeen {
+    #[serde(flatten)]
+    pub device_id: DeviceId,
+    pub warning: &'static str,
+    // Seconds.
+    pub interval: u64,
+    pub missed: u32,
+    // Seconds since the Unix epoch.
+    pub last_seen: u64,
+}
+
+struct Watched {
+    device_id: DeviceId,
+    interval: Duration,
+    missed: u32,
+    last_seen: Instant,
+    last_seen_timestamp: u64,
+    warned: bool,
+}
+
+// Watchdog keeps an eye on devices that are expected to advertise at least every interval, and
+// warns once when one misses `missed` intervals in a row. Devices are watched from the first time
+// they are seen, since watchdog rules may match devices by pattern. Only devices advertising at
+// intervals are watched.
+pub struct Watchdog {
+    rules: Vec<WatchdogConfig>,
+    availability: Vec<AvailabilityConfig>,
+    devices: HashMap<String, Watched>,
+}
+
+impl Watchdog {
+    pub fn new(rules: Vec<WatchdogConfig>, availability: Vec<AvailabilityConfig>) -> Self {
+        Watchdog {
+            rules,
+            availability,
+            devices: HashMap::new(),
+        }
+    }
+
+    pub fn sighting(&mut self, event: &DeviceEvent, now: Instant) {
+        let device_id = event.device_id();
+        if let Some(watched) = self.devices.get_mut(&device_id.id) {
+            watched.last_seen = now;
+            watched.last_seen_timestamp = unix_timestamp();
+            watched.warned = false;
+            return;
+        }
+        let Some(rule) = self
+            .rules
+            .iter()
+            .find(|rule| devices_match(&rule.devices, device_id))
+        else {
+            return;
+        };
+        if availability(&self.availability, device_id) != Availability::Interval {
+            return;
+        }
+        let watched = Watched {
+            device_id: device_id.clone(),
+            interval: Duration::from_secs(rule.interval),
+            missed: rule.missed,
+            last_seen: now,
+            last_seen_timestamp: unix_timestamp(),
+            warned: false,
+        };
+        self.devices.insert(device_id.id.clone(), watched);
+    }
+
+    // overdue returns the devices that have just missed too many intervals.
+    pub fn overdue(&mut self, now: Instant) -> Vec<NotSeen> {
+        let mut overdue = Vec::new();
+        for watched in self.devices.values_mut() {
+            if watched.warned
+                || now.duration_since(watched.last_seen) < watched.interval * watched.missed
+            {
+                continue;
+            }
+            watched.warned = true;
+            overdue.push(NotSeen {
+                device_id: watched.device_id.clone(),
+                warning: "not_seen",
+                interval: watched.interval.as_secs(),
+                missed: watched.missed,
+                last_seen: watched.last_seen_timestamp,
+            });
+        }
+        overdue
+    }
+}
+
+// spawn_watchdog checks every CHECK_INTERVAL for overdue devices, reporting them as diagnostics.
+pub fn spawn_watchdog(watchdog: Arc<Mutex<Watchdog>>) {
+    SUPERVISOR.spawn("watchdog", move || {
+        let watchdog = watchdog.clone();
+        async move {
+            let mut interval = time::interval(CHECK_INTERVAL);
+            loop {
+                interval.tick().await;
+                let overdue = watchdog.lock().unwrap().overdue(Instant::now());
+                for not_seen in overdue {
+                    DIAGNOSTICS.not_seen(not_seen);
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::{Duration, Instant};
+
+    use crate::config::{AvailabilityConfig, WatchdogConfig};
+    use crate::watchdog::{Availability, Watchdog};
+    use crate::{DeviceEvent, DeviceId};
+
+    fn event(name: &str) -> DeviceEvent {
+        DeviceEvent::ManufacturerDataAdvertisement {
+            device_id: DeviceId {
+                id: format!("hci0/{}", name),
+                device_name: name.to_string(),
+                address: String::new(),
+                random_address: None,
+            },
+            manufacturer_data: HashMap::from([(0x0499, vec![0x05])]),
+            rssi: None,
+        }
+    }
+
+    #[test]
+    fn test_watchdog() {
+        let start = Instant::now();
+        let mut watchdog = Watchdog::new(
+            vec![WatchdogConfig {
+                devices: vec!["Ruuvi*".to_string()],
+                interval: 10,
+                missed: 3,
+            }],
+            vec![AvailabilityConfig {
+                devices: vec!["Ruuvi_Leak".to_string()],
+                mode: Availability::EventOnly,
+            }],
+        );
+        watchdog.sighting(&event("Ruuvi_1"), start);
+        watchdog.sighting(&event("ATC_1"), start);
+        // Silence is normal for a device that only advertises on events.
+        watchdog.sighting(&event("Ruuvi_Leak"), start);
+
+        assert!(watchdog.overdue(start + Duration::from_secs(29)).is_empty());
+        let overdue = watchdog.overdue(start + Duration::from_secs(30));
+        assert_eq!(overdue.len(), 1);
+        assert_eq!(overdue[0].device_id.device_name, "Ruuvi_1");
+        assert_eq!(overdue[0].missed, 3);
+        // Only one warning per absence.
+        assert!(watchdog.overdue(start + Duration::from_secs(60)).is_empty());
+
+        watchdog.sighting(&event("Ruuvi_1"), start + Duration::from_secs(61));
+        assert!(watchdog.overdue(start + Duration::from_secs(90)).is_empty());
+        assert_eq!(watchdog.overdue(start + Duration::from_secs(91)).len(), 1);
+    }
+}
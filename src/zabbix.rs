@@ -0,0 +1,248 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time;
+
+use crate::config::ZabbixConfig;
+use crate::names::{LOCATION_UNASSIGNED, TOPIC_NAMES};
+use crate::supervisor::SUPERVISOR;
+use crate::DeviceReading;
+
+// Every Zabbix protocol packet starts with this, followed by the length of the JSON data as a
+// little-endian u64.
+const HEADER: &[u8; 5] = b"ZBXD\x01";
+
+// A response longer than this isn't from a Zabbix server.
+const MAX_RESPONSE: u64 = 1024 * 1024;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Serialize, Debug, PartialEq)]
+struct Item {
+    host: String,
+    key: String,
+    value: String,
+    clock: u64,
+}
+
+#[derive(Serialize)]
+struct Request<'a> {
+    request: &'static str,
+    data: &'a [Item],
+}
+
+#[derive(Deserialize)]
+struct Response {
+    response: String,
+    #[serde(default)]
+    info: String,
+}
+
+// Zabbix sends readings to a Zabbix server or proxy the way zabbix_sender does, as values of
+// trapper items. Each reading goes to the item whose key is the key template, on the host named by
+// hosts or else the host template, with `{device}`, `{kind}`, `{address}`, `{id}` and `{location}`
+// replaced by the reading's, the location being "unassigned" if it has none. The items must exist
+// in Zabbix, with type "Zabbix trapper"; values for unknown items are counted as failed by the
+// server and logged.
+pub struct Zabbix {
+    config: ZabbixConfig,
+    items: Vec<Item>,
+}
+
+impl Zabbix {
+    pub fn new(config: ZabbixConfig) -> Self {
+        Zabbix {
+            config,
+            items: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, reading: &DeviceReading, now: SystemTime) {
+        let device = TOPIC_NAMES.topic_name(&reading.device_id);
+        let location = TOPIC_NAMES
+            .location(&reading.device_id)
+            .unwrap_or_else(|| LOCATION_UNASSIGNED.to_string());
+        let expand = |template: &str| {
+            template
+                .replace("{device}", &device)
+                .replace("{kind}", reading.measurement.kind())
+                .replace("{address}", &reading.device_id.address)
+                .replace("{id}", &reading.device_id.id)
+                .replace("{location}", &location)
+        };
+        let host = match self.config.hosts.get(&reading.device_id.device_name) {
+            Some(host) => host.clone(),
+            None => expand(&self.config.host),
+        };
+        self.items.push(Item {
+            host,
+            key: expand(&self.config.key),
+            value: reading.measurement.value().to_string(),
+            clock: now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+        });
+    }
+
+    // send sends the items collected so far, which are dropped whether or not that succeeds.
+    pub async fn send(&mut self) -> Result<()> {
+        if self.items.is_empty() {
+            return Ok(());
+        }
+        let items = std::mem::take(&mut self.items);
+        let address = (self.config.server.as_str(), self.config.port);
+        let exchange = async {
+            let mut stream = TcpStream::connect(address).await?;
+            stream.write_all(&packet(&items)?).await?;
+            read_response(&mut stream).await
+        };
+        let response = time::timeout(TIMEOUT, exchange)
+            .await
+            .map_err(|_| eyre!("timed out"))?
+            .wrap_err_with(|| format!("sending {} values", items.len()))?;
+        if response.response != "success" {
+            return Err(eyre!(
+                "server responded {}: {}",
+                response.response,
+                response.info
+            ));
+        }
+        // info is like "processed: 2; failed: 1; total: 3; seconds spent: 0.000055".
+        if !response.info.contains("failed: 0;") {
+            println!(
+                "{}: some values were rejected, check the items exist as trapper items: {}",
+                self.config.name, response.info
+            );
+        }
+        Ok(())
+    }
+}
+
+// packet frames a sender data request.
+fn packet(items: &[Item]) -> Result<Vec<u8>> {
+    let data = serde_json::to_vec(&Request {
+        request: "sender data",
+        data: items,
+    })?;
+    let mut packet = HEADER.to_vec();
+    packet.extend_from_slice(&(data.len() as u64).to_le_bytes());
+    packet.extend_from_slice(&data);
+    Ok(packet)
+}
+
+async fn read_response(stream: &mut TcpStream) -> Result<Response> {
+    let mut header = [0; 13];
+    stream.read_exact(&mut header).await?;
+    if &header[..4] != b"ZBXD" {
+        return Err(eyre!("not a Zabbix response"));
+    }
+    let length = u64::from_le_bytes(header[5..].try_into()?);
+    if length > MAX_RESPONSE {
+        return Err(eyre!("response of {} bytes is too long", length));
+    }
+    let mut data = vec![0; length as usize];
+    stream.read_exact(&mut data).await?;
+    Ok(serde_json::from_slice(&data)?)
+}
+
+// spawn_zabbix sends the readings received to zabbix every interval.
+pub fn spawn_zabbix(mut zabbix: Zabbix, mut readings: broadcast::Receiver<Arc<DeviceReading>>) {
+    let name = zabbix.config.name.clone();
+    SUPERVISOR.spawn_once(format!("zabbix {}", name), async move {
+        let mut interval = time::interval(Duration::from_secs(zabbix.config.interval));
+        interval.tick().await;
+        loop {
+            tokio::select! {
+                received = readings.recv() => {
+                    match received {
+                        Ok(reading) => zabbix.push(&reading, SystemTime::now()),
+                        Err(RecvError::Lagged(skipped)) => {
+                            println!("{}: falling behind, dropped {} readings", name, skipped);
+                        }
+                        Err(RecvError::Closed) => return,
+                    }
+                }
+                _ = interval.tick() => {
+                    if let Err(e) = zabbix.send().await {
+                        println!("{}: sending failed: {:?}", name, e);
+                    }
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use crate::config::ZabbixConfig;
+    use crate::zabbix::Zabbix;
+    use crate::{DeviceId, DeviceReading, Measurement};
+
+    #[tokio::test]
+    async fn test_zabbix() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut header = [0; 13];
+            stream.read_exact(&mut header).await.unwrap();
+            assert_eq!(&header[..5], b"ZBXD\x01");
+            let mut data = vec![0; u64::from_le_bytes(header[5..].try_into().unwrap()) as usize];
+            stream.read_exact(&mut data).await.unwrap();
+            let response = br#"{"response":"success","info":"processed: 2; failed: 0; total: 2; seconds spent: 0.0001"}"#;
+            stream.write_all(b"ZBXD\x01").await.unwrap();
+            stream
+                .write_all(&(response.len() as u64).to_le_bytes())
+                .await
+                .unwrap();
+            stream.write_all(response).await.unwrap();
+            serde_json::from_slice::<serde_json::Value>(&data).unwrap()
+        });
+
+        let mut zabbix = Zabbix::new(ZabbixConfig {
+            name: "zabbix".to_string(),
+            server: "127.0.0.1".to_string(),
+            port,
+            host: "{device}".to_string(),
+            key: "blueplug[{kind}]".to_string(),
+            hosts: BTreeMap::from([("Greenhouse".to_string(), "greenhouse.example".to_string())]),
+            interval: 10,
+        });
+        let reading = |name: &str, measurement| DeviceReading {
+            device_id: DeviceId {
+                id: format!("hci0/{}", name),
+                device_name: name.to_string(),
+                address: String::new(),
+                random_address: None,
+            },
+            measurement,
+            advertisement: None,
+            quality: Vec::new(),
+        };
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        zabbix.push(&reading("Greenhouse", Measurement::Temperature(21.5)), now);
+        zabbix.push(&reading("ATC_ZABBIX", Measurement::Humidity(40.0)), now);
+        zabbix.send().await.unwrap();
+
+        assert_eq!(
+            server.await.unwrap(),
+            serde_json::json!({
+                "request": "sender data",
+                "data": [
+                    {"host": "greenhouse.example", "key": "blueplug[temperature]", "value": "21.5", "clock": 1_700_000_000},
+                    {"host": "ATC_ZABBIX", "key": "blueplug[humidity]", "value": "40", "clock": 1_700_000_000},
+                ]
+            })
+        );
+    }
+}